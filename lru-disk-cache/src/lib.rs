@@ -12,6 +12,7 @@ use std::io::prelude::*;
 
 use std::borrow::Borrow;
 use std::boxed::Box;
+use std::collections::HashSet;
 use std::collections::hash_map::RandomState;
 use std::error::Error as StdError;
 use std::ffi::{OsStr, OsString};
@@ -19,8 +20,10 @@ use std::fmt;
 use std::fs::{self,File};
 use std::io;
 use std::hash::BuildHasher;
+use std::mem;
 use std::path::{Path,PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use filetime::{FileTime, set_file_times};
 use lru_cache::{LruCache,Meter};
@@ -38,31 +41,66 @@ impl<K> Meter<K, (PathBuf, u64)> for FileSize {
     }
 }
 
-/// Return an iterator of `(path, size)` of files under `path` sorted by ascending last-modified
-/// time, such that the oldest modified file is returned first.
+/// Return `true` if `path` is a hidden file, such as a temporary file or lock file, that
+/// isn't part of the cache.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().map_or(false, |name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Return `true` if `path` is a temporary file written by `insert_bytes`.
+fn is_temp_file(path: &Path) -> bool {
+    is_hidden(path) && path.file_name().map_or(false, |name| name.to_string_lossy().contains(".tmp"))
+}
+
+/// Temporary files older than this were left behind by a process that died while
+/// writing them.
+const ORPHANED_TEMP_FILE_SECS: u64 = 3600;
+
+/// What `LruDiskCache::rebuild` changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct RebuildStats {
+    /// The number of orphaned temporary files removed.
+    pub temp_files_removed: usize,
+    /// The number of files that were in the cache but had been removed from disk.
+    pub missing: usize,
+    /// The number of files on disk that weren't in the cache.
+    pub added: usize,
+    /// The number of files evicted to bring the cache within its size limit.
+    pub evicted: usize,
+}
+
+/// Return an iterator of `(path, size)` of files under `path` sorted by ascending last-accessed
+/// time, such that the least recently used file is returned first.
 fn get_all_files<P: AsRef<Path>>(path: P) -> Box<Iterator<Item=(PathBuf, u64)>> {
     let mut files: Vec<_> = WalkDir::new(path.as_ref())
         .into_iter()
         .filter_map(|e| e.ok()
                     .and_then(|f| {
                         // Only look at files
-                        if f.file_type().is_file() {
-                            // Get the last-modified time, size, and the full path.
-                            f.metadata().ok().and_then(|m| m.modified().ok().map(|mtime| (mtime, f.path().to_owned(), m.len())))
+                        if f.file_type().is_file() && !is_hidden(f.path()) {
+                            // Get the last-accessed time, size, and the full path. Filesystems
+                            // without access times have the last-modified time used instead.
+                            f.metadata().ok().and_then(|m| {
+                                m.accessed().or_else(|_| m.modified()).ok()
+                                    .map(|atime| (atime, f.path().to_owned(), m.len()))
+                            })
                         } else {
                             None
                         }
                     }))
         .collect();
-    // Sort by last-modified-time, so oldest file first.
+    // Sort by last-accessed-time, so least recently used file first.
     files.sort_by_key(|k| k.0);
-    Box::new(files.into_iter().map(|(_mtime, path, size)| (path, size)))
+    Box::new(files.into_iter().map(|(_atime, path, size)| (path, size)))
 }
 
 /// An LRU cache of files on disk.
 pub struct LruDiskCache<S: BuildHasher = RandomState> {
     lru: LruCache<OsString, (PathBuf, u64), S, FileSize>,
     root: PathBuf,
+    /// Files evicted from the cache whose removal from disk has been deferred,
+    /// if `defer_removals` has been called.
+    evicted: Option<Vec<PathBuf>>,
 }
 
 /// Errors returned by this crate.
@@ -118,6 +156,29 @@ fn filetime_now() -> FileTime {
     FileTime::from_seconds_since_1970(d.as_secs(), d.subsec_nanos())
 }
 
+/// Return `true` if the file at `path` was last modified more than `max_age` ago.
+/// Files that have gone missing count as old, so that they are dropped as well.
+fn is_file_older_than(path: &Path, max_age: Duration) -> bool {
+    let cutoff = match SystemTime::now().checked_sub(max_age) {
+        Some(cutoff) => cutoff,
+        None => return false,
+    };
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(|mtime| mtime < cutoff)
+        .unwrap_or(true)
+}
+
+/// Remove a file that was evicted from the cache. Files that are already gone
+/// are fine; anything else is logged, since the cache no longer tracks the file.
+pub fn remove_evicted_file(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Error removing file from cache: `{:?}`: {}", path, e),
+    }
+}
+
 impl LruDiskCache {
     /// Create an `LruDiskCache` that stores files in `path`, limited to `size` bytes.
     ///
@@ -133,6 +194,7 @@ impl LruDiskCache {
         LruDiskCache {
             lru: LruCache::with_meter(size, FileSize),
             root: PathBuf::from(path),
+            evicted: None,
         }.init()
     }
 
@@ -142,9 +204,47 @@ impl LruDiskCache {
     /// Return the maximum size of the cache.
     pub fn capacity(&self) -> usize { self.lru.capacity() }
 
+    /// Return the number of files in the cache.
+    pub fn len(&self) -> usize { self.lru.len() }
+
     /// Return the path in which the cache is stored.
     pub fn path(&self) -> &Path { self.root.as_path() }
 
+    /// Return the paths of the files in the cache, relative to its root, from least to
+    /// most recently used.
+    pub fn keys(&self) -> Vec<PathBuf> {
+        self.lru.iter().map(|(k, _)| PathBuf::from(k)).collect()
+    }
+
+    /// Stop removing evicted files from disk as part of inserting new files.
+    ///
+    /// Evicted files are still removed from the cache's accounting immediately, but
+    /// the caller is responsible for deleting the paths returned by `take_evicted`
+    /// with `remove_evicted`, which lets it do so one file at a time rather than
+    /// holding any lock around the cache for all of them.
+    pub fn defer_removals(&mut self) {
+        if self.evicted.is_none() {
+            self.evicted = Some(vec!());
+        }
+    }
+
+    /// Return the paths of files evicted since the last call, which should be removed
+    /// from disk. Always empty unless `defer_removals` has been called.
+    pub fn take_evicted(&mut self) -> Vec<PathBuf> {
+        self.evicted.as_mut().map(|v| mem::replace(v, vec!())).unwrap_or(vec!())
+    }
+
+    /// Remove the file at `path`, returned by `take_evicted`, from disk, unless a
+    /// file has been inserted at the same path since it was evicted.
+    pub fn remove_evicted(&self, path: &Path) {
+        let reinserted = path.strip_prefix(&self.root)
+            .map(|rel_path| self.contains_key(rel_path))
+            .unwrap_or(false);
+        if !reinserted {
+            remove_evicted_file(path);
+        }
+    }
+
     /// Scan `self.root` for existing files and store them.
     fn init(mut self) -> Result<Self> {
         try!(fs::create_dir_all(&self.root));
@@ -159,6 +259,46 @@ impl LruDiskCache {
         Ok(self)
     }
 
+    /// Rescan `self.root` and rebuild the cache from the files found there, removing
+    /// orphaned temporary files and evicting files until the cache fits in its size
+    /// limit.
+    ///
+    /// This picks up changes made to the cache directory by other processes.
+    pub fn rebuild(&mut self) -> Result<RebuildStats> {
+        let mut stats = RebuildStats::default();
+        let cutoff = SystemTime::now() - Duration::from_secs(ORPHANED_TEMP_FILE_SECS);
+        for entry in WalkDir::new(&self.root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() || !is_temp_file(entry.path()) {
+                continue;
+            }
+            let orphaned = entry.metadata().ok()
+                .and_then(|m| m.modified().ok())
+                .map_or(false, |mtime| mtime < cutoff);
+            if orphaned && fs::remove_file(entry.path()).is_ok() {
+                stats.temp_files_removed += 1;
+            }
+        }
+        let mut old_keys: HashSet<OsString> = self.lru.iter().map(|(k, _)| k.clone()).collect();
+        self.lru = LruCache::with_meter(self.lru.capacity(), FileSize);
+        let mut inserted = 0;
+        for (file, size) in get_all_files(&self.root) {
+            let rel_path = file.strip_prefix(&self.root).expect("Bad path?").as_os_str().to_owned();
+            if !old_keys.remove(&rel_path) {
+                stats.added += 1;
+            }
+            if !self.can_store(size) {
+                self.evict(file);
+                stats.evicted += 1;
+                continue;
+            }
+            try!(self.add_file(file, Some(&rel_path), size));
+            inserted += 1;
+        }
+        stats.missing = old_keys.len();
+        stats.evicted += inserted - self.len();
+        Ok(stats)
+    }
+
     /// Returns `true` if the disk cache can store a file of `size` bytes.
     pub fn can_store(&self, size: u64) -> bool {
         size <= self.lru.capacity() as u64
@@ -174,17 +314,50 @@ impl LruDiskCache {
         } else {
             path.strip_prefix(&self.root).expect("Bad path?").as_os_str()
         });
+        // If this key is already in the cache its file has just been overwritten, so
+        // drop the old entry from the accounting rather than evicting it below, which
+        // would remove the new file.
+        self.lru.remove(&rel_path);
         //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
         while self.lru.size() as u64 + size > self.lru.capacity() as u64 {
             let (_, (remove_path, _)) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
-            //TODO: check that files are removable during `init`, so that this is only
-            // due to outside interference.
-            fs::remove_file(&remove_path).unwrap_or_else(|e| panic!("Error removing file from cache: `{:?}`: {}", remove_path, e));
+            self.evict(remove_path);
         }
         self.lru.insert(rel_path, (path, size));
         Ok(())
     }
 
+    /// Remove the file at `path`, which has been dropped from `self.lru`.
+    fn evict(&mut self, path: PathBuf) {
+        match self.evicted {
+            Some(ref mut evicted) => evicted.push(path),
+            None => remove_evicted_file(&path),
+        }
+    }
+
+    /// Return `true` if the file at path `key` was inserted more than `max_age` ago,
+    /// however recently it was used. Files keep the time they were inserted as their
+    /// last-modified time, since `get` only updates their last-accessed time.
+    pub fn is_older_than<K: AsRef<OsStr>>(&self, key: K, max_age: Duration) -> bool {
+        self.contains_key(&key) && is_file_older_than(&self.root.join(key.as_ref()), max_age)
+    }
+
+    /// Remove files that were inserted more than `max_age` ago, however recently they
+    /// were used, returning the number of files removed.
+    ///
+    /// This checks every file in the cache, so it belongs in periodic cleanup rather
+    /// than in every insert.
+    pub fn remove_older_than(&mut self, max_age: Duration) -> usize {
+        let expired = self.lru.iter()
+            .filter(|&(_, &(ref path, _))| is_file_older_than(path, max_age))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired.iter() {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
     fn insert_by<K: AsRef<OsStr>, F: Fn(&Path) -> io::Result<()>>(&mut self, key: K, size: u64, by: F) -> Result<()> {
         if !self.can_store(size) {
             return Err(Error::FileTooLarge);
@@ -202,44 +375,100 @@ impl LruDiskCache {
     }
 
     /// Add a file with `bytes` as its contents to the cache at path `key`.
+    ///
+    /// The file is written under a temporary name and renamed into place, so other
+    /// processes sharing the cache directory never see a partially-written file.
     pub fn insert_bytes<K: AsRef<OsStr>>(&mut self, key: K, bytes: &[u8]) -> Result<()> {
         self.insert_by(key, bytes.len() as u64, |path| {
-            let mut f = try!(File::create(&path));
-            try!(f.write_all(bytes));
-            Ok(())
+            let mut tmp_name = OsString::from(".");
+            tmp_name.push(path.file_name().expect("Bad path?"));
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+            tmp_name.push(format!(".tmp{}.{}", process::id(), nanos));
+            let tmp_path = path.with_file_name(tmp_name);
+            let result = File::create(&tmp_path)
+                .and_then(|mut f| f.write_all(bytes))
+                .and_then(|_| fs::rename(&tmp_path, path));
+            if result.is_err() {
+                fs::remove_file(&tmp_path).unwrap_or(());
+            }
+            result
         })
     }
 
+    /// Add the file at path `key`, which was written to the cache directory by another
+    /// process sharing it, to the cache.
+    pub fn adopt<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
+        let rel_path = key.as_ref();
+        let path = self.root.join(rel_path);
+        let size = match fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(Error::FileNotInCache),
+            Err(e) => return Err(e.into()),
+        };
+        self.add_file(path, Some(rel_path), size)
+    }
+
     /// Add an existing file at `path` to the cache at path `key`.
     pub fn insert_file<K: AsRef<OsStr>, P: AsRef<OsStr>>(&mut self, key: K, path: P) -> Result<()> {
         let size = try!(fs::metadata(path.as_ref())).len();
         self.insert_by(key, size, |new_path| {
-            fs::rename(path.as_ref(), new_path)
+            try!(fs::rename(path.as_ref(), new_path)
                 .or_else(|_| {
                     warn!("fs::rename failed, falling back to copy!");
                     try!(fs::copy(path.as_ref(), new_path));
                     fs::remove_file(path.as_ref())
                         .unwrap_or_else(|e| error!("Failed to remove original file in insert_file: {}", e));
                     Ok(())
-                })
+                }));
+            // The file's age is counted from when it was inserted.
+            let t = filetime_now();
+            set_file_times(new_path, t, t)
         })
     }
 
+    /// Remove the file at path `key` from the cache, if present.
+    pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) {
+        if let Some((path, _)) = self.lru.remove(key.as_ref()) {
+            self.evict(path);
+        }
+    }
+
+    /// Remove every file from the cache, returning the number of files removed.
+    pub fn clear(&mut self) -> usize {
+        let mut removed = 0;
+        while let Some((_, (path, _))) = self.lru.remove_lru() {
+            self.evict(path);
+            removed += 1;
+        }
+        removed
+    }
+
     /// Return `true` if a file with path `key` is in the cache.
     pub fn contains_key<K: AsRef<OsStr>>(&self, key: K) -> bool {
         self.lru.contains_key(key.as_ref())
     }
 
     /// Get an opened readable and seekable handle to the file at `key`, if one exists and can
-    /// be opened. Updates the LRU state of the file if present.
+    /// be opened. Updates the LRU state of the file if present, and its last-accessed time,
+    /// leaving its last-modified time as the time it was inserted.
+    ///
+    /// Files that have been removed from disk behind the cache's back, for example by another
+    /// process sharing the cache directory, are dropped from the cache.
     pub fn get<K: AsRef<OsStr>>(&mut self, key: K) -> Result<Box<ReadSeek>> {
-        self.lru.get(key.as_ref())
+        let result = self.lru.get(key.as_ref())
             .ok_or(Error::FileNotInCache)
             .and_then(|&(ref path, _)| {
-                let t = filetime_now();
-                try!(set_file_times(path, t, t));
+                let mtime = FileTime::from_last_modification_time(&try!(fs::metadata(path)));
+                try!(set_file_times(path, filetime_now(), mtime));
                 Ok(Box::new(try!(File::open(path))) as Box<ReadSeek>)
-            })
+            });
+        match result {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                self.lru.remove(key.as_ref());
+                Err(Error::FileNotInCache)
+            }
+            result => result,
+        }
     }
 }
 
@@ -251,6 +480,7 @@ mod tests {
     use std::fs::{self,File};
     use std::io::{self, Read, Write};
     use std::path::{Path,PathBuf};
+    use std::time::Duration;
     use tempdir::TempDir;
 
     struct TestFixture {
@@ -410,6 +640,167 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_insert_bytes_existing_key() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        // Overwriting the least-recently-used file shouldn't evict it.
+        c.insert_bytes("file1", &vec![3; 10]).unwrap();
+        assert_eq!(c.size(), 20);
+        assert_eq!(c.len(), 2);
+        assert_eq!(read_all(&mut c.get("file1").unwrap()).unwrap(), vec![3u8; 10]);
+    }
+
+    #[test]
+    fn test_deferred_removals() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.defer_removals();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        assert!(c.take_evicted().is_empty());
+        c.insert_bytes("file3", &vec![3; 10]).unwrap();
+        assert_eq!(c.size(), 20);
+        assert!(!c.contains_key("file1"));
+        // The file is only removed from the accounting, not from disk.
+        assert!(f.tmp().join("file1").exists());
+        let evicted = c.take_evicted();
+        assert_eq!(evicted, vec![f.tmp().join("file1")]);
+        assert!(c.take_evicted().is_empty());
+        for path in evicted.iter() {
+            c.remove_evicted(path);
+        }
+        assert!(!f.tmp().join("file1").exists());
+    }
+
+    #[test]
+    fn test_deferred_removal_reinserted() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.defer_removals();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        c.insert_bytes("file3", &vec![3; 10]).unwrap();
+        let evicted = c.take_evicted();
+        assert_eq!(evicted, vec![f.tmp().join("file1")]);
+        // Stored again before the evicted file was removed.
+        c.insert_bytes("file1", &vec![4; 10]).unwrap();
+        for path in evicted.iter() {
+            c.remove_evicted(path);
+        }
+        assert!(f.tmp().join("file1").exists());
+        assert_eq!(read_all(&mut c.get("file1").unwrap()).unwrap(), vec![4u8; 10]);
+    }
+
+    #[test]
+    fn test_remove_older_than() {
+        let f = TestFixture::new();
+        set_mtime_back(f.create_file("file1", 10), 1000);
+        set_mtime_back(f.create_file("file2", 10), 500);
+        f.create_file("file3", 10);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert_eq!(c.remove_older_than(Duration::from_secs(2000)), 0);
+        assert_eq!(c.remove_older_than(Duration::from_secs(600)), 1);
+        assert!(!c.contains_key("file1"));
+        assert!(!f.tmp().join("file1").exists());
+        assert!(c.contains_key("file2"));
+        assert_eq!(c.size(), 20);
+        // Using a file doesn't make it new again.
+        c.get("file2").unwrap();
+        assert!(c.is_older_than("file2", Duration::from_secs(100)));
+        assert!(!c.is_older_than("file3", Duration::from_secs(100)));
+        assert_eq!(c.remove_older_than(Duration::from_secs(100)), 1);
+        assert!(!c.contains_key("file2"));
+        assert_eq!(c.len(), 1);
+    }
+
+    #[test]
+    fn test_shared_directory() {
+        let f = TestFixture::new();
+        let mut c1 = LruDiskCache::new(f.tmp(), 100).unwrap();
+        let mut c2 = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c1.insert_bytes("a/file1", &vec![1; 10]).unwrap();
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(f.tmp().join("a")).unwrap().count(), 1);
+        // Files written by another process can be picked up.
+        assert!(c2.get("a/file1").is_err());
+        c2.adopt("a/file1").unwrap();
+        assert_eq!(read_all(&mut c2.get("a/file1").unwrap()).unwrap(), vec![1u8; 10]);
+        // Files removed by another process are misses.
+        fs::remove_file(f.tmp().join("a/file1")).unwrap();
+        match c1.get("a/file1") {
+            Err(Error::FileNotInCache) => {}
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+        assert_eq!(c1.size(), 0);
+        match c2.adopt("a/file1") {
+            Err(Error::FileNotInCache) => {}
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        // Changes made by other processes.
+        fs::remove_file(f.tmp().join("file1")).unwrap();
+        f.create_file("file3", 10);
+        set_mtime_back(f.create_file(".file4.tmp1.2", 10), 7200);
+        f.create_file(".file5.tmp1.2", 10);
+        assert_eq!(c.rebuild().unwrap(), RebuildStats {
+            temp_files_removed: 1,
+            missing: 1,
+            added: 1,
+            evicted: 0,
+        });
+        assert!(!c.contains_key("file1"));
+        assert!(c.contains_key("file3"));
+        assert!(!f.tmp().join(".file4.tmp1.2").exists());
+        // Temporary files that may still be being written are left alone.
+        assert!(f.tmp().join(".file5.tmp1.2").exists());
+        assert_eq!(c.size(), 20);
+        // Files added beyond the size limit are evicted, oldest first.
+        set_mtime_back(f.tmp().join("file2"), 10);
+        f.create_file("file6", 10);
+        assert_eq!(c.rebuild().unwrap().evicted, 1);
+        assert!(!f.tmp().join("file2").exists());
+        assert_eq!(c.size(), 20);
+    }
+
+    #[test]
+    fn test_remove() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        c.remove("file1");
+        c.remove("file3");
+        assert!(!c.contains_key("file1"));
+        assert!(!f.tmp().join("file1").exists());
+        assert_eq!(c.size(), 10);
+    }
+
+    #[test]
+    fn test_clear() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("dir/file2", &vec![2; 10]).unwrap();
+        assert_eq!(c.clear(), 2);
+        assert_eq!(c.len(), 0);
+        assert_eq!(c.size(), 0);
+        assert!(!f.tmp().join("file1").exists());
+        assert!(!f.tmp().join("dir").join("file2").exists());
+        // The cache can be filled up to its limit again.
+        c.insert_bytes("file3", &vec![3; 20]).unwrap();
+        assert_eq!(c.size(), 20);
+    }
+
     #[test]
     fn test_insert_bytes_too_large() {
         let f = TestFixture::new();
@@ -0,0 +1,209 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serving the configured storage as a Bazel HTTP remote cache.
+//!
+//! Bazel clients `GET` and `PUT` blobs at `/ac/<hash>`, for action
+//! results, and `/cas/<hash>`, for files by the SHA-256 digest of their
+//! contents. Each blob is stored as a cache entry holding a single object,
+//! under a key derived from its path so that it can't collide with the
+//! entries of compilations.
+//!
+//! Unless it's given tokens for clients to present, it only listens on
+//! the loopback interface.
+
+use cache::{Cache, Storage, storage_from_environment};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use futures::{Future, future};
+use futures_cpupool::CpuPool;
+use http_server::{self, RequestHead, Response, Tokens};
+use sha1;
+use std::env;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+use errors::*;
+
+/// The port the Bazel cache listens on by default.
+pub const DEFAULT_PORT: u16 = 10602;
+
+/// The name of the object holding a blob in its cache entry.
+const BLOB_OBJECT: &'static str = "blob";
+
+/// Configuration of the Bazel cache.
+pub struct Config {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// The tokens clients present, if any are needed.
+    pub tokens: Option<Tokens>,
+    /// The largest blob clients may store.
+    pub max_blob_size: usize,
+}
+
+impl Config {
+    /// Listen on `SCCACHE_BAZEL_CACHE_LISTEN`, with the tokens in
+    /// `SCCACHE_BAZEL_CACHE_TOKEN` and `SCCACHE_BAZEL_CACHE_READ_TOKEN`.
+    /// Without a token, only loopback addresses may be listened on.
+    pub fn from_environment() -> Result<Config> {
+        let listen = env::var("SCCACHE_BAZEL_CACHE_LISTEN")
+            .unwrap_or_else(|_| format!("127.0.0.1:{}", DEFAULT_PORT));
+        let listen = listen.parse::<SocketAddr>()
+            .chain_err(|| format!("invalid SCCACHE_BAZEL_CACHE_LISTEN {}", listen))?;
+        let tokens = Tokens::from_environment("SCCACHE_BAZEL_CACHE_TOKEN",
+                                              "SCCACHE_BAZEL_CACHE_READ_TOKEN")?;
+        if tokens.is_none() && !listen.ip().is_loopback() {
+            bail!("SCCACHE_BAZEL_CACHE_TOKEN must be set to listen on {}", listen);
+        }
+        Ok(Config {
+            listen: listen,
+            tokens: tokens,
+            max_blob_size: http_server::max_body_from_environment(),
+        })
+    }
+}
+
+/// The kinds of blobs Bazel stores.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlobKind {
+    /// Action results, by the digest of the action.
+    ActionCache,
+    /// Files, by the digest of their contents.
+    ContentAddressed,
+}
+
+/// Parse the path of a request into the kind and hash of the blob it's
+/// for, accepting hex hashes only.
+fn parse_path(path: &str) -> Option<(BlobKind, String)> {
+    let (kind, hash) = if path.starts_with("/ac/") {
+        (BlobKind::ActionCache, &path[4..])
+    } else if path.starts_with("/cas/") {
+        (BlobKind::ContentAddressed, &path[5..])
+    } else {
+        return None
+    };
+    if hash.is_empty() || !hash.chars().all(|c| c.is_digit(16)) {
+        return None
+    }
+    Some((kind, hash.to_lowercase()))
+}
+
+/// The cache key to store a blob under.
+fn blob_key(kind: BlobKind, hash: &str) -> String {
+    let kind = match kind {
+        BlobKind::ActionCache => "ac",
+        BlobKind::ContentAddressed => "cas",
+    };
+    let mut m = sha1::Sha1::new();
+    m.update(format!("bazel/{}/{}", kind, hash).as_bytes());
+    m.digest().to_string()
+}
+
+/// Whether `data` may be stored as the content-addressed blob `hash`.
+/// Only SHA-256 hashes can be checked; Bazel uses them by default.
+fn content_matches(hash: &str, data: &[u8]) -> bool {
+    if hash.len() != 64 {
+        return true
+    }
+    let mut m = Sha256::new();
+    m.input(data);
+    m.result_str() == hash
+}
+
+/// Serve the configured storage as a Bazel remote cache, until the
+/// process is killed.
+pub fn run(config: Config) -> Result<()> {
+    let mut core = Core::new()?;
+    let pool = CpuPool::new(20);
+    let storage = storage_from_environment(&pool, &core.handle())?;
+    let listener = TcpListener::bind(&config.listen, &core.handle())
+        .chain_err(|| format!("couldn't listen on {}", config.listen))?;
+    info!("Serving {} as a Bazel remote cache on http://{}", storage.location(),
+          listener.local_addr()?);
+    let max_body = config.max_blob_size;
+    let tokens = config.tokens;
+    http_server::serve(&mut core, listener, max_body,
+                       // Without tokens, only local clients can connect, and may do anything.
+                       Rc::new(move |head: &RequestHead| {
+                           tokens.as_ref().and_then(|tokens| tokens.check(head))
+                       }),
+                       Rc::new(move |head, body| handle(head, body, storage.clone(), pool.clone())))
+}
+
+/// Handle a request for a blob, which `Tokens::check` has allowed.
+fn handle(head: RequestHead, body: Vec<u8>, storage: Arc<Storage>, pool: CpuPool) -> SFuture<Response> {
+    let (kind, hash) = match parse_path(&head.path) {
+        Some(blob) => blob,
+        None => return future::ok(Response::new("404 Not Found")).boxed(),
+    };
+    let key = blob_key(kind, &hash);
+    match &head.method[..] {
+        "GET" => {
+            Box::new(storage.get(&key).and_then(move |result| -> SFuture<Response> {
+                match result {
+                    Cache::Hit(mut entry) => Box::new(pool.spawn_fn(move || {
+                        let mut data = vec!();
+                        entry.get_object(BLOB_OBJECT, &mut data)?;
+                        Ok(Response { status: "200 OK", body: data })
+                    })),
+                    Cache::Miss | Cache::Recache => future::ok(Response::new("404 Not Found")).boxed(),
+                    Cache::Unavailable => future::ok(Response::new("503 Service Unavailable")).boxed(),
+                }
+            }))
+        }
+        "PUT" => {
+            if head.content_length.is_none() {
+                return future::ok(Response::new("411 Length Required")).boxed();
+            }
+            if kind == BlobKind::ContentAddressed && !content_matches(&hash, &body) {
+                return future::ok(Response::new("400 Bad Request")).boxed();
+            }
+            let entry = storage.start_put(&key).and_then(|mut entry| {
+                entry.put_object(BLOB_OBJECT, &mut &body[..])?;
+                Ok(entry)
+            });
+            let put = match entry {
+                Ok(entry) => storage.finish_put(&key, entry),
+                Err(e) => future::err(e).boxed(),
+            };
+            Box::new(put.then(Response::stored))
+        }
+        _ => future::ok(Response::new("405 Method Not Allowed")).boxed(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(Some((BlobKind::ActionCache, "abc123".to_owned())), parse_path("/ac/ABC123"));
+        assert_eq!(Some((BlobKind::ContentAddressed, "abc123".to_owned())), parse_path("/cas/abc123"));
+        assert_eq!(None, parse_path("/cas/"));
+        assert_eq!(None, parse_path("/cas/../ac/abc"));
+        assert_eq!(None, parse_path("/metrics"));
+        assert!(blob_key(BlobKind::ActionCache, "abc") != blob_key(BlobKind::ContentAddressed, "abc"));
+    }
+
+    #[test]
+    fn test_content_matches() {
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(content_matches(hash, b"hello"));
+        assert!(!content_matches(hash, b"hello!"));
+    }
+}
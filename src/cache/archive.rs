@@ -0,0 +1,178 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export and import of the local disk cache as a zstd-compressed tar archive.
+//!
+//! The disk cache has no index of its own: its size accounting and LRU order
+//! are rebuilt from the files' sizes and modification times when it is opened,
+//! so archives keep the modification times of the files.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tar::{Archive, Builder, Header};
+use zstd;
+
+use errors::*;
+
+/// Name of the first member of an archive, describing its contents.
+const MANIFEST: &'static str = "sccache-archive";
+
+/// Version of the archive format, the first line of the manifest.
+const ARCHIVE_VERSION: &'static str = "1";
+
+/// zstd compression level for archives. Cache entries are mostly compressed
+/// already, so there is little to gain from higher levels.
+const ARCHIVE_COMPRESSION_LEVEL: i32 = 3;
+
+/// What an archive contains.
+#[derive(Debug, Default, PartialEq)]
+pub struct ArchiveStats {
+    /// The number of files in the archive.
+    pub files: usize,
+    /// The total size of the files, in bytes.
+    pub size: u64,
+}
+
+/// Find the files in the cache directory `root`, as paths relative to it.
+///
+/// Hidden files, like lock files and partially-written entries, are skipped.
+fn cache_files(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = dir.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            cache_files(root, &path, files)?;
+        } else if metadata.is_file() {
+            files.push((path, metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Write the contents of the cache directory `root` to a new archive at `path`.
+pub fn export(root: &Path, path: &Path) -> Result<ArchiveStats> {
+    let mut files = vec!();
+    cache_files(root, Path::new(""), &mut files)?;
+    let stats = ArchiveStats {
+        files: files.len(),
+        size: files.iter().map(|&(_, size)| size).sum(),
+    };
+    let f = File::create(path).chain_err(|| format!("failed to create {}", path.display()))?;
+    let encoder = zstd::stream::Encoder::new(f, ARCHIVE_COMPRESSION_LEVEL)?;
+    let mut builder = Builder::new(encoder);
+    let manifest = format!("{}\n{} {}\n", ARCHIVE_VERSION, stats.files, stats.size);
+    let mut header = Header::new_gnu();
+    header.set_path(MANIFEST)?;
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append(&header, manifest.as_bytes())?;
+    for (file, _) in files {
+        // This records the file's modification time, which sets its place
+        // in the LRU order when imported.
+        builder.append_file(&file, &mut File::open(root.join(&file))?)?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(stats)
+}
+
+/// Check the manifest of an archive, returning what it says the archive contains.
+fn parse_manifest(manifest: &str) -> Result<ArchiveStats> {
+    let mut lines = manifest.lines();
+    match lines.next() {
+        Some(version) if version == ARCHIVE_VERSION => {}
+        Some(version) => bail!("Unsupported cache archive version {}", version),
+        None => bail!("Invalid cache archive manifest"),
+    }
+    let counts = lines.next().map(|l| l.split(' ').collect::<Vec<_>>()).unwrap_or(vec!());
+    match (counts.get(0).and_then(|n| usize::from_str(n).ok()),
+           counts.get(1).and_then(|n| u64::from_str(n).ok())) {
+        (Some(files), Some(size)) => Ok(ArchiveStats { files: files, size: size }),
+        _ => bail!("Invalid cache archive manifest"),
+    }
+}
+
+/// Extract the archive at `path` into the cache directory `root`, adding its
+/// entries to those already there.
+pub fn import(path: &Path, root: &Path) -> Result<ArchiveStats> {
+    let f = File::open(path).chain_err(|| format!("failed to open {}", path.display()))?;
+    let mut archive = Archive::new(zstd::stream::Decoder::new(f)?);
+    let mut entries = archive.entries()?;
+    let expected = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()? != Path::new(MANIFEST) {
+                bail!("{} is not an sccache cache archive", path.display());
+            }
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            parse_manifest(&manifest)?
+        }
+        None => bail!("{} is not an sccache cache archive", path.display()),
+    };
+    fs::create_dir_all(root)?;
+    let mut stats = ArchiveStats::default();
+    for entry in entries {
+        let mut entry = entry?;
+        let size = entry.header().size()?;
+        if !entry.unpack_in(root)? {
+            bail!("Cache archive contains an invalid path: {}", entry.path()?.display());
+        }
+        stats.files += 1;
+        stats.size += size;
+    }
+    if stats != expected {
+        bail!("Cache archive is incomplete: expected {} files, found {}", expected.files, stats.files);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use test::utils::*;
+
+    #[test]
+    fn test_export_import() {
+        let f = TestFixture::new();
+        let root = f.tempdir.path().join("cache");
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        File::create(root.join("a").join("b").join("abcdef")).unwrap().write_all(b"entry").unwrap();
+        File::create(root.join(".sccache-lock")).unwrap();
+        let archive = f.tempdir.path().join("cache.tar.zst");
+        let stats = export(&root, &archive).unwrap();
+        assert_eq!(ArchiveStats { files: 1, size: 5 }, stats);
+        let imported = f.tempdir.path().join("imported");
+        assert_eq!(stats, import(&archive, &imported).unwrap());
+        let mut contents = vec!();
+        File::open(imported.join("a").join("b").join("abcdef")).unwrap()
+            .read_to_end(&mut contents).unwrap();
+        assert_eq!(b"entry", &contents[..]);
+        assert!(!imported.join(".sccache-lock").exists());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        assert_eq!(ArchiveStats { files: 2, size: 100 }, parse_manifest("1\n2 100\n").unwrap());
+        assert!(parse_manifest("2\n2 100\n").is_err());
+        assert!(parse_manifest("1\n").is_err());
+    }
+}
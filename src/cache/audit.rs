@@ -0,0 +1,248 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    GcStats,
+    Storage,
+};
+use cache::metered::BackendStats;
+use futures::{Future, future};
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use time;
+
+use errors::*;
+
+/// An append-only log of the operations on cache entries, one JSON object
+/// per line.
+pub struct AuditLog {
+    path: PathBuf,
+    file: RefCell<File>,
+    /// The name of the machine the server runs on.
+    host: String,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<AuditLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)
+            .chain_err(|| format!("failed to open audit log {}", path.display()))?;
+        Ok(AuditLog {
+            path: path.to_owned(),
+            file: RefCell::new(file),
+            host: hostname(),
+        })
+    }
+
+    /// Open the log at `SCCACHE_AUDIT_LOG`, unless it isn't set.
+    pub fn from_environment() -> Result<Option<AuditLog>> {
+        match env::var_os("SCCACHE_AUDIT_LOG") {
+            Some(path) => AuditLog::open(Path::new(&path)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Where the log is written.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Log that `user` did `operation` on the entry `key`, with `result`,
+    /// where the entry was `size` bytes if it is known. The user is only
+    /// what the client claims, so it's logged as `client_user`.
+    fn record(&self, user: &str, operation: &str, key: &str, result: &str, size: Option<u64>) {
+        let mut fields = BTreeMap::new();
+        fields.insert("time".to_owned(),
+                      Value::String(time::now_utc().strftime("%Y-%m-%dT%H:%M:%SZ").unwrap().to_string()));
+        fields.insert("operation".to_owned(), Value::String(operation.to_owned()));
+        fields.insert("key".to_owned(), Value::String(key.to_owned()));
+        fields.insert("result".to_owned(), Value::String(result.to_owned()));
+        if let Some(size) = size {
+            fields.insert("size".to_owned(), Value::U64(size));
+        }
+        fields.insert("client_user".to_owned(), Value::String(user.to_owned()));
+        fields.insert("host".to_owned(), Value::String(self.host.clone()));
+        let line = serde_json::to_string(&Value::Object(fields)).unwrap();
+        // Write each line at once, so that lines stay whole even if other
+        // processes append to the same file.
+        if let Err(e) = self.file.borrow_mut().write_all(format!("{}\n", line).as_bytes()) {
+            warn!("Failed to write to audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// The name of this machine.
+#[cfg(unix)]
+fn hostname() -> String {
+    use libc;
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// The name of this machine.
+#[cfg(windows)]
+fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// A wrapper around another `Storage` that logs the operations done on
+/// behalf of `user`, as the client names them, to an `AuditLog`.
+pub struct AuditedStorage {
+    inner: Arc<Storage>,
+    log: Rc<AuditLog>,
+    user: String,
+}
+
+impl AuditedStorage {
+    /// Create a new `AuditedStorage` logging the operations of `user` on
+    /// `inner` to `log`.
+    pub fn new(inner: Arc<Storage>, log: Rc<AuditLog>, user: String) -> AuditedStorage {
+        AuditedStorage {
+            inner: inner,
+            log: log,
+            user: user,
+        }
+    }
+}
+
+impl Storage for AuditedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let log = self.log.clone();
+        let user = self.user.clone();
+        let key = key.to_owned();
+        Box::new(self.inner.get(&key).then(move |result| {
+            match result {
+                Ok(Cache::Hit(ref entry)) => log.record(&user, "get", &key, "hit", Some(entry.size())),
+                Ok(Cache::Miss) => log.record(&user, "get", &key, "miss", None),
+                // Nothing was read.
+                Ok(Cache::Recache) | Ok(Cache::Unavailable) => {}
+                Err(_) => log.record(&user, "get", &key, "error", None),
+            }
+            result
+        }))
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        self.inner.start_put(key)
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        // Finish the entry here to know its size.
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let size = data.len() as u64;
+        let log = self.log.clone();
+        let user = self.user.clone();
+        let key = key.to_owned();
+        Box::new(self.inner.finish_put(&key, CacheWrite::from_bytes(data)).then(move |result| {
+            match result {
+                Ok(_) => log.record(&user, "put", &key, "stored", Some(size)),
+                // Nothing was written.
+                Err(Error(ErrorKind::ReadOnlyCache, _)) |
+                Err(Error(ErrorKind::CacheUnavailable, _)) |
+                Err(Error(ErrorKind::EntryTooLarge(_), _)) => {}
+                Err(_) => log.record(&user, "put", &key, "error", Some(size)),
+            }
+            result
+        }))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let log = self.log.clone();
+        let user = self.user.clone();
+        let key = key.to_owned();
+        Box::new(self.inner.remove(&key).then(move |result| {
+            log.record(&user, "remove", &key, if result.is_ok() { "removed" } else { "error" }, None);
+            result
+        }))
+    }
+
+    fn is_read_only(&self) -> bool { self.inner.is_read_only() }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> { self.inner.gc() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> { self.inner.clear(remote) }
+
+    fn keys(&self) -> SFuture<Vec<String>> { self.inner.keys() }
+
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> { self.inner.backend_stats() }
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> { self.inner.backends() }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<usize> { self.inner.current_size() }
+    fn max_size(&self) -> Option<usize> { self.inner.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.inner.entry_count() }
+    fn background_writes(&self) -> usize { self.inner.background_writes() }
+    fn remote_location(&self) -> Option<String> { self.inner.remote_location() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::fs;
+    use std::io::Read;
+    use std::usize;
+    use test::utils::*;
+
+    #[test]
+    fn test_audit_log() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap());
+        let path = f.tempdir.path().join("audit.log");
+        let log = Rc::new(AuditLog::open(&path).unwrap());
+        let cache = AuditedStorage::new(disk, log, "alice".to_owned());
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"contents"[..]).unwrap();
+        cache.finish_put("abcdef", entry).wait().unwrap();
+        cache.get("abcdef").wait().unwrap();
+        cache.get("ghijkl").wait().unwrap();
+
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let lines = contents.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(3, lines.len());
+        let field = |i: usize, name: &str| lines[i].find(name).and_then(Value::as_str).map(|s| s.to_owned());
+        assert_eq!(Some("put".to_owned()), field(0, "operation"));
+        assert_eq!(Some("stored".to_owned()), field(0, "result"));
+        assert_eq!(Some("abcdef".to_owned()), field(0, "key"));
+        assert_eq!(Some("alice".to_owned()), field(0, "client_user"));
+        assert_eq!(None, lines[0].find("user"));
+        assert_eq!(Some("hit".to_owned()), field(1, "result"));
+        assert_eq!(lines[0].find("size"), lines[1].find("size"));
+        assert_eq!(Some("miss".to_owned()), field(2, "result"));
+        assert_eq!(None, lines[2].find("size"));
+    }
+}
@@ -0,0 +1,486 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    multipart_threshold,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+    retry_policy,
+};
+use cache::retry::{RetryPolicy, retry};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use futures::{Future, Stream};
+use futures::future;
+use futures_cpupool::CpuPool;
+use http::{HttpsClient, https_client};
+use hyper::{self, header, Method};
+use hyper::client::{Request, Response};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use time;
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// The storage service version we sign requests for.
+const API_VERSION: &'static str = "2016-05-31";
+
+/// The size of blocks when uploading large entries in blocks.
+const BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// How many times to retry the upload of a single block.
+const BLOCK_RETRIES: usize = 2;
+
+/// How requests to the blob service are authorized.
+#[derive(Clone)]
+enum Auth {
+    /// Shared Key authorization with the base64-decoded account key.
+    SharedKey(Vec<u8>),
+    /// A shared access signature, appended to every request URL.
+    Sas(String),
+    /// No authorization, for reading from containers with public access.
+    Anonymous,
+}
+
+/// Account details parsed from an Azure Storage connection string.
+#[derive(Clone)]
+pub struct AzureCredentials {
+    account_name: String,
+    blob_endpoint: String,
+    auth: Auth,
+}
+
+impl AzureCredentials {
+    /// Parse an Azure Storage connection string, as shown in the Azure portal.
+    ///
+    /// If `sas_token` is given it takes precedence over any key in the
+    /// string. Without either an `AccountKey` or a `SharedAccessSignature`,
+    /// the container is accessed anonymously, which only allows reads.
+    pub fn parse(connection_string: &str, sas_token: Option<&str>) -> Result<AzureCredentials> {
+        let values = connection_string.split(';')
+            .filter(|kv| !kv.is_empty())
+            .filter_map(|kv| {
+                let mut parts = kv.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) => Some((k.trim(), v.trim())),
+                    _ => None,
+                }
+            })
+            .collect::<HashMap<_, _>>();
+        let account_name = match values.get("AccountName") {
+            Some(name) => name.to_string(),
+            None => bail!("Azure connection string is missing AccountName"),
+        };
+        let blob_endpoint = match values.get("BlobEndpoint") {
+            Some(endpoint) => endpoint.trim_right_matches('/').to_owned(),
+            None => {
+                let protocol = values.get("DefaultEndpointsProtocol").cloned().unwrap_or("https");
+                let suffix = values.get("EndpointSuffix").cloned().unwrap_or("core.windows.net");
+                format!("{}://{}.blob.{}", protocol, account_name, suffix)
+            }
+        };
+        let auth = match (sas_token.or(values.get("SharedAccessSignature").cloned()), values.get("AccountKey")) {
+            (Some(sas), _) => Auth::Sas(sas.trim_left_matches('?').to_owned()),
+            (None, Some(key)) => Auth::SharedKey(key.from_base64().chain_err(|| {
+                "Azure AccountKey is not valid base64"
+            })?),
+            (None, None) => Auth::Anonymous,
+        };
+        Ok(AzureCredentials {
+            account_name: account_name,
+            blob_endpoint: blob_endpoint,
+            auth: auth,
+        })
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
+
+/// Compute the Shared Key signature for a request.
+///
+/// https://docs.microsoft.com/en-us/rest/api/storageservices/authentication-for-the-azure-storage-services
+fn signature(verb: &str, content_length: usize, content_type: &str, ms_headers: &[(&str, &str)],
+             resource: &str, key: &[u8]) -> String {
+    let content_length = if content_length == 0 { String::new() } else { content_length.to_string() };
+    let mut canonical_headers = String::new();
+    // `ms_headers` must be sorted by header name.
+    for &(name, value) in ms_headers {
+        canonical_headers.push_str(&format!("{}:{}\n", name, value));
+    }
+    let string_to_sign = format!("{verb}\n\n\n{length}\n\n{ty}\n\n\n\n\n\n\n{headers}{resource}",
+                                 verb = verb,
+                                 length = content_length,
+                                 ty = content_type,
+                                 headers = canonical_headers,
+                                 resource = resource);
+    hmac_sha256(key, string_to_sign.as_bytes()).to_base64(STANDARD)
+}
+
+/// An Azure blob container.
+struct BlobContainer {
+    name: String,
+    credentials: AzureCredentials,
+    client: HttpsClient,
+}
+
+impl fmt::Display for BlobContainer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlobContainer(name={}, endpoint={})", self.name, self.credentials.blob_endpoint)
+    }
+}
+
+impl BlobContainer {
+    fn new(name: &str, credentials: AzureCredentials, handle: &Handle) -> BlobContainer {
+        BlobContainer {
+            name: name.to_owned(),
+            credentials: credentials,
+            client: https_client(handle),
+        }
+    }
+
+    /// The URL of `key`, with the `query` parameters, which must not need escaping.
+    fn url(&self, key: &str, query: &[(&str, &str)]) -> String {
+        let url = format!("{}/{}/{}", self.credentials.blob_endpoint, self.name, key);
+        let mut params = query.iter().map(|&(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>();
+        if let Auth::Sas(ref sas) = self.credentials.auth {
+            params.insert(0, sas.clone());
+        }
+        if params.is_empty() {
+            url
+        } else {
+            format!("{}?{}", url, params.join("&"))
+        }
+    }
+
+    /// Build a request for `key`, adding the `x-ms-*` headers and authorization.
+    ///
+    /// `query` must be sorted by parameter name.
+    fn request(&self, method: Method, key: &str, query: &[(&str, &str)],
+               extra_headers: &[(&'static str, &'static str)],
+               content_length: usize, content_type: &str) -> Request {
+        let url = self.url(key, query);
+        debug!("{} {}", method, self.credentials.blob_endpoint);
+        let mut request = Request::new(method.clone(), url.parse().unwrap());
+        let date = time::now_utc().rfc822().to_string();
+        let mut ms_headers = vec![("x-ms-date", date.as_str()), ("x-ms-version", API_VERSION)];
+        ms_headers.extend_from_slice(extra_headers);
+        ms_headers.sort();
+        for &(name, value) in ms_headers.iter() {
+            request.headers_mut().set_raw(name, vec!(value.as_bytes().to_vec()));
+        }
+        if let Auth::SharedKey(ref key_bytes) = self.credentials.auth {
+            let mut resource = format!("/{}/{}/{}", self.credentials.account_name, self.name, key);
+            for &(name, value) in query {
+                resource.push_str(&format!("\n{}:{}", name, value));
+            }
+            let sig = signature(&method.to_string(), content_length, content_type, &ms_headers,
+                                &resource, key_bytes);
+            let auth = format!("SharedKey {}:{}", self.credentials.account_name, sig);
+            request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
+        }
+        request
+    }
+
+    fn get(&self, key: &str) -> SFuture<Vec<u8>> {
+        let request = self.request(Method::Get, key, &[], &[], 0, "");
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed GET from Azure"
+        }).and_then(|res| {
+            if res.status().class() == hyper::status::StatusClass::Success {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }))
+    }
+
+    fn put(&self, key: &str, content: Vec<u8>) -> SFuture<()> {
+        let content_type = "application/octet-stream";
+        let mut request = self.request(Method::Put, key, &[], &[("x-ms-blob-type", "BlockBlob")],
+                                       content.len(), content_type);
+        request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+        Box::new(self.client.request(request).then(check_put))
+    }
+
+    /// Upload `content` as the uncommitted block `block_id` of the blob at `key`.
+    fn put_block(&self, key: &str, block_id: &str, content: Vec<u8>) -> SFuture<()> {
+        let mut request = self.request(Method::Put, key, &[("blockid", block_id), ("comp", "block")],
+                                       &[], content.len(), "");
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+        Box::new(self.client.request(request).then(check_put))
+    }
+
+    /// Commit the blob at `key` from the blocks `block_ids`, in order.
+    fn put_block_list(&self, key: &str, block_ids: &[String]) -> SFuture<()> {
+        let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>");
+        for id in block_ids {
+            body.push_str(&format!("<Latest>{}</Latest>", id));
+        }
+        body.push_str("</BlockList>");
+        let mut request = self.request(Method::Put, key, &[("comp", "blocklist")], &[],
+                                       body.len(), "");
+        request.headers_mut().set(header::ContentLength(body.len() as u64));
+        request.set_body(body.into_bytes());
+        Box::new(self.client.request(request).then(check_put))
+    }
+}
+
+fn check_put(result: ::std::result::Result<Response, hyper::Error>) -> Result<()> {
+    match result {
+        Ok(res) => {
+            if res.status().class() == hyper::status::StatusClass::Success {
+                trace!("PUT succeeded");
+                Ok(())
+            } else {
+                trace!("PUT failed with HTTP status: {}", res.status());
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }
+        Err(e) => {
+            trace!("PUT failed with error: {:?}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// The id of block `n` of a blob. Ids must be base64 and all the same length;
+/// base64-encoded digits never need URL escaping.
+fn block_id(n: usize) -> String {
+    format!("{:06}", n).as_bytes().to_base64(STANDARD)
+}
+
+/// Upload block `block_id`, retrying up to `retries` more times on failure.
+fn put_block_with_retry(container: Rc<BlobContainer>, key: String, block_id: String,
+                        content: Vec<u8>, retries: usize) -> SFuture<()> {
+    let block = container.put_block(&key, &block_id, content.clone());
+    Box::new(block.or_else(move |e| {
+        if retries == 0 {
+            return Box::new(future::err(e)) as SFuture<_>;
+        }
+        warn!("Retrying upload of block {}: {}", block_id, e);
+        put_block_with_retry(container, key, block_id, content, retries - 1)
+    }))
+}
+
+/// Upload `content` to `key` in `container` as blocks of `block_size` bytes,
+/// uploaded in parallel.
+fn put_blocks(container: Rc<BlobContainer>, key: String, content: Vec<u8>,
+              block_size: usize) -> SFuture<()> {
+    let block_ids = (0..(content.len() + block_size - 1) / block_size).map(block_id).collect::<Vec<_>>();
+    debug!("Uploading {} in {} blocks", key, block_ids.len());
+    let blocks = content.chunks(block_size).zip(block_ids.iter()).map(|(chunk, id)| {
+        put_block_with_retry(container.clone(), key.clone(), id.clone(), chunk.to_vec(), BLOCK_RETRIES)
+    }).collect::<Vec<_>>();
+    Box::new(future::join_all(blocks).and_then(move |_| {
+        container.put_block_list(&key, &block_ids)
+    }))
+}
+
+/// A cache that stores entries in Azure Blob Storage.
+pub struct AzureBlobCache {
+    container: Rc<BlobContainer>,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+    /// Entries larger than this are uploaded in parallel blocks.
+    multipart_threshold: Option<usize>,
+    /// How failed requests are retried.
+    retry: RetryPolicy,
+    handle: Handle,
+}
+
+impl AzureBlobCache {
+    /// Create a new `AzureBlobCache` storing data in `container`.
+    pub fn new(container: &str, credentials: AzureCredentials, handle: &Handle) -> Result<AzureBlobCache> {
+        Ok(AzureBlobCache {
+            container: Rc::new(BlobContainer::new(container, credentials, handle)),
+            key_prefix: String::new(),
+            multipart_threshold: None,
+            retry: RetryPolicy::default(),
+            handle: handle.clone(),
+        })
+    }
+
+    /// Upload entries larger than `threshold` bytes in parallel blocks.
+    pub fn set_multipart_threshold(&mut self, threshold: usize) {
+        self.multipart_threshold = Some(threshold);
+    }
+
+    /// Retry failed requests according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Store entries under `prefix`, to share the container with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+
+    /// Whether the container is accessed without credentials.
+    fn is_anonymous(&self) -> bool {
+        match self.container.credentials.auth {
+            Auth::Anonymous => true,
+            _ => false,
+        }
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+}
+
+impl Storage for AzureBlobCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let container = self.container.clone();
+        let response = retry(&self.retry, &self.handle, move || container.get(&key));
+        Box::new(response.then(|result| {
+            match result {
+                Ok(data) => Ok(remote_hit(data)),
+                Err(Error(ErrorKind::BadHTTPStatus(status), _)) => {
+                    warn!("Got Azure error: HTTP status {}", status);
+                    Ok(Cache::Miss)
+                }
+                // Anything else means the service couldn't be reached.
+                Err(e) => Err(e),
+            }
+        }))
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        // Just hand back an in-memory buffer.
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.is_anonymous() {
+            return future::err(ErrorKind::ReadOnlyCache.into()).boxed();
+        }
+        let key = prefix_key(&self.key_prefix, normalize_key(&key));
+        let start = Instant::now();
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+        let response = match self.multipart_threshold {
+            Some(threshold) if data.len() > threshold => {
+                // Blocks are retried individually.
+                put_blocks(self.container.clone(), key, data, BLOCK_SIZE)
+            }
+            _ => {
+                let container = self.container.clone();
+                retry(&self.retry, &self.handle, move || container.put(&key, data.clone()))
+            }
+        }.chain_err(|| {
+            "failed to put cache entry in Azure"
+        });
+        Box::new(response.map(move |_| start.elapsed()))
+    }
+
+    fn is_read_only(&self) -> bool { self.is_anonymous() }
+
+    fn location(&self) -> String {
+        format!("Azure, container: {}{}", self.container, prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create an `AzureBlobCache` from the environment, if
+/// `SCCACHE_AZURE_BLOB_CONTAINER` is set.
+pub fn from_environment(_pool: &CpuPool, handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let container = match env::var("SCCACHE_AZURE_BLOB_CONTAINER") {
+        Ok(container) => container,
+        Err(_) => return None,
+    };
+    let connection_string = env::var("SCCACHE_AZURE_CONNECTION_STRING").unwrap_or(String::new());
+    let sas_token = env::var("SCCACHE_AZURE_SAS_TOKEN").ok();
+    debug!("Trying AzureBlobCache({})", container);
+    Some(AzureCredentials::parse(&connection_string, sas_token.as_ref().map(|s| s.as_str()))
+         .and_then(|creds| AzureBlobCache::new(&container, creds, handle))
+         .map(|mut s| {
+             if let Ok(prefix) = env::var("SCCACHE_AZURE_KEY_PREFIX") {
+                 s.set_key_prefix(&prefix);
+             }
+             s.set_retry_policy(retry_policy("AZURE"));
+             s.set_multipart_threshold(multipart_threshold());
+             Arc::new(s) as Arc<Storage>
+         }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_connection_string_key() {
+        let creds = AzureCredentials::parse("DefaultEndpointsProtocol=https;AccountName=foo;AccountKey=YWJjZA==;EndpointSuffix=core.windows.net", None).unwrap();
+        assert_eq!("foo", creds.account_name);
+        assert_eq!("https://foo.blob.core.windows.net", creds.blob_endpoint);
+        match creds.auth {
+            Auth::SharedKey(ref key) => assert_eq!(b"abcd", &key[..]),
+            _ => panic!("Expected a shared key"),
+        }
+    }
+
+    #[test]
+    fn test_parse_connection_string_sas() {
+        let creds = AzureCredentials::parse("BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1/;AccountName=devstoreaccount1", Some("?sv=2016&sig=abc")).unwrap();
+        assert_eq!("http://127.0.0.1:10000/devstoreaccount1", creds.blob_endpoint);
+        match creds.auth {
+            Auth::Sas(ref sas) => assert_eq!("sv=2016&sig=abc", sas),
+            _ => panic!("Expected a SAS token"),
+        }
+    }
+
+    #[test]
+    fn test_block_id() {
+        assert_eq!("MDAwMDAw", block_id(0));
+        assert_eq!("MDAwMDEy", block_id(12));
+    }
+
+    #[test]
+    fn test_parse_connection_string_no_auth() {
+        match AzureCredentials::parse("AccountName=foo", None).unwrap().auth {
+            Auth::Anonymous => {}
+            _ => panic!("Expected anonymous access"),
+        }
+        assert!(AzureCredentials::parse("AccountKey=YWJjZA==", None).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    GcStats,
+    Storage,
+};
+use cache::metered::BackendStats;
+use futures::future::{self, Future};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+/// A wrapper around remote storage that stops using it for a while after it
+/// fails to respond, so that builds fall back to plain compilation instead of
+/// waiting on an unreachable server for every lookup.
+///
+/// Storage wrapped in a `CircuitBreaker` should return errors from `get` only
+/// when it couldn't be reached, and `Cache::Miss` for anything else.
+pub struct CircuitBreaker {
+    inner: Arc<Storage>,
+    /// How long to skip `inner` after it fails.
+    cooldown: Duration,
+    /// When `inner` last failed, while the breaker is tripped.
+    tripped_at: Rc<Cell<Option<Instant>>>,
+}
+
+impl CircuitBreaker {
+    /// Create a new `CircuitBreaker` around `inner`, skipping it for
+    /// `cooldown` after each failure.
+    pub fn new(inner: Arc<Storage>, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            inner: inner,
+            cooldown: cooldown,
+            tripped_at: Rc::new(Cell::new(None)),
+        }
+    }
+
+    /// Whether `inner` is currently being skipped.
+    fn is_tripped(&self) -> bool {
+        match self.tripped_at.get() {
+            Some(at) if at.elapsed() < self.cooldown => true,
+            _ => false,
+        }
+    }
+}
+
+impl Storage for CircuitBreaker {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        if self.is_tripped() {
+            return future::ok(Cache::Unavailable).boxed();
+        }
+        let tripped_at = self.tripped_at.clone();
+        let cooldown = self.cooldown;
+        Box::new(self.inner.get(key).then(move |result| {
+            match result {
+                Ok(cache) => {
+                    tripped_at.set(None);
+                    Ok(cache)
+                }
+                Err(e) => {
+                    warn!("Cache storage is unavailable, not using it for {}s: {}",
+                          cooldown.as_secs(), e);
+                    tripped_at.set(Some(Instant::now()));
+                    Ok(Cache::Unavailable)
+                }
+            }
+        }))
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        self.inner.start_put(key)
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.is_tripped() {
+            return future::err(ErrorKind::CacheUnavailable.into()).boxed();
+        }
+        self.inner.finish_put(key, entry)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool { self.inner.is_read_only() }
+
+    fn location(&self) -> String {
+        if self.is_tripped() {
+            format!("{} (unavailable)", self.inner.location())
+        } else {
+            self.inner.location()
+        }
+    }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> { self.inner.gc() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> { self.inner.clear(remote) }
+
+    fn keys(&self) -> SFuture<Vec<String>> { self.inner.keys() }
+
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> { self.inner.backend_stats() }
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> { self.inner.backends() }
+
+    fn current_size(&self) -> Option<usize> { self.inner.current_size() }
+    fn max_size(&self) -> Option<usize> { self.inner.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.inner.entry_count() }
+    fn background_writes(&self) -> usize { self.inner.background_writes() }
+    fn remote_location(&self) -> Option<String> {
+        let tripped = self.is_tripped();
+        self.inner.remote_location().map(|remote| {
+            if tripped { format!("{} (unavailable)", remote) } else { remote }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Storage that fails lookups until told otherwise.
+    struct FlakyStorage {
+        fail: Rc<Cell<bool>>,
+        gets: Rc<Cell<usize>>,
+    }
+
+    impl Storage for FlakyStorage {
+        fn get(&self, _key: &str) -> SFuture<Cache> {
+            self.gets.set(self.gets.get() + 1);
+            if self.fail.get() {
+                future::err("connection refused".into()).boxed()
+            } else {
+                future::ok(Cache::Miss).boxed()
+            }
+        }
+        fn start_put(&self, _key: &str) -> Result<CacheWrite> { Ok(CacheWrite::new()) }
+        fn finish_put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
+            future::ok(Duration::new(0, 0)).boxed()
+        }
+        fn location(&self) -> String { "flaky".to_owned() }
+        fn current_size(&self) -> Option<usize> { None }
+        fn max_size(&self) -> Option<usize> { None }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let fail = Rc::new(Cell::new(true));
+        let gets = Rc::new(Cell::new(0));
+        let inner = Arc::new(FlakyStorage { fail: fail.clone(), gets: gets.clone() });
+        let breaker = CircuitBreaker::new(inner, Duration::from_secs(3600));
+        match breaker.get("abcdef").wait().unwrap() {
+            Cache::Unavailable => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        // The storage isn't tried again while the breaker is tripped.
+        fail.set(false);
+        match breaker.get("abcdef").wait().unwrap() {
+            Cache::Unavailable => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        assert_eq!(1, gets.get());
+        assert!(breaker.finish_put("abcdef", CacheWrite::new()).wait().is_err());
+        // After the cooldown, it is used again.
+        breaker.tripped_at.set(Some(Instant::now() - Duration::from_secs(3601)));
+        match breaker.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        assert_eq!(2, gets.get());
+        assert!(breaker.finish_put("abcdef", CacheWrite::new()).wait().is_ok());
+    }
+}
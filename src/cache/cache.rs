@@ -17,21 +17,51 @@ use app_dirs::{
     AppInfo,
     app_dir,
 };
-use cache::disk::DiskCache;
-use cache::s3::S3Cache;
+#[cfg(feature = "azure")]
+use cache::azure;
+use cache::breaker::CircuitBreaker;
+#[cfg(feature = "cos")]
+use cache::cos;
+use cache::disk::{DiskCache, DiskIndex, LinkMode, link_file, open_index};
+use cache::encrypt::EncryptionKey;
+#[cfg(feature = "gcs")]
+use cache::gcs;
+#[cfg(feature = "gha")]
+use cache::gha;
+#[cfg(feature = "memcached")]
+use cache::memcached;
+#[cfg(feature = "oss")]
+use cache::oss;
+use cache::metered::{BackendStats, MeteredStorage};
+use cache::readonly::ReadOnlyStorage;
+use cache::retry::RetryPolicy;
+use cache::sizelimit::SizeLimitedStorage;
+#[cfg(feature = "s3")]
+use cache::s3;
+#[cfg(feature = "sftp")]
+use cache::sftp;
+use cache::tiered::{TwoTierCache, WritePolicy};
+#[cfg(feature = "webdav")]
+use cache::webdav;
 use compiler::Compiler;
+use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
 use regex::Regex;
 use sha1;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
 use std::fmt;
+use std::fs::File;
 use std::io::{
     self,
     Read,
     Seek,
+    SeekFrom,
     Write,
 };
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -41,17 +71,71 @@ use zip::{
     ZipArchive,
     ZipWriter,
 };
+use zstd;
 
 use errors::*;
 
 //TODO: might need to put this somewhere more central
-const APP_INFO: AppInfo = AppInfo {
+pub const APP_INFO: AppInfo = AppInfo {
     name: "sccache",
     author: "Mozilla",
 };
 
 const TEN_GIGS: usize = 10 * 1024 * 1024 * 1024;
 
+const DEFAULT_MULTIPART_THRESHOLD: usize = 64 * 1024 * 1024;
+
+/// How long to stop using remote storage after it couldn't be reached, in seconds.
+const DEFAULT_OFFLINE_RETRY: u64 = 60;
+
+/// Name of the zip member marking entries whose objects are zstd-compressed.
+///
+/// Entries without it store objects deflated by zip itself.
+const ZSTD_MARKER: &'static str = "sccache-zstd";
+
+/// Name of the zip member holding the digest of the other objects in an entry.
+const DIGEST: &'static str = "sccache-sha1";
+
+/// Name of the zip member holding how long the compiler took to produce an
+/// entry, in milliseconds.
+const COMPILE_TIME: &'static str = "sccache-compile-ms";
+
+/// Name of the zip member holding the `CACHE_VERSION` an entry was written
+/// with.
+const FORMAT: &'static str = "sccache-format";
+
+/// Whether `name` is a zip member sccache keeps about an entry, rather than
+/// one of its objects.
+fn is_metadata(name: &str) -> bool {
+    name == ZSTD_MARKER || name == DIGEST || name == COMPILE_TIME || name == FORMAT
+}
+
+/// Add the object `name` with stored contents `data` to the entry digest `m`.
+fn update_digest(m: &mut sha1::Sha1, name: &str, data: &[u8]) {
+    m.update(name.as_bytes());
+    m.update(&[0]);
+    m.update(data);
+}
+
+/// The zstd compression level used when `SCCACHE_COMPRESSION_LEVEL` is unset.
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Get the zstd compression level for new cache entries from the environment.
+///
+/// A level of 0 disables zstd compression.
+fn compression_level() -> i32 {
+    match env::var("SCCACHE_COMPRESSION_LEVEL") {
+        Ok(val) => match i32::from_str(&val) {
+            Ok(level) if level >= 0 && level <= 22 => level,
+            _ => {
+                warn!("Invalid SCCACHE_COMPRESSION_LEVEL, using {}", DEFAULT_COMPRESSION_LEVEL);
+                DEFAULT_COMPRESSION_LEVEL
+            }
+        },
+        Err(_) => DEFAULT_COMPRESSION_LEVEL,
+    }
+}
+
 /// Result of a cache lookup.
 pub enum Cache {
     /// Result was found in cache.
@@ -60,6 +144,8 @@ pub enum Cache {
     Miss,
     /// Cache entry should be ignored, force compilation.
     Recache,
+    /// The cache couldn't be reached, so the lookup was skipped.
+    Unavailable,
 }
 
 impl fmt::Debug for Cache {
@@ -68,6 +154,7 @@ impl fmt::Debug for Cache {
             Cache::Hit(_) => write!(f, "Cache::Hit(...)"),
             Cache::Miss => write!(f, "Cache::Miss"),
             Cache::Recache => write!(f, "Cache::Recache"),
+            Cache::Unavailable => write!(f, "Cache::Unavailable"),
         }
     }
 }
@@ -80,59 +167,294 @@ impl<T: Read + Seek + Send> ReadSeek for T {}
 /// Data stored in the compiler cache.
 pub struct CacheRead {
     zip: ZipArchive<Box<ReadSeek>>,
+    /// Objects stored as separate files rather than in `zip`, which can be
+    /// linked into place instead of copied.
+    external: HashMap<String, (PathBuf, LinkMode)>,
+    /// Whether objects in `zip` are zstd-compressed.
+    compressed: bool,
+    /// The size of the entry as stored, not counting external objects.
+    size: u64,
 }
 
 impl CacheRead {
     /// Create a cache entry from `reader`.
-    pub fn from<R: ReadSeek + 'static>(reader: R) -> Result<CacheRead> {
-        let z = ZipArchive::new(Box::new(reader) as Box<ReadSeek>).chain_err(|| {
+    pub fn from<R: ReadSeek + 'static>(mut reader: R) -> Result<CacheRead> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut z = ZipArchive::new(Box::new(reader) as Box<ReadSeek>).chain_err(|| {
             "Failed to parse cache entry"
         })?;
+        let compressed = z.by_name(ZSTD_MARKER).is_ok();
         Ok(CacheRead {
             zip: z,
+            external: HashMap::new(),
+            compressed: compressed,
+            size: size,
         })
     }
 
+    /// The size of this entry as it was stored, such as the number of bytes
+    /// downloaded for it.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the names of the objects in this cache entry.
+    pub fn object_names(&mut self) -> Result<Vec<String>> {
+        let mut names = vec!();
+        for i in 0..self.zip.len() {
+            let file = self.zip.by_index(i).chain_err(|| "Failed to read cache entry")?;
+            if !is_metadata(file.name()) {
+                names.push(file.name().to_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    /// The `CACHE_VERSION` this entry was written with, unless it was
+    /// written before entries recorded it.
+    pub fn format_version(&mut self) -> Option<String> {
+        let mut version = String::new();
+        match self.zip.by_name(FORMAT) {
+            Ok(mut file) => file.read_to_string(&mut version).ok().map(|_| version),
+            Err(_) => None,
+        }
+    }
+
+    /// Check that this cache entry was written in the current format, and
+    /// its objects against the digest stored with it, returning an error if
+    /// the entry can't be used.
+    ///
+    /// Entries in the current format always have a digest, so one without
+    /// it is treated as corrupt.
+    pub fn verify(&mut self) -> Result<()> {
+        let version = self.format_version();
+        if version.as_ref().map(|v| v.as_bytes()) != Some(CACHE_VERSION) {
+            bail!("Cache entry format {} isn't supported",
+                  version.unwrap_or_else(|| "unknown".to_owned()));
+        }
+        let mut expected = String::new();
+        match self.zip.by_name(DIGEST) {
+            Ok(mut file) => file.read_to_string(&mut expected)?,
+            Err(_) => bail!("Cache entry has no digest"),
+        };
+        let mut m = sha1::Sha1::new();
+        for i in 0..self.zip.len() {
+            let mut file = self.zip.by_index(i).chain_err(|| "Failed to read cache entry")?;
+            let name = file.name().to_owned();
+            if is_metadata(&name) {
+                continue;
+            }
+            // Reading the whole object also has zip check its CRC.
+            let mut data = vec!();
+            file.read_to_end(&mut data).chain_err(|| {
+                format!("Failed to read object `{}` from cache entry", name)
+            })?;
+            update_digest(&mut m, &name, &data);
+        }
+        if m.digest().to_string() != expected {
+            bail!("Cache entry digest mismatch");
+        }
+        Ok(())
+    }
+
+    /// How long the compiler took to produce this entry, unless it was
+    /// stored without that.
+    pub fn compile_time(&mut self) -> Option<Duration> {
+        let mut millis = String::new();
+        match self.zip.by_name(COMPILE_TIME) {
+            Ok(mut file) => {
+                if file.read_to_string(&mut millis).is_err() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+        millis.parse().ok().map(Duration::from_millis)
+    }
+
+    /// Record that the object `name` is stored in the file at `path`, which
+    /// can be linked into place with `mode`.
+    pub fn set_external_object(&mut self, name: &str, path: PathBuf, mode: LinkMode) {
+        self.external.insert(name.to_owned(), (path, mode));
+    }
+
     /// Get an object from this cache entry at `name` and write it to `to`.
     pub fn get_object<T: Write>(&mut self, name: &str, to: &mut T) -> Result<()> {
+        if let Some(&(ref path, _)) = self.external.get(name) {
+            let mut file = File::open(path).chain_err(|| {
+                "Failed to read object from cache entry"
+            })?;
+            io::copy(&mut file, to)?;
+            return Ok(());
+        }
         let mut file = self.zip.by_name(name).chain_err(|| {
             "Failed to read object from cache entry"
         })?;
-        io::copy(&mut file, to)?;
+        if self.compressed {
+            zstd::stream::copy_decode(&mut file, to).chain_err(|| {
+                "Failed to decompress object from cache entry"
+            })?;
+        } else {
+            io::copy(&mut file, to)?;
+        }
         Ok(())
     }
+
+    /// Copy the objects in this cache entry into a new `CacheWrite`, to store
+    /// the entry in another cache.
+    pub fn to_write(&mut self) -> Result<CacheWrite> {
+        let mut entry = CacheWrite::new();
+        for name in self.object_names()? {
+            let mut data = vec!();
+            self.get_object(&name, &mut data)?;
+            entry.put_object(&name, &mut &data[..])?;
+        }
+        if let Some(compile_time) = self.compile_time() {
+            entry.set_compile_time(compile_time);
+        }
+        Ok(entry)
+    }
+
+    /// Write the object at `name` to a new file at `path`, linking it from the
+    /// cache instead of copying if possible.
+    pub fn extract_object(&mut self, name: &str, path: &Path) -> Result<()> {
+        if let Some(&(ref src, mode)) = self.external.get(name) {
+            match link_file(src, path, mode) {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("Failed to {:?} {:?} to {:?}, copying: {}", mode, src, path, e),
+            }
+        }
+        let mut f = File::create(path)?;
+        self.get_object(name, &mut f)
+    }
 }
 
 /// Data to be stored in the compiler cache.
 pub struct CacheWrite {
-    zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    inner: CacheWriteInner,
+    /// zstd compression level for objects, or 0 to let zip deflate them.
+    level: i32,
+    /// Digest of the objects put so far.
+    digest: sha1::Sha1,
+    /// How long the compiler took to produce the entry, if known.
+    compile_time: Option<Duration>,
+}
+
+enum CacheWriteInner {
+    /// An entry that is still being written.
+    Zip(ZipWriter<io::Cursor<Vec<u8>>>),
+    /// An already-finished entry.
+    Finished(Vec<u8>),
 }
 
 impl CacheWrite {
     /// Create a new, empty cache entry.
     pub fn new() -> CacheWrite {
+        CacheWrite::with_compression_level(compression_level())
+    }
+
+    /// Create a new, empty cache entry compressing objects with zstd at
+    /// `level`, or with zip's own deflate if `level` is 0.
+    pub fn with_compression_level(level: i32) -> CacheWrite {
         CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec!())),
+            inner: CacheWriteInner::Zip(ZipWriter::new(io::Cursor::new(vec!()))),
+            level: level,
+            digest: sha1::Sha1::new(),
+            compile_time: None,
+        }
+    }
+
+    /// Create a cache entry from `data` previously returned by `finish`, for
+    /// storing the same entry in more than one place.
+    pub fn from_bytes(data: Vec<u8>) -> CacheWrite {
+        CacheWrite {
+            inner: CacheWriteInner::Finished(data),
+            level: 0,
+            digest: sha1::Sha1::new(),
+            compile_time: None,
         }
     }
 
     /// Add an object containing the contents of `from` to this cache entry at `name`.
     pub fn put_object<T: Read>(&mut self, name: &str, from: &mut T) -> Result<()> {
-        self.zip.start_file(name, CompressionMethod::Deflated).chain_err(|| {
+        let zip = match self.inner {
+            CacheWriteInner::Zip(ref mut zip) => zip,
+            CacheWriteInner::Finished(_) => bail!("Cache entry is already finished"),
+        };
+        let mut data = vec!();
+        let method = if self.level == 0 {
+            from.read_to_end(&mut data)?;
+            CompressionMethod::Deflated
+        } else {
+            // Objects are put from the thread pool, so compress them here rather
+            // than when the entry is finished.
+            zstd::stream::copy_encode(from, &mut data, self.level).chain_err(|| {
+                "Failed to compress cache entry object"
+            })?;
+            CompressionMethod::Stored
+        };
+        update_digest(&mut self.digest, name, &data);
+        zip.start_file(name, method).chain_err(|| {
             "Failed to start cache entry object"
         })?;
-        io::copy(from, &mut self.zip)?;
+        zip.write_all(&data)?;
         Ok(())
     }
 
+    /// Record that the compiler took `duration` to produce this entry, from
+    /// which hits estimate the time they save.
+    pub fn set_compile_time(&mut self, duration: Duration) {
+        self.compile_time = Some(duration);
+    }
+
     /// Finish writing data to the cache entry writer, and return the data.
     pub fn finish(self) -> Result<Vec<u8>> {
-        let CacheWrite { mut zip } = self;
-        let cur = zip.finish().chain_err(|| "Failed to finish cache entry zip")?;
-        Ok(cur.into_inner())
+        match self.inner {
+            CacheWriteInner::Zip(mut zip) => {
+                zip.start_file(DIGEST, CompressionMethod::Stored).chain_err(|| {
+                    "Failed to store cache entry digest"
+                })?;
+                zip.write_all(self.digest.digest().to_string().as_bytes())?;
+                zip.start_file(FORMAT, CompressionMethod::Stored).chain_err(|| {
+                    "Failed to store cache entry format"
+                })?;
+                zip.write_all(CACHE_VERSION)?;
+                if let Some(duration) = self.compile_time {
+                    zip.start_file(COMPILE_TIME, CompressionMethod::Stored).chain_err(|| {
+                        "Failed to store cache entry compile time"
+                    })?;
+                    let millis = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64;
+                    zip.write_all(millis.to_string().as_bytes())?;
+                }
+                if self.level != 0 {
+                    zip.start_file(ZSTD_MARKER, CompressionMethod::Stored).chain_err(|| {
+                        "Failed to mark cache entry as compressed"
+                    })?;
+                }
+                let cur = zip.finish().chain_err(|| "Failed to finish cache entry zip")?;
+                Ok(cur.into_inner())
+            }
+            CacheWriteInner::Finished(data) => Ok(data),
+        }
     }
 }
 
+/// What garbage collection of a local disk cache changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct GcStats {
+    /// The number of orphaned temporary files removed.
+    pub temp_files_removed: usize,
+    /// The number of entries dropped because their files were gone.
+    pub missing: usize,
+    /// The number of files found on disk that weren't in the index.
+    pub added: usize,
+    /// The number of files evicted to fit the size limit.
+    pub evicted: usize,
+    /// The number of files removed because they expired.
+    pub expired: usize,
+}
+
 /// An interface to cache storage.
 pub trait Storage {
     /// Get a cache entry by `key`.
@@ -153,6 +475,53 @@ pub trait Storage {
     /// finished.
     fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration>;
 
+    /// Remove the entry for `key`, such as when it turns out to be corrupt.
+    ///
+    /// Storage that can't remove entries ignores this.
+    fn remove(&self, _key: &str) -> SFuture<()> {
+        future::ok(()).boxed()
+    }
+
+    /// Whether this storage only serves reads. Writes to read-only storage
+    /// fail with `ErrorKind::ReadOnlyCache`.
+    fn is_read_only(&self) -> bool { false }
+
+    /// Rebuild the index of a local disk cache from the files on disk and
+    /// enforce its limits, returning what changed.
+    ///
+    /// Storage without a local disk cache returns `None`.
+    fn gc(&self) -> SFuture<Option<GcStats>> {
+        future::ok(None).boxed()
+    }
+
+    /// Remove every entry, returning the number of files removed.
+    ///
+    /// Local disk caches are always emptied. Remote storage is only emptied
+    /// if `remote` is set, in which case storage that can't be emptied
+    /// returns an error.
+    fn clear(&self, remote: bool) -> SFuture<usize> {
+        if remote {
+            future::err(format!("Clearing {} isn't supported", self.location()).into()).boxed()
+        } else {
+            future::ok(0).boxed()
+        }
+    }
+
+    /// List the keys of the entries in this storage.
+    ///
+    /// Storage that can't be listed returns an error.
+    fn keys(&self) -> SFuture<Vec<String>> {
+        future::err(format!("Listing the entries of {} isn't supported, pass --keys with the keys to copy",
+                            self.location()).into()).boxed()
+    }
+
+    /// Get the statistics of the storage backends making up this storage.
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> { vec!() }
+
+    /// Get the storage backends making up this storage, by name, limited as
+    /// this storage limits them, so that each can be checked on its own.
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> { vec!() }
+
     /// Get the storage location.
     fn location(&self) -> String;
 
@@ -161,9 +530,21 @@ pub trait Storage {
 
     /// Get the maximum storage size, if applicable.
     fn max_size(&self) -> Option<usize>;
+
+    /// Get the number of entries in the storage, if applicable.
+    fn entry_count(&self) -> Option<usize> { None }
+
+    /// Get the number of writes still running in the background after their
+    /// `finish_put` completed, such as write-back uploads.
+    fn background_writes(&self) -> usize { 0 }
+
+    /// Describe the remote storage making up this storage, if any.
+    ///
+    /// Storage is taken to be remote unless it says otherwise.
+    fn remote_location(&self) -> Option<String> { Some(self.location()) }
 }
 
-fn parse_size(val: &str) -> Option<usize> {
+pub fn parse_size(val: &str) -> Option<usize> {
     let re = Regex::new(r"^(\d+)([KMGT])$").unwrap();
     re.captures(val)
         .and_then(|caps| caps.at(1).and_then(|size| usize::from_str(size).ok()).and_then(|size| Some((size, caps.at(2)))))
@@ -178,70 +559,447 @@ fn parse_size(val: &str) -> Option<usize> {
         })
 }
 
-/// Get a suitable `Storage` implementation from the environment.
-pub fn storage_from_environment(pool: &CpuPool, handle: &Handle) -> Arc<Storage> {
-    if let Ok(bucket) = env::var("SCCACHE_BUCKET") {
-        let endpoint = match env::var("SCCACHE_ENDPOINT") {
-            Ok(endpoint) => format!("{}/{}", endpoint, bucket),
-            _ => match env::var("SCCACHE_REGION") {
-                Ok(ref region) if region != "us-east-1" =>
-                    format!("{}.s3-{}.amazonaws.com", bucket, region),
-                _ => format!("{}.s3.amazonaws.com", bucket),
-            },
+/// Parse a duration such as `30d`, `12h`, `45m` or `90s`. A bare number is in seconds.
+pub fn parse_duration(val: &str) -> Option<Duration> {
+    let re = Regex::new(r"^(\d+)([smhd]?)$").unwrap();
+    re.captures(val).and_then(|caps| {
+        let n = match caps.at(1).and_then(|n| u64::from_str(n).ok()) {
+            Some(n) => n,
+            None => return None,
+        };
+        let secs = match caps.at(2) {
+            Some("") | Some("s") | None => n,
+            Some("m") => n * 60,
+            Some("h") => n * 60 * 60,
+            Some("d") => n * 60 * 60 * 24,
+            _ => return None,
+        };
+        Some(Duration::from_secs(secs))
+    })
+}
+
+/// Parse an entry downloaded from remote storage. Entries that can't be
+/// parsed are treated as misses, since the storage itself was reachable.
+pub fn remote_hit(data: Vec<u8>) -> Cache {
+    match CacheRead::from(io::Cursor::new(data)) {
+        Ok(hit) => Cache::Hit(hit),
+        Err(e) => {
+            warn!("Failed to parse cache entry, treating it as a miss: {}", e);
+            Cache::Miss
+        }
+    }
+}
+
+/// Prepend `prefix` to the object key `key`, unless `prefix` is empty.
+pub fn prefix_key(prefix: &str, key: String) -> String {
+    if prefix.is_empty() {
+        key
+    } else {
+        format!("{}/{}", prefix, key)
+    }
+}
+
+/// Describe `prefix` for a storage location, or nothing if it is empty.
+pub fn prefix_location(prefix: &str) -> String {
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!(", prefix: {}", prefix)
+    }
+}
+
+/// Get the size above which entries are uploaded in parallel parts from the environment.
+pub fn multipart_threshold() -> usize {
+    env::var("SCCACHE_MULTIPART_THRESHOLD")
+        .ok()
+        .and_then(|v| parse_size(&v))
+        .unwrap_or(DEFAULT_MULTIPART_THRESHOLD)
+}
+
+/// Get the retry policy for `backend` from the environment.
+pub fn retry_policy(backend: &str) -> RetryPolicy {
+    let var = |name: &str| env::var(format!("SCCACHE_{}_{}", backend, name)).ok();
+    let mut policy = RetryPolicy::default();
+    if let Some(retries) = var("RETRIES").and_then(|v| u32::from_str(&v).ok()) {
+        policy.retries = retries;
+    }
+    if let Some(backoff) = var("RETRY_BACKOFF_MS").and_then(|v| u64::from_str(&v).ok()) {
+        policy.backoff = Duration::from_millis(backoff);
+    }
+    policy.timeout = var("TIMEOUT").and_then(|v| parse_duration(&v));
+    policy
+}
+
+/// Get how long to stop using unreachable remote storage from the environment.
+fn offline_retry() -> Duration {
+    env::var("SCCACHE_OFFLINE_RETRY")
+        .ok()
+        .and_then(|v| parse_duration(&v))
+        .unwrap_or(Duration::from_secs(DEFAULT_OFFLINE_RETRY))
+}
+
+/// Look up `key` in `storage`, verifying hits against their digest on `pool`.
+///
+/// Corrupt entries, and entries in another format, are removed from
+/// `storage` and treated as misses.
+pub fn get_verified(storage: Arc<Storage>, key: &str, pool: &CpuPool) -> SFuture<Cache> {
+    let pool = pool.clone();
+    let key = key.to_owned();
+    Box::new(storage.get(&key).and_then(move |result| -> SFuture<Cache> {
+        let mut entry = match result {
+            Cache::Hit(entry) => entry,
+            result => return future::ok(result).boxed(),
         };
-        debug!("Trying S3Cache({})", endpoint);
-        match S3Cache::new(&bucket, &endpoint, handle) {
-            Ok(s) => {
-                trace!("Using S3Cache");
-                return Arc::new(s);
+        Box::new(pool.spawn_fn(move || {
+            let result = entry.verify();
+            Ok::<_, Error>((entry, result))
+        }).and_then(move |(entry, result)| -> SFuture<Cache> {
+            match result {
+                Ok(()) => future::ok(Cache::Hit(entry)).boxed(),
+                Err(e) => {
+                    warn!("Removing unusable cache entry {}: {}", key, e);
+                    Box::new(storage.remove(&key).then(|_| Ok(Cache::Miss)))
+                }
             }
-            Err(e) => warn!("Failed to create S3Cache: {:?}", e),
+        }))
+    }))
+}
+
+/// Creates remote storage from the environment, returning `None` if it isn't
+/// configured there.
+pub type BackendFactory = fn(&CpuPool, &Handle) -> Option<Result<Arc<Storage>>>;
+
+/// The remote storage backends built into this binary, in the order they are
+/// tried, as (short name, name, factory).
+///
+/// Each backend is behind a cargo feature named like its short name, so that
+/// binaries can be built with only the disk cache.
+pub fn backends() -> Vec<(&'static str, &'static str, BackendFactory)> {
+    #[allow(unused_mut)]
+    let mut backends: Vec<(&'static str, &'static str, BackendFactory)> = vec!();
+    #[cfg(feature = "s3")]
+    backends.push(("s3", "S3Cache", s3::from_environment));
+    #[cfg(feature = "gcs")]
+    backends.push(("gcs", "GCSCache", gcs::from_environment));
+    #[cfg(feature = "azure")]
+    backends.push(("azure", "AzureBlobCache", azure::from_environment));
+    #[cfg(feature = "oss")]
+    backends.push(("oss", "OSSCache", oss::from_environment));
+    #[cfg(feature = "cos")]
+    backends.push(("cos", "COSCache", cos::from_environment));
+    #[cfg(feature = "memcached")]
+    backends.push(("memcached", "MemcachedCache", memcached::from_environment));
+    #[cfg(feature = "webdav")]
+    backends.push(("webdav", "WebdavCache", webdav::from_environment));
+    #[cfg(feature = "sftp")]
+    backends.push(("sftp", "SftpCache", sftp::from_environment));
+    #[cfg(feature = "gha")]
+    backends.push(("gha", "GHACache", gha::from_environment));
+    backends
+}
+
+/// Get a remote `Storage` implementation from the environment, if one is configured.
+fn remote_storage_from_environment(pool: &CpuPool, handle: &Handle) -> Option<Arc<Storage>> {
+    for (_, name, factory) in backends() {
+        match factory(pool, handle) {
+            Some(Ok(storage)) => {
+                trace!("Using {}", name);
+                return Some(Arc::new(MeteredStorage::new(name, storage)));
+            }
+            Some(Err(e)) => warn!("Failed to create {}: {:?}", name, e),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Get the storage named `name` from the environment: either `disk` for the
+/// local disk cache, or the short name of a remote storage backend, which must
+/// be configured.
+pub fn storage_by_name(name: &str, pool: &CpuPool, handle: &Handle) -> Result<Arc<Storage>> {
+    if name == "disk" {
+        return Ok(Arc::new(disk_cache_from_environment(pool)?));
+    }
+    let backends = backends();
+    match backends.iter().find(|&&(short, _, _)| short == name) {
+        Some(&(_, name, factory)) => match factory(pool, handle) {
+            Some(storage) => storage,
+            None => bail!("{} is not configured in the environment", name),
+        },
+        None => {
+            let names = backends.iter().map(|&(short, _, _)| short).collect::<Vec<_>>();
+            bail!("Unknown storage `{}`, expected disk or one of: {}", name, names.join(", "))
         }
     }
-    let d = env::var_os("SCCACHE_DIR")
+}
+
+/// Get the directory of the local disk cache from the environment.
+pub fn disk_cache_dir() -> PathBuf {
+    env::var_os("SCCACHE_DIR")
         .map(|p| PathBuf::from(p))
         .or_else(|| app_dir(AppDataType::UserCache, &APP_INFO, "").ok())
         // Fall back to something, even if it's not very good.
-        .unwrap_or(env::temp_dir().join("sccache_cache"));
-    trace!("Using DiskCache({:?})", d);
-    let cache_size = env::var("SCCACHE_CACHE_SIZE")
+        .unwrap_or(env::temp_dir().join("sccache_cache"))
+}
+
+/// Get the maximum size of the local disk cache from the environment.
+fn disk_cache_size() -> usize {
+    env::var("SCCACHE_CACHE_SIZE")
         .ok()
         .and_then(|v| parse_size(&v))
-        .unwrap_or(TEN_GIGS);
+        .unwrap_or(TEN_GIGS)
+}
+
+/// Get the index of the local disk cache configured in the environment,
+/// scanning its directory if it isn't in use yet. While the index is held,
+/// creating storage from the environment doesn't scan the directory again.
+pub fn disk_index_from_environment() -> Result<DiskIndex> {
+    Ok(open_index(&disk_cache_dir(), disk_cache_size())?)
+}
+
+/// Get a `DiskCache` configured from the environment.
+fn disk_cache_from_environment(pool: &CpuPool) -> Result<DiskCache> {
+    let d = disk_cache_dir();
+    trace!("Using DiskCache({:?})", d);
+    let cache_size = disk_cache_size();
     trace!("DiskCache size: {}", cache_size);
-    Arc::new(DiskCache::new(&d, cache_size, pool))
+    let mut cache = DiskCache::new(&d, cache_size, pool)?;
+    match env::var("SCCACHE_DISK_LINK").as_ref().map(|s| s.as_str()) {
+        Ok("hardlink") => cache.set_link_mode(LinkMode::Hardlink),
+        Ok("reflink") => cache.set_link_mode(LinkMode::Reflink),
+        Ok("copy") | Err(_) => {}
+        Ok(mode) => warn!("Invalid SCCACHE_DISK_LINK: {}", mode),
+    }
+    if let Ok(ttl) = env::var("SCCACHE_CACHE_TTL") {
+        match parse_duration(&ttl) {
+            Some(max_age) => {
+                trace!("DiskCache TTL: {:?}", max_age);
+                cache.set_max_age(max_age);
+            }
+            None => warn!("Invalid SCCACHE_CACHE_TTL: {}", ttl),
+        }
+    }
+    if env_flag("SCCACHE_DIR_SHARED") {
+        trace!("DiskCache is shared");
+        cache.set_shared();
+    }
+    // Rather than storing entries unencrypted, fail if the key is unusable.
+    let key = match (env::var("SCCACHE_DISK_KEY"), env::var_os("SCCACHE_DISK_KEY_FILE")) {
+        (Ok(hex), _) => Some(EncryptionKey::from_hex(&hex).chain_err(|| "Invalid SCCACHE_DISK_KEY")?),
+        (_, Some(path)) => Some(EncryptionKey::from_file(Path::new(&path)).chain_err(|| "Invalid SCCACHE_DISK_KEY_FILE")?),
+        _ => None,
+    };
+    if let Some(key) = key {
+        trace!("DiskCache is encrypted");
+        cache.set_encryption_key(key);
+    }
+    Ok(cache)
+}
+
+/// Whether the environment variable `var` is set to a true value.
+pub fn env_flag(var: &str) -> bool {
+    env::var(var).ok().map_or(false, |v| v == "1" || v == "on" || v == "true")
+}
+
+/// Get a suitable `Storage` implementation from the environment.
+pub fn storage_from_environment(pool: &CpuPool, handle: &Handle) -> Result<Arc<Storage>> {
+    let storage = match remote_storage_from_environment(pool, handle) {
+        Some(remote) => {
+            let remote: Arc<Storage> = Arc::new(CircuitBreaker::new(remote, offline_retry()));
+            let remote: Arc<Storage> = if env_flag("SCCACHE_REMOTE_READ_ONLY") {
+                trace!("Remote cache is read-only");
+                Arc::new(ReadOnlyStorage::new(remote))
+            } else {
+                remote
+            };
+            if env_flag("SCCACHE_LOCAL_CACHE") {
+                let local: Arc<Storage> = Arc::new(MeteredStorage::new("DiskCache", Arc::new(disk_cache_from_environment(pool)?)));
+                trace!("Using TwoTierCache");
+                let mut cache = TwoTierCache::new(local, remote, pool, handle);
+                if let Ok(policy) = env::var("SCCACHE_LOCAL_WRITE_POLICY") {
+                    match WritePolicy::parse(&policy) {
+                        Some(policy) => cache.set_write_policy(policy),
+                        None => warn!("Invalid SCCACHE_LOCAL_WRITE_POLICY: {}", policy),
+                    }
+                }
+                Arc::new(cache)
+            } else {
+                remote
+            }
+        }
+        None => Arc::new(MeteredStorage::new("DiskCache", Arc::new(disk_cache_from_environment(pool)?))) as Arc<Storage>,
+    };
+    let storage = match env::var("SCCACHE_MAX_ENTRY_SIZE").ok().and_then(|v| parse_size(&v)) {
+        Some(max_entry_size) => {
+            trace!("Not storing entries larger than {} bytes", max_entry_size);
+            Arc::new(SizeLimitedStorage::new(storage, max_entry_size)) as Arc<Storage>
+        }
+        None => storage,
+    };
+    if env_flag("SCCACHE_READ_ONLY") {
+        trace!("Cache is read-only");
+        Ok(Arc::new(ReadOnlyStorage::new(storage)))
+    } else {
+        Ok(storage)
+    }
+}
+
+/// Get the remote storage and the local cache in front of it from the
+/// environment, for copying entries from one to the other.
+pub fn tiers_from_environment(pool: &CpuPool, handle: &Handle) -> Result<(Arc<Storage>, Arc<Storage>)> {
+    if !env_flag("SCCACHE_LOCAL_CACHE") {
+        bail!("SCCACHE_LOCAL_CACHE must be set to use a local cache");
+    }
+    let remote = remote_storage_from_environment(pool, handle).ok_or("No remote cache is configured")?;
+    Ok((remote, Arc::new(disk_cache_from_environment(pool)?)))
 }
 
-/// The cache is versioned by the inputs to `hash_key`.
-pub const CACHE_VERSION : &'static [u8] = b"3";
+/// The version of the cache, which is part of every cache key and stored in
+/// every entry.
+///
+/// Change it whenever the inputs to `hash_key` or the layout of cache
+/// entries change incompatibly, so that entries of other versions are missed
+/// rather than misread.
+pub const CACHE_VERSION : &'static [u8] = b"5";
 
-/// Environment variables that are factored into the cache key.
+/// Environment variables that are factored into the cache key, because
+/// they change what compilers output.
 pub const CACHED_ENV_VARS : &'static [&'static str] = &[
     "MACOSX_DEPLOYMENT_TARGET",
     "IPHONEOS_DEPLOYMENT_TARGET",
+    // Include and library search paths.
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "OBJC_INCLUDE_PATH",
+    "GCC_EXEC_PREFIX",
+    "COMPILER_PATH",
+    // Reproducible builds embed it instead of the current time.
+    "SOURCE_DATE_EPOCH",
+    // The language of diagnostics, which are cached.
+    "LANG",
+    "LC_ALL",
+    "LC_MESSAGES",
+    // Extra options of MSVC.
+    "CL",
+    "_CL_",
 ];
 
+/// The names of the environment variables that are passed from the client
+/// to `compiler`, and are part of the cache key of its compilations unless
+/// it is sloppy about them.
+pub fn key_env_var_names(compiler: &Compiler) -> Vec<String> {
+    let mut names = CACHED_ENV_VARS.iter().map(|var| var.to_string()).collect::<Vec<_>>();
+    for var in &compiler.hashed_env_vars {
+        if !names.contains(var) {
+            names.push(var.clone());
+        }
+    }
+    names
+}
+
+/// The values of the environment variables that are part of the cache
+/// key of compilations with `compiler`, for those that are set.
+fn key_env_vars(compiler: &Compiler) -> Vec<(String, String)> {
+    key_env_var_names(compiler).into_iter()
+        .filter(|var| !compiler.sloppiness.ignores_env_var(var))
+        .filter_map(|var| compiler.env_var(&var).map(|val| (var, val)))
+        .collect()
+}
+
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
-#[allow(dead_code)]
-pub fn hash_key(compiler: &Compiler, arguments: &str, preprocessor_output: &[u8]) -> String {
-    // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
+pub fn hash_key(compiler: &Compiler, arguments: &str, mut preprocessor_output: &[u8]) -> String {
+    // Reading a slice can't fail.
+    hash_key_from(compiler, arguments, &mut preprocessor_output).unwrap()
+}
+
+/// Compute the hash key of `compiler` compiling the preprocessor output
+/// read from `preprocessor_output` with `args`, without reading it all
+/// into memory.
+pub fn hash_key_from<R: Read>(compiler: &Compiler, arguments: &str, preprocessor_output: &mut R)
+                              -> io::Result<String> {
+    // If you change any of the inputs to the hash, you must change `CACHE_VERSION`.
     let mut m = sha1::Sha1::new();
     m.update(compiler.digest.as_bytes());
     m.update(CACHE_VERSION);
     m.update(arguments.as_bytes());
-    //TODO: should propogate these over from the client.
-    // https://github.com/glandium/sccache/issues/5
-    for var in CACHED_ENV_VARS.iter() {
-        if let Ok(val) = env::var(var) {
-            m.update(var.as_bytes());
-            m.update(&b"="[..]);
-            m.update(val.as_bytes());
+    for (var, val) in key_env_vars(compiler) {
+        m.update(var.as_bytes());
+        m.update(&b"="[..]);
+        m.update(val.as_bytes());
+    }
+    sha1_update_from(&mut m, preprocessor_output)?;
+    Ok(m.digest().to_string())
+}
+
+/// Feed everything read from `reader` into `m`.
+pub fn sha1_update_from<R: Read>(m: &mut sha1::Sha1, reader: &mut R) -> io::Result<()> {
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => m.update(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The inputs to `hash_key`, kept apart to explain cache misses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyInputs {
+    /// The digest of the compiler executable.
+    pub compiler: String,
+    /// The arguments that are part of the key.
+    pub arguments: String,
+    /// The values of `CACHED_ENV_VARS` and the compiler's hashed
+    /// environment variables that are set.
+    pub env: Vec<(String, String)>,
+    /// The SHA-1 digest of the preprocessor output.
+    pub preprocessed: String,
+}
+
+impl KeyInputs {
+    /// The inputs to the key of `compiler` compiling preprocessor output
+    /// with digest `preprocessed` with `arguments`.
+    pub fn new(compiler: &Compiler, arguments: &str, preprocessed: String) -> KeyInputs {
+        KeyInputs {
+            compiler: compiler.digest.clone(),
+            arguments: arguments.to_owned(),
+            env: key_env_vars(compiler),
+            preprocessed: preprocessed,
+        }
+    }
+
+    /// Describe the inputs that differ from those in `previous`.
+    pub fn changes(&self, previous: &KeyInputs) -> Vec<String> {
+        let mut changes = vec!();
+        if self.compiler != previous.compiler {
+            changes.push(format!("compiler digest {} -> {}", previous.compiler, self.compiler));
+        }
+        if self.arguments != previous.arguments {
+            changes.push(format!("arguments `{}` -> `{}`", previous.arguments, self.arguments));
+        }
+        if self.env != previous.env {
+            changes.push(format!("environment `{}` -> `{}`", format_env(&previous.env), format_env(&self.env)));
+        }
+        if self.preprocessed != previous.preprocessed {
+            changes.push(format!("preprocessor output digest {} -> {}", previous.preprocessed, self.preprocessed));
         }
+        changes
     }
-    m.update(preprocessor_output);
-    m.digest().to_string()
 }
 
+impl fmt::Display for KeyInputs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compiler digest {}, arguments `{}`, environment `{}`, preprocessor output digest {}",
+               self.compiler, self.arguments, format_env(&self.env), self.preprocessed)
+    }
+}
+
+fn format_env(env: &[(String, String)]) -> String {
+    env.iter().map(|&(ref var, ref val)| format!("{}={}", var, val)).collect::<Vec<_>>().join(" ")
+}
 
 #[test]
 fn test_parse_size() {
@@ -253,6 +1011,37 @@ fn test_parse_size() {
     assert_eq!(Some(1024 * TEN_GIGS), parse_size("10T"));
 }
 
+#[test]
+fn test_prefix_key() {
+    assert_eq!("a/b/c/abc", prefix_key("", "a/b/c/abc".to_owned()));
+    assert_eq!("proj/a/b/c/abc", prefix_key("proj", "a/b/c/abc".to_owned()));
+}
+
+#[test]
+fn test_remote_hit() {
+    match remote_hit(b"not a zip file".to_vec()) {
+        Cache::Miss => {}
+        x => panic!("Unexpected cache result: {:?}", x),
+    }
+    let mut entry = CacheWrite::new();
+    entry.put_object("obj", &mut &b"some data"[..]).unwrap();
+    match remote_hit(entry.finish().unwrap()) {
+        Cache::Hit(_) => {}
+        x => panic!("Unexpected cache result: {:?}", x),
+    }
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(None, parse_duration(""));
+    assert_eq!(None, parse_duration("1w"));
+    assert_eq!(Some(Duration::from_secs(100)), parse_duration("100"));
+    assert_eq!(Some(Duration::from_secs(100)), parse_duration("100s"));
+    assert_eq!(Some(Duration::from_secs(120)), parse_duration("2m"));
+    assert_eq!(Some(Duration::from_secs(3 * 3600)), parse_duration("3h"));
+    assert_eq!(Some(Duration::from_secs(30 * 86400)), parse_duration("30d"));
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -261,6 +1050,101 @@ mod test {
     use std::io::Write;
     use test::utils::*;
 
+    fn round_trip(level: i32) {
+        let mut entry = CacheWrite::with_compression_level(level);
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        let data = entry.finish().unwrap();
+        let mut hit = CacheRead::from(io::Cursor::new(data)).unwrap();
+        assert_eq!(vec!["obj".to_owned()], hit.object_names().unwrap());
+        let mut contents = vec!();
+        hit.get_object("obj", &mut contents).unwrap();
+        assert_eq!(b"object contents", &contents[..]);
+    }
+
+    #[test]
+    fn test_cache_entry_verify() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        let mut hit = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        hit.verify().unwrap();
+
+        let mut zip = ZipWriter::new(io::Cursor::new(vec!()));
+        zip.start_file("obj", CompressionMethod::Stored).unwrap();
+        zip.write_all(b"object contents").unwrap();
+        zip.start_file(DIGEST, CompressionMethod::Stored).unwrap();
+        zip.write_all(b"0000000000000000000000000000000000000000").unwrap();
+        zip.start_file(FORMAT, CompressionMethod::Stored).unwrap();
+        zip.write_all(CACHE_VERSION).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let mut hit = CacheRead::from(io::Cursor::new(data)).unwrap();
+        assert!(hit.verify().is_err());
+
+        // Entries of the current format always have a digest.
+        let mut zip = ZipWriter::new(io::Cursor::new(vec!()));
+        zip.start_file("obj", CompressionMethod::Stored).unwrap();
+        zip.write_all(b"object contents").unwrap();
+        zip.start_file(FORMAT, CompressionMethod::Stored).unwrap();
+        zip.write_all(CACHE_VERSION).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let mut hit = CacheRead::from(io::Cursor::new(data)).unwrap();
+        assert!(hit.verify().is_err());
+    }
+
+    #[test]
+    fn test_cache_entry_format() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        let mut hit = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        assert_eq!(Some(String::from_utf8(CACHE_VERSION.to_vec()).unwrap()), hit.format_version());
+        assert_eq!(vec!["obj".to_owned()], hit.object_names().unwrap());
+
+        // Entries of other versions, or of versions before entries recorded
+        // theirs, aren't used.
+        for version in &[Some(&b"3"[..]), None] {
+            let mut zip = ZipWriter::new(io::Cursor::new(vec!()));
+            zip.start_file("obj", CompressionMethod::Stored).unwrap();
+            zip.write_all(b"object contents").unwrap();
+            if let Some(version) = *version {
+                zip.start_file(FORMAT, CompressionMethod::Stored).unwrap();
+                zip.write_all(version).unwrap();
+            }
+            let data = zip.finish().unwrap().into_inner();
+            let mut hit = CacheRead::from(io::Cursor::new(data)).unwrap();
+            assert!(hit.verify().is_err());
+        }
+    }
+
+    #[test]
+    fn test_cache_entry_compile_time() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        let mut hit = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        assert_eq!(None, hit.compile_time());
+
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        entry.set_compile_time(Duration::from_millis(2500));
+        let mut hit = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        assert_eq!(Some(Duration::from_millis(2500)), hit.compile_time());
+        assert_eq!(vec!["obj".to_owned()], hit.object_names().unwrap());
+        hit.verify().unwrap();
+        // Copies of the entry keep it.
+        let data = hit.to_write().unwrap().finish().unwrap();
+        let mut copy = CacheRead::from(io::Cursor::new(data)).unwrap();
+        assert_eq!(Some(Duration::from_millis(2500)), copy.compile_time());
+    }
+
+    #[test]
+    fn test_cache_entry_zstd() {
+        round_trip(DEFAULT_COMPRESSION_LEVEL);
+    }
+
+    #[test]
+    fn test_cache_entry_uncompressed() {
+        // Entries written without zstd, as by older versions, are still readable.
+        round_trip(0);
+    }
+
     #[test]
     fn test_hash_key_executable_contents_differs() {
         let f = TestFixture::new();
@@ -299,6 +1183,34 @@ mod test {
                     hash_key(&c, &args, &b"goodbye"[..]));
     }
 
+    #[test]
+    fn test_hash_key_from_reader() {
+        let f = TestFixture::new();
+        let c = Compiler::new(f.bins[0].to_str().unwrap(), CompilerKind::Gcc).unwrap();
+        let args = "a b c";
+        // Larger than the buffer the reader is hashed through.
+        let preprocessed = vec![b'x'; 200 * 1024];
+        assert_eq!(hash_key(&c, &args, &preprocessed),
+                   hash_key_from(&c, &args, &mut io::Cursor::new(&preprocessed)).unwrap());
+    }
+
+    #[test]
+    fn test_key_inputs_changes() {
+        let inputs = KeyInputs {
+            compiler: "c".to_owned(),
+            arguments: "-c -O2".to_owned(),
+            env: vec!(),
+            preprocessed: "p".to_owned(),
+        };
+        assert!(inputs.changes(&inputs).is_empty());
+        let mut previous = inputs.clone();
+        previous.arguments = "-c".to_owned();
+        previous.env = vec!(("MACOSX_DEPLOYMENT_TARGET".to_owned(), "10.7".to_owned()));
+        assert_eq!(vec!("arguments `-c` -> `-c -O2`".to_owned(),
+                        "environment `MACOSX_DEPLOYMENT_TARGET=10.7` -> ``".to_owned()),
+                   inputs.changes(&previous));
+    }
+
     #[test]
     fn test_hash_key_env_var_differs() {
         let f = TestFixture::new();
@@ -321,4 +1233,65 @@ mod test {
             assert_neq!(h2, h3);
         }
     }
+
+    #[test]
+    fn test_hash_key_client_env() {
+        let f = TestFixture::new();
+        let mut c = Compiler::new(f.bins[0].to_str().unwrap(), CompilerKind::Gcc).unwrap();
+        let args = "a b c";
+        const PREPROCESSED : &'static [u8] = b"hello world";
+        c.client_env = Some(vec!(("LANG".to_owned(), "C".to_owned())));
+        let h1 = hash_key(&c, &args, &PREPROCESSED);
+        c.client_env = Some(vec!(("LANG".to_owned(), "de_DE.UTF-8".to_owned())));
+        let h2 = hash_key(&c, &args, &PREPROCESSED);
+        assert_neq!(h1, h2);
+        // The server's own environment doesn't matter then.
+        let var = "SCCACHE_TEST_CLIENT_ENV_VAR";
+        c.hashed_env_vars.push(var.to_owned());
+        env::set_var(var, "something");
+        let h3 = hash_key(&c, &args, &PREPROCESSED);
+        env::remove_var(var);
+        assert_eq!(h2, h3);
+        // Variables that don't change the output aren't part of the key.
+        c.client_env = Some(vec!(("LANG".to_owned(), "de_DE.UTF-8".to_owned()),
+                                 ("HOME".to_owned(), "/home/me".to_owned())));
+        assert_eq!(h2, hash_key(&c, &args, &PREPROCESSED));
+    }
+
+    #[test]
+    fn test_hash_key_sloppy_env_vars() {
+        let f = TestFixture::new();
+        let mut c = Compiler::new(f.bins[0].to_str().unwrap(), CompilerKind::Gcc).unwrap();
+        let args = "a b c";
+        const PREPROCESSED : &'static [u8] = b"hello world";
+        c.sloppiness.locale = true;
+        c.sloppiness.env_vars.push("CPATH".to_owned());
+        c.client_env = Some(vec!(("LANG".to_owned(), "C".to_owned()),
+                                 ("CPATH".to_owned(), "/a".to_owned())));
+        let h1 = hash_key(&c, &args, &PREPROCESSED);
+        c.client_env = Some(vec!(("LC_ALL".to_owned(), "de_DE.UTF-8".to_owned()),
+                                 ("CPATH".to_owned(), "/b".to_owned())));
+        let h2 = hash_key(&c, &args, &PREPROCESSED);
+        assert_eq!(h1, h2);
+        c.client_env = Some(vec!(("C_INCLUDE_PATH".to_owned(), "/b".to_owned())));
+        assert_neq!(h1, hash_key(&c, &args, &PREPROCESSED));
+    }
+
+    #[test]
+    fn test_hash_key_hashed_env_var_differs() {
+        let f = TestFixture::new();
+        let mut c = Compiler::new(f.bins[0].to_str().unwrap(), CompilerKind::Gcc).unwrap();
+        let args = "a b c";
+        const PREPROCESSED : &'static [u8] = b"hello world";
+        let var = "SCCACHE_TEST_HASHED_ENV_VAR";
+        env::set_var(var, "something");
+        let h1 = hash_key(&c, &args, &PREPROCESSED);
+        c.hashed_env_vars.push(var.to_owned());
+        let h2 = hash_key(&c, &args, &PREPROCESSED);
+        env::set_var(var, "something else");
+        let h3 = hash_key(&c, &args, &PREPROCESSED);
+        env::remove_var(var);
+        assert_neq!(h1, h2);
+        assert_neq!(h2, h3);
+    }
 }
@@ -0,0 +1,301 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+    retry_policy,
+};
+use cache::retry::{RetryPolicy, retry};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use futures::{Future, Stream};
+use futures::future;
+use futures_cpupool::CpuPool;
+use http::{HttpsClient, https_client};
+use hyper::{self, header, Method};
+use hyper::client::Request;
+use rustc_serialize::hex::ToHex;
+use std::env;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use time;
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// How long request signatures are valid for, in seconds.
+const SIGNATURE_LIFETIME: i64 = 3600;
+
+/// A Tencent Cloud API key.
+#[derive(Clone)]
+pub struct COSCredentials {
+    pub secret_id: String,
+    pub secret_key: String,
+}
+
+fn hmac_sha1_hex(key: &[u8], data: &[u8]) -> String {
+    let mut hmac = Hmac::new(Sha1::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_hex()
+}
+
+/// Build the `Authorization` header value for a request, valid from `start`, as
+/// described in https://intl.cloud.tencent.com/document/product/436/7778
+fn authorization(creds: &COSCredentials, method: &str, path: &str, host: &str,
+                 start: i64) -> String {
+    let key_time = format!("{};{}", start, start + SIGNATURE_LIFETIME);
+    let sign_key = hmac_sha1_hex(creds.secret_key.as_bytes(), key_time.as_bytes());
+    // Only the host header is signed, and there are no URL parameters.
+    let http_string = format!("{}\n{}\n\nhost={}\n", method.to_lowercase(), path, host);
+    let mut sha1 = Sha1::new();
+    sha1.input_str(&http_string);
+    let string_to_sign = format!("sha1\n{}\n{}\n", key_time, sha1.result_str());
+    let signature = hmac_sha1_hex(sign_key.as_bytes(), string_to_sign.as_bytes());
+    format!("q-sign-algorithm=sha1&q-ak={}&q-sign-time={kt}&q-key-time={kt}\
+             &q-header-list=host&q-url-param-list=&q-signature={}",
+            creds.secret_id, signature, kt = key_time)
+}
+
+/// A COS bucket.
+struct Bucket {
+    /// The bucket name, including the APPID suffix.
+    name: String,
+    /// The bucket's host name.
+    host: String,
+    credentials: Option<COSCredentials>,
+    client: HttpsClient,
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bucket(name={}, host={})", self.name, self.host)
+    }
+}
+
+impl Bucket {
+    /// Build a request for `key`, signing it if credentials are configured.
+    fn request(&self, method: Method, key: &str) -> Request {
+        let path = format!("/{}", key);
+        let url = format!("https://{}{}", self.host, path);
+        debug!("{} {}", method, url);
+        let mut request = Request::new(method.clone(), url.parse().unwrap());
+        if let Some(ref creds) = self.credentials {
+            let start = time::now_utc().to_timespec().sec;
+            let auth = authorization(creds, &method.to_string(), &path, &self.host, start);
+            request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
+        }
+        request
+    }
+
+    fn get(&self, key: &str) -> SFuture<Vec<u8>> {
+        let request = self.request(Method::Get, key);
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed GET from COS"
+        }).and_then(|res| {
+            if res.status().class() == hyper::status::StatusClass::Success {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }))
+    }
+
+    fn put(&self, key: &str, content: Vec<u8>) -> SFuture<()> {
+        let mut request = self.request(Method::Put, key);
+        request.headers_mut().set(header::ContentType("application/octet-stream".parse().unwrap()));
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+        Box::new(self.client.request(request).then(|result| {
+            match result {
+                Ok(res) => {
+                    if res.status().class() == hyper::status::StatusClass::Success {
+                        trace!("PUT succeeded");
+                        Ok(())
+                    } else {
+                        trace!("PUT failed with HTTP status: {}", res.status());
+                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                    }
+                }
+                Err(e) => {
+                    trace!("PUT failed with error: {:?}", e);
+                    Err(e.into())
+                }
+            }
+        }))
+    }
+}
+
+/// A cache that stores entries in Tencent Cloud Object Storage.
+pub struct COSCache {
+    bucket: Rc<Bucket>,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+    /// How failed requests are retried.
+    retry: RetryPolicy,
+    handle: Handle,
+}
+
+impl COSCache {
+    /// Create a new `COSCache` storing data in `bucket`, a bucket name of the
+    /// form `name-APPID`, in `region`, such as `ap-guangzhou`.
+    ///
+    /// `endpoint` overrides the default `cos.<region>.myqcloud.com` endpoint.
+    /// Without `credentials`, the cache is read-only and the bucket must
+    /// allow anonymous reads.
+    pub fn new(bucket: &str, region: &str, endpoint: Option<&str>,
+               credentials: Option<COSCredentials>, handle: &Handle) -> Result<COSCache> {
+        if !bucket.contains('-') {
+            bail!("COS bucket name must include the APPID, as in `name-1250000000`");
+        }
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.trim_left_matches("https://").trim_right_matches('/').to_owned(),
+            None => format!("cos.{}.myqcloud.com", region),
+        };
+        Ok(COSCache {
+            bucket: Rc::new(Bucket {
+                name: bucket.to_owned(),
+                host: format!("{}.{}", bucket, endpoint),
+                credentials: credentials,
+                client: https_client(handle),
+            }),
+            key_prefix: String::new(),
+            retry: RetryPolicy::default(),
+            handle: handle.clone(),
+        })
+    }
+
+    /// Retry failed requests according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Store entries under `prefix`, to share the bucket with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+}
+
+impl Storage for COSCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let bucket = self.bucket.clone();
+        let response = retry(&self.retry, &self.handle, move || bucket.get(&key));
+        Box::new(response.then(|result| {
+            match result {
+                Ok(data) => Ok(remote_hit(data)),
+                Err(Error(ErrorKind::BadHTTPStatus(status), _)) => {
+                    warn!("Got COS error: HTTP status {}", status);
+                    Ok(Cache::Miss)
+                }
+                // Anything else means the service couldn't be reached.
+                Err(e) => Err(e),
+            }
+        }))
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        // Just hand back an in-memory buffer.
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.is_read_only() {
+            return future::err(ErrorKind::ReadOnlyCache.into()).boxed();
+        }
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let start = Instant::now();
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+        let bucket = self.bucket.clone();
+        let response = retry(&self.retry, &self.handle, move || {
+            bucket.put(&key, data.clone())
+        }).chain_err(|| {
+            "failed to put cache entry in COS"
+        });
+        Box::new(response.map(move |_| start.elapsed()))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.bucket.credentials.is_none()
+    }
+
+    fn location(&self) -> String {
+        format!("COS, bucket: {}{}", self.bucket, prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create a `COSCache` from the environment, if `SCCACHE_COS_BUCKET` is set.
+pub fn from_environment(_pool: &CpuPool, handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let bucket = match env::var("SCCACHE_COS_BUCKET") {
+        Ok(bucket) => bucket,
+        Err(_) => return None,
+    };
+    let region = env::var("SCCACHE_COS_REGION").unwrap_or("ap-guangzhou".to_owned());
+    let endpoint = env::var("SCCACHE_COS_ENDPOINT").ok();
+    let credentials = match (env::var("SCCACHE_COS_SECRET_ID"),
+                             env::var("SCCACHE_COS_SECRET_KEY")) {
+        (Ok(id), Ok(key)) => Some(COSCredentials {
+            secret_id: id,
+            secret_key: key,
+        }),
+        _ => None,
+    };
+    debug!("Trying COSCache({}, {})", bucket, region);
+    Some(COSCache::new(&bucket, &region, endpoint.as_ref().map(|s| s.as_str()),
+                       credentials, handle).map(|mut s| {
+        if let Ok(prefix) = env::var("SCCACHE_COS_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        s.set_retry_policy(retry_policy("COS"));
+        Arc::new(s) as Arc<Storage>
+    }))
+}
+
+#[test]
+fn test_authorization() {
+    let creds = COSCredentials {
+        secret_id: "secretid".to_owned(),
+        secret_key: "secretkey".to_owned(),
+    };
+    assert_eq!("q-sign-algorithm=sha1&q-ak=secretid&q-sign-time=1500000000;1500003600\
+                &q-key-time=1500000000;1500003600&q-header-list=host&q-url-param-list=\
+                &q-signature=0b946cb63e57f52aa344f90191267fc49aca2f98",
+               authorization(&creds, "GET", "/a/b/c/abcdef",
+                             "bucket-1250000000.cos.ap-beijing.myqcloud.com", 1500000000));
+}
@@ -16,15 +16,22 @@ use cache::{
     Cache,
     CacheRead,
     CacheWrite,
+    GcStats,
     Storage,
 };
-use futures::Future;
+use cache::encrypt::EncryptionKey;
+use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
-use lru_disk_cache::LruDiskCache;
+use lru_disk_cache::{LruDiskCache, ReadSeek};
 use lru_disk_cache::Error as LruError;
+use lru_disk_cache::Result as LruResult;
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
 use std::path::{Path,PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
 use std::time::{Instant, Duration};
 
 use errors::*;
@@ -36,40 +43,386 @@ pub struct DiskCache {
     lru: Arc<Mutex<LruDiskCache>>,
     /// Thread pool to execute disk I/O
     pool: CpuPool,
+    /// Entries that haven't been used for this long are expired.
+    max_age: Option<Duration>,
+    /// If set, objects are stored as separate files and linked into place on hits.
+    link_mode: Option<LinkMode>,
+    /// Whether the cache directory is shared with other machines, e.g. over NFS.
+    shared: bool,
+    /// If set, entries are encrypted with this key on disk.
+    encryption: Option<Arc<EncryptionKey>>,
+}
+
+/// How to place an object stored in the disk cache at its output path.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LinkMode {
+    /// Create a hard link to the cached file.
+    Hardlink,
+    /// Create a copy-on-write clone of the cached file, where the filesystem supports it.
+    Reflink,
+}
+
+/// Name of the zip member listing the objects that are stored as separate files.
+const EXTERNAL_OBJECTS: &'static str = "sccache-external-objects";
+
+/// Name of the lock file in a shared cache directory.
+const LOCK_FILE: &'static str = ".sccache-lock";
+
+/// How long to wait for the lock on a shared cache directory.
+const LOCK_TIMEOUT_SECS: u64 = 10;
+
+/// Locks older than this were left behind by a crashed process.
+const STALE_LOCK_SECS: u64 = 60;
+
+/// An advisory lock on a shared cache directory, held while entries are
+/// published or removed, so that machines don't remove files another one is
+/// in the middle of publishing.
+///
+/// This creates the lock file exclusively rather than using `flock`, which
+/// isn't reliable over NFS.
+struct LockFile {
+    path: PathBuf,
+}
+
+impl LockFile {
+    fn acquire(root: &Path) -> io::Result<LockFile> {
+        let path = root.join(LOCK_FILE);
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(LockFile { path: path }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            let stale = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|mtime| mtime.elapsed().ok())
+                .map_or(false, |age| age > Duration::from_secs(STALE_LOCK_SECS));
+            if stale {
+                warn!("Removing stale cache lock {:?}", path);
+                fs::remove_file(&path).unwrap_or(());
+                continue;
+            }
+            if start.elapsed() > Duration::from_secs(LOCK_TIMEOUT_SECS) {
+                return Err(io::Error::new(io::ErrorKind::Other, "timed out waiting for cache lock"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).unwrap_or(());
+    }
+}
+
+/// Link `src` to a new file at `dst` using `mode`, replacing any existing file.
+pub fn link_file(src: &Path, dst: &Path, mode: LinkMode) -> io::Result<()> {
+    match fs::remove_file(dst) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    match mode {
+        LinkMode::Hardlink => fs::hard_link(src, dst),
+        LinkMode::Reflink => reflink(src, dst),
+    }
+}
+
+/// Make a hard-linked cache object read-only, so that tools which rewrite
+/// their output in place fail instead of corrupting the cache. This is only
+/// done on Unix, where read-only files can still be removed on eviction.
+#[cfg(unix)]
+fn make_read_only(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o444)) {
+        warn!("Failed to make {:?} read-only: {}", path, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn make_read_only(_path: &Path) {}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use libc;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int) from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x40049409;
+    let src_file = File::open(src)?;
+    let dst_file = File::create(dst)?;
+    if unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE, src_file.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        let e = io::Error::last_os_error();
+        drop(dst_file);
+        fs::remove_file(dst).unwrap_or(());
+        Err(e)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported on this platform"))
+}
+
+/// The index of the entries in a cache directory, which all the
+/// `DiskCache`s of this process using the directory share.
+pub type DiskIndex = Arc<Mutex<LruDiskCache>>;
+
+lazy_static! {
+    /// The indexes of the cache directories in use, so that using one again,
+    /// such as after the configuration is reloaded, doesn't scan it again.
+    static ref INDEXES: Mutex<HashMap<(PathBuf, usize), Weak<Mutex<LruDiskCache>>>> = Mutex::new(HashMap::new());
+}
+
+/// Get the index of the cache directory `root` holding up to `max_size`
+/// bytes, scanning the directory unless it's already in use.
+pub fn open_index(root: &Path, max_size: usize) -> LruResult<DiskIndex> {
+    let mut indexes = INDEXES.lock().unwrap();
+    let key = (root.to_owned(), max_size);
+    if let Some(index) = indexes.get(&key).and_then(|index| index.upgrade()) {
+        return Ok(index);
+    }
+    let mut lru = LruDiskCache::new(root, max_size)?;
+    // Evicted files are removed one at a time after the operation that
+    // evicted them, so that cache reads aren't blocked on deleting them all.
+    lru.defer_removals();
+    let index = Arc::new(Mutex::new(lru));
+    indexes.insert(key, Arc::downgrade(&index));
+    Ok(index)
 }
 
 impl DiskCache {
     /// Create a new `DiskCache` rooted at `root`, with `max_size` as the maximum cache size on-disk, in bytes.
     pub fn new<T: AsRef<OsStr>>(root: &T,
                                 max_size: usize,
-                                pool: &CpuPool) -> DiskCache {
-        DiskCache {
-            //TODO: change this function to return a Result
-            lru: Arc::new(Mutex::new(LruDiskCache::new(root, max_size).expect("Couldn't instantiate disk cache!"))),
+                                pool: &CpuPool) -> Result<DiskCache> {
+        let lru = open_index(Path::new(root), max_size)
+            .chain_err(|| format!("Couldn't open disk cache {:?}", root.as_ref()))?;
+        Ok(DiskCache {
+            lru: lru,
             pool: pool.clone(),
+            max_age: None,
+            link_mode: None,
+            shared: false,
+            encryption: None,
+        })
+    }
+
+    /// Coordinate with other machines using the same cache directory, such as
+    /// over NFS.
+    ///
+    /// Entries written by other machines are picked up on lookup, and entries
+    /// are only published or removed while holding a lock file in the cache
+    /// directory.
+    pub fn set_shared(&mut self) {
+        self.shared = true;
+    }
+
+    /// Store objects as separate files, and link them into place with `mode`
+    /// on cache hits instead of copying them.
+    ///
+    /// Hard links share the cached file with the output, so tools that modify
+    /// output files in place will corrupt the cache entry.
+    pub fn set_link_mode(&mut self, mode: LinkMode) {
+        self.link_mode = Some(mode);
+    }
+
+    /// Encrypt entries with `key` on disk.
+    ///
+    /// Encrypted entries are always read into memory, so objects aren't linked
+    /// into place even if a link mode is set. Entries that aren't encrypted
+    /// with `key` are treated as misses.
+    pub fn set_encryption_key(&mut self, key: EncryptionKey) {
+        self.encryption = Some(Arc::new(key));
+    }
+
+    /// The link mode to use, if any.
+    fn link_mode(&self) -> Option<LinkMode> {
+        if self.encryption.is_some() { None } else { self.link_mode }
+    }
+
+    /// Expire entries stored more than `max_age` ago, however recently
+    /// they were used.
+    ///
+    /// Expired entries are treated as misses, and removed from disk when
+    /// they're looked up or by `gc`.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age);
+    }
+}
+
+/// Remove files that were evicted from the cache.
+fn remove_evicted(lru: &Mutex<LruDiskCache>, evicted: Vec<PathBuf>) {
+    if !evicted.is_empty() {
+        trace!("DiskCache evicting {} entries", evicted.len());
+    }
+    // Each file is removed under the index lock, so that an entry stored
+    // again since it was evicted keeps its new file.
+    for path in evicted.iter() {
+        lru.lock().unwrap().remove_evicted(path);
+    }
+}
+
+/// Remove files that were evicted from the shared cache at `root`, while
+/// holding its lock.
+fn remove_evicted_shared(root: &Path, lru: &Mutex<LruDiskCache>, evicted: Vec<PathBuf>) {
+    if evicted.is_empty() {
+        return;
+    }
+    match LockFile::acquire(root) {
+        Ok(_lock) => remove_evicted(lru, evicted),
+        // The files are left for whichever machine evicts them next.
+        Err(e) => warn!("Failed to lock shared cache, not evicting: {}", e),
+    }
+}
+
+/// Get the file at `path` from `lru`, picking up files written by other
+/// machines if the cache is `shared`.
+fn lru_get(lru: &mut LruDiskCache, path: &Path, shared: bool) -> LruResult<Box<ReadSeek>> {
+    match lru.get(path) {
+        Err(LruError::FileNotInCache) if shared => {
+            lru.adopt(path)?;
+            lru.get(path)
         }
+        result => result,
     }
 }
 
+/// Read and decrypt the entry with key `key` from `f`.
+fn decrypt_entry(mut f: Box<ReadSeek>, key: &str, encryption: &EncryptionKey) -> Result<Box<ReadSeek>> {
+    let mut data = vec!();
+    f.read_to_end(&mut data)?;
+    let data = encryption.decrypt(key.as_bytes(), &data)?;
+    Ok(Box::new(io::Cursor::new(data)))
+}
+
 /// Make a path to the cache entry with key `key`.
 fn make_key_path(key: &str) -> PathBuf {
     Path::new(&key[0..1]).join(&key[1..2]).join(key)
 }
 
+/// Get the key of the cache entry stored at `path`, unless it holds a
+/// separately-stored object, which is named after its entry's key with an
+/// extension.
+fn entry_key(path: &Path) -> Option<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| if name.len() >= 3 && name.chars().all(|c| c.is_digit(16)) { Some(name) } else { None })
+}
+
+/// Make a path to the separately-stored object `name` of the cache entry at `key_path`.
+fn make_object_path(key_path: &Path, name: &str) -> PathBuf {
+    let mut path = key_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(name);
+    PathBuf::from(path)
+}
+
+/// Split the objects out of the cache entry `data`, returning a new cache entry
+/// that lists them in `EXTERNAL_OBJECTS` and the objects themselves.
+fn split_objects(data: Vec<u8>) -> Result<(Vec<u8>, Vec<(String, Vec<u8>)>)> {
+    let mut hit = CacheRead::from(io::Cursor::new(data))?;
+    let mut entry = CacheWrite::new();
+    let mut objects = vec!();
+    for name in hit.object_names()? {
+        let mut contents = vec!();
+        hit.get_object(&name, &mut contents)?;
+        if name == "stdout" || name == "stderr" {
+            entry.put_object(&name, &mut &contents[..])?;
+        } else {
+            objects.push((name, contents));
+        }
+    }
+    let names = objects.iter().map(|&(ref name, _)| name.as_str()).collect::<Vec<_>>().join("\n");
+    entry.put_object(EXTERNAL_OBJECTS, &mut names.as_bytes())?;
+    if let Some(compile_time) = hit.compile_time() {
+        entry.set_compile_time(compile_time);
+    }
+    Ok((entry.finish()?, objects))
+}
+
 impl Storage for DiskCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
         trace!("DiskCache::get({})", key);
         let path = make_key_path(key);
-        let lru = self.lru.clone();
+        let key = key.to_owned();
+        let index = self.lru.clone();
+        let max_age = self.max_age;
+        let link_mode = self.link_mode();
+        let shared = self.shared;
+        let encryption = self.encryption.clone();
         self.pool.spawn_fn(move || {
-            let mut lru = lru.lock().unwrap();
-            let f = match lru.get(&path) {
-                Ok(f) => f,
-                Err(LruError::FileNotInCache) => return Ok(Cache::Miss),
-                Err(LruError::Io(e)) => return Err(e.into()),
+            let mut lru = index.lock().unwrap();
+            let root = lru.path().to_owned();
+            if max_age.map_or(false, |max_age| lru.is_older_than(&path, max_age)) {
+                lru.remove(&path);
+            }
+            let result = match lru_get(&mut lru, &path, shared) {
+                Ok(f) if encryption.is_some() => {
+                    let encryption = encryption.as_ref().unwrap();
+                    match decrypt_entry(f, &key, encryption).and_then(|f| CacheRead::from(f)) {
+                        Ok(hit) => Ok(Some(hit)),
+                        // Such as entries written before encryption was enabled.
+                        Err(e) => {
+                            debug!("DiskCache failed to decrypt {:?}: {}", path, e);
+                            Ok(None)
+                        }
+                    }
+                }
+                Ok(f) => {
+                    match CacheRead::from(f) {
+                        Ok(hit) => Ok(Some(hit)),
+                        // Another machine may have removed the entry while it was being read.
+                        Err(e) if shared => {
+                            debug!("DiskCache failed to read {:?}: {}", path, e);
+                            Ok(None)
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(LruError::FileNotInCache) => Ok(None),
+                Err(LruError::Io(e)) => Err(e.into()),
                 Err(_) => panic!("Unexpected error!"),
             };
-            let hit = CacheRead::from(f)?;
+            let mut hit = match result {
+                Ok(Some(hit)) => hit,
+                result => {
+                    let evicted = lru.take_evicted();
+                    drop(lru);
+                    if shared {
+                        remove_evicted_shared(&root, &index, evicted);
+                    } else {
+                        remove_evicted(&index, evicted);
+                    }
+                    return result.map(|_| Cache::Miss);
+                }
+            };
+            let mut names = vec!();
+            if link_mode.is_some() && hit.get_object(EXTERNAL_OBJECTS, &mut names).is_ok() {
+                for name in String::from_utf8_lossy(&names).lines() {
+                    let object_path = make_object_path(&path, name);
+                    // Getting the object also marks it as recently used.
+                    if lru_get(&mut lru, &object_path, shared).is_err() {
+                        debug!("DiskCache object {:?} is missing", object_path);
+                        return Ok(Cache::Miss);
+                    }
+                    hit.set_external_object(name, lru.path().join(&object_path), link_mode.unwrap());
+                }
+            }
+            let evicted = lru.take_evicted();
+            drop(lru);
+            if shared {
+                remove_evicted_shared(&root, &index, evicted);
+            } else {
+                remove_evicted(&index, evicted);
+            }
             Ok(Cache::Hit(hit))
         }).boxed()
     }
@@ -84,19 +437,267 @@ impl Storage for DiskCache {
         // everything in memory...
         trace!("DiskCache::finish_put({})", key);
         let lru = self.lru.clone();
+        let context = key.to_owned();
         let key = make_key_path(key);
+        let link_mode = self.link_mode();
+        let shared = self.shared;
+        let encryption = self.encryption.clone();
         self.pool.spawn_fn(move || {
             let start = Instant::now();
             let v = entry.finish()?;
-            lru.lock().unwrap().insert_bytes(key, &v)?;
+            let v = match encryption {
+                Some(ref encryption) => encryption.encrypt(context.as_bytes(), &v)?,
+                None => v,
+            };
+            let (v, objects) = if link_mode.is_some() {
+                split_objects(v)?
+            } else {
+                (v, vec!())
+            };
+            let _lock = if shared {
+                let root = lru.lock().unwrap().path().to_owned();
+                Some(LockFile::acquire(&root).chain_err(|| "failed to lock shared cache")?)
+            } else {
+                None
+            };
+            let evicted = {
+                let mut lru = lru.lock().unwrap();
+                // Insert the objects first, so that the entry is never visible without them.
+                for &(ref name, ref contents) in objects.iter() {
+                    let object_path = make_object_path(&key, name);
+                    lru.insert_bytes(&object_path, contents)?;
+                    if link_mode == Some(LinkMode::Hardlink) {
+                        make_read_only(&lru.path().join(&object_path));
+                    }
+                }
+                lru.insert_bytes(key, &v)?;
+                lru.take_evicted()
+            };
+            remove_evicted(&lru, evicted);
             Ok(start.elapsed())
         }).boxed()
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        trace!("DiskCache::remove({})", key);
+        let lru = self.lru.clone();
+        let path = make_key_path(key);
+        let shared = self.shared;
+        self.pool.spawn_fn(move || {
+            let (root, evicted) = {
+                let mut lru = lru.lock().unwrap();
+                lru.remove(&path);
+                (lru.path().to_owned(), lru.take_evicted())
+            };
+            if shared {
+                remove_evicted_shared(&root, &lru, evicted);
+            } else {
+                remove_evicted(&lru, evicted);
+            }
+            Ok(())
+        }).boxed()
+    }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> {
+        trace!("DiskCache::gc");
+        let lru = self.lru.clone();
+        let max_age = self.max_age;
+        let shared = self.shared;
+        self.pool.spawn_fn(move || {
+            let _lock = if shared {
+                let root = lru.lock().unwrap().path().to_owned();
+                Some(LockFile::acquire(&root).chain_err(|| "failed to lock shared cache")?)
+            } else {
+                None
+            };
+            let (rebuilt, expired, evicted) = {
+                let mut lru = lru.lock().unwrap();
+                let rebuilt = lru.rebuild()?;
+                let expired = max_age.map_or(0, |max_age| lru.remove_older_than(max_age));
+                (rebuilt, expired, lru.take_evicted())
+            };
+            remove_evicted(&lru, evicted);
+            Ok(Some(GcStats {
+                temp_files_removed: rebuilt.temp_files_removed,
+                missing: rebuilt.missing,
+                added: rebuilt.added,
+                evicted: rebuilt.evicted,
+                expired: expired,
+            }))
+        }).boxed()
+    }
+
+    fn clear(&self, _remote: bool) -> SFuture<usize> {
+        trace!("DiskCache::clear");
+        let lru = self.lru.clone();
+        let shared = self.shared;
+        self.pool.spawn_fn(move || {
+            let _lock = if shared {
+                let root = lru.lock().unwrap().path().to_owned();
+                Some(LockFile::acquire(&root).chain_err(|| "failed to lock shared cache")?)
+            } else {
+                None
+            };
+            let (removed, evicted) = {
+                let mut lru = lru.lock().unwrap();
+                // Pick up the files the index doesn't know about, such as
+                // those written by other machines sharing the cache.
+                let rebuilt = lru.rebuild()?;
+                let cleared = lru.clear();
+                (rebuilt.temp_files_removed + rebuilt.evicted + cleared, lru.take_evicted())
+            };
+            remove_evicted(&lru, evicted);
+            Ok(removed)
+        }).boxed()
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        let paths = self.lru.lock().unwrap().keys();
+        let keys = paths.iter()
+            .filter_map(|path| entry_key(path))
+            .map(|name| name.to_owned())
+            .collect();
+        future::ok(keys).boxed()
+    }
+
     fn location(&self) -> String {
         format!("Local disk: {:?}", self.lru.lock().unwrap().path())
     }
 
     fn current_size(&self) -> Option<usize> { Some(self.lru.lock().unwrap().size()) }
     fn max_size(&self) -> Option<usize> { Some(self.lru.lock().unwrap().capacity()) }
+
+    fn entry_count(&self) -> Option<usize> {
+        let paths = self.lru.lock().unwrap().keys();
+        Some(paths.iter().filter(|path| entry_key(path).is_some()).count())
+    }
+
+    fn remote_location(&self) -> Option<String> { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::{Cache, CacheWrite, Storage};
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::iter;
+    use std::usize;
+    use test::utils::*;
+
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut v = vec!();
+        File::open(path).unwrap().read_to_end(&mut v).unwrap();
+        v
+    }
+
+    #[test]
+    fn test_open_index() {
+        let f = TestFixture::new();
+        let root = f.tempdir.path().join("cache");
+        let index = open_index(&root, usize::MAX).unwrap();
+        // An index in use is shared rather than scanned again.
+        let again = open_index(&root, usize::MAX).unwrap();
+        assert_eq!(&*index as *const _, &*again as *const _);
+        assert_eq!(2, Arc::strong_count(&index));
+        drop(index);
+        drop(again);
+        // One no longer in use is scanned again, finding new files.
+        create_file(&root, "a", |mut f| f.write_all(b"contents")).unwrap();
+        assert_eq!(1, open_index(&root, usize::MAX).unwrap().lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_clear() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let root = f.tempdir.path().join("cache");
+        let cache = DiskCache::new(&root, usize::MAX, &pool).unwrap();
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        cache.finish_put("abcdef", entry).wait().unwrap();
+        // A file written by another machine sharing the directory.
+        fs::create_dir_all(root.join("1").join("2")).unwrap();
+        File::create(root.join("1").join("2").join("123456")).unwrap();
+        assert_eq!(2, cache.clear(false).wait().unwrap());
+        assert_eq!(Some(0), cache.current_size());
+        assert!(!root.join("a").join("b").join("abcdef").exists());
+        assert!(!root.join("1").join("2").join("123456").exists());
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_hardlink_objects() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut cache = DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap();
+        cache.set_link_mode(LinkMode::Hardlink);
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        entry.put_object("stdout", &mut &b"some output"[..]).unwrap();
+        cache.finish_put("abcdef", entry).wait().unwrap();
+        // The separately-stored objects aren't counted as entries.
+        assert_eq!(Some(1), cache.entry_count());
+        let out = f.tempdir.path().join("out.o");
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                entry.extract_object("obj", &out).unwrap();
+                let mut stdout = vec!();
+                entry.get_object("stdout", &mut stdout).unwrap();
+                assert_eq!(b"some output", &stdout[..]);
+            }
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        assert_eq!(b"object contents", &read_file(&out)[..]);
+        let cached = f.tempdir.path().join("cache").join("a").join("b").join("abcdef.obj");
+        assert!(cached.exists());
+        if cfg!(unix) {
+            assert!(fs::metadata(&cached).unwrap().permissions().readonly());
+        }
+        // Removing the cached object makes the entry a miss.
+        fs::remove_file(&cached).unwrap();
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let root = f.tempdir.path().join("cache");
+        let mut cache = DiskCache::new(&root, usize::MAX, &pool).unwrap();
+        cache.set_encryption_key(EncryptionKey::from_hex(&iter::repeat("ab").take(32).collect::<String>()).unwrap());
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object contents"[..]).unwrap();
+        cache.finish_put("abcdef", entry).wait().unwrap();
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut v = vec!();
+                entry.get_object("obj", &mut v).unwrap();
+                assert_eq!(b"object contents", &v[..]);
+            }
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        // The entry can't be read without the key.
+        let plain = DiskCache::new(&root, usize::MAX, &pool).unwrap();
+        assert!(CacheRead::from(io::Cursor::new(read_file(&root.join("a").join("b").join("abcdef")))).is_err());
+        match plain.get("abcdef").wait() {
+            Ok(Cache::Hit(_)) => panic!("Unexpected cache hit"),
+            _ => {}
+        }
+        // Nor with another key.
+        let mut other = DiskCache::new(&root, usize::MAX, &pool).unwrap();
+        other.set_encryption_key(EncryptionKey::from_hex(&iter::repeat("cd").take(32).collect::<String>()).unwrap());
+        match other.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+    }
 }
@@ -0,0 +1,148 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encryption of cache entries at rest, with AES-256-GCM.
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::FromHex;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+
+/// Marks the start of an encrypted entry, followed by a format version.
+const MAGIC: &'static [u8] = b"SCCE\x01";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A key to encrypt cache entries with.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+// Keep the key out of logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    /// Parse a key given as 64 hexadecimal digits.
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey> {
+        let bytes = hex.trim().from_hex().chain_err(|| "encryption key isn't valid hex")?;
+        EncryptionKey::from_bytes(&bytes)
+    }
+
+    /// Read a key from `path`, holding either the 32 bytes of the key or
+    /// 64 hexadecimal digits.
+    pub fn from_file(path: &Path) -> Result<EncryptionKey> {
+        let mut contents = vec!();
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .chain_err(|| format!("failed to read encryption key from {}", path.display()))?;
+        if contents.len() == KEY_LEN {
+            return EncryptionKey::from_bytes(&contents);
+        }
+        match String::from_utf8(contents) {
+            Ok(hex) => EncryptionKey::from_hex(&hex),
+            Err(_) => bail!("encryption key in {} must be {} bytes or {} hex digits",
+                            path.display(), KEY_LEN, KEY_LEN * 2),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<EncryptionKey> {
+        if bytes.len() != KEY_LEN {
+            bail!("encryption key must be {} bytes, not {}", KEY_LEN, bytes.len());
+        }
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(bytes);
+        Ok(EncryptionKey(key))
+    }
+
+    /// Encrypt `data`, which will only decrypt with the same `context`,
+    /// such as the cache key of the entry.
+    pub fn encrypt(&self, context: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng::new().chain_err(|| "failed to generate nonce")?.fill_bytes(&mut nonce);
+        let mut tag = [0; TAG_LEN];
+        let mut ciphertext = vec![0; data.len()];
+        AesGcm::new(KeySize::KeySize256, &self.0, &nonce, context)
+            .encrypt(data, &mut ciphertext, &mut tag);
+        let mut out = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len() + TAG_LEN);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    /// Decrypt `data` produced by `encrypt` with the same `context`.
+    pub fn decrypt(&self, context: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < MAGIC.len() + NONCE_LEN + TAG_LEN || !data.starts_with(MAGIC) {
+            bail!("cache entry isn't encrypted");
+        }
+        let nonce = &data[MAGIC.len()..MAGIC.len() + NONCE_LEN];
+        let (ciphertext, tag) = data[MAGIC.len() + NONCE_LEN..].split_at(data.len() - MAGIC.len() - NONCE_LEN - TAG_LEN);
+        let mut plaintext = vec![0; ciphertext.len()];
+        if !AesGcm::new(KeySize::KeySize256, &self.0, nonce, context).decrypt(ciphertext, &mut plaintext, tag) {
+            bail!("failed to decrypt cache entry");
+        }
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use test::utils::*;
+
+    const HEX_KEY: &'static str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn test_roundtrip() {
+        let key = EncryptionKey::from_hex(HEX_KEY).unwrap();
+        let encrypted = key.encrypt(b"abcdef", b"secret object").unwrap();
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+        assert_eq!(b"secret object", &key.decrypt(b"abcdef", &encrypted).unwrap()[..]);
+        // Entries can't be moved to another key, or tampered with.
+        assert!(key.decrypt(b"fedcba", &encrypted).is_err());
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(key.decrypt(b"abcdef", &tampered).is_err());
+        assert!(key.decrypt(b"abcdef", b"PK\x03\x04 plain zip").is_err());
+    }
+
+    #[test]
+    fn test_key_from_file() {
+        let f = TestFixture::new();
+        let path = f.tempdir.path().join("key");
+        File::create(&path).unwrap().write_all(format!("{}\n", HEX_KEY).as_bytes()).unwrap();
+        let hex = EncryptionKey::from_file(&path).unwrap();
+        File::create(&path).unwrap().write_all(&[7; 32]).unwrap();
+        let raw = EncryptionKey::from_file(&path).unwrap();
+        assert_eq!(&hex.0[..4], &[0, 1, 2, 3]);
+        assert_eq!(raw.0, [7; 32]);
+        assert!(EncryptionKey::from_hex("0011").is_err());
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+    retry_policy,
+};
+use cache::retry::{RetryPolicy, retry};
+use futures::{Future, Stream};
+use futures::future;
+use futures_cpupool::CpuPool;
+use http::{HttpsClient, https_client, parse_base_url};
+use hyper::{self, header, Method, Url};
+use hyper::client::{Request, Response};
+use hyper::status::{StatusClass, StatusCode};
+use serde_json::{self, Value};
+use sha1;
+use std::env;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+const ACCEPT: &'static str = "application/json;api-version=6.0-preview.1";
+
+fn read_body(res: Response) -> SFuture<Vec<u8>> {
+    if res.status().class() != StatusClass::Success {
+        return future::err(ErrorKind::BadHTTPStatus(res.status().clone()).into()).boxed();
+    }
+    Box::new(res.body().fold(Vec::new(), |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        Ok::<_, hyper::Error>(body)
+    }).chain_err(|| {
+        "failed to read HTTP body"
+    }))
+}
+
+fn read_json(res: Response) -> SFuture<Value> {
+    Box::new(read_body(res).and_then(|body| {
+        serde_json::from_slice(&body).chain_err(|| {
+            "failed to parse Actions cache response"
+        })
+    }))
+}
+
+fn check_status(res: Response) -> Result<()> {
+    if res.status().class() == StatusClass::Success {
+        Ok(())
+    } else {
+        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+    }
+}
+
+/// A client for the GitHub Actions cache service.
+struct ActionsCache {
+    /// Base URL of the cache service, from `ACTIONS_CACHE_URL`.
+    url: Url,
+    /// Runtime token, from `ACTIONS_RUNTIME_TOKEN`.
+    token: String,
+    /// Cache version string; entries are only visible to the same version.
+    version: String,
+    client: HttpsClient,
+}
+
+impl ActionsCache {
+    fn request(&self, method: Method, path: &str, query: &[(&str, &str)]) -> Request {
+        let mut url = self.url.clone();
+        // `GHACache::new` checked that the base URL can have a path.
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(&["_apis", "artifactcache"]).extend(path.split('/'));
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        debug!("{} {}", method, url);
+        let mut request = Request::new(method, url);
+        request.headers_mut().set(header::Authorization(header::Bearer { token: self.token.clone() }));
+        request.headers_mut().set_raw("Accept", vec!(ACCEPT.as_bytes().to_vec()));
+        request
+    }
+
+    fn json_request(&self, method: Method, path: &str, body: String) -> Request {
+        let mut request = self.request(method, path, &[]);
+        request.headers_mut().set(header::ContentType::json());
+        request.headers_mut().set(header::ContentLength(body.len() as u64));
+        request.set_body(body.into_bytes());
+        request
+    }
+
+    /// Look up `key`, returning the URL its archive can be downloaded from.
+    fn lookup(&self, key: &str) -> SFuture<Option<String>> {
+        let request = self.request(Method::Get, "cache", &[("keys", key), ("version", &self.version)]);
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed to query Actions cache"
+        }).and_then(|res| -> SFuture<Option<String>> {
+            if *res.status() == StatusCode::NoContent || *res.status() == StatusCode::NotFound {
+                return future::ok(None).boxed();
+            }
+            Box::new(read_json(res).map(|json| {
+                json.find("archiveLocation").and_then(|v| v.as_str()).map(|s| s.to_owned())
+            }))
+        }))
+    }
+
+    fn download(&self, url: &str) -> SFuture<Vec<u8>> {
+        debug!("GET {}", url);
+        let url = match url.parse() {
+            Ok(url) => url,
+            Err(_) => return future::err(format!("invalid archive location: {}", url).into()).boxed(),
+        };
+        Box::new(self.client.get(url).chain_err(|| {
+            "failed to download Actions cache entry"
+        }).and_then(read_body))
+    }
+
+    /// Reserve `key` for upload, returning the cache id to upload to.
+    fn reserve(&self, key: &str) -> SFuture<u64> {
+        let body = format!(r#"{{"key":"{}","version":"{}"}}"#, key, self.version);
+        let request = self.json_request(Method::Post, "caches", body);
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed to reserve Actions cache entry"
+        }).and_then(read_json).and_then(|json| {
+            json.find("cacheId").and_then(|v| v.as_u64()).ok_or("no cacheId in reserve response".into())
+        }))
+    }
+
+    fn upload(&self, cache_id: u64, data: Vec<u8>) -> SFuture<()> {
+        let mut request = self.request(Method::Patch, &format!("caches/{}", cache_id), &[]);
+        request.headers_mut().set(header::ContentType("application/octet-stream".parse().unwrap()));
+        request.headers_mut().set(header::ContentLength(data.len() as u64));
+        let range = format!("bytes 0-{}/*", data.len().saturating_sub(1));
+        request.headers_mut().set_raw("Content-Range", vec!(range.into_bytes()));
+        request.set_body(data);
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed to upload Actions cache entry"
+        }).and_then(check_status))
+    }
+
+    fn commit(&self, cache_id: u64, size: usize) -> SFuture<()> {
+        let request = self.json_request(Method::Post, &format!("caches/{}", cache_id),
+                                        format!(r#"{{"size":{}}}"#, size));
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed to commit Actions cache entry"
+        }).and_then(check_status))
+    }
+}
+
+/// A cache that stores entries in the GitHub Actions cache service.
+pub struct GHACache {
+    cache: Rc<ActionsCache>,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+    /// How failed requests are retried.
+    retry: RetryPolicy,
+    handle: Handle,
+}
+
+impl GHACache {
+    /// Create a new `GHACache` talking to the cache service at `url`.
+    ///
+    /// `version` isolates entries from caches with a different version.
+    pub fn new(url: &str, token: &str, version: &str, handle: &Handle) -> Result<GHACache> {
+        let url = parse_base_url(url, "Actions cache URL")?;
+        let mut m = sha1::Sha1::new();
+        m.update(b"sccache-");
+        m.update(version.as_bytes());
+        Ok(GHACache {
+            cache: Rc::new(ActionsCache {
+                url: url,
+                token: token.to_owned(),
+                version: m.digest().to_string(),
+                client: https_client(handle),
+            }),
+            key_prefix: String::new(),
+            retry: RetryPolicy::default(),
+            handle: handle.clone(),
+        })
+    }
+
+    /// Retry failed requests according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Store entries under `prefix`, to share the repository cache with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    format!("sccache-{}", key)
+}
+
+impl Storage for GHACache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = normalize_key(&prefix_key(&self.key_prefix, key.to_owned()));
+        let cache = self.cache.clone();
+        let response = retry(&self.retry, &self.handle, move || -> SFuture<Option<Vec<u8>>> {
+            let download = cache.clone();
+            Box::new(cache.lookup(&key).and_then(move |location| {
+                match location {
+                    Some(location) => Box::new(download.download(&location).map(Some)) as SFuture<_>,
+                    None => future::ok(None).boxed(),
+                }
+            }))
+        });
+        Box::new(response.then(|result| {
+            match result {
+                Ok(Some(data)) => Ok(remote_hit(data)),
+                Ok(None) => Ok(Cache::Miss),
+                Err(Error(ErrorKind::BadHTTPStatus(status), _)) => {
+                    warn!("Got Actions cache error: HTTP status {}", status);
+                    Ok(Cache::Miss)
+                }
+                // Anything else means the service couldn't be reached.
+                Err(e) => Err(e),
+            }
+        }))
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        // Just hand back an in-memory buffer.
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let key = normalize_key(&prefix_key(&self.key_prefix, key.to_owned()));
+        let start = Instant::now();
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+        let size = data.len();
+        let cache = self.cache.clone();
+        let response = retry(&self.retry, &self.handle, move || -> SFuture<()> {
+            let upload_cache = cache.clone();
+            let commit_cache = cache.clone();
+            let data = data.clone();
+            Box::new(cache.reserve(&key).and_then(move |cache_id| {
+                upload_cache.upload(cache_id, data).map(move |_| cache_id)
+            }).and_then(move |cache_id| {
+                commit_cache.commit(cache_id, size)
+            }))
+        });
+        Box::new(response.map(move |_| start.elapsed()))
+    }
+
+    fn location(&self) -> String {
+        format!("GitHub Actions cache, url: {}{}", self.cache.url, prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create a `GHACache` from the environment, if `SCCACHE_GHA_ENABLED` is set.
+pub fn from_environment(_pool: &CpuPool, handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    if !env::var("SCCACHE_GHA_ENABLED").ok().map_or(false, |v| v == "on" || v == "true") {
+        return None;
+    }
+    let (url, token) = match (env::var("ACTIONS_CACHE_URL"), env::var("ACTIONS_RUNTIME_TOKEN")) {
+        (Ok(url), Ok(token)) => (url, token),
+        _ => return Some(Err("SCCACHE_GHA_ENABLED is set but ACTIONS_CACHE_URL or ACTIONS_RUNTIME_TOKEN is missing".into())),
+    };
+    let version = env::var("SCCACHE_GHA_VERSION").unwrap_or(String::new());
+    debug!("Trying GHACache({})", url);
+    Some(GHACache::new(&url, &token, &version, handle).map(|mut s| {
+        if let Ok(prefix) = env::var("SCCACHE_GHA_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        s.set_retry_policy(retry_policy("GHA"));
+        Arc::new(s) as Arc<Storage>
+    }))
+}
@@ -0,0 +1,331 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+};
+use futures::Future;
+use futures_cpupool::CpuPool;
+use sha1;
+use std::env;
+use std::io::{
+    BufRead,
+    BufReader,
+    Read,
+    Write,
+};
+use std::net::TcpStream;
+use std::str;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// The largest value memcached will store by default.
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+
+/// Leave some room under `MAX_ITEM_SIZE` for the item header.
+const CHUNK_SIZE: usize = MAX_ITEM_SIZE - 1024;
+
+/// Item flag marking a value as a chunk count rather than entry data.
+const FLAG_CHUNKED: u32 = 1;
+
+/// Number of points each server gets on the hash ring.
+const POINTS_PER_SERVER: usize = 160;
+
+/// A consistent hash ring mapping keys to servers.
+struct Ring {
+    servers: Vec<String>,
+    /// (point, server index), sorted by point.
+    points: Vec<(u32, usize)>,
+}
+
+fn hash32(data: &str) -> u32 {
+    let mut m = sha1::Sha1::new();
+    m.update(data.as_bytes());
+    let digest = m.digest().bytes();
+    ((digest[0] as u32) << 24) | ((digest[1] as u32) << 16) | ((digest[2] as u32) << 8) | (digest[3] as u32)
+}
+
+impl Ring {
+    fn new(servers: Vec<String>) -> Ring {
+        let mut points = vec!();
+        for (i, server) in servers.iter().enumerate() {
+            for p in 0..POINTS_PER_SERVER {
+                points.push((hash32(&format!("{}-{}", server, p)), i));
+            }
+        }
+        points.sort();
+        Ring {
+            servers: servers,
+            points: points,
+        }
+    }
+
+    /// Get the server responsible for `key`.
+    fn server_for(&self, key: &str) -> &str {
+        let h = hash32(key);
+        let idx = match self.points.binary_search_by(|&(p, _)| p.cmp(&h)) {
+            Ok(i) => i,
+            Err(i) if i == self.points.len() => 0,
+            Err(i) => i,
+        };
+        &self.servers[self.points[idx].1]
+    }
+}
+
+/// A single connection speaking the memcached text protocol.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn connect(server: &str) -> Result<Connection> {
+        let stream = TcpStream::connect(server).chain_err(|| {
+            format!("failed to connect to memcached server {}", server)
+        })?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        if !line.ends_with("\r\n") {
+            bail!("unexpected end of memcached response");
+        }
+        let len = line.len() - 2;
+        line.truncate(len);
+        Ok(line)
+    }
+
+    /// Fetch `key`, returning its flags and value if present.
+    fn get(&mut self, key: &str) -> Result<Option<(u32, Vec<u8>)>> {
+        write!(self.writer, "get {}\r\n", key)?;
+        let line = self.read_line()?;
+        if line == "END" {
+            return Ok(None);
+        }
+        let parts = line.split(' ').collect::<Vec<_>>();
+        if parts.len() < 4 || parts[0] != "VALUE" {
+            bail!("unexpected memcached response: {}", line);
+        }
+        let flags = parts[2].parse::<u32>().chain_err(|| "bad memcached flags")?;
+        let len = parts[3].parse::<usize>().chain_err(|| "bad memcached length")?;
+        let mut data = vec![0; len + 2];
+        self.reader.read_exact(&mut data)?;
+        data.truncate(len);
+        let end = self.read_line()?;
+        if end != "END" {
+            bail!("unexpected memcached response: {}", end);
+        }
+        Ok(Some((flags, data)))
+    }
+
+    fn set(&mut self, key: &str, flags: u32, data: &[u8]) -> Result<()> {
+        write!(self.writer, "set {} {} 0 {}\r\n", key, flags, data.len())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(b"\r\n")?;
+        let line = self.read_line()?;
+        if line != "STORED" {
+            bail!("memcached failed to store {}: {}", key, line);
+        }
+        Ok(())
+    }
+}
+
+/// Key for chunk `n` of the entry at `key`.
+fn chunk_key(key: &str, n: usize) -> String {
+    format!("{}/{}", key, n)
+}
+
+/// A cache that stores entries in one or more memcached servers.
+#[derive(Clone)]
+pub struct MemcachedCache {
+    ring: Arc<Ring>,
+    /// Thread pool to execute network I/O.
+    pool: CpuPool,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+}
+
+impl MemcachedCache {
+    /// Create a new `MemcachedCache` from a whitespace-separated list of
+    /// `host:port` server addresses, optionally prefixed with `tcp://`.
+    pub fn new(servers: &str, pool: &CpuPool) -> Result<MemcachedCache> {
+        let servers = servers.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.trim_left_matches("tcp://").to_owned())
+            .collect::<Vec<_>>();
+        if servers.is_empty() {
+            bail!("no memcached servers configured");
+        }
+        Ok(MemcachedCache {
+            ring: Arc::new(Ring::new(servers)),
+            pool: pool.clone(),
+            key_prefix: String::new(),
+        })
+    }
+
+    /// Store entries under `prefix`, to share the servers with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+
+    fn get_data(ring: &Ring, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut conn = Connection::connect(ring.server_for(key))?;
+        match conn.get(key)? {
+            None => Ok(None),
+            Some((FLAG_CHUNKED, count)) => {
+                let count = str::from_utf8(&count).ok()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .ok_or("bad memcached chunk count")?;
+                let mut data = vec!();
+                for n in 0..count {
+                    let key = chunk_key(key, n);
+                    let mut conn = Connection::connect(ring.server_for(&key))?;
+                    match conn.get(&key)? {
+                        Some((_, chunk)) => data.extend_from_slice(&chunk),
+                        // A chunk was evicted, so the entry is incomplete.
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(data))
+            }
+            Some((_, data)) => Ok(Some(data)),
+        }
+    }
+
+    fn put_data(ring: &Ring, key: &str, data: &[u8]) -> Result<()> {
+        if data.len() <= CHUNK_SIZE {
+            return Connection::connect(ring.server_for(key))?.set(key, 0, data);
+        }
+        // Store the chunks first so that readers never see a chunk count
+        // for chunks that don't exist yet.
+        let mut count = 0;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            let key = chunk_key(key, count);
+            Connection::connect(ring.server_for(&key))?.set(&key, 0, chunk)?;
+            count += 1;
+        }
+        Connection::connect(ring.server_for(key))?.set(key, FLAG_CHUNKED, count.to_string().as_bytes())
+    }
+}
+
+impl Storage for MemcachedCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("MemcachedCache::get({})", key);
+        let ring = self.ring.clone();
+        let key = prefix_key(&self.key_prefix, key.to_owned());
+        self.pool.spawn_fn(move || {
+            match MemcachedCache::get_data(&ring, &key) {
+                Ok(Some(data)) => Ok(remote_hit(data)),
+                Ok(None) => Ok(Cache::Miss),
+                Err(e) => {
+                    warn!("Got memcached error: {:?}", e);
+                    Err(e)
+                }
+            }
+        }).boxed()
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        trace!("MemcachedCache::start_put({})", key);
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        trace!("MemcachedCache::finish_put({})", key);
+        let ring = self.ring.clone();
+        let key = prefix_key(&self.key_prefix, key.to_owned());
+        self.pool.spawn_fn(move || {
+            let start = Instant::now();
+            let data = entry.finish()?;
+            MemcachedCache::put_data(&ring, &key, &data)?;
+            Ok(start.elapsed())
+        }).boxed()
+    }
+
+    fn location(&self) -> String {
+        format!("Memcached: {}{}", self.ring.servers.join(", "), prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create a `MemcachedCache` from the environment, if `SCCACHE_MEMCACHED` is set.
+pub fn from_environment(pool: &CpuPool, _handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let servers = match env::var("SCCACHE_MEMCACHED") {
+        Ok(servers) => servers,
+        Err(_) => return None,
+    };
+    debug!("Trying MemcachedCache({})", servers);
+    Some(MemcachedCache::new(&servers, pool).map(|mut s| {
+        if let Ok(prefix) = env::var("SCCACHE_MEMCACHED_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        Arc::new(s) as Arc<Storage>
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_single_server() {
+        let ring = Ring::new(vec!["a:11211".to_owned()]);
+        assert_eq!("a:11211", ring.server_for("abc"));
+        assert_eq!("a:11211", ring.server_for("xyz"));
+    }
+
+    #[test]
+    fn test_ring_stable() {
+        let servers = vec!["a:11211".to_owned(), "b:11211".to_owned(), "c:11211".to_owned()];
+        let ring = Ring::new(servers.clone());
+        let keys = (0..100).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let before = keys.iter().map(|k| ring.server_for(k).to_owned()).collect::<Vec<_>>();
+        // Every server should get some keys.
+        for s in servers.iter() {
+            assert!(before.contains(s));
+        }
+        // Removing a server should only move the keys that were on it.
+        let ring = Ring::new(vec!["a:11211".to_owned(), "b:11211".to_owned()]);
+        for (k, old) in keys.iter().zip(before.iter()) {
+            if old != "c:11211" {
+                assert_eq!(old, ring.server_for(k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_servers() {
+        let pool = ::futures_cpupool::CpuPool::new(1);
+        let cache = MemcachedCache::new("tcp://a:1 b:2,c:3", &pool).unwrap();
+        assert_eq!(vec!["a:1", "b:2", "c:3"], cache.ring.servers);
+        assert!(MemcachedCache::new("  ", &pool).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    GcStats,
+    Storage,
+};
+use futures::{Future, future};
+use metrics::Histogram;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+/// How many of the most recent requests latency percentiles are computed from.
+const LATENCY_SAMPLES: usize = 1000;
+
+/// The durations of recent requests.
+#[derive(Debug, Default)]
+pub struct Latencies {
+    samples: VecDeque<Duration>,
+}
+
+impl Latencies {
+    fn record(&mut self, duration: Duration) {
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(duration);
+    }
+
+    /// Get the duration that `percent` percent of recent requests took at most.
+    pub fn percentile(&self, percent: usize) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.iter().cloned().collect::<Vec<_>>();
+        sorted.sort();
+        let index = cmp::min(sorted.len() * percent / 100, sorted.len() - 1);
+        Some(sorted[index])
+    }
+}
+
+/// Statistics about the requests made to one storage backend.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    /// The name of the backend.
+    pub name: String,
+    /// The count of lookups.
+    pub reads: u64,
+    /// The count of lookups that failed.
+    pub read_errors: u64,
+    /// The durations of recent lookups.
+    pub read_latencies: Latencies,
+    /// The durations of all lookups.
+    pub read_histogram: Histogram,
+    /// The size of the entries found.
+    pub bytes_read: u64,
+    /// The count of entries stored.
+    pub writes: u64,
+    /// The count of entries that failed to be stored.
+    pub write_errors: u64,
+    /// The durations of recent writes.
+    pub write_latencies: Latencies,
+    /// The durations of all writes.
+    pub write_histogram: Histogram,
+    /// The size of the entries stored.
+    pub bytes_written: u64,
+}
+
+impl BackendStats {
+    /// Create empty statistics for the backend `name`.
+    pub fn new(name: &str) -> BackendStats {
+        BackendStats {
+            name: name.to_owned(),
+            .. BackendStats::default()
+        }
+    }
+
+    /// Zero the statistics.
+    pub fn reset(&mut self) {
+        *self = BackendStats::new(&self.name);
+    }
+}
+
+/// A wrapper around another `Storage` that records how many requests are
+/// made to it, how many fail, how long they take, and how much data they
+/// transfer.
+pub struct MeteredStorage {
+    inner: Arc<Storage>,
+    stats: Rc<RefCell<BackendStats>>,
+}
+
+impl MeteredStorage {
+    /// Create a new `MeteredStorage` recording requests to `inner` under `name`.
+    pub fn new(name: &str, inner: Arc<Storage>) -> MeteredStorage {
+        MeteredStorage {
+            inner: inner,
+            stats: Rc::new(RefCell::new(BackendStats::new(name))),
+        }
+    }
+}
+
+impl Storage for MeteredStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let stats = self.stats.clone();
+        let start = Instant::now();
+        Box::new(self.inner.get(key).then(move |result| {
+            let mut stats = stats.borrow_mut();
+            let duration = start.elapsed();
+            stats.reads += 1;
+            stats.read_latencies.record(duration);
+            stats.read_histogram.record(duration);
+            match result {
+                Ok(Cache::Hit(ref entry)) => stats.bytes_read += entry.size(),
+                Ok(_) => {}
+                Err(_) => stats.read_errors += 1,
+            }
+            result
+        }))
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        self.inner.start_put(key)
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        // Finish the entry here to know its size.
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let size = data.len() as u64;
+        let stats = self.stats.clone();
+        Box::new(self.inner.finish_put(key, CacheWrite::from_bytes(data)).then(move |result| {
+            let mut stats = stats.borrow_mut();
+            match result {
+                Ok(duration) => {
+                    stats.writes += 1;
+                    stats.bytes_written += size;
+                    stats.write_latencies.record(duration);
+                    stats.write_histogram.record(duration);
+                }
+                Err(Error(ErrorKind::ReadOnlyCache, _)) |
+                Err(Error(ErrorKind::CacheUnavailable, _)) |
+                Err(Error(ErrorKind::EntryTooLarge(_), _)) => {}
+                Err(_) => stats.write_errors += 1,
+            }
+            result
+        }))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool { self.inner.is_read_only() }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> { self.inner.gc() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> { self.inner.clear(remote) }
+
+    fn keys(&self) -> SFuture<Vec<String>> { self.inner.keys() }
+
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> {
+        vec![self.stats.clone()]
+    }
+
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> {
+        vec![(self.stats.borrow().name.clone(), self.inner.clone())]
+    }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<usize> { self.inner.current_size() }
+    fn max_size(&self) -> Option<usize> { self.inner.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.inner.entry_count() }
+    fn background_writes(&self) -> usize { self.inner.background_writes() }
+    fn remote_location(&self) -> Option<String> { self.inner.remote_location() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::usize;
+    use test::utils::*;
+
+    #[test]
+    fn test_latencies() {
+        let mut latencies = Latencies::default();
+        assert_eq!(None, latencies.percentile(50));
+        for ms in 1..101 {
+            latencies.record(Duration::from_millis(ms));
+        }
+        assert_eq!(Some(Duration::from_millis(51)), latencies.percentile(50));
+        assert_eq!(Some(Duration::from_millis(100)), latencies.percentile(99));
+    }
+
+    #[test]
+    fn test_metered_storage() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap());
+        let storage = MeteredStorage::new("disk", disk);
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"contents"[..]).unwrap();
+        storage.finish_put("abcdef", entry).wait().unwrap();
+        storage.get("abcdef").wait().unwrap();
+        storage.get("fedcba").wait().unwrap();
+        let stats = storage.backend_stats();
+        let stats = stats[0].borrow();
+        assert_eq!("disk", stats.name);
+        assert_eq!(2, stats.reads);
+        assert_eq!(0, stats.read_errors);
+        assert_eq!(1, stats.writes);
+        assert!(stats.bytes_written > 0);
+        assert_eq!(stats.bytes_written, stats.bytes_read);
+        assert!(stats.read_latencies.percentile(50).is_some());
+    }
+}
@@ -12,8 +12,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod archive;
+pub mod audit;
+#[cfg(feature = "azure")]
+pub mod azure;
+pub mod breaker;
 pub mod cache;
+#[cfg(feature = "cos")]
+pub mod cos;
 pub mod disk;
+pub mod encrypt;
+#[cfg(feature = "gcs")]
+pub mod gcs;
+#[cfg(feature = "gha")]
+pub mod gha;
+#[cfg(feature = "memcached")]
+pub mod memcached;
+pub mod metered;
+#[cfg(feature = "oss")]
+pub mod oss;
+pub mod prefetch;
+pub mod readonly;
+pub mod retry;
+#[cfg(feature = "s3")]
 pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+pub mod sizelimit;
+pub mod split;
+pub mod tiered;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 pub use cache::cache::*;
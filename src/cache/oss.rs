@@ -0,0 +1,272 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+    retry_policy,
+};
+use cache::retry::{RetryPolicy, retry};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use futures::{Future, Stream};
+use futures::future;
+use futures_cpupool::CpuPool;
+use http::{HttpsClient, https_client};
+use hyper::{self, header, Method};
+use hyper::client::Request;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use std::env;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use time;
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// An Alibaba Cloud access key.
+#[derive(Clone)]
+pub struct OSSCredentials {
+    pub access_key_id: String,
+    pub access_key_secret: String,
+}
+
+/// Sign `string_to_sign` with `secret`, as described in
+/// https://www.alibabacloud.com/help/doc-detail/31951.htm
+fn signature(string_to_sign: &str, secret: &str) -> String {
+    let mut hmac = Hmac::new(Sha1::new(), secret.as_bytes());
+    hmac.input(string_to_sign.as_bytes());
+    hmac.result().code().to_base64(STANDARD)
+}
+
+/// An OSS bucket.
+struct Bucket {
+    name: String,
+    endpoint: String,
+    credentials: Option<OSSCredentials>,
+    client: HttpsClient,
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bucket(name={}, endpoint={})", self.name, self.endpoint)
+    }
+}
+
+impl Bucket {
+    /// Build a request for `key`, signing it if credentials are configured.
+    fn request(&self, method: Method, key: &str, content_type: &str) -> Request {
+        let url = format!("https://{}.{}/{}", self.name, self.endpoint, key);
+        debug!("{} {}", method, url);
+        let mut request = Request::new(method.clone(), url.parse().unwrap());
+        let date = time::now_utc().rfc822().to_string();
+        if let Some(ref creds) = self.credentials {
+            let string_to_sign = format!("{}\n\n{}\n{}\n/{}/{}",
+                                         method, content_type, date, self.name, key);
+            let auth = format!("OSS {}:{}", creds.access_key_id,
+                               signature(&string_to_sign, &creds.access_key_secret));
+            request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
+        }
+        request.headers_mut().set_raw("Date", vec!(date.into_bytes()));
+        request
+    }
+
+    fn get(&self, key: &str) -> SFuture<Vec<u8>> {
+        let request = self.request(Method::Get, key, "");
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed GET from OSS"
+        }).and_then(|res| {
+            if res.status().class() == hyper::status::StatusClass::Success {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }))
+    }
+
+    fn put(&self, key: &str, content: Vec<u8>) -> SFuture<()> {
+        let content_type = "application/octet-stream";
+        let mut request = self.request(Method::Put, key, content_type);
+        request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+        Box::new(self.client.request(request).then(|result| {
+            match result {
+                Ok(res) => {
+                    if res.status().class() == hyper::status::StatusClass::Success {
+                        trace!("PUT succeeded");
+                        Ok(())
+                    } else {
+                        trace!("PUT failed with HTTP status: {}", res.status());
+                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                    }
+                }
+                Err(e) => {
+                    trace!("PUT failed with error: {:?}", e);
+                    Err(e.into())
+                }
+            }
+        }))
+    }
+}
+
+/// A cache that stores entries in Alibaba Cloud Object Storage Service.
+pub struct OSSCache {
+    bucket: Rc<Bucket>,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+    /// How failed requests are retried.
+    retry: RetryPolicy,
+    handle: Handle,
+}
+
+impl OSSCache {
+    /// Create a new `OSSCache` storing data in `bucket` at `endpoint`, such
+    /// as `oss-cn-hangzhou.aliyuncs.com`.
+    ///
+    /// Without `credentials`, the cache is read-only and the bucket must
+    /// allow anonymous reads.
+    pub fn new(bucket: &str, endpoint: &str, credentials: Option<OSSCredentials>,
+               handle: &Handle) -> Result<OSSCache> {
+        let endpoint = endpoint.trim_left_matches("https://").trim_left_matches("http://");
+        Ok(OSSCache {
+            bucket: Rc::new(Bucket {
+                name: bucket.to_owned(),
+                endpoint: endpoint.trim_right_matches('/').to_owned(),
+                credentials: credentials,
+                client: https_client(handle),
+            }),
+            key_prefix: String::new(),
+            retry: RetryPolicy::default(),
+            handle: handle.clone(),
+        })
+    }
+
+    /// Retry failed requests according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Store entries under `prefix`, to share the bucket with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+}
+
+impl Storage for OSSCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let bucket = self.bucket.clone();
+        let response = retry(&self.retry, &self.handle, move || bucket.get(&key));
+        Box::new(response.then(|result| {
+            match result {
+                Ok(data) => Ok(remote_hit(data)),
+                Err(Error(ErrorKind::BadHTTPStatus(status), _)) => {
+                    warn!("Got OSS error: HTTP status {}", status);
+                    Ok(Cache::Miss)
+                }
+                // Anything else means the service couldn't be reached.
+                Err(e) => Err(e),
+            }
+        }))
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        // Just hand back an in-memory buffer.
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.is_read_only() {
+            return future::err(ErrorKind::ReadOnlyCache.into()).boxed();
+        }
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let start = Instant::now();
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+        let bucket = self.bucket.clone();
+        let response = retry(&self.retry, &self.handle, move || {
+            bucket.put(&key, data.clone())
+        }).chain_err(|| {
+            "failed to put cache entry in OSS"
+        });
+        Box::new(response.map(move |_| start.elapsed()))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.bucket.credentials.is_none()
+    }
+
+    fn location(&self) -> String {
+        format!("OSS, bucket: {}{}", self.bucket, prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create an `OSSCache` from the environment, if `SCCACHE_OSS_BUCKET` is set.
+pub fn from_environment(_pool: &CpuPool, handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let bucket = match env::var("SCCACHE_OSS_BUCKET") {
+        Ok(bucket) => bucket,
+        Err(_) => return None,
+    };
+    // Prefer the internal endpoint, which avoids traffic charges from
+    // inside Alibaba Cloud.
+    let endpoint = env::var("SCCACHE_OSS_INTERNAL_ENDPOINT")
+        .or_else(|_| env::var("SCCACHE_OSS_ENDPOINT"))
+        .unwrap_or("oss-cn-hangzhou.aliyuncs.com".to_owned());
+    let credentials = match (env::var("SCCACHE_OSS_ACCESS_KEY_ID"),
+                             env::var("SCCACHE_OSS_ACCESS_KEY_SECRET")) {
+        (Ok(id), Ok(secret)) => Some(OSSCredentials {
+            access_key_id: id,
+            access_key_secret: secret,
+        }),
+        _ => None,
+    };
+    debug!("Trying OSSCache({}, {})", bucket, endpoint);
+    Some(OSSCache::new(&bucket, &endpoint, credentials, handle).map(|mut s| {
+        if let Ok(prefix) = env::var("SCCACHE_OSS_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        s.set_retry_policy(retry_policy("OSS"));
+        Arc::new(s) as Arc<Storage>
+    }))
+}
+
+#[test]
+fn test_signature() {
+    let string_to_sign = "GET\n\n\nThu, 17 Nov 2005 18:49:58 GMT\n/bucket/a/b/c/abcdef";
+    assert_eq!("9ngdhrItozxwSMYhP+28SEPHe88=", signature(string_to_sign, "secret"));
+}
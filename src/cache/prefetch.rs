@@ -0,0 +1,199 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    Storage,
+    get_verified,
+};
+use futures::{future, stream, Future, Stream};
+use futures_cpupool::CpuPool;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use errors::*;
+
+/// How many entries to fetch at once.
+const PARALLEL_FETCHES: usize = 16;
+
+/// The outcome of fetching one entry.
+enum Fetched {
+    /// The entry was copied into the local cache.
+    Fetched,
+    /// The entry was already in the local cache.
+    Present,
+    /// The entry wasn't in the remote cache.
+    Missing,
+    /// Fetching or storing the entry failed.
+    Failed,
+}
+
+/// Counts of what happened to the entries given to `prefetch`.
+#[derive(Debug, Default, PartialEq)]
+pub struct PrefetchStats {
+    pub fetched: usize,
+    pub present: usize,
+    pub missing: usize,
+    pub failed: usize,
+}
+
+/// Read the cache keys listed in `path`.
+///
+/// Each line holds a key, optionally followed by other columns, as in a
+/// manifest. Blank lines and lines starting with `#` are ignored.
+pub fn read_keys(path: &Path) -> Result<Vec<String>> {
+    let f = File::open(path).chain_err(|| format!("failed to open {}", path.display()))?;
+    let mut keys = vec!();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        let key = match line.split_whitespace().next() {
+            Some(key) if !key.starts_with('#') => key,
+            _ => continue,
+        };
+        // Backends split keys into directories by their first characters.
+        if key.len() < 3 || !key.chars().all(|c| c.is_digit(16)) {
+            warn!("Ignoring invalid cache key `{}`", key);
+            continue;
+        }
+        keys.push(key.to_owned());
+    }
+    Ok(keys)
+}
+
+/// Copy the entries for `keys` from `remote` into `local`, skipping those
+/// `local` already has.
+pub fn prefetch(remote: Arc<Storage>, local: Arc<Storage>, keys: Vec<String>,
+                pool: &CpuPool) -> SFuture<PrefetchStats> {
+    let pool = pool.clone();
+    let fetches = stream::iter(keys.into_iter().map(Ok)).map(move |key| {
+        fetch(remote.clone(), local.clone(), key, &pool)
+    }).buffer_unordered(PARALLEL_FETCHES);
+    Box::new(fetches.fold(PrefetchStats::default(), |mut stats, fetched| {
+        match fetched {
+            Fetched::Fetched => stats.fetched += 1,
+            Fetched::Present => stats.present += 1,
+            Fetched::Missing => stats.missing += 1,
+            Fetched::Failed => stats.failed += 1,
+        }
+        Ok::<_, Error>(stats)
+    }))
+}
+
+/// Copy every entry in `from` into `to`, skipping those `to` already has.
+///
+/// Storage that can't list its entries can be copied from by giving the
+/// `keys` of the entries to copy.
+pub fn migrate(from: Arc<Storage>, to: Arc<Storage>, keys: Option<Vec<String>>,
+               pool: &CpuPool) -> SFuture<PrefetchStats> {
+    let keys = match keys {
+        Some(keys) => future::ok(keys).boxed(),
+        None => from.keys(),
+    };
+    let pool = pool.clone();
+    Box::new(keys.and_then(move |keys| prefetch(from, to, keys, &pool)))
+}
+
+/// Copy the entry for `key` from `remote` into `local`.
+fn fetch(remote: Arc<Storage>, local: Arc<Storage>, key: String,
+         pool: &CpuPool) -> SFuture<Fetched> {
+    let pool = pool.clone();
+    let lookup = local.get(&key).then(move |result| -> SFuture<Fetched> {
+        if let Ok(Cache::Hit(_)) = result {
+            return future::ok(Fetched::Present).boxed();
+        }
+        let fetch_key = key.clone();
+        Box::new(get_verified(remote, &key, &pool).and_then(move |result| -> SFuture<Fetched> {
+            let mut entry = match result {
+                Cache::Hit(entry) => entry,
+                _ => return future::ok(Fetched::Missing).boxed(),
+            };
+            let write = pool.spawn_fn(move || entry.to_write());
+            Box::new(write.and_then(move |write| {
+                local.finish_put(&fetch_key, write)
+            }).map(|_| Fetched::Fetched))
+        }))
+    });
+    Box::new(lookup.or_else(|e| {
+        warn!("Failed to prefetch cache entry: {}", e);
+        Ok(Fetched::Failed)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::CacheWrite;
+    use cache::disk::DiskCache;
+    use futures::Future;
+    use std::fs::File;
+    use std::io::Write;
+    use std::usize;
+    use test::utils::*;
+
+    fn put(storage: &Storage, key: &str, contents: &[u8]) {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..]).unwrap();
+        storage.finish_put(key, entry).wait().unwrap();
+    }
+
+    #[test]
+    fn test_read_keys() {
+        let f = TestFixture::new();
+        let path = f.tempdir.path().join("keys");
+        File::create(&path).unwrap()
+            .write_all(b"# keys\nabcdef\n\n0123456789 foo.o\nnot-a-key\n").unwrap();
+        assert_eq!(vec!["abcdef", "0123456789"], read_keys(&path).unwrap());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let local = Arc::new(DiskCache::new(&f.tempdir.path().join("local"), usize::MAX, &pool).unwrap());
+        let remote = Arc::new(DiskCache::new(&f.tempdir.path().join("remote"), usize::MAX, &pool).unwrap());
+        put(&*remote, "aaaa", b"remote");
+        put(&*remote, "bbbb", b"remote");
+        put(&*local, "bbbb", b"local");
+        let keys = vec!["aaaa".to_owned(), "bbbb".to_owned(), "cccc".to_owned()];
+        let stats = prefetch(remote, local.clone(), keys, &pool).wait().unwrap();
+        assert_eq!(PrefetchStats { fetched: 1, present: 1, missing: 1, failed: 0 }, stats);
+        match local.get("aaaa").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut v = vec!();
+                entry.get_object("obj", &mut v).unwrap();
+                assert_eq!(b"remote", &v[..]);
+            }
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let from = Arc::new(DiskCache::new(&f.tempdir.path().join("from"), usize::MAX, &pool).unwrap());
+        let to = Arc::new(DiskCache::new(&f.tempdir.path().join("to"), usize::MAX, &pool).unwrap());
+        put(&*from, "aaaa", b"from");
+        put(&*from, "bbbb", b"from");
+        put(&*to, "bbbb", b"to");
+        let stats = migrate(from, to.clone(), None, &pool).wait().unwrap();
+        assert_eq!(PrefetchStats { fetched: 1, present: 1, missing: 0, failed: 0 }, stats);
+        match to.get("aaaa").wait().unwrap() {
+            Cache::Hit(_) => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    GcStats,
+    Storage,
+};
+use cache::metered::BackendStats;
+use futures::future::{self, Future};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// A wrapper around another `Storage` that serves reads but never writes.
+pub struct ReadOnlyStorage {
+    inner: Arc<Storage>,
+}
+
+impl ReadOnlyStorage {
+    /// Create a new `ReadOnlyStorage` reading from `inner`.
+    pub fn new(inner: Arc<Storage>) -> ReadOnlyStorage {
+        ReadOnlyStorage {
+            inner: inner,
+        }
+    }
+}
+
+impl Storage for ReadOnlyStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.inner.get(key)
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        Err(ErrorKind::ReadOnlyCache.into())
+    }
+
+    fn finish_put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
+        future::err(ErrorKind::ReadOnlyCache.into()).boxed()
+    }
+
+    fn is_read_only(&self) -> bool { true }
+
+    fn location(&self) -> String {
+        format!("{} (read-only)", self.inner.location())
+    }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> { self.inner.gc() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> {
+        if remote {
+            future::err(ErrorKind::ReadOnlyCache.into()).boxed()
+        } else {
+            self.inner.clear(false)
+        }
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> { self.inner.keys() }
+
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> { self.inner.backend_stats() }
+
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> {
+        self.inner.backends().into_iter().map(|(name, backend)| {
+            (name, Arc::new(ReadOnlyStorage::new(backend)) as Arc<Storage>)
+        }).collect()
+    }
+
+    fn current_size(&self) -> Option<usize> { self.inner.current_size() }
+    fn max_size(&self) -> Option<usize> { self.inner.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.inner.entry_count() }
+    fn background_writes(&self) -> usize { self.inner.background_writes() }
+    fn remote_location(&self) -> Option<String> {
+        self.inner.remote_location().map(|remote| format!("{} (read-only)", remote))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use cache::metered::MeteredStorage;
+    use futures_cpupool::CpuPool;
+    use std::usize;
+    use test::utils::*;
+
+    #[test]
+    fn test_read_only() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap());
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"contents"[..]).unwrap();
+        disk.finish_put("abcdef", entry).wait().unwrap();
+        let cache = ReadOnlyStorage::new(disk);
+        assert!(cache.is_read_only());
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        match cache.finish_put("ghijkl", CacheWrite::new()).wait() {
+            Err(Error(ErrorKind::ReadOnlyCache, _)) => {}
+            x => panic!("Unexpected write result: {:?}", x.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_read_only_backends() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap());
+        let cache = ReadOnlyStorage::new(Arc::new(MeteredStorage::new("DiskCache", disk)));
+        let backends = cache.backends();
+        assert_eq!(vec!["DiskCache"], backends.iter().map(|&(ref name, _)| &name[..]).collect::<Vec<_>>());
+        // Health checks mustn't write to backends behind a read-only cache.
+        assert!(backends[0].1.is_read_only());
+    }
+}
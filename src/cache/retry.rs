@@ -0,0 +1,201 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::future::{self, Future};
+use hyper::status::{StatusClass, StatusCode};
+use std::cmp;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::*;
+
+/// The longest to wait between attempts, unless the backoff is longer.
+const MAX_BACKOFF_SECS: u64 = 60;
+
+/// How requests to remote storage are retried.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times to retry a failed request.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubled for each further one.
+    pub backoff: Duration,
+    /// How long to wait for each attempt to complete, if at all.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retrying after `attempt` failed attempts.
+    fn delay(&self, attempt: u32) -> Duration {
+        let max = cmp::max(self.backoff, Duration::from_secs(MAX_BACKOFF_SECS));
+        1u32.checked_shl(attempt)
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .map_or(max, |delay| cmp::min(delay, max))
+    }
+}
+
+/// Whether a request that failed with `e` may succeed if retried.
+///
+/// Anything but an HTTP status meaning the request itself was wrong is
+/// considered transient.
+pub fn is_transient(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::BadHTTPStatus(ref status) => {
+            status.class() == StatusClass::ServerError || *status == StatusCode::TooManyRequests
+        }
+        ErrorKind::ReadOnlyCache |
+        ErrorKind::CacheUnavailable |
+        ErrorKind::EntryTooLarge(_) => false,
+        _ => true,
+    }
+}
+
+/// Run the request made by `request`, retrying transient failures according
+/// to `policy`.
+pub fn retry<T, F>(policy: &RetryPolicy, handle: &Handle, request: F) -> SFuture<T>
+    where F: Fn() -> SFuture<T> + 'static,
+          T: 'static,
+{
+    attempt(policy.clone(), handle.clone(), Rc::new(request), 0)
+}
+
+fn attempt<T: 'static>(policy: RetryPolicy,
+                       handle: Handle,
+                       request: Rc<Fn() -> SFuture<T>>,
+                       n: u32) -> SFuture<T> {
+    let response = with_timeout(request(), policy.timeout, &handle);
+    Box::new(response.or_else(move |e| -> SFuture<T> {
+        if n >= policy.retries || !is_transient(&e) {
+            return Box::new(future::err(e));
+        }
+        let delay = policy.delay(n);
+        warn!("Request failed, retrying in {}ms: {}",
+              delay.as_secs() * 1000 + (delay.subsec_nanos() / 1000_000) as u64, e);
+        let timer = match Timeout::new(delay, &handle) {
+            Ok(timer) => timer,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        Box::new(timer.map_err(Error::from).and_then(move |()| {
+            attempt(policy, handle, request, n + 1)
+        }))
+    }))
+}
+
+/// Fail `response` if it doesn't complete within `timeout`.
+fn with_timeout<T: 'static>(response: SFuture<T>,
+                            timeout: Option<Duration>,
+                            handle: &Handle) -> SFuture<T> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return response,
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let timer = timer.then(move |_| -> Result<T> {
+        bail!("request timed out after {}s", timeout.as_secs())
+    });
+    Box::new(response.select(timer).map(|(item, _)| item).map_err(|(e, _)| e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::status::StatusCode;
+    use std::cell::Cell;
+    use tokio_core::reactor::Core;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            retries: 2,
+            backoff: Duration::new(0, 0),
+            timeout: None,
+        }
+    }
+
+    /// Fail with `status` for the first `failures` attempts of a request.
+    fn flaky(status: StatusCode, failures: usize, attempts: &Cell<usize>) -> SFuture<usize> {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() <= failures {
+            Box::new(future::err(ErrorKind::BadHTTPStatus(status).into()))
+        } else {
+            Box::new(future::ok(attempts.get()))
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(200),
+            ..policy()
+        };
+        assert_eq!(Duration::from_millis(200), policy.delay(0));
+        assert_eq!(Duration::from_millis(800), policy.delay(2));
+        assert_eq!(Duration::from_secs(MAX_BACKOFF_SECS), policy.delay(20));
+        assert_eq!(Duration::from_secs(MAX_BACKOFF_SECS), policy.delay(40));
+        let policy = RetryPolicy {
+            backoff: Duration::from_secs(u64::max_value()),
+            ..policy
+        };
+        assert_eq!(policy.backoff, policy.delay(3));
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let a = attempts.clone();
+        let request = move || flaky(StatusCode::ServiceUnavailable, 2, &a);
+        assert_eq!(3, core.run(retry(&policy(), &core.handle(), request)).unwrap());
+        // Requests fail once the retries are used up.
+        let attempts = Rc::new(Cell::new(0));
+        let a = attempts.clone();
+        let request = move || flaky(StatusCode::ServiceUnavailable, 3, &a);
+        assert!(core.run(retry(&policy(), &core.handle(), request)).is_err());
+        assert_eq!(3, attempts.get());
+    }
+
+    #[test]
+    fn test_retry_not_transient() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let a = attempts.clone();
+        let request = move || flaky(StatusCode::NotFound, 1, &a);
+        assert!(core.run(retry(&policy(), &core.handle(), request)).is_err());
+        assert_eq!(1, attempts.get());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut core = Core::new().unwrap();
+        let policy = RetryPolicy {
+            retries: 0,
+            backoff: Duration::new(0, 0),
+            timeout: Some(Duration::from_millis(10)),
+        };
+        let request = || Box::new(future::empty()) as SFuture<()>;
+        assert!(core.run(retry(&policy, &core.handle(), request)).is_err());
+    }
+}
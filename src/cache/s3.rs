@@ -14,38 +14,57 @@
 
 use cache::{
     Cache,
-    CacheRead,
     CacheWrite,
     Storage,
+    env_flag,
+    multipart_threshold,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+    retry_policy,
 };
+use cache::retry::{RetryPolicy, retry};
+use cache::split::SplitStorage;
 use futures::future::{self, Future};
+use futures_cpupool::CpuPool;
 use simples3::{
     AutoRefreshingProvider,
     Bucket,
+    put_multipart,
     ChainProvider,
     ProfileProvider,
     ProvideAwsCredentials,
     Ssl,
 };
 use std::env;
-use std::io;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{Instant, Duration};
 use tokio_core::reactor::Handle;
 
 use errors::*;
 
+/// The size of parts in multipart uploads. S3 requires at least 5MB.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+
 /// A cache that stores entries in Amazon S3.
 pub struct S3Cache {
     /// The S3 bucket.
     bucket: Rc<Bucket>,
-    /// Credentials provider.
-    provider: AutoRefreshingProvider<ChainProvider>,
+    /// Credentials provider, or `None` to read the bucket anonymously.
+    provider: Option<AutoRefreshingProvider<ChainProvider>>,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+    /// Entries larger than this are stored with a multipart upload.
+    multipart_threshold: Option<usize>,
+    /// How failed requests are retried.
+    retry: RetryPolicy,
+    handle: Handle,
 }
 
 impl S3Cache {
     /// Create a new `S3Cache` storing data in `bucket`.
-    pub fn new(bucket: &str, endpoint: &str, handle: &Handle) -> Result<S3Cache> {
+    pub fn new(bucket: &str, endpoint: &str, ssl: Ssl, handle: &Handle) -> Result<S3Cache> {
         let home = env::home_dir().ok_or("Couldn't find home directory")?;
         let profile_providers = vec![
             ProfileProvider::with_configuration(home.join(".aws").join("credentials"), "default"),
@@ -55,13 +74,49 @@ impl S3Cache {
             ProfileProvider::with_configuration(home.join(".boto"), "Credentials"),
         ];
         let provider = AutoRefreshingProvider::new(ChainProvider::with_profile_providers(profile_providers, handle));
-        //TODO: configurable SSL
-        let bucket = Rc::new(Bucket::new(bucket, endpoint, Ssl::No, handle));
+        let bucket = Rc::new(Bucket::new(bucket, endpoint, ssl, handle));
         Ok(S3Cache {
             bucket: bucket,
-            provider: provider,
+            provider: Some(provider),
+            key_prefix: String::new(),
+            multipart_threshold: None,
+            retry: RetryPolicy::default(),
+            handle: handle.clone(),
         })
     }
+
+    /// Upload entries larger than `threshold` bytes with a parallel multipart upload.
+    pub fn set_multipart_threshold(&mut self, threshold: usize) {
+        self.multipart_threshold = Some(threshold);
+    }
+
+    /// Retry failed requests according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Store entries under `prefix`, to share the bucket with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+
+    /// Read the bucket without credentials, which requires it to allow
+    /// public reads, and don't store entries in it.
+    pub fn set_anonymous(&mut self) {
+        self.provider = None;
+    }
+}
+
+/// Split the scheme off `endpoint`, defaulting to plain HTTP without one.
+fn parse_endpoint(endpoint: &str) -> (Ssl, &str) {
+    let (ssl, endpoint) = if endpoint.starts_with("https://") {
+        (Ssl::Yes, &endpoint["https://".len()..])
+    } else if endpoint.starts_with("http://") {
+        (Ssl::No, &endpoint["http://".len()..])
+    } else {
+        (Ssl::No, endpoint)
+    };
+    (ssl, endpoint.trim_right_matches('/'))
 }
 
 fn normalize_key(key: &str) -> String {
@@ -70,17 +125,18 @@ fn normalize_key(key: &str) -> String {
 
 impl Storage for S3Cache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = normalize_key(key);
-        Box::new(self.bucket.get(&key).then(|result| {
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let bucket = self.bucket.clone();
+        let response = retry(&self.retry, &self.handle, move || bucket.get(&key));
+        Box::new(response.then(|result| {
             match result {
-                Ok(data) => {
-                    let hit = CacheRead::from(io::Cursor::new(data))?;
-                    Ok(Cache::Hit(hit))
-                }
-                Err(e) => {
-                    warn!("Got AWS error: {:?}", e);
+                Ok(data) => Ok(remote_hit(data)),
+                Err(Error(ErrorKind::BadHTTPStatus(status), _)) => {
+                    warn!("Got AWS error: HTTP status {}", status);
                     Ok(Cache::Miss)
                 }
+                // Anything else means the service couldn't be reached.
+                Err(e) => Err(e),
             }
         }))
     }
@@ -91,19 +147,31 @@ impl Storage for S3Cache {
     }
 
     fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = normalize_key(&key);
+        let key = prefix_key(&self.key_prefix, normalize_key(&key));
         let start = Instant::now();
         let data = match entry.finish() {
             Ok(data) => data,
             Err(e) => return future::err(e.into()).boxed(),
         };
-        let credentials = self.provider.credentials().chain_err(|| {
-            "failed to get AWS credentials"
-        });
+        let credentials = match self.provider {
+            Some(ref provider) => provider.credentials().chain_err(|| {
+                "failed to get AWS credentials"
+            }),
+            None => return future::err(ErrorKind::ReadOnlyCache.into()).boxed(),
+        };
 
         let bucket = self.bucket.clone();
+        let multipart = self.multipart_threshold.map_or(false, |threshold| data.len() > threshold);
+        let policy = self.retry.clone();
+        let handle = self.handle.clone();
         let response = credentials.and_then(move |credentials| {
-            bucket.put(&key, data, &credentials).chain_err(|| {
+            let put = if multipart {
+                // Parts are retried individually.
+                put_multipart(bucket, &key, data, &credentials, PART_SIZE)
+            } else {
+                retry(&policy, &handle, move || bucket.put(&key, data.clone(), &credentials))
+            };
+            put.chain_err(|| {
                 "failed to put cache entry in s3"
             })
         });
@@ -111,10 +179,68 @@ impl Storage for S3Cache {
         Box::new(response.map(move |_| start.elapsed()))
     }
 
+    fn is_read_only(&self) -> bool {
+        self.provider.is_none()
+    }
+
     fn location(&self) -> String {
-        format!("S3, bucket: {}", self.bucket)
+        let anonymous = if self.provider.is_none() { ", anonymous" } else { "" };
+        format!("S3, bucket: {}{}{}", self.bucket, prefix_location(&self.key_prefix), anonymous)
     }
 
     fn current_size(&self) -> Option<usize> { None }
     fn max_size(&self) -> Option<usize> { None }
 }
+
+/// Create an `S3Cache` from the environment, if `SCCACHE_BUCKET` is set.
+///
+/// With `SCCACHE_S3_PUBLIC_URL`, entries are read anonymously from that URL
+/// and written to the bucket.
+pub fn from_environment(_pool: &CpuPool, handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let bucket = match env::var("SCCACHE_BUCKET") {
+        Ok(bucket) => bucket,
+        Err(_) => return None,
+    };
+    let endpoint = match env::var("SCCACHE_ENDPOINT") {
+        Ok(endpoint) => format!("{}/{}", endpoint, bucket),
+        _ => match env::var("SCCACHE_REGION") {
+            Ok(ref region) if region != "us-east-1" =>
+                format!("{}.s3-{}.amazonaws.com", bucket, region),
+            _ => format!("{}.s3.amazonaws.com", bucket),
+        },
+    };
+    let anonymous = env_flag("SCCACHE_S3_NO_CREDENTIALS");
+    let cache = |endpoint: &str, anonymous: bool| -> Result<Arc<Storage>> {
+        debug!("Trying S3Cache({})", endpoint);
+        let (ssl, endpoint) = parse_endpoint(endpoint);
+        let mut s = S3Cache::new(&bucket, endpoint, ssl, handle)?;
+        if let Ok(prefix) = env::var("SCCACHE_S3_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        if anonymous {
+            s.set_anonymous();
+        }
+        s.set_retry_policy(retry_policy("S3"));
+        s.set_multipart_threshold(multipart_threshold());
+        Ok(Arc::new(s))
+    };
+    match env::var("SCCACHE_S3_PUBLIC_URL") {
+        Ok(public_url) => Some(cache(&public_url, true).and_then(|read| {
+            let write = cache(&endpoint, anonymous)?;
+            Ok(Arc::new(SplitStorage::new(read, write)) as Arc<Storage>)
+        })),
+        Err(_) => Some(cache(&endpoint, anonymous)),
+    }
+}
+
+#[test]
+fn test_parse_endpoint() {
+    match parse_endpoint("https://pub-abc.r2.dev/") {
+        (Ssl::Yes, "pub-abc.r2.dev") => {}
+        (_, e) => panic!("Unexpected endpoint: {}", e),
+    }
+    match parse_endpoint("localhost:9000/bucket") {
+        (Ssl::No, "localhost:9000/bucket") => {}
+        (_, e) => panic!("Unexpected endpoint: {}", e),
+    }
+}
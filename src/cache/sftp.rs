@@ -0,0 +1,414 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+};
+use futures::{Future, future};
+use futures_cpupool::CpuPool;
+use ssh2::{
+    self,
+    CheckResult,
+    KnownHostFileKind,
+    Session,
+    Sftp,
+};
+use std::env;
+use std::io::{
+    Read,
+    Write,
+};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio_core::reactor::Handle;
+use uuid::Uuid;
+
+use errors::*;
+
+/// The SFTP status code for a file that doesn't exist.
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+/// The port SSH servers listen on by default.
+const DEFAULT_PORT: u16 = 22;
+
+/// How long to wait for the server before giving up, in milliseconds.
+const TIMEOUT_MS: u32 = 5000;
+
+/// Where to store entries, parsed from `user@host:/path`.
+#[derive(Debug, PartialEq)]
+struct Target {
+    user: String,
+    host: String,
+    path: String,
+}
+
+impl Target {
+    /// Parse `target`, using `default_user` if it doesn't name a user.
+    fn parse(target: &str, default_user: &str) -> Result<Target> {
+        let (user, rest) = match target.find('@') {
+            Some(i) => (&target[..i], &target[i + 1..]),
+            None => (default_user, target),
+        };
+        let (host, path) = match rest.find(':') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => bail!("SFTP target must be of the form user@host:/path"),
+        };
+        if user.is_empty() || host.is_empty() || path.is_empty() {
+            bail!("SFTP target must be of the form user@host:/path");
+        }
+        Ok(Target {
+            user: user.to_owned(),
+            host: host.to_owned(),
+            path: path.trim_right_matches('/').to_owned(),
+        })
+    }
+}
+
+/// How to authenticate to the server.
+#[derive(Clone, Debug)]
+enum Auth {
+    /// Use the keys held by the running ssh-agent.
+    Agent,
+    /// Use the private key in this file.
+    KeyFile(PathBuf),
+}
+
+/// An authenticated SSH session.
+struct Connection {
+    // The session uses the socket, but doesn't own it.
+    _tcp: TcpStream,
+    session: Session,
+}
+
+impl Connection {
+    fn connect(target: &Target, port: u16, auth: &Auth, known_hosts: &Path) -> Result<Connection> {
+        let tcp = TcpStream::connect((&target.host[..], port)).chain_err(|| {
+            format!("failed to connect to SFTP server {}:{}", target.host, port)
+        })?;
+        let mut session = Session::new().ok_or("failed to create SSH session")?;
+        session.set_timeout(TIMEOUT_MS);
+        session.handshake(&tcp).chain_err(|| "SSH handshake failed")?;
+        Connection::check_host_key(&session, target, port, known_hosts)?;
+        match *auth {
+            Auth::Agent => session.userauth_agent(&target.user),
+            Auth::KeyFile(ref key) => session.userauth_pubkey_file(&target.user, None, key, None),
+        }.chain_err(|| format!("SSH authentication as {} failed", target.user))?;
+        Ok(Connection {
+            _tcp: tcp,
+            session: session,
+        })
+    }
+
+    /// Refuse to talk to servers whose key isn't in `known_hosts`.
+    fn check_host_key(session: &Session, target: &Target, port: u16, known_hosts: &Path) -> Result<()> {
+        let mut hosts = session.known_hosts().chain_err(|| "failed to read known hosts")?;
+        hosts.read_file(known_hosts, KnownHostFileKind::OpenSSH).chain_err(|| {
+            format!("failed to read known hosts from {}", known_hosts.display())
+        })?;
+        let (key, _) = session.host_key().ok_or("SSH server sent no host key")?;
+        match hosts.check_port(&target.host, port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => bail!("host key for {} doesn't match {}", target.host, known_hosts.display()),
+            CheckResult::NotFound => bail!("host {} isn't in {}", target.host, known_hosts.display()),
+            CheckResult::Failure => bail!("failed to check host key for {}", target.host),
+        }
+    }
+
+    fn sftp(&self) -> Result<Sftp> {
+        self.session.sftp().chain_err(|| "failed to start SFTP session")
+    }
+}
+
+/// Create the parent directories of `path`, which is relative to `root`.
+fn create_parents(sftp: &Sftp, root: &str, path: &str) -> Result<()> {
+    let mut dir = root.to_owned();
+    let parts = path.split('/').collect::<Vec<_>>();
+    for part in &parts[..parts.len() - 1] {
+        dir = format!("{}/{}", dir, part);
+        if sftp.stat(Path::new(&dir)).is_err() {
+            // Another client may have created it in the meantime.
+            if let Err(e) = sftp.mkdir(Path::new(&dir), 0o755) {
+                if sftp.stat(Path::new(&dir)).is_err() {
+                    return Err(e).chain_err(|| format!("failed to create {}", dir));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` could be a cache entry, a hex sha-1 digest, or, at
+/// `depth` levels above the entries, one of the directories they're
+/// spread over, named by a character of their key.
+fn is_entry_name(name: &str, depth: usize) -> bool {
+    let len = if depth == 0 { 40 } else { 1 };
+    name.len() == len && name.chars().all(|c| c.is_digit(16))
+}
+
+/// Remove the cache entries `depth` levels of directories below `dir`,
+/// returning the number of entries removed. Anything that isn't shaped
+/// like an entry is left alone, with the directories holding it.
+fn remove_entries(sftp: &Sftp, dir: &Path, depth: usize) -> Result<usize> {
+    let entries = sftp.readdir(dir).chain_err(|| format!("failed to list {}", dir.display()))?;
+    let mut removed = 0;
+    for (path, stat) in entries {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_owned();
+        if !is_entry_name(&name, depth) || stat.is_dir() != (depth > 0) {
+            continue
+        }
+        if depth > 0 {
+            removed += remove_entries(sftp, &path, depth - 1)?;
+            // Fails if anything else is left in it.
+            let _ = sftp.rmdir(&path);
+        } else {
+            sftp.unlink(&path).chain_err(|| format!("failed to remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// A cache that stores entries on a server over SFTP.
+#[derive(Clone)]
+pub struct SftpCache {
+    target: Arc<Target>,
+    port: u16,
+    auth: Auth,
+    /// The OpenSSH `known_hosts` file to check the server's key against.
+    known_hosts: PathBuf,
+    /// Thread pool to execute network I/O.
+    pool: CpuPool,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+}
+
+impl SftpCache {
+    /// Create a new `SftpCache` storing entries at `target`, of the form
+    /// `user@host:/path`, authenticating with the keys of the running ssh-agent.
+    pub fn new(target: &str, pool: &CpuPool) -> Result<SftpCache> {
+        let default_user = env::var("USER").unwrap_or_default();
+        let home = env::home_dir().ok_or("Couldn't find home directory")?;
+        Ok(SftpCache {
+            target: Arc::new(Target::parse(target, &default_user)?),
+            port: DEFAULT_PORT,
+            auth: Auth::Agent,
+            known_hosts: home.join(".ssh").join("known_hosts"),
+            pool: pool.clone(),
+            key_prefix: String::new(),
+        })
+    }
+
+    /// Connect to `port` instead of the default SSH port.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Authenticate with the private key in `key` instead of the ssh-agent.
+    pub fn set_key_file(&mut self, key: &Path) {
+        self.auth = Auth::KeyFile(key.to_owned());
+    }
+
+    /// Check the server's key against `known_hosts` instead of `~/.ssh/known_hosts`.
+    pub fn set_known_hosts(&mut self, known_hosts: &Path) {
+        self.known_hosts = known_hosts.to_owned();
+    }
+
+    /// Store entries under `prefix`, to share the server with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        Connection::connect(&self.target, self.port, &self.auth, &self.known_hosts)
+    }
+
+    fn get_data(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let conn = self.connect()?;
+        let sftp = conn.sftp()?;
+        let path = format!("{}/{}", self.target.path, key);
+        let mut file = match sftp.open(Path::new(&path)) {
+            Ok(file) => file,
+            Err(ref e) if e.code() == SFTP_NO_SUCH_FILE => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| format!("failed to open {}", path)),
+        };
+        let mut data = vec!();
+        file.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Store `data` at `key`, publishing it with a rename so that readers
+    /// never see a partially written entry.
+    fn put_data(&self, key: &str, data: &[u8]) -> Result<()> {
+        let conn = self.connect()?;
+        let sftp = conn.sftp()?;
+        create_parents(&sftp, &self.target.path, key)?;
+        let path = format!("{}/{}", self.target.path, key);
+        let tmp = format!("{}.tmp.{}", path, Uuid::new_v4().simple());
+        let written = sftp.create(Path::new(&tmp))
+            .chain_err(|| format!("failed to create {}", tmp))
+            .and_then(|mut file| file.write_all(data).map_err(|e| e.into()))
+            .and_then(|_| {
+                sftp.rename(Path::new(&tmp), Path::new(&path),
+                            Some(ssh2::OVERWRITE | ssh2::ATOMIC | ssh2::NATIVE))
+                    .chain_err(|| format!("failed to rename {} to {}", tmp, path))
+            });
+        if written.is_err() {
+            let _ = sftp.unlink(Path::new(&tmp));
+        }
+        written
+    }
+
+    /// Remove every entry under the key prefix, leaving the rest of the
+    /// server's directory alone, even without a prefix.
+    fn clear_data(&self) -> Result<usize> {
+        let conn = self.connect()?;
+        let sftp = conn.sftp()?;
+        let dir = if self.key_prefix.is_empty() {
+            self.target.path.clone()
+        } else {
+            format!("{}/{}", self.target.path, self.key_prefix)
+        };
+        match sftp.stat(Path::new(&dir)) {
+            // Entries are spread over three levels of directories, as
+            // `normalize_key` does.
+            Ok(_) => remove_entries(&sftp, Path::new(&dir), 3),
+            Err(ref e) if e.code() == SFTP_NO_SUCH_FILE => Ok(0),
+            Err(e) => Err(e).chain_err(|| format!("failed to open {}", dir)),
+        }
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+}
+
+impl Storage for SftpCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("SftpCache::get({})", key);
+        let me = self.clone();
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        self.pool.spawn_fn(move || {
+            match me.get_data(&key) {
+                Ok(Some(data)) => Ok(remote_hit(data)),
+                Ok(None) => Ok(Cache::Miss),
+                Err(e) => {
+                    warn!("Got SFTP error: {:?}", e);
+                    Err(e)
+                }
+            }
+        }).boxed()
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        trace!("SftpCache::start_put({})", key);
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        trace!("SftpCache::finish_put({})", key);
+        let me = self.clone();
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        self.pool.spawn_fn(move || {
+            let start = Instant::now();
+            let data = entry.finish()?;
+            me.put_data(&key, &data)?;
+            Ok(start.elapsed())
+        }).boxed()
+    }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> {
+        trace!("SftpCache::clear");
+        if !remote {
+            return future::ok(0).boxed();
+        }
+        let me = self.clone();
+        self.pool.spawn_fn(move || me.clear_data()).boxed()
+    }
+
+    fn location(&self) -> String {
+        format!("SFTP: {}@{}:{}{}", self.target.user, self.target.host, self.target.path,
+                prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create an `SftpCache` from the environment, if `SCCACHE_SFTP` is set.
+pub fn from_environment(pool: &CpuPool, _handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let target = match env::var("SCCACHE_SFTP") {
+        Ok(target) => target,
+        Err(_) => return None,
+    };
+    debug!("Trying SftpCache({})", target);
+    let port = match env::var("SCCACHE_SFTP_PORT") {
+        Ok(port) => match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return Some(Err(format!("Invalid SCCACHE_SFTP_PORT: {}", port).into())),
+        },
+        Err(_) => DEFAULT_PORT,
+    };
+    Some(SftpCache::new(&target, pool).map(|mut s| {
+        s.set_port(port);
+        if let Some(key) = env::var_os("SCCACHE_SFTP_KEY") {
+            s.set_key_file(Path::new(&key));
+        }
+        if let Some(known_hosts) = env::var_os("SCCACHE_SFTP_KNOWN_HOSTS") {
+            s.set_known_hosts(Path::new(&known_hosts));
+        }
+        if let Ok(prefix) = env::var("SCCACHE_SFTP_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        Arc::new(s) as Arc<Storage>
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(Target {
+            user: "build".to_owned(),
+            host: "files.example.com".to_owned(),
+            path: "/srv/sccache".to_owned(),
+        }, Target::parse("build@files.example.com:/srv/sccache/", "me").unwrap());
+        assert_eq!(Target {
+            user: "me".to_owned(),
+            host: "files".to_owned(),
+            path: "cache".to_owned(),
+        }, Target::parse("files:cache", "me").unwrap());
+        assert!(Target::parse("build@files.example.com", "me").is_err());
+        assert!(Target::parse("build@:/srv", "me").is_err());
+        assert!(Target::parse("files:/srv", "").is_err());
+    }
+
+    #[test]
+    fn test_is_entry_name() {
+        assert!(is_entry_name("a", 3));
+        assert!(!is_entry_name("ab", 2));
+        assert!(!is_entry_name("g", 1));
+        assert!(is_entry_name("da39a3ee5e6b4b0d3255bfef95601890afd80709", 0));
+        assert!(!is_entry_name("da39a3ee5e6b4b0d3255bfef95601890afd80709.tmp", 0));
+        assert!(!is_entry_name("a", 0));
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    GcStats,
+    Storage,
+};
+use cache::metered::BackendStats;
+use futures::future::{self, Future};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// A wrapper around another `Storage` that doesn't store entries larger
+/// than a given size.
+pub struct SizeLimitedStorage {
+    inner: Arc<Storage>,
+    /// The largest entry to store, in bytes.
+    max_entry_size: usize,
+}
+
+impl SizeLimitedStorage {
+    /// Create a new `SizeLimitedStorage` storing entries of at most
+    /// `max_entry_size` bytes in `inner`.
+    pub fn new(inner: Arc<Storage>, max_entry_size: usize) -> SizeLimitedStorage {
+        SizeLimitedStorage {
+            inner: inner,
+            max_entry_size: max_entry_size,
+        }
+    }
+}
+
+impl Storage for SizeLimitedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.inner.get(key)
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        self.inner.start_put(key)
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e).boxed(),
+        };
+        if data.len() > self.max_entry_size {
+            return future::err(ErrorKind::EntryTooLarge(data.len()).into()).boxed();
+        }
+        self.inner.finish_put(key, CacheWrite::from_bytes(data))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool { self.inner.is_read_only() }
+
+    fn location(&self) -> String { self.inner.location() }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> { self.inner.gc() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> { self.inner.clear(remote) }
+
+    fn keys(&self) -> SFuture<Vec<String>> { self.inner.keys() }
+
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> { self.inner.backend_stats() }
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> { self.inner.backends() }
+
+    fn current_size(&self) -> Option<usize> { self.inner.current_size() }
+    fn max_size(&self) -> Option<usize> { self.inner.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.inner.entry_count() }
+    fn background_writes(&self) -> usize { self.inner.background_writes() }
+    fn remote_location(&self) -> Option<String> { self.inner.remote_location() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::usize;
+    use test::utils::*;
+
+    #[test]
+    fn test_size_limit() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap());
+        let cache = SizeLimitedStorage::new(disk, 1024);
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"contents"[..]).unwrap();
+        cache.finish_put("abcdef", entry).wait().unwrap();
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        // Pseudo-random contents, so that they don't compress below the limit.
+        let mut x: u32 = 1;
+        let contents = (0..4096).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect::<Vec<_>>();
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..]).unwrap();
+        match cache.finish_put("ghijkl", entry).wait() {
+            Err(Error(ErrorKind::EntryTooLarge(size), _)) => assert!(size > 1024),
+            x => panic!("Unexpected write result: {:?}", x.map(|_| ())),
+        }
+        match cache.get("ghijkl").wait().unwrap() {
+            Cache::Miss => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// A cache that reads entries from one storage and writes them to another,
+/// such as a public, anonymously-readable endpoint and an authenticated one
+/// that only trusted builds can write to.
+///
+/// The two are expected to hold the same entries.
+pub struct SplitStorage {
+    read: Arc<Storage>,
+    write: Arc<Storage>,
+}
+
+impl SplitStorage {
+    /// Create a new `SplitStorage` reading from `read` and writing to `write`.
+    pub fn new(read: Arc<Storage>, write: Arc<Storage>) -> SplitStorage {
+        SplitStorage {
+            read: read,
+            write: write,
+        }
+    }
+}
+
+impl Storage for SplitStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.read.get(key)
+    }
+
+    fn start_put(&self, key: &str) -> Result<CacheWrite> {
+        self.write.start_put(key)
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        self.write.finish_put(key, entry)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.write.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool { self.write.is_read_only() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> { self.write.clear(remote) }
+
+    fn keys(&self) -> SFuture<Vec<String>> { self.read.keys() }
+
+    fn location(&self) -> String {
+        format!("{}, writing to {}", self.read.location(), self.write.location())
+    }
+
+    fn current_size(&self) -> Option<usize> { self.read.current_size() }
+    fn max_size(&self) -> Option<usize> { self.read.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.read.entry_count() }
+    fn background_writes(&self) -> usize { self.write.background_writes() }
+    fn remote_location(&self) -> Option<String> {
+        self.read.remote_location().map(|_| self.location())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use cache::readonly::ReadOnlyStorage;
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::usize;
+    use test::utils::*;
+
+    #[test]
+    fn test_split() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let read = Arc::new(DiskCache::new(&f.tempdir.path().join("read"), usize::MAX, &pool).unwrap());
+        let write = Arc::new(DiskCache::new(&f.tempdir.path().join("write"), usize::MAX, &pool).unwrap());
+        let cache = SplitStorage::new(read.clone(), write.clone());
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"contents"[..]).unwrap();
+        cache.finish_put("abcdef", entry).wait().unwrap();
+        // Writes go to one storage, and reads to the other.
+        match write.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            x => panic!("Unexpected cache result: {:?}", x),
+        }
+        let cache = SplitStorage::new(read, Arc::new(ReadOnlyStorage::new(write)));
+        assert!(cache.is_read_only());
+    }
+}
@@ -0,0 +1,333 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    GcStats,
+    Storage,
+};
+use cache::metered::BackendStats;
+use futures::Future;
+use futures::future;
+use futures_cpupool::CpuPool;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// How a `TwoTierCache` stores new entries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// Store entries in both tiers before the write completes.
+    Through,
+    /// Store entries in the local tier, and upload them to the remote tier
+    /// in the background.
+    Back,
+    /// Only store entries in the local tier, and only read from the remote one.
+    LocalOnly,
+}
+
+impl WritePolicy {
+    /// Parse a policy named `through`, `back` or `local`.
+    pub fn parse(policy: &str) -> Option<WritePolicy> {
+        match policy {
+            "through" => Some(WritePolicy::Through),
+            "back" => Some(WritePolicy::Back),
+            "local" => Some(WritePolicy::LocalOnly),
+            _ => None,
+        }
+    }
+}
+
+/// A cache that checks a fast local cache before a slower remote one, and
+/// stores entries in both, depending on its `WritePolicy`.
+///
+/// Entries found in the remote cache are copied into the local one, so that
+/// later lookups for them don't have to go to the remote cache again.
+pub struct TwoTierCache {
+    /// The local cache, checked first.
+    local: Arc<Storage>,
+    /// The remote cache, checked on local misses.
+    remote: Arc<Storage>,
+    /// Thread pool to copy remote hits on.
+    pool: CpuPool,
+    /// Event loop to run background uploads on.
+    handle: Handle,
+    policy: WritePolicy,
+    /// The number of write-back uploads in progress.
+    uploads: Rc<Cell<usize>>,
+}
+
+impl TwoTierCache {
+    /// Create a new `TwoTierCache` in front of `remote`, using `local` as the
+    /// first tier and writing entries through to both.
+    pub fn new(local: Arc<Storage>, remote: Arc<Storage>, pool: &CpuPool, handle: &Handle) -> TwoTierCache {
+        TwoTierCache {
+            local: local,
+            remote: remote,
+            pool: pool.clone(),
+            handle: handle.clone(),
+            policy: WritePolicy::Through,
+            uploads: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Store new entries according to `policy`.
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.policy = policy;
+    }
+
+    fn writes_remote(&self) -> bool {
+        self.policy != WritePolicy::LocalOnly && !self.remote.is_read_only()
+    }
+}
+
+/// Copy `entry`, found in the remote cache under `key`, into `local`.
+///
+/// Resolves to `entry` once it has been stored; failing to store it only
+/// loses the copy.
+fn promote(local: Arc<Storage>, key: String, entry: CacheRead, pool: &CpuPool) -> SFuture<Cache> {
+    if local.is_read_only() {
+        return future::ok(Cache::Hit(entry)).boxed();
+    }
+    // Corrupt entries aren't copied, and are dealt with by the caller.
+    let copy = pool.spawn_fn(move || -> Result<_> {
+        let mut entry = entry;
+        let write = entry.verify().and_then(|()| entry.to_write());
+        Ok((entry, write))
+    });
+    Box::new(copy.and_then(move |(entry, write)| -> SFuture<Cache> {
+        let write = match write {
+            Ok(write) => write,
+            Err(e) => {
+                debug!("Not copying remote cache entry {}: {}", key, e);
+                return future::ok(Cache::Hit(entry)).boxed();
+            }
+        };
+        Box::new(local.finish_put(&key, write).then(move |result| {
+            match result {
+                Ok(_) => trace!("TwoTierCache: copied remote hit {} to local cache", key),
+                Err(e) => warn!("Failed to copy remote cache entry {}: {}", key, e),
+            }
+            Ok(Cache::Hit(entry))
+        }))
+    }))
+}
+
+impl Storage for TwoTierCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let local = self.local.clone();
+        let remote = self.remote.clone();
+        let pool = self.pool.clone();
+        let key = key.to_owned();
+        Box::new(self.local.get(&key).then(move |result| -> SFuture<Cache> {
+            match result {
+                Ok(Cache::Hit(hit)) => {
+                    trace!("TwoTierCache: local hit");
+                    return future::ok(Cache::Hit(hit)).boxed();
+                }
+                Ok(Cache::Recache) => return future::ok(Cache::Recache).boxed(),
+                Ok(Cache::Miss) | Ok(Cache::Unavailable) => {}
+                Err(e) => warn!("Local cache error: {:?}", e),
+            }
+            Box::new(remote.get(&key).and_then(move |result| -> SFuture<Cache> {
+                match result {
+                    Cache::Hit(entry) => promote(local, key, entry, &pool),
+                    other => future::ok(other).boxed(),
+                }
+            }))
+        }))
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e).boxed(),
+        };
+        // Only write to the tiers that accept writes.
+        let put = |storage: &Arc<Storage>, writes: bool| -> SFuture<Duration> {
+            if !writes {
+                future::ok(Duration::new(0, 0)).boxed()
+            } else {
+                // A tier that can't be reached shouldn't stop writes to the other.
+                Box::new(storage.finish_put(key, CacheWrite::from_bytes(data.clone())).or_else(|e| {
+                    match e {
+                        Error(ErrorKind::CacheUnavailable, _) => Ok(Duration::new(0, 0)),
+                        e => Err(e),
+                    }
+                }))
+            }
+        };
+        let local = put(&self.local, !self.local.is_read_only());
+        let remote = put(&self.remote, self.writes_remote());
+        match self.policy {
+            WritePolicy::Through | WritePolicy::LocalOnly => {
+                Box::new(local.join(remote).map(|(local, remote)| cmp::max(local, remote)))
+            }
+            WritePolicy::Back => {
+                let key = key.to_owned();
+                let uploads = self.uploads.clone();
+                uploads.set(uploads.get() + 1);
+                self.handle.spawn(remote.then(move |result| {
+                    uploads.set(uploads.get() - 1);
+                    match result {
+                        Ok(_) => trace!("TwoTierCache: uploaded {} to remote cache", key),
+                        Err(e) => warn!("Failed to upload cache entry {}: {}", key, e),
+                    }
+                    Ok(())
+                }));
+                local
+            }
+        }
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        Box::new(self.local.remove(key).join(self.remote.remove(key)).map(|_| ()))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.local.is_read_only() && !self.writes_remote()
+    }
+
+    fn gc(&self) -> SFuture<Option<GcStats>> { self.local.gc() }
+
+    fn clear(&self, remote: bool) -> SFuture<usize> {
+        let local = self.local.clear(false);
+        if remote {
+            Box::new(local.join(self.remote.clear(true)).map(|(local, remote)| local + remote))
+        } else {
+            local
+        }
+    }
+
+    fn backend_stats(&self) -> Vec<Rc<RefCell<BackendStats>>> {
+        let mut stats = self.local.backend_stats();
+        stats.extend(self.remote.backend_stats());
+        stats
+    }
+
+    fn backends(&self) -> Vec<(String, Arc<Storage>)> {
+        let mut backends = self.local.backends();
+        backends.extend(self.remote.backends());
+        backends
+    }
+
+    fn location(&self) -> String {
+        let policy = match self.policy {
+            WritePolicy::Through => "",
+            WritePolicy::Back => " (write-back)",
+            WritePolicy::LocalOnly => " (not written to)",
+        };
+        format!("{}, in front of {}{}", self.local.location(), self.remote.location(), policy)
+    }
+
+    fn current_size(&self) -> Option<usize> { self.local.current_size() }
+    fn max_size(&self) -> Option<usize> { self.local.max_size() }
+    fn entry_count(&self) -> Option<usize> { self.local.entry_count() }
+    fn background_writes(&self) -> usize {
+        self.uploads.get() + self.local.background_writes() + self.remote.background_writes()
+    }
+    fn remote_location(&self) -> Option<String> { self.remote.remote_location() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::{Cache, CacheWrite, Storage};
+    use cache::disk::DiskCache;
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::sync::Arc;
+    use std::usize;
+    use test::utils::*;
+    use tokio_core::reactor::Core;
+
+    fn put(storage: &Storage, key: &str, contents: &[u8]) {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..]).unwrap();
+        storage.finish_put(key, entry).wait().unwrap();
+    }
+
+    fn get(storage: &Storage, key: &str) -> Option<Vec<u8>> {
+        match storage.get(key).wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut v = vec!();
+                entry.get_object("obj", &mut v).unwrap();
+                Some(v)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_two_tier() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let local = Arc::new(DiskCache::new(&f.tempdir.path().join("local"), usize::MAX, &pool).unwrap());
+        let remote = Arc::new(DiskCache::new(&f.tempdir.path().join("remote"), usize::MAX, &pool).unwrap());
+        let cache = TwoTierCache::new(local.clone(), remote.clone(), &pool, &core.handle());
+        // Entries in either tier are found.
+        put(&*local, "aaaa", b"local");
+        put(&*remote, "bbbb", b"remote");
+        assert_eq!(Some(b"local".to_vec()), get(&cache, "aaaa"));
+        assert_eq!(Some(b"remote".to_vec()), get(&cache, "bbbb"));
+        assert_eq!(None, get(&cache, "cccc"));
+        // Remote hits are copied into the local tier.
+        assert_eq!(Some(b"remote".to_vec()), get(&*local, "bbbb"));
+        // New entries are stored in both tiers.
+        put(&cache, "dddd", b"both");
+        assert_eq!(Some(b"both".to_vec()), get(&*local, "dddd"));
+        assert_eq!(Some(b"both".to_vec()), get(&*remote, "dddd"));
+    }
+
+    #[test]
+    fn test_write_policy() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut core = Core::new().unwrap();
+        let local = Arc::new(DiskCache::new(&f.tempdir.path().join("local"), usize::MAX, &pool).unwrap());
+        let remote = Arc::new(DiskCache::new(&f.tempdir.path().join("remote"), usize::MAX, &pool).unwrap());
+        let mut cache = TwoTierCache::new(local.clone(), remote.clone(), &pool, &core.handle());
+        // Local-only writes leave the remote tier alone.
+        cache.set_write_policy(WritePolicy::LocalOnly);
+        put(&cache, "aaaa", b"local");
+        assert_eq!(Some(b"local".to_vec()), get(&*local, "aaaa"));
+        assert_eq!(None, get(&*remote, "aaaa"));
+        // Write-back uploads once the event loop runs.
+        cache.set_write_policy(WritePolicy::Back);
+        put(&cache, "bbbb", b"back");
+        assert_eq!(Some(b"back".to_vec()), get(&*local, "bbbb"));
+        // The upload is counted until it finishes.
+        assert_eq!(1, cache.background_writes());
+        for _ in 0..100 {
+            if cache.background_writes() == 0 {
+                break;
+            }
+            core.turn(Some(Duration::from_millis(10)));
+        }
+        assert_eq!(Some(b"back".to_vec()), get(&*remote, "bbbb"));
+        assert_eq!(None, WritePolicy::parse("sometimes"));
+    }
+}
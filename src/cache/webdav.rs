@@ -0,0 +1,294 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    Storage,
+    prefix_key,
+    prefix_location,
+    remote_hit,
+    retry_policy,
+};
+use cache::readonly::ReadOnlyStorage;
+use cache::retry::{RetryPolicy, retry};
+use cache::split::SplitStorage;
+use futures::{Future, Stream};
+use futures::future;
+use futures_cpupool::CpuPool;
+use http::{HttpsClient, https_client, parse_base_url};
+use hyper::{self, header, Method, Url};
+use hyper::client::Request;
+use hyper::status::{StatusClass, StatusCode};
+use std::env;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// How to authenticate to the server.
+#[derive(Clone)]
+pub enum WebdavAuth {
+    None,
+    Basic { username: String, password: Option<String> },
+    Bearer(String),
+}
+
+/// An HTTP server that accepts GET and PUT of arbitrary paths.
+struct Server {
+    base_url: Url,
+    auth: WebdavAuth,
+    client: HttpsClient,
+}
+
+impl Server {
+    fn request(&self, method: Method, path: &str) -> Request {
+        let mut url = self.base_url.clone();
+        // `WebdavCache::new` checked that the base URL can have a path.
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(path.split('/'));
+        }
+        debug!("{} {}", method, url);
+        let mut request = Request::new(method, url);
+        match self.auth {
+            WebdavAuth::None => {}
+            WebdavAuth::Basic { ref username, ref password } => {
+                request.headers_mut().set(header::Authorization(header::Basic {
+                    username: username.clone(),
+                    password: password.clone(),
+                }));
+            }
+            WebdavAuth::Bearer(ref token) => {
+                request.headers_mut().set(header::Authorization(header::Bearer {
+                    token: token.clone(),
+                }));
+            }
+        }
+        request
+    }
+
+    fn get(&self, path: &str) -> SFuture<Option<Vec<u8>>> {
+        let request = self.request(Method::Get, path);
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed GET from WebDAV server"
+        }).and_then(|res| -> SFuture<Option<Vec<u8>>> {
+            let status = res.status().clone();
+            if status == StatusCode::NotFound {
+                return future::ok(None).boxed();
+            }
+            if status.class() != StatusClass::Success {
+                return future::err(ErrorKind::BadHTTPStatus(status).into()).boxed();
+            }
+            Box::new(res.body().fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            }).map(Some))
+        }))
+    }
+
+    /// PUT `content` at `path`, returning the HTTP status.
+    fn put(&self, path: &str, content: Vec<u8>) -> SFuture<StatusCode> {
+        let mut request = self.request(Method::Put, path);
+        request.headers_mut().set(header::ContentType("application/octet-stream".parse().unwrap()));
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed PUT to WebDAV server"
+        }).map(|res| res.status().clone()))
+    }
+
+    /// Create the collection at `path`. Failure is ignored, since the
+    /// collection may already exist.
+    fn mkcol(&self, path: &str) -> SFuture<()> {
+        let request = self.request(Method::Extension("MKCOL".to_owned()), path);
+        Box::new(self.client.request(request).then(|_| Ok(())))
+    }
+}
+
+/// A cache that stores entries on a WebDAV or plain HTTP server via GET and PUT.
+pub struct WebdavCache {
+    server: Rc<Server>,
+    /// Prefix for the keys of all entries.
+    key_prefix: String,
+    /// How failed requests are retried.
+    retry: RetryPolicy,
+    handle: Handle,
+}
+
+impl WebdavCache {
+    /// Create a new `WebdavCache` storing data under `base_url`.
+    pub fn new(base_url: &str, auth: WebdavAuth, handle: &Handle) -> Result<WebdavCache> {
+        let base_url = parse_base_url(base_url, "WebDAV endpoint")?;
+        Ok(WebdavCache {
+            server: Rc::new(Server {
+                base_url: base_url,
+                auth: auth,
+                client: https_client(handle),
+            }),
+            key_prefix: String::new(),
+            retry: RetryPolicy::default(),
+            handle: handle.clone(),
+        })
+    }
+
+    /// Retry failed requests according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Store entries under `prefix`, to share the server with other caches.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.trim_matches('/').to_owned();
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+}
+
+/// The parent collections of `path`, outermost first.
+fn parent_collections(path: &str) -> Vec<String> {
+    let parts = path.split('/').collect::<Vec<_>>();
+    (1..parts.len()).map(|n| parts[..n].join("/")).collect()
+}
+
+/// Store `data` at `key` on `server`, creating collections as needed.
+fn put_entry(server: Rc<Server>, key: String, data: Vec<u8>) -> SFuture<()> {
+    // Plain HTTP servers usually create intermediate directories on PUT,
+    // but WebDAV servers answer 409 Conflict until the collections exist.
+    Box::new(server.put(&key, data.clone()).and_then(move |status| -> SFuture<StatusCode> {
+        if status != StatusCode::Conflict {
+            return future::ok(status).boxed();
+        }
+        trace!("PUT returned Conflict, creating collections");
+        let mut mkcols: SFuture<()> = future::ok(()).boxed();
+        for collection in parent_collections(&key) {
+            let server = server.clone();
+            mkcols = Box::new(mkcols.and_then(move |_| server.mkcol(&collection)));
+        }
+        Box::new(mkcols.and_then(move |_| server.put(&key, data)))
+    }).and_then(|status| {
+        if status.class() == StatusClass::Success {
+            trace!("PUT succeeded");
+            Ok(())
+        } else {
+            trace!("PUT failed with HTTP status: {}", status);
+            Err(ErrorKind::BadHTTPStatus(status).into())
+        }
+    }))
+}
+
+impl Storage for WebdavCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = prefix_key(&self.key_prefix, normalize_key(key));
+        let server = self.server.clone();
+        let response = retry(&self.retry, &self.handle, move || server.get(&key));
+        Box::new(response.then(|result| {
+            match result {
+                Ok(Some(data)) => Ok(remote_hit(data)),
+                Ok(None) => Ok(Cache::Miss),
+                Err(Error(ErrorKind::BadHTTPStatus(status), _)) => {
+                    warn!("Got WebDAV error: HTTP status {}", status);
+                    Ok(Cache::Miss)
+                }
+                // Anything else means the service couldn't be reached.
+                Err(e) => Err(e),
+            }
+        }))
+    }
+
+    fn start_put(&self, _key: &str) -> Result<CacheWrite> {
+        // Just hand back an in-memory buffer.
+        Ok(CacheWrite::new())
+    }
+
+    fn finish_put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let key = prefix_key(&self.key_prefix, normalize_key(&key));
+        let start = Instant::now();
+        let data = match entry.finish() {
+            Ok(data) => data,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+        let server = self.server.clone();
+        let response = retry(&self.retry, &self.handle, move || {
+            put_entry(server.clone(), key.clone(), data.clone())
+        });
+        Box::new(response.map(move |_| start.elapsed()))
+    }
+
+    fn location(&self) -> String {
+        format!("WebDAV, url: {}{}", self.server.base_url, prefix_location(&self.key_prefix))
+    }
+
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { None }
+}
+
+/// Create a `WebdavCache` from the environment, if `SCCACHE_WEBDAV_ENDPOINT` is set.
+///
+/// If `SCCACHE_WEBDAV_READ_ENDPOINT` is also set, entries are read from it
+/// without credentials, and only written to `SCCACHE_WEBDAV_ENDPOINT` if
+/// credentials are given.
+pub fn from_environment(_pool: &CpuPool, handle: &Handle) -> Option<Result<Arc<Storage>>> {
+    let endpoint = match env::var("SCCACHE_WEBDAV_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => return None,
+    };
+    let auth = match (env::var("SCCACHE_WEBDAV_TOKEN"), env::var("SCCACHE_WEBDAV_USERNAME")) {
+        (Ok(token), _) => WebdavAuth::Bearer(token),
+        (_, Ok(username)) => WebdavAuth::Basic {
+            username: username,
+            password: env::var("SCCACHE_WEBDAV_PASSWORD").ok(),
+        },
+        _ => WebdavAuth::None,
+    };
+    let cache = |endpoint: &str, auth: WebdavAuth| -> Result<Arc<Storage>> {
+        debug!("Trying WebdavCache({})", endpoint);
+        let mut s = WebdavCache::new(endpoint, auth, handle)?;
+        if let Ok(prefix) = env::var("SCCACHE_WEBDAV_KEY_PREFIX") {
+            s.set_key_prefix(&prefix);
+        }
+        s.set_retry_policy(retry_policy("WEBDAV"));
+        Ok(Arc::new(s))
+    };
+    match env::var("SCCACHE_WEBDAV_READ_ENDPOINT") {
+        Ok(read_endpoint) => {
+            let write_only_with_auth = match auth {
+                WebdavAuth::None => true,
+                _ => false,
+            };
+            Some(cache(&read_endpoint, WebdavAuth::None).and_then(|read| {
+                let write = cache(&endpoint, auth)?;
+                let write = if write_only_with_auth {
+                    debug!("No WebDAV credentials, not writing to {}", endpoint);
+                    Arc::new(ReadOnlyStorage::new(write)) as Arc<Storage>
+                } else {
+                    write
+                };
+                Ok(Arc::new(SplitStorage::new(read, write)) as Arc<Storage>)
+            }))
+        }
+        Err(_) => Some(cache(&endpoint, auth)),
+    }
+}
+
+#[test]
+fn test_parent_collections() {
+    assert_eq!(vec!["a", "a/b", "a/b/c"], parent_collections("a/b/c/abcdef"));
+    assert!(parent_collections("abcdef").is_empty());
+}
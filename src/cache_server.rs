@@ -0,0 +1,144 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serving the configured storage to other sccache instances over HTTP.
+//!
+//! Other instances use it as their WebDAV storage, which GETs and PUTs
+//! cache entries at paths ending in their key, presenting a bearer token.
+//! Entries are checked before they're stored, so that a client can't
+//! store anything but cache entries, and entries larger than
+//! `SCCACHE_MAX_ENTRY_SIZE` are refused before they're read.
+
+use cache::{Cache, CacheRead, CacheWrite, Storage, storage_from_environment};
+use futures::{Future, future};
+use futures_cpupool::CpuPool;
+use http_server::{self, RequestHead, Response, Tokens};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+use errors::*;
+
+/// Configuration of the HTTP cache server.
+pub struct Config {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// The tokens clients present.
+    pub tokens: Tokens,
+    /// The largest cache entry clients may store.
+    pub max_entry_size: usize,
+}
+
+impl Config {
+    /// Listen on `listen`, with the tokens in `SCCACHE_SERVER_HTTP_TOKEN`
+    /// and `SCCACHE_SERVER_HTTP_READ_TOKEN`, and the size limit in
+    /// `SCCACHE_MAX_ENTRY_SIZE`.
+    pub fn from_environment(listen: &str) -> Result<Config> {
+        let listen = listen.parse::<SocketAddr>()
+            .chain_err(|| format!("invalid address to listen on: {}", listen))?;
+        let tokens = match Tokens::from_environment("SCCACHE_SERVER_HTTP_TOKEN",
+                                                    "SCCACHE_SERVER_HTTP_READ_TOKEN")? {
+            Some(tokens) => tokens,
+            None => bail!("SCCACHE_SERVER_HTTP_TOKEN must be set to the token clients present"),
+        };
+        Ok(Config {
+            listen: listen,
+            tokens: tokens,
+            max_entry_size: http_server::max_body_from_environment(),
+        })
+    }
+}
+
+/// The cache key at the end of a request path, such as
+/// `/prefix/a/b/c/<key>`. Keys are hex sha-1 digests.
+fn path_key(path: &str) -> Option<&str> {
+    match path.rsplit('/').next() {
+        Some(key) if key.len() == 40 && key.chars().all(|c| c.is_digit(16)) => Some(key),
+        _ => None,
+    }
+}
+
+/// Serve the configured storage to other sccache instances, until the
+/// process is killed.
+pub fn run(config: Config) -> Result<()> {
+    let mut core = Core::new()?;
+    let pool = CpuPool::new(20);
+    let storage = storage_from_environment(&pool, &core.handle())?;
+    let listener = TcpListener::bind(&config.listen, &core.handle())
+        .chain_err(|| format!("couldn't listen on {}", config.listen))?;
+    info!("Serving {} on http://{}", storage.location(), listener.local_addr()?);
+    let max_body = config.max_entry_size;
+    let tokens = config.tokens;
+    http_server::serve(&mut core, listener, max_body,
+                       Rc::new(move |head: &RequestHead| tokens.check(head)),
+                       Rc::new(move |head, body| handle(head, body, storage.clone(), pool.clone())))
+}
+
+/// Handle a request for a cache entry, which `Tokens::check` has allowed.
+fn handle(head: RequestHead, body: Vec<u8>, storage: Arc<Storage>, pool: CpuPool) -> SFuture<Response> {
+    match &head.method[..] {
+        // The WebDAV storage creates collections when a PUT conflicts,
+        // which never happens here.
+        "MKCOL" => return future::ok(Response::new("201 Created")).boxed(),
+        "GET" | "PUT" => {}
+        _ => return future::ok(Response::new("405 Method Not Allowed")).boxed(),
+    }
+    let key = match path_key(&head.path) {
+        Some(key) => key.to_owned(),
+        None => return future::ok(Response::new("404 Not Found")).boxed(),
+    };
+    if head.method == "GET" {
+        return Box::new(storage.get(&key).and_then(move |result| -> SFuture<Response> {
+            match result {
+                Cache::Hit(mut entry) => Box::new(pool.spawn_fn(move || {
+                    let data = entry.to_write()?.finish()?;
+                    Ok(Response { status: "200 OK", body: data })
+                })),
+                Cache::Miss | Cache::Recache => future::ok(Response::new("404 Not Found")).boxed(),
+                Cache::Unavailable => future::ok(Response::new("503 Service Unavailable")).boxed(),
+            }
+        }))
+    }
+    if head.content_length.is_none() {
+        return future::ok(Response::new("411 Length Required")).boxed();
+    }
+    Box::new(pool.spawn_fn(move || {
+        let valid = CacheRead::from(Cursor::new(body.clone())).and_then(|mut entry| entry.verify());
+        Ok::<_, Error>((valid, body))
+    }).and_then(move |(valid, body)| -> SFuture<Response> {
+        if let Err(e) = valid {
+            debug!("Refusing invalid cache entry {}: {}", key, e);
+            return future::ok(Response::new("400 Bad Request")).boxed();
+        }
+        Box::new(storage.finish_put(&key, CacheWrite::from_bytes(body)).then(Response::stored))
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_key() {
+        let key = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+        assert_eq!(Some(key), path_key(&format!("/d/a/3/{}", key)));
+        assert_eq!(Some(key), path_key(&format!("/prefix/d/a/3/{}", key)));
+        assert_eq!(None, path_key("/a/b/c/abcdef"));
+        assert_eq!(None, path_key("/a/b/c/"));
+        assert_eq!(None, path_key("/a/b/c/../etc"));
+    }
+}
@@ -12,18 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use protobuf::{
-    CodedInputStream,
-    Message,
-    ProtobufError,
-    ProtobufResult,
-    parse_from_bytes,
-};
 use protocol::{
-    ClientRequest,
-    ServerResponse,
+    HEADER_LEN,
+    LEGACY_SHUTDOWN,
+    PROTOCOL_VERSION,
+    Request,
+    Response,
+    check_message_len,
+    decode,
+    encode,
+    parse_header,
 };
 use retry::retry;
+use server::{SHARED_USER, ServerAddr, current_user, server_id};
+use std::error;
+use std::fmt;
 use std::io::{
     self,
     BufReader,
@@ -31,71 +34,228 @@ use std::io::{
     Read,
     Write,
 };
-use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
 
 /// A connection to an sccache server.
 pub struct ServerConnection {
     /// A reader for the socket connected to the server.
-    reader : BufReader<TcpStream>,
+    reader : BufReader<Box<Read>>,
     /// A writer for the socket connected to the server.
-    writer : BufWriter<TcpStream>,
+    writer : BufWriter<Box<Write>>,
 }
 
 impl ServerConnection {
     /// Create a new connection using `stream`.
     pub fn new(stream : TcpStream) -> io::Result<ServerConnection> {
         let writer = try!(stream.try_clone());
-        Ok(ServerConnection {
-            reader : BufReader::new(stream),
+        Ok(ServerConnection::from_parts(Box::new(stream), Box::new(writer)))
+    }
+
+    /// Create a new connection using the unix domain socket `stream`.
+    #[cfg(unix)]
+    pub fn from_unix(stream : UnixStream) -> io::Result<ServerConnection> {
+        let writer = try!(stream.try_clone());
+        Ok(ServerConnection::from_parts(Box::new(stream), Box::new(writer)))
+    }
+
+    fn from_parts(reader : Box<Read>, writer : Box<Write>) -> ServerConnection {
+        ServerConnection {
+            reader : BufReader::new(reader),
             writer : BufWriter::new(writer),
-        })
+        }
     }
 
-    /// Send `request` to the server, read and return a `ServerResponse`.
-    pub fn request(&mut self, request : ClientRequest)
-                   -> ProtobufResult<ServerResponse> {
+    /// Send `request` to the server, read and return a `Response`.
+    pub fn request(&mut self, request : Request) -> io::Result<Response> {
         trace!("ServerConnection::request");
-        try!(request.write_length_delimited_to_writer(&mut self.writer));
-        try!(self.writer.flush().or_else(|e| Err(ProtobufError::IoError(e))));
+        let mut buf = vec!();
+        try!(encode(&request, &mut buf));
+        try!(self.writer.write_all(&buf));
+        try!(self.writer.flush());
         trace!("ServerConnection::request: sent request");
         self.read_one_response()
     }
 
-    /// Read a single `ServerResponse` from the server.
-    pub fn read_one_response(&mut self) -> ProtobufResult<ServerResponse> {
+    /// Ask a server of protocol version 1, which predates frame headers,
+    /// to shut down, and wait for it to respond.
+    pub fn legacy_shutdown(&mut self) -> io::Result<()> {
+        try!(self.writer.write_all(LEGACY_SHUTDOWN));
+        try!(self.writer.flush());
+        // The response doesn't matter, only that it was sent.
+        try!(self.reader.read(&mut [0]));
+        Ok(())
+    }
+
+    /// Read a single `Response` from the server.
+    ///
+    /// Fails with a `VersionMismatch` error if the server speaks another
+    /// protocol version.
+    pub fn read_one_response(&mut self) -> io::Result<Response> {
         trace!("ServerConnection::read_one_response");
-        //FIXME: wish `parse_length_delimited_from` worked here!
-        let len = try!({
-            let mut is = CodedInputStream::from_buffered_reader(&mut self.reader);
-            is.read_raw_varint32()
-        });
+        let mut header = [0; HEADER_LEN];
+        try!(self.reader.read_exact(&mut header));
+        let (version, len) = parse_header(&header);
+        if version != PROTOCOL_VERSION {
+            let version = format!("a version speaking protocol {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, VersionMismatch(version)));
+        }
+        try!(check_message_len(len));
         trace!("Should read {} more bytes", len);
-        let mut buf = vec![0; len as usize];
-        try!(self.reader.read_exact(&mut buf).or_else(|e| Err(ProtobufError::IoError(e))));
+        let mut buf = vec![0; len];
+        try!(self.reader.read_exact(&mut buf));
         trace!("Done reading");
-        parse_from_bytes::<ServerResponse>(&buf)
+        decode(&buf)
+    }
+}
+
+/// The server speaks a different version of the protocol, most likely
+/// because sccache was upgraded while it was running.
+#[derive(Debug)]
+struct VersionMismatch(String);
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The sccache server is {}, not {}", self.0, env!("CARGO_PKG_VERSION"))
+    }
+}
+
+impl error::Error for VersionMismatch {
+    fn description(&self) -> &str {
+        "the sccache server speaks a different protocol version"
+    }
+}
+
+/// Whether `e` is from connecting to a server speaking a different
+/// protocol version, which should be replaced.
+pub fn is_version_mismatch(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |e| e.is::<VersionMismatch>())
+}
+
+/// Whether `e` is from the server going away, for example because it
+/// exited or was replaced, rather than from what was sent to it.
+pub fn is_connection_lost(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
+
+/// Check that the server at the other end of `conn` speaks our protocol
+/// version, is listening on `addr` and was started by the current user, so
+/// that clients don't use servers configured for someone else.
+///
+/// Fails with `io::ErrorKind::InvalidData` if it isn't.
+fn check_server_info(conn: &mut ServerConnection, addr: &ServerAddr) -> io::Result<()> {
+    let info = match conn.request(Request::GetServerInfo) {
+        Ok(Response::ServerInfo(info)) => info,
+        Ok(_) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Unexpected response from the sccache server"));
+        }
+        // Servers of protocol version 1 hang up on frames they can't parse.
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            let version = "an older version".to_owned();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, VersionMismatch(version)));
+        }
+        Err(e) => return Err(e),
+    };
+    let mismatch = |what: String| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("The sccache server at {} {}; set SCCACHE_SERVER_PORT \
+                                or SCCACHE_SERVER_UDS to use a different server", addr, what))
+    };
+    let (bind_address, port) = addr.info();
+    if info.bind_address != bind_address || info.port != port {
+        return Err(mismatch(format!("is listening on {}:{}", info.bind_address, info.port)));
+    }
+    let user = current_user();
+    // Any user may use a server run as a system service.
+    if info.user != user && info.user != SHARED_USER {
+        return Err(mismatch(format!("belongs to user {}", info.user)));
+    }
+    if info.instance != server_id() {
+        return Err(match info.instance {
+            Some(instance) => mismatch(format!("is instance {}", instance)),
+            None => mismatch("is the default instance".to_owned()),
+        });
+    }
+    Ok(())
+}
+
+/// Establish a connection to whatever is listening on `addr`, without
+/// checking that it's a server this client can use.
+pub fn connect(addr: &ServerAddr) -> io::Result<ServerConnection> {
+    connect_timeout(addr, None)
+}
+
+/// Like `connect`, but reading a response fails with `WouldBlock` or
+/// `TimedOut` if the server takes longer than `timeout` to send it.
+pub fn connect_timeout(addr: &ServerAddr, timeout: Option<Duration>) -> io::Result<ServerConnection> {
+    match *addr {
+        ServerAddr::Tcp(ref addr) => {
+            // A server listening on all interfaces is reached on the loopback one.
+            let mut target = *addr;
+            match addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => target.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+                IpAddr::V6(ip) if ip.is_unspecified() => target.set_ip(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))),
+                _ => {}
+            }
+            let stream = try!(TcpStream::connect(target));
+            try!(stream.set_read_timeout(timeout));
+            ServerConnection::new(stream)
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(ref path) => {
+            let stream = try!(UnixStream::connect(path));
+            try!(stream.set_read_timeout(timeout));
+            ServerConnection::from_unix(stream)
+        }
+    }
+}
+
+/// Whether a server of an instance other than this client's is listening
+/// on `addr`. Anything else there, or nothing, is left for the client to
+/// connect to and report on.
+pub fn is_other_instance(addr: &ServerAddr) -> bool {
+    let mut conn = match connect(addr) {
+        Ok(conn) => conn,
+        Err(_) => return false,
+    };
+    match conn.request(Request::GetServerInfo) {
+        Ok(Response::ServerInfo(info)) => info.instance != server_id(),
+        _ => false,
     }
 }
 
-/// Establish a TCP connection to an sccache server listening on `port`.
-pub fn connect_to_server(port: u16) -> io::Result<ServerConnection> {
-    trace!("connect_to_server({})", port);
-    let stream = try!(TcpStream::connect(("127.0.0.1", port)));
-    ServerConnection::new(stream)
+/// Establish a connection to an sccache server listening on `addr`.
+pub fn connect_to_server(addr: &ServerAddr) -> io::Result<ServerConnection> {
+    trace!("connect_to_server({})", addr);
+    let mut conn = try!(connect(addr));
+    try!(check_server_info(&mut conn, addr));
+    Ok(conn)
 }
 
-/// Attempt to establish a TCP connection to an sccache server listening on `port`.
+/// Attempt to establish a connection to an sccache server listening on `addr`.
 ///
 /// If the connection fails, retry a few times.
-pub fn connect_with_retry(port: u16) -> io::Result<ServerConnection> {
-    trace!("connect_with_retry({})", port);
+pub fn connect_with_retry(addr: &ServerAddr) -> io::Result<ServerConnection> {
+    trace!("connect_with_retry({})", addr);
     // TODOs:
     // * Pass the server Child in here, so we can stop retrying
     //   if the process exited.
     // * Send a pipe handle to the server process so it can notify
     //   us once it starts the server instead of us polling.
-    match retry(10, 500, || connect_to_server(port), |res| res.is_ok()) {
-        Ok(Ok(conn)) => Ok(conn),
+    let mismatched = |res: &io::Result<ServerConnection>| {
+        res.as_ref().err().map_or(false, |e| e.kind() == io::ErrorKind::InvalidData)
+    };
+    match retry(10, 500, || connect_to_server(addr), |res| res.is_ok() || mismatched(res)) {
+        Ok(res) => res,
         _ => Err(io::Error::new(io::ErrorKind::TimedOut,
                                 "Connection to server timed out")),
     }
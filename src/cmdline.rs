@@ -18,23 +18,134 @@ use clap::{
     Arg,
 };
 use errors::*;
+use logging;
 use std::env;
 use std::ffi::OsString;
 use std::path::PathBuf;
 use which::which_in;
 
+/// How many lines `--server-log` shows by default.
+const DEFAULT_SERVER_LOG_LINES: u32 = 100;
+
+/// How to output cache statistics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsFormat {
+    /// A human-readable table.
+    Text,
+    /// A JSON object.
+    Json,
+}
+
 /// A specific command to run.
 pub enum Command {
     /// Show cache statistics and exit.
-    ShowStats,
+    ShowStats {
+        /// How to output the statistics.
+        format: StatsFormat,
+        /// Only show the statistics of this build.
+        build: Option<String>,
+    },
     /// Zero cache statistics and exit.
     ZeroStats,
     /// Run background server.
     InternalStartServer,
     /// Start background server as a subprocess.
     StartServer,
+    /// Install the server as a Windows service.
+    InstallService,
+    /// Remove the Windows service.
+    UninstallService,
+    /// Run the server as a Windows service, for the service control manager.
+    InternalRunService,
     /// Stop background server.
-    StopServer,
+    StopServer {
+        /// Kill the server rather than waiting for its work to finish.
+        force: bool,
+    },
+    /// Collect garbage in the local disk cache.
+    Gc,
+    /// Remove every entry from the cache.
+    ClearCache {
+        /// Clear the remote cache as well as the local disk cache.
+        remote: bool,
+        /// Don't ask for confirmation first.
+        yes: bool,
+    },
+    /// Change the level the background server logs at.
+    SetLogLevel {
+        /// The new log level.
+        level: String,
+    },
+    /// Make the background server re-read the configuration file.
+    ReloadConfig,
+    /// Check that the background server and its cache storage work.
+    Health,
+    /// Show the compilations the background server is running, until
+    /// interrupted.
+    Top,
+    /// Show the compilers the background server has seen.
+    ShowCompilers,
+    /// Show the last lines the background server logged.
+    ServerLog {
+        /// How many lines to show.
+        lines: u32,
+    },
+    /// Write the local disk cache to an archive.
+    ExportCache {
+        /// The archive to create.
+        archive: PathBuf,
+    },
+    /// Add the entries in an archive to the local disk cache.
+    ImportCache {
+        /// The archive to read.
+        archive: PathBuf,
+    },
+    /// Copy the cache entries listed in a file from remote storage into the local cache.
+    Prefetch {
+        /// The file listing the cache keys to copy.
+        keys: PathBuf,
+    },
+    /// Copy the entries of one storage into another.
+    MigrateCache {
+        /// The storage to copy from: `disk` or a remote storage backend.
+        from: String,
+        /// The storage to copy to.
+        to: String,
+        /// A file listing the cache keys to copy, for storage that can't be listed.
+        keys: Option<PathBuf>,
+    },
+    /// Serve the cache storage as a Bazel HTTP remote cache.
+    BazelCache,
+    /// Serve the cache storage to other sccache instances over HTTP.
+    ServerHttp {
+        /// The address to listen on.
+        addr: String,
+    },
+    /// Run a scheduler for distributed compilation.
+    DistScheduler,
+    /// Run a build server for distributed compilation.
+    DistServer,
+    /// Show the build servers known to the distributed compilation scheduler.
+    DistStatus,
+    /// Run compilations for distcc clients, through the cache.
+    DistccServer,
+    /// Package a compiler into a toolchain archive for build servers.
+    DistPackageToolchain {
+        /// The compiler to package.
+        compiler: OsString,
+        /// The file to write the archive to.
+        output: PathBuf,
+    },
+    /// Look up the cache entry a compiler command would use, without
+    /// running it.
+    CheckCached {
+        /// The binary to execute.
+        exe: OsString,
+        /// The commandline arguments to pass to `exe`.
+        cmdline: Vec<OsString>,
+        /// The directory in which to execute the command.
+        cwd: PathBuf,
+    },
     /// Run a compiler command.
     Compile {
         /// The binary to execute.
@@ -55,7 +166,90 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
             "-s --show-stats 'show cache statistics'
              -z, --zero-stats 'zero statistics counters'
              --start-server  'start background server'
-             --stop-server   'stop background server'"
+             --stop-server   'stop background server'
+             --install-service 'install the server as a Windows service shared by all users, which starts with the system'
+             --uninstall-service 'stop and remove the Windows service'
+             --force 'with --stop-server, kill the server without waiting for its compilations and cache writes'
+             --gc 'rebuild the local cache index and remove stale files'
+             --clear-cache 'remove every entry from the local cache, after asking for confirmation'
+             --remote 'with --clear-cache, also remove the entries under the key prefix of the remote cache'
+             --yes 'with --clear-cache, clear the cache without asking for confirmation'
+             --reload-config 'make the background server re-read the configuration file'
+             --health 'check that the background server and its cache storage work, failing if not'
+             --check-cached 'report whether the compile command that follows is cached, and its key, without running it'
+             --bazel-cache 'serve the cache storage as a Bazel HTTP remote cache in the foreground'
+             --dist-scheduler 'run a scheduler for distributed compilation in the foreground'
+             --dist-server 'run a build server for distributed compilation in the foreground'
+             --distcc-server 'run compilations for distcc clients through the cache, in the foreground'
+             --dist-status 'show the build servers known to the scheduler in SCCACHE_DIST_SCHEDULER'
+             --top 'show the compilations the background server is running, refreshed every second'
+             --show-compilers 'list the compilers the background server has seen, and whether they are supported'
+             --server-log [N] 'show the last N lines the background server logged, 100 by default'"
+                )
+        .arg(
+            Arg::with_name("migrate-cache")
+                .long("migrate-cache")
+                .value_names(&["FROM", "TO"])
+                .help("copy the cache entries in storage FROM to storage TO, such as disk and s3; only disk can list its entries, so other storage needs --keys")
+                )
+        .arg(
+            Arg::with_name("prefetch")
+                .long("prefetch")
+                .value_name("FILE")
+                .help("copy the cache entries listed in FILE into the local cache")
+                )
+        .arg(
+            Arg::with_name("export-cache")
+                .long("export-cache")
+                .value_name("FILE")
+                .help("write the local cache to the archive FILE")
+                )
+        .arg(
+            Arg::with_name("import-cache")
+                .long("import-cache")
+                .value_name("FILE")
+                .help("add the entries in the archive FILE to the local cache")
+                )
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .value_name("FILE")
+                .help("with --migrate-cache, only copy the cache entries listed in FILE")
+                )
+        .arg(
+            Arg::with_name("set-log-level")
+                .long("set-log-level")
+                .value_name("LEVEL")
+                .help("change the level the background server logs at, such as debug or trace")
+                )
+        .arg(
+            Arg::with_name("stats-format")
+                .long("stats-format")
+                .value_name("FORMAT")
+                .help("with --show-stats, output the statistics as text or json")
+                )
+        .arg(
+            Arg::with_name("server-http")
+                .long("server-http")
+                .value_name("ADDR")
+                .help("serve the cache storage to other sccache instances over HTTP on ADDR, in the foreground")
+                )
+        .arg(
+            Arg::with_name("build")
+                .long("build")
+                .value_name("ID")
+                .help("with --show-stats, only show the statistics of the compilations run with SCCACHE_BUILD_ID set to ID")
+                )
+        .arg(
+            Arg::with_name("dist-package-toolchain")
+                .long("dist-package-toolchain")
+                .value_names(&["COMPILER", "OUTPUT"])
+                .help("package COMPILER into the toolchain archive OUTPUT, to use as a cross toolchain")
+                )
+        .arg(
+            Arg::with_name("internal-run-service")
+                .long("internal-run-service")
+                .hidden(true)
                 )
         .arg(
             Arg::with_name("cmd")
@@ -106,9 +300,41 @@ pub fn parse() -> Result<Command> {
     let matches = get_app().get_matches_from(args);
 
     let show_stats = matches.is_present("show-stats");
+    let stats_format = match matches.value_of("stats-format") {
+        None | Some("text") => StatsFormat::Text,
+        Some("json") => StatsFormat::Json,
+        Some(format) => bail!("Unknown stats format {}, expected text or json", format),
+    };
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
+    let install_service = matches.is_present("install-service");
+    let uninstall_service = matches.is_present("uninstall-service");
+    let internal_run_service = matches.is_present("internal-run-service");
+    let force = matches.is_present("force");
     let zero_stats = matches.is_present("zero-stats");
+    let gc = matches.is_present("gc");
+    let clear_cache = matches.is_present("clear-cache");
+    let set_log_level = matches.value_of("set-log-level");
+    let reload_config = matches.is_present("reload-config");
+    let health = matches.is_present("health");
+    let top = matches.is_present("top");
+    let show_compilers = matches.is_present("show-compilers");
+    let check_cached = matches.is_present("check-cached");
+    let server_http = matches.value_of("server-http");
+    let bazel_cache = matches.is_present("bazel-cache");
+    let dist_scheduler = matches.is_present("dist-scheduler");
+    let dist_server = matches.is_present("dist-server");
+    let dist_status = matches.is_present("dist-status");
+    let distcc_server = matches.is_present("distcc-server");
+    let server_log = matches.is_present("server-log");
+    let prefetch = matches.value_of_os("prefetch");
+    let export_cache = matches.value_of_os("export-cache");
+    let import_cache = matches.value_of_os("import-cache");
+    let migrate_cache = matches.values_of("migrate-cache")
+        .map(|v| v.map(|s| s.to_owned()).collect::<Vec<_>>());
+    let dist_package_toolchain = matches.values_of_os("dist-package-toolchain")
+        .map(|v| v.map(|s| s.to_owned()).collect::<Vec<_>>());
+    let keys = matches.value_of_os("keys");
     let cmd = matches.values_of_os("cmd");
     // Ensure that we've only received one command to run.
     fn is_some<T>(x : &Option<T>) -> bool {
@@ -119,7 +345,31 @@ pub fn parse() -> Result<Command> {
         show_stats,
         start_server,
         stop_server,
-        is_some(&cmd),
+        install_service,
+        uninstall_service,
+        internal_run_service,
+        zero_stats,
+        gc,
+        clear_cache,
+        is_some(&set_log_level),
+        reload_config,
+        health,
+        top,
+        show_compilers,
+        is_some(&server_http),
+        bazel_cache,
+        dist_scheduler,
+        dist_server,
+        dist_status,
+        distcc_server,
+        is_some(&dist_package_toolchain),
+        server_log,
+        is_some(&prefetch),
+        is_some(&export_cache),
+        is_some(&import_cache),
+        is_some(&migrate_cache),
+        // --check-cached takes the command as its argument.
+        is_some(&cmd) || check_cached,
             ].iter()
         .fold(0, |acc, &x| acc + (x as usize)) > 1 {
             bail!("Too many commands specified");
@@ -127,24 +377,105 @@ pub fn parse() -> Result<Command> {
     if internal_start_server {
         Ok(Command::InternalStartServer)
     } else if show_stats {
-        Ok(Command::ShowStats)
+        Ok(Command::ShowStats {
+            format: stats_format,
+            build: matches.value_of("build").map(|id| id.to_owned()),
+        })
     } else if start_server {
         Ok(Command::StartServer)
     } else if stop_server {
-        Ok(Command::StopServer)
+        Ok(Command::StopServer { force: force })
+    } else if install_service {
+        Ok(Command::InstallService)
+    } else if uninstall_service {
+        Ok(Command::UninstallService)
+    } else if internal_run_service {
+        Ok(Command::InternalRunService)
     } else if zero_stats {
         Ok(Command::ZeroStats)
+    } else if gc {
+        Ok(Command::Gc)
+    } else if clear_cache {
+        Ok(Command::ClearCache {
+            remote: matches.is_present("remote"),
+            yes: matches.is_present("yes"),
+        })
+    } else if let Some(level) = set_log_level {
+        if logging::parse_level(level).is_none() {
+            bail!("Invalid log level {}, expected one of off, error, warn, info, debug or trace", level);
+        }
+        Ok(Command::SetLogLevel { level: level.to_owned() })
+    } else if reload_config {
+        Ok(Command::ReloadConfig)
+    } else if health {
+        Ok(Command::Health)
+    } else if top {
+        Ok(Command::Top)
+    } else if show_compilers {
+        Ok(Command::ShowCompilers)
+    } else if let Some(addr) = server_http {
+        Ok(Command::ServerHttp { addr: addr.to_owned() })
+    } else if bazel_cache {
+        Ok(Command::BazelCache)
+    } else if dist_scheduler {
+        Ok(Command::DistScheduler)
+    } else if dist_server {
+        Ok(Command::DistServer)
+    } else if dist_status {
+        Ok(Command::DistStatus)
+    } else if distcc_server {
+        Ok(Command::DistccServer)
+    } else if let Some(mut args) = dist_package_toolchain {
+        let output = args.pop().unwrap();
+        let compiler = args.pop().unwrap();
+        Ok(Command::DistPackageToolchain {
+            compiler: compiler,
+            output: cwd.join(output),
+        })
+    } else if server_log {
+        let lines = match matches.value_of("server-log") {
+            None => DEFAULT_SERVER_LOG_LINES,
+            Some(n) => match n.parse() {
+                Ok(n) => n,
+                Err(_) => bail!("Invalid number of log lines {}", n),
+            },
+        };
+        Ok(Command::ServerLog { lines: lines })
+    } else if let Some(keys) = prefetch {
+        Ok(Command::Prefetch { keys: cwd.join(keys) })
+    } else if let Some(archive) = export_cache {
+        Ok(Command::ExportCache { archive: cwd.join(archive) })
+    } else if let Some(archive) = import_cache {
+        Ok(Command::ImportCache { archive: cwd.join(archive) })
+    } else if let Some(mut storages) = migrate_cache {
+        let to = storages.pop().unwrap();
+        let from = storages.pop().unwrap();
+        Ok(Command::MigrateCache {
+            from: from,
+            to: to,
+            keys: keys.map(|keys| cwd.join(keys)),
+        })
     } else if let Some(mut args) = cmd {
         if let Some(exe) = args.next() {
             let cmdline = args.map(|s| s.to_owned()).collect::<Vec<_>>();
-            Ok(Command::Compile {
-                exe: exe.to_owned(),
-                cmdline: cmdline,
-                cwd: cwd,
-            })
+            if check_cached {
+                Ok(Command::CheckCached {
+                    exe: exe.to_owned(),
+                    cmdline: cmdline,
+                    cwd: cwd,
+                })
+            } else {
+                Ok(Command::Compile {
+                    exe: exe.to_owned(),
+                    cmdline: cmdline,
+                    cwd: cwd,
+                })
+            }
         } else {
             bail!("No compile command");
         }
+    } else if check_cached {
+        bail!("No compile command to check");
     } else {
         bail!("No command specified");
     }
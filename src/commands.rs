@@ -12,52 +12,84 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use bazel;
+use cache_server;
+use cache::{self, CACHED_ENV_VARS, disk_cache_dir, storage_by_name, tiers_from_environment};
+use cache::prefetch::{migrate, prefetch, read_keys};
 use client::{
+    connect,
+    connect_timeout,
     connect_to_server,
     connect_with_retry,
+    is_connection_lost,
+    is_other_instance,
+    is_version_mismatch,
     ServerConnection,
 };
-use cmdline::Command;
+use cmdline::{Command, StatsFormat};
+use dist::{self, SchedulerRequest, SchedulerResponse};
+use distcc;
 use compiler::{
+    CompilerPolicies,
+    get_compiler_info,
     run_input_output,
 };
+use futures_cpupool::CpuPool;
+#[cfg(unix)]
+use libc;
 use log::LogLevel::Trace;
+use logging;
 use mock_command::{
     CommandCreatorSync,
     ProcessCommandCreator,
     RunCommand,
+    status_signal,
 };
 use number_prefix::{
     binary_prefix,
     Prefixed,
     Standalone,
 };
-use protobuf::RepeatedField;
 use protocol::{
+    Activity,
+    CacheCheckResult,
     CacheStats,
-    ClientRequest,
+    CacheStatValue,
     Compile,
     CompileFinished,
-    CompileStarted,
-    GetStats,
-    Shutdown,
-    UnhandledCompile,
-    ZeroStats,
+    CompilePhase,
+    CompilerInfo,
+    ConfigReloaded,
+    HealthReport,
+    PROTOCOL_VERSION,
+    Priority,
+    Request,
+    Response,
+    ServerInfo,
+    ShuttingDown,
+    StatGroup,
 };
-use server;
+use retry::retry;
+use serde_json::{self, Value};
+use server::{self, ServerAddr};
+use std::collections::{BTreeMap, VecDeque};
 use std::env;
 use std::ffi::{OsStr,OsString};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{
     self,
+    Read,
     Write,
 };
-#[cfg(unix)]
-use std::os::unix::process::ExitStatusExt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{
     Path,
+    PathBuf,
 };
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use systemd;
 use tokio_core::reactor::Core;
 use which::which_in;
 
@@ -66,15 +98,37 @@ use errors::*;
 /// The default sccache server port.
 pub const DEFAULT_PORT: u16 = 4226;
 
+/// How many ports, starting with its own, are tried for a server instance
+/// whose port another instance has taken.
+const INSTANCE_PORT_PROBES: u16 = 10;
+
+/// The number of milliseconds `--stop-server --force` waits for the server
+/// to describe itself before killing it without knowing what it's doing.
+const FORCE_STOP_INFO_TIMEOUT_MS: u64 = 2000;
+
 /// The number of milliseconds to wait for server startup.
 const SERVER_STARTUP_TIMEOUT_MS: u32 = 5000;
 
+/// The number of milliseconds after which a lock on starting the server
+/// is considered left behind, longer than starting a server takes.
+const SERVER_START_LOCK_STALE_MS: u64 = 3 * SERVER_STARTUP_TIMEOUT_MS as u64;
+
+/// The exit status of a compilation that sccache failed, by default. Like
+/// `docker run`, 125, which compilers don't exit with.
+const DEFAULT_ERROR_EXIT_CODE: i32 = 125;
+
+/// How often `--top` polls the server, in milliseconds.
+const TOP_INTERVAL_MS: u64 = 1000;
+
+/// The period over which `--top` shows the cache hit rate, in seconds.
+const HIT_RATE_WINDOW_SECS: u64 = 60;
+
 /// Possible responses from the server for a `Compile` request.
 enum CompileResponse {
     /// The compilation was started.
-    CompileStarted(CompileStarted),
+    CompileStarted,
     /// The server could not handle this compilation request.
-    UnhandledCompile(UnhandledCompile),
+    UnhandledCompile,
 }
 
 // Should this just be a Result?
@@ -88,49 +142,116 @@ enum ServerStartup {
     Err(Error),
 }
 
-/// Get the port on which the server should listen.
-fn get_port() -> u16 {
-    env::var("SCCACHE_SERVER_PORT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(DEFAULT_PORT)
+/// Get the unix domain socket the server should listen on, if
+/// `SCCACHE_SERVER_UDS` is set: `sccache/server.sock` in `$XDG_RUNTIME_DIR`,
+/// which is only accessible to the current user.
+#[cfg(unix)]
+fn get_socket_addr() -> Option<ServerAddr> {
+    if !cache::env_flag("SCCACHE_SERVER_UDS") {
+        return None;
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => {
+            let name = match server::server_id() {
+                Some(id) => format!("server-{}.sock", id),
+                None => "server.sock".to_owned(),
+            };
+            Some(ServerAddr::Unix(Path::new(&dir).join("sccache").join(name)))
+        }
+        None => {
+            warn!("SCCACHE_SERVER_UDS is set but XDG_RUNTIME_DIR isn't, using TCP");
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn get_socket_addr() -> Option<ServerAddr> { None }
+
+/// Get the address on which the server should listen, from
+/// `SCCACHE_SERVER_UDS`, or `SCCACHE_SERVER_ADDRESS` and `SCCACHE_SERVER_PORT`.
+/// Without a port, each instance named by `SCCACHE_SERVER_ID` gets its own.
+///
+/// The server runs compilers for whoever connects to it, so only loopback
+/// addresses are accepted.
+pub fn get_addr() -> Result<ServerAddr> {
+    if let Some(addr) = get_socket_addr() {
+        return Ok(addr);
+    }
+    let ip = match env::var("SCCACHE_SERVER_ADDRESS") {
+        Ok(s) => {
+            let ip = s.parse::<IpAddr>().chain_err(|| format!("Invalid SCCACHE_SERVER_ADDRESS: {}", s))?;
+            if !ip.is_loopback() {
+                bail!("SCCACHE_SERVER_ADDRESS must be a loopback address, not {}", ip);
+            }
+            ip
+        }
+        Err(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+    };
+    let port = match env::var("SCCACHE_SERVER_PORT").ok().and_then(|s| s.parse().ok()) {
+        Some(port) => port,
+        None => match server::server_id() {
+            Some(id) => return Ok(instance_addr(ip, &id)),
+            None => DEFAULT_PORT,
+        },
+    };
+    Ok(ServerAddr::Tcp(SocketAddr::new(ip, port)))
+}
+
+/// The address of server instance `id` on `ip`: the first of the ports
+/// from its own on that isn't taken by a server of another instance.
+fn instance_addr(ip: IpAddr, id: &str) -> ServerAddr {
+    let first = server::instance_port(id, DEFAULT_PORT);
+    let mut addr = ServerAddr::Tcp(SocketAddr::new(ip, first));
+    for port in first + 1..first + INSTANCE_PORT_PROBES {
+        if !is_other_instance(&addr) {
+            break;
+        }
+        debug!("Port {} is taken by another sccache instance, trying {}", port - 1, port);
+        addr = ServerAddr::Tcp(SocketAddr::new(ip, port));
+    }
+    addr
 }
 
 /// Re-execute the current executable as a background server, and wait
 /// for it to start up.
 #[cfg(not(windows))]
 fn run_server_process() -> Result<ServerStartup> {
-    extern crate tokio_uds;
-
     use futures::{Future, Stream};
     use std::time::Duration;
     use tempdir::TempDir;
-    use tokio_core::io::read_exact;
+    use tokio_core::io::{read_exact, read_to_end};
     use tokio_core::reactor::Timeout;
+    use tokio_uds;
 
     trace!("run_server_process");
+    // Not in SCCACHE_TMPDIR, which may be too long a path for a socket.
     let tempdir = TempDir::new("sccache")?;
     let socket_path = tempdir.path().join("sock");
     let mut core = Core::new()?;
     let handle = core.handle();
     let listener = tokio_uds::UnixListener::bind(&socket_path, &handle)?;
     let exe_path = env::current_exe()?;
+    // A client recaching its own compilations mustn't leave the server
+    // recaching everyone's: they say so in each request instead.
     let _child = process::Command::new(exe_path)
             .env("SCCACHE_START_SERVER", "1")
             .env("SCCACHE_STARTUP_NOTIFY", &socket_path)
             .env("RUST_BACKTRACE", "1")
+            .env_remove("SCCACHE_RECACHE")
             .spawn()?;
 
     let startup = listener.incoming().into_future().map_err(|e| e.0);
     let startup = startup.and_then(|(socket, _rest)| {
         let (socket, _addr) = socket.unwrap(); // incoming() never returns None
-        read_exact(socket, [0u8]).map(|(_socket, byte)| {
-            if byte[0] == 0 {
-                ServerStartup::Ok
-            } else {
-                let err = format!("Server startup failed: {}", byte[0]).into();
-                ServerStartup::Err(err)
-            }
+        read_exact(socket, [0u8]).and_then(|(socket, byte)| {
+            read_to_end(socket, vec!()).map(move |(_socket, message)| {
+                if byte[0] == 0 {
+                    ServerStartup::Ok
+                } else {
+                    ServerStartup::Err(startup_error(&message))
+                }
+            })
         })
     });
 
@@ -181,12 +302,30 @@ fn redirect_stderr(f: File) -> Result<()> {
     Ok(())
 }
 
-/// If `SCCACHE_ERROR_LOG` is set, redirect stderr to it.
+/// Run one of the Windows service commands.
+#[cfg(windows)]
+fn run_service_command(cmd: Command) -> Result<()> {
+    use windows_service;
+    match cmd {
+        Command::InstallService => windows_service::install(),
+        Command::UninstallService => windows_service::uninstall(),
+        _ => windows_service::run(),
+    }
+}
+
+#[cfg(not(windows))]
+fn run_service_command(_cmd: Command) -> Result<()> {
+    bail!("Windows services are only supported on Windows")
+}
+
+/// If `SCCACHE_ERROR_LOG` is set, redirect stderr to it, rotating it first
+/// if it has grown too large.
 fn redirect_error_log() -> Result<()> {
     let name = match env::var("SCCACHE_ERROR_LOG") {
         Ok(filename) => filename,
         _ => return Ok(()),
     };
+    logging::rotate_if_large(Path::new(&name), logging::max_size(), logging::keep())?;
     let f = OpenOptions::new().create(true).append(true).open(name)?;
     redirect_stderr(f)
 }
@@ -227,7 +366,9 @@ fn run_server_process() -> Result<ServerStartup> {
             (OsString::from("SCCACHE_STARTUP_NOTIFY"), OsString::from(&pipe_name)),
             (OsString::from("RUST_BACKTRACE"), OsString::from("1")),
         ];
-        for (key, val) in env::vars_os().chain(extra_vars) {
+        // See the unix version about SCCACHE_RECACHE.
+        let vars = env::vars_os().filter(|&(ref key, _)| key.as_os_str() != OsStr::new("SCCACHE_RECACHE"));
+        for (key, val) in vars.chain(extra_vars) {
             v.extend(key.encode_wide().chain(Some('=' as u16)).chain(val.encode_wide()).chain(Some(0)));
         }
         v.push(0);
@@ -266,45 +407,189 @@ fn run_server_process() -> Result<ServerStartup> {
     // It would be nice to have a read timeout here.
     let mut buffer = [0; 1];
     pipe.read_exact(&mut buffer)?;
+    let mut message = vec!();
+    pipe.read_to_end(&mut message)?;
     if buffer[0] == 0 {
         info!("Server started up successfully");
         Ok(ServerStartup::Ok)
     } else {
-        //TODO: send error messages over the socket as well.
-        error!("Server startup failed: {}", buffer[0]);
-        Ok(ServerStartup::Err(format!("Server startup failed: {}", buffer[0]).into()))
+        let err = startup_error(&message);
+        error!("{}", err);
+        Ok(ServerStartup::Err(err))
+    }
+}
+
+/// The error of a server that failed to start, from the message it sent.
+fn startup_error(message: &[u8]) -> Error {
+    format!("Server startup failed: {}", String::from_utf8_lossy(message)).into()
+}
+
+/// A lock on starting the server, held by the one client that starts it
+/// while other clients wait. It's a file that only one client can create,
+/// and is removed when dropped.
+struct StartLock {
+    path: PathBuf,
+}
+
+impl StartLock {
+    /// Take the lock at `path`, returning `None` if another client holds
+    /// it. A lock older than `SERVER_START_LOCK_STALE_MS` was left behind
+    /// by a client that didn't finish, and is taken over.
+    fn try_acquire(path: &Path) -> Result<Option<StartLock>> {
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(_) => return Ok(Some(StartLock { path: path.to_owned() })),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(path).and_then(|m| m.modified()).ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    match age {
+                        Some(age) if age > Duration::from_millis(SERVER_START_LOCK_STALE_MS) => {
+                            debug!("Removing stale server start lock {}", path.display());
+                            let _ = fs::remove_file(path);
+                        }
+                        _ => return Ok(None),
+                    }
+                }
+                Err(e) => return Err(e).chain_err(|| format!("couldn't create {}", path.display())),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for StartLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The path of the lock on starting the server, next to its statistics in
+/// the cache directory.
+fn start_lock_path() -> PathBuf {
+    let name = match server::server_id() {
+        Some(id) => format!(".server-start-{}.lock", id),
+        None => ".server-start.lock".to_owned(),
+    };
+    disk_cache_dir().join(name)
+}
+
+/// Start a server listening on `addr` and connect to it. Of concurrent
+/// clients, only the one holding the start lock starts a server and waits
+/// for it to report that it's ready, so that the others neither start
+/// servers of their own nor connect before it's ready.
+fn start_server_and_connect(addr: &ServerAddr) -> Result<ServerConnection> {
+    let path = start_lock_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let deadline = Instant::now() + Duration::from_millis(SERVER_START_LOCK_STALE_MS);
+    loop {
+        if let Some(_lock) = StartLock::try_acquire(&path)? {
+            // Another client may have started the server while we waited.
+            if let Ok(server) = connect_to_server(addr) {
+                return Ok(server);
+            }
+            match run_server_process()? {
+                ServerStartup::Ok => {}
+                ServerStartup::TimedOut => bail!("Timed out waiting for the sccache server to start"),
+                ServerStartup::Err(e) => return Err(e),
+            }
+            return Ok(connect_with_retry(addr)?);
+        }
+        if Instant::now() > deadline {
+            bail!("Timed out waiting for another client to start the sccache server");
+        }
+        thread::sleep(Duration::from_millis(50));
     }
 }
 
 /// Attempt to connect to an sccache server listening on `port`, or start one if no server is running.
-fn connect_or_start_server(port: u16) -> Result<ServerConnection> {
-    trace!("connect_or_start_server({})", port);
-    match connect_to_server(port) {
+fn connect_or_start_server(addr: &ServerAddr) -> Result<ServerConnection> {
+    trace!("connect_or_start_server({})", addr);
+    match connect_to_server(addr) {
         Ok(server) => Ok(server),
         Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused ||
+                      e.kind() == io::ErrorKind::NotFound ||
                       e.kind() == io::ErrorKind::TimedOut => {
-            // If the connection was refused we probably need to start
-            // the server.
-            //TODO: check startup value!
-            let _startup = run_server_process()?;
-            let server = connect_with_retry(port)?;
-            Ok(server)
+            // If the connection was refused, or there's no socket to connect
+            // to, we probably need to start the server.
+            start_server_and_connect(addr)
+        }
+        Err(ref e) if is_version_mismatch(e) => {
+            info!("{}, restarting it", e);
+            stop_mismatched_server(addr)?;
+            start_server_and_connect(addr)
         }
         Err(e) => Err(e.into())
     }
 }
 
+/// Stop the server listening on `addr`, which speaks a different protocol
+/// version, and wait for it to stop listening.
+fn stop_mismatched_server(addr: &ServerAddr) -> Result<()> {
+    trace!("stop_mismatched_server({})", addr);
+    // Every version with frame headers understands our shutdown request,
+    // though we may not understand the response. Version 1 servers need
+    // one of their own, and hang up on ours, as ours do on theirs.
+    let mut conn = connect(addr)?;
+    let _ = conn.request(Request::Shutdown);
+    drop(conn);
+    if let Ok(mut conn) = connect(addr) {
+        let _ = conn.legacy_shutdown();
+    }
+    wait_for_server_exit(addr)
+}
+
+/// Wait until nothing is listening on `addr` any more.
+fn wait_for_server_exit(addr: &ServerAddr) -> Result<()> {
+    match retry(50, 100, || connect(addr), |res| res.is_err()) {
+        Ok(_) => Ok(()),
+        Err(_) => bail!("the sccache server at {} didn't stop", addr),
+    }
+}
+
+/// Read the process id the server wrote when it started.
+fn read_pid_file() -> Result<u32> {
+    let path = server::pid_file();
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("couldn't read {}", path.display()))?;
+    contents.trim().parse().chain_err(|| format!("invalid pid in {}", path.display()))
+}
+
+/// Kill the server process `pid` without letting it finish its work.
+#[cfg(unix)]
+fn kill_server(pid: u32) -> Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+        return Err(io::Error::last_os_error())
+            .chain_err(|| format!("couldn't kill sccache server process {}", pid));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn kill_server(pid: u32) -> Result<()> {
+    let status = process::Command::new("taskkill")
+        .args(&["/F", "/PID", &pid.to_string()])
+        .stdout(process::Stdio::null())
+        .status()
+        .chain_err(|| "couldn't run taskkill")?;
+    if !status.success() {
+        bail!("couldn't kill sccache server process {}", pid);
+    }
+    Ok(())
+}
+
 /// Send a `ZeroStats` request to the server, and return the `CacheStats` request if successful.
 pub fn request_zero_stats(mut conn : ServerConnection) -> Result<CacheStats> {
     debug!("request_stats");
-    let mut req = ClientRequest::new();
-    req.set_zero_stats(ZeroStats::new());
     //TODO: better error mapping
-    let mut response = conn.request(req).chain_err(|| {
+    let response = conn.request(Request::ZeroStats).chain_err(|| {
         "failed to send zero statistics command to server or failed to receive respone"
     })?;
-    if response.has_stats() {
-        Ok(response.take_stats())
+    if let Response::Stats(stats) = response {
+        Ok(stats)
     } else {
         bail!("Unexpected server response!")
     }
@@ -313,61 +598,389 @@ pub fn request_zero_stats(mut conn : ServerConnection) -> Result<CacheStats> {
 /// Send a `GetStats` request to the server, and return the `CacheStats` request if successful.
 pub fn request_stats(mut conn : ServerConnection) -> Result<CacheStats> {
     debug!("request_stats");
-    let mut req = ClientRequest::new();
-    req.set_get_stats(GetStats::new());
     //TODO: better error mapping
-    let mut response = conn.request(req).chain_err(|| {
+    let response = conn.request(Request::GetStats).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Stats(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `Gc` request to the server, and return what changed if successful, or `None` if there's no local disk cache.
+pub fn request_gc(mut conn : ServerConnection) -> Result<Option<CacheStats>> {
+    debug!("request_gc");
+    //TODO: better error mapping
+    let response = conn.request(Request::Gc).chain_err(|| {
         "Failed to send data to or receive data from server"
     })?;
-    if response.has_stats() {
-        Ok(response.take_stats())
+    if let Response::GcFinished(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetBuildStats` request to the server, and return the statistics of `build_id` if successful, or `None` if the server has none.
+pub fn request_build_stats(mut conn : ServerConnection, build_id : &str) -> Result<Option<CacheStats>> {
+    debug!("request_build_stats");
+    let response = conn.request(Request::GetBuildStats(build_id.to_owned())).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::BuildStats(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `SetLogLevel` request to the server, and return the previous level if successful, or `None` if it can't be changed.
+pub fn request_set_log_level(mut conn : ServerConnection, level : &str) -> Result<Option<String>> {
+    debug!("request_set_log_level");
+    let response = conn.request(Request::SetLogLevel(level.to_owned())).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::LogLevelSet(previous) = response {
+        Ok(previous)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `ReloadConfig` request to the server, and return the `ConfigReloaded` response if successful.
+pub fn request_reload_config(mut conn : ServerConnection) -> Result<::std::result::Result<ConfigReloaded, String>> {
+    debug!("request_reload_config");
+    let response = conn.request(Request::ReloadConfig).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::ConfigReloaded(reloaded) = response {
+        Ok(reloaded)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `ClearCache` request to the server, and return the number of files removed if successful.
+pub fn request_clear_cache(mut conn : ServerConnection, remote : bool) -> Result<::std::result::Result<u64, String>> {
+    debug!("request_clear_cache");
+    let response = conn.request(Request::ClearCache(remote)).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::CacheCleared(cleared) = response {
+        Ok(cleared)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Whether `answer` to a yes/no question means yes.
+fn is_yes(answer: &str) -> bool {
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => true,
+        _ => false,
+    }
+}
+
+/// Ask `question` on the terminal, returning whether it was answered with
+/// yes. No answer, such as when stdin is closed, means no.
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+/// Send a `GetServerLog` request to the server, and return the lines if successful.
+pub fn request_server_log(mut conn : ServerConnection, lines : u32) -> Result<Vec<String>> {
+    debug!("request_server_log");
+    let response = conn.request(Request::GetServerLog(lines)).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::ServerLog(lines) = response {
+        Ok(lines)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetCompilers` request to the server, and return the compilers if successful.
+pub fn request_compilers(mut conn : ServerConnection) -> Result<Vec<CompilerInfo>> {
+    debug!("request_compilers");
+    let response = conn.request(Request::GetCompilers).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Compilers(compilers) = response {
+        Ok(compilers)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetActivity` request to the server, and return the `Activity` if successful.
+pub fn request_activity(conn: &mut ServerConnection) -> Result<Activity> {
+    debug!("request_activity");
+    let response = conn.request(Request::GetActivity).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Activity(activity) = response {
+        Ok(activity)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `HealthCheck` request to the server, and return the `HealthReport` if successful.
+pub fn request_health_check(mut conn : ServerConnection) -> Result<HealthReport> {
+    debug!("request_health_check");
+    let response = conn.request(Request::HealthCheck).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::HealthReport(report) = response {
+        Ok(report)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetServerInfo` request to the server, and return the `ServerInfo` if successful.
+pub fn request_server_info(conn: &mut ServerConnection) -> Result<ServerInfo> {
+    debug!("request_server_info");
+    let response = conn.request(Request::GetServerInfo).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::ServerInfo(info) = response {
+        Ok(info)
     } else {
         bail!("Unexpected server response!")
     }
 }
 
 /// Send a `Shutdown` request to the server, and return the `CacheStats` contained within the response if successful.
-pub fn request_shutdown(mut conn : ServerConnection) -> Result<CacheStats> {
+pub fn request_shutdown(mut conn : ServerConnection) -> Result<ShuttingDown> {
     debug!("request_shutdown");
-    let mut req = ClientRequest::new();
-    req.set_shutdown(Shutdown::new());
     //TODO: better error mapping
-    let mut response = conn.request(req).chain_err(|| {
+    let response = conn.request(Request::Shutdown).chain_err(|| {
         "Failed to send data to or receive data from server"
     })?;
-    if response.has_shutting_down() {
-        Ok(response.take_shutting_down().take_stats())
+    if let Response::ShuttingDown(shutting_down) = response {
+        Ok(shutting_down)
     } else {
         bail!("Unexpected server response!")
     }
 }
 
+/// Format `size` bytes for display.
+fn format_size(size: u64) -> String {
+    match binary_prefix(size as f64) {
+        Standalone(bytes) => format!("{} bytes", bytes),
+        Prefixed(prefix, n) => format!("{:.1} {}B", n, prefix),
+    }
+}
+
+/// Values longer than this, such as the cache location, don't widen the
+/// value column of the stats tables, and stick out of it instead.
+const MAX_STAT_VALUE_WIDTH: usize = 24;
+
+/// The heading of the table of statistics in `group`, unless they're shown
+/// without one.
+fn stat_group_heading(group: StatGroup) -> Option<&'static str> {
+    match group {
+        StatGroup::General => None,
+        StatGroup::Storage => Some("Storage"),
+        StatGroup::Requests => Some("Requests"),
+        StatGroup::Cache => Some("Cache"),
+        StatGroup::Errors => Some("Errors"),
+        StatGroup::Timings => Some("Timings"),
+        StatGroup::Backends => Some("Backends"),
+    }
+}
+
+/// Get the count named `name` in `stats`.
+fn stat_count(stats: &CacheStats, name: &str) -> Option<u64> {
+    stats.iter().filter(|s| s.name == name).filter_map(|s| match s.value {
+        CacheStatValue::Count(count) => Some(count),
+        _ => None,
+    }).next()
+}
+
+/// Format `stats` as text, with a table for each group of statistics in
+/// the order the groups first appear, sizes with binary prefixes, and the
+/// hit rate after the cache misses.
+fn format_stats(stats: &CacheStats) -> String {
+    let hit_rate = match (stat_count(stats, "Cache hits"), stat_count(stats, "Cache misses")) {
+        (Some(hits), Some(misses)) if hits + misses > 0 => {
+            Some(format!("{:.2} %", hits as f64 * 100. / (hits + misses) as f64))
+        }
+        (Some(_), Some(_)) => Some("-".to_owned()),
+        _ => None,
+    };
+    let mut rows = vec!();
+    for s in stats {
+        let value = match s.value {
+            CacheStatValue::Count(count) => format!("{}", count),
+            CacheStatValue::Str(ref s) => s.clone(),
+            CacheStatValue::Size(size) => format_size(size),
+        };
+        rows.push((s.group, s.name.clone(), value));
+        if s.name == "Cache misses" {
+            if let Some(ref rate) = hit_rate {
+                rows.push((s.group, "Cache hit rate".to_owned(), rate.clone()));
+            }
+        }
+    }
+    let mut groups = vec!();
+    for &(group, _, _) in &rows {
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+    let name_width = rows.iter().map(|&(_, ref n, _)| n.len()).max().unwrap_or(0);
+    let value_width = rows.iter()
+        .map(|&(_, _, ref v)| v.len())
+        .filter(|&len| len <= MAX_STAT_VALUE_WIDTH)
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for group in groups {
+        let indent = match stat_group_heading(group) {
+            Some(heading) => {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(heading);
+                out.push('\n');
+                "  "
+            }
+            None => "",
+        };
+        for &(_, ref name, ref value) in rows.iter().filter(|&&(g, _, _)| g == group) {
+            out.push_str(&format!("{}{:<name_width$}  {:>value_width$}\n", indent, name, value,
+                                  name_width=name_width, value_width=value_width));
+        }
+    }
+    out
+}
+
 /// Print `stats` to stdout.
 fn print_stats(stats: CacheStats) -> Result<()> {
-    let formatted = stats.get_stats().iter()
-        .map(|s| (s.get_name(), if s.has_count() {
-            format!("{}", s.get_count())
-        } else if s.has_str() {
-            s.get_str().to_owned()
-        } else if s.has_size() {
-            match binary_prefix(s.get_size() as f64) {
-                Standalone(bytes) => format!("{} bytes", bytes),
-                Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
-            }
+    print!("{}", format_stats(&stats));
+    Ok(())
+}
+
+/// The cache hit rate over the last `HIT_RATE_WINDOW_SECS`, worked out
+/// from samples of the server's counters.
+struct HitRate {
+    /// When each sample was taken, with the hits and misses counted then.
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl HitRate {
+    fn new() -> HitRate {
+        HitRate { samples: VecDeque::new() }
+    }
+
+    /// Add the `hits` and `misses` counted at `now`.
+    fn sample(&mut self, now: Instant, hits: u64, misses: u64) {
+        // Counters that went back mean the statistics were zeroed.
+        if self.samples.back().map_or(false, |&(_, h, m)| hits < h || misses < m) {
+            self.samples.clear();
+        }
+        self.samples.push_back((now, hits, misses));
+        let window = Duration::from_secs(HIT_RATE_WINDOW_SECS);
+        while self.samples.len() > 1 && now.duration_since(self.samples[0].0) > window {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The fraction of the compilations between the first and last samples
+    /// that were cache hits, unless there were none.
+    fn rate(&self) -> Option<f64> {
+        let (first, last) = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+        let hits = last.1 - first.1;
+        let total = hits + last.2 - first.2;
+        if total == 0 {
+            None
         } else {
-            String::from("???")
-        }))
-        .collect::<Vec<_>>();
-    let name_width = formatted.iter().map(|&(n, _)| n.len()).max().unwrap();
-    let stat_width = formatted.iter().map(|&(_, ref s)| s.len()).max().unwrap();
-    for (name, stat) in formatted {
-        println!("{:<name_width$} {:>stat_width$}", name, stat, name_width=name_width, stat_width=stat_width);
+            Some(hits as f64 / total as f64)
+        }
+    }
+}
+
+/// Describe `phase` in a few words.
+fn phase_name(phase: CompilePhase) -> &'static str {
+    match phase {
+        CompilePhase::Queued => "queued",
+        CompilePhase::Running => "running",
     }
-    Ok(())
+}
+
+/// Format the screen `--top` shows for `activity`.
+fn format_activity(activity: &Activity, hit_rate: Option<f64>) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("{} compilations in progress, {} queued, {} cache writes pending\n",
+                          activity.compiles.len(), activity.queued_compiles, activity.pending_writes));
+    out.push_str(&format!("Cache hit rate over the last minute: {}\n\n",
+                          hit_rate.map_or("-".to_owned(), |rate| format!("{:.1}%", rate * 100.))));
+    out.push_str(&format!("{:>8}  {:<14}  {:<16}  {}\n", "ELAPSED", "PHASE", "COMPILER", "SOURCE"));
+    for compile in &activity.compiles {
+        let compiler = Path::new(&compile.executable).file_name()
+            .map_or_else(|| compile.executable.clone(), |name| name.to_string_lossy().into_owned());
+        out.push_str(&format!("{:>7.1}s  {:<14}  {:<16}  {}\n",
+                              compile.elapsed_ms as f64 / 1000.,
+                              phase_name(compile.phase),
+                              compiler,
+                              compile.input));
+    }
+    out
+}
+
+/// The version of the JSON stats format, increased whenever existing keys
+/// change meaning or are removed.
+const STATS_JSON_VERSION: u64 = 1;
+
+/// Turn the name of a statistic into a key, such as `cache_hits` for
+/// "Cache hits".
+fn stat_key(name: &str) -> String {
+    let mut key = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() {
+            key.extend(c.to_lowercase());
+        } else if !key.is_empty() && !key.ends_with('_') {
+            key.push('_');
+        }
+    }
+    let len = key.trim_right_matches('_').len();
+    key.truncate(len);
+    key
+}
+
+/// Convert `stats` into a JSON object, with the statistics keyed by
+/// `stat_key`. Counts and sizes in bytes are numbers, other values strings.
+fn stats_json(stats: &CacheStats) -> Value {
+    let mut values = BTreeMap::new();
+    for s in stats {
+        let value = match s.value {
+            CacheStatValue::Count(count) => Value::U64(count),
+            CacheStatValue::Size(size) => Value::U64(size),
+            CacheStatValue::Str(ref s) => Value::String(s.clone()),
+        };
+        values.insert(stat_key(&s.name), value);
+    }
+    let mut json = BTreeMap::new();
+    json.insert("version".to_owned(), Value::U64(STATS_JSON_VERSION));
+    json.insert("stats".to_owned(), Value::Object(values));
+    Value::Object(json)
 }
 
 /// Send a `Compile` request to the server, and return the server response if successful.
-fn request_compile<W: AsRef<Path>, X: AsRef<OsStr>, Y: AsRef<Path>>(conn: &mut ServerConnection, exe: W, args: &Vec<X>, cwd: Y) -> Result<CompileResponse> {
+/// Describe running `exe` with `args` in `cwd` for the server.
+fn compile_request<W: AsRef<Path>, X: AsRef<OsStr>, Y: AsRef<Path>>(exe: W, args: &Vec<X>, cwd: Y) -> Result<Compile> {
     //TODO: It'd be nicer to send these over as raw bytes.
     let exe = exe.as_ref().to_str().ok_or("bad exe")?;
     let cwd = cwd.as_ref().to_str().ok_or("bad cwd")?;
@@ -375,64 +988,127 @@ fn request_compile<W: AsRef<Path>, X: AsRef<OsStr>, Y: AsRef<Path>>(conn: &mut S
     if args.is_empty() {
         bail!("bad commandline")
     }
-    let mut req = ClientRequest::new();
-    let mut compile = Compile::new();
-    compile.set_exe(exe.to_owned());
-    compile.set_cwd(cwd.to_owned());
-    compile.set_command(RepeatedField::from_vec(args));
-    trace!("request_compile: {:?}", compile);
-    req.set_compile(compile);
-    //TODO: better error mapping?
-    let mut response = conn.request(req).chain_err(|| {
+    Ok(Compile {
+        exe: exe.to_owned(),
+        cwd: cwd.to_owned(),
+        command: args,
+        recache: env::var("SCCACHE_RECACHE").is_ok(),
+        no_cache: env::var("SCCACHE_NO_CACHE").is_ok(),
+        explain: env::var("SCCACHE_DEBUG_WHY_MISS").is_ok(),
+        priority: priority_from_environment(),
+        build_id: env::var("SCCACHE_BUILD_ID").ok().and_then(|id| if id.is_empty() { None } else { Some(id) }),
+        user: server::current_user(),
+        env: key_env_vars(),
+    })
+}
+
+/// The environment variables the server may make part of the cache key,
+/// those that always are and those the compiler policies name, which the
+/// server reads from the same settings.
+fn key_env_vars() -> Vec<(String, String)> {
+    let mut names = CACHED_ENV_VARS.iter().map(|var| var.to_string()).collect::<Vec<_>>();
+    for var in CompilerPolicies::from_environment().hashed_env_var_names() {
+        if !names.contains(&var) {
+            names.push(var);
+        }
+    }
+    names.into_iter().filter_map(|var| env::var(&var).ok().map(|val| (var, val))).collect()
+}
+
+/// The priority of compilations, from `SCCACHE_PRIORITY`, which is either
+/// `interactive` or `batch`, the default.
+fn priority_from_environment() -> Priority {
+    match env::var("SCCACHE_PRIORITY") {
+        Ok(ref priority) if priority == "interactive" => Priority::Interactive,
+        Ok(ref priority) if priority != "batch" => {
+            warn!("Unknown SCCACHE_PRIORITY {}, using batch", priority);
+            Priority::Batch
+        }
+        _ => Priority::Batch,
+    }
+}
+
+/// Send a `CheckCached` request for running `exe` with `args` in `cwd` to the server, and return the result if successful.
+pub fn request_check_cached<W: AsRef<Path>, X: AsRef<OsStr>, Y: AsRef<Path>>(mut conn: ServerConnection, exe: W, args: &Vec<X>, cwd: Y) -> Result<CacheCheckResult> {
+    let compile = compile_request(exe, args, cwd)?;
+    trace!("request_check_cached: {:?}", compile);
+    let response = conn.request(Request::CheckCached(compile)).chain_err(|| {
         "Failed to send data to or receive data from server"
     })?;
-    if response.has_compile_started() {
-        Ok(CompileResponse::CompileStarted(response.take_compile_started()))
-    } else if response.has_unhandled_compile() {
-        Ok(CompileResponse::UnhandledCompile(response.take_unhandled_compile()))
+    if let Response::CacheChecked(result) = response {
+        Ok(result)
     } else {
-        bail!("Unexpected response from server")
+        bail!("Unexpected server response!")
     }
 }
 
-/// Return the signal that caused a process to exit from `status`.
+fn request_compile<W: AsRef<Path>, X: AsRef<OsStr>, Y: AsRef<Path>>(conn: &mut ServerConnection, exe: W, args: &Vec<X>, cwd: Y) -> Result<CompileResponse> {
+    let compile = compile_request(exe, args, cwd)?;
+    trace!("request_compile: {:?}", compile);
+    let response = match conn.request(Request::Compile(compile)) {
+        Ok(response) => response,
+        // The server didn't start the compile, so it can be sent again.
+        Err(ref e) if is_connection_lost(e) => {
+            debug!("request_compile: {}", e);
+            bail!(ErrorKind::ServerConnectionLost)
+        }
+        Err(e) => {
+            return Err(e).chain_err(|| "Failed to send data to or receive data from server")
+                .chain_err(|| ErrorKind::ServerUnusable)
+        }
+    };
+    match response {
+        Response::CompileStarted => Ok(CompileResponse::CompileStarted),
+        Response::UnhandledCompile => Ok(CompileResponse::UnhandledCompile),
+        _ => Err(Error::from("Unexpected response from server")).chain_err(|| ErrorKind::ServerUnusable),
+    }
+}
+
+/// How a compilation run by `do_compile` ended.
+#[derive(Debug, PartialEq)]
+pub enum CompileStatus {
+    /// The compiler exited with this status.
+    Exited(i32),
+    /// The compiler was terminated by this signal.
+    Signaled(i32),
+}
+
+/// Terminate this process with signal `sig`, the way the compiler was
+/// terminated, so that whoever ran sccache can tell. Returns if `sig`
+/// doesn't terminate the process.
 #[cfg(unix)]
-#[allow(dead_code)]
-fn status_signal(status : process::ExitStatus) -> Option<i32> {
-    status.signal()
+fn raise_signal(sig: i32) {
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
 }
 
-/// Not implemented for non-Unix.
 #[cfg(not(unix))]
-#[allow(dead_code)]
-fn status_signal(_status : process::ExitStatus) -> Option<i32> {
-    None
-}
+fn raise_signal(_sig: i32) {}
 
 /// Handle `response`, the output from running a compile on the server. Return the compiler exit status.
 fn handle_compile_finished(response: CompileFinished,
                            stdout: &mut Write,
-                           stderr: &mut Write) -> Result<i32> {
+                           stderr: &mut Write) -> Result<CompileStatus> {
     trace!("handle_compile_finished");
+    if let Some(error) = response.error {
+        return Err(Error::from(error)).chain_err(|| ErrorKind::ServerUnusable);
+    }
     // It might be nice if the server sent stdout/stderr as the process
     // ran, but then it would have to also save them in the cache as
     // interleaved streams to really make it work.
-    if response.has_stdout() {
-        try!(stdout.write_all(response.get_stdout()));
-    }
-    if response.has_stderr() {
-        try!(stderr.write_all(response.get_stderr()));
-    }
-    if response.has_retcode() {
-        let ret = response.get_retcode();
+    try!(stdout.write_all(&response.stdout));
+    try!(stderr.write_all(&response.stderr));
+    if let Some(ret) = response.retcode {
         trace!("compiler exited with status {}", ret);
-        Ok(ret)
-    } else if response.has_signal() {
-        println!("Compiler killed by signal {}", response.get_signal());
-        Ok(-2)
+        Ok(CompileStatus::Exited(ret))
+    } else if let Some(signal) = response.signal {
+        debug!("compiler terminated by signal {}", signal);
+        Ok(CompileStatus::Signaled(signal))
     } else {
-        println!("Missing compiler exit status!");
-        Ok(-3)
+        Err(Error::from("the server didn't report the compiler's exit status"))
+            .chain_err(|| ErrorKind::ServerUnusable)
     }
 }
 
@@ -443,7 +1119,7 @@ fn handle_compile_finished(response: CompileFinished,
 ///
 /// If the server returned `UnhandledCompile`, run the compilation command
 /// locally using `creator` and return the result.
-fn handle_compile_response<T>(mut creator: T,
+fn handle_compile_response<T>(creator: T,
                               core: &mut Core,
                               conn: &mut ServerConnection,
                               response: CompileResponse,
@@ -451,52 +1127,67 @@ fn handle_compile_response<T>(mut creator: T,
                               cmdline: Vec<OsString>,
                               cwd: &Path,
                               stdout: &mut Write,
-                              stderr: &mut Write) -> Result<i32>
+                              stderr: &mut Write) -> Result<CompileStatus>
     where T : CommandCreatorSync,
 {
     match response {
-        CompileResponse::CompileStarted(_) => {
+        CompileResponse::CompileStarted => {
             debug!("Server sent CompileStarted");
             // Wait for CompileFinished.
-            let mut res = conn.read_one_response().chain_err(|| {
+            let res = conn.read_one_response().chain_err(|| {
                 //TODO: something better here?
                 "error reading compile response from server"
-            })?;
-            if res.has_compile_finished() {
+            }).chain_err(|| ErrorKind::ServerUnusable)?;
+            if let Response::CompileFinished(finish) = res {
                 trace!("Server sent CompileFinished");
-                handle_compile_finished(res.take_compile_finished(),
-                                        stdout, stderr)
+                handle_compile_finished(finish, stdout, stderr)
             } else {
-                bail!("unexpected response from server")
+                Err(Error::from("unexpected response from server")).chain_err(|| ErrorKind::ServerUnusable)
             }
         }
-        CompileResponse::UnhandledCompile(_) => {
+        CompileResponse::UnhandledCompile => {
             debug!("Server sent UnhandledCompile");
-            //TODO: possibly capture output here for testing.
-            let mut cmd = creator.new_command_sync(exe);
-            cmd.args(&cmdline)
-                .current_dir(cwd);
-            if log_enabled!(Trace) {
-                trace!("running command: {:?}", cmd);
-            }
-            let output = try!(core.run(run_input_output(cmd, None)));
-            if !output.stdout.is_empty() {
-                try!(stdout.write_all(&output.stdout));
-            }
-            if !output.stderr.is_empty() {
-                try!(stderr.write_all(&output.stderr));
-            }
-            Ok(output.status.code().unwrap_or_else(|| {
-                if let Some(sig) = status_signal(output.status) {
-                   println!("Compile terminated by signal {}", sig);
-                }
-                // Arbitrary.
-                2
-            }))
+            run_compiler(creator, core, exe, cmdline, cwd, stdout, stderr)
         }
     }
 }
 
+/// Run the compiler `exe` with `cmdline` in `cwd` locally using `creator`,
+/// bypassing the cache, and return its exit status.
+fn run_compiler<T>(mut creator: T,
+                   core: &mut Core,
+                   exe: &Path,
+                   cmdline: Vec<OsString>,
+                   cwd: &Path,
+                   stdout: &mut Write,
+                   stderr: &mut Write) -> Result<CompileStatus>
+    where T : CommandCreatorSync,
+{
+    //TODO: possibly capture output here for testing.
+    let mut cmd = creator.new_command_sync(exe);
+    cmd.args(&cmdline)
+        .current_dir(cwd);
+    if log_enabled!(Trace) {
+        trace!("running command: {:?}", cmd);
+    }
+    let output = try!(core.run(run_input_output(cmd, None)));
+    if !output.stdout.is_empty() {
+        try!(stdout.write_all(&output.stdout));
+    }
+    if !output.stderr.is_empty() {
+        try!(stderr.write_all(&output.stderr));
+    }
+    if let Some(code) = output.status.code() {
+        Ok(CompileStatus::Exited(code))
+    } else if let Some(sig) = status_signal(output.status) {
+        debug!("compiler terminated by signal {}", sig);
+        Ok(CompileStatus::Signaled(sig))
+    } else {
+        // Arbitrary.
+        Ok(CompileStatus::Exited(2))
+    }
+}
+
 /// Send a `Compile` request to the sccache server `conn`, and handle the response.
 ///
 /// The first entry in `cmdline` will be looked up in `path` if it is not
@@ -510,7 +1201,7 @@ pub fn do_compile<T>(creator: T,
                      cwd: &Path,
                      path: Option<OsString>,
                      stdout: &mut Write,
-                     stderr: &mut Write) -> Result<i32>
+                     stderr: &mut Write) -> Result<CompileStatus>
     where T : CommandCreatorSync,
 {
     trace!("do_compile");
@@ -519,23 +1210,154 @@ pub fn do_compile<T>(creator: T,
     handle_compile_response(creator, core, &mut conn, res, &exe_path, cmdline, cwd, stdout, stderr)
 }
 
+/// Run a compilation entirely in this process, looking it up in and
+/// storing it to the cache the way the server would, without starting or
+/// connecting to a server.
+pub fn do_compile_in_process<T>(creator: T,
+                                core: &mut Core,
+                                exe: &Path,
+                                cmdline: Vec<OsString>,
+                                cwd: &Path,
+                                path: Option<OsString>,
+                                stdout: &mut Write,
+                                stderr: &mut Write) -> Result<CompileStatus>
+    where T : CommandCreatorSync,
+{
+    trace!("do_compile_in_process");
+    let exe_path = which_in(exe, path, &cwd)?;
+    let compile = compile_request(&exe_path, &cmdline, cwd)?;
+    let finish = server::compile_in_process(compile).chain_err(|| ErrorKind::ServerUnusable)?;
+    match finish {
+        Some(finish) => handle_compile_finished(finish, stdout, stderr),
+        None => run_compiler(creator, core, &exe_path, cmdline, cwd, stdout, stderr),
+    }
+}
+
+/// Run a compilation on the server, starting it if need be, and retrying
+/// on a new server if the connection is lost before the compile starts.
+fn compile_on_server<T>(creator: T,
+                        core: &mut Core,
+                        exe: &OsStr,
+                        cmdline: Vec<OsString>,
+                        cwd: &Path) -> Result<CompileStatus>
+    where T : CommandCreatorSync,
+{
+    let addr = get_addr().chain_err(|| ErrorKind::ServerUnusable)?;
+    let conn = connect_or_start_server(&addr).chain_err(|| ErrorKind::ServerUnusable)?;
+    match do_compile(creator.clone(),
+                     core,
+                     conn,
+                     exe.as_ref(),
+                     cmdline.clone(),
+                     cwd,
+                     env::var_os("PATH"),
+                     &mut io::stdout(),
+                     &mut io::stderr()) {
+        // The server went away before starting the compile, for
+        // example because it was being replaced, so send it to
+        // a new one.
+        Err(Error(ErrorKind::ServerConnectionLost, _)) => {
+            info!("Lost the connection to the server, retrying the compile");
+            let conn = connect_or_start_server(&addr).chain_err(|| ErrorKind::ServerUnusable)?;
+            do_compile(creator,
+                       core,
+                       conn,
+                       exe.as_ref(),
+                       cmdline,
+                       cwd,
+                       env::var_os("PATH"),
+                       &mut io::stdout(),
+                       &mut io::stderr())
+        }
+        res => res,
+    }
+}
+
+/// The exit status of a compilation that failed because of sccache rather
+/// than the compiler, from `SCCACHE_ERROR_EXIT_CODE`.
+pub fn error_exit_code() -> i32 {
+    env::var("SCCACHE_ERROR_EXIT_CODE").ok()
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(DEFAULT_ERROR_EXIT_CODE)
+}
+
+/// Whether `e` means the server couldn't be used, as opposed to the
+/// compilation failing. A connection lost again after a retry counts.
+pub fn is_server_unusable(e: &Error) -> bool {
+    match *e {
+        Error(ErrorKind::ServerUnusable, _) | Error(ErrorKind::ServerConnectionLost, _) => true,
+        _ => false,
+    }
+}
+
+/// Arguments with which compilers only report about themselves.
+const PROBE_ARGS: &'static [&'static str] = &[
+    "--version", "-dumpversion", "-dumpfullversion", "-dumpmachine", "-dumpspecs", "--help", "-###",
+];
+
+/// Whether `cmdline`, run in `cwd`, probes the compiler the way configure
+/// scripts and CMake do: asking for its version, search paths or
+/// predefined macros, or compiling a test program. These are never worth
+/// caching, so the client runs them without asking the server.
+fn is_probe(cmdline: &[OsString], cwd: &Path) -> bool {
+    let args = cmdline.iter().filter_map(|arg| arg.to_str()).collect::<Vec<_>>();
+    if args == ["-v"] {
+        return true;
+    }
+    if args.iter().any(|arg| PROBE_ARGS.iter().any(|probe| probe == arg) || arg.starts_with("-print-") ||
+                       arg.starts_with("--print-")) {
+        return true;
+    }
+    if args.contains(&"-E") && args.contains(&"-dM") {
+        return true;
+    }
+    // Test programs of autoconf, and of CMake's compiler detection and
+    // try_compile.
+    let test_program = args.iter().any(|arg| {
+        Path::new(arg).file_name().and_then(|name| name.to_str()).map_or(false, |name| {
+            name.starts_with("conftest.") ||
+                (name.starts_with("CMake") && name.contains("CompilerId"))
+        })
+    });
+    test_program || cwd.components().any(|c| c.as_os_str() == "CMakeTmp")
+}
+
 /// Run `cmd` and return the process exit status.
 pub fn run_command(cmd: Command) -> Result<i32> {
     match cmd {
-        Command::ShowStats => {
-            trace!("Command::ShowStats");
-            let srv = connect_or_start_server(get_port())?;
-            let response = request_stats(srv).chain_err(|| {
-                "failed to get stats from server"
-            })?;
-            print_stats(response)?;
+        Command::ShowStats { format, build } => {
+            trace!("Command::ShowStats {{ {:?}, {:?} }}", format, build);
+            let srv = connect_or_start_server(&get_addr()?)?;
+            let response = match build {
+                Some(build) => {
+                    request_build_stats(srv, &build).chain_err(|| {
+                        "failed to get stats from server"
+                    })?.ok_or_else(|| format!("The server has no statistics for build {}", build))?
+                }
+                None => {
+                    request_stats(srv).chain_err(|| {
+                        "failed to get stats from server"
+                    })?
+                }
+            };
+            match format {
+                StatsFormat::Text => print_stats(response)?,
+                StatsFormat::Json => {
+                    let json = serde_json::to_string_pretty(&stats_json(&response))
+                        .chain_err(|| "failed to format stats as JSON")?;
+                    println!("{}", json);
+                }
+            }
         }
         Command::InternalStartServer => {
             trace!("Command::InternalStartServer");
             // Can't report failure here, we're already daemonized.
-            daemonize()?;
+            // A server run by systemd stays in the foreground.
+            if !systemd::is_managed() {
+                daemonize()?;
+            }
             redirect_error_log()?;
-            server::start_server(get_port())?;
+            server::start_server(&get_addr()?)?;
         }
         Command::StartServer => {
             trace!("Command::StartServer");
@@ -553,41 +1375,570 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                 }
             }
         }
-        Command::StopServer => {
-            trace!("Command::StopServer");
+        Command::InstallService => {
+            trace!("Command::InstallService");
+            run_service_command(Command::InstallService)?;
+            println!("Installed and started the sccache service");
+        }
+        Command::UninstallService => {
+            trace!("Command::UninstallService");
+            run_service_command(Command::UninstallService)?;
+            println!("Removed the sccache service");
+        }
+        Command::InternalRunService => {
+            trace!("Command::InternalRunService");
+            run_service_command(Command::InternalRunService)?;
+        }
+        Command::StopServer { force } => {
+            trace!("Command::StopServer {{ force: {} }}", force);
+            let addr = get_addr()?;
+            if force {
+                println!("Killing sccache server...");
+                // Don't insist on a server of this version: the point is to
+                // get rid of it.
+                let timeout = Duration::from_millis(FORCE_STOP_INFO_TIMEOUT_MS);
+                let mut server = connect_timeout(&addr, Some(timeout))
+                    .chain_err(|| "couldn't connect to server")?;
+                let info = request_server_info(&mut server);
+                drop(server);
+                let pid = match info {
+                    Ok(ref info) => info.pid,
+                    // A wedged server doesn't answer, but left its pid behind.
+                    Err(ref e) => read_pid_file().chain_err(|| {
+                        format!("the server didn't answer ({}) and its pid is unknown", e)
+                    })?,
+                };
+                kill_server(pid)?;
+                wait_for_server_exit(&addr)?;
+                // A socket left behind is replaced by the next server.
+                println!("Killed sccache server process {}", pid);
+                if let Ok(info) = info {
+                    let compiles = info.active_compiles;
+                    let writes = info.pending_writes;
+                    if compiles + writes > 0 {
+                        println!("Abandoned {} compilations and {} cache writes", compiles, writes);
+                    }
+                }
+                return Ok(0);
+            }
             println!("Stopping sccache server...");
-            let server = connect_to_server(get_port()).chain_err(|| {
+            let mut server = match connect_to_server(&addr) {
+                Err(ref e) if is_version_mismatch(e) => {
+                    stop_mismatched_server(&addr)?;
+                    println!("Stopped a server of a different sccache version");
+                    return Ok(0);
+                }
+                server => server.chain_err(|| "couldn't connect to server")?,
+            };
+            // Say why stopping may take a while.
+            let info = request_server_info(&mut server)?;
+            let compiles = info.active_compiles;
+            let writes = info.pending_writes;
+            if compiles + writes > 0 {
+                println!("Waiting for {} compilations and {} cache writes to finish \
+                          (use --force to stop without waiting)...", compiles, writes);
+            }
+            let shutting_down = request_shutdown(server)?;
+            print_stats(shutting_down.stats)?;
+            let compiles = shutting_down.unfinished_compiles;
+            let writes = shutting_down.unfinished_writes;
+            if compiles + writes > 0 {
+                println!("Stopped with {} compilations and {} cache writes unfinished",
+                         compiles, writes);
+            } else {
+                println!("All compilations and cache writes finished");
+            }
+        }
+        Command::Gc => {
+            trace!("Command::Gc");
+            let conn = connect_or_start_server(&get_addr()?)?;
+            let gc_finished = request_gc(conn).chain_err(|| {
+                "couldn't collect garbage on server"
+            })?;
+            if let Some(stats) = gc_finished {
+                print_stats(stats)?
+            } else {
+                println!("Nothing to collect: sccache is not using a local disk cache");
+            }
+        }
+        Command::SetLogLevel { level } => {
+            trace!("Command::SetLogLevel {{ {} }}", level);
+            let conn = connect_to_server(&get_addr()?).chain_err(|| {
+                "couldn't connect to server"
+            })?;
+            if let Some(previous) = request_set_log_level(conn, &level)? {
+                println!("Server log level changed from {} to {}", previous, level);
+            } else {
+                bail!("The server's log level can't be changed; start it with SCCACHE_LOG_FILE \
+                       or SCCACHE_LOG_LEVEL set")
+            }
+        }
+        Command::ClearCache { remote, yes } => {
+            trace!("Command::ClearCache {{ remote: {}, yes: {} }}", remote, yes);
+            let question = if remote {
+                "Remove every entry from the local cache and the remote cache?"
+            } else {
+                "Remove every entry from the local cache?"
+            };
+            if !yes && !confirm(question)? {
+                bail!("Not clearing the cache; pass --yes to clear it without asking")
+            }
+            let conn = connect_or_start_server(&get_addr()?)?;
+            match request_clear_cache(conn, remote)? {
+                Ok(removed) => println!("Removed {} files from the cache", removed),
+                Err(e) => bail!("The server couldn't clear the cache: {}", e),
+            }
+        }
+        Command::ReloadConfig => {
+            trace!("Command::ReloadConfig");
+            let conn = connect_to_server(&get_addr()?).chain_err(|| {
+                "couldn't connect to server"
+            })?;
+            let reloaded = match request_reload_config(conn)? {
+                Ok(reloaded) => reloaded,
+                Err(e) => bail!("The server couldn't reload its configuration: {}", e),
+            };
+            if reloaded.changed.is_empty() && reloaded.needs_restart.is_empty() {
+                println!("The configuration is unchanged");
+            }
+            for key in &reloaded.changed {
+                println!("Changed {}", key);
+            }
+            if !reloaded.needs_restart.is_empty() {
+                println!("Restart the server for changes to {} to take effect",
+                         reloaded.needs_restart.join(", "));
+            }
+            println!("Caching in {}", reloaded.cache_location);
+        }
+        Command::ServerLog { lines } => {
+            trace!("Command::ServerLog {{ {} }}", lines);
+            let conn = connect_to_server(&get_addr()?).chain_err(|| {
+                "couldn't connect to server"
+            })?;
+            let server_log = request_server_log(conn, lines)?;
+            if server_log.is_empty() {
+                println!("The server hasn't logged anything; use --set-log-level to log more");
+            }
+            for line in &server_log {
+                println!("{}", line);
+            }
+        }
+        Command::Top => {
+            trace!("Command::Top");
+            let mut conn = connect_to_server(&get_addr()?).chain_err(|| {
+                "couldn't connect to server"
+            })?;
+            let mut hit_rate = HitRate::new();
+            loop {
+                let activity = request_activity(&mut conn)?;
+                hit_rate.sample(Instant::now(), activity.cache_hits, activity.cache_misses);
+                // Clear the terminal and draw from its top left corner.
+                print!("\x1b[2J\x1b[H{}", format_activity(&activity, hit_rate.rate()));
+                io::stdout().flush()?;
+                thread::sleep(Duration::from_millis(TOP_INTERVAL_MS));
+            }
+        }
+        Command::ShowCompilers => {
+            trace!("Command::ShowCompilers");
+            let conn = connect_to_server(&get_addr()?).chain_err(|| {
                 "couldn't connect to server"
             })?;
-            let stats = request_shutdown(server)?;
-            print_stats(stats)?
+            let compilers = request_compilers(conn)?;
+            if compilers.is_empty() {
+                println!("The server hasn't been asked to run any compilers yet");
+            }
+            for compiler in &compilers {
+                match compiler.kind {
+                    Some(ref kind) => {
+                        println!("{}: {} {}, sha-1 {}",
+                                 compiler.executable,
+                                 kind,
+                                 compiler.version.as_ref().map(|v| &v[..]).unwrap_or("(unknown version)"),
+                                 compiler.digest.as_ref().map(|d| &d[..]).unwrap_or(""));
+                    }
+                    None => {
+                        println!("{}: unsupported, compilations with it aren't cached",
+                                 compiler.executable);
+                    }
+                }
+            }
+        }
+        Command::Health => {
+            trace!("Command::Health");
+            let addr = get_addr()?;
+            // Don't start a server: the point is to check the one running.
+            let conn = connect_to_server(&addr).chain_err(|| {
+                format!("couldn't connect to the sccache server at {}", addr)
+            })?;
+            let report = request_health_check(conn)?;
+            let info = &report.server_info;
+            println!("Server: sccache {} (protocol version {}), process {}, listening on {}",
+                     info.version, PROTOCOL_VERSION, info.pid, addr);
+            println!("Cache location: {}", report.cache_location);
+            let mut failed = false;
+            for backend in &report.backends {
+                if backend.read_only {
+                    println!("{} write: skipped, it is read-only", backend.name);
+                } else if let Some(ref e) = backend.write_error {
+                    println!("{} write: failed: {}", backend.name, e);
+                } else {
+                    println!("{} write: ok", backend.name);
+                }
+                if let Some(ref e) = backend.read_error {
+                    println!("{} read: failed: {}", backend.name, e);
+                } else {
+                    println!("{} read: ok", backend.name);
+                }
+                failed |= backend.write_error.is_some() || backend.read_error.is_some();
+            }
+            if failed {
+                return Ok(1);
+            }
+        }
+        Command::ExportCache { archive } => {
+            trace!("Command::ExportCache {{ {:?} }}", archive);
+            let dir = disk_cache_dir();
+            println!("Exporting {} to {}...", dir.display(), archive.display());
+            let stats = cache::archive::export(&dir, &archive)?;
+            println!("Exported {} files, {}", stats.files, format_size(stats.size));
+        }
+        Command::ImportCache { archive } => {
+            trace!("Command::ImportCache {{ {:?} }}", archive);
+            // A running server wouldn't see entries added to its local cache.
+            if connect_to_server(&get_addr()?).is_ok() {
+                bail!("The sccache server is running, stop it with --stop-server before importing");
+            }
+            let dir = disk_cache_dir();
+            println!("Importing {} into {}...", archive.display(), dir.display());
+            let stats = cache::archive::import(&archive, &dir)?;
+            println!("Imported {} files, {}", stats.files, format_size(stats.size));
+        }
+        Command::Prefetch { keys } => {
+            trace!("Command::Prefetch {{ {:?} }}", keys);
+            // A running server wouldn't see entries added to its local cache.
+            if connect_to_server(&get_addr()?).is_ok() {
+                bail!("The sccache server is running, stop it with --stop-server before prefetching");
+            }
+            let keys = read_keys(&keys)?;
+            let mut core = Core::new()?;
+            let pool = CpuPool::new(20);
+            let (remote, local) = tiers_from_environment(&pool, &core.handle())?;
+            println!("Prefetching {} cache entries from {}...", keys.len(), remote.location());
+            let stats = core.run(prefetch(remote, local, keys, &pool))?;
+            println!("Fetched {}, already present {}, not found {}, failed {}",
+                     stats.fetched, stats.present, stats.missing, stats.failed);
+        }
+        Command::MigrateCache { from, to, keys } => {
+            trace!("Command::MigrateCache {{ {:?}, {:?}, {:?} }}", from, to, keys);
+            // A running server wouldn't see entries added to its local cache.
+            if to == "disk" && connect_to_server(&get_addr()?).is_ok() {
+                bail!("The sccache server is running, stop it with --stop-server before migrating to disk");
+            }
+            let keys = match keys {
+                Some(keys) => Some(read_keys(&keys)?),
+                None => None,
+            };
+            let mut core = Core::new()?;
+            let pool = CpuPool::new(20);
+            let from = storage_by_name(&from, &pool, &core.handle())?;
+            let to = storage_by_name(&to, &pool, &core.handle())?;
+            println!("Copying cache entries from {} to {}...", from.location(), to.location());
+            let stats = core.run(migrate(from, to, keys, &pool))?;
+            println!("Copied {}, already present {}, not found {}, failed {}",
+                     stats.fetched, stats.present, stats.missing, stats.failed);
         }
         Command::Compile { exe, cmdline, cwd } => {
             trace!("Command::Compile {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
-            let conn = connect_or_start_server(get_port())?;
             let mut core = Core::new()?;
-            let res = do_compile(ProcessCommandCreator::new(&core.handle()),
+            let creator = ProcessCommandCreator::new(&core.handle());
+            let res = if is_probe(&cmdline, &cwd) {
+                debug!("Running compiler probe {:?} {:?} without the cache", exe, cmdline);
+                let exe_path = which_in(&exe, env::var_os("PATH"), &cwd)?;
+                run_compiler(creator.clone(),
+                             &mut core,
+                             &exe_path,
+                             cmdline.clone(),
+                             &cwd,
+                             &mut io::stdout(),
+                             &mut io::stderr())
+            } else if cache::env_flag("SCCACHE_NO_DAEMON") {
+                do_compile_in_process(creator.clone(),
+                                      &mut core,
+                                      exe.as_ref(),
+                                      cmdline.clone(),
+                                      &cwd,
+                                      env::var_os("PATH"),
+                                      &mut io::stdout(),
+                                      &mut io::stderr())
+            } else {
+                compile_on_server(creator.clone(), &mut core, &exe, cmdline.clone(), &cwd)
+            };
+            let res = match res {
+                // Rather than failing the build, run the compiler
+                // without the cache.
+                Err(ref e) if is_server_unusable(e) && !cache::env_flag("SCCACHE_NO_FALLBACK") => {
+                    let reasons = e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                    writeln!(io::stderr(), "sccache: warning: {}, running the compiler without the cache",
+                             reasons.join(": "))?;
+                    let exe_path = which_in(&exe, env::var_os("PATH"), &cwd)?;
+                    run_compiler(creator,
                                  &mut core,
-                                 conn,
-                                 exe.as_ref(),
+                                 &exe_path,
                                  cmdline,
                                  &cwd,
-                                 env::var_os("PATH"),
                                  &mut io::stdout(),
-                                 &mut io::stderr());
-            return res.chain_err(|| {
-                "failed to execute compile"
-            })
+                                 &mut io::stderr())
+                }
+                res => res,
+            };
+            let status = match res {
+                Ok(status) => status,
+                // Keep the kind, for main to exit with `error_exit_code`.
+                Err(e) => if is_server_unusable(&e) {
+                    return Err(e)
+                } else {
+                    return Err(e).chain_err(|| "failed to execute compile")
+                },
+            };
+            return match status {
+                CompileStatus::Exited(code) => Ok(code),
+                CompileStatus::Signaled(sig) => {
+                    io::stdout().flush()?;
+                    raise_signal(sig);
+                    // Like a shell reports a process terminated by a signal.
+                    Ok(128 + sig)
+                }
+            }
+        }
+        Command::ServerHttp { addr } => {
+            trace!("Command::ServerHttp {{ {} }}", addr);
+            cache_server::run(cache_server::Config::from_environment(&addr)?)?;
+        }
+        Command::BazelCache => {
+            trace!("Command::BazelCache");
+            bazel::run(bazel::Config::from_environment()?)?;
+        }
+        Command::DistScheduler => {
+            trace!("Command::DistScheduler");
+            dist::scheduler::run(dist::scheduler::Config::from_environment()?)?;
+        }
+        Command::DistServer => {
+            trace!("Command::DistServer");
+            dist::server::run(dist::server::Config::from_environment()?)?;
+        }
+        Command::DistStatus => {
+            trace!("Command::DistStatus");
+            let addr = dist::scheduler_addr().ok_or("SCCACHE_DIST_SCHEDULER is not set")?;
+            let connector = dist::Connector::from_environment()?;
+            let token = dist::auth::client_token();
+            let status = match connector.scheduler_request(&addr, &token, SchedulerRequest::Status)? {
+                SchedulerResponse::Status(status) => status,
+                _ => bail!("Unexpected response from the scheduler"),
+            };
+            println!("Scheduler: {}, {} jobs allocated", addr, status.jobs_allocated);
+            if status.servers.is_empty() {
+                println!("No build servers are sending heartbeats");
+            }
+            for server in &status.servers {
+                println!("{} ({}): {}/{} jobs, {} toolchains",
+                         server.addr, server.platform, server.running_jobs, server.max_jobs,
+                         server.toolchains.len());
+            }
+        }
+        Command::DistccServer => {
+            trace!("Command::DistccServer");
+            distcc::run(distcc::Config::from_environment()?)?;
+        }
+        Command::DistPackageToolchain { compiler, output } => {
+            trace!("Command::DistPackageToolchain {{ {:?}, {:?} }}", compiler, output);
+            let cwd = env::current_dir()?;
+            let exe_path = which_in(compiler, env::var_os("PATH"), &cwd)?;
+            let exe = exe_path.to_str().ok_or("Compiler path is not valid unicode")?;
+            let mut core = Core::new()?;
+            let pool = CpuPool::new(1);
+            let creator = ProcessCommandCreator::new(&core.handle());
+            let compiler = core.run(get_compiler_info(&creator, exe, &pool))?;
+            let toolchain = dist::toolchain::package_compiler(&compiler)?;
+            File::create(&output).and_then(|mut f| f.write_all(&toolchain.archive))
+                .chain_err(|| format!("failed to write {}", output.display()))?;
+            println!("Packaged {} into {} as toolchain {}",
+                     exe_path.display(), output.display(), toolchain.id);
+        }
+        Command::CheckCached { exe, cmdline, cwd } => {
+            trace!("Command::CheckCached {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let conn = connect_or_start_server(&get_addr()?)?;
+            let exe_path = which_in(exe, env::var_os("PATH"), &cwd)?;
+            let result = request_check_cached(conn, &exe_path, &cmdline, &cwd)?;
+            // Only hits and misses have a key to report; anything else
+            // exits with 2 so that scripts can tell.
+            match result {
+                CacheCheckResult::Hit(key) => println!("Cache hit: {}", key),
+                CacheCheckResult::Miss(key) => {
+                    println!("Cache miss: {}", key);
+                    return Ok(1);
+                }
+                CacheCheckResult::Unavailable(key) => {
+                    println!("Cache unavailable, key: {}", key);
+                    return Ok(2);
+                }
+                CacheCheckResult::NotCacheable(why) => {
+                    println!("Not cacheable: {}", why);
+                    return Ok(2);
+                }
+                CacheCheckResult::Unhandled => {
+                    println!("Not a compilation with a supported compiler");
+                    return Ok(2);
+                }
+                CacheCheckResult::PreprocessorFailed(stderr) => {
+                    io::stderr().write_all(&stderr)?;
+                    println!("The preprocessor failed, so there is no key");
+                    return Ok(2);
+                }
+                CacheCheckResult::Failed(e) => bail!("Failed to compute the cache key: {}", e),
+            }
         }
         Command::ZeroStats => {
             trace!("Command::ZeroStats");
-            let conn = connect_or_start_server(get_port())?;
-            let stats = request_zero_stats(conn).chain_err(|| {
+            let conn = connect_or_start_server(&get_addr()?)?;
+            request_zero_stats(conn).chain_err(|| {
                 "couldn't zero stats on server"
             })?;
-            print_stats(stats)?
+            println!("Statistics zeroed.");
         }
     }
 
     Ok(0)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use protocol::CacheStatistic;
+
+    #[test]
+    fn test_is_probe() {
+        fn probe(args: &[&str], cwd: &str) -> bool {
+            is_probe(&args.iter().map(OsString::from).collect::<Vec<_>>(), Path::new(cwd))
+        }
+        assert!(probe(&["--version"], "/src"));
+        assert!(probe(&["-v"], "/src"));
+        assert!(probe(&["-print-file-name=libgcc.a"], "/src"));
+        assert!(probe(&["-E", "-dM", "-x", "c", "/dev/null"], "/src"));
+        assert!(probe(&["-c", "conftest.c", "-o", "conftest.o"], "/src"));
+        assert!(probe(&["CMakeFiles/3.7.2/CompilerIdC/CMakeCCompilerId.c"], "/build"));
+        assert!(probe(&["-c", "src.c", "-o", "src.o"], "/build/CMakeFiles/CMakeTmp"));
+        assert!(!probe(&["-c", "foo.c", "-o", "foo.o"], "/src"));
+        assert!(!probe(&["-v", "-c", "foo.c", "-o", "foo.o"], "/src"));
+        assert!(!probe(&["-E", "foo.c"], "/src"));
+    }
+
+    #[test]
+    fn test_hit_rate() {
+        let start = Instant::now();
+        let mut hit_rate = HitRate::new();
+        hit_rate.sample(start, 10, 10);
+        assert_eq!(None, hit_rate.rate());
+        hit_rate.sample(start + Duration::from_secs(30), 13, 11);
+        assert_eq!(Some(0.75), hit_rate.rate());
+        // The first sample falls out of the window.
+        hit_rate.sample(start + Duration::from_secs(70), 13, 13);
+        assert_eq!(Some(0.), hit_rate.rate());
+        // Zeroing the statistics starts over.
+        hit_rate.sample(start + Duration::from_secs(71), 1, 0);
+        assert_eq!(None, hit_rate.rate());
+    }
+
+    #[test]
+    fn test_stats_json() {
+        assert_eq!("cache_misses_cache_unavailable", stat_key("Cache misses (cache unavailable)"));
+        assert_eq!("s3_read_latency_p50_p90_p99", stat_key("s3 read latency p50/p90/p99"));
+        let stats = vec!(CacheStatistic::count("Cache hits", 3),
+                         CacheStatistic::str("Cache location", "Local disk".to_owned()));
+        let json = stats_json(&stats);
+        assert_eq!(Some(1), json.lookup("version").and_then(Value::as_u64));
+        assert_eq!(Some(3), json.lookup("stats.cache_hits").and_then(Value::as_u64));
+        assert_eq!(Some("Local disk"), json.lookup("stats.cache_location").and_then(Value::as_str));
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let stats = vec!(CacheStatistic::str("Cache location", "Local disk".to_owned()).in_group(StatGroup::Storage),
+                         CacheStatistic::size("Cache size", 1536).in_group(StatGroup::Storage),
+                         CacheStatistic::count("Cache hits", 3).in_group(StatGroup::Cache),
+                         CacheStatistic::count("Cache misses", 1).in_group(StatGroup::Cache),
+                         CacheStatistic::count("Cache errors", 0).in_group(StatGroup::Errors));
+        assert_eq!("Storage\n\
+                    \x20 Cache location  Local disk\n\
+                    \x20 Cache size         1.5 KiB\n\
+                    \n\
+                    Cache\n\
+                    \x20 Cache hits               3\n\
+                    \x20 Cache misses             1\n\
+                    \x20 Cache hit rate     75.00 %\n\
+                    \n\
+                    Errors\n\
+                    \x20 Cache errors             0\n",
+                   format_stats(&stats));
+        // Without lookups, there is no hit rate yet.
+        let stats = vec!(CacheStatistic::count("Cache hits", 0), CacheStatistic::count("Cache misses", 0));
+        assert_eq!("Cache hits      0\n\
+                    Cache misses    0\n\
+                    Cache hit rate  -\n",
+                   format_stats(&stats));
+    }
+
+    #[test]
+    fn test_compile_finished_signal() {
+        let finish = CompileFinished {
+            retcode: None,
+            signal: Some(11),
+            stdout: b"out".to_vec(),
+            stderr: b"err".to_vec(),
+            error: None,
+        };
+        let mut stdout = vec!();
+        let mut stderr = vec!();
+        assert_eq!(CompileStatus::Signaled(11),
+                   handle_compile_finished(finish, &mut stdout, &mut stderr).unwrap());
+        assert_eq!(b"out", &stdout[..]);
+        assert_eq!(b"err", &stderr[..]);
+    }
+
+    #[test]
+    fn test_compile_finished_error() {
+        let finish = CompileFinished {
+            retcode: Some(-2),
+            signal: None,
+            stdout: b"out".to_vec(),
+            stderr: vec!(),
+            error: Some("failed to store `foo.o` to cache".to_owned()),
+        };
+        let mut stdout = vec!();
+        let mut stderr = vec!();
+        let e = handle_compile_finished(finish, &mut stdout, &mut stderr).unwrap_err();
+        assert!(is_server_unusable(&e));
+        assert!(stdout.is_empty());
+    }
+
+    #[test]
+    fn test_is_server_unusable() {
+        assert!(is_server_unusable(&ErrorKind::ServerUnusable.into()));
+        assert!(is_server_unusable(&ErrorKind::ServerConnectionLost.into()));
+        let e: Result<()> = Err(Error::from("no response")).chain_err(|| ErrorKind::ServerUnusable);
+        assert!(is_server_unusable(&e.unwrap_err()));
+        assert!(!is_server_unusable(&"compiler not found".into()));
+    }
+
+    #[test]
+    fn test_start_lock() {
+        use tempdir::TempDir;
+        let dir = TempDir::new("sccache_test_start_lock").unwrap();
+        let path = dir.path().join("lock");
+        let lock = StartLock::try_acquire(&path).unwrap();
+        assert!(lock.is_some());
+        assert!(StartLock::try_acquire(&path).unwrap().is_none());
+        drop(lock);
+        assert!(!path.exists());
+        assert!(StartLock::try_acquire(&path).unwrap().is_some());
+    }
+}
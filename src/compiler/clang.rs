@@ -20,8 +20,8 @@ use ::compiler::{
     Compiler,
     CompilerArguments,
     ParsedArguments,
+    PreprocessorOutput,
     run_input_output,
-    write_temp_file,
 };
 use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
@@ -50,7 +50,7 @@ pub fn argument_takes_value(arg: &str) -> bool {
 
 pub fn compile<T>(creator: &T,
                   compiler: &Compiler,
-                  preprocessor_output: Vec<u8>,
+                  preprocessor_output: PreprocessorOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &str,
                   pool: &CpuPool)
@@ -65,7 +65,7 @@ pub fn compile<T>(creator: &T,
             Some(name) => name,
             None => return future::err("missing input filename".into()).boxed(),
         };
-        write_temp_file(pool, filename.as_ref(), preprocessor_output)
+        preprocessor_output.into_temp_file(pool, filename.as_ref())
     };
     let input = parsed_args.input.clone();
     let out_file = match parsed_args.outputs.get("obj") {
@@ -75,7 +75,7 @@ pub fn compile<T>(creator: &T,
         }
     };
 
-    let mut attempt = creator.clone().new_command_sync(&compiler.executable);
+    let mut attempt = compiler.new_command(creator);
     attempt.arg("-c")
         .arg("-o")
         .arg(&out_file)
@@ -98,7 +98,7 @@ pub fn compile<T>(creator: &T,
         return Box::new(output.map(|output| (Cacheable::Yes, output)))
     }
 
-    let mut cmd = creator.clone().new_command_sync(&compiler.executable);
+    let mut cmd = compiler.new_command(creator);
     cmd.arg("-c")
         .arg(&parsed_args.input)
         .arg("-o")
@@ -184,7 +184,7 @@ mod test {
         next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
         let (cacheable, _) = compile(&creator,
                                      &compiler,
-                                     vec!(),
+                                     PreprocessorOutput::Memory(vec!()),
                                      &parsed_args,
                                      f.tempdir.path().to_str().unwrap(),
                                      &pool).wait().unwrap();
@@ -214,7 +214,7 @@ mod test {
         next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
         let (cacheable, output) = compile(&creator,
                                           &compiler,
-                                          vec!(),
+                                          PreprocessorOutput::Memory(vec!()),
                                           &parsed_args,
                                           f.tempdir.path().to_str().unwrap(),
                                           &pool).wait().unwrap();
@@ -14,16 +14,27 @@
 
 use cache::{
     Cache,
+    KeyInputs,
     Storage,
+    env_flag,
+    get_verified,
     hash_key,
+    hash_key_from,
+    key_env_var_names,
+    parse_size,
+    sha1_update_from,
 };
 use compiler::{
+    Sloppiness,
     clang,
     gcc,
     msvc,
 };
+use dist::RunJob;
+use dist::client::Client as DistClient;
 use filetime::FileTime;
-use futures::future;
+use futures::future::{self, Loop, loop_fn};
+use futures::sync::oneshot;
 use futures::{Future, IntoFuture};
 use futures_cpupool::CpuPool;
 use log::LogLevel::Debug;
@@ -32,11 +43,14 @@ use mock_command::{
     CommandCreatorSync,
     RunCommand,
     exit_status,
+    exit_status_from_code,
 };
 use sha1;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::ffi::OsString;
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{self,File};
 use std::io::prelude::*;
@@ -46,6 +60,7 @@ use std::io::{
 };
 use std::path::{Path, PathBuf};
 use std::process::{self,Stdio};
+use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
 use std::time::{
@@ -53,9 +68,19 @@ use std::time::{
     Instant,
 };
 use tempdir::TempDir;
+use tokio_core::io::read_to_end;
 
 use errors::*;
 
+/// How much preprocessor output is held in memory before it's written to a
+/// temporary file instead.
+const DEFAULT_SPILL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The names of the input and object files of compilations run on build
+/// servers.
+const DIST_INPUT_NAME: &'static str = "input";
+const DIST_OUTPUT_NAME: &'static str = "output.o";
+
 /// Supported compilers.
 #[derive(Debug, PartialEq, Clone)]
 pub enum CompilerKind {
@@ -71,6 +96,15 @@ pub enum CompilerKind {
 }
 
 impl CompilerKind {
+    /// The name of this kind of compiler.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CompilerKind::Gcc => "gcc",
+            CompilerKind::Clang => "clang",
+            CompilerKind::Msvc { .. } => "msvc",
+        }
+    }
+
     pub fn parse_arguments(&self,
                            arguments: &[String],
                            cwd: &Path) -> CompilerArguments {
@@ -89,7 +123,7 @@ impl CompilerKind {
                          parsed_args: &ParsedArguments,
                          cwd: &str,
                          pool: &CpuPool)
-                         -> SFuture<process::Output>
+                         -> SFuture<Preprocessed>
         where T: CommandCreatorSync
     {
         match *self {
@@ -104,7 +138,7 @@ impl CompilerKind {
     pub fn compile<T>(&self,
                       creator: &T,
                       compiler: &Compiler,
-                      preprocessor_output: Vec<u8>,
+                      preprocessor_output: PreprocessorOutput,
                       parsed_args: &ParsedArguments,
                       cwd: &str,
                       pool: &CpuPool)
@@ -117,6 +151,19 @@ impl CompilerKind {
             CompilerKind::Msvc { .. } => msvc::compile(creator, compiler, preprocessor_output, parsed_args, cwd, pool),
         }
     }
+
+    /// The arguments to compile preprocessor output on a build server, or
+    /// `None` if this compilation can't run on one.
+    pub fn dist_arguments(&self,
+                          parsed_args: &ParsedArguments,
+                          input_name: &str,
+                          output_name: &str) -> Option<Vec<String>> {
+        match *self {
+            CompilerKind::Gcc | CompilerKind::Clang => gcc::dist_arguments(parsed_args, input_name, output_name),
+            // Only clang-cl can run these, from a cross toolchain.
+            CompilerKind::Msvc { .. } => msvc::dist_arguments(parsed_args, input_name, output_name),
+        }
+    }
 }
 
 /// The results of parsing a compiler commandline.
@@ -148,8 +195,9 @@ impl ParsedArguments {
 pub enum CompilerArguments {
     /// Commandline can be handled.
     Ok(ParsedArguments),
-    /// Cannot cache this compilation.
-    CannotCache,
+    /// Cannot cache this compilation, because of the given argument or
+    /// kind of argument.
+    CannotCache(&'static str),
     /// This commandline is not a compile.
     NotCompilation,
 }
@@ -165,6 +213,31 @@ pub struct Compiler {
     pub digest: String,
     /// The kind of compiler, from the set of known compilers.
     pub kind: CompilerKind,
+    /// The version of the compiler, if it could be detected.
+    pub version: Option<String>,
+    /// Environment variables that are part of the cache key of its
+    /// compilations, in addition to `CACHED_ENV_VARS`, from its policy.
+    pub hashed_env_vars: Vec<String>,
+    /// The environment of the client that requested the compilation, if
+    /// any. Cached environment variables are taken from it instead of the
+    /// server's environment, and passed on to the compiler.
+    pub client_env: Option<Vec<(String, String)>>,
+    /// What to be less strict about when caching its compilations, from
+    /// its policy.
+    pub sloppiness: Sloppiness,
+}
+
+/// The result of `Compiler::check_cached`.
+#[derive(Debug, PartialEq)]
+pub enum CacheCheck {
+    /// The preprocessor failed, with this stderr, so there is no key.
+    PreprocessorFailed(Vec<u8>),
+    /// The key is in the cache.
+    Hit(String),
+    /// The key is not in the cache.
+    Miss(String),
+    /// The cache couldn't be reached to look up the key.
+    Unavailable(String),
 }
 
 /// Specifics about cache misses.
@@ -174,6 +247,8 @@ pub enum MissType {
     Normal,
     /// Cache lookup was overridden, recompilation was forced.
     ForcedRecache,
+    /// The cache couldn't be reached, so it wasn't checked.
+    CacheUnavailable,
 }
 
 /// Information about a successful cache write.
@@ -182,6 +257,21 @@ pub struct CacheWriteInfo {
     pub duration: Duration,
 }
 
+/// How long the phases of `get_cached_or_compile` took. Phases that
+/// didn't run are `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseTimings {
+    /// Running the preprocessor.
+    pub preprocess: Option<Duration>,
+    /// Looking up the cache entry, and extracting it on a hit.
+    pub cache_lookup: Option<Duration>,
+    /// Running the compiler.
+    pub compile: Option<Duration>,
+    /// On a cache hit, how long running the compiler took when the entry
+    /// was stored, if the entry recorded it.
+    pub cached_compile: Option<Duration>,
+}
+
 /// The result of a compilation or cache retrieval.
 pub enum CompileResult {
     /// An error made the compilation not possible.
@@ -197,6 +287,8 @@ pub enum CompileResult {
     NotCacheable,
     /// Not in cache, but compilation failed.
     CompileFailed,
+    /// The cache was bypassed, the compiler was run as is.
+    Bypassed,
 }
 
 
@@ -209,6 +301,7 @@ impl fmt::Debug for CompileResult {
             &CompileResult::CacheMiss(ref m, ref d, _) => write!(f, "CompileResult::CacheMiss({:?}, {:?}, _)", d, m),
             &CompileResult::NotCacheable => write!(f, "CompileResult::NotCacheable"),
             &CompileResult::CompileFailed => write!(f, "CompileResult::CompileFailed"),
+            &CompileResult::Bypassed => write!(f, "CompileResult::Bypassed"),
         }
     }
 }
@@ -222,6 +315,7 @@ impl PartialEq<CompileResult> for CompileResult {
             (&CompileResult::CacheMiss(ref m, _, _), &CompileResult::CacheMiss(ref n, _, _)) => m == n,
             (&CompileResult::NotCacheable, &CompileResult::NotCacheable) => true,
             (&CompileResult::CompileFailed, &CompileResult::CompileFailed) => true,
+            (&CompileResult::Bypassed, &CompileResult::Bypassed) => true,
             _ => false,
         }
     }
@@ -241,6 +335,84 @@ pub enum CacheControl {
     Default,
     /// Ignore existing cache entries, force recompilation.
     ForceRecache,
+    /// Run the compiler as is, neither looking up nor storing a cache entry.
+    Bypass,
+}
+
+/// Explains cache misses by comparing the inputs to the key of each
+/// compilation with those of the last compilation of the same output.
+#[derive(Clone, Default)]
+pub struct MissExplainer {
+    previous: Rc<RefCell<HashMap<PathBuf, KeyInputs>>>,
+}
+
+impl MissExplainer {
+    /// Log `inputs`, the inputs to `key`, the key of compiling `output`,
+    /// and if it wasn't a `hit`, which of them changed since `output` was
+    /// last compiled.
+    pub fn explain(&self, output: PathBuf, key: &str, inputs: KeyInputs, hit: bool) {
+        let name = output.display().to_string();
+        info!("[{}]: Cache {} for key {}: {}", name, if hit { "hit" } else { "miss" }, key, inputs);
+        let previous = self.previous.borrow_mut().insert(output, inputs.clone());
+        if hit {
+            return;
+        }
+        match previous {
+            None => info!("[{}]: Not compiled before, nothing to compare with", name),
+            Some(ref previous) if *previous == inputs => {
+                info!("[{}]: Key unchanged since the last compilation, its entry was evicted or not stored", name)
+            }
+            Some(previous) => {
+                for change in inputs.changes(&previous) {
+                    info!("[{}]: Changed since the last compilation: {}", name, change);
+                }
+            }
+        }
+    }
+}
+
+/// The keys of the compilations in progress, so that identical
+/// compilations requested meanwhile wait for the first to store its result
+/// instead of running the compiler again.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    waiting: Rc<RefCell<HashMap<String, Vec<oneshot::Sender<()>>>>>,
+}
+
+/// Held by the first compilation of a key until its cache entry has been
+/// stored, or it has turned out not to store one.
+pub struct InFlightGuard {
+    in_flight: InFlight,
+    key: String,
+}
+
+impl InFlight {
+    /// Start the compilation of `key`. If it's already being compiled,
+    /// returns a receiver that's completed (or canceled) once that
+    /// compilation is done. Otherwise, returns a guard to hold until this
+    /// one is.
+    pub fn start(&self, key: &str) -> ::std::result::Result<InFlightGuard, oneshot::Receiver<()>> {
+        let mut waiting = self.waiting.borrow_mut();
+        if let Some(waiters) = waiting.get_mut(key) {
+            let (tx, rx) = oneshot::channel();
+            waiters.push(tx);
+            return Err(rx);
+        }
+        waiting.insert(key.to_owned(), vec!());
+        Ok(InFlightGuard {
+            in_flight: self.clone(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let waiters = self.in_flight.waiting.borrow_mut().remove(&self.key);
+        for tx in waiters.into_iter().flat_map(|w| w) {
+            tx.complete(());
+        }
+    }
 }
 
 impl Compiler {
@@ -266,9 +438,49 @@ impl Compiler {
             mtime: FileTime::from_last_modification_time(&attr),
             digest: m.digest().to_string(),
             kind: kind,
+            version: None,
+            hashed_env_vars: vec!(),
+            client_env: None,
+            sloppiness: Sloppiness::default(),
         })
     }
 
+    /// The value of the environment variable `var` for compilations, from
+    /// the client's environment if there is one.
+    pub fn env_var(&self, var: &str) -> Option<String> {
+        match self.client_env {
+            Some(ref client_env) => {
+                client_env.iter().find(|&&(ref k, _)| k == var).map(|&(_, ref v)| v.clone())
+            }
+            None => env::var(var).ok(),
+        }
+    }
+
+    /// Create a command running this compiler, with the cached environment
+    /// variables set as in the client's environment, so that the output
+    /// matches the cache key.
+    pub fn new_command<T: CommandCreatorSync>(&self, creator: &T) -> T::Cmd {
+        self.new_command_running(creator, &self.executable)
+    }
+
+    /// Create a command running `program`, such as a wrapper of this
+    /// compiler, with the environment of `new_command`.
+    fn new_command_running<T, S>(&self, creator: &T, program: S) -> T::Cmd
+        where T: CommandCreatorSync,
+              S: AsRef<OsStr>,
+    {
+        let mut cmd = creator.clone().new_command_sync(program);
+        if self.client_env.is_some() {
+            for var in key_env_var_names(self) {
+                match self.env_var(&var) {
+                    Some(val) => { cmd.env(&var, val); }
+                    None => { cmd.env_remove(&var); }
+                }
+            }
+        }
+        cmd
+    }
+
     /// Check that this compiler can handle and cache when run with `arguments`, and parse out the relevant bits.
     ///
     /// Not all compiler options can be cached, so this tests the set of
@@ -283,7 +495,7 @@ impl Compiler {
         let parsed_args = self.kind.parse_arguments(arguments, cwd);
         match parsed_args {
             CompilerArguments::Ok(_) => debug!("parse_arguments: Ok"),
-            CompilerArguments::CannotCache => debug!("parse_arguments: CannotCache"),
+            CompilerArguments::CannotCache(why) => debug!("parse_arguments: CannotCache({})", why),
             CompilerArguments::NotCompilation => debug!("parse_arguments: NotCompilation"),
         };
         parsed_args
@@ -291,6 +503,9 @@ impl Compiler {
 
     /// Look up a cached compile result in `storage`. If not found, run the
     /// compile and store the result.
+    ///
+    /// With an `explainer`, the inputs to the key are logged, along with
+    /// which of them changed on a miss.
     pub fn get_cached_or_compile<T>(&self,
                                     creator: &T,
                                     storage: &Arc<Storage>,
@@ -298,8 +513,11 @@ impl Compiler {
                                     parsed_args: &ParsedArguments,
                                     cwd: &str,
                                     cache_control: CacheControl,
+                                    explainer: Option<MissExplainer>,
+                                    dist_client: Option<DistClient>,
+                                    in_flight: &InFlight,
                                     pool: &CpuPool)
-                                    -> SFuture<(CompileResult, process::Output)>
+                                    -> SFuture<(CompileResult, process::Output, PhaseTimings)>
         where T: CommandCreatorSync
     {
         let out_file = parsed_args.output_file();
@@ -307,6 +525,22 @@ impl Compiler {
             let cmd_str = arguments.join(" ");
             debug!("[{}]: get_cached_or_compile: {}", out_file, cmd_str);
         }
+        if cache_control == CacheControl::Bypass {
+            debug!("[{}]: Bypassing the cache", out_file);
+            for path in parsed_args.outputs.values() {
+                remove_output(&Path::new(cwd).join(path));
+            }
+            let mut cmd = self.new_command(creator);
+            cmd.args(arguments)
+                .current_dir(cwd);
+            let start = Instant::now();
+            return Box::new(run_input_output(cmd, None).map(move |output| {
+                let mut timings = PhaseTimings::default();
+                timings.compile = Some(start.elapsed());
+                (CompileResult::Bypassed, output, timings)
+            }))
+        }
+        let preprocess_start = Instant::now();
         let result = self.kind.preprocess(creator, self, parsed_args, cwd, pool);
         let out_file = out_file.into_owned();
         let result = result.map_err(move |e| {
@@ -319,8 +553,12 @@ impl Compiler {
         let storage = storage.clone();
         let pool = pool.clone();
         let creator = creator.clone();
+        let command_args = arguments.to_vec();
+        let in_flight = in_flight.clone();
 
         Box::new(result.and_then(move |preprocessor_result| -> SFuture<_> {
+            let mut timings = PhaseTimings::default();
+            timings.preprocess = Some(preprocess_start.elapsed());
             // If the preprocessor failed, just return that result.
             if !preprocessor_result.status.success() {
                 debug!("[{}]: preprocessor returned error status {:?}",
@@ -328,117 +566,208 @@ impl Compiler {
                        preprocessor_result.status.code());
                 // Drop the stdout since it's the preprocessor output, just hand back stderr and the exit status.
                 let output = process::Output {
+                    status: preprocessor_result.status,
                     stdout: vec!(),
-                    ..preprocessor_result
+                    stderr: preprocessor_result.stderr,
                 };
-                return Box::new(future::ok((CompileResult::Error, output)))
+                return Box::new(future::ok((CompileResult::Error, output, timings)))
             }
             trace!("[{}]: Preprocessor output is {} bytes",
                    parsed_args.output_file(),
                    preprocessor_result.stdout.len());
 
-            // Remove object file from arguments before hash calculation
-            let key = {
-                let out_file = parsed_args.output_file();
-                let arguments = parsed_args.common_args.iter()
-                    .filter(|a| **a != out_file)
-                    .map(|a| a.as_str())
-                    .collect::<String>();
-                hash_key(&me, &arguments, &preprocessor_result.stdout)
-            };
-            trace!("[{}]: Hash key: {}", parsed_args.output_file(), key);
-            // If `ForceRecache` is enabled, we won't check the cache.
-            let start = Instant::now();
-            let cache_status = if cache_control == CacheControl::ForceRecache {
-                Box::new(future::ok(Cache::Recache))
+            let arguments = key_arguments(&parsed_args);
+            let key = preprocessor_result.stdout.hash_key(&me, &arguments, &pool);
+            let inputs: SFuture<Option<KeyInputs>> = if explainer.is_some() {
+                let me = me.clone();
+                Box::new(preprocessor_result.stdout.digest(&pool).map(move |digest| {
+                    Some(KeyInputs::new(&me, &arguments, digest))
+                }))
             } else {
-                storage.get(&key)
+                Box::new(future::ok(None))
             };
+            Box::new(key.join(inputs).and_then(move |(key, inputs)| -> SFuture<_> {
+                trace!("[{}]: Hash key: {}", parsed_args.output_file(), key);
+                // A forced recache compiles regardless. Otherwise, if the
+                // same key is being compiled, wait for that compilation to
+                // store its entry and look it up then. If it didn't store
+                // one, compile as usual.
+                let started: SFuture<Option<InFlightGuard>> = if cache_control == CacheControl::ForceRecache {
+                    Box::new(future::ok(None))
+                } else {
+                    match in_flight.start(&key) {
+                        Ok(guard) => Box::new(future::ok(Some(guard))),
+                        Err(done) => {
+                            debug!("[{}]: Waiting for an identical compilation", parsed_args.output_file());
+                            Box::new(done.then(|_| Ok(None)))
+                        }
+                    }
+                };
+                Box::new(started.and_then(move |guard| {
+                    // If `ForceRecache` is enabled, we won't check the cache.
+                    let start = Instant::now();
+                    let cache_status = if cache_control == CacheControl::ForceRecache {
+                        Box::new(future::ok(Cache::Recache))
+                    } else {
+                        get_verified(storage.clone(), &key, &pool)
+                    };
 
-            Box::new(cache_status.and_then(move |result| {
-                let duration = start.elapsed();
-                let pwd = Path::new(&cwd);
-                let outputs = parsed_args.outputs.iter()
-                    .map(|(key, path)| (key.to_string(), pwd.join(path)))
-                    .collect::<HashMap<_, _>>();
-
-                let miss_type = match result {
-                    Cache::Hit(mut entry) => {
-                        debug!("[{}]: Cache hit!", parsed_args.output_file());
-                        let mut stdout = io::Cursor::new(vec!());
-                        let mut stderr = io::Cursor::new(vec!());
-                        entry.get_object("stdout", &mut stdout).unwrap_or(());
-                        entry.get_object("stderr", &mut stderr).unwrap_or(());
-                        let write = pool.spawn_fn(move ||{
-                            for (key, path) in &outputs {
-                                let mut f = try!(File::create(path));
-                                try!(entry.get_object(&key, &mut f));
+                    Box::new(cache_status.and_then(move |result| {
+                        let duration = start.elapsed();
+                        let pwd = Path::new(&cwd);
+                        let outputs = parsed_args.outputs.iter()
+                            .map(|(key, path)| (key.to_string(), pwd.join(path)))
+                            .collect::<HashMap<_, _>>();
+
+                        timings.cache_lookup = Some(duration);
+                        if let (Some(explainer), Some(inputs)) = (explainer, inputs) {
+                            let hit = match result {
+                                Cache::Hit(_) => true,
+                                _ => false,
+                            };
+                            explainer.explain(pwd.join(&*parsed_args.output_file()), &key, inputs, hit);
+                        }
+                        let miss_type = match result {
+                            Cache::Hit(mut entry) => {
+                                debug!("[{}]: Cache hit!", parsed_args.output_file());
+                                let mut stdout = io::Cursor::new(vec!());
+                                let mut stderr = io::Cursor::new(vec!());
+                                entry.get_object("stdout", &mut stdout).unwrap_or(());
+                                entry.get_object("stderr", &mut stderr).unwrap_or(());
+                                timings.cached_compile = entry.compile_time();
+                                let write = pool.spawn_fn(move ||{
+                                    for (key, path) in &outputs {
+                                        try!(entry.extract_object(&key, path));
+                                    }
+                                    Ok(())
+                                });
+                                let output = process::Output {
+                                    status: exit_status(0),
+                                    stdout: stdout.into_inner(),
+                                    stderr: stderr.into_inner(),
+                                };
+                                let result = CompileResult::CacheHit(duration);
+                                drop(guard);
+                                return Box::new(write.map(move |_| {
+                                    timings.cache_lookup = Some(start.elapsed());
+                                    (result, output, timings)
+                                })) as SFuture<_>
+                            }
+                            Cache::Miss => {
+                                debug!("[{}]: Cache miss!", parsed_args.output_file());
+                                MissType::Normal
+                            }
+                            Cache::Recache => {
+                                debug!("[{}]: Cache recache!", parsed_args.output_file());
+                                MissType::ForcedRecache
+                            }
+                            Cache::Unavailable => {
+                                debug!("[{}]: Cache unavailable!", parsed_args.output_file());
+                                MissType::CacheUnavailable
                             }
-                            Ok(())
-                        });
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout: stdout.into_inner(),
-                            stderr: stderr.into_inner(),
                         };
-                        let result = CompileResult::CacheHit(duration);
-                        return Box::new(write.map(|_| {
-                            (result, output)
-                        })) as SFuture<_>
-                    }
-                    Cache::Miss => {
-                        debug!("[{}]: Cache miss!", parsed_args.output_file());
-                        MissType::Normal
-                    }
-                    Cache::Recache => {
-                        debug!("[{}]: Cache recache!", parsed_args.output_file());
-                        MissType::ForcedRecache
+                        me.compile(&creator,
+                                   preprocessor_result,
+                                   parsed_args,
+                                   command_args,
+                                   &cwd,
+                                   pool,
+                                   outputs,
+                                   storage,
+                                   key,
+                                   miss_type,
+                                   timings,
+                                   dist_client,
+                                   guard)
+                    }))
+                }))
+            }))
+        }))
+    }
+
+    /// Compute the cache key of a compilation run with `parsed_args` the
+    /// way `get_cached_or_compile` does, and look it up in `storage`,
+    /// without running the compiler or extracting anything. The
+    /// preprocessor still has to run.
+    pub fn check_cached<T>(&self,
+                           creator: &T,
+                           storage: &Arc<Storage>,
+                           parsed_args: &ParsedArguments,
+                           cwd: &str,
+                           pool: &CpuPool)
+                           -> SFuture<CacheCheck>
+        where T: CommandCreatorSync
+    {
+        let result = self.kind.preprocess(creator, self, parsed_args, cwd, pool);
+        let arguments = key_arguments(parsed_args);
+        let me = self.clone();
+        let storage = storage.clone();
+        let pool = pool.clone();
+        Box::new(result.and_then(move |preprocessor_result| -> SFuture<_> {
+            if !preprocessor_result.status.success() {
+                return Box::new(future::ok(CacheCheck::PreprocessorFailed(preprocessor_result.stderr)))
+            }
+            let output = preprocessor_result.stdout;
+            let key = output.hash_key(&me, &arguments, &pool);
+            Box::new(key.and_then(move |key| {
+                // Keep the output until it's hashed.
+                drop(output);
+                get_verified(storage, &key, &pool).map(move |result| {
+                    match result {
+                        Cache::Hit(_) => CacheCheck::Hit(key),
+                        Cache::Unavailable => CacheCheck::Unavailable(key),
+                        Cache::Miss | Cache::Recache => CacheCheck::Miss(key),
                     }
-                };
-                me.compile(&creator,
-                           preprocessor_result,
-                           parsed_args,
-                           &cwd,
-                           pool,
-                           outputs,
-                           storage,
-                           key,
-                           miss_type)
+                })
             }))
         }))
     }
 
     fn compile<T>(&self,
                   creator: &T,
-                  preprocessor_result: process::Output,
+                  preprocessor_result: Preprocessed,
                   parsed_args: ParsedArguments,
+                  command_args: Vec<String>,
                   cwd: &str,
                   pool: CpuPool,
                   outputs: HashMap<String, PathBuf>,
                   storage: Arc<Storage>,
                   key: String,
-                  miss_type: MissType)
-                  -> SFuture<(CompileResult, process::Output)>
+                  miss_type: MissType,
+                  mut timings: PhaseTimings,
+                  dist_client: Option<DistClient>,
+                  in_flight: Option<InFlightGuard>)
+                  -> SFuture<(CompileResult, process::Output, PhaseTimings)>
         where T: CommandCreatorSync,
     {
-        let process::Output { stdout, .. } = preprocessor_result;
+        let Preprocessed { stdout, .. } = preprocessor_result;
         let start = Instant::now();
         let out_file = parsed_args.output_file().into_owned();
+        for path in outputs.values() {
+            remove_output(path);
+        }
 
-        let compile = self.kind.compile(creator, self, stdout, &parsed_args, cwd, &pool);
+        let compile = self.dist_or_local_compile(creator, stdout, &parsed_args, command_args, cwd, &pool,
+                                                 dist_client);
         Box::new(compile.and_then(move |(cacheable, compiler_result)| {
             let duration = start.elapsed();
+            timings.compile = Some(duration);
             if !compiler_result.status.success() {
                 debug!("[{}]: Compiled but failed, not storing in cache",
                        parsed_args.output_file());
-                return Box::new(future::ok((CompileResult::CompileFailed, compiler_result)))
+                return Box::new(future::ok((CompileResult::CompileFailed, compiler_result, timings)))
                     as SFuture<_>
             }
             if cacheable != Cacheable::Yes {
                 // Not cacheable
                 debug!("[{}]: Compiled but not cacheable",
                        parsed_args.output_file());
-                return Box::new(future::ok((CompileResult::NotCacheable, compiler_result)))
+                return Box::new(future::ok((CompileResult::NotCacheable, compiler_result, timings)))
+            }
+            if storage.is_read_only() {
+                debug!("[{}]: Compiled, but cache is read-only", parsed_args.output_file());
+                let future = future::err(ErrorKind::ReadOnlyCache.into()).boxed();
+                return Box::new(future::ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result, timings)))
             }
             debug!("[{}]: Compiled, storing in cache", parsed_args.output_file());
             let mut entry = match storage.start_put(&key) {
@@ -464,12 +793,16 @@ impl Compiler {
                     let mut stderr = &compiler_result.stderr[..];
                     entry.put_object("stderr", &mut stderr)?;
                 }
+                entry.set_compile_time(duration);
 
                 // Try to finish storing the newly-written cache
                 // entry. We'll get the result back elsewhere.
                 let out_file = parsed_args.output_file().into_owned();
                 let future = storage.finish_put(&key, entry)
                     .then(move |res| {
+                        // Identical compilations waiting for this one can
+                        // look the entry up now.
+                        drop(in_flight);
                         match res {
                             Ok(_) => debug!("[{}]: Stored in cache successfully!", out_file),
                             Err(ref e) => debug!("[{}]: Cache write error: {:?}", out_file, e),
@@ -480,12 +813,153 @@ impl Compiler {
                         })
                     });
                 let future = Box::new(future);
-                Ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result))
+                Ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result, timings))
             }).chain_err(move || {
                 format!("failed to store `{}` to cache", out_file)
             }))
         }))
     }
+
+    /// Run the compiler on `preprocessor_output` on a build server of
+    /// `dist_client` if there is one and the compilation can run there,
+    /// through icecc if `SCCACHE_ICECC` is set, and locally otherwise, or
+    /// if the build server or icecc couldn't run it. `command_args` are
+    /// the arguments the compiler was originally run with.
+    fn dist_or_local_compile<T>(&self,
+                                creator: &T,
+                                preprocessor_output: PreprocessorOutput,
+                                parsed_args: &ParsedArguments,
+                                command_args: Vec<String>,
+                                cwd: &str,
+                                pool: &CpuPool,
+                                dist_client: Option<DistClient>)
+                                -> SFuture<(Cacheable, process::Output)>
+        where T: CommandCreatorSync,
+    {
+        let arguments = self.kind.dist_arguments(parsed_args, DIST_INPUT_NAME, DIST_OUTPUT_NAME);
+        let (client, arguments) = match (dist_client, arguments) {
+            (Some(client), Some(arguments)) => (client, arguments),
+            _ => match self.kind {
+                CompilerKind::Gcc | CompilerKind::Clang if env_flag("SCCACHE_ICECC") => {
+                    return self.icecc_or_local_compile(creator, preprocessor_output, parsed_args,
+                                                       command_args, cwd, pool)
+                }
+                _ => return self.kind.compile(creator, self, preprocessor_output, parsed_args, cwd, pool),
+            },
+        };
+        let obj = match parsed_args.outputs.get("obj") {
+            Some(obj) => Path::new(cwd).join(obj),
+            None => return future::err("Missing object file output".into()).boxed(),
+        };
+        let job = RunJob {
+            // The client fills in the job id, the toolchain and the path of
+            // the compiler in it.
+            job_id: 0,
+            toolchain: String::new(),
+            compiler: String::new(),
+            arguments: arguments,
+            input_name: DIST_INPUT_NAME.to_owned(),
+            input: vec!(),
+            outputs: vec![("obj".to_owned(), DIST_OUTPUT_NAME.to_owned())],
+        };
+        let compiler = self.clone();
+        let remote = pool.spawn_fn(move || -> Result<_> {
+            let result = run_dist_job(&client, &compiler, job, &preprocessor_output, &obj);
+            Ok((result, preprocessor_output))
+        });
+        let me = self.clone();
+        let creator = creator.clone();
+        let parsed_args = parsed_args.clone();
+        let cwd = cwd.to_owned();
+        let pool = pool.clone();
+        Box::new(remote.and_then(move |(result, preprocessor_output)| {
+            match result {
+                Ok(Some(output)) => return future::ok((Cacheable::Yes, output)).boxed(),
+                Ok(None) => {}
+                Err(e) => warn!("[{}]: Distributed compilation failed, compiling locally: {}",
+                                parsed_args.output_file(), e),
+            }
+            me.kind.compile(&creator, &me, preprocessor_output, &parsed_args, &cwd, &pool)
+        }))
+    }
+
+    /// Run the original compiler command through icecc, which hands it to
+    /// a machine of the icecream scheduler if one is available, and compile
+    /// `preprocessor_output` locally if icecc can't be run or fails. A
+    /// compile error is reported by the local compile, so it doesn't matter
+    /// whether icecc or the compiler failed.
+    fn icecc_or_local_compile<T>(&self,
+                                 creator: &T,
+                                 preprocessor_output: PreprocessorOutput,
+                                 parsed_args: &ParsedArguments,
+                                 command_args: Vec<String>,
+                                 cwd: &str,
+                                 pool: &CpuPool)
+                                 -> SFuture<(Cacheable, process::Output)>
+        where T: CommandCreatorSync,
+    {
+        let mut cmd = self.new_command_running(creator, "icecc");
+        cmd.arg(&self.executable)
+            .args(&command_args)
+            .current_dir(cwd);
+        let me = self.clone();
+        let creator = creator.clone();
+        let parsed_args = parsed_args.clone();
+        let cwd = cwd.to_owned();
+        let pool = pool.clone();
+        Box::new(run_input_output(cmd, None).then(move |result| {
+            match result {
+                Ok(output) => {
+                    if output.status.success() {
+                        return future::ok((Cacheable::Yes, output)).boxed()
+                    }
+                    debug!("[{}]: icecc failed with {}, compiling locally",
+                           parsed_args.output_file(), output.status);
+                }
+                Err(e) => warn!("[{}]: Failed to run icecc, compiling locally: {}",
+                                parsed_args.output_file(), e),
+            }
+            me.kind.compile(&creator, &me, preprocessor_output, &parsed_args, &cwd, &pool)
+        }))
+    }
+}
+
+/// Run `job` with the contents of `preprocessor_output` as its input on a
+/// build server of `client`, from the toolchain of `compiler`, and write
+/// the object file it returns to `obj`. Returns `None` if no build server
+/// could run it.
+fn run_dist_job(client: &DistClient,
+                compiler: &Compiler,
+                mut job: RunJob,
+                preprocessor_output: &PreprocessorOutput,
+                obj: &Path) -> Result<Option<process::Output>> {
+    let toolchain = match client.toolchain(compiler) {
+        Some(toolchain) => toolchain,
+        None => return Ok(None),
+    };
+    job.input = preprocessor_output.contents()
+        .chain_err(|| "failed to read preprocessor output")?;
+    let result = match client.run_job(job, &toolchain)? {
+        Ok(result) => result,
+        Err(why) => {
+            debug!("Compiling locally, not on a build server: {:?}", why);
+            return Ok(None)
+        }
+    };
+    let retcode = result.retcode.unwrap_or(1);
+    if retcode == 0 {
+        let data = match result.outputs.into_iter().find(|&(ref name, _)| name == "obj") {
+            Some((_, data)) => data,
+            None => bail!("build server didn't send back the object file"),
+        };
+        File::create(obj).and_then(|mut f| f.write_all(&data))
+            .chain_err(|| format!("failed to write {:?}", obj))?;
+    }
+    Ok(Some(process::Output {
+        status: exit_status_from_code(retcode),
+        stdout: result.stdout,
+        stderr: result.stderr,
+    }))
 }
 
 /// Creates a future that will write `contents` to `path` inside of a temporary
@@ -497,11 +971,22 @@ impl Compiler {
 ///
 /// Note that when the `TempDir` is dropped it will delete all of its contents
 /// including the path returned.
+/// Remove a previous compile's output at `path`, which may be hard-linked
+/// into the disk cache, so that the compiler writes a new file instead of
+/// overwriting the cached one in place.
+fn remove_output(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => debug!("Failed to remove {:?}: {}", path, e),
+    }
+}
+
 pub fn write_temp_file(pool: &CpuPool, path: &Path, contents: Vec<u8>)
                        -> SFuture<(TempDir, PathBuf)> {
     let path = path.to_owned();
     pool.spawn_fn(move || -> Result<_> {
-        let dir = TempDir::new("sccache")?;
+        let dir = temp_dir("sccache")?;
         let src = dir.path().join(path);
         let mut file = File::create(&src)?;
         file.write_all(&contents)?;
@@ -511,18 +996,307 @@ pub fn write_temp_file(pool: &CpuPool, path: &Path, contents: Vec<u8>)
     })
 }
 
+/// The directory temporary files are created in, from `SCCACHE_TMPDIR`,
+/// or the system's.
+pub fn temp_root() -> PathBuf {
+    env::var_os("SCCACHE_TMPDIR").map(PathBuf::from).unwrap_or_else(env::temp_dir)
+}
+
+/// Create a new temporary directory in `temp_root`, named starting with
+/// `prefix`.
+pub fn temp_dir(prefix: &str) -> io::Result<TempDir> {
+    TempDir::new_in(temp_root(), prefix)
+}
+
+/// Warn when the filesystem of `temp_root` has less free space than this,
+/// in bytes.
+const MIN_TEMP_SPACE: u64 = 100 * 1024 * 1024;
+
+/// Check that temporary files can be written in `temp_root`, warning if
+/// its filesystem is short of space.
+pub fn check_temp_root() -> Result<()> {
+    let root = temp_root();
+    let dir = temp_dir("sccache").chain_err(|| {
+        format!("can't create temporary files in {}", root.display())
+    })?;
+    File::create(dir.path().join("check")).and_then(|mut f| f.write_all(b"sccache"))
+        .chain_err(|| format!("can't write temporary files in {}", root.display()))?;
+    match free_space(&root) {
+        Some(space) if space < MIN_TEMP_SPACE => {
+            warn!("Only {} bytes are free for temporary files in {}", space, root.display());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The free space on the filesystem of `path`, in bytes, if it can be found.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use libc;
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return None,
+    };
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+/// The free space on the filesystem of `path`, in bytes, if it can be found.
+#[cfg(windows)]
+fn free_space(path: &Path) -> Option<u64> {
+    use kernel32;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    let path = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<_>>();
+    let mut available = 0;
+    let ok = unsafe {
+        kernel32::GetDiskFreeSpaceExW(path.as_ptr(), &mut available, ptr::null_mut(), ptr::null_mut())
+    };
+    if ok == 0 { None } else { Some(available) }
+}
+
+/// Get how much preprocessor output is held in memory before it's written
+/// to a temporary file, from `SCCACHE_SPILL_SIZE`.
+fn spill_size() -> u64 {
+    env::var("SCCACHE_SPILL_SIZE")
+        .ok()
+        .and_then(|s| parse_size(&s))
+        .map_or(DEFAULT_SPILL_SIZE, |s| s as u64)
+}
+
+/// The output of the preprocessor.
+pub enum PreprocessorOutput {
+    /// Output small enough to be held in memory.
+    Memory(Vec<u8>),
+    /// Output written to a file in a temporary directory, which is removed
+    /// when this is dropped.
+    File(TempDir, PathBuf),
+}
+
+impl PreprocessorOutput {
+    /// The size of the output, in bytes.
+    pub fn len(&self) -> u64 {
+        match *self {
+            PreprocessorOutput::Memory(ref data) => data.len() as u64,
+            PreprocessorOutput::File(_, ref path) => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        }
+    }
+
+    /// The output itself, read from its file if it's in one.
+    fn contents(&self) -> io::Result<Vec<u8>> {
+        match *self {
+            PreprocessorOutput::Memory(ref data) => Ok(data.clone()),
+            PreprocessorOutput::File(_, ref path) => {
+                let mut data = vec!();
+                File::open(path)?.read_to_end(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Compute the hash key of `compiler` compiling this output with
+    /// `arguments`. Output in a file is hashed on `pool`.
+    fn hash_key(&self, compiler: &Compiler, arguments: &str, pool: &CpuPool) -> SFuture<String> {
+        match *self {
+            PreprocessorOutput::Memory(ref data) => {
+                Box::new(future::ok(hash_key(compiler, arguments, data)))
+            }
+            PreprocessorOutput::File(_, ref path) => {
+                let compiler = compiler.clone();
+                let arguments = arguments.to_owned();
+                let path = path.clone();
+                pool.spawn_fn(move || {
+                    let mut file = BufReader::new(File::open(&path)?);
+                    hash_key_from(&compiler, &arguments, &mut file)
+                }).chain_err(|| "failed to hash preprocessor output")
+            }
+        }
+    }
+
+    /// Compute the SHA-1 digest of this output. Output in a file is hashed
+    /// on `pool`.
+    fn digest(&self, pool: &CpuPool) -> SFuture<String> {
+        match *self {
+            PreprocessorOutput::Memory(ref data) => {
+                let mut m = sha1::Sha1::new();
+                m.update(data);
+                Box::new(future::ok(m.digest().to_string()))
+            }
+            PreprocessorOutput::File(_, ref path) => {
+                let path = path.clone();
+                pool.spawn_fn(move || -> io::Result<String> {
+                    let mut m = sha1::Sha1::new();
+                    sha1_update_from(&mut m, &mut File::open(&path)?)?;
+                    Ok(m.digest().to_string())
+                }).chain_err(|| "failed to hash preprocessor output")
+            }
+        }
+    }
+
+    /// Put this output in a file named `name` in a temporary directory,
+    /// returning the directory and the path of the file.
+    pub fn into_temp_file(self, pool: &CpuPool, name: &Path) -> SFuture<(TempDir, PathBuf)> {
+        match self {
+            PreprocessorOutput::Memory(data) => write_temp_file(pool, name, data),
+            PreprocessorOutput::File(dir, path) => {
+                let dest = dir.path().join(name);
+                let res = fs::rename(&path, &dest).map(|()| (dir, dest));
+                Box::new(future::result(res).chain_err(|| "failed to rename preprocessor output"))
+            }
+        }
+    }
+}
+
+/// The result of running the preprocessor.
+pub struct Preprocessed {
+    pub status: process::ExitStatus,
+    pub stdout: PreprocessorOutput,
+    pub stderr: Vec<u8>,
+}
+
+/// Preprocessor output being read, which is moved to a temporary file once
+/// it grows larger than `spill_size`.
+struct Capture {
+    data: Vec<u8>,
+    file: Option<(TempDir, PathBuf, File)>,
+    spill_size: u64,
+}
+
+impl Capture {
+    /// Add the first `n` bytes of `buf` to the output, handing `buf` back
+    /// once it can be reused. Output is only written to the file on `pool`.
+    fn write(mut self, buf: Vec<u8>, n: usize, pool: &CpuPool) -> SFuture<(Capture, Vec<u8>)> {
+        if self.file.is_none() {
+            self.data.extend_from_slice(&buf[..n]);
+            if self.data.len() as u64 <= self.spill_size {
+                return Box::new(future::ok((self, buf)));
+            }
+        }
+        let mut capture = self;
+        pool.spawn_fn(move || -> io::Result<_> {
+            match capture.file.take() {
+                Some((dir, path, mut file)) => {
+                    file.write_all(&buf[..n])?;
+                    capture.file = Some((dir, path, file));
+                }
+                None => {
+                    let dir = temp_dir("sccache")?;
+                    let path = dir.path().join("preprocessed");
+                    let mut file = File::create(&path)?;
+                    file.write_all(&capture.data)?;
+                    capture.data = vec!();
+                    capture.file = Some((dir, path, file));
+                }
+            }
+            Ok((capture, buf))
+        }).chain_err(|| "failed to write preprocessor output")
+    }
+
+    fn finish(self) -> PreprocessorOutput {
+        match self.file {
+            // The file is closed here, so it can be renamed on Windows.
+            Some((dir, path, _)) => PreprocessorOutput::File(dir, path),
+            None => PreprocessorOutput::Memory(self.data),
+        }
+    }
+}
+
+/// Read all of `output`, the preprocessor's stdout, holding it in memory up
+/// to `spill_size` bytes and writing it to a temporary file beyond that.
+fn read_spilling<R>(output: R, spill_size: u64, pool: &CpuPool) -> SFuture<PreprocessorOutput>
+    where R: Read + 'static,
+{
+    use tokio_core::io::read;
+    let capture = Capture {
+        data: vec!(),
+        file: None,
+        spill_size: spill_size,
+    };
+    let buf = vec![0; 64 * 1024];
+    let pool = pool.clone();
+    Box::new(loop_fn((output, buf, capture), move |(output, buf, capture)| {
+        let pool = pool.clone();
+        read(output, buf).chain_err(|| "failed to read preprocessor output")
+            .and_then(move |(output, buf, n)| -> SFuture<_> {
+                if n == 0 {
+                    return Box::new(future::ok(Loop::Break(capture.finish())));
+                }
+                Box::new(capture.write(buf, n, &pool).map(move |(capture, buf)| {
+                    Loop::Continue((output, buf, capture))
+                }))
+            })
+    }))
+}
+
+/// Run the preprocessor `command` and return its status and output, writing
+/// the output to a temporary file if it's larger than `SCCACHE_SPILL_SIZE`.
+pub fn run_preprocessor<C>(mut command: C, pool: &CpuPool) -> SFuture<Preprocessed>
+    where C: RunCommand
+{
+    let child = command
+        .no_console()
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .chain_err(|| "failed to spawn preprocessor");
+    let spill_size = spill_size();
+    let pool = pool.clone();
+    Box::new(future::result(child).and_then(move |mut child| {
+        let stdout = match child.take_stdout() {
+            Some(io) => read_spilling(io, spill_size, &pool),
+            None => Box::new(future::ok(PreprocessorOutput::Memory(vec!()))),
+        };
+        let stderr = child.take_stderr().map(|io| read_to_end(io, Vec::new()));
+        let stderr = stderr.chain_err(|| "failed to read stderr");
+        wait_child(child).join3(stdout, stderr).map(|(status, stdout, stderr)| {
+            Preprocessed {
+                status: status,
+                stdout: stdout,
+                stderr: stderr.map(|p| p.1).unwrap_or_default(),
+            }
+        })
+    }))
+}
+
 /// If `executable` is a known compiler, return `Some(CompilerKind)`.
 pub fn detect_compiler_kind<T>(creator: &T, executable: &str, pool: &CpuPool)
                                -> SFuture<Option<CompilerKind>>
     where T: CommandCreatorSync
+{
+    Box::new(detect_compiler(creator, executable, pool).map(|detected| {
+        detected.map(|(kind, _)| kind)
+    }))
+}
+
+/// Detect the kind and, if possible, the version of the compiler `executable`.
+fn detect_compiler<T>(creator: &T, executable: &str, pool: &CpuPool)
+                      -> SFuture<Option<(CompilerKind, Option<String>)>>
+    where T: CommandCreatorSync
 {
     trace!("detect_compiler");
+    // The version macros are printed on a separate line, joined with `.`
+    // afterwards so that the preprocessor can't paste them together.
     let test = b"#if defined(_MSC_VER)
 msvc
+version _MSC_FULL_VER
 #elif defined(__clang__)
 clang
+version __clang_major__ __clang_minor__ __clang_patchlevel__
 #elif defined(__GNUC__)
 gcc
+version __GNUC__ __GNUC_MINOR__ __GNUC_PATCHLEVEL__
 #endif
 ".to_vec();
     let write = write_temp_file(pool, "testfile.c".as_ref(), test);
@@ -552,24 +1326,25 @@ gcc
             Ok(s) => s,
             Err(_) => return future::err("Failed to parse output".into()).boxed(),
         };
+        let version = parse_compiler_version(stdout);
         for line in stdout.lines() {
             //TODO: do something smarter here.
             if line == "gcc" {
-                debug!("Found GCC");
-                return future::ok(Some(CompilerKind::Gcc)).boxed()
+                debug!("Found GCC {}", version.as_ref().map(|v| &v[..]).unwrap_or("(unknown version)"));
+                return future::ok(Some((CompilerKind::Gcc, version))).boxed()
             } else if line == "clang" {
-                debug!("Found clang");
-                return future::ok(Some(CompilerKind::Clang)).boxed()
+                debug!("Found clang {}", version.as_ref().map(|v| &v[..]).unwrap_or("(unknown version)"));
+                return future::ok(Some((CompilerKind::Clang, version))).boxed()
             } else if line == "msvc" {
-                debug!("Found MSVC");
+                debug!("Found MSVC {}", version.as_ref().map(|v| &v[..]).unwrap_or("(unknown version)"));
                 let prefix = msvc::detect_showincludes_prefix(&creator,
                                                               executable.as_ref(),
                                                               &pool);
                 return Box::new(prefix.map(|prefix| {
                     trace!("showIncludes prefix: '{}'", prefix);
-                    Some(CompilerKind::Msvc {
+                    Some((CompilerKind::Msvc {
                         includes_prefix: prefix,
-                    })
+                    }, version))
                 }))
             }
         }
@@ -577,6 +1352,26 @@ gcc
     }))
 }
 
+/// The arguments of `parsed_args` that are part of the cache key, which
+/// leaves out the output file.
+pub fn key_arguments(parsed_args: &ParsedArguments) -> String {
+    let out_file = parsed_args.output_file();
+    parsed_args.common_args.iter()
+        .filter(|a| **a != out_file)
+        .map(|a| a.as_str())
+        .collect::<String>()
+}
+
+/// Find the version printed by the test file of `detect_compiler` in the
+/// preprocessor output `stdout`.
+fn parse_compiler_version(stdout: &str) -> Option<String> {
+    stdout.lines()
+        .filter(|line| line.starts_with("version "))
+        .map(|line| line.split_whitespace().skip(1).collect::<Vec<_>>().join("."))
+        // Macros the compiler didn't define are left as they were.
+        .find(|version| !version.is_empty() && version.chars().all(|c| c.is_digit(10) || c == '.'))
+}
+
 /// If `executable` is a known compiler, return `Some(Compiler)` containing information about it.
 pub fn get_compiler_info<T>(creator: &T, executable: &str, pool: &CpuPool)
                             -> SFuture<Compiler>
@@ -584,17 +1379,35 @@ pub fn get_compiler_info<T>(creator: &T, executable: &str, pool: &CpuPool)
 {
     let executable = executable.to_string();
     let pool = pool.clone();
-    Box::new(detect_compiler_kind(creator, &executable, &pool).and_then(move |kind| {
-        match kind {
-            Some(kind) => {
-                pool.spawn_fn(move || Compiler::new(&executable, kind))
-                    .chain_err(|| "failed to learn compiler metadata")
+    Box::new(detect_compiler(creator, &executable, &pool).and_then(move |detected| {
+        match detected {
+            Some((kind, version)) => {
+                pool.spawn_fn(move || {
+                    Compiler::new(&executable, kind).map(|compiler| {
+                        Compiler { version: version, ..compiler }
+                    })
+                }).chain_err(|| "failed to learn compiler metadata")
             }
             None => future::err("could not determine compiler kind".into()).boxed(),
         }
     }))
 }
 
+/// Wait for `child` to exit, failing with `ErrorKind::ProcessTimedOut` if it
+/// was killed for running too long.
+fn wait_child<T>(child: T) -> SFuture<process::ExitStatus>
+    where T: CommandChild + 'static,
+{
+    Box::new(child.wait().then(|res| {
+        match res {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                Err(ErrorKind::ProcessTimedOut.into())
+            }
+            res => res.chain_err(|| "failed to wait for child"),
+        }
+    }))
+}
+
 /// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
 ///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
@@ -603,7 +1416,7 @@ pub fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
                                  -> SFuture<process::Output>
     where T: CommandChild + 'static,
 {
-    use tokio_core::io::{write_all, read_to_end};
+    use tokio_core::io::write_all;
     let stdin = input.and_then(|i| {
         child.take_stdin().map(|stdin| {
             write_all(stdin, i)
@@ -617,7 +1430,7 @@ pub fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
     // Finish writing stdin before waiting, because waiting drops stdin.
     let status = Future::and_then(stdin, |io| {
         drop(io);
-        child.wait().chain_err(|| "failed to wait for child")
+        wait_child(child)
     });
 
     Box::new(status.join3(stdout, stderr).map(|(status, out, err)| {
@@ -657,12 +1470,43 @@ mod test {
     use futures_cpupool::CpuPool;
     use mock_command::*;
     use std::fs::{self,File};
-    use std::io::Write;
+    use std::io::{self, Read, Write};
     use std::sync::Arc;
     use std::time::Duration;
     use std::usize;
     use test::utils::*;
 
+    #[test]
+    fn test_read_spilling() {
+        let data = b"# 1 \"foo.c\"\nint main() { return 0; }\n";
+        let pool = CpuPool::new(1);
+        match read_spilling(io::Cursor::new(data.to_vec()), 100, &pool).wait().unwrap() {
+            PreprocessorOutput::Memory(output) => assert_eq!(&data[..], &output[..]),
+            PreprocessorOutput::File(..) => panic!("Small output was spilled"),
+        }
+        let output = read_spilling(io::Cursor::new(data.to_vec()), 10, &pool).wait().unwrap();
+        assert_eq!(data.len() as u64, output.len());
+        let (dir, path) = output.into_temp_file(&pool, "foo.i".as_ref()).wait().unwrap();
+        assert_eq!(dir.path().join("foo.i"), path);
+        let mut contents = vec!();
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(&data[..], &contents[..]);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let in_flight = InFlight::default();
+        let guard = in_flight.start("key").ok().unwrap();
+        let waiter = match in_flight.start("key") {
+            Ok(_) => panic!("The key should already be in flight"),
+            Err(waiter) => waiter,
+        };
+        assert!(in_flight.start("other").is_ok());
+        drop(guard);
+        waiter.wait().unwrap();
+        assert!(in_flight.start("key").is_ok());
+    }
+
     #[test]
     fn test_detect_compiler_kind_gcc() {
         let creator = new_creator();
@@ -703,6 +1547,14 @@ mod test {
         assert_eq!(Some(CompilerKind::Msvc { includes_prefix: prefix }), kind);
     }
 
+    #[test]
+    fn test_parse_compiler_version() {
+        assert_eq!(Some("6.3.0".to_owned()), parse_compiler_version("gcc\nversion 6 3 0\n"));
+        assert_eq!(Some("190024215".to_owned()), parse_compiler_version("msvc\r\nversion 190024215\r\n"));
+        assert_eq!(None, parse_compiler_version("clang\nversion __clang_major__ __clang_minor__ __clang_patchlevel__\n"));
+        assert_eq!(None, parse_compiler_version("gcc\n"));
+    }
+
     #[test]
     fn test_detect_compiler_kind_unknown() {
         let creator = new_creator();
@@ -725,7 +1577,7 @@ mod test {
         let pool = CpuPool::new(1);
         let f = TestFixture::new();
         // Pretend to be GCC.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc\nversion 6 3 0", "")));
         let c = get_compiler_info(&creator,
                                   f.bins[0].to_str().unwrap(),
                                   &pool).wait().unwrap();
@@ -733,6 +1585,7 @@ mod test {
         // sha-1 digest of an empty file.
         assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", c.digest);
         assert_eq!(CompilerKind::Gcc, c.kind);
+        assert_eq!(Some("6.3.0".to_owned()), c.version);
     }
 
     #[test]
@@ -744,7 +1597,7 @@ mod test {
         let pool = CpuPool::new(1);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      usize::MAX,
-                                     &pool);
+                                     &pool).unwrap();
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -772,13 +1625,16 @@ mod test {
             CompilerArguments::Ok(parsed) => parsed,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::Default,
-                                                    &pool).wait().unwrap();
+        let (cached, res, timings) = c.get_cached_or_compile(&creator,
+                                                              &storage,
+                                                              &arguments,
+                                                              &parsed_args,
+                                                              cwd,
+                                                              CacheControl::Default,
+                                                              None,
+                                                              None,
+                                                              &InFlight::default(),
+                                                              &pool).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -791,18 +1647,24 @@ mod test {
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+        assert!(timings.preprocess.is_some());
+        assert!(timings.cache_lookup.is_some());
+        assert!(timings.compile.is_some());
         // Now compile again, which should be a cache hit.
         fs::remove_file(&obj).unwrap();
         // The preprocessor invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
         // There should be no actual compiler invocation.
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::Default,
-                                                    &pool).wait().unwrap();
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::Default,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
@@ -820,7 +1682,7 @@ mod test {
         let pool = CpuPool::new(1);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      usize::MAX,
-                                     &pool);
+                                     &pool).unwrap();
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -848,13 +1710,16 @@ mod test {
             CompilerArguments::Ok(parsed) => parsed,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::Default,
-                                                    &pool).wait().unwrap();
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::Default,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -873,13 +1738,16 @@ mod test {
         // The preprocessor invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
         // There should be no actual compiler invocation.
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::Default,
-                                                    &pool).wait().unwrap();
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::Default,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
@@ -888,6 +1756,71 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_check_cached() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     usize::MAX,
+                                     &pool).unwrap();
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  f.bins[0].to_str().unwrap(),
+                                  &pool).wait().unwrap();
+        let cwd = f.tempdir.path().to_str().unwrap();
+        let arguments = stringvec!["-c", "foo.c", "-o", "foo.o"];
+        let parsed_args = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // Only the preprocessor runs.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let key = match c.check_cached(&creator, &storage, &parsed_args, cwd, &pool).wait().unwrap() {
+            CacheCheck::Miss(key) => key,
+            o @ _ => panic!("Unexpected cache check result: {:?}", o),
+        };
+        // Compile, storing the result under the same key.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move || {
+            match File::create(&o)
+                .and_then(|mut f| f.write_all(b"file contents")) {
+                    Ok(_) => Ok(MockChild::new(exit_status(0), "", "")),
+                    Err(e) => Err(e),
+                }
+        });
+        let (cached, _, _) = c.get_cached_or_compile(&creator,
+                                                     &storage,
+                                                     &arguments,
+                                                     &parsed_args,
+                                                     cwd,
+                                                     CacheControl::Default,
+                                                     None,
+                                                     None,
+                                                     &InFlight::default(),
+                                                     &pool).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        fs::remove_file(&obj).unwrap();
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        assert_eq!(CacheCheck::Hit(key),
+                   c.check_cached(&creator, &storage, &parsed_args, cwd, &pool).wait().unwrap());
+        // Nothing was extracted.
+        assert!(fs::metadata(&obj).is_err());
+        // A failing preprocessor leaves no key.
+        next_command(&creator, Ok(MockChild::new(exit_status(1), "", "error")));
+        assert_eq!(CacheCheck::PreprocessorFailed(b"error".to_vec()),
+                   c.check_cached(&creator, &storage, &parsed_args, cwd, &pool).wait().unwrap());
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_force_recache() {
         use env_logger;
@@ -897,7 +1830,7 @@ mod test {
         let pool = CpuPool::new(1);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      usize::MAX,
-                                     &pool);
+                                     &pool).unwrap();
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -929,13 +1862,16 @@ mod test {
             CompilerArguments::Ok(parsed) => parsed,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::Default,
-                                                    &pool).wait().unwrap();
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::Default,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -950,13 +1886,16 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
         // Now compile again, but force recaching.
         fs::remove_file(&obj).unwrap();
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::ForceRecache,
-                                                    &pool).wait().unwrap();
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::ForceRecache,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -971,6 +1910,47 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_bypass() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     usize::MAX,
+                                     &pool).unwrap();
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  f.bins[0].to_str().unwrap(),
+                                  &pool).wait().unwrap();
+        const COMPILER_STDOUT: &'static [u8] = b"compiler stdout";
+        const COMPILER_STDERR: &'static [u8] = b"compiler stderr";
+        // Only the compiler is run, without the preprocessor.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), COMPILER_STDOUT, COMPILER_STDERR)));
+        let cwd = f.tempdir.path().to_str().unwrap();
+        let arguments = stringvec!["-c", "foo.c", "-o", "foo.o"];
+        let parsed_args = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::Bypass,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
+        assert_eq!(CompileResult::Bypassed, cached);
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
+        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_preprocessor_error() {
         use env_logger;
@@ -980,7 +1960,7 @@ mod test {
         let pool = CpuPool::new(1);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      usize::MAX,
-                                     &pool);
+                                     &pool).unwrap();
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -996,13 +1976,16 @@ mod test {
             CompilerArguments::Ok(parsed) => parsed,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = c.get_cached_or_compile(&creator,
-                                                    &storage,
-                                                    &arguments,
-                                                    &parsed_args,
-                                                    cwd,
-                                                    CacheControl::Default,
-                                                    &pool).wait().unwrap();
+        let (cached, res, _) = c.get_cached_or_compile(&creator,
+                                                       &storage,
+                                                       &arguments,
+                                                       &parsed_args,
+                                                       cwd,
+                                                       CacheControl::Default,
+                                                       None,
+                                                       None,
+                                                       &InFlight::default(),
+                                                       &pool).wait().unwrap();
         assert_eq!(cached, CompileResult::Error);
         assert_eq!(exit_status(1), res.status);
         // Shouldn't get anything on stdout, since that would just be preprocessor spew!
@@ -0,0 +1,117 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use regex::{self, Regex};
+use std::env;
+use std::path::{MAIN_SEPARATOR, Path};
+
+/// A glob pattern, such as `*-gcc`, matching the file name of a compiler
+/// executable, or its full path if it contains a path separator.
+#[derive(Debug)]
+pub struct Pattern {
+    re: Regex,
+    full_path: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let re = regex::quote(pattern).replace(r"\*", ".*").replace(r"\?", ".");
+        let re = if cfg!(windows) { format!("(?i)^{}$", re) } else { format!("^{}$", re) };
+        Pattern {
+            re: Regex::new(&re).unwrap(),
+            full_path: pattern.contains('/') || pattern.contains(MAIN_SEPARATOR),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        if self.full_path {
+            return self.re.is_match(path);
+        }
+        Path::new(path).file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| self.re.is_match(name))
+    }
+}
+
+/// Which compiler executables the server caches the compilations of.
+/// Others are run by the client as they are, without being inspected.
+#[derive(Debug, Default)]
+pub struct CompilerFilter {
+    /// If not empty, only executables matching one of these are cached.
+    allow: Vec<Pattern>,
+    /// Executables matching one of these are never cached.
+    deny: Vec<Pattern>,
+}
+
+/// Parse a comma-separated list of patterns.
+fn parse_patterns(patterns: &str) -> Vec<Pattern> {
+    patterns.split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(Pattern::new)
+        .collect()
+}
+
+impl CompilerFilter {
+    /// Create a filter from the comma-separated patterns in `allow` and `deny`.
+    pub fn new(allow: &str, deny: &str) -> CompilerFilter {
+        CompilerFilter {
+            allow: parse_patterns(allow),
+            deny: parse_patterns(deny),
+        }
+    }
+
+    /// Create a filter from `SCCACHE_COMPILER_ALLOW` and
+    /// `SCCACHE_COMPILER_DENY`.
+    pub fn from_environment() -> CompilerFilter {
+        CompilerFilter::new(&env::var("SCCACHE_COMPILER_ALLOW").unwrap_or_default(),
+                            &env::var("SCCACHE_COMPILER_DENY").unwrap_or_default())
+    }
+
+    /// Whether compilations run with the executable `path` may be cached.
+    pub fn allows(&self, path: &str) -> bool {
+        if self.deny.iter().any(|p| p.matches(path)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|p| p.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compiler_filter() {
+        let filter = CompilerFilter::default();
+        assert!(filter.allows("/usr/bin/gcc"));
+
+        let filter = CompilerFilter::new("", "*-wrapper, /opt/gen/*");
+        assert!(filter.allows("/usr/bin/gcc"));
+        assert!(!filter.allows("/usr/bin/cc-wrapper"));
+        assert!(!filter.allows("/opt/gen/bin/clang"));
+        assert!(filter.allows("/opt/generated/clang"));
+
+        let filter = CompilerFilter::new("gcc,clang?.?", "");
+        assert!(filter.allows("/usr/bin/gcc"));
+        assert!(filter.allows("/usr/bin/clang3.9"));
+        assert!(!filter.allows("/usr/bin/gcc-6"));
+        assert!(!filter.allows("/usr/bin/xgcc"));
+
+        // Denying wins.
+        let filter = CompilerFilter::new("*", "gcc");
+        assert!(!filter.allows("/usr/bin/gcc"));
+        assert!(filter.allows("/usr/bin/clang"));
+    }
+}
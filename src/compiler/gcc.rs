@@ -17,7 +17,10 @@ use ::compiler::{
     Compiler,
     CompilerArguments,
     ParsedArguments,
+    Preprocessed,
+    PreprocessorOutput,
     run_input_output,
+    run_preprocessor,
 };
 use log::LogLevel::Trace;
 use futures::future::{self, Future};
@@ -113,14 +116,14 @@ fn _parse_arguments(arguments: &[String],
             }
             "-MT" => dep_target = it.next(),
             // Can't cache Clang modules.
-            "-fcxx-modules" => return CompilerArguments::CannotCache,
-            "-fmodules" => return CompilerArguments::CannotCache,
+            "-fcxx-modules" => return CompilerArguments::CannotCache("-fcxx-modules"),
+            "-fmodules" => return CompilerArguments::CannotCache("-fmodules"),
             // Can't cache PGO profiled output.
-            "-fprofile-use" => return CompilerArguments::CannotCache,
+            "-fprofile-use" => return CompilerArguments::CannotCache("-fprofile-use"),
             // We already expanded `@` files we could through
             // `ExpandIncludeFile` above, so if one of those arguments now
             // makes it this far we won't understand it.
-            v if v.starts_with('@') => return CompilerArguments::CannotCache,
+            v if v.starts_with('@') => return CompilerArguments::CannotCache("@"),
             "-M" | "-MM" | "-MD" | "-MMD" => {
                 // If one of the above options is on the command line, we'll
                 // need -MT on the preprocessor command line, whether it's
@@ -134,10 +137,13 @@ fn _parse_arguments(arguments: &[String],
             }
             // Anything else is an input file.
             _ => {
-                if input_arg.is_some() || arg == "-" {
-                    // Can't cache compilations with multiple inputs
-                    // or compilation from stdin.
-                    return CompilerArguments::CannotCache;
+                // Can't cache compilations with multiple inputs
+                // or compilation from stdin.
+                if arg == "-" {
+                    return CompilerArguments::CannotCache("stdin input");
+                }
+                if input_arg.is_some() {
+                    return CompilerArguments::CannotCache("multiple input files");
                 }
                 input_arg = Some(arg.clone());
             }
@@ -155,17 +161,17 @@ fn _parse_arguments(arguments: &[String],
                 Some(e @ "c") | Some(e @ "cc") | Some(e @ "cpp") | Some(e @ "cxx") => (i.to_owned(), e.to_owned()),
                 e => {
                     trace!("Unknown source extension: {}", e.unwrap_or("(None)"));
-                    return CompilerArguments::CannotCache;
+                    return CompilerArguments::CannotCache("unknown source extension");
                 }
             }
         }
         // We can't cache compilation without an input.
-        None => return CompilerArguments::CannotCache,
+        None => return CompilerArguments::CannotCache("no input file"),
     };
     let mut outputs = HashMap::new();
     match output_arg {
         // We can't cache compilation that doesn't go to a file
-        None => return CompilerArguments::CannotCache,
+        None => return CompilerArguments::CannotCache("no output file"),
         Some(o) => {
             outputs.insert("obj", o.to_owned());
             if split_dwarf {
@@ -195,26 +201,31 @@ pub fn preprocess<T>(creator: &T,
                      compiler: &Compiler,
                      parsed_args: &ParsedArguments,
                      cwd: &str,
-                     _pool: &CpuPool)
-                     -> SFuture<process::Output>
+                     pool: &CpuPool)
+                     -> SFuture<Preprocessed>
     where T: CommandCreatorSync
 {
     trace!("preprocess");
-    let mut cmd = creator.clone().new_command_sync(&compiler.executable);
+    let mut cmd = compiler.new_command(creator);
     cmd.arg("-E")
         .arg(&parsed_args.input)
         .args(&parsed_args.preprocessor_args)
         .args(&parsed_args.common_args)
         .current_dir(cwd);
+    // Expand `__DATE__` and `__TIME__` the same way every time, unless the
+    // client fixed them itself.
+    if compiler.sloppiness.time_macros && compiler.env_var("SOURCE_DATE_EPOCH").is_none() {
+        cmd.env("SOURCE_DATE_EPOCH", "0");
+    }
     if log_enabled!(Trace) {
         trace!("preprocess: {:?}", cmd);
     }
-    run_input_output(cmd, None)
+    run_preprocessor(cmd, pool)
 }
 
 pub fn compile<T>(creator: &T,
                   compiler: &Compiler,
-                  preprocessor_output: Vec<u8>,
+                  preprocessor_output: PreprocessorOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &str,
                   _pool: &CpuPool)
@@ -230,24 +241,66 @@ pub fn compile<T>(creator: &T,
         }
     };
 
-    let mut cmd = creator.clone().new_command_sync(&compiler.executable);
-    cmd.args(&["-c", "-x"])
-        .arg(match parsed_args.extension.as_ref() {
-            "c" => "cpp-output",
-            "cc" | "cpp" | "cxx" => "c++-cpp-output",
-            e => {
-                error!("gcc::compile: Got an unexpected file extension {}", e);
-                return future::err("Unexpected file extension".into()).boxed()
-            }
-        })
-        .args(&["-", "-o", &output.clone()])
+    let language = match preprocessed_language(&parsed_args.extension) {
+        Some(language) => language,
+        None => {
+            error!("gcc::compile: Got an unexpected file extension {}", parsed_args.extension);
+            return future::err("Unexpected file extension".into()).boxed()
+        }
+    };
+    let mut cmd = compiler.new_command(creator);
+    cmd.args(&["-c", "-x", language])
+        .args(&["-o", &output.clone()])
         .args(&parsed_args.common_args)
         .current_dir(cwd);
-    Box::new(run_input_output(cmd, Some(preprocessor_output)).map(|output| {
+    // Large preprocessor output is read from the file it was spilled to.
+    let (input, spilled) = match preprocessor_output {
+        PreprocessorOutput::Memory(data) => {
+            cmd.arg("-");
+            (Some(data), None)
+        }
+        PreprocessorOutput::File(dir, path) => {
+            cmd.arg(&path);
+            (None, Some(dir))
+        }
+    };
+    Box::new(run_input_output(cmd, input).map(move |output| {
+        drop(spilled);
         (Cacheable::Yes, output)
     }))
 }
 
+/// The `-x` language of the preprocessed form of a source file with
+/// `extension`.
+fn preprocessed_language(extension: &str) -> Option<&'static str> {
+    match extension {
+        "c" => Some("cpp-output"),
+        "cc" | "cpp" | "cxx" => Some("c++-cpp-output"),
+        _ => None,
+    }
+}
+
+/// The arguments to compile preprocessor output in a file named
+/// `input_name` to an object file named `output_name`, on a machine that
+/// has none of the other files of the compilation, or `None` if it needs
+/// outputs other than the object file.
+pub fn dist_arguments(parsed_args: &ParsedArguments,
+                      input_name: &str,
+                      output_name: &str) -> Option<Vec<String>> {
+    if parsed_args.outputs.keys().any(|&k| k != "obj") {
+        return None
+    }
+    let language = match preprocessed_language(&parsed_args.extension) {
+        Some(language) => language,
+        None => return None,
+    };
+    let mut arguments = vec!["-c".to_owned(), "-x".to_owned(), language.to_owned(),
+                             "-o".to_owned(), output_name.to_owned()];
+    arguments.extend(parsed_args.common_args.iter().cloned());
+    arguments.push(input_name.to_owned());
+    Some(arguments)
+}
+
 struct ExpandIncludeFile<'a> {
     cwd: &'a Path,
     stack: Vec<String>,
@@ -474,30 +527,46 @@ mod test {
 
     #[test]
     fn test_parse_arguments_too_many_inputs() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("multiple input files"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "-o", "foo.o", "bar.c"]));
     }
 
     #[test]
     fn test_parse_arguments_clangmodules() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-fcxx-modules"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "-fcxx-modules", "-o", "foo.o"]));
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-fmodules"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules", "-o", "foo.o"]));
     }
 
     #[test]
     fn test_parse_arguments_pgo() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-fprofile-use"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]));
     }
 
     #[test]
     fn test_parse_arguments_response_file() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("@"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "@foo", "-o", "foo.o"]));
     }
 
+    #[test]
+    fn test_dist_arguments() {
+        let parsed = match _parse_arguments(&stringvec!["-c", "foo.cpp", "-O2", "-o", "foo.o"]) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some(stringvec!["-c", "-x", "c++-cpp-output", "-o", "out.o", "-O2", "in"]),
+                   dist_arguments(&parsed, "in", "out.o"));
+        // Split DWARF writes a second output.
+        let parsed = match _parse_arguments(&stringvec!["-gsplit-dwarf", "-c", "foo.cpp", "-o", "foo.o"]) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(None, dist_arguments(&parsed, "in", "out.o"));
+    }
+
     #[test]
     fn at_signs() {
         let td = TempDir::new("sccache").unwrap();
@@ -14,7 +14,11 @@
 
 mod clang;
 mod compiler;
+mod filter;
+mod policy;
 mod gcc;
 mod msvc;
 
 pub use compiler::compiler::*;
+pub use compiler::filter::CompilerFilter;
+pub use compiler::policy::{CompilerPolicies, CompilerPolicy, Sloppiness};
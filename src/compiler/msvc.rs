@@ -17,7 +17,10 @@ use ::compiler::{
     Compiler,
     CompilerArguments,
     ParsedArguments,
+    Preprocessed,
+    PreprocessorOutput,
     run_input_output,
+    run_preprocessor,
     write_temp_file,
 };
 use local_encoding::{Encoding, Encoder};
@@ -130,11 +133,17 @@ pub fn parse_arguments(arguments: &[String]) -> CompilerArguments {
                         depfile = Some(v[5..].to_owned());
                     }
                     // Arguments we can't handle.
-                    "-showIncludes" => return CompilerArguments::CannotCache,
-                    a if a.starts_with('@') => return CompilerArguments::CannotCache,
+                    "-showIncludes" => return CompilerArguments::CannotCache("-showIncludes"),
+                    a if a.starts_with('@') => return CompilerArguments::CannotCache("@"),
                     // Arguments we can't handle because they output more files.
                     // TODO: support more multi-file outputs.
-                    "-FA" | "-Fa" | "-Fe" | "-Fm" | "-Fp" | "-FR" | "-Fx" => return CompilerArguments::CannotCache,
+                    "-FA" => return CompilerArguments::CannotCache("-FA"),
+                    "-Fa" => return CompilerArguments::CannotCache("-Fa"),
+                    "-Fe" => return CompilerArguments::CannotCache("-Fe"),
+                    "-Fm" => return CompilerArguments::CannotCache("-Fm"),
+                    "-Fp" => return CompilerArguments::CannotCache("-Fp"),
+                    "-FR" => return CompilerArguments::CannotCache("-FR"),
+                    "-Fx" => return CompilerArguments::CannotCache("-Fx"),
                     "-Zi" => {
                         debug_info = true;
                         common_args.push(arg.clone());
@@ -151,7 +160,7 @@ pub fn parse_arguments(arguments: &[String]) -> CompilerArguments {
                     v => {
                         if input_arg.is_some() {
                             // Can't cache compilations with multiple inputs.
-                            return CompilerArguments::CannotCache;
+                            return CompilerArguments::CannotCache("multiple input files");
                         }
                         input_arg = Some(v);
                     }
@@ -170,17 +179,17 @@ pub fn parse_arguments(arguments: &[String]) -> CompilerArguments {
                 Some(e) => (i.to_owned(), e.to_owned()),
                 _ => {
                     trace!("Bad or missing source extension: {:?}", i);
-                    return CompilerArguments::CannotCache;
+                    return CompilerArguments::CannotCache("unknown source extension");
                 }
             }
         }
         // We can't cache compilation without an input.
-        None => return CompilerArguments::CannotCache,
+        None => return CompilerArguments::CannotCache("no input file"),
     };
     let mut outputs = HashMap::new();
     match output_arg {
         // We can't cache compilation that doesn't go to a file
-        None => return CompilerArguments::CannotCache,
+        None => return CompilerArguments::CannotCache("no output file"),
         Some(o) => {
             outputs.insert("obj", o.to_owned());
             // -Fd is not taken into account unless -Zi is given
@@ -191,7 +200,7 @@ pub fn parse_arguments(arguments: &[String]) -> CompilerArguments {
                         // -Zi without -Fd defaults to vcxxx.pdb (where xxx depends on the
                         // MSVC version), and that's used for all compilations with the same
                         // working directory. We can't cache such a pdb.
-                        return CompilerArguments::CannotCache;
+                        return CompilerArguments::CannotCache("-Zi without -Fd");
                     }
                 };
             }
@@ -253,11 +262,11 @@ pub fn preprocess<T>(creator: &T,
                      parsed_args: &ParsedArguments,
                      cwd: &str,
                      includes_prefix: &str,
-                     _pool: &CpuPool)
-                     -> SFuture<process::Output>
+                     pool: &CpuPool)
+                     -> SFuture<Preprocessed>
     where T: CommandCreatorSync
 {
-    let mut cmd = creator.clone().new_command_sync(&compiler.executable);
+    let mut cmd = compiler.new_command(creator);
     cmd.arg("-E")
         .arg(&parsed_args.input)
         .arg("-nologo")
@@ -275,12 +284,12 @@ pub fn preprocess<T>(creator: &T,
     let includes_prefix = includes_prefix.to_string();
     let cwd = cwd.to_string();
 
-    Box::new(run_input_output(cmd, None).and_then(move |output| {
+    Box::new(run_preprocessor(cmd, pool).and_then(move |output| {
         let parsed_args = &parsed_args;
         if let (Some(ref objfile), &Some(ref depfile)) = (parsed_args.outputs.get("obj"), &parsed_args.depfile) {
             let mut f = File::create(Path::new(&cwd).join(depfile))?;
             write!(f, "{}: {} ", objfile, parsed_args.input)?;
-            let process::Output { status, stdout, stderr: stderr_bytes } = output;
+            let Preprocessed { status, stdout, stderr: stderr_bytes } = output;
             let stderr = from_local_codepage(&stderr_bytes)?;
             let mut deps = HashSet::new();
             let mut stderr_bytes = vec!();
@@ -307,7 +316,7 @@ pub fn preprocess<T>(creator: &T,
                     writeln!(f, "{}:", dep)?;
                 }
             }
-            Ok(process::Output { status: status, stdout: stdout, stderr: stderr_bytes })
+            Ok(Preprocessed { status: status, stdout: stdout, stderr: stderr_bytes })
         } else {
             Ok(output)
         }
@@ -316,7 +325,7 @@ pub fn preprocess<T>(creator: &T,
 
 pub fn compile<T>(creator: &T,
                   compiler: &Compiler,
-                  preprocessor_output: Vec<u8>,
+                  preprocessor_output: PreprocessorOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &str,
                   pool: &CpuPool)
@@ -350,10 +359,10 @@ pub fn compile<T>(creator: &T,
             Some(name) => name,
             None => return future::err("missing input filename".into()).boxed(),
         };
-        write_temp_file(pool, filename.as_ref(), preprocessor_output)
+        preprocessor_output.into_temp_file(pool, filename.as_ref())
     };
 
-    let mut cmd = creator.clone().new_command_sync(&compiler.executable);
+    let mut cmd = compiler.new_command(creator);
     cmd.arg("-c")
         .arg(&format!("-Fo{}", out_file))
         .args(&parsed_args.common_args)
@@ -372,7 +381,7 @@ pub fn compile<T>(creator: &T,
     //
     // We may just throw away this `cmd` if our execution turns out to be
     // successful.
-    let mut cmd = creator.clone().new_command_sync(&compiler.executable);
+    let mut cmd = compiler.new_command(creator);
     cmd.arg("-c")
         .arg(&parsed_args.input)
         .arg(&format!("-Fo{}", out_file))
@@ -390,6 +399,32 @@ pub fn compile<T>(creator: &T,
     }))
 }
 
+/// The arguments to compile preprocessor output in a file named
+/// `input_name` to an object file named `output_name` with clang-cl on a
+/// build server, or `None` if it needs outputs other than the object file.
+pub fn dist_arguments(parsed_args: &ParsedArguments,
+                      input_name: &str,
+                      output_name: &str) -> Option<Vec<String>> {
+    if parsed_args.outputs.keys().any(|&k| k != "obj") {
+        return None
+    }
+    let mut arguments = vec!["-c".to_owned(), format!("-Fo{}", output_name)];
+    let mut it = parsed_args.common_args.iter();
+    while let Some(arg) = it.next() {
+        // The preprocessor already included forced includes, which the
+        // build server doesn't have.
+        if arg == "-FI" {
+            it.next();
+        } else {
+            arguments.push(arg.clone());
+        }
+    }
+    // The input file has no extension to tell its language by.
+    arguments.push(if parsed_args.extension == "c" { "-TC" } else { "-TP" }.to_owned());
+    arguments.push(input_name.to_owned());
+    Some(arguments)
+}
+
 
 #[cfg(test)]
 mod test {
@@ -501,34 +536,50 @@ mod test {
 
     #[test]
     fn test_parse_arguments_too_many_inputs() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("multiple input files"),
                    parse_arguments(&stringvec!["-c", "foo.c", "-Fofoo.obj", "bar.c"]));
     }
 
     #[test]
     fn test_parse_arguments_unsupported() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-FA"),
                    parse_arguments(&stringvec!["-c", "foo.c", "-Fofoo.obj", "-FA"]));
 
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-Fa"),
                    parse_arguments(&stringvec!["-Fa", "-c", "foo.c", "-Fofoo.obj"]));
 
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-FR"),
                    parse_arguments(&stringvec!["-c", "foo.c", "-FR", "-Fofoo.obj"]));
     }
 
     #[test]
     fn test_parse_arguments_response_file() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("@"),
                    parse_arguments(&stringvec!["-c", "foo.c", "@foo", "-Fofoo.obj"]));
     }
 
     #[test]
     fn test_parse_arguments_missing_pdb() {
-        assert_eq!(CompilerArguments::CannotCache,
+        assert_eq!(CompilerArguments::CannotCache("-Zi without -Fd"),
                    parse_arguments(&stringvec!["-c", "foo.c", "-Zi", "-Fofoo.obj"]));
     }
 
+    #[test]
+    fn test_dist_arguments() {
+        let parsed = match parse_arguments(&stringvec!["-c", "foo.cpp", "-FI", "pre.h", "-O2", "-Fofoo.obj"]) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some(stringvec!["-c", "-Foout.obj", "-O2", "-TP", "in"]),
+                   dist_arguments(&parsed, "in", "out.obj"));
+        // A PDB is a second output.
+        let parsed = match parse_arguments(&stringvec!["-c", "foo.c", "-Zi", "-Fdfoo.pdb", "-Fofoo.obj"]) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(None, dist_arguments(&parsed, "in", "out.obj"));
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -549,7 +600,7 @@ mod test {
         next_command(&creator, Ok(MockChild::new(exit_status(1), "", "")));
         let (cacheable, _) = compile(&creator,
                                      &compiler,
-                                     vec!(),
+                                     PreprocessorOutput::Memory(vec!()),
                                      &parsed_args,
                                      f.tempdir.path().to_str().unwrap(),
                                      &pool).wait().unwrap();
@@ -580,7 +631,7 @@ mod test {
         next_command(&creator, Ok(MockChild::new(exit_status(1), "", "")));
         let (cacheable, _) = compile(&creator,
                                      &compiler,
-                                     vec!(),
+                                     PreprocessorOutput::Memory(vec!()),
                                      &parsed_args,
                                      f.tempdir.path().to_str().unwrap(),
                                      &pool).wait().unwrap();
@@ -610,7 +661,7 @@ mod test {
         next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
         let (cacheable, _) = compile(&creator,
                                      &compiler,
-                                     vec!(),
+                                     PreprocessorOutput::Memory(vec!()),
                                      &parsed_args,
                                      f.tempdir.path().to_str().unwrap(),
                                      &pool).wait().unwrap();
@@ -0,0 +1,274 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use compiler::filter::Pattern;
+use std::env;
+
+use errors::*;
+
+/// The prefix of the settings defining policies, one per setting, such as
+/// `SCCACHE_COMPILER_POLICY_VENDOR = /opt/vendor/bin/*: read-only`.
+const POLICY_PREFIX: &'static str = "SCCACHE_COMPILER_POLICY_";
+
+/// The setting listing environment variables that are part of the cache
+/// key of all compilers, separated by commas.
+const HASH_ENV: &'static str = "SCCACHE_HASH_ENV";
+
+/// The setting listing the sloppiness options, separated by commas, as
+/// with ccache's `sloppiness`.
+const SLOPPINESS: &'static str = "SCCACHE_SLOPPINESS";
+
+/// What to be less strict about when caching, trading correctness for
+/// cache hits where that is known to be safe.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sloppiness {
+    /// Leave the locale environment variables out of the cache key.
+    pub locale: bool,
+    /// Preprocess with a fixed `SOURCE_DATE_EPOCH`, so that uses of
+    /// `__DATE__` and `__TIME__` don't change the cache key.
+    pub time_macros: bool,
+    /// Other environment variables to leave out of the cache key.
+    pub env_vars: Vec<String>,
+}
+
+impl Sloppiness {
+    /// Whether the environment variable `var` is left out of the cache key.
+    pub fn ignores_env_var(&self, var: &str) -> bool {
+        (self.locale && (var == "LANG" || var.starts_with("LC_")))
+            || self.env_vars.iter().any(|v| v == var)
+    }
+}
+
+/// Parse sloppiness options, `locale`, `time_macros`, `pch_defines` and
+/// `env=<VAR>`, separated by commas.
+fn parse_sloppiness(value: &str) -> Result<Sloppiness> {
+    let mut sloppiness = Sloppiness::default();
+    for option in value.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        if option == "locale" {
+            sloppiness.locale = true;
+        } else if option == "time_macros" {
+            sloppiness.time_macros = true;
+        } else if option == "pch_defines" {
+            // Precompiling headers is never cached, so there is nothing
+            // to be sloppy about, but accept it for ccache configurations.
+        } else if option.starts_with("env=") && option.len() > 4 {
+            sloppiness.env_vars.push(option[4..].trim().to_owned());
+        } else {
+            bail!("unknown option `{}`", option);
+        }
+    }
+    Ok(sloppiness)
+}
+
+/// How to cache the compilations of some compilers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompilerPolicy {
+    /// Look compilations up in the cache, but don't store them.
+    pub read_only: bool,
+    /// Don't look compilations up in the cache, but store them.
+    pub recache: bool,
+    /// Environment variables of the client that are part of the cache key,
+    /// in addition to the ones that always are.
+    pub hashed_env_vars: Vec<String>,
+    /// What to be less strict about, from `SCCACHE_SLOPPINESS`.
+    pub sloppiness: Sloppiness,
+}
+
+impl CompilerPolicy {
+    /// Add the settings of `other` to this policy.
+    fn merge(&mut self, other: &CompilerPolicy) {
+        self.read_only |= other.read_only;
+        self.recache |= other.recache;
+        for var in &other.hashed_env_vars {
+            if !self.hashed_env_vars.contains(var) {
+                self.hashed_env_vars.push(var.clone());
+            }
+        }
+    }
+}
+
+/// The policies of the compilers matching patterns.
+#[derive(Debug, Default)]
+pub struct CompilerPolicies {
+    policies: Vec<(Pattern, CompilerPolicy)>,
+    /// Environment variables that are part of the cache key of all
+    /// compilers, from `SCCACHE_HASH_ENV`.
+    hashed_env_vars: Vec<String>,
+    /// What to be less strict about for all compilers.
+    sloppiness: Sloppiness,
+}
+
+/// Parse a policy, `<pattern>: <option>, ...`, where the options are
+/// `read-only`, `recache` and `hash-env=<VAR>`.
+fn parse_policy(value: &str) -> Result<(Pattern, CompilerPolicy)> {
+    let colon = value.rfind(':').ok_or("expected `<pattern>: <option>, ...`")?;
+    let (pattern, options) = (value[..colon].trim(), &value[colon + 1..]);
+    if pattern.is_empty() {
+        bail!("expected a pattern before `:`");
+    }
+    let mut policy = CompilerPolicy::default();
+    for option in options.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        if option == "read-only" {
+            policy.read_only = true;
+        } else if option == "recache" {
+            policy.recache = true;
+        } else if option.starts_with("hash-env=") && option.len() > 9 {
+            policy.hashed_env_vars.push(option[9..].trim().to_owned());
+        } else {
+            bail!("unknown option `{}`", option);
+        }
+    }
+    Ok((Pattern::new(pattern), policy))
+}
+
+/// Parse a comma-separated list of environment variable names.
+fn parse_env_vars(value: &str) -> Vec<String> {
+    value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).map(|v| v.to_owned()).collect()
+}
+
+impl CompilerPolicies {
+    /// Read the policies from the `SCCACHE_COMPILER_POLICY_*` settings,
+    /// ignoring invalid ones, the variables of `SCCACHE_HASH_ENV` and the
+    /// options of `SCCACHE_SLOPPINESS`.
+    pub fn from_environment() -> CompilerPolicies {
+        let mut settings = env::vars().filter(|&(ref key, _)| key.starts_with(POLICY_PREFIX)).collect::<Vec<_>>();
+        settings.sort();
+        let policies = settings.into_iter().filter_map(|(key, value)| {
+            match parse_policy(&value) {
+                Ok(policy) => Some(policy),
+                Err(e) => {
+                    warn!("Ignoring invalid {}: {}", key, e);
+                    None
+                }
+            }
+        }).collect();
+        let sloppiness = match env::var(SLOPPINESS).map(|value| parse_sloppiness(&value)) {
+            Ok(Ok(sloppiness)) => sloppiness,
+            Ok(Err(e)) => {
+                warn!("Ignoring invalid {}: {}", SLOPPINESS, e);
+                Sloppiness::default()
+            }
+            Err(_) => Sloppiness::default(),
+        };
+        CompilerPolicies {
+            policies: policies,
+            hashed_env_vars: env::var(HASH_ENV).map(|value| parse_env_vars(&value)).unwrap_or_default(),
+            sloppiness: sloppiness,
+        }
+    }
+
+    /// The policy for the compiler executable `path`, combining those of
+    /// all the patterns it matches.
+    pub fn policy_for(&self, path: &str) -> CompilerPolicy {
+        let mut policy = CompilerPolicy {
+            hashed_env_vars: self.hashed_env_vars.clone(),
+            sloppiness: self.sloppiness.clone(),
+            .. CompilerPolicy::default()
+        };
+        for &(ref pattern, ref p) in &self.policies {
+            if pattern.matches(path) {
+                policy.merge(p);
+            }
+        }
+        policy
+    }
+
+    /// The environment variables that are part of the cache key of any
+    /// compiler, other than those that always are.
+    pub fn hashed_env_var_names(&self) -> Vec<String> {
+        let mut names = self.hashed_env_vars.clone();
+        for &(_, ref policy) in &self.policies {
+            for var in &policy.hashed_env_vars {
+                if !names.contains(var) {
+                    names.push(var.clone());
+                }
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let (pattern, policy) = parse_policy("/opt/vendor/bin/*: read-only, hash-env=VENDOR_ROOT").unwrap();
+        assert!(pattern.matches("/opt/vendor/bin/cc"));
+        assert_eq!(CompilerPolicy {
+                       read_only: true,
+                       recache: false,
+                       hashed_env_vars: vec!["VENDOR_ROOT".to_owned()],
+                       sloppiness: Sloppiness::default(),
+                   }, policy);
+        // Windows paths contain colons too.
+        assert!(parse_policy(r"C:\vendor\*: recache").is_ok());
+        assert!(parse_policy("gcc").is_err());
+        assert!(parse_policy(": recache").is_err());
+        assert!(parse_policy("gcc: fast").is_err());
+        assert!(parse_policy("gcc: hash-env=").is_err());
+    }
+
+    #[test]
+    fn test_policy_for() {
+        let policies = CompilerPolicies {
+            policies: vec![parse_policy("*: hash-env=A").unwrap(),
+                           parse_policy("gcc: recache, hash-env=A, hash-env=B").unwrap()],
+            hashed_env_vars: vec![],
+            sloppiness: Sloppiness::default(),
+        };
+        assert_eq!(CompilerPolicy {
+                       read_only: false,
+                       recache: true,
+                       hashed_env_vars: vec!["A".to_owned(), "B".to_owned()],
+                       sloppiness: Sloppiness::default(),
+                   }, policies.policy_for("/usr/bin/gcc"));
+        assert_eq!(vec!["A".to_owned()], policies.policy_for("/usr/bin/clang").hashed_env_vars);
+    }
+
+    #[test]
+    fn test_hash_env() {
+        assert_eq!(vec!["A".to_owned(), "B".to_owned()], parse_env_vars(" A,,B "));
+        let policies = CompilerPolicies {
+            policies: vec![parse_policy("gcc: hash-env=B, hash-env=C").unwrap()],
+            hashed_env_vars: parse_env_vars("A,B"),
+            sloppiness: Sloppiness::default(),
+        };
+        assert_eq!(vec!["A".to_owned(), "B".to_owned(), "C".to_owned()],
+                   policies.policy_for("/usr/bin/gcc").hashed_env_vars);
+        assert_eq!(vec!["A".to_owned(), "B".to_owned()],
+                   policies.policy_for("/usr/bin/clang").hashed_env_vars);
+        assert_eq!(vec!["A".to_owned(), "B".to_owned(), "C".to_owned()],
+                   policies.hashed_env_var_names());
+    }
+
+    #[test]
+    fn test_parse_sloppiness() {
+        let sloppiness = parse_sloppiness("locale, time_macros, pch_defines, env=BUILD_HOST").unwrap();
+        assert_eq!(Sloppiness {
+                       locale: true,
+                       time_macros: true,
+                       env_vars: vec!["BUILD_HOST".to_owned()],
+                   }, sloppiness);
+        assert!(sloppiness.ignores_env_var("LANG"));
+        assert!(sloppiness.ignores_env_var("LC_ALL"));
+        assert!(sloppiness.ignores_env_var("BUILD_HOST"));
+        assert!(!sloppiness.ignores_env_var("CPATH"));
+        assert!(!Sloppiness::default().ignores_env_var("LANG"));
+        assert_eq!(Sloppiness::default(), parse_sloppiness("").unwrap());
+        assert!(parse_sloppiness("file_macro").is_err());
+        assert!(parse_sloppiness("env=").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sccache configuration file.
+//!
+//! The file holds `SCCACHE_*` settings as `KEY = value` lines, with `#`
+//! starting a comment. Settings in the environment take precedence over
+//! those in the file, so the client and the server it starts see the same
+//! configuration. The settings taken from the file are listed in
+//! `SCCACHE_CONF_SETTINGS`, so that a running server can tell them apart
+//! when it reloads the file.
+
+use app_dirs::{AppDataType, app_dir};
+use cache::APP_INFO;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use errors::*;
+
+/// The variable listing the settings taken from the configuration file.
+pub const FILE_SETTINGS_VAR: &'static str = "SCCACHE_CONF_SETTINGS";
+
+/// Get the path of the configuration file, from `SCCACHE_CONF` or the
+/// user's configuration directory.
+pub fn config_file() -> Option<PathBuf> {
+    env::var_os("SCCACHE_CONF")
+        .map(PathBuf::from)
+        .or_else(|| app_dir(AppDataType::UserConfig, &APP_INFO, "").ok().map(|d| d.join("config")))
+}
+
+/// Parse the contents of a configuration file into `(key, value)` pairs.
+pub fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut settings = vec!();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => bail!("line {}: expected `KEY = value`", i + 1),
+        };
+        if !key.starts_with("SCCACHE_") {
+            bail!("line {}: unknown setting `{}`", i + 1, key);
+        }
+        settings.push((key.to_owned(), value.to_owned()));
+    }
+    Ok(settings)
+}
+
+/// Read the settings in the configuration file, if there is one.
+fn read() -> Result<Vec<(String, String)>> {
+    let path = match config_file() {
+        Some(path) => path,
+        None => return Ok(vec!()),
+    };
+    let mut contents = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec!()),
+        Err(e) => return Err(e).chain_err(|| format!("failed to read {}", path.display())),
+    }
+    parse(&contents).chain_err(|| format!("invalid config file {}", path.display()))
+}
+
+/// Get the names of the settings taken from the configuration file.
+fn file_settings() -> Vec<String> {
+    env::var(FILE_SETTINGS_VAR).ok().map_or(vec!(), |keys| {
+        keys.split(',').filter(|key| !key.is_empty()).map(|key| key.to_owned()).collect()
+    })
+}
+
+/// Set any of `settings` that aren't already set in the environment,
+/// recording that they came from the configuration file.
+fn apply(settings: Vec<(String, String)>) {
+    let mut keys = file_settings();
+    for (key, value) in settings {
+        if env::var_os(&key).is_none() {
+            env::set_var(&key, value);
+            keys.push(key);
+        }
+    }
+    if !keys.is_empty() {
+        env::set_var(FILE_SETTINGS_VAR, keys.join(","));
+    }
+}
+
+/// Load the configuration file, if there is one, setting any of its
+/// settings that aren't already set in the environment.
+pub fn load() -> Result<()> {
+    apply(read()?);
+    Ok(())
+}
+
+/// Read the configuration file again, replacing the settings previously
+/// taken from it, and return the names of the settings that changed.
+///
+/// Settings in the environment still take precedence. Nothing changes if
+/// the file can't be read.
+pub fn reload() -> Result<Vec<String>> {
+    let settings = read()?;
+    let old = file_settings().into_iter().map(|key| {
+        let value = env::var_os(&key);
+        env::remove_var(&key);
+        (key, value)
+    }).collect::<Vec<_>>();
+    env::remove_var(FILE_SETTINGS_VAR);
+    apply(settings);
+    let mut changed = old.iter()
+        .filter(|&&(ref key, ref value)| env::var_os(key) != *value)
+        .map(|&(ref key, _)| key.clone())
+        .collect::<Vec<_>>();
+    changed.extend(file_settings().into_iter().filter(|key| !old.iter().any(|&(ref k, _)| k == key)));
+    changed.sort();
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let settings = parse("# shared build machine\n\
+                              SCCACHE_SERVER_PORT = 4300\n\
+                              \n\
+                              SCCACHE_SERVER_ADDRESS=127.0.0.1  # loopback only\n").unwrap();
+        assert_eq!(settings, vec!(("SCCACHE_SERVER_PORT".to_owned(), "4300".to_owned()),
+                                  ("SCCACHE_SERVER_ADDRESS".to_owned(), "127.0.0.1".to_owned())));
+        assert!(parse("SCCACHE_SERVER_PORT\n").is_err());
+        assert!(parse("PATH = /bin\n").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("sccache_test_config").unwrap();
+        let path = dir.path().join("config");
+        let write = |contents: &str| File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        write("SCCACHE_TEST_CONF_A = 1\nSCCACHE_TEST_CONF_B = 2\n");
+        env::set_var("SCCACHE_CONF", &path);
+        env::set_var("SCCACHE_TEST_CONF_B", "env");
+        load().unwrap();
+        assert_eq!(Ok("1".to_owned()), env::var("SCCACHE_TEST_CONF_A"));
+        assert_eq!(Ok("env".to_owned()), env::var("SCCACHE_TEST_CONF_B"));
+
+        // The environment still wins, and dropped settings are unset.
+        write("SCCACHE_TEST_CONF_B = 3\nSCCACHE_TEST_CONF_C = 4\n");
+        assert_eq!(vec!("SCCACHE_TEST_CONF_A".to_owned(), "SCCACHE_TEST_CONF_C".to_owned()),
+                   reload().unwrap());
+        assert!(env::var_os("SCCACHE_TEST_CONF_A").is_none());
+        assert_eq!(Ok("env".to_owned()), env::var("SCCACHE_TEST_CONF_B"));
+        assert_eq!(Ok("4".to_owned()), env::var("SCCACHE_TEST_CONF_C"));
+
+        // A broken file changes nothing.
+        write("SCCACHE_TEST_CONF_C\n");
+        assert!(reload().is_err());
+        assert_eq!(Ok("4".to_owned()), env::var("SCCACHE_TEST_CONF_C"));
+        write("SCCACHE_TEST_CONF_C = 4\n");
+        assert!(reload().unwrap().is_empty());
+
+        env::remove_var("SCCACHE_CONF");
+        env::remove_var(FILE_SETTINGS_VAR);
+        for key in &["SCCACHE_TEST_CONF_A", "SCCACHE_TEST_CONF_B", "SCCACHE_TEST_CONF_C"] {
+            env::remove_var(key);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authentication of requests to the scheduler and build servers.
+//!
+//! Clients of the scheduler present one of the bearer tokens it's
+//! configured with, and build servers present a secret they share with the
+//! scheduler. Build servers only run jobs for clients holding a job token,
+//! which the scheduler issues when it allocates a job: it signs the job's
+//! id, the build server and an expiry time with the shared secret, so that
+//! build servers can check job tokens without asking the scheduler.
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rustc_serialize::hex::ToHex;
+use std::env;
+
+use errors::*;
+
+/// How long job tokens are valid for, in seconds.
+const JOB_TOKEN_LIFETIME_SECS: u64 = 600;
+
+/// Whether `token` is `expected`, taking the same time wherever they differ.
+pub fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len() && fixed_time_eq(token.as_bytes(), expected.as_bytes())
+}
+
+/// The token clients present to the scheduler, from
+/// `SCCACHE_DIST_CLIENT_TOKEN`.
+pub fn client_token() -> String {
+    env::var("SCCACHE_DIST_CLIENT_TOKEN").unwrap_or_default()
+}
+
+/// The tokens the scheduler accepts from clients, from the comma-separated
+/// `SCCACHE_DIST_CLIENT_TOKENS`.
+pub fn client_tokens() -> Result<Vec<String>> {
+    let tokens = env::var("SCCACHE_DIST_CLIENT_TOKENS").unwrap_or_default();
+    let tokens = tokens.split(',')
+        .map(|t| t.trim().to_owned())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+    if tokens.is_empty() {
+        bail!("SCCACHE_DIST_CLIENT_TOKENS must be set to the tokens clients present");
+    }
+    Ok(tokens)
+}
+
+/// The secret the scheduler and build servers share, from
+/// `SCCACHE_DIST_SERVER_TOKEN`.
+pub fn server_token() -> Result<String> {
+    match env::var("SCCACHE_DIST_SERVER_TOKEN") {
+        Ok(ref token) if token.is_empty() => bail!("SCCACHE_DIST_SERVER_TOKEN is empty"),
+        Ok(token) => Ok(token),
+        Err(_) => bail!("SCCACHE_DIST_SERVER_TOKEN must be set to the secret the scheduler \
+                         and build servers share"),
+    }
+}
+
+fn job_token_signature(secret: &str, job_id: u64, expires: u64, server: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(format!("{}.{}.{}", job_id, expires, server).as_bytes());
+    hmac.result().code().to_hex()
+}
+
+/// Issue the token for job `job_id` on the build server at `server`, at
+/// `now` seconds since the epoch.
+pub fn issue_job_token(secret: &str, job_id: u64, server: &str, now: u64) -> String {
+    let expires = now + JOB_TOKEN_LIFETIME_SECS;
+    format!("{}.{}.{}", job_id, expires, job_token_signature(secret, job_id, expires, server))
+}
+
+/// Check a job token presented to the build server at `server`, at `now`
+/// seconds since the epoch, returning the id of the job it was issued for
+/// if it's valid.
+pub fn verify_job_token(secret: &str, token: &str, server: &str, now: u64) -> Option<u64> {
+    let parts = token.splitn(3, '.').collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return None
+    }
+    let (job_id, expires) = match (parts[0].parse(), parts[1].parse()) {
+        (Ok(job_id), Ok(expires)) => (job_id, expires),
+        _ => return None,
+    };
+    if expires < now {
+        return None
+    }
+    let signature = job_token_signature(secret, job_id, expires, server);
+    if token_matches(parts[2], &signature) { Some(job_id) } else { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_token() {
+        let token = issue_job_token("secret", 7, "build:10601", 1000);
+        assert_eq!(Some(7), verify_job_token("secret", &token, "build:10601", 1000));
+        assert_eq!(Some(7), verify_job_token("secret", &token, "build:10601", 1600));
+        // Expired.
+        assert_eq!(None, verify_job_token("secret", &token, "build:10601", 1601));
+        // Issued for another build server, or with another secret.
+        assert_eq!(None, verify_job_token("secret", &token, "other:10601", 1000));
+        assert_eq!(None, verify_job_token("other", &token, "build:10601", 1000));
+        // Tampered with.
+        let tampered = token.replacen("7.", "8.", 1);
+        assert_eq!(None, verify_job_token("secret", &tampered, "build:10601", 1000));
+        assert_eq!(None, verify_job_token("secret", "", "build:10601", 1000));
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("abc", "abc"));
+        assert!(!token_matches("abd", "abc"));
+        assert!(!token_matches("ab", "abc"));
+    }
+}
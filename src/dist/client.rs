@@ -0,0 +1,303 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running compilations on build servers, for the sccache server.
+//!
+//! Compilers are packaged into toolchains as they're found on Linux. Other
+//! clients run compilers that target the same platform as a local compiler
+//! from cross toolchains, which are archives built beforehand (with
+//! `sccache --dist-package-toolchain` on a Linux machine) and listed in the
+//! JSON file in `SCCACHE_DIST_CROSS_TOOLCHAINS`, such as:
+//!
+//! ```json
+//! [{"compiler": "C:\\LLVM\\bin\\clang-cl.exe",
+//!   "archive": "C:\\toolchains\\clang-cl.tar.zst",
+//!   "executable": "/usr/bin/clang",
+//!   "arguments": ["--driver-mode=cl", "--target=x86_64-pc-windows-msvc"]}]
+//! ```
+
+use compiler::Compiler;
+use dist::{
+    self,
+    Authenticated,
+    Connector,
+    JobAlloc,
+    JobRequest,
+    JobResult,
+    RunJob,
+    SchedulerRequest,
+    SchedulerResponse,
+    ServerRequest,
+    ServerResponse,
+    Toolchain,
+};
+use dist::{auth, toolchain};
+use serde_json;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tls::read_file;
+
+use errors::*;
+
+/// How long a build server may take to run a job or store a toolchain, in
+/// seconds.
+const JOB_TIMEOUT_SECS: u64 = 600;
+
+/// Runs jobs on the build servers of a scheduler.
+#[derive(Clone)]
+pub struct Client {
+    scheduler: String,
+    /// The token presented to the scheduler.
+    token: String,
+    connector: Connector,
+    /// The cross toolchains to run local compilers from.
+    cross_toolchains: Arc<Vec<CrossToolchain>>,
+    /// The toolchains of the compilers used so far, by the digest of the
+    /// compiler, or `None` for those that can't run on build servers.
+    toolchains: Arc<Mutex<HashMap<String, Option<Arc<CompilerToolchain>>>>>,
+}
+
+/// A toolchain to run on build servers in place of a local compiler, such
+/// as a clang that targets the platform the local compiler runs on.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct CrossToolchain {
+    /// The path of the local compiler.
+    pub compiler: PathBuf,
+    /// The path of the toolchain's archive.
+    pub archive: PathBuf,
+    /// The path of the compiler in the toolchain.
+    pub executable: String,
+    /// The platform the toolchain runs on.
+    #[serde(default = "default_platform")]
+    pub platform: String,
+    /// Arguments to pass to the compiler before those of each job, to make
+    /// it compile like the local compiler.
+    #[serde(default)]
+    pub arguments: Vec<String>,
+}
+
+fn default_platform() -> String {
+    "x86_64-linux".to_owned()
+}
+
+/// Parse a list of cross toolchains from JSON.
+pub fn parse_cross_toolchains(json: &str) -> Result<Vec<CrossToolchain>> {
+    serde_json::from_str(json).chain_err(|| "invalid list of cross toolchains")
+}
+
+/// The cross toolchains listed in the file in
+/// `SCCACHE_DIST_CROSS_TOOLCHAINS`, if it's set.
+fn cross_toolchains() -> Result<Vec<CrossToolchain>> {
+    match env::var_os("SCCACHE_DIST_CROSS_TOOLCHAINS") {
+        Some(path) => {
+            let json = read_file(Path::new(&path))?;
+            parse_cross_toolchains(&String::from_utf8_lossy(&json))
+                .chain_err(|| format!("failed to read cross toolchains from {:?}", path))
+        }
+        None => Ok(vec!()),
+    }
+}
+
+/// How to run a local compiler on build servers.
+pub struct CompilerToolchain {
+    pub toolchain: Toolchain,
+    /// The path of the compiler in the toolchain.
+    pub executable: String,
+    /// The platform the toolchain runs on.
+    pub platform: String,
+    /// Arguments to pass to the compiler before those of each job.
+    pub arguments: Vec<String>,
+}
+
+/// Why a job didn't run on a build server.
+#[derive(Debug, PartialEq)]
+pub enum NotRun {
+    /// All build servers are busy.
+    NoServer,
+    /// The build server doesn't have the job's toolchain.
+    MissingToolchain,
+}
+
+impl Client {
+    /// A client of the scheduler in `SCCACHE_DIST_SCHEDULER`, if it's set,
+    /// presenting the token in `SCCACHE_DIST_CLIENT_TOKEN`.
+    pub fn from_environment() -> Result<Option<Client>> {
+        let scheduler = match dist::scheduler_addr() {
+            Some(scheduler) => scheduler,
+            None => return Ok(None),
+        };
+        Ok(Some(Client {
+            scheduler: scheduler,
+            token: auth::client_token(),
+            connector: Connector::from_environment()?,
+            cross_toolchains: Arc::new(cross_toolchains()?),
+            toolchains: Arc::new(Mutex::new(HashMap::new())),
+        }))
+    }
+
+    /// The address of the scheduler.
+    pub fn scheduler(&self) -> &str {
+        &self.scheduler
+    }
+
+    /// The toolchain to run `compiler` from, which is loaded or packaged
+    /// the first time it's needed, or `None` if there isn't one.
+    pub fn toolchain(&self, compiler: &Compiler) -> Option<Arc<CompilerToolchain>> {
+        // Compilers are packaged one at a time, and only once.
+        let mut toolchains = self.toolchains.lock().unwrap();
+        toolchains.entry(compiler.digest.clone()).or_insert_with(|| {
+            match self.load_toolchain(compiler) {
+                Ok(Some(toolchain)) => {
+                    info!("Running {} from toolchain {}", compiler.executable, toolchain.toolchain.id);
+                    Some(Arc::new(toolchain))
+                }
+                Ok(None) => {
+                    info!("No cross toolchain for {}, it only runs locally", compiler.executable);
+                    None
+                }
+                Err(e) => {
+                    warn!("Failed to load a toolchain for {}, it only runs locally: {}",
+                          compiler.executable, e);
+                    None
+                }
+            }
+        }).clone()
+    }
+
+    fn load_toolchain(&self, compiler: &Compiler) -> Result<Option<CompilerToolchain>> {
+        let executable = PathBuf::from(&compiler.executable);
+        if let Some(cross) = self.cross_toolchains.iter().find(|c| c.compiler == executable) {
+            let archive = read_file(&cross.archive)?;
+            return Ok(Some(CompilerToolchain {
+                toolchain: Toolchain {
+                    id: toolchain::digest(&archive),
+                    archive: archive,
+                },
+                executable: cross.executable.clone(),
+                platform: cross.platform.clone(),
+                arguments: cross.arguments.clone(),
+            }))
+        }
+        // Build servers only run on Linux, so other compilers need cross
+        // toolchains.
+        if !cfg!(target_os = "linux") {
+            return Ok(None)
+        }
+        Ok(Some(CompilerToolchain {
+            toolchain: toolchain::package_compiler(compiler)?,
+            executable: compiler.executable.clone(),
+            platform: dist::platform(),
+            arguments: vec!(),
+        }))
+    }
+
+    /// Run `job` from `compiler` on a build server allocated by the
+    /// scheduler, uploading its toolchain first if the build server doesn't
+    /// have it. This blocks until the job is done.
+    pub fn run_job(&self, mut job: RunJob, compiler: &CompilerToolchain)
+                   -> Result<::std::result::Result<JobResult, NotRun>> {
+        let toolchain = &compiler.toolchain;
+        let request = SchedulerRequest::AllocJob(JobRequest {
+            toolchain: toolchain.id.clone(),
+            platform: compiler.platform.clone(),
+        });
+        let alloc = match self.connector.scheduler_request(&self.scheduler, &self.token, request)? {
+            SchedulerResponse::JobAllocated(Some(alloc)) => alloc,
+            SchedulerResponse::JobAllocated(None) => return Ok(Err(NotRun::NoServer)),
+            _ => bail!("unexpected response from the scheduler"),
+        };
+        let mut uploaded = false;
+        if !alloc.has_toolchain {
+            self.put_toolchain(&alloc, toolchain)?;
+            uploaded = true;
+        }
+        debug!("Running job {} on {}", alloc.job_id, alloc.server);
+        job.job_id = alloc.job_id;
+        job.toolchain = toolchain.id.clone();
+        job.compiler = compiler.executable.clone();
+        job.arguments = compiler.arguments.iter().cloned().chain(job.arguments).collect();
+        let request = Authenticated {
+            token: alloc.job_token.clone(),
+            request: ServerRequest::RunJob(job),
+        };
+        let timeout = Some(Duration::from_secs(JOB_TIMEOUT_SECS));
+        loop {
+            match self.connector.request(&alloc.server, &request, timeout)? {
+                ServerResponse::JobComplete(result) => return Ok(Ok(result)),
+                // The build server may have removed the toolchain since its
+                // last heartbeat.
+                ServerResponse::MissingToolchain if !uploaded => {
+                    self.put_toolchain(&alloc, toolchain)?;
+                    uploaded = true;
+                }
+                ServerResponse::MissingToolchain => return Ok(Err(NotRun::MissingToolchain)),
+                ServerResponse::Failed(e) => bail!("build server {} failed to run the job: {}", alloc.server, e),
+                ServerResponse::Unauthorized => bail!("build server {} rejected the job token", alloc.server),
+                ServerResponse::ToolchainStored => bail!("unexpected response from {}", alloc.server),
+            }
+        }
+    }
+
+    /// Upload `toolchain` to the build server allocated to the job `alloc`.
+    fn put_toolchain(&self, alloc: &JobAlloc, toolchain: &Toolchain) -> Result<()> {
+        debug!("Uploading toolchain {} to {}", toolchain.id, alloc.server);
+        let request = Authenticated {
+            token: alloc.job_token.clone(),
+            request: ServerRequest::PutToolchain(toolchain.clone()),
+        };
+        let timeout = Some(Duration::from_secs(JOB_TIMEOUT_SECS));
+        match self.connector.request(&alloc.server, &request, timeout)? {
+            ServerResponse::ToolchainStored => Ok(()),
+            ServerResponse::Failed(e) => bail!("build server {} failed to store toolchain {}: {}",
+                                               alloc.server, toolchain.id, e),
+            ServerResponse::Unauthorized => bail!("build server {} rejected the job token", alloc.server),
+            _ => bail!("unexpected response from {}", alloc.server),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_cross_toolchains() {
+        let json = r#"[
+            {"compiler": "/usr/bin/clang-cl", "archive": "/tc/clang.tar.zst",
+             "executable": "/usr/bin/clang", "arguments": ["--driver-mode=cl"]},
+            {"compiler": "/usr/bin/cc", "archive": "/tc/gcc.tar.zst",
+             "executable": "/usr/bin/gcc", "platform": "aarch64-linux"}
+        ]"#;
+        assert_eq!(vec![CrossToolchain {
+                            compiler: PathBuf::from("/usr/bin/clang-cl"),
+                            archive: PathBuf::from("/tc/clang.tar.zst"),
+                            executable: "/usr/bin/clang".to_owned(),
+                            platform: "x86_64-linux".to_owned(),
+                            arguments: vec!["--driver-mode=cl".to_owned()],
+                        },
+                        CrossToolchain {
+                            compiler: PathBuf::from("/usr/bin/cc"),
+                            archive: PathBuf::from("/tc/gcc.tar.zst"),
+                            executable: "/usr/bin/gcc".to_owned(),
+                            platform: "aarch64-linux".to_owned(),
+                            arguments: vec!(),
+                        }],
+                   parse_cross_toolchains(json).unwrap());
+        assert!(parse_cross_toolchains(r#"[{"compiler": "/usr/bin/cc"}]"#).is_err());
+    }
+}
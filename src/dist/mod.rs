@@ -0,0 +1,465 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Distributed compilation.
+//!
+//! A scheduler keeps track of a pool of build servers, which tell it about
+//! their load and the toolchains they have in regular heartbeats. An
+//! sccache server that misses the cache asks the scheduler for a build
+//! server to run the compilation on, instead of running it locally.
+//!
+//! Compilers run on build servers from toolchains: archives of the
+//! compiler and the files it needs, packaged by the sccache server and
+//! named by their digest, which are uploaded to each build server once.
+//!
+//! All of them exchange the messages in this module in the frames of
+//! `protocol`, one request and response per connection, over TLS if
+//! they're configured to use it. Every request carries a token that
+//! authorizes it, as described in `auth`.
+
+use futures::{Future, Stream};
+use native_tls::{TlsAcceptor, TlsConnector};
+use protocol::{self, HEADER_LEN, PROTOCOL_VERSION};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::io::{Codec, EasyBuf, Framed, Io};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::Service;
+use tokio_tls::TlsAcceptorExt;
+use tls::{TlsConfig, read_file};
+
+use errors::*;
+
+pub mod auth;
+pub mod client;
+pub mod scheduler;
+pub mod server;
+pub mod toolchain;
+
+/// The port the scheduler listens on by default.
+pub const DEFAULT_SCHEDULER_PORT: u16 = 10600;
+
+/// The port build servers listen on by default.
+pub const DEFAULT_SERVER_PORT: u16 = 10601;
+
+/// How often build servers send the scheduler a heartbeat, in seconds.
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 10;
+
+/// How long requests to the scheduler may take, in seconds.
+const SCHEDULER_TIMEOUT_SECS: u64 = 10;
+
+/// The state of a build server, as reported in its heartbeats.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// The address clients reach the build server on.
+    pub addr: String,
+    /// The platform the build server runs on, as returned by `platform`.
+    pub platform: String,
+    /// The number of jobs the build server runs at once.
+    pub max_jobs: u32,
+    /// The number of jobs the build server is running.
+    pub running_jobs: u32,
+    /// The toolchains the build server has, by id.
+    pub toolchains: Vec<String>,
+}
+
+/// A request for a build server to run a job on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobRequest {
+    /// The toolchain the job needs, by id.
+    pub toolchain: String,
+    /// The platform the toolchain runs on.
+    pub platform: String,
+}
+
+/// A build server allocated to a job.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobAlloc {
+    /// The id of the job, unique for the scheduler process.
+    pub job_id: u64,
+    /// The address of the build server.
+    pub server: String,
+    /// Whether the build server has the toolchain the job needs.
+    pub has_toolchain: bool,
+    /// The token that authorizes the job on the build server.
+    pub job_token: String,
+}
+
+/// What the scheduler knows about its build servers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerStatus {
+    /// The build servers that sent a heartbeat recently, by address.
+    pub servers: Vec<ServerStatus>,
+    /// The number of jobs allocated since the scheduler started.
+    pub jobs_allocated: u64,
+}
+
+/// A request, with the token that authorizes it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Authenticated<T> {
+    pub token: String,
+    pub request: T,
+}
+
+/// A request to the scheduler.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum SchedulerRequest {
+    /// A build server's heartbeat.
+    Heartbeat(ServerStatus),
+    /// Allocate a build server to a job.
+    AllocJob(JobRequest),
+    /// Describe the build servers.
+    Status,
+}
+
+/// A response from the scheduler.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum SchedulerResponse {
+    /// The heartbeat was recorded.
+    HeartbeatAccepted,
+    /// The build server allocated to the job, unless none can take it.
+    JobAllocated(Option<JobAlloc>),
+    /// What the scheduler knows about its build servers.
+    Status(SchedulerStatus),
+    /// The token isn't valid for the request.
+    Unauthorized,
+}
+
+/// A compilation to run on a build server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunJob {
+    /// The id the scheduler gave the job.
+    pub job_id: u64,
+    /// The toolchain to run the compiler from, by id.
+    pub toolchain: String,
+    /// The path of the compiler in the toolchain.
+    pub compiler: String,
+    /// The arguments to run the compiler with, naming files relative to
+    /// the directory it's run in.
+    pub arguments: Vec<String>,
+    /// The name of the input file, relative to the directory the compiler
+    /// is run in.
+    pub input_name: String,
+    /// The contents of the input file, which has been preprocessed.
+    pub input: Vec<u8>,
+    /// The outputs to send back, as pairs of a name and a path relative to
+    /// the directory the compiler is run in.
+    pub outputs: Vec<(String, String)>,
+}
+
+/// The result of a compilation run on a build server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobResult {
+    /// The return code of the compiler, if it exited normally.
+    pub retcode: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The contents of the outputs the compiler wrote, by name.
+    pub outputs: Vec<(String, Vec<u8>)>,
+}
+
+/// A packaged toolchain.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Toolchain {
+    /// The id of the toolchain, the digest of `archive`.
+    pub id: String,
+    /// A zstd-compressed tar archive of the toolchain's files, at their
+    /// paths relative to the root directory.
+    pub archive: Vec<u8>,
+}
+
+/// A request to a build server.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerRequest {
+    /// Run a compilation.
+    RunJob(RunJob),
+    /// Store a toolchain for jobs to run from.
+    PutToolchain(Toolchain),
+}
+
+/// A response from a build server.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerResponse {
+    /// The compiler ran, successfully or not.
+    JobComplete(JobResult),
+    /// The build server doesn't have the toolchain of the job.
+    MissingToolchain,
+    /// The toolchain was stored.
+    ToolchainStored,
+    /// The token isn't valid for the request.
+    Unauthorized,
+    /// The build server couldn't run the compiler, for this reason.
+    Failed(String),
+}
+
+/// The platform this process runs on, such as `x86_64-linux`. Jobs only
+/// run on build servers of the platform of their toolchain.
+pub fn platform() -> String {
+    format!("{}-{}", env::consts::ARCH, env::consts::OS)
+}
+
+/// The address of the scheduler from `SCCACHE_DIST_SCHEDULER`, with the
+/// default port if it has none.
+pub fn scheduler_addr() -> Option<String> {
+    env::var("SCCACHE_DIST_SCHEDULER").ok()
+        .and_then(|addr| with_default_port(&addr, DEFAULT_SCHEDULER_PORT))
+}
+
+/// `addr`, with `port` unless it already has one, or `None` if it's empty.
+pub fn with_default_port(addr: &str, port: u16) -> Option<String> {
+    let addr = addr.trim();
+    if addr.is_empty() {
+        None
+    } else if addr.rsplit(':').next().map_or(false, |p| p.parse::<u16>().is_ok()) {
+        Some(addr.to_owned())
+    } else {
+        Some(format!("{}:{}", addr, port))
+    }
+}
+
+/// The host name in `addr`, a `host:port` address.
+fn host(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+    host.trim_left_matches('[').trim_right_matches(']')
+}
+
+/// Send `request` on `stream`, and read the response.
+fn exchange<S, Req, Resp>(stream: &mut S, addr: &str, request: &Req) -> Result<Resp>
+    where S: Read + Write,
+          Req: Serialize,
+          Resp: Deserialize,
+{
+    let mut buf = vec!();
+    protocol::encode(request, &mut buf)?;
+    stream.write_all(&buf)?;
+    let mut header = [0; HEADER_LEN];
+    stream.read_exact(&mut header)
+        .chain_err(|| format!("no response from {}", addr))?;
+    let (version, len) = protocol::parse_header(&header);
+    if version != PROTOCOL_VERSION {
+        bail!("{} speaks protocol version {}, not {}", addr, version, PROTOCOL_VERSION);
+    }
+    protocol::check_message_len(len)?;
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(protocol::decode(&buf)?)
+}
+
+/// Connects to the scheduler and build servers.
+#[derive(Clone)]
+pub struct Connector {
+    /// Connections use TLS if this is set.
+    tls: Option<Arc<TlsConnector>>,
+}
+
+impl Connector {
+    /// Connect with TLS if `SCCACHE_DIST_TLS` is set, trusting the
+    /// certificate authorities configured for remote storage.
+    pub fn from_environment() -> Result<Connector> {
+        let tls = match env::var_os("SCCACHE_DIST_TLS") {
+            Some(_) => Some(Arc::new(TlsConfig::from_env()?.connector()?)),
+            None => None,
+        };
+        Ok(Connector { tls: tls })
+    }
+
+    /// Send `request` to `addr`, and wait up to `timeout` for the response.
+    pub fn request<Req, Resp>(&self, addr: &str, request: &Req, timeout: Option<Duration>) -> Result<Resp>
+        where Req: Serialize,
+              Resp: Deserialize,
+    {
+        let stream = TcpStream::connect(addr)
+            .chain_err(|| format!("couldn't connect to {}", addr))?;
+        stream.set_read_timeout(timeout)?;
+        match self.tls {
+            Some(ref tls) => {
+                let mut stream = tls.connect(host(addr), stream)
+                    .chain_err(|| format!("TLS handshake with {} failed", addr))?;
+                exchange(&mut stream, addr, request)
+            }
+            None => {
+                let mut stream = stream;
+                exchange(&mut stream, addr, request)
+            }
+        }
+    }
+
+    /// Send `request` to the scheduler at `addr`, authorized by `token`.
+    pub fn scheduler_request(&self, addr: &str, token: &str, request: SchedulerRequest)
+                             -> Result<SchedulerResponse> {
+        let request = Authenticated {
+            token: token.to_owned(),
+            request: request,
+        };
+        let timeout = Some(Duration::from_secs(SCHEDULER_TIMEOUT_SECS));
+        match self.request(addr, &request, timeout)? {
+            SchedulerResponse::Unauthorized => bail!("the scheduler at {} rejected the token", addr),
+            response => Ok(response),
+        }
+    }
+}
+
+/// The TLS acceptor for the certificate in `SCCACHE_DIST_TLS_CERT` and its
+/// key in `SCCACHE_DIST_TLS_KEY`, both PEM files, if they're set.
+pub fn tls_acceptor() -> Result<Option<Arc<TlsAcceptor>>> {
+    match (env::var_os("SCCACHE_DIST_TLS_CERT"), env::var_os("SCCACHE_DIST_TLS_KEY")) {
+        (Some(cert), Some(key)) => {
+            let mut config = TlsConfig::default();
+            config.set_identity(&read_file(Path::new(&cert))?, &read_file(Path::new(&key))?)?;
+            Ok(Some(Arc::new(config.acceptor()?)))
+        }
+        (None, None) => Ok(None),
+        _ => bail!("SCCACHE_DIST_TLS_CERT and SCCACHE_DIST_TLS_KEY must be set together"),
+    }
+}
+
+/// Serve requests of type `In` with `service` on the connections
+/// `listener` accepts, over TLS if `tls` is set, until the process is
+/// killed.
+pub fn serve<In, Out, S>(core: &mut Core,
+                         listener: TcpListener,
+                         tls: Option<Arc<TlsAcceptor>>,
+                         service: S) -> Result<()>
+    where In: Deserialize + 'static,
+          Out: Serialize + 'static,
+          S: Service<Request = In, Response = Out, Error = io::Error> + Clone + 'static,
+{
+    let handle = core.handle();
+    let server = listener.incoming().for_each(move |(socket, addr)| {
+        let service = service.clone();
+        match tls {
+            Some(ref tls) => {
+                let inner = handle.clone();
+                handle.spawn(tls.accept_async(socket).then(move |res| {
+                    match res {
+                        Ok(socket) => DistProto::<In, Out>::new().bind_server(&inner, socket, service),
+                        Err(e) => debug!("TLS handshake with {} failed: {}", addr, e),
+                    }
+                    Ok(())
+                }));
+            }
+            None => DistProto::<In, Out>::new().bind_server(&handle, socket, service),
+        }
+        Ok(())
+    });
+    core.run(server)?;
+    Ok(())
+}
+
+/// A codec for the frames of `protocol`, decoding `In` and encoding `Out`.
+pub struct DistCodec<In, Out> {
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out> DistCodec<In, Out> {
+    pub fn new() -> DistCodec<In, Out> {
+        DistCodec { _messages: PhantomData }
+    }
+}
+
+impl<In, Out> Codec for DistCodec<In, Out>
+    where In: Deserialize,
+          Out: Serialize,
+{
+    type In = In;
+    type Out = Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<In>> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None)
+        }
+        let (version, len) = protocol::parse_header(buf.as_slice());
+        if version != PROTOCOL_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("request of protocol version {}", version)));
+        }
+        // Refuse frames too large to buffer before reading any of them.
+        protocol::check_message_len(len)?;
+        if buf.len() < HEADER_LEN + len {
+            return Ok(None)
+        }
+        let frame = buf.drain_to(HEADER_LEN + len);
+        protocol::decode(&frame.as_slice()[HEADER_LEN..]).map(Some)
+    }
+
+    fn encode(&mut self, msg: Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        protocol::encode(&msg, buf)
+    }
+}
+
+/// tokio-proto protocol for serving requests of type `In` with responses of
+/// type `Out`.
+pub struct DistProto<In, Out> {
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out> DistProto<In, Out> {
+    pub fn new() -> DistProto<In, Out> {
+        DistProto { _messages: PhantomData }
+    }
+}
+
+impl<I, In, Out> ServerProto<I> for DistProto<In, Out>
+    where I: Io + 'static,
+          In: Deserialize + 'static,
+          Out: Serialize + 'static,
+{
+    type Request = In;
+    type Response = Out;
+    type Transport = Framed<I, DistCodec<In, Out>>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: I) -> Self::BindTransport {
+        Ok(io.framed(DistCodec::new()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_with_default_port() {
+        assert_eq!(Some("sched:10600".to_owned()), with_default_port("sched", DEFAULT_SCHEDULER_PORT));
+        assert_eq!(Some("sched:1234".to_owned()), with_default_port(" sched:1234", DEFAULT_SCHEDULER_PORT));
+        assert_eq!(Some("[::1]:10600".to_owned()), with_default_port("[::1]", DEFAULT_SCHEDULER_PORT));
+        assert_eq!(None, with_default_port("", DEFAULT_SCHEDULER_PORT));
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!("sched", host("sched:10600"));
+        assert_eq!("::1", host("[::1]:10600"));
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        let mut codec = DistCodec::<ServerRequest, ServerResponse>::new();
+        // Refused from the header alone.
+        let mut buf = EasyBuf::from(vec!(PROTOCOL_VERSION, 0xff, 0xff, 0xff, 0xff));
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
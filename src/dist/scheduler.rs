@@ -0,0 +1,345 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The scheduler, which assigns jobs to build servers.
+
+use dist::{
+    self,
+    Authenticated,
+    HEARTBEAT_INTERVAL_SECS,
+    JobAlloc,
+    JobRequest,
+    SchedulerRequest,
+    SchedulerResponse,
+    SchedulerStatus,
+    ServerStatus,
+};
+use dist::auth;
+use futures::future::{self, FutureResult};
+use native_tls::TlsAcceptor;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_service::Service;
+
+use errors::*;
+
+/// Build servers that missed this many heartbeats are forgotten.
+const MISSED_HEARTBEATS: u64 = 3;
+
+/// The configuration of a scheduler.
+pub struct Config {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// The tokens clients may present.
+    pub client_tokens: Vec<String>,
+    /// The secret shared with build servers.
+    pub server_token: String,
+    /// Connections use TLS if this is set.
+    pub tls: Option<Arc<TlsAcceptor>>,
+}
+
+impl Config {
+    /// Read the configuration from the environment.
+    pub fn from_environment() -> Result<Config> {
+        let listen = env::var("SCCACHE_DIST_SCHEDULER_LISTEN")
+            .unwrap_or_else(|_| format!("0.0.0.0:{}", dist::DEFAULT_SCHEDULER_PORT));
+        let listen = listen.parse::<SocketAddr>()
+            .chain_err(|| format!("invalid SCCACHE_DIST_SCHEDULER_LISTEN {}", listen))?;
+        Ok(Config {
+            listen: listen,
+            client_tokens: auth::client_tokens()?,
+            server_token: auth::server_token()?,
+            tls: dist::tls_acceptor()?,
+        })
+    }
+}
+
+/// A build server known to the scheduler.
+struct Server {
+    /// Its last heartbeat.
+    status: ServerStatus,
+    /// When its last heartbeat was received.
+    last_seen: Instant,
+    /// The jobs allocated to it since its last heartbeat, which that
+    /// heartbeat didn't count yet.
+    allocated: u32,
+}
+
+impl Server {
+    /// The jobs the server is running or about to run.
+    fn load(&self) -> u32 {
+        self.status.running_jobs + self.allocated
+    }
+}
+
+/// The build servers known to the scheduler, and how busy they are.
+pub struct Scheduler {
+    servers: HashMap<String, Server>,
+    jobs_allocated: u64,
+    /// The secret shared with build servers, which signs job tokens.
+    server_token: String,
+}
+
+impl Scheduler {
+    pub fn new(server_token: String) -> Scheduler {
+        Scheduler {
+            servers: HashMap::new(),
+            jobs_allocated: 0,
+            server_token: server_token,
+        }
+    }
+
+    /// Record the heartbeat of a build server received at `now`.
+    pub fn heartbeat(&mut self, status: ServerStatus, now: Instant) {
+        if !self.servers.contains_key(&status.addr) {
+            info!("Build server {} ({}, {} jobs) joined", status.addr, status.platform, status.max_jobs);
+        }
+        self.servers.insert(status.addr.clone(), Server {
+            status: status,
+            last_seen: now,
+            allocated: 0,
+        });
+    }
+
+    /// Forget the build servers that stopped sending heartbeats by `now`.
+    fn expire(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(HEARTBEAT_INTERVAL_SECS * MISSED_HEARTBEATS);
+        let expired = self.servers.iter()
+            .filter(|&(_, server)| now.duration_since(server.last_seen) > timeout)
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        for addr in expired {
+            info!("Build server {} stopped sending heartbeats", addr);
+            self.servers.remove(&addr);
+        }
+    }
+
+    /// Allocate the least loaded build server of the job's platform with a
+    /// free job slot to `job`, preferring one that has its toolchain, or
+    /// `None` if all of them are busy.
+    pub fn alloc_job(&mut self, job: &JobRequest, now: Instant) -> Option<JobAlloc> {
+        self.expire(now);
+        let jobs_allocated = &mut self.jobs_allocated;
+        let server_token = &self.server_token;
+        let best = self.servers.values_mut()
+            .filter(|server| server.status.platform == job.platform)
+            .filter(|server| server.load() < server.status.max_jobs)
+            .min_by_key(|server| {
+                let has_toolchain = server.status.toolchains.contains(&job.toolchain);
+                // Compare loads as fractions of the servers' job slots.
+                (server.load() as u64 * 1000 / server.status.max_jobs as u64,
+                 !has_toolchain,
+                 server.status.addr.clone())
+            });
+        best.map(|server| {
+            server.allocated += 1;
+            *jobs_allocated += 1;
+            let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+            JobAlloc {
+                job_id: *jobs_allocated,
+                server: server.status.addr.clone(),
+                has_toolchain: server.status.toolchains.contains(&job.toolchain),
+                job_token: auth::issue_job_token(server_token, *jobs_allocated,
+                                                 &server.status.addr, epoch.as_secs()),
+            }
+        })
+    }
+
+    /// Describe the build servers still sending heartbeats at `now`.
+    pub fn status(&mut self, now: Instant) -> SchedulerStatus {
+        self.expire(now);
+        let mut servers = self.servers.values().map(|server| {
+            let mut status = server.status.clone();
+            status.running_jobs = server.load();
+            status
+        }).collect::<Vec<_>>();
+        servers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        SchedulerStatus {
+            servers: servers,
+            jobs_allocated: self.jobs_allocated,
+        }
+    }
+}
+
+/// Serves scheduler requests.
+#[derive(Clone)]
+struct SchedulerService {
+    scheduler: Rc<RefCell<Scheduler>>,
+    client_tokens: Rc<Vec<String>>,
+    server_token: Rc<String>,
+}
+
+impl SchedulerService {
+    /// Whether `token` authorizes `req`: heartbeats come from build
+    /// servers, and everything else from clients.
+    fn authorized(&self, token: &str, req: &SchedulerRequest) -> bool {
+        match *req {
+            SchedulerRequest::Heartbeat(_) => auth::token_matches(token, &self.server_token),
+            SchedulerRequest::AllocJob(_) | SchedulerRequest::Status => {
+                self.client_tokens.iter().any(|t| auth::token_matches(token, t))
+            }
+        }
+    }
+}
+
+impl Service for SchedulerService {
+    type Request = Authenticated<SchedulerRequest>;
+    type Response = SchedulerResponse;
+    type Error = io::Error;
+    type Future = FutureResult<SchedulerResponse, io::Error>;
+
+    fn call(&self, req: Authenticated<SchedulerRequest>) -> Self::Future {
+        let Authenticated { token, request: req } = req;
+        if !self.authorized(&token, &req) {
+            warn!("Rejected a request with an invalid token");
+            return future::ok(SchedulerResponse::Unauthorized)
+        }
+        let now = Instant::now();
+        let mut scheduler = self.scheduler.borrow_mut();
+        let res = match req {
+            SchedulerRequest::Heartbeat(status) => {
+                trace!("Heartbeat from {}", status.addr);
+                scheduler.heartbeat(status, now);
+                SchedulerResponse::HeartbeatAccepted
+            }
+            SchedulerRequest::AllocJob(job) => {
+                let alloc = scheduler.alloc_job(&job, now);
+                match alloc {
+                    Some(ref alloc) => debug!("Job {} for toolchain {} allocated to {}",
+                                              alloc.job_id, job.toolchain, alloc.server),
+                    None => debug!("No build server free for toolchain {}", job.toolchain),
+                }
+                SchedulerResponse::JobAllocated(alloc)
+            }
+            SchedulerRequest::Status => SchedulerResponse::Status(scheduler.status(now)),
+        };
+        future::ok(res)
+    }
+}
+
+/// Run a scheduler with `config` until the process is killed.
+pub fn run(config: Config) -> Result<()> {
+    let mut core = Core::new()?;
+    let listener = TcpListener::bind(&config.listen, &core.handle())
+        .chain_err(|| format!("couldn't listen on {}", config.listen))?;
+    info!("Scheduler listening on {}{}", config.listen,
+          if config.tls.is_some() { " with TLS" } else { "" });
+    let service = SchedulerService {
+        scheduler: Rc::new(RefCell::new(Scheduler::new(config.server_token.clone()))),
+        client_tokens: Rc::new(config.client_tokens),
+        server_token: Rc::new(config.server_token),
+    };
+    dist::serve(&mut core, listener, config.tls, service)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dist::{JobRequest, ServerStatus};
+    use std::time::{Duration, Instant};
+
+    fn status(addr: &str, max_jobs: u32, running_jobs: u32, toolchains: &[&str]) -> ServerStatus {
+        ServerStatus {
+            addr: addr.to_owned(),
+            platform: "x86_64-linux".to_owned(),
+            max_jobs: max_jobs,
+            running_jobs: running_jobs,
+            toolchains: toolchains.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    fn job(toolchain: &str) -> JobRequest {
+        JobRequest {
+            toolchain: toolchain.to_owned(),
+            platform: "x86_64-linux".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_alloc_job_least_loaded() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new("secret".to_owned());
+        scheduler.heartbeat(status("a:1", 4, 2, &[]), now);
+        scheduler.heartbeat(status("b:1", 8, 2, &[]), now);
+        let alloc = scheduler.alloc_job(&job("gcc"), now).unwrap();
+        assert_eq!("b:1", alloc.server);
+        assert_eq!(1, alloc.job_id);
+        assert!(!alloc.has_toolchain);
+        // Allocated jobs count until the next heartbeat.
+        for _ in 0..2 {
+            assert_eq!("b:1", scheduler.alloc_job(&job("gcc"), now).unwrap().server);
+        }
+        assert_eq!("a:1", scheduler.alloc_job(&job("gcc"), now).unwrap().server);
+    }
+
+    #[test]
+    fn test_alloc_job_prefers_toolchain() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new("secret".to_owned());
+        scheduler.heartbeat(status("a:1", 4, 0, &[]), now);
+        scheduler.heartbeat(status("b:1", 4, 0, &["gcc"]), now);
+        let alloc = scheduler.alloc_job(&job("gcc"), now).unwrap();
+        assert_eq!("b:1", alloc.server);
+        assert!(alloc.has_toolchain);
+    }
+
+    #[test]
+    fn test_alloc_job_full() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new("secret".to_owned());
+        scheduler.heartbeat(status("a:1", 1, 0, &[]), now);
+        let mut other_platform = status("b:1", 4, 0, &[]);
+        other_platform.platform = "x86_64-macos".to_owned();
+        scheduler.heartbeat(other_platform, now);
+        assert!(scheduler.alloc_job(&job("gcc"), now).is_some());
+        assert_eq!(None, scheduler.alloc_job(&job("gcc"), now));
+        // A heartbeat brings the server's load up to date.
+        scheduler.heartbeat(status("a:1", 1, 0, &[]), now);
+        assert!(scheduler.alloc_job(&job("gcc"), now).is_some());
+    }
+
+    #[test]
+    fn test_authorized() {
+        let service = SchedulerService {
+            scheduler: Rc::new(RefCell::new(Scheduler::new("secret".to_owned()))),
+            client_tokens: Rc::new(vec!["a".to_owned(), "b".to_owned()]),
+            server_token: Rc::new("secret".to_owned()),
+        };
+        let heartbeat = SchedulerRequest::Heartbeat(status("a:1", 4, 0, &[]));
+        assert!(service.authorized("secret", &heartbeat));
+        assert!(!service.authorized("b", &heartbeat));
+        assert!(service.authorized("b", &SchedulerRequest::Status));
+        assert!(!service.authorized("secret", &SchedulerRequest::Status));
+        assert!(!service.authorized("", &SchedulerRequest::AllocJob(job("gcc"))));
+    }
+
+    #[test]
+    fn test_expire() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new("secret".to_owned());
+        scheduler.heartbeat(status("a:1", 4, 0, &[]), now);
+        assert_eq!(1, scheduler.status(now).servers.len());
+        let later = now + Duration::from_secs(HEARTBEAT_INTERVAL_SECS * (MISSED_HEARTBEATS + 1));
+        assert_eq!(None, scheduler.alloc_job(&job("gcc"), later));
+        assert!(scheduler.status(later).servers.is_empty());
+    }
+}
@@ -0,0 +1,597 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The build server, which runs compilations for clients in a sandbox.
+//!
+//! A toolchain is a directory holding a copy of the parts of the client's
+//! filesystem the compiler needs, named by the toolchain's id. Clients
+//! upload the toolchains that are missing, and the least recently used
+//! ones are removed once there are more than `max_toolchains`. Each job
+//! runs in a new directory with bubblewrap, which makes the toolchain the
+//! root filesystem, read-only, and mounts the job's directory on `/build`
+//! with nothing else from the build server visible, and no network.
+
+use cache::disk_cache_dir;
+use compiler::temp_dir;
+use dist::{
+    self,
+    Authenticated,
+    Connector,
+    HEARTBEAT_INTERVAL_SECS,
+    JobResult,
+    RunJob,
+    SchedulerRequest,
+    ServerRequest,
+    ServerResponse,
+    ServerStatus,
+    Toolchain,
+};
+use dist::{auth, toolchain};
+use filetime::{FileTime, set_file_times};
+use futures::Future;
+use futures::future;
+use futures_cpupool::CpuPool;
+#[cfg(unix)]
+use libc;
+use native_tls::TlsAcceptor;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempdir::TempDir;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_service::Service;
+
+use errors::*;
+
+/// Where jobs are run in the sandbox.
+const SANDBOX_BUILD_DIR: &'static str = "/build";
+
+/// How many toolchains are kept by default.
+const DEFAULT_MAX_TOOLCHAINS: usize = 16;
+
+/// The configuration of a build server.
+pub struct Config {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// The address clients reach the build server on.
+    pub public_addr: String,
+    /// The address of the scheduler.
+    pub scheduler: String,
+    /// The number of jobs to run at once.
+    pub max_jobs: u32,
+    /// The directory holding the toolchains, by id.
+    pub toolchains: PathBuf,
+    /// The number of toolchains to keep.
+    pub max_toolchains: usize,
+    /// The bubblewrap executable.
+    pub bwrap: PathBuf,
+    /// The secret shared with the scheduler.
+    pub server_token: String,
+    /// Connects to the scheduler.
+    pub connector: Connector,
+    /// Connections use TLS if this is set.
+    pub tls: Option<Arc<TlsAcceptor>>,
+}
+
+impl Config {
+    /// Read the configuration from the environment.
+    pub fn from_environment() -> Result<Config> {
+        let listen = env::var("SCCACHE_DIST_SERVER_LISTEN")
+            .unwrap_or_else(|_| format!("0.0.0.0:{}", dist::DEFAULT_SERVER_PORT));
+        let listen = listen.parse::<SocketAddr>()
+            .chain_err(|| format!("invalid SCCACHE_DIST_SERVER_LISTEN {}", listen))?;
+        let public_addr = match env::var("SCCACHE_DIST_SERVER_ADDR") {
+            Ok(addr) => dist::with_default_port(&addr, listen.port())
+                .ok_or("SCCACHE_DIST_SERVER_ADDR is empty")?,
+            Err(_) if !is_unspecified(&listen) => listen.to_string(),
+            Err(_) => bail!("SCCACHE_DIST_SERVER_ADDR must be set to the address clients reach \
+                             this build server on"),
+        };
+        let scheduler = dist::scheduler_addr().ok_or("SCCACHE_DIST_SCHEDULER is not set")?;
+        let max_jobs = match env::var("SCCACHE_DIST_MAX_JOBS") {
+            Ok(n) => match n.parse() {
+                Ok(n) if n > 0 => n,
+                _ => bail!("invalid SCCACHE_DIST_MAX_JOBS {}", n),
+            },
+            Err(_) => num_cpus(),
+        };
+        let toolchains = env::var_os("SCCACHE_DIST_TOOLCHAINS")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| disk_cache_dir().join("dist-toolchains"));
+        let max_toolchains = match env::var("SCCACHE_DIST_MAX_TOOLCHAINS") {
+            Ok(n) => match n.parse() {
+                Ok(n) if n > 0 => n,
+                _ => bail!("invalid SCCACHE_DIST_MAX_TOOLCHAINS {}", n),
+            },
+            Err(_) => DEFAULT_MAX_TOOLCHAINS,
+        };
+        let bwrap = env::var_os("SCCACHE_DIST_BWRAP")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("bwrap"));
+        Ok(Config {
+            listen: listen,
+            public_addr: public_addr,
+            scheduler: scheduler,
+            max_jobs: max_jobs,
+            toolchains: toolchains,
+            max_toolchains: max_toolchains,
+            bwrap: bwrap,
+            server_token: auth::server_token()?,
+            connector: Connector::from_environment()?,
+            tls: dist::tls_acceptor()?,
+        })
+    }
+}
+
+fn is_unspecified(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V4(ref a) => a.ip().is_unspecified(),
+        SocketAddr::V6(ref a) => a.ip().is_unspecified(),
+    }
+}
+
+/// The number of online processors.
+#[cfg(unix)]
+fn num_cpus() -> u32 {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n > 0 { n as u32 } else { 1 }
+}
+
+#[cfg(not(unix))]
+fn num_cpus() -> u32 { 1 }
+
+/// The ids of the toolchains in `dir`.
+fn toolchains(dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec!(),
+    };
+    let mut toolchains = entries.filter_map(|e| e.ok())
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .filter_map(|e| e.file_name().into_string().ok())
+        // Toolchains being put in place are hidden.
+        .filter(|name| !name.starts_with('.'))
+        .collect::<Vec<_>>();
+    toolchains.sort();
+    toolchains
+}
+
+/// Whether `path`, a relative path sent by a client, stays inside the
+/// directory it's relative to.
+fn is_confined(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.as_os_str().is_empty() && !path.has_root() && path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+/// The command to run `job`, with its files in `build_dir`, in a sandbox.
+fn sandbox_command(config: &Config, root: &Path, build_dir: &Path, job: &RunJob) -> Command {
+    let mut cmd = Command::new(&config.bwrap);
+    cmd.arg("--die-with-parent")
+        .arg("--unshare-all")
+        .arg("--ro-bind").arg(root).arg("/")
+        .arg("--bind").arg(build_dir).arg(SANDBOX_BUILD_DIR)
+        .args(&["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"])
+        .args(&["--chdir", SANDBOX_BUILD_DIR])
+        .arg("--")
+        .arg(&job.compiler)
+        .args(&job.arguments)
+        .env_clear()
+        .env("PATH", "/usr/bin:/bin")
+        .stdin(Stdio::null());
+    cmd
+}
+
+/// Read the output `path` of a job run in `build_dir`. Only regular files
+/// in the build directory are read, so that a job can't have the server
+/// send it files from outside the sandbox through symlinks.
+fn read_output(build_dir: &Path, path: &str) -> Result<Vec<u8>> {
+    let full_path = build_dir.join(path);
+    let metadata = fs::symlink_metadata(&full_path)?;
+    if !metadata.file_type().is_file() {
+        bail!("{} is not a regular file", path);
+    }
+    let dir = full_path.parent().unwrap_or(build_dir).canonicalize()?;
+    if !dir.starts_with(build_dir.canonicalize()?) {
+        bail!("{} is outside the build directory", path);
+    }
+    let mut data = vec!();
+    open_no_follow(&full_path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(unix)]
+fn open_no_follow(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(path)
+}
+
+#[cfg(not(unix))]
+fn open_no_follow(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Run `job` in a sandbox.
+fn run_job(config: &Config, in_use: &ToolchainsInUse, job: RunJob) -> Result<ServerResponse> {
+    if !cfg!(target_os = "linux") {
+        bail!("build servers only run on Linux");
+    }
+    if !is_confined(&job.toolchain) {
+        bail!("invalid toolchain id {}", job.toolchain);
+    }
+    // Before checking that it's there, so that it isn't removed after.
+    let _toolchain = in_use.acquire(&job.toolchain);
+    let root = config.toolchains.join(&job.toolchain);
+    if !root.is_dir() {
+        return Ok(ServerResponse::MissingToolchain);
+    }
+    // The modification time of a toolchain is when it was last used.
+    let d = since_epoch();
+    let now = FileTime::from_seconds_since_1970(d.as_secs(), d.subsec_nanos());
+    if let Err(e) = set_file_times(&root, now, now) {
+        debug!("Failed to update the modification time of toolchain {}: {}", job.toolchain, e);
+    }
+    if !is_confined(&job.input_name) || !job.outputs.iter().all(|&(_, ref path)| is_confined(path)) {
+        bail!("job files must be relative to the build directory");
+    }
+    let build_dir = temp_dir("sccache-dist")?;
+    File::create(build_dir.path().join(&job.input_name))?.write_all(&job.input)?;
+    let output = sandbox_command(config, &root, build_dir.path(), &job).output()
+        .chain_err(|| format!("failed to run {}", config.bwrap.display()))?;
+    let mut outputs = vec!();
+    if output.status.success() {
+        for &(ref name, ref path) in &job.outputs {
+            let data = read_output(build_dir.path(), path)
+                .chain_err(|| format!("the compiler didn't write {}", path))?;
+            outputs.push((name.clone(), data));
+        }
+    }
+    Ok(ServerResponse::JobComplete(JobResult {
+        retcode: output.status.code(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+        outputs: outputs,
+    }))
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+}
+
+/// Unpack `toolchain` into the toolchain directory, unless it's there
+/// already, and remove the least recently used toolchains beyond
+/// `config.max_toolchains`.
+fn store_toolchain(config: &Config, in_use: &ToolchainsInUse, toolchain: Toolchain) -> Result<ServerResponse> {
+    // This also makes sure that the id is a file name.
+    if toolchain::digest(&toolchain.archive) != toolchain.id {
+        bail!("toolchain {} doesn't match its digest", toolchain.id);
+    }
+    let root = config.toolchains.join(&toolchain.id);
+    if !root.is_dir() {
+        fs::create_dir_all(&config.toolchains)?;
+        // Toolchains are unpacked in a hidden directory, so that jobs never
+        // see half of one.
+        let dir = TempDir::new_in(&config.toolchains, ".upload")?;
+        toolchain::unpack(&toolchain.archive, dir.path())
+            .chain_err(|| format!("failed to unpack toolchain {}", toolchain.id))?;
+        let dir = dir.into_path();
+        match fs::rename(&dir, &root) {
+            Ok(()) => info!("Stored toolchain {} ({} bytes)", toolchain.id, toolchain.archive.len()),
+            // Another client uploaded the same toolchain first.
+            Err(_) => drop(fs::remove_dir_all(&dir)),
+        }
+    }
+    remove_unused_toolchains(config, in_use, &toolchain.id);
+    Ok(ServerResponse::ToolchainStored)
+}
+
+/// Remove the least recently used toolchains other than `keep` and those
+/// jobs are running with until at most `config.max_toolchains` are left.
+fn remove_unused_toolchains(config: &Config, in_use: &ToolchainsInUse, keep: &str) {
+    // Jobs can't start using a toolchain while it's being removed.
+    let in_use = in_use.0.lock().unwrap();
+    let mut unused = toolchains(&config.toolchains).into_iter()
+        .filter(|id| id != keep && !in_use.contains_key(id))
+        .map(|id| {
+            let mtime = fs::metadata(config.toolchains.join(&id))
+                .map(|m| FileTime::from_last_modification_time(&m))
+                .unwrap_or(FileTime::zero());
+            (mtime, id)
+        })
+        .collect::<Vec<_>>();
+    unused.sort();
+    let excess = (unused.len() + 1).saturating_sub(config.max_toolchains);
+    for (_, id) in unused.into_iter().take(excess) {
+        info!("Removing toolchain {}", id);
+        if let Err(e) = fs::remove_dir_all(config.toolchains.join(&id)) {
+            warn!("Failed to remove toolchain {}: {}", id, e);
+        }
+    }
+}
+
+/// The toolchains running jobs use, with how many jobs use each, so that
+/// they aren't removed while in use.
+#[derive(Clone, Default)]
+struct ToolchainsInUse(Arc<Mutex<HashMap<String, usize>>>);
+
+impl ToolchainsInUse {
+    fn acquire(&self, id: &str) -> ToolchainUse {
+        *self.0.lock().unwrap().entry(id.to_owned()).or_insert(0) += 1;
+        ToolchainUse(self.clone(), id.to_owned())
+    }
+}
+
+/// Counts a use of a toolchain while it's alive.
+struct ToolchainUse(ToolchainsInUse, String);
+
+impl Drop for ToolchainUse {
+    fn drop(&mut self) {
+        let mut in_use = (self.0).0.lock().unwrap();
+        let last = match in_use.get_mut(&self.1) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if last {
+            in_use.remove(&self.1);
+        }
+    }
+}
+
+/// Counts a running job while it's alive.
+struct RunningJob(Arc<AtomicUsize>);
+
+impl RunningJob {
+    fn new(running: &Arc<AtomicUsize>) -> RunningJob {
+        running.fetch_add(1, Ordering::SeqCst);
+        RunningJob(running.clone())
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves build server requests.
+#[derive(Clone)]
+struct ServerService {
+    config: Arc<Config>,
+    pool: CpuPool,
+    running: Arc<AtomicUsize>,
+    toolchains_in_use: ToolchainsInUse,
+}
+
+impl ServerService {
+    /// Whether `token` authorizes `req`: only clients the scheduler
+    /// allocated a job on this build server may store toolchains and run
+    /// the job.
+    fn authorized(&self, token: &str, req: &ServerRequest) -> bool {
+        let config = &self.config;
+        let now = since_epoch().as_secs();
+        match auth::verify_job_token(&config.server_token, token, &config.public_addr, now) {
+            Some(job_id) => match *req {
+                ServerRequest::RunJob(ref job) => job.job_id == job_id,
+                ServerRequest::PutToolchain(_) => true,
+            },
+            None => false,
+        }
+    }
+}
+
+impl Service for ServerService {
+    type Request = Authenticated<ServerRequest>;
+    type Response = ServerResponse;
+    type Error = io::Error;
+    type Future = Box<Future<Item = ServerResponse, Error = io::Error>>;
+
+    fn call(&self, req: Authenticated<ServerRequest>) -> Self::Future {
+        let Authenticated { token, request: req } = req;
+        if !self.authorized(&token, &req) {
+            warn!("Rejected a request with an invalid job token");
+            return Box::new(future::ok(ServerResponse::Unauthorized))
+        }
+        match req {
+            ServerRequest::RunJob(job) => {
+                let config = self.config.clone();
+                let running = self.running.clone();
+                let in_use = self.toolchains_in_use.clone();
+                Box::new(self.pool.spawn_fn(move || -> io::Result<_> {
+                    let _running = RunningJob::new(&running);
+                    let job_id = job.job_id;
+                    debug!("Running job {}", job_id);
+                    Ok(match run_job(&config, &in_use, job) {
+                        Ok(res) => res,
+                        Err(e) => {
+                            warn!("Job {} failed: {}", job_id, e);
+                            ServerResponse::Failed(e.to_string())
+                        }
+                    })
+                }))
+            }
+            ServerRequest::PutToolchain(toolchain) => {
+                let config = self.config.clone();
+                let in_use = self.toolchains_in_use.clone();
+                Box::new(self.pool.spawn_fn(move || -> io::Result<_> {
+                    let id = toolchain.id.clone();
+                    Ok(match store_toolchain(&config, &in_use, toolchain) {
+                        Ok(res) => res,
+                        Err(e) => {
+                            warn!("Failed to store toolchain {}: {}", id, e);
+                            ServerResponse::Failed(e.to_string())
+                        }
+                    })
+                }))
+            }
+        }
+    }
+}
+
+/// Send the scheduler a heartbeat every `HEARTBEAT_INTERVAL_SECS`, forever.
+fn send_heartbeats(config: Arc<Config>, running: Arc<AtomicUsize>) {
+    let mut reachable = true;
+    loop {
+        let status = ServerStatus {
+            addr: config.public_addr.clone(),
+            platform: dist::platform(),
+            max_jobs: config.max_jobs,
+            running_jobs: running.load(Ordering::SeqCst) as u32,
+            toolchains: toolchains(&config.toolchains),
+        };
+        let request = SchedulerRequest::Heartbeat(status);
+        match config.connector.scheduler_request(&config.scheduler, &config.server_token, request) {
+            Ok(_) if !reachable => {
+                info!("Reached the scheduler at {} again", config.scheduler);
+                reachable = true;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if reachable {
+                    warn!("Failed to send a heartbeat to the scheduler at {}: {}", config.scheduler, e);
+                }
+                reachable = false;
+            }
+        }
+        thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+    }
+}
+
+/// Run a build server with `config` until the process is killed.
+pub fn run(config: Config) -> Result<()> {
+    let mut core = Core::new()?;
+    let listener = TcpListener::bind(&config.listen, &core.handle())
+        .chain_err(|| format!("couldn't listen on {}", config.listen))?;
+    info!("Build server listening on {}{}, reachable at {}, running {} jobs at once",
+          config.listen, if config.tls.is_some() { " with TLS" } else { "" },
+          config.public_addr, config.max_jobs);
+    let tls = config.tls.clone();
+    let config = Arc::new(config);
+    let running = Arc::new(AtomicUsize::new(0));
+    {
+        let config = config.clone();
+        let running = running.clone();
+        thread::spawn(move || send_heartbeats(config, running));
+    }
+    let service = ServerService {
+        pool: CpuPool::new(config.max_jobs as usize),
+        config: config,
+        running: running,
+        toolchains_in_use: ToolchainsInUse::default(),
+    };
+    dist::serve(&mut core, listener, tls, service)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use test::utils::*;
+
+    #[test]
+    fn test_is_confined() {
+        assert!(is_confined("foo.o"));
+        assert!(is_confined("out/foo.o"));
+        assert!(!is_confined("/etc/passwd"));
+        assert!(!is_confined("../foo.o"));
+        assert!(!is_confined("out/../../foo.o"));
+        assert!(!is_confined(""));
+    }
+
+    #[test]
+    fn test_toolchains() {
+        let f = TestFixture::new();
+        let dir = f.tempdir.path().join("toolchains");
+        assert!(toolchains(&dir).is_empty());
+        for name in &["b", "a", ".incoming"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        File::create(dir.join("c")).unwrap();
+        assert_eq!(vec!("a".to_owned(), "b".to_owned()), toolchains(&dir));
+    }
+
+    #[test]
+    fn test_remove_unused_toolchains() {
+        let f = TestFixture::new();
+        let config = Config {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            public_addr: "127.0.0.1:0".to_owned(),
+            scheduler: "127.0.0.1:0".to_owned(),
+            max_jobs: 1,
+            toolchains: f.tempdir.path().join("toolchains"),
+            max_toolchains: 2,
+            bwrap: PathBuf::from("bwrap"),
+            server_token: "secret".to_owned(),
+            connector: Connector::from_environment().unwrap(),
+            tls: None,
+        };
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            let dir = config.toolchains.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            let t = FileTime::from_seconds_since_1970(1000 + i as u64, 0);
+            set_file_times(&dir, t, t).unwrap();
+        }
+        // The least recently used toolchain goes, unless it's the one kept.
+        let in_use = ToolchainsInUse::default();
+        remove_unused_toolchains(&config, &in_use, "a");
+        assert_eq!(vec!("a".to_owned(), "c".to_owned()), toolchains(&config.toolchains));
+        // Nor does one a job is running with.
+        fs::create_dir_all(config.toolchains.join("d")).unwrap();
+        {
+            let _job = in_use.acquire("a");
+            remove_unused_toolchains(&config, &in_use, "d");
+            assert_eq!(vec!("a".to_owned(), "c".to_owned(), "d".to_owned()), toolchains(&config.toolchains));
+        }
+        remove_unused_toolchains(&config, &in_use, "d");
+        assert_eq!(vec!("c".to_owned(), "d".to_owned()), toolchains(&config.toolchains));
+    }
+
+    #[test]
+    fn test_read_output() {
+        let f = TestFixture::new();
+        let build_dir = f.tempdir.path().join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        File::create(build_dir.join("foo.o")).unwrap().write_all(b"object").unwrap();
+        assert_eq!(b"object".to_vec(), read_output(&build_dir, "foo.o").unwrap());
+        fs::create_dir_all(build_dir.join("dir.o")).unwrap();
+        assert!(read_output(&build_dir, "dir.o").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_output_symlink() {
+        use std::os::unix::fs::symlink;
+        let f = TestFixture::new();
+        let build_dir = f.tempdir.path().join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        File::create(f.tempdir.path().join("secret")).unwrap().write_all(b"secret").unwrap();
+        symlink(f.tempdir.path().join("secret"), build_dir.join("foo.o")).unwrap();
+        assert!(read_output(&build_dir, "foo.o").is_err());
+        symlink(f.tempdir.path(), build_dir.join("out")).unwrap();
+        assert!(read_output(&build_dir, "out/secret").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Packaging compilers into toolchains, and unpacking them.
+//!
+//! A toolchain holds the compiler binary, the programs it runs to compile
+//! preprocessed source, and the shared libraries all of them load, at the
+//! same paths as on the machine they were packaged on. Archives are built
+//! the same way from the same files, so that a toolchain's id only changes
+//! when the compiler does.
+
+use compiler::{Compiler, CompilerKind};
+use dist::Toolchain;
+use sha1;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tar::{Archive, Builder, EntryType, Header};
+use which::which_in;
+use zstd;
+
+use errors::*;
+
+/// zstd compression level for toolchain archives, which are built once
+/// for each compiler.
+const TOOLCHAIN_COMPRESSION_LEVEL: i32 = 9;
+
+/// The programs gcc runs to compile preprocessed source to an object file.
+const GCC_PROGRAMS: &'static [&'static str] = &["cc1", "cc1plus", "as"];
+
+/// The hex sha-1 digest of `data`.
+pub fn digest(data: &[u8]) -> String {
+    let mut m = sha1::Sha1::new();
+    m.update(data);
+    m.digest().to_string()
+}
+
+/// Package `compiler`, its programs and their shared libraries.
+pub fn package_compiler(compiler: &Compiler) -> Result<Toolchain> {
+    let executable = Path::new(&compiler.executable);
+    if !executable.is_absolute() {
+        bail!("compiler path {} isn't absolute", compiler.executable);
+    }
+    let mut programs = vec![executable.to_owned()];
+    match compiler.kind {
+        CompilerKind::Gcc => {
+            for name in GCC_PROGRAMS {
+                if let Some(program) = gcc_program(executable, name)? {
+                    programs.push(program);
+                }
+            }
+        }
+        // Clang assembles by itself.
+        CompilerKind::Clang => {}
+        CompilerKind::Msvc { .. } => bail!("MSVC can't be packaged"),
+    }
+    let mut files = BTreeSet::new();
+    for program in programs {
+        files.extend(shared_libraries(&program)?);
+        files.insert(program);
+    }
+    let files = files.into_iter()
+        .map(|path| (path.strip_prefix("/").unwrap().to_owned(), path))
+        .collect::<Vec<_>>();
+    let archive = archive(&files)?;
+    debug!("Packaged {} files of {} into toolchain of {} bytes",
+           files.len(), compiler.executable, archive.len());
+    Ok(Toolchain {
+        id: digest(&archive),
+        archive: archive,
+    })
+}
+
+/// The path of the program `name` that gcc at `executable` runs, if it
+/// has one.
+fn gcc_program(executable: &Path, name: &str) -> Result<Option<PathBuf>> {
+    let output = Command::new(executable)
+        .arg(format!("-print-prog-name={}", name))
+        .output()
+        .chain_err(|| format!("failed to run {}", executable.display()))?;
+    let path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    if path.is_absolute() {
+        Ok(if path.is_file() { Some(path) } else { None })
+    } else {
+        // gcc looks for programs it doesn't have in `PATH`.
+        Ok(which_in(&path, env::var_os("PATH"), &env::current_dir()?).ok())
+    }
+}
+
+/// The shared libraries the program at `path` loads, including its
+/// dynamic linker.
+fn shared_libraries(path: &Path) -> Result<Vec<PathBuf>> {
+    let output = Command::new("ldd").arg(path).output()
+        .chain_err(|| "failed to run ldd")?;
+    // Statically linked programs make ldd fail.
+    if !output.status.success() {
+        return Ok(vec!())
+    }
+    Ok(parse_ldd(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the output of ldd into the paths of the libraries it lists.
+fn parse_ldd(output: &str) -> Vec<PathBuf> {
+    output.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        let path = match words.next() {
+            Some(path) if path.starts_with('/') => Some(path),
+            // `name => path (address)`, or `name => not found`.
+            Some(_) if words.next() == Some("=>") => words.next(),
+            _ => None,
+        };
+        match path {
+            Some(path) if path.starts_with('/') => Some(PathBuf::from(path)),
+            _ => None,
+        }
+    }).collect()
+}
+
+/// Build an archive of `files`, pairs of a path in the archive and the
+/// file to read its contents from.
+fn archive(files: &[(PathBuf, PathBuf)]) -> Result<Vec<u8>> {
+    let encoder = zstd::stream::Encoder::new(vec!(), TOOLCHAIN_COMPRESSION_LEVEL)?;
+    let mut builder = Builder::new(encoder);
+    for &(ref name, ref source) in files {
+        let mut data = vec!();
+        File::open(source).and_then(|mut f| f.read_to_end(&mut data))
+            .chain_err(|| format!("failed to read {}", source.display()))?;
+        let metadata = source.metadata()?;
+        let mut header = Header::new_gnu();
+        header.set_path(name)?;
+        header.set_size(data.len() as u64);
+        header.set_mode(mode(&metadata));
+        // Leave out everything else that could differ between machines.
+        header.set_mtime(0);
+        header.set_cksum();
+        builder.append(&header, &data[..])?;
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &::std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o111 != 0 { 0o755 } else { 0o644 }
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &::std::fs::Metadata) -> u32 {
+    0o755
+}
+
+/// Unpack the archive of a toolchain into the directory `root`, which
+/// must be empty. Only regular files and directories are accepted, so
+/// that a toolchain can't reach outside `root`.
+pub fn unpack(archive: &[u8], root: &Path) -> Result<()> {
+    let mut archive = Archive::new(zstd::stream::Decoder::new(archive)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => {}
+            _ => bail!("toolchain contains a special file: {}", entry.path()?.display()),
+        }
+        if !entry.unpack_in(root)? {
+            bail!("toolchain contains an invalid path: {}", entry.path()?.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use test::utils::*;
+
+    #[test]
+    fn test_parse_ldd() {
+        let output = "\
+\tlinux-vdso.so.1 (0x00007ffd5c5e1000)
+\tlibm.so.6 => /lib/x86_64-linux-gnu/libm.so.6 (0x00007f2a3c0f0000)
+\tlibfoo.so => not found
+\tlibc.so.6 => /lib/x86_64-linux-gnu/libc.so.6 (0x00007f2a3bd26000)
+\t/lib64/ld-linux-x86-64.so.2 (0x00007f2a3c3f9000)
+";
+        assert_eq!(vec![PathBuf::from("/lib/x86_64-linux-gnu/libm.so.6"),
+                        PathBuf::from("/lib/x86_64-linux-gnu/libc.so.6"),
+                        PathBuf::from("/lib64/ld-linux-x86-64.so.2")],
+                   parse_ldd(output));
+        assert!(parse_ldd("\tnot a dynamic executable\n").is_empty());
+    }
+
+    #[test]
+    fn test_archive_unpack() {
+        let f = TestFixture::new();
+        let source = f.tempdir.path().join("cc");
+        File::create(&source).unwrap().write_all(b"compiler").unwrap();
+        let files = vec![(PathBuf::from("usr/bin/cc"), source)];
+        let data = archive(&files).unwrap();
+        // Archives of the same files are identical.
+        assert_eq!(data, archive(&files).unwrap());
+        let root = f.tempdir.path().join("root");
+        unpack(&data, &root).unwrap();
+        let mut contents = vec!();
+        File::open(root.join("usr/bin/cc")).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(b"compiler", &contents[..]);
+    }
+}
@@ -0,0 +1,460 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running compilations for distcc clients, through the cache.
+//!
+//! distcc clients preprocess locally and send a job as a sequence of
+//! tokens: four letters and a value of eight hex digits, followed by that
+//! many bytes for strings. A job is `DIST` (the protocol version, only 1 is
+//! supported), `ARGC`, an `ARGV` string for each argument and `DOTI`, the
+//! preprocessed source. The reply is `DONE`, `STAT` (the compiler's wait
+//! status), `SERR`, `SOUT` and `DOTO`, the object file.
+//!
+//! Jobs are looked up in the cache with the key a local compilation of the
+//! same preprocessed source would have, and only compiled on a miss.
+//! Jobs that can't be handled close the connection, which makes distcc
+//! clients compile locally.
+
+use cache::{Cache, Storage, get_verified, hash_key, storage_from_environment};
+use compiler::{Compiler, CompilerArguments, CompilerKind, get_compiler_info, key_arguments, temp_dir};
+use filetime::FileTime;
+use futures::{Future, Stream, future};
+use futures::future::{Loop, loop_fn};
+use futures_cpupool::CpuPool;
+use mock_command::{ProcessCommandCreator, exit_status_from_code};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::process::{self, Command};
+use std::rc::Rc;
+use std::str;
+use std::sync::Arc;
+use tokio_core::io::{Io, read_exact, write_all};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use which::which_in;
+
+use errors::*;
+
+/// The port distcc servers listen on.
+pub const DEFAULT_PORT: u16 = 3632;
+
+/// The compilers distcc clients may run, unless `SCCACHE_DISTCC_COMPILERS`
+/// says otherwise.
+const DEFAULT_COMPILERS: &'static str = "cc,c++,gcc,g++,clang,clang++";
+
+/// The most arguments a job may have.
+const MAX_ARGC: u32 = 10000;
+
+/// The largest string a client may send, such as the preprocessed source.
+const MAX_STRING_LEN: u32 = 512 * 1024 * 1024;
+
+/// The names the input and object file of a job are given.
+const INPUT_NAME: &'static str = "input";
+const OUTPUT_NAME: &'static str = "output.o";
+
+/// Configuration of the distcc server.
+pub struct Config {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// The networks clients may connect from.
+    pub allow: Vec<Network>,
+    /// The names of the compilers clients may run, looked up in `PATH`.
+    pub compilers: Vec<String>,
+}
+
+impl Config {
+    /// Read the configuration from `SCCACHE_DISTCC_LISTEN`,
+    /// `SCCACHE_DISTCC_ALLOW` and `SCCACHE_DISTCC_COMPILERS`.
+    pub fn from_environment() -> Result<Config> {
+        let listen = env::var("SCCACHE_DISTCC_LISTEN")
+            .unwrap_or_else(|_| format!("0.0.0.0:{}", DEFAULT_PORT));
+        let listen = listen.parse::<SocketAddr>()
+            .chain_err(|| format!("invalid SCCACHE_DISTCC_LISTEN {}", listen))?;
+        let allow = env::var("SCCACHE_DISTCC_ALLOW").unwrap_or_default();
+        let allow = split_list(&allow).iter()
+            .map(|net| Network::parse(net).ok_or_else(|| format!("invalid network {} in SCCACHE_DISTCC_ALLOW", net)))
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        if allow.is_empty() {
+            bail!("SCCACHE_DISTCC_ALLOW must be set to the networks clients connect from");
+        }
+        let compilers = env::var("SCCACHE_DISTCC_COMPILERS")
+            .unwrap_or_else(|_| DEFAULT_COMPILERS.to_owned());
+        Ok(Config {
+            listen: listen,
+            allow: allow,
+            compilers: split_list(&compilers),
+        })
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// An address, or a network of them such as `10.0.0.0/8`.
+#[derive(Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl Network {
+    pub fn parse(s: &str) -> Option<Network> {
+        let mut parts = s.splitn(2, '/');
+        let addr = match parts.next().and_then(|a| a.parse::<IpAddr>().ok()) {
+            Some(addr) => addr,
+            None => return None,
+        };
+        let max = octets(&addr).len() as u32 * 8;
+        let prefix_len = match parts.next() {
+            Some(len) => match len.parse() {
+                Ok(len) if len <= max => len,
+                _ => return None,
+            },
+            None => max,
+        };
+        Some(Network {
+            addr: addr,
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// Whether `addr` is in this network.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let (net, addr) = (octets(&self.addr), octets(addr));
+        if net.len() != addr.len() {
+            return false
+        }
+        let full = (self.prefix_len / 8) as usize;
+        let rest = self.prefix_len % 8;
+        if net[..full] != addr[..full] {
+            return false
+        }
+        rest == 0 || {
+            let mask = !(0xffu8 >> rest);
+            net[full] & mask == addr[full] & mask
+        }
+    }
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(ref a) => a.octets().to_vec(),
+        IpAddr::V6(ref a) => a.octets().to_vec(),
+    }
+}
+
+/// A token of the distcc protocol, with the integer `value`.
+fn token(name: &str, value: u32) -> Vec<u8> {
+    format!("{}{:08x}", name, value).into_bytes()
+}
+
+/// A token of the distcc protocol, followed by the string `data`.
+fn string_token(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut out = token(name, data.len() as u32);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Parse the value of a token that must be named `name`.
+fn parse_token(buf: &[u8], name: &str) -> Result<u32> {
+    if &buf[..4] != name.as_bytes() {
+        bail!("expected {} token, got {}", name, String::from_utf8_lossy(&buf[..4]));
+    }
+    str::from_utf8(&buf[4..]).ok()
+        .and_then(|value| u32::from_str_radix(value, 16).ok())
+        .ok_or_else(|| format!("invalid value for {} token", name).into())
+}
+
+fn read_token<R: Io + 'static>(reader: R, name: &'static str) -> SFuture<(R, u32)> {
+    Box::new(read_exact(reader, [0; 12]).from_err().and_then(move |(reader, buf)| {
+        let value = parse_token(&buf, name)?;
+        Ok((reader, value))
+    }))
+}
+
+fn read_string<R: Io + 'static>(reader: R, name: &'static str) -> SFuture<(R, Vec<u8>)> {
+    Box::new(read_token(reader, name).and_then(move |(reader, len)| -> SFuture<_> {
+        if len > MAX_STRING_LEN {
+            return Box::new(future::err(format!("{} of {} bytes is too large", name, len).into()))
+        }
+        Box::new(read_exact(reader, vec![0; len as usize]).from_err())
+    }))
+}
+
+/// A job sent by a distcc client.
+struct Job {
+    argv: Vec<String>,
+    input: Vec<u8>,
+}
+
+fn read_job<R: Io + 'static>(reader: R) -> SFuture<(R, Job)> {
+    let argc = read_token(reader, "DIST").and_then(|(reader, version)| {
+        if version != 1 {
+            bail!("unsupported distcc protocol version {}", version);
+        }
+        Ok(reader)
+    }).and_then(|reader| read_token(reader, "ARGC"));
+    let argv = argc.and_then(|(reader, argc)| -> SFuture<_> {
+        if argc == 0 || argc > MAX_ARGC {
+            return Box::new(future::err(format!("invalid argument count {}", argc).into()))
+        }
+        Box::new(loop_fn((reader, vec!()), move |(reader, mut argv): (R, Vec<String>)| {
+            read_string(reader, "ARGV").map(move |(reader, arg)| {
+                argv.push(String::from_utf8_lossy(&arg).into_owned());
+                if argv.len() == argc as usize {
+                    Loop::Break((reader, argv))
+                } else {
+                    Loop::Continue((reader, argv))
+                }
+            })
+        }))
+    });
+    Box::new(argv.and_then(|(reader, argv)| {
+        read_string(reader, "DOTI").map(move |(reader, input)| {
+            (reader, Job { argv: argv, input: input })
+        })
+    }))
+}
+
+/// The reply to a job.
+fn reply(output: &process::Output, obj: &[u8]) -> Vec<u8> {
+    let mut out = token("DONE", 1);
+    out.extend(token("STAT", wait_status(&output.status)));
+    out.extend(string_token("SERR", &output.stderr));
+    out.extend(string_token("SOUT", &output.stdout));
+    out.extend(string_token("DOTO", obj));
+    out
+}
+
+#[cfg(unix)]
+fn wait_status(status: &process::ExitStatus) -> u32 {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => ((code & 0xff) << 8) as u32,
+        (None, Some(signal)) => signal as u32,
+        (None, None) => 1 << 8,
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_status(status: &process::ExitStatus) -> u32 {
+    (status.code().unwrap_or(1) as u32 & 0xff) << 8
+}
+
+/// Runs the jobs of distcc clients.
+struct DistccServer {
+    handle: Handle,
+    pool: CpuPool,
+    storage: Arc<Storage>,
+    creator: ProcessCommandCreator,
+    /// The names of the compilers clients may run.
+    allowed_compilers: Vec<String>,
+    /// The compilers seen so far, by path.
+    compilers: Rc<RefCell<HashMap<String, Compiler>>>,
+}
+
+impl DistccServer {
+    /// Find the compiler named `name`, which must be gcc or clang.
+    fn compiler(&self, name: &str) -> SFuture<Compiler> {
+        if !self.allowed_compilers.iter().any(|c| c == name) {
+            return future::err(format!("compiler {} isn't allowed", name).into()).boxed()
+        }
+        let path = match which_in(name, env::var_os("PATH"), &env::current_dir().unwrap_or_default()) {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(_) => return future::err(format!("compiler {} not found", name).into()).boxed(),
+        };
+        let mtime = fs::metadata(&path).map(|m| FileTime::from_last_modification_time(&m)).ok();
+        match self.compilers.borrow().get(&path) {
+            Some(c) if Some(c.mtime) == mtime => return future::ok(c.clone()).boxed(),
+            _ => {}
+        }
+        let compilers = self.compilers.clone();
+        Box::new(get_compiler_info(&self.creator, &path, &self.pool).and_then(move |compiler| {
+            match compiler.kind {
+                CompilerKind::Gcc | CompilerKind::Clang => {}
+                CompilerKind::Msvc { .. } => bail!("{} isn't gcc or clang", compiler.executable),
+            }
+            compilers.borrow_mut().insert(compiler.executable.clone(), compiler.clone());
+            Ok(compiler)
+        }))
+    }
+
+    /// Run `job`, from the cache if it's there.
+    fn run_job(&self, job: Job) -> SFuture<(process::Output, Vec<u8>)> {
+        let storage = self.storage.clone();
+        let pool = self.pool.clone();
+        let handle = self.handle.clone();
+        Box::new(self.compiler(&job.argv[0]).and_then(move |compiler| -> SFuture<_> {
+            let arguments = job.argv[1..].to_vec();
+            let parsed_args = match compiler.parse_arguments(&arguments, &env::temp_dir()) {
+                CompilerArguments::Ok(parsed_args) => parsed_args,
+                _ => return future::err("job can't be cached".into()).boxed(),
+            };
+            let compile_args = match compiler.kind.dist_arguments(&parsed_args, INPUT_NAME, OUTPUT_NAME) {
+                Some(args) => args,
+                None => return future::err("job can't be compiled from preprocessed source".into()).boxed(),
+            };
+            let key = hash_key(&compiler, &key_arguments(&parsed_args), &job.input);
+            let lookup = get_verified(storage.clone(), &key, &pool);
+            Box::new(lookup.and_then(move |result| -> SFuture<_> {
+                if let Cache::Hit(mut entry) = result {
+                    debug!("distcc job {}: cache hit", key);
+                    return Box::new(pool.spawn_fn(move || {
+                        let (mut obj, mut stdout, mut stderr) = (vec!(), vec!(), vec!());
+                        entry.get_object("obj", &mut obj)?;
+                        entry.get_object("stdout", &mut stdout).unwrap_or(());
+                        entry.get_object("stderr", &mut stderr).unwrap_or(());
+                        let output = process::Output {
+                            status: exit_status_from_code(0),
+                            stdout: stdout,
+                            stderr: stderr,
+                        };
+                        Ok((output, obj))
+                    }))
+                }
+                debug!("distcc job {}: cache miss", key);
+                let executable = compiler.executable.clone();
+                let compile = pool.spawn_fn(move || compile(&executable, compile_args, job.input));
+                Box::new(compile.map(move |(output, obj)| {
+                    if output.status.success() {
+                        store(&handle, storage, key, &output, &obj);
+                    }
+                    (output, obj)
+                }))
+            }))
+        }))
+    }
+}
+
+/// Compile `input` with the compiler at `executable` and `arguments`, in a
+/// temporary directory, returning its output and the object file.
+fn compile(executable: &str, arguments: Vec<String>, input: Vec<u8>) -> Result<(process::Output, Vec<u8>)> {
+    let dir = temp_dir("sccache_distcc")?;
+    File::create(dir.path().join(INPUT_NAME)).and_then(|mut f| f.write_all(&input))?;
+    let output = Command::new(executable)
+        .args(&arguments)
+        .current_dir(dir.path())
+        .output()
+        .chain_err(|| format!("failed to run {}", executable))?;
+    let mut obj = vec!();
+    if output.status.success() {
+        File::open(dir.path().join(OUTPUT_NAME)).and_then(|mut f| f.read_to_end(&mut obj))
+            .chain_err(|| "the compiler didn't write the object file")?;
+    }
+    Ok((output, obj))
+}
+
+/// Store the result of a job in `storage` in the background.
+fn store(handle: &Handle, storage: Arc<Storage>, key: String, output: &process::Output, obj: &[u8]) {
+    let entry = storage.start_put(&key).and_then(|mut entry| {
+        entry.put_object("obj", &mut &obj[..])?;
+        if !output.stdout.is_empty() {
+            entry.put_object("stdout", &mut &output.stdout[..])?;
+        }
+        if !output.stderr.is_empty() {
+            entry.put_object("stderr", &mut &output.stderr[..])?;
+        }
+        Ok(entry)
+    });
+    let put = match entry {
+        Ok(entry) => storage.finish_put(&key, entry),
+        Err(e) => future::err(e).boxed(),
+    };
+    handle.spawn(put.then(move |result| {
+        match result {
+            Ok(_) => debug!("distcc job {}: stored in cache", key),
+            Err(e) => debug!("distcc job {}: cache write error: {}", key, e),
+        }
+        Ok(())
+    }));
+}
+
+/// Run a distcc server until the process is killed.
+pub fn run(config: Config) -> Result<()> {
+    let mut core = Core::new()?;
+    let pool = CpuPool::new(20);
+    let handle = core.handle();
+    let server = Rc::new(DistccServer {
+        handle: handle.clone(),
+        pool: pool.clone(),
+        storage: storage_from_environment(&pool, &handle)?,
+        creator: ProcessCommandCreator::new(&handle),
+        allowed_compilers: config.compilers,
+        compilers: Rc::new(RefCell::new(HashMap::new())),
+    });
+    let listener = TcpListener::bind(&config.listen, &handle)
+        .chain_err(|| format!("couldn't listen on {}", config.listen))?;
+    info!("distcc server listening on {}, caching in {}", config.listen, server.storage.location());
+    let allow = config.allow;
+    core.run(listener.incoming().for_each(move |(socket, addr)| {
+        if !allow.iter().any(|net| net.contains(&addr.ip())) {
+            warn!("Refusing distcc connection from {}", addr);
+            return Ok(())
+        }
+        let server = server.clone();
+        let job = read_job(socket).and_then(move |(socket, job)| {
+            trace!("distcc job from {}: {:?}", addr, job.argv);
+            server.run_job(job).and_then(move |(output, obj)| {
+                write_all(socket, reply(&output, &obj)).from_err()
+            })
+        });
+        handle.spawn(job.then(move |result| {
+            if let Err(e) = result {
+                debug!("distcc job from {} failed: {}", addr, e);
+            }
+            Ok(())
+        }));
+        Ok(())
+    }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(b"ARGC00000003".to_vec(), token("ARGC", 3));
+        assert_eq!(b"DOTO0000000aobject....".to_vec(), string_token("DOTO", b"object...."));
+        assert_eq!(26, parse_token(b"DOTI0000001a", "DOTI").unwrap());
+        assert!(parse_token(b"DOTI0000001a", "ARGV").is_err());
+        assert!(parse_token(b"DOTI0000001z", "DOTI").is_err());
+    }
+
+    #[test]
+    fn test_network() {
+        let net = Network::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&"10.1.2.3".parse::<IpAddr>().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse::<IpAddr>().unwrap()));
+        assert!(!net.contains(&"::1".parse::<IpAddr>().unwrap()));
+        let net = Network::parse("192.168.0.128/25").unwrap();
+        assert!(net.contains(&"192.168.0.200".parse::<IpAddr>().unwrap()));
+        assert!(!net.contains(&"192.168.0.100".parse::<IpAddr>().unwrap()));
+        let net = Network::parse("::1").unwrap();
+        assert!(net.contains(&"::1".parse::<IpAddr>().unwrap()));
+        assert!(!net.contains(&"::2".parse::<IpAddr>().unwrap()));
+        assert_eq!(None, Network::parse("10.0.0.0/33"));
+        assert_eq!(None, Network::parse("localhost"));
+    }
+}
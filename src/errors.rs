@@ -31,6 +31,30 @@ error_chain! {
             description("failed to get a successful HTTP status")
             display("didn't get a successful HTTP status, got `{}`", status)
         }
+        ReadOnlyCache {
+            description("the cache is read-only")
+            display("the cache is read-only")
+        }
+        CacheUnavailable {
+            description("the cache is unavailable")
+            display("the cache is unavailable")
+        }
+        ProcessTimedOut {
+            description("a compiler process timed out")
+            display("a compiler process ran for longer than SCCACHE_COMPILER_TIMEOUT and was killed")
+        }
+        ServerConnectionLost {
+            description("the connection to the server was lost")
+            display("the connection to the sccache server was lost")
+        }
+        ServerUnusable {
+            description("the server couldn't be used")
+            display("couldn't use the sccache server")
+        }
+        EntryTooLarge(size: usize) {
+            description("the cache entry is too large")
+            display("the cache entry is too large ({} bytes)", size)
+        }
     }
 }
 
@@ -0,0 +1,304 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal HTTP/1.1 server for serving cache storage to other tools.
+//!
+//! Each connection carries a single request, whose body is only read for
+//! `PUT`s, as many bytes as `Content-Length` says. Requests are checked
+//! before their body is read, so that a client that may not store
+//! anything can't make the server buffer it.
+
+use cache::parse_size;
+use dist::auth::token_matches;
+use futures::{Future, Stream};
+use futures::future::{Loop, loop_fn};
+use std::env;
+use std::io::{BufReader, Read};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::io::{read_exact, read_until, write_all};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+
+use errors::*;
+
+/// The most bytes the head of a request may take up.
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+
+/// The largest request body accepted, unless `SCCACHE_MAX_ENTRY_SIZE`
+/// says otherwise.
+pub const DEFAULT_MAX_BODY: usize = 256 * 1024 * 1024;
+
+/// The largest request body to accept. Bodies are stored as cache
+/// entries, so they're limited by `SCCACHE_MAX_ENTRY_SIZE` when it's set.
+pub fn max_body_from_environment() -> usize {
+    env::var("SCCACHE_MAX_ENTRY_SIZE").ok()
+        .and_then(|v| parse_size(&v))
+        .unwrap_or(DEFAULT_MAX_BODY)
+}
+
+/// What a client may do.
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    None,
+    Read,
+    ReadWrite,
+}
+
+/// The bearer tokens clients present to be given access.
+pub struct Tokens {
+    /// The token clients that may read and write present.
+    pub token: String,
+    /// The token clients that may only read present, if any.
+    pub read_token: Option<String>,
+}
+
+impl Tokens {
+    /// The tokens in the environment variables `var` and `read_var`, or
+    /// `None` if `var` isn't set.
+    pub fn from_environment(var: &str, read_var: &str) -> Result<Option<Tokens>> {
+        let token = match env::var(var) {
+            Ok(ref token) if token.is_empty() => bail!("{} is empty", var),
+            Ok(token) => token,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(Tokens {
+            token: token,
+            read_token: env::var(read_var).ok().and_then(|token| {
+                if token.is_empty() { None } else { Some(token) }
+            }),
+        }))
+    }
+
+    /// What a client presenting `token` may do.
+    pub fn access(&self, token: Option<&str>) -> Access {
+        let token = match token {
+            Some(token) => token,
+            None => return Access::None,
+        };
+        if token_matches(token, &self.token) {
+            Access::ReadWrite
+        } else if self.read_token.as_ref().map_or(false, |t| token_matches(token, t)) {
+            Access::Read
+        } else {
+            Access::None
+        }
+    }
+
+    /// Refuse the request with `head` if its client may not make it,
+    /// before its body is read.
+    pub fn check(&self, head: &RequestHead) -> Option<Response> {
+        match self.access(head.bearer_token()) {
+            Access::None => Some(Response::new("401 Unauthorized")),
+            Access::Read if head.method == "PUT" => Some(Response::new("403 Forbidden")),
+            Access::Read | Access::ReadWrite => None,
+        }
+    }
+}
+
+/// The head of an HTTP request.
+#[derive(Debug, Default, PartialEq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub content_length: Option<usize>,
+    pub authorization: Option<String>,
+}
+
+impl RequestHead {
+    /// Add a line of the request head: the request line, then headers.
+    fn add_line(&mut self, line: &str) {
+        if self.method.is_empty() {
+            let mut words = line.split_whitespace();
+            self.method = words.next().unwrap_or("").to_owned();
+            self.path = words.next().unwrap_or("").to_owned();
+        } else if let Some(colon) = line.find(':') {
+            let (name, value) = (&line[..colon], line[colon + 1..].trim());
+            match &name.to_lowercase()[..] {
+                "content-length" => self.content_length = value.parse().ok(),
+                "authorization" => self.authorization = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+    }
+
+    /// The bearer token the request was sent with, if any.
+    pub fn bearer_token(&self) -> Option<&str> {
+        match self.authorization {
+            Some(ref auth) if auth.starts_with("Bearer ") => Some(auth[7..].trim()),
+            _ => None,
+        }
+    }
+}
+
+/// An HTTP response status and body.
+pub struct Response {
+    pub status: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// A response with `status`, which is also its body.
+    pub fn new(status: &'static str) -> Response {
+        Response {
+            status: status,
+            body: format!("{}\n", status).into_bytes(),
+        }
+    }
+
+    /// The response to storing a cache entry with `result`.
+    pub fn stored(result: Result<Duration>) -> Result<Response> {
+        match result {
+            Ok(_) => Ok(Response::new("200 OK")),
+            Err(Error(ErrorKind::ReadOnlyCache, _)) => Ok(Response::new("403 Forbidden")),
+            Err(Error(ErrorKind::EntryTooLarge(_), _)) => Ok(Response::new("413 Payload Too Large")),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Checks the head of a request before its body is read, returning the
+/// response to refuse it with, if any.
+pub type Check = Rc<Fn(&RequestHead) -> Option<Response>>;
+
+/// Answers a request, given its head and body.
+pub type Handler = Rc<Fn(RequestHead, Vec<u8>) -> SFuture<Response>>;
+
+/// Answer the requests of connections to `listener` with `handler`, until
+/// the process is killed. Requests are first passed to `check`, and those
+/// with bodies larger than `max_body` bytes are refused.
+pub fn serve(core: &mut Core, listener: TcpListener, max_body: usize, check: Check, handler: Handler)
+             -> Result<()> {
+    let handle = core.handle();
+    core.run(listener.incoming().for_each(move |(socket, addr)| {
+        handle.spawn(respond(socket, max_body, check.clone(), handler.clone()).map_err(move |e| {
+            debug!("HTTP request from {} failed: {}", addr, e);
+        }));
+        Ok(())
+    }))?;
+    Ok(())
+}
+
+/// Answer a single HTTP request on `socket`.
+fn respond(socket: TcpStream, max_body: usize, check: Check, handler: Handler) -> SFuture<()> {
+    let reader = BufReader::new(socket).take(MAX_HEAD_LEN);
+    let head = loop_fn((reader, RequestHead::default()), |(reader, mut head)| {
+        read_until(reader, b'\n', vec!()).map(|(reader, line)| {
+            if !line.ends_with(b"\n") {
+                // The head was cut short, by its limit or the client.
+                let status = if reader.limit() == 0 {
+                    "431 Request Header Fields Too Large"
+                } else {
+                    "400 Bad Request"
+                };
+                return Loop::Break((reader, Err(Response::new(status))))
+            }
+            let line = String::from_utf8_lossy(&line).trim().to_owned();
+            if line.is_empty() {
+                Loop::Break((reader, Ok(head)))
+            } else {
+                head.add_line(&line);
+                Loop::Continue((reader, head))
+            }
+        })
+    });
+    Box::new(head.from_err::<Error>().and_then(move |(reader, head)| -> SFuture<()> {
+        let reader = reader.into_inner();
+        let head = match head {
+            Ok(head) => head,
+            Err(response) => return write_response(reader.into_inner(), response),
+        };
+        if let Some(response) = check(&head) {
+            return write_response(reader.into_inner(), response);
+        }
+        let length = if head.method == "PUT" { head.content_length.unwrap_or(0) } else { 0 };
+        if length > max_body {
+            return write_response(reader.into_inner(), Response::new("413 Payload Too Large"));
+        }
+        let request = format!("{} {}", head.method, head.path);
+        Box::new(read_exact(reader, vec![0; length]).from_err().and_then(move |(reader, body)| {
+            handler(head, body).then(move |result| {
+                let response = result.unwrap_or_else(|e| {
+                    warn!("{} failed: {}", request, e);
+                    Response::new("500 Internal Server Error")
+                });
+                write_response(reader.into_inner(), response)
+            })
+        }))
+    }))
+}
+
+/// Write `response` to `socket`.
+fn write_response(socket: TcpStream, response: Response) -> SFuture<()> {
+    let mut data = format!("HTTP/1.1 {}\r\n\
+                            Content-Type: application/octet-stream\r\n\
+                            Content-Length: {}\r\n\
+                            Connection: close\r\n\
+                            \r\n", response.status, response.body.len()).into_bytes();
+    data.extend(response.body);
+    Box::new(write_all(socket, data).map(|_| ()).from_err())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_access() {
+        let tokens = Tokens {
+            token: "write".to_owned(),
+            read_token: Some("read".to_owned()),
+        };
+        assert_eq!(Access::ReadWrite, tokens.access(Some("write")));
+        assert_eq!(Access::Read, tokens.access(Some("read")));
+        assert_eq!(Access::None, tokens.access(Some("other")));
+        assert_eq!(Access::None, tokens.access(None));
+    }
+
+    #[test]
+    fn test_check() {
+        let tokens = Tokens {
+            token: "write".to_owned(),
+            read_token: Some("read".to_owned()),
+        };
+        let head = |method: &str, token: Option<&str>| RequestHead {
+            method: method.to_owned(),
+            authorization: token.map(|t| format!("Bearer {}", t)),
+            ..RequestHead::default()
+        };
+        let status = |r: Option<Response>| r.map(|r| r.status);
+        assert_eq!(None, status(tokens.check(&head("PUT", Some("write")))));
+        assert_eq!(None, status(tokens.check(&head("GET", Some("read")))));
+        assert_eq!(Some("403 Forbidden"), status(tokens.check(&head("PUT", Some("read")))));
+        assert_eq!(Some("401 Unauthorized"), status(tokens.check(&head("PUT", None))));
+    }
+
+    #[test]
+    fn test_request_head() {
+        let mut head = RequestHead::default();
+        head.add_line("PUT /cas/abc HTTP/1.1");
+        head.add_line("Host: cache");
+        head.add_line("content-length: 5");
+        head.add_line("Authorization: Bearer secret");
+        assert_eq!(RequestHead {
+                       method: "PUT".to_owned(),
+                       path: "/cas/abc".to_owned(),
+                       content_length: Some(5),
+                       authorization: Some("Bearer secret".to_owned()),
+                   }, head);
+        assert_eq!(Some("secret"), head.bearer_token());
+        head.authorization = Some("Basic dXNlcg==".to_owned());
+        assert_eq!(None, head.bearer_token());
+    }
+}
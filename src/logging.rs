@@ -0,0 +1,423 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Logging for the server: log files that are rotated when they grow too
+//! large, changing the log level while the server runs, and keeping the
+//! last lines logged in memory so that clients can fetch them.
+//!
+//! Log files can hold one JSON object per record instead of lines of text,
+//! for aggregating the logs of many machines. Events such as finished
+//! compilations are logged with `log_event`, whose fields become fields of
+//! the JSON object.
+
+use cache::parse_size;
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter, SetLoggerError};
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time;
+
+/// The size at which log files are rotated.
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The number of rotated log files kept.
+const DEFAULT_KEEP: usize = 3;
+
+/// The number of log lines kept in memory.
+const RECENT_LINES: usize = 1000;
+
+thread_local! {
+    /// The maximum log level of the logger installed by `install`.
+    static MAX_LEVEL: RefCell<Option<MaxLogLevelFilter>> = RefCell::new(None);
+    /// The last lines logged by the logger installed by `install`.
+    static RECENT: RefCell<Option<Arc<RecentLines>>> = RefCell::new(None);
+    /// The fields of the event being logged by `log_event` on this thread.
+    static EVENT_FIELDS: RefCell<Option<BTreeMap<String, Value>>> = RefCell::new(None)
+}
+
+/// How log records are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Lines of text, such as `[2017-01-31][12:00:00][INFO][sccache::server] message`.
+    Text,
+    /// One JSON object per line, with `time`, `level`, `target` and
+    /// `message` fields, and the fields of the event logged.
+    Json,
+}
+
+/// Get the format of log records, from `SCCACHE_LOG_FORMAT`, which is
+/// either `text`, the default, or `json`.
+pub fn format_from_environment() -> LogFormat {
+    match env::var("SCCACHE_LOG_FORMAT") {
+        Ok(ref format) if format == "json" => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+/// Log `message` at `level` from `target`, with `fields` describing the
+/// event for JSON logs.
+pub fn log_event(level: LogLevel, target: &str, message: &str, fields: BTreeMap<String, Value>) {
+    if level > log::max_log_level() {
+        return;
+    }
+    // Logging is synchronous, so the logger formats the record on this
+    // thread.
+    EVENT_FIELDS.with(|f| *f.borrow_mut() = Some(fields));
+    log!(target: target, level, "{}", message);
+    EVENT_FIELDS.with(|f| *f.borrow_mut() = None);
+}
+
+/// The number of milliseconds in `duration`, for the fields of events.
+pub fn millis(duration: Duration) -> Value {
+    Value::F64(duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1e6)
+}
+
+/// Parse a log level such as `debug`.
+pub fn parse_level(level: &str) -> Option<LogLevelFilter> {
+    level.parse().ok()
+}
+
+/// Install `logger` as the global logger, logging at `level`, which can
+/// later be changed with `set_level`. The last lines logged are also kept
+/// in memory, to be fetched with `recent_lines`.
+pub fn install(logger: Box<Log>, level: LogLevelFilter) -> Result<(), SetLoggerError> {
+    let recent = Arc::new(RecentLines::new(RECENT_LINES));
+    RECENT.with(|r| *r.borrow_mut() = Some(recent.clone()));
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        MAX_LEVEL.with(|m| *m.borrow_mut() = Some(max_level));
+        Box::new(RecordingLogger {
+            inner: logger,
+            recent: recent,
+        })
+    })
+}
+
+/// Get the last `n` lines logged by the logger installed by `install`,
+/// oldest first.
+pub fn recent_lines(n: usize) -> Vec<String> {
+    RECENT.with(|r| r.borrow().as_ref().map_or(vec!(), |recent| recent.last(n)))
+}
+
+/// Change the level the logger installed by `install` logs at, returning
+/// the previous level, or `None` if there is no such logger.
+pub fn set_level(level: LogLevelFilter) -> Option<LogLevelFilter> {
+    MAX_LEVEL.with(|m| {
+        m.borrow().as_ref().map(|max_level| {
+            let previous = max_level.get();
+            max_level.set(level);
+            previous
+        })
+    })
+}
+
+/// Get the size at which log files are rotated, from `SCCACHE_LOG_MAX_SIZE`.
+pub fn max_size() -> u64 {
+    env::var("SCCACHE_LOG_MAX_SIZE")
+        .ok()
+        .and_then(|v| parse_size(&v))
+        .map_or(DEFAULT_MAX_SIZE, |s| s as u64)
+}
+
+/// Get the number of rotated log files to keep, from `SCCACHE_LOG_FILES`.
+pub fn keep() -> usize {
+    env::var("SCCACHE_LOG_FILES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_KEEP)
+}
+
+/// The name of the `n`th rotated copy of `path`, such as `sccache.log.1`.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Move `path` out of the way, to `path.1`, shifting older copies along
+/// and removing the oldest so that at most `keep` copies remain.
+pub fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return match fs::remove_file(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        };
+    }
+    let _ = fs::remove_file(rotated(path, keep));
+    for n in (1..keep).rev() {
+        let _ = fs::rename(rotated(path, n), rotated(path, n + 1));
+    }
+    match fs::rename(path, rotated(path, 1)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Rotate `path` if it is already larger than `max_size`.
+pub fn rotate_if_large(path: &Path, max_size: u64, keep: usize) -> io::Result<()> {
+    match fs::metadata(path) {
+        Ok(ref meta) if meta.len() >= max_size => rotate(path, keep),
+        _ => Ok(()),
+    }
+}
+
+/// A file that is rotated once it reaches a maximum size.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    /// Open `path` for appending.
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<RotatingFile> {
+        rotate_if_large(path, max_size, keep)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_owned(),
+            file: file,
+            size: size,
+            max_size: max_size,
+            keep: keep,
+        })
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            rotate(&self.path, self.keep)?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = 0;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Format `record` as a log line, without a trailing newline.
+fn format_record(record: &LogRecord) -> String {
+    format_message(LogFormat::Text, record.level(), record.target(), &record.args().to_string())
+}
+
+/// Format `message`, logged at `level` from `target`, as a log line in
+/// `format`, without a trailing newline.
+pub fn format_message(format: LogFormat, level: LogLevel, target: &str, message: &str) -> String {
+    match format {
+        LogFormat::Text => {
+            format!("[{}][{}][{}] {}",
+                    time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(),
+                    level,
+                    target,
+                    message)
+        }
+        LogFormat::Json => {
+            let mut fields = EVENT_FIELDS.with(|f| f.borrow().clone()).unwrap_or_default();
+            fields.insert("time".to_owned(),
+                          Value::String(time::now_utc().strftime("%Y-%m-%dT%H:%M:%SZ").unwrap().to_string()));
+            fields.insert("level".to_owned(), Value::String(level.to_string()));
+            fields.insert("target".to_owned(), Value::String(target.to_owned()));
+            fields.insert("message".to_owned(), Value::String(message.to_owned()));
+            // Values of plain JSON types always serialize.
+            serde_json::to_string(&Value::Object(fields)).unwrap()
+        }
+    }
+}
+
+/// The last lines logged, up to a fixed number of them.
+pub struct RecentLines {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl RecentLines {
+    pub fn new(capacity: usize) -> RecentLines {
+        RecentLines {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity,
+        }
+    }
+
+    /// Add `line`, dropping the oldest line if there are too many.
+    pub fn push(&self, line: String) {
+        if let Ok(mut lines) = self.lines.lock() {
+            if lines.len() >= self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    /// Get the last `n` lines, oldest first.
+    pub fn last(&self, n: usize) -> Vec<String> {
+        match self.lines.lock() {
+            Ok(lines) => lines.iter().skip(lines.len().saturating_sub(n)).cloned().collect(),
+            Err(_) => vec!(),
+        }
+    }
+}
+
+/// A logger keeping the lines it logs in `RecentLines`, before passing
+/// them on to another logger.
+struct RecordingLogger {
+    inner: Box<Log>,
+    recent: Arc<RecentLines>,
+}
+
+impl Log for RecordingLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= log::max_log_level()
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.recent.push(format_record(record));
+        self.inner.log(record);
+    }
+}
+
+/// A logger that discards everything, for a server that only keeps its
+/// log in memory.
+pub struct NullLogger;
+
+impl Log for NullLogger {
+    fn enabled(&self, _metadata: &LogMetadata) -> bool {
+        false
+    }
+
+    fn log(&self, _record: &LogRecord) {}
+}
+
+/// A logger writing to a `RotatingFile` in `format`, at the global maximum
+/// log level.
+pub struct FileLogger {
+    file: Mutex<RotatingFile>,
+    format: LogFormat,
+}
+
+impl FileLogger {
+    pub fn new(file: RotatingFile, format: LogFormat) -> FileLogger {
+        FileLogger {
+            file: Mutex::new(file),
+            format: format,
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= log::max_log_level()
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_message(self.format, record.level(), record.target(),
+                                  &record.args().to_string());
+        let line = format!("{}\n", line);
+        if let Ok(mut file) = self.file.lock() {
+            // There's nowhere to report failing to log.
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use test::utils::*;
+
+    fn contents(path: &Path) -> String {
+        let mut s = String::new();
+        File::open(path).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(Some(LogLevelFilter::Trace), parse_level("trace"));
+        assert_eq!(Some(LogLevelFilter::Warn), parse_level("WARN"));
+        assert_eq!(None, parse_level("verbose"));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut fields = BTreeMap::new();
+        fields.insert("outcome".to_owned(), Value::String("hit".to_owned()));
+        EVENT_FIELDS.with(|f| *f.borrow_mut() = Some(fields));
+        let line = format_message(LogFormat::Json, LogLevel::Info, "sccache::server", "done");
+        EVENT_FIELDS.with(|f| *f.borrow_mut() = None);
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(Some("INFO"), json.lookup("level").and_then(Value::as_str));
+        assert_eq!(Some("sccache::server"), json.lookup("target").and_then(Value::as_str));
+        assert_eq!(Some("done"), json.lookup("message").and_then(Value::as_str));
+        assert_eq!(Some("hit"), json.lookup("outcome").and_then(Value::as_str));
+        let line = format_message(LogFormat::Json, LogLevel::Warn, "sccache", "plain");
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(None, json.lookup("outcome"));
+    }
+
+    #[test]
+    fn test_recent_lines() {
+        let recent = RecentLines::new(3);
+        assert!(recent.last(2).is_empty());
+        for line in &["one", "two", "three", "four"] {
+            recent.push(line.to_string());
+        }
+        assert_eq!(vec!("three", "four"), recent.last(2));
+        assert_eq!(vec!("two", "three", "four"), recent.last(10));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let f = TestFixture::new();
+        let path = f.tempdir.path().join("sccache.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["one\n", "two\n", "three\n", "four\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!("four\n", contents(&path));
+        assert_eq!("three\n", contents(&rotated(&path, 1)));
+        assert_eq!("one\ntwo\n", contents(&rotated(&path, 2)));
+        // Reopening a full log starts a new one.
+        drop(file);
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"12345678").unwrap();
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_all(b"five\n").unwrap();
+        assert_eq!("five\n", contents(&path));
+        assert_eq!("four\n12345678", contents(&rotated(&path, 1)));
+        assert_eq!("three\n", contents(&rotated(&path, 2)));
+        assert!(!rotated(&path, 3).exists());
+    }
+}
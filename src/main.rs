@@ -14,7 +14,11 @@
 
 #![cfg_attr(feature = "unstable", feature(windows_process_extensions))]
 
+#[cfg(windows)]
+extern crate advapi32;
 extern crate app_dirs;
+extern crate bincode;
+#[cfg(any(feature = "gcs", feature = "s3"))]
 extern crate chrono;
 extern crate clap;
 extern crate crypto;
@@ -28,9 +32,10 @@ extern crate filetime;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate hyper;
-extern crate hyper_tls;
 #[cfg(windows)]
 extern crate kernel32;
+#[macro_use]
+extern crate lazy_static;
 extern crate local_encoding;
 #[macro_use]
 extern crate log;
@@ -39,48 +44,91 @@ extern crate fern;
 extern crate libc;
 #[cfg(windows)]
 extern crate named_pipe;
+extern crate native_tls;
 extern crate number_prefix;
-extern crate protobuf;
+#[cfg(unix)]
+extern crate openssl;
+extern crate rand;
 extern crate regex;
+#[cfg(feature = "gcs")]
+extern crate ring;
 extern crate retry;
 extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 extern crate sha1;
+#[cfg(feature = "sftp")]
+extern crate ssh2;
+extern crate tar;
 extern crate tempdir;
 extern crate time;
 extern crate tokio_core;
 extern crate tokio_process;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_tls;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(feature = "gcs")]
+extern crate untrusted;
 extern crate uuid;
 #[cfg(windows)]
 extern crate winapi;
 extern crate which;
 extern crate zip;
+extern crate zstd;
 
 // To get macros in scope, this has to be first.
 #[cfg(test)]
 #[macro_use]
 mod test;
 
+mod bazel;
 mod cache;
+mod cache_server;
 mod client;
 mod cmdline;
 mod commands;
 mod compiler;
+mod config;
+mod dist;
+mod distcc;
 mod errors;
+#[cfg(any(feature = "azure", feature = "cos", feature = "gcs", feature = "gha",
+          feature = "oss", feature = "s3", feature = "webdav"))]
+mod http;
+mod http_server;
+mod logging;
+mod metrics;
 mod mock_command;
 mod protocol;
 mod server;
+#[cfg(feature = "s3")]
 mod simples3;
+mod statsd;
+mod systemd;
+mod tls;
+#[cfg(windows)]
+mod windows_service;
 
+use fern::IntoLog;
 use std::env;
 use std::io::Write;
 
 fn main() {
+    if let Err(e) = config::load() {
+        writeln!(std::io::stderr(), "sccache: {}", e).unwrap();
+        std::process::exit(2);
+    }
     init_logging();
     std::process::exit(match cmdline::parse() {
         Ok(cmd) => {
+            let is_compile = match cmd {
+                cmdline::Command::Compile { .. } => true,
+                _ => false,
+            };
             match commands::run_command(cmd) {
                 Ok(s) => s,
                 Err(e) =>  {
@@ -90,7 +138,13 @@ fn main() {
                     for e in e.iter().skip(1) {
                         writeln!(stderr, "caused by: {}", e).unwrap();
                     }
-                    2
+                    // Build systems can tell a compilation the server failed
+                    // from one the compiler failed by its exit status.
+                    if is_compile && commands::is_server_unusable(&e) {
+                        commands::error_exit_code()
+                    } else {
+                        2
+                    }
                 }
             }
         }
@@ -103,34 +157,83 @@ fn main() {
     });
 }
 
+/// Parse a `SCCACHE_LOG_LEVEL` value.
+fn parse_log_level(log_level: &str) -> log::LogLevelFilter {
+    match logging::parse_level(log_level) {
+        Some(level) => level,
+        None => panic!("Invalid log level {}", log_level),
+    }
+}
+
+/// Whether this is the server process.
+fn is_server_process() -> bool {
+    env::var("SCCACHE_START_SERVER").ok().map_or(false, |v| v == "1")
+}
+
+/// If this is the server process and `SCCACHE_LOG_FILE` is set, log to
+/// that file, rotating it when it grows too large.
+fn init_server_log_file() -> Option<Result<(), String>> {
+    if !is_server_process() {
+        return None;
+    }
+    let path = match env::var_os("SCCACHE_LOG_FILE") {
+        Some(path) => std::path::PathBuf::from(path),
+        None => return None,
+    };
+    let level = env::var("SCCACHE_LOG_LEVEL")
+        .map(|l| parse_log_level(&l))
+        .unwrap_or(log::LogLevelFilter::Info);
+    Some(logging::RotatingFile::open(&path, logging::max_size(), logging::keep())
+        .map_err(|e| format!("couldn't open {}: {}", path.display(), e))
+        .and_then(|file| {
+            let logger = logging::FileLogger::new(file, logging::format_from_environment());
+            logging::install(Box::new(logger), level)
+                .map_err(|e| format!("{:?}", e))
+        }))
+}
+
 fn init_logging() {
-    match if env::var("RUST_LOG").is_ok() {
-        env_logger::init()
+    match if let Some(res) = init_server_log_file() {
+        res
+    } else if let Ok(filters) = env::var("RUST_LOG") {
+        let logger = env_logger::LogBuilder::new().parse(&filters).build();
+        let level = logger.filter();
+        logging::install(Box::new(logger), level)
             .map_err(|e| format!("{:?}", e))
     } else {
         match env::var("SCCACHE_LOG_LEVEL") {
             Ok(log_level) => {
-                let log_level = match &*log_level.to_lowercase() {
-                    "off" => log::LogLevelFilter::Off,
-                    "trace" => log::LogLevelFilter::Trace,
-                    "debug" => log::LogLevelFilter::Debug,
-                    "info" => log::LogLevelFilter::Info,
-                    "warn" => log::LogLevelFilter::Warn,
-                    "error" => log::LogLevelFilter::Error,
-                    _ => panic!("Invalid log level {}", log_level),
-                };
+                let log_level = parse_log_level(&log_level);
+                let format = logging::format_from_environment();
 
                 let logger_config = fern::DispatchConfig {
-                    format: Box::new(|msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
-                        format!("[{}][{}] {}", time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(), level, msg)
+                    format: Box::new(move |msg: &str, level: &log::LogLevel, location: &log::LogLocation| {
+                        match format {
+                            logging::LogFormat::Json => {
+                                logging::format_message(format, *level, location.module_path(), msg)
+                            }
+                            logging::LogFormat::Text => {
+                                format!("[{}][{}] {}", time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(), level, msg)
+                            }
+                        }
                     }),
                     //TODO: only the server process should output to the log file.
                     output: vec![fern::OutputConfig::stdout(), fern::OutputConfig::file("sccache.log")],
                     level: log::LogLevelFilter::Trace,
                 };
-                fern::init_global_logger(logger_config, log_level)
+                // Install the logger ourselves so that the server's log
+                // level can be changed while it runs.
+                logger_config.into_log()
                     .map_err(|e| format!("{:?}", e))
+                    .and_then(|logger| {
+                        logging::install(logger, log_level).map_err(|e| format!("{:?}", e))
+                    })
             },
+            // The server keeps its log in memory for `--server-log` anyway.
+            Err(_) if is_server_process() => {
+                logging::install(Box::new(logging::NullLogger), log::LogLevelFilter::Info)
+                    .map_err(|e| format!("{:?}", e))
+            }
             Err(_) => Ok(()),
         }
     } {
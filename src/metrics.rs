@@ -0,0 +1,221 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server metrics in the Prometheus text format, served over HTTP at
+//! `/metrics`.
+
+use futures::{Future, Stream};
+use futures::future::{Loop, loop_fn};
+use std::env;
+use std::fmt::Write;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::io::{read_until, write_all};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+
+/// The upper bounds of histogram buckets, in seconds.
+const BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+                                  1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Get the address to serve metrics on, from `SCCACHE_METRICS_ADDR`.
+pub fn addr_from_environment() -> Option<SocketAddr> {
+    env::var("SCCACHE_METRICS_ADDR").ok().and_then(|addr| {
+        match addr.parse() {
+            Ok(addr) => Some(addr),
+            Err(_) => {
+                warn!("Ignoring invalid SCCACHE_METRICS_ADDR {}", addr);
+                None
+            }
+        }
+    })
+}
+
+/// A histogram of durations, with fixed buckets.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    /// The number of observations no larger than each of `BUCKETS`.
+    counts: Vec<u64>,
+    /// The total of all observations, in seconds.
+    sum: f64,
+    /// The number of observations.
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            counts: vec![0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    /// Record an observation of `duration`.
+    pub fn record(&mut self, duration: Duration) {
+        let secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+/// Format a label for a sample, such as `backend="s3"`.
+pub fn label(name: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("{}=\"{}\"", name, value)
+}
+
+/// Metrics being written in the Prometheus text format.
+pub struct Metrics {
+    out: String,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { out: String::new() }
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: &str) {
+        if labels.is_empty() {
+            writeln!(self.out, "{} {}", name, value).unwrap();
+        } else {
+            writeln!(self.out, "{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+
+    /// Add a counter, with a value for each set of labels.
+    pub fn counter(&mut self, name: &str, help: &str, values: &[(String, u64)]) {
+        self.header(name, "counter", help);
+        for &(ref labels, value) in values {
+            self.sample(name, labels, &value.to_string());
+        }
+    }
+
+    /// Add a gauge, with a value for each set of labels.
+    pub fn gauge(&mut self, name: &str, help: &str, values: &[(String, u64)]) {
+        self.header(name, "gauge", help);
+        for &(ref labels, value) in values {
+            self.sample(name, labels, &value.to_string());
+        }
+    }
+
+    /// Add a histogram, with a value for each set of labels.
+    pub fn histogram(&mut self, name: &str, help: &str, values: &[(String, &Histogram)]) {
+        self.header(name, "histogram", help);
+        for &(ref labels, histogram) in values {
+            let with = |extra: String| {
+                if labels.is_empty() { extra } else { format!("{},{}", labels, extra) }
+            };
+            for (bound, count) in BUCKETS.iter().zip(histogram.counts.iter()) {
+                self.sample(&format!("{}_bucket", name), &with(label("le", &bound.to_string())),
+                            &count.to_string());
+            }
+            self.sample(&format!("{}_bucket", name), &with(label("le", "+Inf")),
+                        &histogram.count.to_string());
+            self.sample(&format!("{}_sum", name), labels, &histogram.sum.to_string());
+            self.sample(&format!("{}_count", name), labels, &histogram.count.to_string());
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        self.out
+    }
+}
+
+/// Serve the metrics produced by `render` at `/metrics` on `addr`.
+pub fn serve(addr: &SocketAddr, handle: &Handle, render: Rc<Fn() -> String>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr, handle)?;
+    info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    let conn_handle = handle.clone();
+    handle.spawn(listener.incoming().for_each(move |(socket, _addr)| {
+        conn_handle.spawn(respond(socket, render.clone()).map_err(|e| {
+            debug!("Metrics request failed: {}", e);
+        }));
+        Ok(())
+    }).map_err(|e| {
+        warn!("Metrics server failed: {}", e);
+    }));
+    Ok(())
+}
+
+/// Answer a single HTTP request on `socket`.
+fn respond(socket: TcpStream, render: Rc<Fn() -> String>) -> Box<Future<Item=(), Error=io::Error>> {
+    // Read the request line and headers, keeping the path.
+    let request = loop_fn((BufReader::new(socket), None), |(reader, path): (_, Option<String>)| {
+        read_until(reader, b'\n', vec!()).map(|(reader, line)| {
+            let line = String::from_utf8_lossy(&line).trim().to_owned();
+            if line.is_empty() {
+                Loop::Break((reader, path))
+            } else if path.is_none() {
+                let path = line.split_whitespace().nth(1).unwrap_or("").to_owned();
+                Loop::Continue((reader, Some(path)))
+            } else {
+                Loop::Continue((reader, path))
+            }
+        })
+    });
+    Box::new(request.and_then(move |(reader, path)| {
+        let (status, body) = match path.as_ref().map(|p| &p[..]) {
+            Some("/metrics") => ("200 OK", render()),
+            _ => ("404 Not Found", "Not found\n".to_owned()),
+        };
+        let response = format!("HTTP/1.1 {}\r\n\
+                                Content-Type: text/plain; version=0.0.4\r\n\
+                                Content-Length: {}\r\n\
+                                Connection: close\r\n\
+                                \r\n\
+                                {}", status, body.len(), body);
+        write_all(reader.into_inner(), response.into_bytes()).map(|_| ())
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metrics() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_millis(20));
+        histogram.record(Duration::from_secs(2));
+        let mut metrics = Metrics::new();
+        metrics.counter("sccache_cache_hits_total", "Cache hits.", &[(String::new(), 3)]);
+        metrics.histogram("sccache_backend_read_duration_seconds", "Reads.",
+                          &[(label("backend", "s3"), &histogram)]);
+        let out = metrics.into_string();
+        assert!(out.starts_with("# HELP sccache_cache_hits_total Cache hits.\n\
+                                 # TYPE sccache_cache_hits_total counter\n\
+                                 sccache_cache_hits_total 3\n"));
+        assert!(out.contains("sccache_backend_read_duration_seconds_bucket{backend=\"s3\",le=\"0.01\"} 0\n"));
+        assert!(out.contains("sccache_backend_read_duration_seconds_bucket{backend=\"s3\",le=\"0.025\"} 1\n"));
+        assert!(out.contains("sccache_backend_read_duration_seconds_bucket{backend=\"s3\",le=\"2.5\"} 2\n"));
+        assert!(out.contains("sccache_backend_read_duration_seconds_bucket{backend=\"s3\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("sccache_backend_read_duration_seconds_count{backend=\"s3\"} 2\n"));
+    }
+}
@@ -45,10 +45,12 @@
 //! then create an `Arc<Mutex<MockCommandCreator>>` and safely provide
 //! `MockChild` outputs.
 
+use cache::parse_duration;
 #[cfg(unix)]
 use libc;
 use futures::future::{self, Future};
 use std::boxed::Box;
+use std::env;
 use std::ffi::OsStr;
 use std::fmt;
 use std::io::{
@@ -56,6 +58,8 @@ use std::io::{
     Read,
     Write,
 };
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process::{
     Command,
@@ -64,6 +68,7 @@ use std::process::{
     Stdio,
 };
 use std::sync::{Arc,Mutex};
+use std::time::Duration;
 use tokio_process::{
     Child,
     ChildStderr,
@@ -71,7 +76,7 @@ use tokio_process::{
     ChildStdout,
     CommandExt,
 };
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 
 /// A trait that provides a subset of the methods of `std::process::Child`.
 pub trait CommandChild {
@@ -105,6 +110,10 @@ pub trait RunCommand: fmt::Debug {
     fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> &mut Self;
     /// Set the working directory of the process to `dir`.
     fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self;
+    /// Set the environment variable `key` of the process to `val`.
+    fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self;
+    /// Remove the environment variable `key` from the process' environment.
+    fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self;
     /// Create the proces without a visible console on Windows.
     fn no_console(&mut self) -> &mut Self;
     /// Set the process' stdin from `cfg`.
@@ -161,23 +170,111 @@ impl CommandChild for Child {
     }
 }
 
+/// Get how long compiler processes may run before they're killed, from
+/// `SCCACHE_COMPILER_TIMEOUT`.
+fn process_timeout() -> Option<Duration> {
+    env::var("SCCACHE_COMPILER_TIMEOUT").ok().and_then(|s| parse_duration(&s))
+}
+
+/// Kill the process `pid` and the processes it started.
+#[cfg(unix)]
+fn kill_tree(pid: u32) {
+    // The process leads its own process group, see `AsyncCommand::spawn`.
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(windows)]
+fn kill_tree(pid: u32) {
+    let res = Command::new("taskkill")
+        .args(&["/F", "/T", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if let Err(e) = res {
+        warn!("Failed to run taskkill on process {}: {}", pid, e);
+    }
+}
+
+/// A child process that is killed, along with the processes it started, if
+/// it runs for longer than a timeout.
+pub struct ProcessChild {
+    inner: Child,
+    handle: Handle,
+    timeout: Option<Duration>,
+}
+
+impl ProcessChild {
+    /// Wait for `future`, which finishes when the child exits, for at most
+    /// the timeout, failing with `io::ErrorKind::TimedOut` after it.
+    fn watch<F>(future: F, pid: u32, handle: &Handle, timeout: Option<Duration>)
+                -> Box<Future<Item = F::Item, Error = io::Error>>
+        where F: Future<Error = io::Error> + 'static,
+              F::Item: 'static,
+    {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Box::new(future),
+        };
+        let timer = match Timeout::new(timeout, handle) {
+            Ok(timer) => timer.map(|()| None),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(future.map(Some).select(timer).map_err(|(e, _)| e).and_then(move |(res, _rest)| {
+            match res {
+                Some(res) => Ok(res),
+                None => {
+                    warn!("Process {} still running after {}s, killing it", pid, timeout.as_secs());
+                    kill_tree(pid);
+                    Err(io::Error::new(io::ErrorKind::TimedOut,
+                                       format!("process {} timed out", pid)))
+                }
+            }
+        }))
+    }
+}
+
+impl CommandChild for ProcessChild {
+    type I = ChildStdin;
+    type O = ChildStdout;
+    type E = ChildStderr;
+
+    fn take_stdin(&mut self) -> Option<ChildStdin> { self.inner.stdin().take() }
+    fn take_stdout(&mut self) -> Option<ChildStdout> { self.inner.stdout().take() }
+    fn take_stderr(&mut self) -> Option<ChildStderr> { self.inner.stderr().take() }
+
+    fn wait(self) -> Box<Future<Item = ExitStatus, Error = io::Error>> {
+        let pid = self.inner.id();
+        ProcessChild::watch(self.inner, pid, &self.handle, self.timeout)
+    }
+
+    fn wait_with_output(self) -> Box<Future<Item = Output, Error = io::Error>> {
+        let pid = self.inner.id();
+        ProcessChild::watch(self.inner.wait_with_output(), pid, &self.handle, self.timeout)
+    }
+}
+
 pub struct AsyncCommand {
     inner: Command,
     handle: Handle,
+    timeout: Option<Duration>,
 }
 
 impl AsyncCommand {
-    pub fn new<S: AsRef<OsStr>>(program: S, handle: Handle) -> AsyncCommand {
+    /// Create a command whose process is killed if it runs for longer than
+    /// `timeout`.
+    pub fn new<S: AsRef<OsStr>>(program: S, handle: Handle, timeout: Option<Duration>)
+                                -> AsyncCommand {
         AsyncCommand {
             inner: Command::new(program),
             handle: handle,
+            timeout: timeout,
         }
     }
 }
 
-/// Trivial implementation of `RunCommand` for `std::process::Command`.
+/// Implementation of `RunCommand` for `std::process::Command`.
 impl RunCommand for AsyncCommand {
-    type C = Child;
+    type C = ProcessChild;
 
     fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut AsyncCommand {
         self.inner.arg(arg);
@@ -191,6 +288,14 @@ impl RunCommand for AsyncCommand {
         self.inner.current_dir(dir);
         self
     }
+    fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut AsyncCommand {
+        self.inner.env(key, val);
+        self
+    }
+    fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut AsyncCommand {
+        self.inner.env_remove(key);
+        self
+    }
 
     #[cfg(all(windows, feature = "unstable"))]
     fn no_console(&mut self) -> &mut AsyncCommand {
@@ -217,8 +322,25 @@ impl RunCommand for AsyncCommand {
         self.inner.stderr(cfg);
         self
     }
-    fn spawn(&mut self) -> io::Result<Child> {
-        self.inner.spawn_async(&self.handle)
+    fn spawn(&mut self) -> io::Result<ProcessChild> {
+        // Put the process in its own process group, so that the processes
+        // it starts can be killed along with it.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            if self.timeout.is_some() {
+                self.inner.before_exec(|| {
+                    unsafe { libc::setpgid(0, 0) };
+                    Ok(())
+                });
+            }
+        }
+        let child = self.inner.spawn_async(&self.handle)?;
+        Ok(ProcessChild {
+            inner: child,
+            handle: self.handle.clone(),
+            timeout: self.timeout,
+        })
     }
 }
 
@@ -232,6 +354,8 @@ impl fmt::Debug for AsyncCommand {
 #[derive(Clone)]
 pub struct ProcessCommandCreator {
     handle: Handle,
+    /// How long processes may run before they're killed.
+    timeout: Option<Duration>,
 }
 
 /// Trivial implementation of `CommandCreator` for `ProcessCommandCreator`.
@@ -241,11 +365,12 @@ impl CommandCreator for ProcessCommandCreator {
     fn new(handle: &Handle) -> ProcessCommandCreator {
         ProcessCommandCreator {
             handle: handle.clone(),
+            timeout: process_timeout(),
         }
     }
 
     fn new_command<S: AsRef<OsStr>>(&mut self, program: S) -> AsyncCommand {
-        AsyncCommand::new(program, self.handle.clone())
+        AsyncCommand::new(program, self.handle.clone(), self.timeout)
     }
 }
 
@@ -280,6 +405,31 @@ pub fn exit_status(v : ExitStatusValue) -> ExitStatus {
     unsafe { transmute(InnerExitStatus(v)) }
 }
 
+/// The status of a process that exited with `code`.
+#[cfg(unix)]
+pub fn exit_status_from_code(code: i32) -> ExitStatus {
+    // Wait statuses hold the exit code in their second byte.
+    exit_status((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+pub fn exit_status_from_code(code: i32) -> ExitStatus {
+    exit_status(code as u32)
+}
+
+/// Return the signal that terminated a process from its exit `status`, if
+/// one did.
+#[cfg(unix)]
+pub fn status_signal(status : ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+/// Processes aren't terminated by signals on non-Unix.
+#[cfg(not(unix))]
+pub fn status_signal(_status : ExitStatus) -> Option<i32> {
+    None
+}
+
 /// A struct that mocks `std::process::Child`.
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -383,6 +533,12 @@ impl RunCommand for MockCommand {
         //TODO: assert value of dir
         self
     }
+    fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, _key: K, _val: V) -> &mut MockCommand {
+        self
+    }
+    fn env_remove<K: AsRef<OsStr>>(&mut self, _key: K) -> &mut MockCommand {
+        self
+    }
     fn no_console(&mut self) -> &mut MockCommand {
         self
     }
@@ -1,3379 +1,503 @@
-// This file is generated. Do not edit
-// @generated
-
-// https://github.com/Manishearth/rust-clippy/issues/702
-#![allow(unknown_lints)]
-#![allow(clippy)]
-
-#![cfg_attr(rustfmt, rustfmt_skip)]
-
-#![allow(dead_code)]
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(non_upper_case_globals)]
-#![allow(unused_imports)]
-
-use protobuf::Message as Message_imported_for_functions;
-use protobuf::ProtobufEnum as ProtobufEnum_imported_for_functions;
-
-#[derive(Clone,Default)]
-pub struct GetStats {
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for GetStats {}
-
-impl GetStats {
-    pub fn new() -> GetStats {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static GetStats {
-        static mut instance: ::protobuf::lazy::Lazy<GetStats> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const GetStats,
-        };
-        unsafe {
-            instance.get(|| {
-                GetStats {
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-}
-
-impl ::protobuf::Message for GetStats {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<GetStats>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for GetStats {
-    fn new() -> GetStats {
-        GetStats::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<GetStats>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let fields = ::std::vec::Vec::new();
-                ::protobuf::reflect::MessageDescriptor::new::<GetStats>(
-                    "GetStats",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for GetStats {
-    fn clear(&mut self) {
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for GetStats {
-    fn eq(&self, other: &GetStats) -> bool {
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for GetStats {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct Shutdown {
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for Shutdown {}
-
-impl Shutdown {
-    pub fn new() -> Shutdown {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static Shutdown {
-        static mut instance: ::protobuf::lazy::Lazy<Shutdown> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const Shutdown,
-        };
-        unsafe {
-            instance.get(|| {
-                Shutdown {
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-}
-
-impl ::protobuf::Message for Shutdown {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<Shutdown>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for Shutdown {
-    fn new() -> Shutdown {
-        Shutdown::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<Shutdown>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let fields = ::std::vec::Vec::new();
-                ::protobuf::reflect::MessageDescriptor::new::<Shutdown>(
-                    "Shutdown",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for Shutdown {
-    fn clear(&mut self) {
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for Shutdown {
-    fn eq(&self, other: &Shutdown) -> bool {
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for Shutdown {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct ZeroStats {
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for ZeroStats {}
-
-impl ZeroStats {
-    pub fn new() -> ZeroStats {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static ZeroStats {
-        static mut instance: ::protobuf::lazy::Lazy<ZeroStats> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ZeroStats,
-        };
-        unsafe {
-            instance.get(|| {
-                ZeroStats {
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-}
-
-impl ::protobuf::Message for ZeroStats {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<ZeroStats>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for ZeroStats {
-    fn new() -> ZeroStats {
-        ZeroStats::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<ZeroStats>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let fields = ::std::vec::Vec::new();
-                ::protobuf::reflect::MessageDescriptor::new::<ZeroStats>(
-                    "ZeroStats",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for ZeroStats {
-    fn clear(&mut self) {
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for ZeroStats {
-    fn eq(&self, other: &ZeroStats) -> bool {
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for ZeroStats {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The messages exchanged by the sccache client and server.
+//!
+//! Each message is sent as a frame: a byte holding the `PROTOCOL_VERSION`
+//! of the sender, the length of the message as a big-endian `u32`, and then
+//! the message encoded with bincode. The frame header stays the same in
+//! every version, so that a client can tell when the server speaks another
+//! version, and `Request::Shutdown` is always encoded the same way, so that
+//! the client can then replace it.
+
+use bincode::SizeLimit;
+use bincode::serde::{deserialize, serialize_into};
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::io;
+
+/// The version of this protocol.
+///
+/// Version 1 was protobuf-based, without frame headers. Version 2 is
+/// skipped because its version byte would look like the length of most
+/// version 1 requests. Version 3 added frame headers, version 4 the
+/// priority of compilations, version 5 the reason the server failed a
+/// compilation, version 6 the activity of the server, version 7 build ids, version 8
+/// the user running the client, version 9 clearing the cache, version 10
+/// the groups of statistics, version 11 the client's environment,
+/// version 12 dropped the client's jobserver, and version 13 checks the
+/// health of each storage backend.
+pub const PROTOCOL_VERSION: u8 = 13;
+
+/// The first protocol version with frame headers.
+const FIRST_FRAMED_VERSION: u8 = 3;
+
+/// The length of a frame header.
+pub const HEADER_LEN: usize = 5;
+
+/// The longest message a frame may hold. Frames announcing longer ones are
+/// refused before any of the message is read, so that a bad length doesn't
+/// make the reader buffer gigabytes.
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024 * 1024;
+
+/// A request from a client.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    /// Shut down the server. This must stay the first variant, without
+    /// fields, for servers of every version to understand it.
+    Shutdown,
+    /// Run a compile command.
+    Compile(Compile),
+    /// Get cache statistics.
+    GetStats,
+    /// Zero cache statistics.
+    ZeroStats,
+    /// Collect garbage in the local disk cache.
+    Gc,
+    /// Get the configuration the server is running with.
+    GetServerInfo,
+    /// Change the level the server logs at to one of off, error, warn,
+    /// info, debug or trace.
+    SetLogLevel(String),
+    /// Re-read the configuration file.
+    ReloadConfig,
+    /// Check that the server and its storage work.
+    HealthCheck,
+    /// Get at most this many of the last lines the server logged.
+    GetServerLog(u32),
+    /// Get the compiler executables the server has seen.
+    GetCompilers,
+    /// Look up the cache entry a compile command would use, without
+    /// running the compiler.
+    CheckCached(Compile),
+    /// Get the compilations in progress, for `--top`.
+    GetActivity,
+    /// Get the statistics of the compilations of a build, by build id.
+    GetBuildStats(String),
+    /// Remove every entry from the cache, and from remote storage as well
+    /// if set.
+    ClearCache(bool),
+}
+
+/// A compile command to run.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Compile {
-    // message fields
-    cwd: ::protobuf::SingularField<::std::string::String>,
-    exe: ::protobuf::SingularField<::std::string::String>,
-    command: ::protobuf::RepeatedField<::std::string::String>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for Compile {}
-
-impl Compile {
-    pub fn new() -> Compile {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static Compile {
-        static mut instance: ::protobuf::lazy::Lazy<Compile> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const Compile,
-        };
-        unsafe {
-            instance.get(|| {
-                Compile {
-                    cwd: ::protobuf::SingularField::none(),
-                    exe: ::protobuf::SingularField::none(),
-                    command: ::protobuf::RepeatedField::new(),
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // required string cwd = 1;
-
-    pub fn clear_cwd(&mut self) {
-        self.cwd.clear();
-    }
-
-    pub fn has_cwd(&self) -> bool {
-        self.cwd.is_some()
-    }
-
-    // Param is passed by value, moved
-    pub fn set_cwd(&mut self, v: ::std::string::String) {
-        self.cwd = ::protobuf::SingularField::some(v);
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_cwd<'a>(&'a mut self) -> &'a mut ::std::string::String {
-        if self.cwd.is_none() {
-            self.cwd.set_default();
-        };
-        self.cwd.as_mut().unwrap()
-    }
-
-    // Take field
-    pub fn take_cwd(&mut self) -> ::std::string::String {
-        self.cwd.take().unwrap_or_else(|| ::std::string::String::new())
-    }
-
-    pub fn get_cwd<'a>(&'a self) -> &'a str {
-        match self.cwd.as_ref() {
-            Some(v) => &v,
-            None => "",
-        }
-    }
-
-    // required string exe = 2;
-
-    pub fn clear_exe(&mut self) {
-        self.exe.clear();
-    }
-
-    pub fn has_exe(&self) -> bool {
-        self.exe.is_some()
-    }
-
-    // Param is passed by value, moved
-    pub fn set_exe(&mut self, v: ::std::string::String) {
-        self.exe = ::protobuf::SingularField::some(v);
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_exe<'a>(&'a mut self) -> &'a mut ::std::string::String {
-        if self.exe.is_none() {
-            self.exe.set_default();
-        };
-        self.exe.as_mut().unwrap()
-    }
-
-    // Take field
-    pub fn take_exe(&mut self) -> ::std::string::String {
-        self.exe.take().unwrap_or_else(|| ::std::string::String::new())
-    }
-
-    pub fn get_exe<'a>(&'a self) -> &'a str {
-        match self.exe.as_ref() {
-            Some(v) => &v,
-            None => "",
-        }
-    }
-
-    // repeated string command = 3;
-
-    pub fn clear_command(&mut self) {
-        self.command.clear();
-    }
-
-    // Param is passed by value, moved
-    pub fn set_command(&mut self, v: ::protobuf::RepeatedField<::std::string::String>) {
-        self.command = v;
-    }
-
-    // Mutable pointer to the field.
-    pub fn mut_command<'a>(&'a mut self) -> &'a mut ::protobuf::RepeatedField<::std::string::String> {
-        &mut self.command
-    }
-
-    // Take field
-    pub fn take_command(&mut self) -> ::protobuf::RepeatedField<::std::string::String> {
-        ::std::mem::replace(&mut self.command, ::protobuf::RepeatedField::new())
-    }
-
-    pub fn get_command<'a>(&'a self) -> &'a [::std::string::String] {
-        &self.command
-    }
-}
-
-impl ::protobuf::Message for Compile {
-    fn is_initialized(&self) -> bool {
-        if self.cwd.is_none() {
-            return false;
-        };
-        if self.exe.is_none() {
-            return false;
-        };
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    try!(::protobuf::rt::read_singular_string_into(wire_type, is, &mut self.cwd));
-                },
-                2 => {
-                    try!(::protobuf::rt::read_singular_string_into(wire_type, is, &mut self.exe));
-                },
-                3 => {
-                    try!(::protobuf::rt::read_repeated_string_into(wire_type, is, &mut self.command));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        for value in self.cwd.iter() {
-            my_size += ::protobuf::rt::string_size(1, &value);
-        };
-        for value in self.exe.iter() {
-            my_size += ::protobuf::rt::string_size(2, &value);
-        };
-        for value in self.command.iter() {
-            my_size += ::protobuf::rt::string_size(3, &value);
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        if let Some(v) = self.cwd.as_ref() {
-            try!(os.write_string(1, &v));
-        };
-        if let Some(v) = self.exe.as_ref() {
-            try!(os.write_string(2, &v));
-        };
-        for v in self.command.iter() {
-            try!(os.write_string(3, &v));
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<Compile>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
+    /// The directory in which to run the command.
+    pub cwd: String,
+    /// The executable to run.
+    pub exe: String,
+    /// The command line arguments.
+    pub command: Vec<String>,
+    /// Whether to ignore an existing cache entry, compiling and storing
+    /// the result again.
+    pub recache: bool,
+    /// Whether to run the compiler as is, neither looking up nor storing a
+    /// cache entry.
+    pub no_cache: bool,
+    /// Whether to log the inputs to the cache key, and on a miss, which of
+    /// them changed since the output was last compiled.
+    pub explain: bool,
+    /// Whether to run ahead of other compilations waiting for a job slot.
+    pub priority: Priority,
+    /// The build the compilation is part of, from `SCCACHE_BUILD_ID`,
+    /// whose statistics the server also keeps apart.
+    pub build_id: Option<String>,
+    /// The user running the client, as the client reports it, for the
+    /// audit log.
+    pub user: String,
+    /// The client's values of the environment variables that may be part
+    /// of the cache key, which are passed to the compiler.
+    pub env: Vec<(String, String)>,
+}
+
+/// The priority of a compilation waiting for a job slot.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Priority {
+    /// A compilation someone is waiting on, such as one run by an editor,
+    /// which runs before any batch compilations.
+    Interactive,
+    /// A compilation of a build, run in the order it arrived.
+    Batch,
+}
+
+/// A response from the server.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    /// Cache statistics.
+    Stats(CacheStats),
+    /// The server is shutting down.
+    ShuttingDown(ShuttingDown),
+    /// The server started executing a compilation, and will send a
+    /// `CompileFinished` once it's done.
+    CompileStarted,
+    /// The server ran a compile command.
+    CompileFinished(CompileFinished),
+    /// The server could not handle this compile command line.
+    UnhandledCompile,
+    /// The request was unknown to the server.
+    UnknownCommand,
+    /// The server collected garbage in the local disk cache, with what
+    /// changed, unless there is no local disk cache.
+    GcFinished(Option<CacheStats>),
+    /// The configuration the server is running with.
+    ServerInfo(ServerInfo),
+    /// The server changed its log level, returning the level it logged at
+    /// before, unless its log level can't be changed.
+    LogLevelSet(Option<String>),
+    /// The server re-read its configuration file, or why it couldn't, in
+    /// which case nothing changed.
+    ConfigReloaded(Result<ConfigReloaded, String>),
+    /// The result of a health check.
+    HealthReport(HealthReport),
+    /// The last lines the server logged, oldest first.
+    ServerLog(Vec<String>),
+    /// The compiler executables the server has seen, by path.
+    Compilers(Vec<CompilerInfo>),
+    /// Whether the compile command is cached.
+    CacheChecked(CacheCheckResult),
+    /// What the server is doing.
+    Activity(Activity),
+    /// The statistics of a build, unless the server has none for it.
+    BuildStats(Option<CacheStats>),
+    /// The number of files removed from the cache, or why it couldn't be
+    /// cleared.
+    CacheCleared(Result<u64, String>),
+}
+
+/// The value of a cache statistic.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CacheStatValue {
+    /// A simple counter.
+    Count(u64),
+    /// A string value.
+    Str(String),
+    /// A size, in bytes.
+    Size(u64),
+}
+
+/// What a cache statistic is about, to show related statistics together.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StatGroup {
+    /// Statistics that aren't grouped.
+    General,
+    /// Where the cache is and how full it is.
+    Storage,
+    /// The requests the server handled.
+    Requests,
+    /// How the cache was used.
+    Cache,
+    /// Failures of compilations and of the cache.
+    Errors,
+    /// How long each step of a compilation took.
+    Timings,
+    /// The traffic of each storage backend.
+    Backends,
+}
+
+/// A single cache statistic.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheStatistic {
+    pub name: String,
+    pub value: CacheStatValue,
+    pub group: StatGroup,
 }
 
-impl ::protobuf::MessageStatic for Compile {
-    fn new() -> Compile {
-        Compile::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<Compile>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_string_accessor(
-                    "cwd",
-                    Compile::has_cwd,
-                    Compile::get_cwd,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_string_accessor(
-                    "exe",
-                    Compile::has_exe,
-                    Compile::get_exe,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_repeated_string_accessor(
-                    "command",
-                    Compile::get_command,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<Compile>(
-                    "Compile",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
+impl CacheStatistic {
+    /// A counter named `name`.
+    pub fn count<S: Into<String>>(name: S, count: u64) -> CacheStatistic {
+        CacheStatistic { name: name.into(), value: CacheStatValue::Count(count), group: StatGroup::General }
     }
-}
 
-impl ::protobuf::Clear for Compile {
-    fn clear(&mut self) {
-        self.clear_cwd();
-        self.clear_exe();
-        self.clear_command();
-        self.unknown_fields.clear();
+    /// A string value named `name`.
+    pub fn str<S: Into<String>>(name: S, value: String) -> CacheStatistic {
+        CacheStatistic { name: name.into(), value: CacheStatValue::Str(value), group: StatGroup::General }
     }
-}
 
-impl ::std::cmp::PartialEq for Compile {
-    fn eq(&self, other: &Compile) -> bool {
-        self.cwd == other.cwd &&
-        self.exe == other.exe &&
-        self.command == other.command &&
-        self.unknown_fields == other.unknown_fields
+    /// A size named `name`, in bytes.
+    pub fn size<S: Into<String>>(name: S, size: u64) -> CacheStatistic {
+        CacheStatistic { name: name.into(), value: CacheStatValue::Size(size), group: StatGroup::General }
     }
-}
 
-impl ::std::fmt::Debug for Compile {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
+    /// Put this statistic in `group`.
+    pub fn in_group(mut self, group: StatGroup) -> CacheStatistic {
+        self.group = group;
+        self
     }
 }
 
-#[derive(Clone,Default)]
-pub struct ClientRequest {
-    // message oneof groups
-    request: ::std::option::Option<ClientRequest_oneof_request>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for ClientRequest {}
-
-#[derive(Clone,PartialEq)]
-pub enum ClientRequest_oneof_request {
-    compile(Compile),
-    get_stats(GetStats),
-    shutdown(Shutdown),
-    zero_stats(ZeroStats),
-}
-
-impl ClientRequest {
-    pub fn new() -> ClientRequest {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static ClientRequest {
-        static mut instance: ::protobuf::lazy::Lazy<ClientRequest> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ClientRequest,
-        };
-        unsafe {
-            instance.get(|| {
-                ClientRequest {
-                    request: ::std::option::Option::None,
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // optional .sccache.Compile compile = 1;
-
-    pub fn clear_compile(&mut self) {
-        self.request = ::std::option::Option::None;
-    }
-
-    pub fn has_compile(&self) -> bool {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::compile(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_compile(&mut self, v: Compile) {
-        self.request = ::std::option::Option::Some(ClientRequest_oneof_request::compile(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_compile<'a>(&'a mut self) -> &'a mut Compile {
-        if let ::std::option::Option::Some(ClientRequest_oneof_request::compile(_)) = self.request {
-        } else {
-            self.request = ::std::option::Option::Some(ClientRequest_oneof_request::compile(Compile::new()));
-        }
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::compile(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_compile(&mut self) -> Compile {
-        if self.has_compile() {
-            match self.request.take() {
-                ::std::option::Option::Some(ClientRequest_oneof_request::compile(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            Compile::new()
-        }
-    }
-
-    pub fn get_compile<'a>(&'a self) -> &'a Compile {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::compile(ref v)) => v,
-            _ => Compile::default_instance(),
-        }
-    }
-
-    // optional .sccache.GetStats get_stats = 2;
-
-    pub fn clear_get_stats(&mut self) {
-        self.request = ::std::option::Option::None;
-    }
-
-    pub fn has_get_stats(&self) -> bool {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_get_stats(&mut self, v: GetStats) {
-        self.request = ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_get_stats<'a>(&'a mut self) -> &'a mut GetStats {
-        if let ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(_)) = self.request {
-        } else {
-            self.request = ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(GetStats::new()));
-        }
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_get_stats(&mut self) -> GetStats {
-        if self.has_get_stats() {
-            match self.request.take() {
-                ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            GetStats::new()
-        }
-    }
-
-    pub fn get_get_stats<'a>(&'a self) -> &'a GetStats {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(ref v)) => v,
-            _ => GetStats::default_instance(),
-        }
-    }
-
-    // optional .sccache.Shutdown shutdown = 3;
+/// A list of cache statistics.
+pub type CacheStats = Vec<CacheStatistic>;
 
-    pub fn clear_shutdown(&mut self) {
-        self.request = ::std::option::Option::None;
-    }
-
-    pub fn has_shutdown(&self) -> bool {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_shutdown(&mut self, v: Shutdown) {
-        self.request = ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_shutdown<'a>(&'a mut self) -> &'a mut Shutdown {
-        if let ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(_)) = self.request {
-        } else {
-            self.request = ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(Shutdown::new()));
-        }
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_shutdown(&mut self) -> Shutdown {
-        if self.has_shutdown() {
-            match self.request.take() {
-                ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            Shutdown::new()
-        }
-    }
-
-    pub fn get_shutdown<'a>(&'a self) -> &'a Shutdown {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(ref v)) => v,
-            _ => Shutdown::default_instance(),
-        }
-    }
-
-    // optional .sccache.ZeroStats zero_stats = 4;
-
-    pub fn clear_zero_stats(&mut self) {
-        self.request = ::std::option::Option::None;
-    }
-
-    pub fn has_zero_stats(&self) -> bool {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_zero_stats(&mut self, v: ZeroStats) {
-        self.request = ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_zero_stats<'a>(&'a mut self) -> &'a mut ZeroStats {
-        if let ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(_)) = self.request {
-        } else {
-            self.request = ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(ZeroStats::new()));
-        }
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_zero_stats(&mut self) -> ZeroStats {
-        if self.has_zero_stats() {
-            match self.request.take() {
-                ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            ZeroStats::new()
-        }
-    }
-
-    pub fn get_zero_stats<'a>(&'a self) -> &'a ZeroStats {
-        match self.request {
-            ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(ref v)) => v,
-            _ => ZeroStats::default_instance(),
-        }
-    }
-}
-
-impl ::protobuf::Message for ClientRequest {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.request = ::std::option::Option::Some(ClientRequest_oneof_request::compile(try!(is.read_message())));
-                },
-                2 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.request = ::std::option::Option::Some(ClientRequest_oneof_request::get_stats(try!(is.read_message())));
-                },
-                3 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.request = ::std::option::Option::Some(ClientRequest_oneof_request::shutdown(try!(is.read_message())));
-                },
-                4 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.request = ::std::option::Option::Some(ClientRequest_oneof_request::zero_stats(try!(is.read_message())));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        if let ::std::option::Option::Some(ref v) = self.request {
-            match v {
-                &ClientRequest_oneof_request::compile(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ClientRequest_oneof_request::get_stats(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ClientRequest_oneof_request::shutdown(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ClientRequest_oneof_request::zero_stats(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-            };
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        if let ::std::option::Option::Some(ref v) = self.request {
-            match v {
-                &ClientRequest_oneof_request::compile(ref v) => {
-                    try!(os.write_tag(1, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ClientRequest_oneof_request::get_stats(ref v) => {
-                    try!(os.write_tag(2, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ClientRequest_oneof_request::shutdown(ref v) => {
-                    try!(os.write_tag(3, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ClientRequest_oneof_request::zero_stats(ref v) => {
-                    try!(os.write_tag(4, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-            };
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<ClientRequest>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for ClientRequest {
-    fn new() -> ClientRequest {
-        ClientRequest::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<ClientRequest>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "compile",
-                    ClientRequest::has_compile,
-                    ClientRequest::get_compile,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "get_stats",
-                    ClientRequest::has_get_stats,
-                    ClientRequest::get_get_stats,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "shutdown",
-                    ClientRequest::has_shutdown,
-                    ClientRequest::get_shutdown,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "zero_stats",
-                    ClientRequest::has_zero_stats,
-                    ClientRequest::get_zero_stats,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<ClientRequest>(
-                    "ClientRequest",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for ClientRequest {
-    fn clear(&mut self) {
-        self.clear_compile();
-        self.clear_get_stats();
-        self.clear_shutdown();
-        self.clear_zero_stats();
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for ClientRequest {
-    fn eq(&self, other: &ClientRequest) -> bool {
-        self.request == other.request &&
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for ClientRequest {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct CacheStatistic {
-    // message fields
-    name: ::protobuf::SingularField<::std::string::String>,
-    // message oneof groups
-    value: ::std::option::Option<CacheStatistic_oneof_value>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for CacheStatistic {}
-
-#[derive(Clone,PartialEq)]
-pub enum CacheStatistic_oneof_value {
-    count(u64),
-    str(::std::string::String),
-    size(u64),
-}
-
-impl CacheStatistic {
-    pub fn new() -> CacheStatistic {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static CacheStatistic {
-        static mut instance: ::protobuf::lazy::Lazy<CacheStatistic> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const CacheStatistic,
-        };
-        unsafe {
-            instance.get(|| {
-                CacheStatistic {
-                    name: ::protobuf::SingularField::none(),
-                    value: ::std::option::Option::None,
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // required string name = 1;
-
-    pub fn clear_name(&mut self) {
-        self.name.clear();
-    }
-
-    pub fn has_name(&self) -> bool {
-        self.name.is_some()
-    }
-
-    // Param is passed by value, moved
-    pub fn set_name(&mut self, v: ::std::string::String) {
-        self.name = ::protobuf::SingularField::some(v);
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_name<'a>(&'a mut self) -> &'a mut ::std::string::String {
-        if self.name.is_none() {
-            self.name.set_default();
-        };
-        self.name.as_mut().unwrap()
-    }
-
-    // Take field
-    pub fn take_name(&mut self) -> ::std::string::String {
-        self.name.take().unwrap_or_else(|| ::std::string::String::new())
-    }
-
-    pub fn get_name<'a>(&'a self) -> &'a str {
-        match self.name.as_ref() {
-            Some(v) => &v,
-            None => "",
-        }
-    }
-
-    // optional uint64 count = 2;
-
-    pub fn clear_count(&mut self) {
-        self.value = ::std::option::Option::None;
-    }
-
-    pub fn has_count(&self) -> bool {
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::count(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_count(&mut self, v: u64) {
-        self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::count(v))
-    }
-
-    pub fn get_count<'a>(&self) -> u64 {
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::count(v)) => v,
-            _ => 0,
-        }
-    }
-
-    // optional string str = 3;
-
-    pub fn clear_str(&mut self) {
-        self.value = ::std::option::Option::None;
-    }
-
-    pub fn has_str(&self) -> bool {
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::str(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_str(&mut self, v: ::std::string::String) {
-        self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::str(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_str<'a>(&'a mut self) -> &'a mut ::std::string::String {
-        if let ::std::option::Option::Some(CacheStatistic_oneof_value::str(_)) = self.value {
-        } else {
-            self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::str(::std::string::String::new()));
-        }
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::str(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_str(&mut self) -> ::std::string::String {
-        if self.has_str() {
-            match self.value.take() {
-                ::std::option::Option::Some(CacheStatistic_oneof_value::str(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            ::std::string::String::new()
-        }
-    }
-
-    pub fn get_str<'a>(&'a self) -> &'a str {
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::str(ref v)) => v,
-            _ => "",
-        }
-    }
-
-    // optional uint64 size = 4;
-
-    pub fn clear_size(&mut self) {
-        self.value = ::std::option::Option::None;
-    }
-
-    pub fn has_size(&self) -> bool {
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::size(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_size(&mut self, v: u64) {
-        self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::size(v))
-    }
-
-    pub fn get_size<'a>(&self) -> u64 {
-        match self.value {
-            ::std::option::Option::Some(CacheStatistic_oneof_value::size(v)) => v,
-            _ => 0,
-        }
-    }
-}
-
-impl ::protobuf::Message for CacheStatistic {
-    fn is_initialized(&self) -> bool {
-        if self.name.is_none() {
-            return false;
-        };
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    try!(::protobuf::rt::read_singular_string_into(wire_type, is, &mut self.name));
-                },
-                2 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::count(try!(is.read_uint64())));
-                },
-                3 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::str(try!(is.read_string())));
-                },
-                4 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.value = ::std::option::Option::Some(CacheStatistic_oneof_value::size(try!(is.read_uint64())));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        for value in self.name.iter() {
-            my_size += ::protobuf::rt::string_size(1, &value);
-        };
-        if let ::std::option::Option::Some(ref v) = self.value {
-            match v {
-                &CacheStatistic_oneof_value::count(v) => {
-                    my_size += ::protobuf::rt::value_size(2, v, ::protobuf::wire_format::WireTypeVarint);
-                },
-                &CacheStatistic_oneof_value::str(ref v) => {
-                    my_size += ::protobuf::rt::string_size(3, &v);
-                },
-                &CacheStatistic_oneof_value::size(v) => {
-                    my_size += ::protobuf::rt::value_size(4, v, ::protobuf::wire_format::WireTypeVarint);
-                },
-            };
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        if let Some(v) = self.name.as_ref() {
-            try!(os.write_string(1, &v));
-        };
-        if let ::std::option::Option::Some(ref v) = self.value {
-            match v {
-                &CacheStatistic_oneof_value::count(v) => {
-                    try!(os.write_uint64(2, v));
-                },
-                &CacheStatistic_oneof_value::str(ref v) => {
-                    try!(os.write_string(3, v));
-                },
-                &CacheStatistic_oneof_value::size(v) => {
-                    try!(os.write_uint64(4, v));
-                },
-            };
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<CacheStatistic>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for CacheStatistic {
-    fn new() -> CacheStatistic {
-        CacheStatistic::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<CacheStatistic>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_string_accessor(
-                    "name",
-                    CacheStatistic::has_name,
-                    CacheStatistic::get_name,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_u64_accessor(
-                    "count",
-                    CacheStatistic::has_count,
-                    CacheStatistic::get_count,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_string_accessor(
-                    "str",
-                    CacheStatistic::has_str,
-                    CacheStatistic::get_str,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_u64_accessor(
-                    "size",
-                    CacheStatistic::has_size,
-                    CacheStatistic::get_size,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<CacheStatistic>(
-                    "CacheStatistic",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for CacheStatistic {
-    fn clear(&mut self) {
-        self.clear_name();
-        self.clear_count();
-        self.clear_str();
-        self.clear_size();
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for CacheStatistic {
-    fn eq(&self, other: &CacheStatistic) -> bool {
-        self.name == other.name &&
-        self.value == other.value &&
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for CacheStatistic {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct CacheStats {
-    // message fields
-    stats: ::protobuf::RepeatedField<CacheStatistic>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for CacheStats {}
-
-impl CacheStats {
-    pub fn new() -> CacheStats {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static CacheStats {
-        static mut instance: ::protobuf::lazy::Lazy<CacheStats> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const CacheStats,
-        };
-        unsafe {
-            instance.get(|| {
-                CacheStats {
-                    stats: ::protobuf::RepeatedField::new(),
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // repeated .sccache.CacheStatistic stats = 1;
-
-    pub fn clear_stats(&mut self) {
-        self.stats.clear();
-    }
-
-    // Param is passed by value, moved
-    pub fn set_stats(&mut self, v: ::protobuf::RepeatedField<CacheStatistic>) {
-        self.stats = v;
-    }
-
-    // Mutable pointer to the field.
-    pub fn mut_stats<'a>(&'a mut self) -> &'a mut ::protobuf::RepeatedField<CacheStatistic> {
-        &mut self.stats
-    }
-
-    // Take field
-    pub fn take_stats(&mut self) -> ::protobuf::RepeatedField<CacheStatistic> {
-        ::std::mem::replace(&mut self.stats, ::protobuf::RepeatedField::new())
-    }
-
-    pub fn get_stats<'a>(&'a self) -> &'a [CacheStatistic] {
-        &self.stats
-    }
-}
-
-impl ::protobuf::Message for CacheStats {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    try!(::protobuf::rt::read_repeated_message_into(wire_type, is, &mut self.stats));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        for value in self.stats.iter() {
-            let len = value.compute_size();
-            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        for v in self.stats.iter() {
-            try!(os.write_tag(1, ::protobuf::wire_format::WireTypeLengthDelimited));
-            try!(os.write_raw_varint32(v.get_cached_size()));
-            try!(v.write_to_with_cached_sizes(os));
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<CacheStats>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for CacheStats {
-    fn new() -> CacheStats {
-        CacheStats::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<CacheStats>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_repeated_message_accessor(
-                    "stats",
-                    CacheStats::get_stats,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<CacheStats>(
-                    "CacheStats",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for CacheStats {
-    fn clear(&mut self) {
-        self.clear_stats();
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for CacheStats {
-    fn eq(&self, other: &CacheStats) -> bool {
-        self.stats == other.stats &&
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for CacheStats {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
+/// The server is shutting down.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ShuttingDown {
-    // message fields
-    stats: ::protobuf::SingularPtrField<CacheStats>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for ShuttingDown {}
-
-impl ShuttingDown {
-    pub fn new() -> ShuttingDown {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static ShuttingDown {
-        static mut instance: ::protobuf::lazy::Lazy<ShuttingDown> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ShuttingDown,
-        };
-        unsafe {
-            instance.get(|| {
-                ShuttingDown {
-                    stats: ::protobuf::SingularPtrField::none(),
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // required .sccache.CacheStats stats = 1;
-
-    pub fn clear_stats(&mut self) {
-        self.stats.clear();
-    }
-
-    pub fn has_stats(&self) -> bool {
-        self.stats.is_some()
-    }
-
-    // Param is passed by value, moved
-    pub fn set_stats(&mut self, v: CacheStats) {
-        self.stats = ::protobuf::SingularPtrField::some(v);
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_stats<'a>(&'a mut self) -> &'a mut CacheStats {
-        if self.stats.is_none() {
-            self.stats.set_default();
-        };
-        self.stats.as_mut().unwrap()
-    }
-
-    // Take field
-    pub fn take_stats(&mut self) -> CacheStats {
-        self.stats.take().unwrap_or_else(|| CacheStats::new())
-    }
-
-    pub fn get_stats<'a>(&'a self) -> &'a CacheStats {
-        self.stats.as_ref().unwrap_or_else(|| CacheStats::default_instance())
-    }
-}
-
-impl ::protobuf::Message for ShuttingDown {
-    fn is_initialized(&self) -> bool {
-        if self.stats.is_none() {
-            return false;
-        };
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    try!(::protobuf::rt::read_singular_message_into(wire_type, is, &mut self.stats));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        for value in self.stats.iter() {
-            let len = value.compute_size();
-            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        if let Some(v) = self.stats.as_ref() {
-            try!(os.write_tag(1, ::protobuf::wire_format::WireTypeLengthDelimited));
-            try!(os.write_raw_varint32(v.get_cached_size()));
-            try!(v.write_to_with_cached_sizes(os));
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<ShuttingDown>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for ShuttingDown {
-    fn new() -> ShuttingDown {
-        ShuttingDown::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<ShuttingDown>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "stats",
-                    ShuttingDown::has_stats,
-                    ShuttingDown::get_stats,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<ShuttingDown>(
-                    "ShuttingDown",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for ShuttingDown {
-    fn clear(&mut self) {
-        self.clear_stats();
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for ShuttingDown {
-    fn eq(&self, other: &ShuttingDown) -> bool {
-        self.stats == other.stats &&
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for ShuttingDown {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct CompileStarted {
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for CompileStarted {}
-
-impl CompileStarted {
-    pub fn new() -> CompileStarted {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static CompileStarted {
-        static mut instance: ::protobuf::lazy::Lazy<CompileStarted> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const CompileStarted,
-        };
-        unsafe {
-            instance.get(|| {
-                CompileStarted {
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-}
-
-impl ::protobuf::Message for CompileStarted {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<CompileStarted>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for CompileStarted {
-    fn new() -> CompileStarted {
-        CompileStarted::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<CompileStarted>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let fields = ::std::vec::Vec::new();
-                ::protobuf::reflect::MessageDescriptor::new::<CompileStarted>(
-                    "CompileStarted",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for CompileStarted {
-    fn clear(&mut self) {
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for CompileStarted {
-    fn eq(&self, other: &CompileStarted) -> bool {
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for CompileStarted {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
+    pub stats: CacheStats,
+    /// Compilations and cache writes the server stopped waiting for.
+    pub unfinished_compiles: u32,
+    pub unfinished_writes: u32,
+}
+
+/// The configuration the server is running with, so that clients only talk
+/// to servers configured like them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The address the server is listening on.
+    pub bind_address: String,
+    /// The port the server is listening on.
+    pub port: u16,
+    /// The user running the server.
+    pub user: String,
+    /// The sccache version of the server.
+    pub version: String,
+    /// The name of the server instance, if it isn't the default one.
+    pub instance: Option<String>,
+    /// The id of the server process.
+    pub pid: u32,
+    /// Compilations and cache writes in progress.
+    pub active_compiles: u32,
+    pub pending_writes: u32,
+}
+
+/// The server re-read its configuration file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigReloaded {
+    /// The settings that changed and took effect.
+    pub changed: Vec<String>,
+    /// The settings that changed but only take effect once the server is
+    /// restarted.
+    pub needs_restart: Vec<String>,
+    /// Where the server now caches.
+    pub cache_location: String,
+}
+
+/// The result of a health check.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub server_info: ServerInfo,
+    pub cache_location: String,
+    /// The results for each storage backend.
+    pub backends: Vec<BackendHealth>,
+}
+
+/// The result of checking one storage backend.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackendHealth {
+    /// The name of the backend, such as `S3Cache`.
+    pub name: String,
+    /// Whether storing the test entry was skipped because the backend is
+    /// read-only.
+    pub read_only: bool,
+    /// Why storing the test entry failed, if it did.
+    pub write_error: Option<String>,
+    /// Why reading the test entry back failed, if it did.
+    pub read_error: Option<String>,
+}
+
+/// A compiler executable the server has seen.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompilerInfo {
+    /// The path to the executable.
+    pub executable: String,
+    /// The kind of compiler, such as gcc, clang or msvc, unless it couldn't
+    /// be detected, in which case compilations with it aren't cached.
+    pub kind: Option<String>,
+    /// The version of the compiler, if it could be detected.
+    pub version: Option<String>,
+    /// The sha-1 digest of the executable, as a hex string, if it was
+    /// detected.
+    pub digest: Option<String>,
+}
+
+/// Whether a compile command is cached, and under which key.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum CacheCheckResult {
+    /// The compiler isn't supported, or the command isn't a compilation.
+    Unhandled,
+    /// The command can't be cached, because of this argument or kind of
+    /// argument.
+    NotCacheable(String),
+    /// The preprocessor failed, with this stderr, so there is no key.
+    PreprocessorFailed(Vec<u8>),
+    /// The key is in the cache.
+    Hit(String),
+    /// The key is not in the cache.
+    Miss(String),
+    /// The cache couldn't be reached to look up the key.
+    Unavailable(String),
+    /// The key couldn't be computed, for this reason.
+    Failed(String),
+}
+
+/// What the server is doing.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Activity {
+    /// The compilations in progress, oldest first.
+    pub compiles: Vec<ActiveCompile>,
+    /// How many of them are waiting for a job slot.
+    pub queued_compiles: u32,
+    /// Cache writes in progress.
+    pub pending_writes: u32,
+    /// The cache hits and misses counted so far, from which clients work
+    /// out the recent hit rate.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+/// A compilation in progress.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ActiveCompile {
+    /// The compiler executable.
+    pub executable: String,
+    /// The source file being compiled.
+    pub input: String,
+    /// How long ago the server received the compilation, in milliseconds.
+    pub elapsed_ms: u64,
+    pub phase: CompilePhase,
+}
+
+/// What a compilation in progress is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CompilePhase {
+    /// Waiting for a job slot of the server, see `SCCACHE_MAX_JOBS`.
+    Queued,
+    /// Looking the compilation up in the cache, or running the compiler.
+    Running,
+}
+
+/// The result of running a compile command.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct CompileFinished {
-    // message fields
-    stdout: ::protobuf::SingularField<::std::vec::Vec<u8>>,
-    stderr: ::protobuf::SingularField<::std::vec::Vec<u8>>,
-    // message oneof groups
-    exit_status: ::std::option::Option<CompileFinished_oneof_exit_status>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for CompileFinished {}
-
-#[derive(Clone,PartialEq)]
-pub enum CompileFinished_oneof_exit_status {
-    retcode(i32),
-    signal(i32),
-}
-
-impl CompileFinished {
-    pub fn new() -> CompileFinished {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static CompileFinished {
-        static mut instance: ::protobuf::lazy::Lazy<CompileFinished> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const CompileFinished,
-        };
-        unsafe {
-            instance.get(|| {
-                CompileFinished {
-                    stdout: ::protobuf::SingularField::none(),
-                    stderr: ::protobuf::SingularField::none(),
-                    exit_status: ::std::option::Option::None,
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // optional int32 retcode = 1;
-
-    pub fn clear_retcode(&mut self) {
-        self.exit_status = ::std::option::Option::None;
-    }
-
-    pub fn has_retcode(&self) -> bool {
-        match self.exit_status {
-            ::std::option::Option::Some(CompileFinished_oneof_exit_status::retcode(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_retcode(&mut self, v: i32) {
-        self.exit_status = ::std::option::Option::Some(CompileFinished_oneof_exit_status::retcode(v))
-    }
-
-    pub fn get_retcode<'a>(&self) -> i32 {
-        match self.exit_status {
-            ::std::option::Option::Some(CompileFinished_oneof_exit_status::retcode(v)) => v,
-            _ => 0,
-        }
-    }
-
-    // optional int32 signal = 2;
-
-    pub fn clear_signal(&mut self) {
-        self.exit_status = ::std::option::Option::None;
-    }
-
-    pub fn has_signal(&self) -> bool {
-        match self.exit_status {
-            ::std::option::Option::Some(CompileFinished_oneof_exit_status::signal(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_signal(&mut self, v: i32) {
-        self.exit_status = ::std::option::Option::Some(CompileFinished_oneof_exit_status::signal(v))
-    }
-
-    pub fn get_signal<'a>(&self) -> i32 {
-        match self.exit_status {
-            ::std::option::Option::Some(CompileFinished_oneof_exit_status::signal(v)) => v,
-            _ => 0,
-        }
-    }
-
-    // optional bytes stdout = 3;
-
-    pub fn clear_stdout(&mut self) {
-        self.stdout.clear();
-    }
-
-    pub fn has_stdout(&self) -> bool {
-        self.stdout.is_some()
-    }
-
-    // Param is passed by value, moved
-    pub fn set_stdout(&mut self, v: ::std::vec::Vec<u8>) {
-        self.stdout = ::protobuf::SingularField::some(v);
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_stdout<'a>(&'a mut self) -> &'a mut ::std::vec::Vec<u8> {
-        if self.stdout.is_none() {
-            self.stdout.set_default();
-        };
-        self.stdout.as_mut().unwrap()
-    }
-
-    // Take field
-    pub fn take_stdout(&mut self) -> ::std::vec::Vec<u8> {
-        self.stdout.take().unwrap_or_else(|| ::std::vec::Vec::new())
-    }
-
-    pub fn get_stdout<'a>(&'a self) -> &'a [u8] {
-        match self.stdout.as_ref() {
-            Some(v) => &v,
-            None => &[],
-        }
-    }
-
-    // optional bytes stderr = 4;
-
-    pub fn clear_stderr(&mut self) {
-        self.stderr.clear();
-    }
-
-    pub fn has_stderr(&self) -> bool {
-        self.stderr.is_some()
-    }
-
-    // Param is passed by value, moved
-    pub fn set_stderr(&mut self, v: ::std::vec::Vec<u8>) {
-        self.stderr = ::protobuf::SingularField::some(v);
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_stderr<'a>(&'a mut self) -> &'a mut ::std::vec::Vec<u8> {
-        if self.stderr.is_none() {
-            self.stderr.set_default();
-        };
-        self.stderr.as_mut().unwrap()
-    }
-
-    // Take field
-    pub fn take_stderr(&mut self) -> ::std::vec::Vec<u8> {
-        self.stderr.take().unwrap_or_else(|| ::std::vec::Vec::new())
-    }
-
-    pub fn get_stderr<'a>(&'a self) -> &'a [u8] {
-        match self.stderr.as_ref() {
-            Some(v) => &v,
-            None => &[],
-        }
-    }
-}
-
-impl ::protobuf::Message for CompileFinished {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.exit_status = ::std::option::Option::Some(CompileFinished_oneof_exit_status::retcode(try!(is.read_int32())));
-                },
-                2 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.exit_status = ::std::option::Option::Some(CompileFinished_oneof_exit_status::signal(try!(is.read_int32())));
-                },
-                3 => {
-                    try!(::protobuf::rt::read_singular_bytes_into(wire_type, is, &mut self.stdout));
-                },
-                4 => {
-                    try!(::protobuf::rt::read_singular_bytes_into(wire_type, is, &mut self.stderr));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        for value in self.stdout.iter() {
-            my_size += ::protobuf::rt::bytes_size(3, &value);
-        };
-        for value in self.stderr.iter() {
-            my_size += ::protobuf::rt::bytes_size(4, &value);
-        };
-        if let ::std::option::Option::Some(ref v) = self.exit_status {
-            match v {
-                &CompileFinished_oneof_exit_status::retcode(v) => {
-                    my_size += ::protobuf::rt::value_size(1, v, ::protobuf::wire_format::WireTypeVarint);
-                },
-                &CompileFinished_oneof_exit_status::signal(v) => {
-                    my_size += ::protobuf::rt::value_size(2, v, ::protobuf::wire_format::WireTypeVarint);
-                },
-            };
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        if let Some(v) = self.stdout.as_ref() {
-            try!(os.write_bytes(3, &v));
-        };
-        if let Some(v) = self.stderr.as_ref() {
-            try!(os.write_bytes(4, &v));
-        };
-        if let ::std::option::Option::Some(ref v) = self.exit_status {
-            match v {
-                &CompileFinished_oneof_exit_status::retcode(v) => {
-                    try!(os.write_int32(1, v));
-                },
-                &CompileFinished_oneof_exit_status::signal(v) => {
-                    try!(os.write_int32(2, v));
-                },
-            };
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<CompileFinished>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for CompileFinished {
-    fn new() -> CompileFinished {
-        CompileFinished::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<CompileFinished>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_i32_accessor(
-                    "retcode",
-                    CompileFinished::has_retcode,
-                    CompileFinished::get_retcode,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_i32_accessor(
-                    "signal",
-                    CompileFinished::has_signal,
-                    CompileFinished::get_signal,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_bytes_accessor(
-                    "stdout",
-                    CompileFinished::has_stdout,
-                    CompileFinished::get_stdout,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_bytes_accessor(
-                    "stderr",
-                    CompileFinished::has_stderr,
-                    CompileFinished::get_stderr,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<CompileFinished>(
-                    "CompileFinished",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for CompileFinished {
-    fn clear(&mut self) {
-        self.clear_retcode();
-        self.clear_signal();
-        self.clear_stdout();
-        self.clear_stderr();
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for CompileFinished {
-    fn eq(&self, other: &CompileFinished) -> bool {
-        self.stdout == other.stdout &&
-        self.stderr == other.stderr &&
-        self.exit_status == other.exit_status &&
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for CompileFinished {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct UnhandledCompile {
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for UnhandledCompile {}
-
-impl UnhandledCompile {
-    pub fn new() -> UnhandledCompile {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static UnhandledCompile {
-        static mut instance: ::protobuf::lazy::Lazy<UnhandledCompile> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const UnhandledCompile,
-        };
-        unsafe {
-            instance.get(|| {
-                UnhandledCompile {
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-}
-
-impl ::protobuf::Message for UnhandledCompile {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<UnhandledCompile>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for UnhandledCompile {
-    fn new() -> UnhandledCompile {
-        UnhandledCompile::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<UnhandledCompile>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let fields = ::std::vec::Vec::new();
-                ::protobuf::reflect::MessageDescriptor::new::<UnhandledCompile>(
-                    "UnhandledCompile",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for UnhandledCompile {
-    fn clear(&mut self) {
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for UnhandledCompile {
-    fn eq(&self, other: &UnhandledCompile) -> bool {
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for UnhandledCompile {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct UnknownCommand {
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for UnknownCommand {}
-
-impl UnknownCommand {
-    pub fn new() -> UnknownCommand {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static UnknownCommand {
-        static mut instance: ::protobuf::lazy::Lazy<UnknownCommand> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const UnknownCommand,
-        };
-        unsafe {
-            instance.get(|| {
-                UnknownCommand {
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-}
-
-impl ::protobuf::Message for UnknownCommand {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<UnknownCommand>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for UnknownCommand {
-    fn new() -> UnknownCommand {
-        UnknownCommand::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<UnknownCommand>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let fields = ::std::vec::Vec::new();
-                ::protobuf::reflect::MessageDescriptor::new::<UnknownCommand>(
-                    "UnknownCommand",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for UnknownCommand {
-    fn clear(&mut self) {
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for UnknownCommand {
-    fn eq(&self, other: &UnknownCommand) -> bool {
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for UnknownCommand {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-#[derive(Clone,Default)]
-pub struct ServerResponse {
-    // message oneof groups
-    response: ::std::option::Option<ServerResponse_oneof_response>,
-    // special fields
-    unknown_fields: ::protobuf::UnknownFields,
-    cached_size: ::std::cell::Cell<u32>,
-}
-
-// see codegen.rs for the explanation why impl Sync explicitly
-unsafe impl ::std::marker::Sync for ServerResponse {}
-
-#[derive(Clone,PartialEq)]
-pub enum ServerResponse_oneof_response {
-    stats(CacheStats),
-    shutting_down(ShuttingDown),
-    compile_started(CompileStarted),
-    compile_finished(CompileFinished),
-    unhandled_compile(UnhandledCompile),
-    unknown(UnknownCommand),
-}
-
-impl ServerResponse {
-    pub fn new() -> ServerResponse {
-        ::std::default::Default::default()
-    }
-
-    pub fn default_instance() -> &'static ServerResponse {
-        static mut instance: ::protobuf::lazy::Lazy<ServerResponse> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ServerResponse,
-        };
-        unsafe {
-            instance.get(|| {
-                ServerResponse {
-                    response: ::std::option::Option::None,
-                    unknown_fields: ::protobuf::UnknownFields::new(),
-                    cached_size: ::std::cell::Cell::new(0),
-                }
-            })
-        }
-    }
-
-    // optional .sccache.CacheStats stats = 1;
-
-    pub fn clear_stats(&mut self) {
-        self.response = ::std::option::Option::None;
-    }
-
-    pub fn has_stats(&self) -> bool {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::stats(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_stats(&mut self, v: CacheStats) {
-        self.response = ::std::option::Option::Some(ServerResponse_oneof_response::stats(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_stats<'a>(&'a mut self) -> &'a mut CacheStats {
-        if let ::std::option::Option::Some(ServerResponse_oneof_response::stats(_)) = self.response {
-        } else {
-            self.response = ::std::option::Option::Some(ServerResponse_oneof_response::stats(CacheStats::new()));
-        }
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::stats(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_stats(&mut self) -> CacheStats {
-        if self.has_stats() {
-            match self.response.take() {
-                ::std::option::Option::Some(ServerResponse_oneof_response::stats(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            CacheStats::new()
-        }
-    }
-
-    pub fn get_stats<'a>(&'a self) -> &'a CacheStats {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::stats(ref v)) => v,
-            _ => CacheStats::default_instance(),
-        }
-    }
-
-    // optional .sccache.ShuttingDown shutting_down = 2;
-
-    pub fn clear_shutting_down(&mut self) {
-        self.response = ::std::option::Option::None;
-    }
-
-    pub fn has_shutting_down(&self) -> bool {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_shutting_down(&mut self, v: ShuttingDown) {
-        self.response = ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_shutting_down<'a>(&'a mut self) -> &'a mut ShuttingDown {
-        if let ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(_)) = self.response {
-        } else {
-            self.response = ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(ShuttingDown::new()));
-        }
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_shutting_down(&mut self) -> ShuttingDown {
-        if self.has_shutting_down() {
-            match self.response.take() {
-                ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            ShuttingDown::new()
-        }
-    }
-
-    pub fn get_shutting_down<'a>(&'a self) -> &'a ShuttingDown {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(ref v)) => v,
-            _ => ShuttingDown::default_instance(),
-        }
-    }
-
-    // optional .sccache.CompileStarted compile_started = 3;
-
-    pub fn clear_compile_started(&mut self) {
-        self.response = ::std::option::Option::None;
-    }
-
-    pub fn has_compile_started(&self) -> bool {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_compile_started(&mut self, v: CompileStarted) {
-        self.response = ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_compile_started<'a>(&'a mut self) -> &'a mut CompileStarted {
-        if let ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(_)) = self.response {
-        } else {
-            self.response = ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(CompileStarted::new()));
-        }
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_compile_started(&mut self) -> CompileStarted {
-        if self.has_compile_started() {
-            match self.response.take() {
-                ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            CompileStarted::new()
-        }
-    }
-
-    pub fn get_compile_started<'a>(&'a self) -> &'a CompileStarted {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(ref v)) => v,
-            _ => CompileStarted::default_instance(),
-        }
-    }
-
-    // optional .sccache.CompileFinished compile_finished = 4;
-
-    pub fn clear_compile_finished(&mut self) {
-        self.response = ::std::option::Option::None;
-    }
-
-    pub fn has_compile_finished(&self) -> bool {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_compile_finished(&mut self, v: CompileFinished) {
-        self.response = ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_compile_finished<'a>(&'a mut self) -> &'a mut CompileFinished {
-        if let ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(_)) = self.response {
-        } else {
-            self.response = ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(CompileFinished::new()));
-        }
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_compile_finished(&mut self) -> CompileFinished {
-        if self.has_compile_finished() {
-            match self.response.take() {
-                ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            CompileFinished::new()
-        }
-    }
-
-    pub fn get_compile_finished<'a>(&'a self) -> &'a CompileFinished {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(ref v)) => v,
-            _ => CompileFinished::default_instance(),
-        }
-    }
-
-    // optional .sccache.UnhandledCompile unhandled_compile = 5;
-
-    pub fn clear_unhandled_compile(&mut self) {
-        self.response = ::std::option::Option::None;
-    }
-
-    pub fn has_unhandled_compile(&self) -> bool {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_unhandled_compile(&mut self, v: UnhandledCompile) {
-        self.response = ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_unhandled_compile<'a>(&'a mut self) -> &'a mut UnhandledCompile {
-        if let ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(_)) = self.response {
-        } else {
-            self.response = ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(UnhandledCompile::new()));
-        }
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_unhandled_compile(&mut self) -> UnhandledCompile {
-        if self.has_unhandled_compile() {
-            match self.response.take() {
-                ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            UnhandledCompile::new()
-        }
-    }
-
-    pub fn get_unhandled_compile<'a>(&'a self) -> &'a UnhandledCompile {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(ref v)) => v,
-            _ => UnhandledCompile::default_instance(),
-        }
-    }
-
-    // optional .sccache.UnknownCommand unknown = 6;
-
-    pub fn clear_unknown(&mut self) {
-        self.response = ::std::option::Option::None;
-    }
-
-    pub fn has_unknown(&self) -> bool {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::unknown(..)) => true,
-            _ => false,
-        }
-    }
-
-    // Param is passed by value, moved
-    pub fn set_unknown(&mut self, v: UnknownCommand) {
-        self.response = ::std::option::Option::Some(ServerResponse_oneof_response::unknown(v))
-    }
-
-    // Mutable pointer to the field.
-    // If field is not initialized, it is initialized with default value first.
-    pub fn mut_unknown<'a>(&'a mut self) -> &'a mut UnknownCommand {
-        if let ::std::option::Option::Some(ServerResponse_oneof_response::unknown(_)) = self.response {
-        } else {
-            self.response = ::std::option::Option::Some(ServerResponse_oneof_response::unknown(UnknownCommand::new()));
-        }
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::unknown(ref mut v)) => v,
-            _ => panic!(),
-        }
-    }
-
-    // Take field
-    pub fn take_unknown(&mut self) -> UnknownCommand {
-        if self.has_unknown() {
-            match self.response.take() {
-                ::std::option::Option::Some(ServerResponse_oneof_response::unknown(v)) => v,
-                _ => panic!(),
-            }
-        } else {
-            UnknownCommand::new()
-        }
-    }
-
-    pub fn get_unknown<'a>(&'a self) -> &'a UnknownCommand {
-        match self.response {
-            ::std::option::Option::Some(ServerResponse_oneof_response::unknown(ref v)) => v,
-            _ => UnknownCommand::default_instance(),
-        }
-    }
-}
-
-impl ::protobuf::Message for ServerResponse {
-    fn is_initialized(&self) -> bool {
-        true
-    }
-
-    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream) -> ::protobuf::ProtobufResult<()> {
-        while !try!(is.eof()) {
-            let (field_number, wire_type) = try!(is.read_tag_unpack());
-            match field_number {
-                1 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.response = ::std::option::Option::Some(ServerResponse_oneof_response::stats(try!(is.read_message())));
-                },
-                2 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.response = ::std::option::Option::Some(ServerResponse_oneof_response::shutting_down(try!(is.read_message())));
-                },
-                3 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.response = ::std::option::Option::Some(ServerResponse_oneof_response::compile_started(try!(is.read_message())));
-                },
-                4 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.response = ::std::option::Option::Some(ServerResponse_oneof_response::compile_finished(try!(is.read_message())));
-                },
-                5 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.response = ::std::option::Option::Some(ServerResponse_oneof_response::unhandled_compile(try!(is.read_message())));
-                },
-                6 => {
-                    if wire_type != ::protobuf::wire_format::WireTypeLengthDelimited {
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
-                    };
-                    self.response = ::std::option::Option::Some(ServerResponse_oneof_response::unknown(try!(is.read_message())));
-                },
-                _ => {
-                    try!(::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields()));
-                },
-            };
-        }
-        ::std::result::Result::Ok(())
-    }
-
-    // Compute sizes of nested messages
-    #[allow(unused_variables)]
-    fn compute_size(&self) -> u32 {
-        let mut my_size = 0;
-        if let ::std::option::Option::Some(ref v) = self.response {
-            match v {
-                &ServerResponse_oneof_response::stats(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ServerResponse_oneof_response::shutting_down(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ServerResponse_oneof_response::compile_started(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ServerResponse_oneof_response::compile_finished(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ServerResponse_oneof_response::unhandled_compile(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-                &ServerResponse_oneof_response::unknown(ref v) => {
-                    let len = v.compute_size();
-                    my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
-                },
-            };
-        };
-        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
-        self.cached_size.set(my_size);
-        my_size
-    }
-
-    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
-        if let ::std::option::Option::Some(ref v) = self.response {
-            match v {
-                &ServerResponse_oneof_response::stats(ref v) => {
-                    try!(os.write_tag(1, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ServerResponse_oneof_response::shutting_down(ref v) => {
-                    try!(os.write_tag(2, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ServerResponse_oneof_response::compile_started(ref v) => {
-                    try!(os.write_tag(3, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ServerResponse_oneof_response::compile_finished(ref v) => {
-                    try!(os.write_tag(4, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ServerResponse_oneof_response::unhandled_compile(ref v) => {
-                    try!(os.write_tag(5, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-                &ServerResponse_oneof_response::unknown(ref v) => {
-                    try!(os.write_tag(6, ::protobuf::wire_format::WireTypeLengthDelimited));
-                    try!(os.write_raw_varint32(v.get_cached_size()));
-                    try!(v.write_to_with_cached_sizes(os));
-                },
-            };
-        };
-        try!(os.write_unknown_fields(self.get_unknown_fields()));
-        ::std::result::Result::Ok(())
-    }
-
-    fn get_cached_size(&self) -> u32 {
-        self.cached_size.get()
-    }
-
-    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
-        &self.unknown_fields
-    }
-
-    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
-        &mut self.unknown_fields
-    }
-
-    fn type_id(&self) -> ::std::any::TypeId {
-        ::std::any::TypeId::of::<ServerResponse>()
-    }
-
-    fn as_any(&self) -> &::std::any::Any {
-        self as &::std::any::Any
-    }
-
-    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
-        ::protobuf::MessageStatic::descriptor_static(None::<Self>)
-    }
-}
-
-impl ::protobuf::MessageStatic for ServerResponse {
-    fn new() -> ServerResponse {
-        ServerResponse::new()
-    }
-
-    fn descriptor_static(_: ::std::option::Option<ServerResponse>) -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy {
-            lock: ::protobuf::lazy::ONCE_INIT,
-            ptr: 0 as *const ::protobuf::reflect::MessageDescriptor,
-        };
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "stats",
-                    ServerResponse::has_stats,
-                    ServerResponse::get_stats,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "shutting_down",
-                    ServerResponse::has_shutting_down,
-                    ServerResponse::get_shutting_down,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "compile_started",
-                    ServerResponse::has_compile_started,
-                    ServerResponse::get_compile_started,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "compile_finished",
-                    ServerResponse::has_compile_finished,
-                    ServerResponse::get_compile_finished,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "unhandled_compile",
-                    ServerResponse::has_unhandled_compile,
-                    ServerResponse::get_unhandled_compile,
-                ));
-                fields.push(::protobuf::reflect::accessor::make_singular_message_accessor(
-                    "unknown",
-                    ServerResponse::has_unknown,
-                    ServerResponse::get_unknown,
-                ));
-                ::protobuf::reflect::MessageDescriptor::new::<ServerResponse>(
-                    "ServerResponse",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
-    }
-}
-
-impl ::protobuf::Clear for ServerResponse {
-    fn clear(&mut self) {
-        self.clear_stats();
-        self.clear_shutting_down();
-        self.clear_compile_started();
-        self.clear_compile_finished();
-        self.clear_unhandled_compile();
-        self.clear_unknown();
-        self.unknown_fields.clear();
-    }
-}
-
-impl ::std::cmp::PartialEq for ServerResponse {
-    fn eq(&self, other: &ServerResponse) -> bool {
-        self.response == other.response &&
-        self.unknown_fields == other.unknown_fields
-    }
-}
-
-impl ::std::fmt::Debug for ServerResponse {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::protobuf::text_format::fmt(self, f)
-    }
-}
-
-static file_descriptor_proto_data: &'static [u8] = &[
-    0x0a, 0x0e, 0x70, 0x72, 0x6f, 0x74, 0x6f, 0x63, 0x6f, 0x6c, 0x2e, 0x70, 0x72, 0x6f, 0x74, 0x6f,
-    0x12, 0x07, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x22, 0x0a, 0x0a, 0x08, 0x47, 0x65, 0x74,
-    0x53, 0x74, 0x61, 0x74, 0x73, 0x22, 0x0a, 0x0a, 0x08, 0x53, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77,
-    0x6e, 0x22, 0x0b, 0x0a, 0x09, 0x5a, 0x65, 0x72, 0x6f, 0x53, 0x74, 0x61, 0x74, 0x73, 0x22, 0x34,
-    0x0a, 0x07, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x12, 0x0b, 0x0a, 0x03, 0x63, 0x77, 0x64,
-    0x18, 0x01, 0x20, 0x02, 0x28, 0x09, 0x12, 0x0b, 0x0a, 0x03, 0x65, 0x78, 0x65, 0x18, 0x02, 0x20,
-    0x02, 0x28, 0x09, 0x12, 0x0f, 0x0a, 0x07, 0x63, 0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x18, 0x03,
-    0x20, 0x03, 0x28, 0x09, 0x22, 0xb8, 0x01, 0x0a, 0x0d, 0x43, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x52,
-    0x65, 0x71, 0x75, 0x65, 0x73, 0x74, 0x12, 0x23, 0x0a, 0x07, 0x63, 0x6f, 0x6d, 0x70, 0x69, 0x6c,
-    0x65, 0x18, 0x01, 0x20, 0x01, 0x28, 0x0b, 0x32, 0x10, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68,
-    0x65, 0x2e, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x48, 0x00, 0x12, 0x26, 0x0a, 0x09, 0x67,
-    0x65, 0x74, 0x5f, 0x73, 0x74, 0x61, 0x74, 0x73, 0x18, 0x02, 0x20, 0x01, 0x28, 0x0b, 0x32, 0x11,
-    0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x47, 0x65, 0x74, 0x53, 0x74, 0x61, 0x74,
-    0x73, 0x48, 0x00, 0x12, 0x25, 0x0a, 0x08, 0x73, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77, 0x6e, 0x18,
-    0x03, 0x20, 0x01, 0x28, 0x0b, 0x32, 0x11, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e,
-    0x53, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77, 0x6e, 0x48, 0x00, 0x12, 0x28, 0x0a, 0x0a, 0x7a, 0x65,
-    0x72, 0x6f, 0x5f, 0x73, 0x74, 0x61, 0x74, 0x73, 0x18, 0x04, 0x20, 0x01, 0x28, 0x0b, 0x32, 0x12,
-    0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x5a, 0x65, 0x72, 0x6f, 0x53, 0x74, 0x61,
-    0x74, 0x73, 0x48, 0x00, 0x42, 0x09, 0x0a, 0x07, 0x72, 0x65, 0x71, 0x75, 0x65, 0x73, 0x74, 0x22,
-    0x57, 0x0a, 0x0e, 0x43, 0x61, 0x63, 0x68, 0x65, 0x53, 0x74, 0x61, 0x74, 0x69, 0x73, 0x74, 0x69,
-    0x63, 0x12, 0x0c, 0x0a, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x18, 0x01, 0x20, 0x02, 0x28, 0x09, 0x12,
-    0x0f, 0x0a, 0x05, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x18, 0x02, 0x20, 0x01, 0x28, 0x04, 0x48, 0x00,
-    0x12, 0x0d, 0x0a, 0x03, 0x73, 0x74, 0x72, 0x18, 0x03, 0x20, 0x01, 0x28, 0x09, 0x48, 0x00, 0x12,
-    0x0e, 0x0a, 0x04, 0x73, 0x69, 0x7a, 0x65, 0x18, 0x04, 0x20, 0x01, 0x28, 0x04, 0x48, 0x00, 0x42,
-    0x07, 0x0a, 0x05, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x22, 0x34, 0x0a, 0x0a, 0x43, 0x61, 0x63, 0x68,
-    0x65, 0x53, 0x74, 0x61, 0x74, 0x73, 0x12, 0x26, 0x0a, 0x05, 0x73, 0x74, 0x61, 0x74, 0x73, 0x18,
-    0x01, 0x20, 0x03, 0x28, 0x0b, 0x32, 0x17, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e,
-    0x43, 0x61, 0x63, 0x68, 0x65, 0x53, 0x74, 0x61, 0x74, 0x69, 0x73, 0x74, 0x69, 0x63, 0x22, 0x32,
-    0x0a, 0x0c, 0x53, 0x68, 0x75, 0x74, 0x74, 0x69, 0x6e, 0x67, 0x44, 0x6f, 0x77, 0x6e, 0x12, 0x22,
-    0x0a, 0x05, 0x73, 0x74, 0x61, 0x74, 0x73, 0x18, 0x01, 0x20, 0x02, 0x28, 0x0b, 0x32, 0x13, 0x2e,
-    0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x43, 0x61, 0x63, 0x68, 0x65, 0x53, 0x74, 0x61,
-    0x74, 0x73, 0x22, 0x10, 0x0a, 0x0e, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x53, 0x74, 0x61,
-    0x72, 0x74, 0x65, 0x64, 0x22, 0x65, 0x0a, 0x0f, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x46,
-    0x69, 0x6e, 0x69, 0x73, 0x68, 0x65, 0x64, 0x12, 0x11, 0x0a, 0x07, 0x72, 0x65, 0x74, 0x63, 0x6f,
-    0x64, 0x65, 0x18, 0x01, 0x20, 0x01, 0x28, 0x05, 0x48, 0x00, 0x12, 0x10, 0x0a, 0x06, 0x73, 0x69,
-    0x67, 0x6e, 0x61, 0x6c, 0x18, 0x02, 0x20, 0x01, 0x28, 0x05, 0x48, 0x00, 0x12, 0x0e, 0x0a, 0x06,
-    0x73, 0x74, 0x64, 0x6f, 0x75, 0x74, 0x18, 0x03, 0x20, 0x01, 0x28, 0x0c, 0x12, 0x0e, 0x0a, 0x06,
-    0x73, 0x74, 0x64, 0x65, 0x72, 0x72, 0x18, 0x04, 0x20, 0x01, 0x28, 0x0c, 0x42, 0x0d, 0x0a, 0x0b,
-    0x65, 0x78, 0x69, 0x74, 0x5f, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73, 0x22, 0x12, 0x0a, 0x10, 0x55,
-    0x6e, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x64, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x22,
-    0x10, 0x0a, 0x0e, 0x55, 0x6e, 0x6b, 0x6e, 0x6f, 0x77, 0x6e, 0x43, 0x6f, 0x6d, 0x6d, 0x61, 0x6e,
-    0x64, 0x22, 0xc0, 0x02, 0x0a, 0x0e, 0x53, 0x65, 0x72, 0x76, 0x65, 0x72, 0x52, 0x65, 0x73, 0x70,
-    0x6f, 0x6e, 0x73, 0x65, 0x12, 0x24, 0x0a, 0x05, 0x73, 0x74, 0x61, 0x74, 0x73, 0x18, 0x01, 0x20,
-    0x01, 0x28, 0x0b, 0x32, 0x13, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x43, 0x61,
-    0x63, 0x68, 0x65, 0x53, 0x74, 0x61, 0x74, 0x73, 0x48, 0x00, 0x12, 0x2e, 0x0a, 0x0d, 0x73, 0x68,
-    0x75, 0x74, 0x74, 0x69, 0x6e, 0x67, 0x5f, 0x64, 0x6f, 0x77, 0x6e, 0x18, 0x02, 0x20, 0x01, 0x28,
-    0x0b, 0x32, 0x15, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x53, 0x68, 0x75, 0x74,
-    0x74, 0x69, 0x6e, 0x67, 0x44, 0x6f, 0x77, 0x6e, 0x48, 0x00, 0x12, 0x32, 0x0a, 0x0f, 0x63, 0x6f,
-    0x6d, 0x70, 0x69, 0x6c, 0x65, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x65, 0x64, 0x18, 0x03, 0x20,
-    0x01, 0x28, 0x0b, 0x32, 0x17, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x43, 0x6f,
-    0x6d, 0x70, 0x69, 0x6c, 0x65, 0x53, 0x74, 0x61, 0x72, 0x74, 0x65, 0x64, 0x48, 0x00, 0x12, 0x34,
-    0x0a, 0x10, 0x63, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x5f, 0x66, 0x69, 0x6e, 0x69, 0x73, 0x68,
-    0x65, 0x64, 0x18, 0x04, 0x20, 0x01, 0x28, 0x0b, 0x32, 0x18, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63,
-    0x68, 0x65, 0x2e, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x46, 0x69, 0x6e, 0x69, 0x73, 0x68,
-    0x65, 0x64, 0x48, 0x00, 0x12, 0x36, 0x0a, 0x11, 0x75, 0x6e, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65,
-    0x64, 0x5f, 0x63, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x18, 0x05, 0x20, 0x01, 0x28, 0x0b, 0x32,
-    0x19, 0x2e, 0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x55, 0x6e, 0x68, 0x61, 0x6e, 0x64,
-    0x6c, 0x65, 0x64, 0x43, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x48, 0x00, 0x12, 0x2a, 0x0a, 0x07,
-    0x75, 0x6e, 0x6b, 0x6e, 0x6f, 0x77, 0x6e, 0x18, 0x06, 0x20, 0x01, 0x28, 0x0b, 0x32, 0x17, 0x2e,
-    0x73, 0x63, 0x63, 0x61, 0x63, 0x68, 0x65, 0x2e, 0x55, 0x6e, 0x6b, 0x6e, 0x6f, 0x77, 0x6e, 0x43,
-    0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x48, 0x00, 0x42, 0x0a, 0x0a, 0x08, 0x72, 0x65, 0x73, 0x70,
-    0x6f, 0x6e, 0x73, 0x65, 0x4a, 0xed, 0x13, 0x0a, 0x06, 0x12, 0x04, 0x0e, 0x00, 0x64, 0x01, 0x0a,
-    0x08, 0x0a, 0x01, 0x02, 0x12, 0x03, 0x0e, 0x08, 0x0f, 0x0a, 0x22, 0x0a, 0x02, 0x04, 0x00, 0x12,
-    0x03, 0x11, 0x00, 0x13, 0x1a, 0x17, 0x20, 0x47, 0x65, 0x74, 0x20, 0x63, 0x61, 0x63, 0x68, 0x65,
-    0x20, 0x73, 0x74, 0x61, 0x74, 0x69, 0x73, 0x74, 0x69, 0x63, 0x73, 0x2e, 0x0a, 0x0a, 0x0a, 0x0a,
-    0x03, 0x04, 0x00, 0x01, 0x12, 0x03, 0x11, 0x08, 0x10, 0x0a, 0x22, 0x0a, 0x02, 0x04, 0x01, 0x12,
-    0x03, 0x14, 0x00, 0x13, 0x1a, 0x17, 0x20, 0x53, 0x68, 0x75, 0x74, 0x20, 0x64, 0x6f, 0x77, 0x6e,
-    0x20, 0x74, 0x68, 0x65, 0x20, 0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0x2e, 0x0a, 0x0a, 0x0a, 0x0a,
-    0x03, 0x04, 0x01, 0x01, 0x12, 0x03, 0x14, 0x08, 0x10, 0x0a, 0x23, 0x0a, 0x02, 0x04, 0x02, 0x12,
-    0x03, 0x17, 0x00, 0x14, 0x1a, 0x18, 0x20, 0x5a, 0x65, 0x72, 0x6f, 0x20, 0x63, 0x61, 0x63, 0x68,
-    0x65, 0x20, 0x73, 0x74, 0x61, 0x74, 0x69, 0x73, 0x74, 0x69, 0x63, 0x73, 0x2e, 0x0a, 0x0a, 0x0a,
-    0x0a, 0x03, 0x04, 0x02, 0x01, 0x12, 0x03, 0x17, 0x08, 0x11, 0x0a, 0x24, 0x0a, 0x02, 0x04, 0x03,
-    0x12, 0x04, 0x1a, 0x00, 0x21, 0x01, 0x1a, 0x18, 0x20, 0x52, 0x75, 0x6e, 0x20, 0x61, 0x20, 0x63,
-    0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x20, 0x63, 0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x2e, 0x0a,
-    0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x03, 0x01, 0x12, 0x03, 0x1a, 0x08, 0x0f, 0x0a, 0x39, 0x0a, 0x04,
-    0x04, 0x03, 0x02, 0x00, 0x12, 0x03, 0x1c, 0x02, 0x1a, 0x1a, 0x2c, 0x20, 0x54, 0x68, 0x65, 0x20,
-    0x64, 0x69, 0x72, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x79, 0x20, 0x69, 0x6e, 0x20, 0x77, 0x68, 0x69,
-    0x63, 0x68, 0x20, 0x74, 0x6f, 0x20, 0x72, 0x75, 0x6e, 0x20, 0x74, 0x68, 0x65, 0x20, 0x63, 0x6f,
-    0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x00, 0x04,
-    0x12, 0x03, 0x1c, 0x02, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x00, 0x05, 0x12, 0x03,
-    0x1c, 0x0b, 0x11, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x00, 0x01, 0x12, 0x03, 0x1c, 0x12,
-    0x15, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x00, 0x03, 0x12, 0x03, 0x1c, 0x18, 0x19, 0x0a,
-    0x25, 0x0a, 0x04, 0x04, 0x03, 0x02, 0x01, 0x12, 0x03, 0x1e, 0x02, 0x1a, 0x1a, 0x18, 0x20, 0x54,
-    0x68, 0x65, 0x20, 0x65, 0x78, 0x65, 0x63, 0x75, 0x74, 0x61, 0x62, 0x6c, 0x65, 0x20, 0x74, 0x6f,
-    0x20, 0x72, 0x75, 0x6e, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x01, 0x04, 0x12,
-    0x03, 0x1e, 0x02, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x01, 0x05, 0x12, 0x03, 0x1e,
-    0x0b, 0x11, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x01, 0x01, 0x12, 0x03, 0x1e, 0x12, 0x15,
-    0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x01, 0x03, 0x12, 0x03, 0x1e, 0x18, 0x19, 0x0a, 0x2a,
-    0x0a, 0x04, 0x04, 0x03, 0x02, 0x02, 0x12, 0x03, 0x20, 0x02, 0x1e, 0x1a, 0x1d, 0x20, 0x54, 0x68,
-    0x65, 0x20, 0x63, 0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x20, 0x6c, 0x69, 0x6e, 0x65, 0x20, 0x61,
-    0x72, 0x67, 0x75, 0x6d, 0x65, 0x6e, 0x74, 0x73, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03,
-    0x02, 0x02, 0x04, 0x12, 0x03, 0x20, 0x02, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x02,
-    0x05, 0x12, 0x03, 0x20, 0x0b, 0x11, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x02, 0x01, 0x12,
-    0x03, 0x20, 0x12, 0x19, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x03, 0x02, 0x02, 0x03, 0x12, 0x03, 0x20,
-    0x1c, 0x1d, 0x0a, 0x0a, 0x0a, 0x02, 0x04, 0x04, 0x12, 0x04, 0x23, 0x00, 0x2b, 0x01, 0x0a, 0x0a,
-    0x0a, 0x03, 0x04, 0x04, 0x01, 0x12, 0x03, 0x23, 0x08, 0x15, 0x0a, 0x41, 0x0a, 0x04, 0x04, 0x04,
-    0x08, 0x00, 0x12, 0x04, 0x25, 0x02, 0x2a, 0x03, 0x1a, 0x33, 0x20, 0x41, 0x20, 0x63, 0x6c, 0x69,
-    0x65, 0x6e, 0x74, 0x20, 0x72, 0x65, 0x71, 0x75, 0x65, 0x73, 0x74, 0x20, 0x63, 0x61, 0x6e, 0x20,
-    0x62, 0x65, 0x20, 0x61, 0x6e, 0x79, 0x20, 0x6f, 0x6e, 0x65, 0x20, 0x6f, 0x66, 0x20, 0x74, 0x68,
-    0x65, 0x20, 0x66, 0x6f, 0x6c, 0x6c, 0x6f, 0x77, 0x69, 0x6e, 0x67, 0x3a, 0x0a, 0x0a, 0x0c, 0x0a,
-    0x05, 0x04, 0x04, 0x08, 0x00, 0x01, 0x12, 0x03, 0x25, 0x08, 0x0f, 0x0a, 0x0b, 0x0a, 0x04, 0x04,
-    0x04, 0x02, 0x00, 0x12, 0x03, 0x26, 0x04, 0x18, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x00,
-    0x06, 0x12, 0x03, 0x26, 0x04, 0x0b, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x00, 0x01, 0x12,
-    0x03, 0x26, 0x0c, 0x13, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x00, 0x03, 0x12, 0x03, 0x26,
-    0x16, 0x17, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x04, 0x02, 0x01, 0x12, 0x03, 0x27, 0x04, 0x1b, 0x0a,
-    0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x01, 0x06, 0x12, 0x03, 0x27, 0x04, 0x0c, 0x0a, 0x0c, 0x0a,
-    0x05, 0x04, 0x04, 0x02, 0x01, 0x01, 0x12, 0x03, 0x27, 0x0d, 0x16, 0x0a, 0x0c, 0x0a, 0x05, 0x04,
-    0x04, 0x02, 0x01, 0x03, 0x12, 0x03, 0x27, 0x19, 0x1a, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x04, 0x02,
-    0x02, 0x12, 0x03, 0x28, 0x04, 0x1a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x02, 0x06, 0x12,
-    0x03, 0x28, 0x04, 0x0c, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x02, 0x01, 0x12, 0x03, 0x28,
-    0x0d, 0x15, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02, 0x02, 0x03, 0x12, 0x03, 0x28, 0x18, 0x19,
-    0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x04, 0x02, 0x03, 0x12, 0x03, 0x29, 0x04, 0x1d, 0x0a, 0x0c, 0x0a,
-    0x05, 0x04, 0x04, 0x02, 0x03, 0x06, 0x12, 0x03, 0x29, 0x04, 0x0d, 0x0a, 0x0c, 0x0a, 0x05, 0x04,
-    0x04, 0x02, 0x03, 0x01, 0x12, 0x03, 0x29, 0x0e, 0x18, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x04, 0x02,
-    0x03, 0x03, 0x12, 0x03, 0x29, 0x1b, 0x1c, 0x0a, 0x27, 0x0a, 0x02, 0x04, 0x05, 0x12, 0x04, 0x2e,
-    0x00, 0x38, 0x01, 0x1a, 0x1b, 0x20, 0x41, 0x20, 0x73, 0x69, 0x6e, 0x67, 0x6c, 0x65, 0x20, 0x63,
-    0x61, 0x63, 0x68, 0x65, 0x20, 0x73, 0x74, 0x61, 0x74, 0x69, 0x73, 0x74, 0x69, 0x63, 0x2e, 0x0a,
-    0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x05, 0x01, 0x12, 0x03, 0x2e, 0x08, 0x16, 0x0a, 0x0b, 0x0a, 0x04,
-    0x04, 0x05, 0x02, 0x00, 0x12, 0x03, 0x2f, 0x02, 0x1b, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02,
-    0x00, 0x04, 0x12, 0x03, 0x2f, 0x02, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x00, 0x05,
-    0x12, 0x03, 0x2f, 0x0b, 0x11, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x00, 0x01, 0x12, 0x03,
-    0x2f, 0x12, 0x16, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x00, 0x03, 0x12, 0x03, 0x2f, 0x19,
-    0x1a, 0x0a, 0x0c, 0x0a, 0x04, 0x04, 0x05, 0x08, 0x00, 0x12, 0x04, 0x30, 0x02, 0x37, 0x03, 0x0a,
-    0x0c, 0x0a, 0x05, 0x04, 0x05, 0x08, 0x00, 0x01, 0x12, 0x03, 0x30, 0x08, 0x0d, 0x0a, 0x20, 0x0a,
-    0x04, 0x04, 0x05, 0x02, 0x01, 0x12, 0x03, 0x32, 0x04, 0x15, 0x1a, 0x13, 0x20, 0x41, 0x20, 0x73,
-    0x69, 0x6d, 0x70, 0x6c, 0x65, 0x20, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x2e, 0x0a, 0x0a,
-    0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x01, 0x05, 0x12, 0x03, 0x32, 0x04, 0x0a, 0x0a, 0x0c, 0x0a,
-    0x05, 0x04, 0x05, 0x02, 0x01, 0x01, 0x12, 0x03, 0x32, 0x0b, 0x10, 0x0a, 0x0c, 0x0a, 0x05, 0x04,
-    0x05, 0x02, 0x01, 0x03, 0x12, 0x03, 0x32, 0x13, 0x14, 0x0a, 0x1e, 0x0a, 0x04, 0x04, 0x05, 0x02,
-    0x02, 0x12, 0x03, 0x34, 0x04, 0x13, 0x1a, 0x11, 0x20, 0x41, 0x20, 0x73, 0x74, 0x72, 0x69, 0x6e,
-    0x67, 0x20, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02,
-    0x02, 0x05, 0x12, 0x03, 0x34, 0x04, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x02, 0x01,
-    0x12, 0x03, 0x34, 0x0b, 0x0e, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x02, 0x03, 0x12, 0x03,
-    0x34, 0x11, 0x12, 0x0a, 0x20, 0x0a, 0x04, 0x04, 0x05, 0x02, 0x03, 0x12, 0x03, 0x36, 0x04, 0x14,
-    0x1a, 0x13, 0x20, 0x41, 0x20, 0x73, 0x69, 0x7a, 0x65, 0x2c, 0x20, 0x69, 0x6e, 0x20, 0x62, 0x79,
-    0x74, 0x65, 0x73, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x03, 0x05, 0x12, 0x03,
-    0x36, 0x04, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x03, 0x01, 0x12, 0x03, 0x36, 0x0b,
-    0x0f, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x05, 0x02, 0x03, 0x03, 0x12, 0x03, 0x36, 0x12, 0x13, 0x0a,
-    0x29, 0x0a, 0x02, 0x04, 0x06, 0x12, 0x04, 0x3b, 0x00, 0x3d, 0x01, 0x1a, 0x1d, 0x20, 0x41, 0x20,
-    0x6c, 0x69, 0x73, 0x74, 0x20, 0x6f, 0x66, 0x20, 0x63, 0x61, 0x63, 0x68, 0x65, 0x20, 0x73, 0x74,
-    0x61, 0x74, 0x69, 0x73, 0x74, 0x69, 0x63, 0x73, 0x2e, 0x0a, 0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x06,
-    0x01, 0x12, 0x03, 0x3b, 0x08, 0x12, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x06, 0x02, 0x00, 0x12, 0x03,
-    0x3c, 0x02, 0x24, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x06, 0x02, 0x00, 0x04, 0x12, 0x03, 0x3c, 0x02,
-    0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x06, 0x02, 0x00, 0x06, 0x12, 0x03, 0x3c, 0x0b, 0x19, 0x0a,
-    0x0c, 0x0a, 0x05, 0x04, 0x06, 0x02, 0x00, 0x01, 0x12, 0x03, 0x3c, 0x1a, 0x1f, 0x0a, 0x0c, 0x0a,
-    0x05, 0x04, 0x06, 0x02, 0x00, 0x03, 0x12, 0x03, 0x3c, 0x22, 0x23, 0x0a, 0x2a, 0x0a, 0x02, 0x04,
-    0x07, 0x12, 0x04, 0x40, 0x00, 0x42, 0x01, 0x1a, 0x1e, 0x20, 0x54, 0x68, 0x65, 0x20, 0x73, 0x65,
-    0x72, 0x76, 0x65, 0x72, 0x20, 0x69, 0x73, 0x20, 0x73, 0x68, 0x75, 0x74, 0x74, 0x69, 0x6e, 0x67,
-    0x20, 0x64, 0x6f, 0x77, 0x6e, 0x2e, 0x0a, 0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x07, 0x01, 0x12, 0x03,
-    0x40, 0x08, 0x14, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x07, 0x02, 0x00, 0x12, 0x03, 0x41, 0x02, 0x20,
-    0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x07, 0x02, 0x00, 0x04, 0x12, 0x03, 0x41, 0x02, 0x0a, 0x0a, 0x0c,
-    0x0a, 0x05, 0x04, 0x07, 0x02, 0x00, 0x06, 0x12, 0x03, 0x41, 0x0b, 0x15, 0x0a, 0x0c, 0x0a, 0x05,
-    0x04, 0x07, 0x02, 0x00, 0x01, 0x12, 0x03, 0x41, 0x16, 0x1b, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x07,
-    0x02, 0x00, 0x03, 0x12, 0x03, 0x41, 0x1e, 0x1f, 0x0a, 0x38, 0x0a, 0x02, 0x04, 0x08, 0x12, 0x03,
-    0x45, 0x00, 0x19, 0x1a, 0x2d, 0x20, 0x54, 0x68, 0x65, 0x20, 0x73, 0x65, 0x72, 0x76, 0x65, 0x72,
-    0x20, 0x73, 0x74, 0x61, 0x72, 0x74, 0x65, 0x64, 0x20, 0x65, 0x78, 0x65, 0x63, 0x75, 0x74, 0x69,
-    0x6e, 0x67, 0x20, 0x61, 0x20, 0x63, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x61, 0x74, 0x69, 0x6f, 0x6e,
-    0x2e, 0x0a, 0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x08, 0x01, 0x12, 0x03, 0x45, 0x08, 0x16, 0x0a, 0x2f,
-    0x0a, 0x02, 0x04, 0x09, 0x12, 0x04, 0x48, 0x00, 0x52, 0x01, 0x1a, 0x23, 0x20, 0x54, 0x68, 0x65,
-    0x20, 0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0x20, 0x72, 0x61, 0x6e, 0x20, 0x61, 0x20, 0x63, 0x6f,
-    0x6d, 0x70, 0x69, 0x6c, 0x65, 0x20, 0x63, 0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x2e, 0x0a, 0x0a,
-    0x0a, 0x0a, 0x03, 0x04, 0x09, 0x01, 0x12, 0x03, 0x48, 0x08, 0x17, 0x0a, 0x2f, 0x0a, 0x04, 0x04,
-    0x09, 0x08, 0x00, 0x12, 0x04, 0x4a, 0x02, 0x4f, 0x03, 0x1a, 0x21, 0x20, 0x54, 0x68, 0x65, 0x20,
-    0x72, 0x65, 0x74, 0x75, 0x72, 0x6e, 0x20, 0x63, 0x6f, 0x64, 0x65, 0x20, 0x6f, 0x66, 0x20, 0x74,
-    0x68, 0x65, 0x20, 0x70, 0x72, 0x6f, 0x63, 0x65, 0x73, 0x73, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05,
-    0x04, 0x09, 0x08, 0x00, 0x01, 0x12, 0x03, 0x4a, 0x08, 0x13, 0x0a, 0x1b, 0x0a, 0x04, 0x04, 0x09,
-    0x02, 0x00, 0x12, 0x03, 0x4c, 0x04, 0x16, 0x1a, 0x0e, 0x20, 0x4e, 0x6f, 0x72, 0x6d, 0x61, 0x6c,
-    0x20, 0x65, 0x78, 0x69, 0x74, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x00, 0x05,
-    0x12, 0x03, 0x4c, 0x04, 0x09, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x00, 0x01, 0x12, 0x03,
-    0x4c, 0x0a, 0x11, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x00, 0x03, 0x12, 0x03, 0x4c, 0x14,
-    0x15, 0x0a, 0x26, 0x0a, 0x04, 0x04, 0x09, 0x02, 0x01, 0x12, 0x03, 0x4e, 0x04, 0x15, 0x1a, 0x19,
-    0x20, 0x54, 0x65, 0x72, 0x6d, 0x69, 0x6e, 0x61, 0x74, 0x65, 0x64, 0x20, 0x62, 0x79, 0x20, 0x61,
-    0x20, 0x73, 0x69, 0x67, 0x6e, 0x61, 0x6c, 0x2e, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02,
-    0x01, 0x05, 0x12, 0x03, 0x4e, 0x04, 0x09, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x01, 0x01,
-    0x12, 0x03, 0x4e, 0x0a, 0x10, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x01, 0x03, 0x12, 0x03,
-    0x4e, 0x13, 0x14, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x09, 0x02, 0x02, 0x12, 0x03, 0x50, 0x02, 0x1c,
-    0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x02, 0x04, 0x12, 0x03, 0x50, 0x02, 0x0a, 0x0a, 0x0c,
-    0x0a, 0x05, 0x04, 0x09, 0x02, 0x02, 0x05, 0x12, 0x03, 0x50, 0x0b, 0x10, 0x0a, 0x0c, 0x0a, 0x05,
-    0x04, 0x09, 0x02, 0x02, 0x01, 0x12, 0x03, 0x50, 0x11, 0x17, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09,
-    0x02, 0x02, 0x03, 0x12, 0x03, 0x50, 0x1a, 0x1b, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x09, 0x02, 0x03,
-    0x12, 0x03, 0x51, 0x02, 0x1c, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x03, 0x04, 0x12, 0x03,
-    0x51, 0x02, 0x0a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x03, 0x05, 0x12, 0x03, 0x51, 0x0b,
-    0x10, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x03, 0x01, 0x12, 0x03, 0x51, 0x11, 0x17, 0x0a,
-    0x0c, 0x0a, 0x05, 0x04, 0x09, 0x02, 0x03, 0x03, 0x12, 0x03, 0x51, 0x1a, 0x1b, 0x0a, 0x43, 0x0a,
-    0x02, 0x04, 0x0a, 0x12, 0x03, 0x55, 0x00, 0x1b, 0x1a, 0x38, 0x20, 0x54, 0x68, 0x65, 0x20, 0x73,
-    0x65, 0x72, 0x76, 0x65, 0x72, 0x20, 0x63, 0x6f, 0x75, 0x6c, 0x64, 0x20, 0x6e, 0x6f, 0x74, 0x20,
-    0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x20, 0x74, 0x68, 0x69, 0x73, 0x20, 0x63, 0x6f, 0x6d, 0x70,
-    0x69, 0x6c, 0x65, 0x20, 0x63, 0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x20, 0x6c, 0x69, 0x6e, 0x65,
-    0x2e, 0x0a, 0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x0a, 0x01, 0x12, 0x03, 0x55, 0x08, 0x18, 0x0a, 0x34,
-    0x0a, 0x02, 0x04, 0x0b, 0x12, 0x03, 0x58, 0x00, 0x19, 0x1a, 0x29, 0x20, 0x54, 0x68, 0x69, 0x73,
-    0x20, 0x63, 0x6f, 0x6d, 0x6d, 0x61, 0x6e, 0x64, 0x20, 0x77, 0x61, 0x73, 0x20, 0x75, 0x6e, 0x6b,
-    0x6e, 0x6f, 0x77, 0x6e, 0x20, 0x74, 0x6f, 0x20, 0x74, 0x68, 0x65, 0x20, 0x73, 0x65, 0x72, 0x76,
-    0x65, 0x72, 0x2e, 0x0a, 0x0a, 0x0a, 0x0a, 0x03, 0x04, 0x0b, 0x01, 0x12, 0x03, 0x58, 0x08, 0x16,
-    0x0a, 0x0a, 0x0a, 0x02, 0x04, 0x0c, 0x12, 0x04, 0x5a, 0x00, 0x64, 0x01, 0x0a, 0x0a, 0x0a, 0x03,
-    0x04, 0x0c, 0x01, 0x12, 0x03, 0x5a, 0x08, 0x16, 0x0a, 0x42, 0x0a, 0x04, 0x04, 0x0c, 0x08, 0x00,
-    0x12, 0x04, 0x5c, 0x02, 0x63, 0x03, 0x1a, 0x34, 0x20, 0x41, 0x20, 0x73, 0x65, 0x72, 0x76, 0x65,
-    0x72, 0x20, 0x72, 0x65, 0x73, 0x70, 0x6f, 0x6e, 0x73, 0x65, 0x20, 0x63, 0x61, 0x6e, 0x20, 0x62,
-    0x65, 0x20, 0x61, 0x6e, 0x79, 0x20, 0x6f, 0x6e, 0x65, 0x20, 0x6f, 0x66, 0x20, 0x74, 0x68, 0x65,
-    0x20, 0x66, 0x6f, 0x6c, 0x6c, 0x6f, 0x77, 0x69, 0x6e, 0x67, 0x3a, 0x0a, 0x0a, 0x0c, 0x0a, 0x05,
-    0x04, 0x0c, 0x08, 0x00, 0x01, 0x12, 0x03, 0x5c, 0x08, 0x10, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x0c,
-    0x02, 0x00, 0x12, 0x03, 0x5d, 0x04, 0x19, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x00, 0x06,
-    0x12, 0x03, 0x5d, 0x04, 0x0e, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x00, 0x01, 0x12, 0x03,
-    0x5d, 0x0f, 0x14, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x00, 0x03, 0x12, 0x03, 0x5d, 0x17,
-    0x18, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x0c, 0x02, 0x01, 0x12, 0x03, 0x5e, 0x04, 0x23, 0x0a, 0x0c,
-    0x0a, 0x05, 0x04, 0x0c, 0x02, 0x01, 0x06, 0x12, 0x03, 0x5e, 0x04, 0x10, 0x0a, 0x0c, 0x0a, 0x05,
-    0x04, 0x0c, 0x02, 0x01, 0x01, 0x12, 0x03, 0x5e, 0x11, 0x1e, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c,
-    0x02, 0x01, 0x03, 0x12, 0x03, 0x5e, 0x21, 0x22, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x0c, 0x02, 0x02,
-    0x12, 0x03, 0x5f, 0x04, 0x27, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x02, 0x06, 0x12, 0x03,
-    0x5f, 0x04, 0x12, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x02, 0x01, 0x12, 0x03, 0x5f, 0x13,
-    0x22, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x02, 0x03, 0x12, 0x03, 0x5f, 0x25, 0x26, 0x0a,
-    0x0b, 0x0a, 0x04, 0x04, 0x0c, 0x02, 0x03, 0x12, 0x03, 0x60, 0x04, 0x29, 0x0a, 0x0c, 0x0a, 0x05,
-    0x04, 0x0c, 0x02, 0x03, 0x06, 0x12, 0x03, 0x60, 0x04, 0x13, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c,
-    0x02, 0x03, 0x01, 0x12, 0x03, 0x60, 0x14, 0x24, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x03,
-    0x03, 0x12, 0x03, 0x60, 0x27, 0x28, 0x0a, 0x0b, 0x0a, 0x04, 0x04, 0x0c, 0x02, 0x04, 0x12, 0x03,
-    0x61, 0x04, 0x2b, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x04, 0x06, 0x12, 0x03, 0x61, 0x04,
-    0x14, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x04, 0x01, 0x12, 0x03, 0x61, 0x15, 0x26, 0x0a,
-    0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x04, 0x03, 0x12, 0x03, 0x61, 0x29, 0x2a, 0x0a, 0x0b, 0x0a,
-    0x04, 0x04, 0x0c, 0x02, 0x05, 0x12, 0x03, 0x62, 0x04, 0x1f, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c,
-    0x02, 0x05, 0x06, 0x12, 0x03, 0x62, 0x04, 0x12, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x05,
-    0x01, 0x12, 0x03, 0x62, 0x13, 0x1a, 0x0a, 0x0c, 0x0a, 0x05, 0x04, 0x0c, 0x02, 0x05, 0x03, 0x12,
-    0x03, 0x62, 0x1d, 0x1e,
-];
-
-static mut file_descriptor_proto_lazy: ::protobuf::lazy::Lazy<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::lazy::Lazy {
-    lock: ::protobuf::lazy::ONCE_INIT,
-    ptr: 0 as *const ::protobuf::descriptor::FileDescriptorProto,
-};
-
-fn parse_descriptor_proto() -> ::protobuf::descriptor::FileDescriptorProto {
-    ::protobuf::parse_from_bytes(file_descriptor_proto_data).unwrap()
-}
-
-pub fn file_descriptor_proto() -> &'static ::protobuf::descriptor::FileDescriptorProto {
-    unsafe {
-        file_descriptor_proto_lazy.get(|| {
-            parse_descriptor_proto()
-        })
+    /// The return code of the process, if it exited normally.
+    pub retcode: Option<i32>,
+    /// The signal that terminated the process, if one did.
+    pub signal: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Why the server couldn't run the compilation, if it failed rather
+    /// than the compiler. The other fields are then meaningless.
+    pub error: Option<String>,
+}
+
+/// Append `message` to `buf` as a frame.
+pub fn encode<T: Serialize>(message: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+    let start = buf.len();
+    buf.extend_from_slice(&[PROTOCOL_VERSION, 0, 0, 0, 0]);
+    serialize_into(buf, message, SizeLimit::Bounded(MAX_MESSAGE_LEN as u64))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let len = buf.len() - start - HEADER_LEN;
+    check_message_len(len)?;
+    let len = len as u32;
+    buf[start + 1..start + HEADER_LEN].copy_from_slice(&[(len >> 24) as u8,
+                                                         (len >> 16) as u8,
+                                                         (len >> 8) as u8,
+                                                         len as u8]);
+    Ok(())
+}
+
+/// Read the frame header at the start of `buf`, returning the version of
+/// the sender and the length of the message that follows.
+pub fn parse_header(buf: &[u8]) -> (u8, usize) {
+    let len = ((buf[1] as u32) << 24) | ((buf[2] as u32) << 16) | ((buf[3] as u32) << 8) | buf[4] as u32;
+    (buf[0], len as usize)
+}
+
+/// Fail if `len`, the length of a message from a frame header, is longer
+/// than `MAX_MESSAGE_LEN`.
+pub fn check_message_len(len: usize) -> io::Result<()> {
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("message of {} bytes is too large", len)));
+    }
+    Ok(())
+}
+
+/// Decode the message of a frame of this protocol version.
+pub fn decode<T: Deserialize>(message: &[u8]) -> io::Result<T> {
+    deserialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Whether `frame`, of any protocol version, is a `Request::Shutdown`,
+/// or `None` if there isn't enough of it to tell yet.
+pub fn is_shutdown_frame(frame: &[u8]) -> Option<bool> {
+    let mut shutdown = vec!();
+    encode(&Request::Shutdown, &mut shutdown).unwrap();
+    // The first byte is the version, which doesn't matter, except that
+    // version 1 messages have no frame header.
+    if frame.is_empty() {
+        return None;
+    }
+    if frame[0] < FIRST_FRAMED_VERSION {
+        return Some(false);
+    }
+    let n = cmp::min(frame.len(), shutdown.len());
+    if frame[1..n] != shutdown[1..n] {
+        Some(false)
+    } else if n < shutdown.len() {
+        None
+    } else {
+        Some(true)
+    }
+}
+
+/// A shutdown request as sent by protobuf-based version 1 clients, for
+/// stopping servers of that version.
+pub const LEGACY_SHUTDOWN: &'static [u8] = &[0x02, 0x1a, 0x00];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = vec!();
+        let request = Request::Compile(Compile {
+            cwd: "/tmp".to_owned(),
+            exe: "cc".to_owned(),
+            command: vec!("-c".to_owned(), "foo.c".to_owned()),
+            recache: false,
+            no_cache: false,
+            explain: false,
+            priority: Priority::Interactive,
+            build_id: Some("ci-42".to_owned()),
+            user: "build".to_owned(),
+            env: vec!(("LANG".to_owned(), "C".to_owned())),
+        });
+        encode(&request, &mut buf).unwrap();
+        let (version, len) = parse_header(&buf);
+        assert_eq!(PROTOCOL_VERSION, version);
+        assert_eq!(buf.len() - HEADER_LEN, len);
+        assert_eq!(request, decode(&buf[HEADER_LEN..]).unwrap());
+    }
+
+    #[test]
+    fn test_shutdown_frame() {
+        // Servers of other versions rely on this encoding.
+        let mut buf = vec!();
+        encode(&Request::Shutdown, &mut buf).unwrap();
+        assert_eq!(vec!(PROTOCOL_VERSION, 0, 0, 0, 4, 0, 0, 0, 0), buf);
+        assert_eq!(Some(true), is_shutdown_frame(&[PROTOCOL_VERSION + 1, 0, 0, 0, 4, 0, 0, 0, 0]));
+        assert_eq!(None, is_shutdown_frame(&[PROTOCOL_VERSION + 1, 0, 0]));
+        assert_eq!(Some(false), is_shutdown_frame(LEGACY_SHUTDOWN));
+        let mut buf = vec!();
+        encode(&Request::GetStats, &mut buf).unwrap();
+        assert_eq!(Some(false), is_shutdown_frame(&buf));
+    }
+
+    #[test]
+    fn test_message_len() {
+        let (_, len) = parse_header(&[PROTOCOL_VERSION, 0xff, 0xff, 0xff, 0xff]);
+        assert!(check_message_len(len).is_err());
+        assert!(check_message_len(MAX_MESSAGE_LEN).is_ok());
     }
 }
@@ -13,173 +13,614 @@
 // limitations under the License.
 
 use cache::{
+    Cache,
     Storage,
+    disk_cache_dir,
+    disk_index_from_environment,
+    parse_duration,
     storage_from_environment,
 };
+use cache::audit::{AuditLog, AuditedStorage};
+use cache::metered::{BackendStats, Latencies};
+use cache::readonly::ReadOnlyStorage;
 use compiler::{
+    CacheCheck,
     CacheControl,
+    CacheWriteInfo,
     Compiler,
     CompilerArguments,
     CompileResult,
+    CompilerFilter,
+    CompilerPolicies,
+    InFlight,
+    MissExplainer,
     MissType,
     ParsedArguments,
+    PhaseTimings,
+    check_temp_root,
     get_compiler_info,
 };
+use config;
+use dist::client::Client as DistClient;
 use filetime::FileTime;
-use futures::future;
-use futures::sync::mpsc;
+use futures::future::{self, Loop, loop_fn};
+use futures::sync::{mpsc, oneshot};
 use futures::task::{self, Task};
 use futures::{Stream, Sink, Async, AsyncSink, Poll, StartSend, Future};
 use futures_cpupool::CpuPool;
+use libc;
+use log::LogLevel;
+use logging;
+use metrics::{self, Histogram, Metrics, label};
 use mock_command::{
     CommandCreatorSync,
     ProcessCommandCreator,
-};
-use protobuf::{
-    self,
-    ProtobufError,
-    RepeatedField,
-    parse_length_delimited_from_bytes,
+    status_signal,
 };
 use protocol::{
-    ClientRequest,
+    self,
+    ActiveCompile,
+    Activity,
+    BackendHealth,
+    CacheCheckResult,
     CacheStats,
     CacheStatistic,
     Compile,
     CompileFinished,
-    CompileStarted,
-    ServerResponse,
+    CompilePhase,
+    CompilerInfo,
+    ConfigReloaded,
+    HealthReport,
+    HEADER_LEN,
+    PROTOCOL_VERSION,
+    Priority,
+    Request,
+    Response,
+    ServerInfo,
     ShuttingDown,
-    UnhandledCompile,
-    UnknownCommand,
+    StatGroup,
 };
-use std::collections::HashMap;
-use std::cell::RefCell;
+use serde_json::{self, Value};
+use sha1;
+use statsd::{Metric, Statsd};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::cell::{Cell, RefCell};
 use std::env;
 use std::ffi::OsString;
-use std::fs::metadata;
-use std::io::{self, Write};
-use std::marker;
+use std::fmt;
+use std::fs::{self, File, metadata};
+use std::io::{self, Read, Write};
 use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
-use std::process::Output;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::{self, Output};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio_core::reactor::{Handle, Core, Timeout};
+use std::time::{Duration, Instant};
+use systemd;
+use time;
+use tokio_core::reactor::{Handle, Core, Interval, Timeout};
 use tokio_core::io::{Codec, EasyBuf, Io, Framed};
 use tokio_core::net::TcpListener;
 use tokio_proto::BindServer;
 use tokio_proto::streaming::pipeline::{Frame, ServerProto, Transport};
 use tokio_proto::streaming::{Body, Message};
 use tokio_service::Service;
+#[cfg(unix)]
+use tokio_uds::UnixListener;
 
 use errors::*;
 
 /// If the server is idle for this many milliseconds, shut down.
 const DEFAULT_IDLE_TIMEOUT: u64 = 600_000;
 
-fn notify_server_startup_internal<W: Write>(mut w: W, success: bool) -> io::Result<()> {
-    let data = [ if success { 0 } else { 1 }; 1];
-    try!(w.write_all(&data));
-    Ok(())
+/// How often statistics are saved, in seconds.
+const STATS_SAVE_INTERVAL: u64 = 60;
+
+/// How long to wait for compilations and cache writes to finish when
+/// shutting down, in seconds.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// The name of the object in the entry stored by a health check.
+const HEALTH_CHECK_OBJECT: &'static str = "health";
+
+/// How many cache writes may be in progress at once before new ones are dropped.
+const DEFAULT_MAX_PENDING_WRITES: usize = 16;
+
+/// Tell the client starting the server whether it started: a zero byte if
+/// it did, or a non-zero byte followed by what went wrong.
+fn notify_server_startup_internal<W: Write>(mut w: W, error: Option<&str>) -> io::Result<()> {
+    match error {
+        None => w.write_all(&[0]),
+        Some(error) => {
+            try!(w.write_all(&[1]));
+            w.write_all(error.as_bytes())
+        }
+    }
 }
 
 #[cfg(unix)]
-fn notify_server_startup(name: &Option<OsString>, success: bool) -> io::Result<()> {
+fn notify_server_startup(name: &Option<OsString>, error: Option<&str>) -> io::Result<()> {
     use std::os::unix::net::UnixStream;
     let name = match *name {
         Some(ref s) => s,
         None => return Ok(()),
     };
-    debug!("notify_server_startup(success: {})", success);
+    debug!("notify_server_startup(error: {:?})", error);
     let stream = try!(UnixStream::connect(name));
-    notify_server_startup_internal(stream, success)
+    notify_server_startup_internal(stream, error)
 }
 
 #[cfg(windows)]
-fn notify_server_startup(name: &Option<OsString>, success: bool) -> io::Result<()> {
+fn notify_server_startup(name: &Option<OsString>, error: Option<&str>) -> io::Result<()> {
     use named_pipe::PipeClient;
     let name = match *name {
         Some(ref s) => s,
         None => return Ok(()),
     };
     let pipe = try!(PipeClient::connect(name));
-    notify_server_startup_internal(pipe, success)
+    notify_server_startup_internal(pipe, error)
+}
+
+/// The user a server that any user may use reports, in place of the user
+/// running it.
+pub const SHARED_USER: &'static str = "*";
+
+/// The user running this process, which clients check the server was started by.
+pub fn current_user() -> String {
+    env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default()
+}
+
+/// Settings that are set for the server process by the client starting it,
+/// rather than being part of the configuration.
+const SERVER_PROCESS_VARS: &'static [&'static str] = &[
+    "SCCACHE_START_SERVER",
+    "SCCACHE_STARTUP_NOTIFY",
+];
+
+/// Settings only the client reads, for its own compilations, which don't
+/// call for a server of their own.
+const CLIENT_VARS: &'static [&'static str] = &[
+    "SCCACHE_BUILD_ID",
+    "SCCACHE_DEBUG_WHY_MISS",
+    "SCCACHE_ERROR_EXIT_CODE",
+    "SCCACHE_NO_CACHE",
+    "SCCACHE_NO_DAEMON",
+    "SCCACHE_NO_FALLBACK",
+    "SCCACHE_PRIORITY",
+    "SCCACHE_RECACHE",
+];
+
+/// Get the name of the server instance to use, from `SCCACHE_SERVER_ID`,
+/// so that several servers can run side by side. `auto` names the instance
+/// after the configuration, giving each configuration its own server.
+///
+/// Characters other than letters, digits, `-` and `_` are replaced with `_`.
+pub fn server_id() -> Option<String> {
+    let id = match env::var("SCCACHE_SERVER_ID") {
+        Ok(ref id) if id == "auto" => config_id(env::vars()),
+        Ok(id) => id,
+        Err(_) => return None,
+    };
+    if id.is_empty() {
+        return None;
+    }
+    Some(id.chars()
+         .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+         .collect())
+}
+
+/// A name for the configuration in `vars`: a hash of the `SCCACHE_*`
+/// settings, other than those selecting the server and those only the
+/// client reads.
+fn config_id<I: Iterator<Item=(String, String)>>(vars: I) -> String {
+    let mut settings = vars.filter(|&(ref key, _)| {
+        key.starts_with("SCCACHE_") && !key.starts_with("SCCACHE_SERVER_") &&
+            !SERVER_PROCESS_VARS.contains(&&key[..]) && !CLIENT_VARS.contains(&&key[..]) &&
+            key != config::FILE_SETTINGS_VAR
+    }).collect::<Vec<_>>();
+    settings.sort();
+    let mut m = sha1::Sha1::new();
+    for (key, value) in settings {
+        m.update(key.as_bytes());
+        m.update(b"=");
+        m.update(value.as_bytes());
+        m.update(b"\n");
+    }
+    m.digest().to_string()[..12].to_owned()
+}
+
+/// Whether the setting `key` is only read when the server starts, so that
+/// changing it in a configuration reload has no effect.
+fn needs_restart(key: &str) -> bool {
+    key.starts_with("SCCACHE_SERVER_") || key.starts_with("SCCACHE_LOG_") ||
+        ["SCCACHE_COMPILER_TIMEOUT", "SCCACHE_ERROR_LOG", "SCCACHE_METRICS_ADDR"].contains(&key)
+}
+
+/// The port a server for instance `id` listens on by default, one of the
+/// thousand ports following `default_port`. Should another instance have
+/// taken it, the client tries the ports after it.
+pub fn instance_port(id: &str, default_port: u16) -> u16 {
+    let mut m = sha1::Sha1::new();
+    m.update(id.as_bytes());
+    let n = u16::from_str_radix(&m.digest().to_string()[..4], 16).unwrap();
+    default_port + 1 + n % 1000
+}
+
+/// An address an sccache server listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerAddr {
+    /// A TCP socket.
+    Tcp(SocketAddr),
+    /// A unix domain socket at the given path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ServerAddr {
+    /// The bind address and port a server listening on this address
+    /// advertises to its clients.
+    pub fn info(&self) -> (String, u16) {
+        match *self {
+            ServerAddr::Tcp(ref addr) => (addr.ip().to_string(), addr.port()),
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => (path.display().to_string(), 0),
+        }
+    }
+
+    /// Clean up after a server that listened on this address.
+    fn remove(&self) {
+        match *self {
+            ServerAddr::Tcp(..) => {}
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A socket the server accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Listen on the unix domain socket at `path`, accessible only to the
+/// current user.
+#[cfg(unix)]
+fn bind_unix(path: &Path, handle: &Handle) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    // Remove a socket left behind by a server that didn't shut down cleanly.
+    if path.exists() && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path, handle)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
 }
 
-/// Start an sccache server, listening on `port`.
+/// Start an sccache server, listening on `addr`.
 ///
 /// Spins an event loop handling client connections until a client
 /// requests a shutdown.
-pub fn start_server(port: u16) -> Result<()> {
+pub fn start_server(addr: &ServerAddr) -> Result<()> {
+    run_server(addr, false, future::empty::<(), ()>(), || {})
+}
+
+/// Run the server on `addr` as a system service that any user may use,
+/// until `stop` completes, calling `running` once it accepts connections.
+/// Unlike a server started by a client, it doesn't shut down when idle.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn run_service<F, R>(addr: &ServerAddr, stop: F, running: R) -> Result<()>
+    where F: Future,
+          R: FnOnce(),
+{
+    run_server(addr, true, stop, running)
+}
+
+/// The file a server writes its process id to, next to its statistics, so
+/// that `--stop-server --force` can kill it even if it doesn't answer.
+pub fn pid_file() -> PathBuf {
+    let name = match server_id() {
+        Some(id) => format!(".server-{}.pid", id),
+        None => ".server.pid".to_owned(),
+    };
+    disk_cache_dir().join(name)
+}
+
+fn write_pid_file(path: &Path) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    write!(f, "{}", unsafe { libc::getpid() })
+}
+
+fn run_server<F, R>(addr: &ServerAddr, service: bool, shutdown: F, running: R) -> Result<()>
+    where F: Future,
+          R: FnOnce(),
+{
     let core = Core::new()?;
     let pool = CpuPool::new(20);
-    let storage = storage_from_environment(&pool, &core.handle());
-    let res = SccacheServer::<ProcessCommandCreator>::new(port, pool, core, storage);
+    let res = check_temp_root().and_then(|()| {
+        let storage = storage_from_environment(&pool, &core.handle())?;
+        bind_server(addr, pool, core, storage)
+    }).and_then(|mut srv| {
+        let stats_file = match server_id() {
+            Some(id) => format!(".stats-{}.json", id),
+            None => ".stats.json".to_owned(),
+        };
+        srv.set_stats_file(disk_cache_dir().join(stats_file))?;
+        if let Some(metrics_addr) = metrics::addr_from_environment() {
+            srv.serve_metrics(&metrics_addr)?;
+        }
+        if service {
+            srv.disable_idle_timeout();
+            srv.set_shared();
+        }
+        Ok(srv)
+    });
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
         Ok(srv) => {
-            notify_server_startup(&notify, true)?;
-            srv.run(future::empty::<(), ()>())?;
+            let pid_file = pid_file();
+            if let Err(e) = write_pid_file(&pid_file) {
+                warn!("Couldn't write {}: {}", pid_file.display(), e);
+            }
+            notify_server_startup(&notify, None)?;
+            systemd::notify("READY=1\nSTATUS=Accepting connections");
+            running();
+            let res = srv.run(shutdown);
+            let _ = fs::remove_file(&pid_file);
+            res?;
             Ok(())
         }
         Err(e) => {
-            notify_server_startup(&notify, false)?;
+            notify_server_startup(&notify, Some(&e.to_string()))?;
             Err(e)
         }
     }
 }
 
+/// Check that `storage`, the backend `name`, works, by storing a small
+/// test entry, unless it's read-only, and reading it back.
+fn check_backend(name: String, storage: Arc<Storage>) -> SFuture<BackendHealth> {
+    let read_only = storage.is_read_only();
+    let mut report = BackendHealth {
+        name: name,
+        read_only: read_only,
+        write_error: None,
+        read_error: None,
+    };
+    let mut m = sha1::Sha1::new();
+    m.update(b"sccache health check");
+    let key = m.digest().to_string();
+    let write: SFuture<Duration> = if read_only {
+        Box::new(future::ok(Duration::new(0, 0)))
+    } else {
+        let entry = storage.start_put(&key).and_then(|mut entry| {
+            entry.put_object(HEALTH_CHECK_OBJECT, &mut &b"ok"[..])?;
+            Ok(entry)
+        });
+        match entry {
+            Ok(entry) => storage.finish_put(&key, entry),
+            Err(e) => Box::new(future::err(e)),
+        }
+    };
+    Box::new(write.then(move |res| {
+        report.write_error = res.as_ref().err().map(|e| e.to_string());
+        let written = res.is_ok() && !read_only;
+        storage.get(&key).then(move |res| -> Result<BackendHealth> {
+            report.read_error = match res {
+                Ok(Cache::Hit(mut entry)) => {
+                    entry.verify()
+                        .and_then(|()| entry.get_object(HEALTH_CHECK_OBJECT, &mut io::sink()))
+                        .err()
+                        .map(|e| e.to_string())
+                }
+                Ok(Cache::Miss) if written => Some("the entry just stored wasn't found".to_owned()),
+                Ok(Cache::Miss) | Ok(Cache::Recache) => None,
+                Ok(Cache::Unavailable) => Some("the storage is unavailable".to_owned()),
+                Err(e) => Some(e.to_string()),
+            };
+            Ok(report)
+        })
+    }))
+}
+
+/// Run `compile` in this process the way the server would, for
+/// environments that can't leave a server running in the background.
+/// Returns `None` if the compilation can't be cached, in which case the
+/// caller runs the compiler itself. The cache entry of a miss is stored
+/// before this returns.
+pub fn compile_in_process(compile: Compile) -> Result<Option<CompileFinished>> {
+    let mut core = Core::new()?;
+    let pool = CpuPool::new(20);
+    let storage = storage_from_environment(&pool, &core.handle())?;
+    let (tx, _rx) = mpsc::channel(1);
+    let (_wait, info) = WaitUntilZero::new();
+    let service = SccacheService::<ProcessCommandCreator>::new(storage, core.handle(), pool, tx, info);
+    let body = match core.run(service.handle_compile(compile))? {
+        Message::WithBody(Response::CompileStarted, body) => body,
+        Message::WithoutBody(Response::UnhandledCompile) => return Ok(None),
+        _ => bail!("unexpected response to a compile request"),
+    };
+    let finish = match core.run(body.into_future().map_err(|(e, _)| e))? {
+        (Some(Response::CompileFinished(finish)), _) => finish,
+        _ => bail!("compilation finished without a result"),
+    };
+    core.run(service.drain())?;
+    Ok(Some(finish))
+}
+
+/// Create the server, listening on the socket passed by systemd socket
+/// activation if there is one, or on `addr` otherwise.
+#[cfg(unix)]
+fn bind_server(addr: &ServerAddr,
+               pool: CpuPool,
+               core: Core,
+               storage: Arc<Storage>) -> Result<SccacheServer<ProcessCommandCreator>> {
+    match systemd::listen_fd() {
+        Some(fd) => {
+            info!("Using the socket passed by systemd");
+            SccacheServer::from_fd(fd, pool, core, storage)
+        }
+        None => SccacheServer::bind(addr, pool, core, storage),
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_server(addr: &ServerAddr,
+               pool: CpuPool,
+               core: Core,
+               storage: Arc<Storage>) -> Result<SccacheServer<ProcessCommandCreator>> {
+    SccacheServer::bind(addr, pool, core, storage)
+}
+
 pub struct SccacheServer<C: CommandCreatorSync> {
     core: Core,
-    listener: TcpListener,
+    listener: Listener,
+    /// Whether the listening socket was passed to this process, rather than
+    /// created by it.
+    inherited: bool,
     rx: mpsc::Receiver<ServerMessage>,
-    timeout: Duration,
+    /// How long the server may be idle before shutting down, or `None` to
+    /// keep running.
+    timeout: Option<Duration>,
     service: SccacheService<C>,
     wait: WaitUntilZero,
 }
 
 impl<C: CommandCreatorSync> SccacheServer<C> {
+    /// Create a server listening on `port` on the loopback interface.
     pub fn new(port: u16,
                pool: CpuPool,
                core: Core,
                storage: Arc<Storage>) -> Result<SccacheServer<C>> {
-        let handle = core.handle();
         let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
-        let listener = TcpListener::bind(&SocketAddr::V4(addr), &handle)?;
+        SccacheServer::bind(&ServerAddr::Tcp(SocketAddr::V4(addr)), pool, core, storage)
+    }
+
+    /// Create a server listening on `addr`.
+    pub fn bind(addr: &ServerAddr,
+                pool: CpuPool,
+                core: Core,
+                storage: Arc<Storage>) -> Result<SccacheServer<C>> {
+        let handle = core.handle();
+        let (listener, addr) = match *addr {
+            ServerAddr::Tcp(ref addr) => {
+                let listener = TcpListener::bind(addr, &handle)?;
+                let addr = ServerAddr::Tcp(listener.local_addr()?);
+                (Listener::Tcp(listener), addr)
+            }
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => {
+                let listener = bind_unix(path, &handle)?;
+                (Listener::Unix(listener), ServerAddr::Unix(path.clone()))
+            }
+        };
+        Ok(SccacheServer::with_listener(listener, addr, pool, core, storage))
+    }
 
-        // Prepare the service which we'll use to service all incoming TCP
+    /// Create a server accepting connections on the listening TCP or unix
+    /// domain socket `fd`, such as one passed by systemd socket activation.
+    #[cfg(unix)]
+    pub fn from_fd(fd: RawFd,
+                   pool: CpuPool,
+                   core: Core,
+                   storage: Arc<Storage>) -> Result<SccacheServer<C>> {
+        use std::net;
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::os::unix::net::UnixListener as StdUnixListener;
+
+        let handle = core.handle();
+        let tcp = unsafe { net::TcpListener::from_raw_fd(fd) };
+        let (listener, addr) = match tcp.local_addr() {
+            Ok(addr) => {
+                let listener = TcpListener::from_listener(tcp, &addr, &handle)?;
+                (Listener::Tcp(listener), ServerAddr::Tcp(addr))
+            }
+            Err(_) => {
+                let unix = unsafe { StdUnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                let path = unix.local_addr()?
+                    .as_pathname()
+                    .map(Path::to_path_buf)
+                    .ok_or("inherited socket isn't a TCP or unix domain socket")?;
+                let listener = UnixListener::from_listener(unix, &handle)?;
+                (Listener::Unix(listener), ServerAddr::Unix(path))
+            }
+        };
+        let mut srv = SccacheServer::with_listener(listener, addr, pool, core, storage);
+        srv.inherited = true;
+        Ok(srv)
+    }
+
+    fn with_listener(listener: Listener,
+                     addr: ServerAddr,
+                     pool: CpuPool,
+                     core: Core,
+                     storage: Arc<Storage>) -> SccacheServer<C> {
+        // Prepare the service which we'll use to service all incoming
         // connections.
         let (tx, rx) = mpsc::channel(1);
         let (wait, info) = WaitUntilZero::new();
-        let service = SccacheService::new(storage, core.handle(), pool, tx, info);
+        let mut service = SccacheService::new(storage, core.handle(), pool, tx, info);
+        service.addr = Some(addr);
 
-        Ok(SccacheServer {
+        SccacheServer {
             core: core,
             listener: listener,
+            inherited: false,
             rx: rx,
             service: service,
-            timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT),
+            timeout: Some(Duration::from_millis(DEFAULT_IDLE_TIMEOUT)),
             wait: wait,
-        })
+        }
     }
 
     /// Configures how long this server will be idle before shutting down.
     #[allow(dead_code)]
     pub fn set_idle_timeout(&mut self, timeout: Duration) {
-        self.timeout = timeout;
+        self.timeout = Some(timeout);
+    }
+
+    /// Keep the server running however long it's idle, for a server run
+    /// as a service.
+    pub fn disable_idle_timeout(&mut self) {
+        self.timeout = None;
+    }
+
+    /// Let any user use this server, rather than only the user running it,
+    /// for a server run as a system service.
+    pub fn set_shared(&mut self) {
+        self.service.shared = true;
     }
 
     /// Set the `force_recache` setting.
     #[allow(dead_code)]
     pub fn set_force_recache(&mut self, force_recache: bool) {
-        self.service.force_recache = force_recache;
+        self.service.force_recache.set(force_recache);
     }
 
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<Storage>) {
-        self.service.storage = storage;
+        *self.service.storage.borrow_mut() = storage;
     }
 
     /// Returns a reference to a thread pool to run work on
@@ -194,10 +635,52 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         &self.service.creator
     }
 
-    /// Returns the port that this server is bound to
+    /// Load statistics from `path`, if it exists, and save them there
+    /// periodically and when the server shuts down, so that they are kept
+    /// across restarts.
+    pub fn set_stats_file(&mut self, path: PathBuf) -> Result<()> {
+        match load_stats(&path) {
+            Ok(Some(stats)) => *self.service.stats.borrow_mut() = stats,
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load statistics from {}: {}", path.display(), e),
+        }
+        let path = Rc::new(path);
+        self.service.stats_file = Some(path.clone());
+        let stats = self.service.stats.clone();
+        let interval = Interval::new(Duration::from_secs(STATS_SAVE_INTERVAL), &self.core.handle())?;
+        self.core.handle().spawn(interval.for_each(move |()| {
+            save_stats(&path, &stats.borrow());
+            Ok(())
+        }).map_err(|e| {
+            warn!("Stopped saving statistics: {}", e);
+        }));
+        Ok(())
+    }
+
+    /// Serve metrics about this server in the Prometheus format at
+    /// `/metrics` on `addr`.
+    pub fn serve_metrics(&self, addr: &SocketAddr) -> Result<()> {
+        let stats = self.service.stats.clone();
+        let storage = self.service.storage.clone();
+        let active_compiles = self.service.active_compiles.clone();
+        let pending_writes = self.service.pending_writes.clone();
+        metrics::serve(addr, &self.core.handle(), Rc::new(move || {
+            let storage = storage.borrow();
+            let pending_writes = pending_writes.get() + storage.background_writes();
+            render_metrics(&stats.borrow(), &**storage, active_compiles.get(), pending_writes)
+        }))?;
+        Ok(())
+    }
+
+    /// Returns the port that this server is bound to, or 0 if it isn't
+    /// listening on a TCP socket.
     #[allow(dead_code)]
     pub fn port(&self) -> u16 {
-        self.listener.local_addr().unwrap().port()
+        match self.listener {
+            Listener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+            #[cfg(unix)]
+            Listener::Unix(..) => 0,
+        }
     }
 
     /// Runs this server to completion.
@@ -214,15 +697,32 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     fn _run<'a>(self, shutdown: Box<Future<Item = (), Error = ()> + 'a>)
                 -> io::Result<()>
     {
-        let SccacheServer { mut core, listener, rx, service, timeout, wait } = self;
+        let SccacheServer { mut core, listener, inherited, rx, service, timeout, wait } = self;
+        let shutdown_timeout = service.shutdown_timeout.clone();
+        let draining = service.draining.clone();
+        let stats = service.stats.clone();
+        let stats_file = service.stats_file.clone();
 
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
         let handle = core.handle();
-        let server = listener.incoming().for_each(move |(socket, _addr)| {
-            SccacheProto.bind_server(&handle, socket, service.clone());
-            Ok(())
-        });
+        // A socket passed by systemd is left for it to clean up.
+        let addr = if inherited { None } else { service.addr.clone() };
+        let server: Box<Future<Item=(), Error=io::Error>> = match listener {
+            Listener::Tcp(listener) => {
+                Box::new(listener.incoming().for_each(move |(socket, _addr)| {
+                    SccacheProto.bind_server(&handle, socket, service.clone());
+                    Ok(())
+                }))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                Box::new(listener.incoming().for_each(move |(socket, _addr)| {
+                    SccacheProto.bind_server(&handle, socket, service.clone());
+                    Ok(())
+                }))
+            }
+        };
 
         // Right now there's a whole bunch of ways to shut down this server for
         // various purposes. These include:
@@ -238,13 +738,16 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         let handle = core.handle();
         let shutdown_idle = ShutdownOrInactive {
             rx: rx,
-            timeout: Timeout::new(timeout, &handle)?,
+            timeout: match timeout {
+                Some(timeout) => Some(Timeout::new(timeout, &handle)?),
+                None => None,
+            },
             handle: handle.clone(),
             timeout_dur: timeout,
         };
 
         let server = future::select_all(vec![
-            Box::new(server) as Box<Future<Item=_, Error=_>>,
+            server,
             Box::new(shutdown_idle),
             Box::new(shutdown.map_err(|()| {
                 io::Error::new(io::ErrorKind::Other, "shutdown signal failed")
@@ -252,6 +755,9 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         ]);
         core.run(server)
             .map_err(|p| p.0)?;
+        systemd::notify("STOPPING=1");
+        // Stop taking on new compilations from connected clients.
+        draining.set(true);
 
         // Once our server has shut down either due to inactivity or a manual
         // request we still need to give a bit of time for all active
@@ -260,9 +766,15 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         //
         // Note that we cap the amount of time this can take, however, as we
         // don't want to wait *too* long.
-        core.run(wait.select(Timeout::new(Duration::new(10, 0), &handle)?))
+        core.run(wait.select(Timeout::new(shutdown_timeout.get(), &handle)?))
             .map_err(|p| p.0)?;
 
+        if let Some(path) = stats_file {
+            save_stats(&path, &stats.borrow());
+        }
+        if let Some(addr) = addr {
+            addr.remove();
+        }
         Ok(())
     }
 }
@@ -273,16 +785,90 @@ struct SccacheService<C: CommandCreatorSync> {
     /// Server statistics.
     stats: Rc<RefCell<ServerStats>>,
 
-    /// Cache storage.
-    storage: Arc<Storage>,
+    /// Cache storage, replaced when the configuration is reloaded.
+    storage: Rc<RefCell<Arc<Storage>>>,
 
     /// A cache of known compiler info.
     compilers: Rc<RefCell<HashMap<String, Option<Compiler>>>>,
 
+    /// Explains cache misses of compilations run with
+    /// `SCCACHE_DEBUG_WHY_MISS` set.
+    explainer: MissExplainer,
+
+    /// The keys being compiled, so that identical compilations share a
+    /// single run of the compiler.
+    in_flight: InFlight,
+
+    /// Which compiler executables to cache the compilations of.
+    compiler_filter: Rc<RefCell<CompilerFilter>>,
+
+    /// How to cache the compilations of particular compilers.
+    compiler_policies: Rc<RefCell<CompilerPolicies>>,
+
     /// True if all compiles should be forced, ignoring existing cache entries.
     ///
-    /// This can be controlled with the `SCCACHE_RECACHE` environment variable.
-    force_recache: bool,
+    /// This can be controlled with the `SCCACHE_RECACHE` environment variable
+    /// of the server, such as in its configuration file. Clients send theirs
+    /// along with each compile rather than passing it on to servers they start.
+    force_recache: Rc<Cell<bool>>,
+
+    /// The number of cache writes currently in progress in the background.
+    pending_writes: Rc<Cell<usize>>,
+
+    /// How many cache writes may be in progress before new ones are dropped.
+    ///
+    /// This can be controlled with the `SCCACHE_MAX_PENDING_WRITES`
+    /// environment variable.
+    max_pending_writes: Rc<Cell<usize>>,
+
+    /// The number of compilations currently in progress, including those
+    /// waiting for a job slot.
+    active_compiles: Rc<Cell<usize>>,
+
+    /// The id of the last compilation started, which tells the log events
+    /// of compilations apart.
+    last_compile_id: Rc<Cell<u64>>,
+
+    /// The compilations in progress, by id, shown by `--top`.
+    compiles: Rc<RefCell<HashMap<u64, CompileActivity>>>,
+
+    /// Statistics of the builds compilations were tagged with by
+    /// `SCCACHE_BUILD_ID`, by build id.
+    build_stats: Rc<RefCell<HashMap<String, BuildStats>>>,
+
+    /// The number of compilations holding a job slot.
+    running_jobs: Rc<Cell<usize>>,
+
+    /// Compilations waiting for a job slot.
+    queued_jobs: Rc<RefCell<JobQueue<oneshot::Sender<()>>>>,
+
+    /// How many compilations may run at once, or `None` for no limit.
+    ///
+    /// This can be controlled with the `SCCACHE_MAX_JOBS` environment
+    /// variable.
+    max_jobs: Rc<Cell<Option<usize>>>,
+
+    /// True once the server is shutting down, after which compile requests
+    /// are left to the client to run.
+    draining: Rc<Cell<bool>>,
+
+    /// How long to wait for compilations and cache writes when shutting down.
+    ///
+    /// This can be controlled with the `SCCACHE_SHUTDOWN_TIMEOUT`
+    /// environment variable.
+    shutdown_timeout: Rc<Cell<Duration>>,
+
+    /// Logs the cache operations of compilations, if a file is set in the
+    /// `SCCACHE_AUDIT_LOG` environment variable.
+    audit_log: Rc<RefCell<Option<Rc<AuditLog>>>>,
+
+    /// Pushes metrics to a statsd agent, if one is set in the
+    /// `SCCACHE_STATSD_ADDR` environment variable.
+    statsd: Rc<RefCell<Option<Statsd>>>,
+
+    /// Runs cache misses on build servers, if a scheduler is set in the
+    /// `SCCACHE_DIST_SCHEDULER` environment variable.
+    dist_client: Rc<RefCell<Option<DistClient>>>,
 
     /// Thread pool to execute work in
     pool: CpuPool,
@@ -305,10 +891,22 @@ struct SccacheService<C: CommandCreatorSync> {
 
     /// Information tracking how many services (connected clients) are active.
     info: ActiveInfo,
+
+    /// The address the server is listening on.
+    addr: Option<ServerAddr>,
+
+    /// Where statistics are saved, to be kept across restarts.
+    stats_file: Option<Rc<PathBuf>>,
+
+    /// The name of the server instance, see `server_id`.
+    instance: Option<String>,
+
+    /// Whether any user may use this server, see `set_shared`.
+    shared: bool,
 }
 
-type SccacheRequest = Message<ClientRequest, Body<(), Error>>;
-type SccacheResponse = Message<ServerResponse, Body<ServerResponse, Error>>;
+type SccacheRequest = Message<Request, Body<(), Error>>;
+type SccacheResponse = Message<Response, Body<Response, Error>>;
 
 /// Messages sent from all services to the main event loop indicating activity.
 ///
@@ -331,7 +929,7 @@ impl<C> Service for SccacheService<C>
     type Future = SFuture<Self::Response>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let mut req = req.into_inner();
+        let req = req.into_inner();
         trace!("handle_client");
 
         // Opportunistically let channel know that we've received a request. We
@@ -339,36 +937,96 @@ impl<C> Service for SccacheService<C>
         // that every message is received.
         drop(self.tx.clone().start_send(ServerMessage::Request));
 
-        if req.has_compile() {
-            debug!("handle_client: compile");
-            self.stats.borrow_mut().compile_requests += 1;
-            self.handle_compile(req.take_compile())
-        } else {
-            // Simple requests that can generate responses right away.
-            let mut res = ServerResponse::new();
-            if req.has_get_stats() {
+        // Simple requests can generate responses right away.
+        let res = match req {
+            Request::Compile(compile) => {
+                debug!("handle_client: compile");
+                self.stats.borrow_mut().compile_requests += 1;
+                return self.handle_compile(compile)
+            }
+            Request::GetStats => {
                 debug!("handle_client: get_stats");
-                res.set_stats(self.get_stats());
-            } else if req.has_zero_stats() {
+                Response::Stats(self.get_stats())
+            }
+            Request::GetServerInfo => {
+                debug!("handle_client: get_server_info");
+                Response::ServerInfo(self.server_info())
+            }
+            Request::ZeroStats => {
                 debug!("handle_client: zero_stats");
-                res.set_stats(self.zero_stats());
-            } else if req.has_shutdown() {
+                Response::Stats(self.zero_stats())
+            }
+            Request::SetLogLevel(level) => {
+                debug!("handle_client: set_log_level({})", level);
+                let previous = logging::parse_level(&level).and_then(logging::set_level);
+                if let Some(previous) = previous {
+                    info!("Log level changed from {} to {}", previous, level);
+                }
+                Response::LogLevelSet(previous.map(|previous| previous.to_string().to_lowercase()))
+            }
+            Request::ReloadConfig => {
+                debug!("handle_client: reload_config");
+                return Box::new(self.reload_config().map(|reloaded| {
+                    Message::WithoutBody(Response::ConfigReloaded(reloaded))
+                }))
+            }
+            Request::GetServerLog(lines) => {
+                debug!("handle_client: get_server_log({})", lines);
+                Response::ServerLog(logging::recent_lines(lines as usize))
+            }
+            Request::GetCompilers => {
+                debug!("handle_client: get_compilers");
+                Response::Compilers(self.compilers_info())
+            }
+            Request::CheckCached(compile) => {
+                debug!("handle_client: check_cached");
+                return self.handle_check_cached(compile)
+            }
+            Request::GetActivity => {
+                debug!("handle_client: get_activity");
+                Response::Activity(self.activity())
+            }
+            Request::GetBuildStats(build_id) => {
+                debug!("handle_client: get_build_stats({})", build_id);
+                Response::BuildStats(self.build_stats.borrow().get(&build_id).map(|stats| {
+                    stats.to_cache_statistics(&build_id)
+                }))
+            }
+            Request::HealthCheck => {
+                debug!("handle_client: health_check");
+                return Box::new(self.check_health().map(|health_report| {
+                    Message::WithoutBody(Response::HealthReport(health_report))
+                }))
+            }
+            Request::ClearCache(remote) => {
+                debug!("handle_client: clear_cache({})", remote);
+                return Box::new(self.clear_cache(remote).map(|cleared| {
+                    Message::WithoutBody(Response::CacheCleared(cleared))
+                }))
+            }
+            Request::Gc => {
+                debug!("handle_client: gc");
+                return Box::new(self.gc().map(|gc_finished| {
+                    Message::WithoutBody(Response::GcFinished(gc_finished))
+                }))
+            }
+            Request::Shutdown => {
                 debug!("handle_client: shutdown");
+                self.draining.set(true);
                 let future = self.tx.clone().send(ServerMessage::Shutdown);
                 let me = self.clone();
-                return Box::new(future.then(move |_| {
-                    let mut shutting_down = ShuttingDown::new();
-                    shutting_down.set_stats(me.get_stats());
-                    res.set_shutting_down(shutting_down);
-                    Ok(Message::WithoutBody(res))
+                let drain = self.drain();
+                return Box::new(future.then(move |_| drain).map(move |(compiles, writes)| {
+                    Message::WithoutBody(Response::ShuttingDown(ShuttingDown {
+                        stats: me.get_stats(),
+                        unfinished_compiles: compiles as u32,
+                        unfinished_writes: writes as u32,
+                    }))
                 }))
-            } else {
-                warn!("handle_client: unknown command");
-                res.set_unknown(UnknownCommand::new());
             }
+        };
 
-            future::ok(Message::WithoutBody(res)).boxed()
-        }
+        future::ok(Message::WithoutBody(res)).boxed()
     }
 }
 
@@ -380,67 +1038,455 @@ impl<C> SccacheService<C>
                pool: CpuPool,
                tx: mpsc::Sender<ServerMessage>,
                info: ActiveInfo) -> SccacheService<C> {
-        SccacheService {
+        let service = SccacheService {
             stats: Rc::new(RefCell::new(ServerStats::default())),
-            storage: storage,
+            storage: Rc::new(RefCell::new(storage)),
             compilers: Rc::new(RefCell::new(HashMap::new())),
-            force_recache: env::var("SCCACHE_RECACHE").is_ok(),
+            explainer: MissExplainer::default(),
+            in_flight: InFlight::default(),
+            compiler_filter: Rc::new(RefCell::new(CompilerFilter::default())),
+            compiler_policies: Rc::new(RefCell::new(CompilerPolicies::default())),
+            force_recache: Rc::new(Cell::new(false)),
+            pending_writes: Rc::new(Cell::new(0)),
+            max_pending_writes: Rc::new(Cell::new(DEFAULT_MAX_PENDING_WRITES)),
+            active_compiles: Rc::new(Cell::new(0)),
+            last_compile_id: Rc::new(Cell::new(0)),
+            compiles: Rc::new(RefCell::new(HashMap::new())),
+            build_stats: Rc::new(RefCell::new(HashMap::new())),
+            running_jobs: Rc::new(Cell::new(0)),
+            queued_jobs: Rc::new(RefCell::new(JobQueue::new())),
+            max_jobs: Rc::new(Cell::new(None)),
+            draining: Rc::new(Cell::new(false)),
+            shutdown_timeout: Rc::new(Cell::new(Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT))),
+            audit_log: Rc::new(RefCell::new(None)),
+            statsd: Rc::new(RefCell::new(None)),
+            dist_client: Rc::new(RefCell::new(None)),
             pool: pool,
             creator: C::new(&handle),
             handle: handle,
             tx: tx,
             info: info,
+            addr: None,
+            stats_file: None,
+            instance: server_id(),
+            shared: false,
+        };
+        service.read_settings();
+        service
+    }
+
+    /// Read the settings that a configuration reload can change from the
+    /// environment.
+    fn read_settings(&self) {
+        self.force_recache.set(env::var("SCCACHE_RECACHE").is_ok());
+        self.max_pending_writes.set(env::var("SCCACHE_MAX_PENDING_WRITES").ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_MAX_PENDING_WRITES));
+        self.max_jobs.set(env::var("SCCACHE_MAX_JOBS").ok()
+            .and_then(|s| s.parse().ok())
+            .and_then(|n| if n > 0 { Some(n) } else { None }));
+        self.shutdown_timeout.set(env::var("SCCACHE_SHUTDOWN_TIMEOUT").ok()
+            .and_then(|s| parse_duration(&s))
+            .unwrap_or(Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT)));
+        *self.compiler_filter.borrow_mut() = CompilerFilter::from_environment();
+        *self.compiler_policies.borrow_mut() = CompilerPolicies::from_environment();
+        let audit_log = match AuditLog::from_environment() {
+            Ok(audit_log) => audit_log,
+            Err(e) => {
+                warn!("Not writing an audit log: {}", e);
+                None
+            }
+        };
+        if let Some(ref audit_log) = audit_log {
+            info!("Logging cache operations to {}", audit_log.path().display());
+        }
+        *self.audit_log.borrow_mut() = audit_log.map(Rc::new);
+        let statsd = match Statsd::from_environment() {
+            Ok(statsd) => statsd,
+            Err(e) => {
+                warn!("Invalid statsd configuration: {}", e);
+                None
+            }
+        };
+        if let Some(ref statsd) = statsd {
+            info!("Sending metrics to statsd at {}", statsd.addr());
+        }
+        *self.statsd.borrow_mut() = statsd;
+        let dist_client = match DistClient::from_environment() {
+            Ok(dist_client) => dist_client,
+            Err(e) => {
+                warn!("Invalid distributed compilation configuration: {}", e);
+                None
+            }
+        };
+        if let Some(ref client) = dist_client {
+            info!("Running cache misses on the build servers of {}", client.scheduler());
         }
+        *self.dist_client.borrow_mut() = dist_client;
     }
 
-    /// Get stats about the cache.
-    fn get_stats(&self) -> CacheStats {
-        let mut stats = CacheStats::new();
-        let mut stats_vec = self.stats.borrow().to_cache_statistics();
+    /// Read the configuration file again, switching to the storage and
+    /// settings it describes. Compilations in progress finish with the
+    /// storage they started with.
+    fn reload_config(&self) -> SFuture<::std::result::Result<ConfigReloaded, String>> {
+        let changed = match config::reload() {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!("Failed to reload configuration: {}", e);
+                return Box::new(future::ok(Err(e.to_string())));
+            }
+        };
+        // A cache directory that isn't in use yet is scanned on the pool,
+        // and the new storage is created while its index is held. Failures
+        // are left for creating the storage to report.
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(disk_index_from_environment).then(move |index| {
+            let storage = storage_from_environment(&me.pool, &me.handle);
+            drop(index);
+            Ok::<_, Error>(match storage {
+                Ok(storage) => Ok(me.use_reloaded_config(storage, changed)),
+                Err(e) => {
+                    warn!("Failed to reload configuration: {}", e);
+                    Err(e.to_string())
+                }
+            })
+        }))
+    }
+
+    /// Switch to `storage` and the settings of the reloaded configuration,
+    /// in which the settings `changed` changed.
+    fn use_reloaded_config(&self, storage: Arc<Storage>, changed: Vec<String>) -> ConfigReloaded {
+        let cache_location = storage.location();
+        info!("Reloaded configuration, caching in {}", cache_location);
+        *self.storage.borrow_mut() = storage;
+        self.read_settings();
+        // Start the queued compilations that a higher limit makes room for.
+        while self.max_jobs.get().map_or(true, |max| self.running_jobs.get() < max) {
+            match self.queued_jobs.borrow_mut().pop_front() {
+                Some(tx) => {
+                    self.running_jobs.set(self.running_jobs.get() + 1);
+                    tx.complete(());
+                }
+                None => break,
+            }
+        }
+        let (needs_restart, changed) = changed.into_iter().partition::<Vec<_>, _>(|key| needs_restart(key));
+        for key in &needs_restart {
+            warn!("{} changed, but only takes effect when the server restarts", key);
+        }
+        ConfigReloaded {
+            changed: changed,
+            needs_restart: needs_restart,
+            cache_location: cache_location,
+        }
+    }
+
+    /// Describe the configuration this server is running with.
+    fn server_info(&self) -> ServerInfo {
+        let (bind_address, port) = self.addr.as_ref()
+            .map_or((String::new(), 0), |addr| addr.info());
+        ServerInfo {
+            bind_address: bind_address,
+            port: port,
+            user: if self.shared { SHARED_USER.to_owned() } else { current_user() },
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            instance: self.instance.clone(),
+            pid: unsafe { libc::getpid() } as u32,
+            active_compiles: self.active_compiles.get() as u32,
+            pending_writes: self.pending_writes() as u32,
+        }
+    }
+
+    /// Describe the compilations in progress.
+    fn activity(&self) -> Activity {
+        let compiles = self.compiles.borrow();
+        let mut ids = compiles.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        let stats = self.stats.borrow();
+        Activity {
+            compiles: ids.iter().map(|id| {
+                let compile = &compiles[id];
+                let elapsed = compile.start.elapsed();
+                ActiveCompile {
+                    executable: compile.executable.clone(),
+                    input: compile.input.clone(),
+                    elapsed_ms: elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64,
+                    phase: compile.phase,
+                }
+            }).collect(),
+            queued_compiles: self.queued_jobs.borrow().len() as u32,
+            pending_writes: self.pending_writes() as u32,
+            cache_hits: stats.cache_hits,
+            cache_misses: stats.cache_misses,
+        }
+    }
+
+    /// Update the statistics of the build `build_id` with `f`, evicting
+    /// those of the build seen least recently if too many are kept.
+    fn build_stats_for<F: FnOnce(&mut BuildStats)>(&self, build_id: &str, f: F) {
+        let mut builds = self.build_stats.borrow_mut();
+        if !builds.contains_key(build_id) && builds.len() >= MAX_BUILDS {
+            let oldest = builds.iter()
+                .min_by_key(|&(_, stats)| stats.last_seen)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                builds.remove(&oldest);
+            }
+        }
+        let stats = builds.entry(build_id.to_owned()).or_insert_with(BuildStats::new);
+        stats.last_seen = Instant::now();
+        f(stats);
+    }
+
+    /// Push `metrics` to the statsd agent, if there is one.
+    fn send_metrics(&self, metrics: &[Metric]) {
+        if let Some(ref statsd) = *self.statsd.borrow() {
+            statsd.send(metrics);
+        }
+    }
+
+    /// Record that the compilation `id` entered `phase`.
+    fn set_compile_phase(&self, id: u64, phase: CompilePhase) {
+        if let Some(compile) = self.compiles.borrow_mut().get_mut(&id) {
+            compile.phase = phase;
+        }
+    }
 
-        let mut stat = CacheStatistic::new();
-        stat.set_name(String::from("Cache location"));
-        stat.set_str(self.storage.location());
-        stats_vec.insert(0, stat);
+    /// Describe the compiler executables this server has seen, sorted by
+    /// path.
+    fn compilers_info(&self) -> Vec<CompilerInfo> {
+        let mut compilers = self.compilers.borrow().iter().map(|(path, compiler)| {
+            CompilerInfo {
+                executable: path.clone(),
+                kind: compiler.as_ref().map(|c| c.kind.name().to_owned()),
+                version: compiler.as_ref().and_then(|c| c.version.clone()),
+                digest: compiler.as_ref().map(|c| c.digest.clone()),
+            }
+        }).collect::<Vec<_>>();
+        compilers.sort_by(|a, b| a.executable.cmp(&b.executable));
+        compilers
+    }
 
-        for &(s, v) in [("Cache size", self.storage.current_size()),
-                       ("Max cache size", self.storage.max_size())].iter() {
+    /// Get stats about the cache.
+    fn get_stats(&self) -> CacheStats {
+        let storage = self.storage.borrow();
+        let mut stats = vec!(CacheStatistic::str("Cache location", storage.location()));
+        if let Some(remote) = storage.remote_location() {
+            stats.push(CacheStatistic::str("Remote storage", remote));
+        }
+        for &(s, v) in [("Cache size", storage.current_size()),
+                       ("Max cache size", storage.max_size())].iter() {
             v.map(|val| {
-                let mut stat = CacheStatistic::new();
-                stat.set_name(String::from(s));
-                stat.set_size(val as u64);
-                stats_vec.insert(0, stat);
+                stats.push(CacheStatistic::size(s, val as u64));
             });
         }
+        if let Some(entries) = storage.entry_count() {
+            stats.push(CacheStatistic::count("Cache entries", entries as u64));
+        }
+        let mut stats = in_group(StatGroup::Storage, stats);
+
+        stats.extend(self.stats.borrow().to_cache_statistics());
+
+        for backend in storage.backend_stats() {
+            stats.extend(backend_statistics(&backend.borrow()));
+        }
 
-        stats.set_stats(RepeatedField::from_vec(stats_vec));
         stats
     }
 
     /// Zero and return stats about the cache.
     fn zero_stats(&self) -> CacheStats {
         *self.stats.borrow_mut() = ServerStats::default();
+        self.build_stats.borrow_mut().clear();
+        for backend in self.storage.borrow().backend_stats() {
+            backend.borrow_mut().reset();
+        }
+        if let Some(ref path) = self.stats_file {
+            save_stats(path, &self.stats.borrow());
+        }
         self.get_stats()
     }
 
+    /// Collect garbage in the local disk cache, returning what was done.
+    fn gc(&self) -> SFuture<Option<CacheStats>> {
+        let storage = self.storage.borrow().clone();
+        Box::new(storage.gc().map(move |gc_stats| {
+            gc_stats.map(|gc_stats| {
+                let mut stats = vec!();
+                for &(s, v) in [("Orphaned temporary files removed", gc_stats.temp_files_removed),
+                               ("Missing files dropped from the index", gc_stats.missing),
+                               ("Files added to the index", gc_stats.added),
+                               ("Files evicted to fit the size limit", gc_stats.evicted),
+                               ("Expired files removed", gc_stats.expired)].iter() {
+                    stats.push(CacheStatistic::count(s, v as u64));
+                }
+                for &(s, v) in [("Cache size", storage.current_size()),
+                               ("Max cache size", storage.max_size())].iter() {
+                    v.map(|val| {
+                        stats.push(CacheStatistic::size(s, val as u64));
+                    });
+                }
+                stats
+            })
+        }))
+    }
+
+    /// Remove every entry from the cache, and from remote storage as well if
+    /// `remote` is set, returning the number of files removed.
+    fn clear_cache(&self, remote: bool) -> SFuture<Result<u64, String>> {
+        let storage = self.storage.borrow().clone();
+        Box::new(storage.clear(remote).then(move |result| {
+            Ok(match result {
+                Ok(removed) => {
+                    info!("Cleared {}: {} files removed", storage.location(), removed);
+                    Ok(removed as u64)
+                }
+                Err(e) => {
+                    warn!("Failed to clear {}: {}", storage.location(), e);
+                    Err(e.to_string())
+                }
+            })
+        }))
+    }
+
+    /// Check that the storage works by storing a test entry and reading it
+    /// back, reporting what failed.
+    ///
+    /// The entry has a fixed key, so that checking repeatedly doesn't fill
+    /// the cache, and servers sharing a cache may overwrite each other's.
+    fn check_health(&self) -> SFuture<HealthReport> {
+        let storage = self.storage.borrow().clone();
+        // Each backend is checked on its own, so that a local cache in front
+        // of remote storage doesn't hide the remote storage failing.
+        let mut backends = storage.backends();
+        if backends.is_empty() {
+            backends.push((storage.location(), storage.clone()));
+        }
+        let checks = backends.into_iter().map(|(name, backend)| check_backend(name, backend)).collect::<Vec<_>>();
+        let server_info = self.server_info();
+        let cache_location = storage.location();
+        Box::new(future::join_all(checks).map(move |backends| {
+            HealthReport {
+                server_info: server_info,
+                cache_location: cache_location,
+                backends: backends,
+            }
+        }))
+    }
+
+    /// Wait, for at most `shutdown_timeout`, until no compilations or cache
+    /// writes are in progress, returning how many of each were left
+    /// unfinished.
+    fn drain(&self) -> SFuture<(usize, usize)> {
+        let deadline = Instant::now() + self.shutdown_timeout.get();
+        let me = self.clone();
+        Box::new(loop_fn((), move |()| -> SFuture<Loop<(usize, usize), ()>> {
+            let compiles = me.active_compiles.get();
+            let writes = me.pending_writes();
+            if compiles + writes == 0 || Instant::now() >= deadline {
+                if compiles + writes > 0 {
+                    info!("Shutting down with {} compilations and {} cache writes unfinished",
+                          compiles, writes);
+                }
+                return future::ok(Loop::Break((compiles, writes))).boxed();
+            }
+            debug!("Waiting for {} compilations and {} cache writes", compiles, writes);
+            match Timeout::new(Duration::from_millis(100), &me.handle) {
+                Ok(timeout) => Box::new(timeout.map(Loop::Continue).map_err(Error::from)),
+                Err(e) => future::err(e.into()).boxed(),
+            }
+        }))
+    }
 
     /// Handle a compile request from a client.
     ///
     /// This will handle a compile request entirely, generating a response with
     /// the inital information and an optional body which will eventually
     /// contain the results of the compilation.
-    fn handle_compile(&self, mut compile: Compile)
+    fn handle_compile(&self, compile: Compile)
                       -> SFuture<SccacheResponse>
     {
-        let exe = compile.take_exe();
-        let cmd = compile.take_command().into_vec();
-        let cwd = compile.take_cwd();
+        if self.draining.get() {
+            debug!("handle_compile: shutting down, leaving compile to the client");
+            return future::ok(Message::WithoutBody(Response::UnhandledCompile)).boxed();
+        }
+        let Compile { exe, command: cmd, cwd, recache, no_cache, explain, priority, build_id, user, env } = compile;
+        if let Some(ref build_id) = build_id {
+            self.build_stats_for(build_id, |stats| stats.compile_requests += 1);
+        }
+        let policy = self.compiler_policies.borrow().policy_for(&exe);
+        let cache_control = if no_cache {
+            CacheControl::Bypass
+        } else if recache || policy.recache || self.force_recache.get() {
+            CacheControl::ForceRecache
+        } else {
+            CacheControl::Default
+        };
+        if !self.compiler_filter.borrow().allows(&exe) {
+            debug!("handle_compile: {} is excluded from caching", exe);
+            self.stats.borrow_mut().requests_excluded_compiler += 1;
+            return future::ok(Message::WithoutBody(Response::UnhandledCompile)).boxed();
+        }
+        let read_only = policy.read_only;
+        let hashed_env_vars = policy.hashed_env_vars;
+        let sloppiness = policy.sloppiness;
         let me = self.clone();
         Box::new(self.compiler_info(&exe).map(move |info| {
-            me.check_compiler(info, cmd, cwd)
+            let info = info.map(|mut compiler| {
+                compiler.hashed_env_vars = hashed_env_vars;
+                compiler.sloppiness = sloppiness;
+                compiler.client_env = Some(env);
+                compiler
+            });
+            me.check_compiler(info, cmd, cwd, cache_control, explain, priority, read_only, build_id, user)
         }))
     }
 
+    /// Look up the cache entry that `compile` would use, without running
+    /// the compiler or counting it in the statistics.
+    fn handle_check_cached(&self, compile: Compile)
+                           -> SFuture<SccacheResponse>
+    {
+        let Compile { exe, command: cmd, cwd, env, .. } = compile;
+        if !self.compiler_filter.borrow().allows(&exe) {
+            return future::ok(Message::WithoutBody(Response::CacheChecked(CacheCheckResult::Unhandled))).boxed();
+        }
+        let policy = self.compiler_policies.borrow().policy_for(&exe);
+        let (hashed_env_vars, sloppiness) = (policy.hashed_env_vars, policy.sloppiness);
+        let me = self.clone();
+        let check = self.compiler_info(&exe).and_then(move |compiler| -> SFuture<CacheCheckResult> {
+            let compiler = match compiler {
+                Some(mut compiler) => {
+                    compiler.hashed_env_vars = hashed_env_vars;
+                    compiler.sloppiness = sloppiness;
+                    compiler.client_env = Some(env);
+                    compiler
+                }
+                None => return future::ok(CacheCheckResult::Unhandled).boxed(),
+            };
+            match compiler.parse_arguments(&cmd, cwd.as_ref()) {
+                CompilerArguments::Ok(args) => {
+                    let storage = me.storage.borrow().clone();
+                    let check = compiler.check_cached(&me.creator, &storage, &args, &cwd, &me.pool);
+                    Box::new(check.then(|res| -> Result<CacheCheckResult> {
+                        Ok(match res {
+                            Ok(CacheCheck::PreprocessorFailed(stderr)) => CacheCheckResult::PreprocessorFailed(stderr),
+                            Ok(CacheCheck::Hit(key)) => CacheCheckResult::Hit(key),
+                            Ok(CacheCheck::Miss(key)) => CacheCheckResult::Miss(key),
+                            Ok(CacheCheck::Unavailable(key)) => CacheCheckResult::Unavailable(key),
+                            Err(e) => CacheCheckResult::Failed(e.to_string()),
+                        })
+                    }))
+                }
+                CompilerArguments::CannotCache(why) => {
+                    future::ok(CacheCheckResult::NotCacheable(why.to_owned())).boxed()
+                }
+                CompilerArguments::NotCompilation => future::ok(CacheCheckResult::Unhandled).boxed(),
+            }
+        });
+        Box::new(check.map(|result| Message::WithoutBody(Response::CacheChecked(result))))
+    }
+
     /// Look up compiler info from the cache for the compiler `path`.
     fn compiler_info(&self, path: &str)
                      -> SFuture<Option<Compiler>> {
@@ -471,7 +1517,9 @@ impl<C> SccacheService<C>
 
                 let info = get_compiler_info(&self.creator, &path, &self.pool);
                 Box::new(info.then(move |info| {
-                    let info = info.ok();
+                    let info = info.map_err(|e| {
+                        debug!("compiler_info: {} isn't a supported compiler: {}", path, e);
+                    }).ok();
                     me.compilers.borrow_mut().insert(path, info.clone());
                     Ok(info)
                 }))
@@ -482,9 +1530,14 @@ impl<C> SccacheService<C>
     fn check_compiler(&self,
                       compiler: Option<Compiler>,
                       cmd: Vec<String>,
-                      cwd: String)
+                      cwd: String,
+                      cache_control: CacheControl,
+                      explain: bool,
+                      priority: Priority,
+                      read_only: bool,
+                      build_id: Option<String>,
+                      user: String)
                       -> SccacheResponse {
-        let mut res = ServerResponse::new();
         let mut stats = self.stats.borrow_mut();
         match compiler {
             None => {
@@ -495,16 +1548,22 @@ impl<C> SccacheService<C>
                 debug!("check_compiler: Supported compiler");
                 // Now check that we can handle this compiler with
                 // the provided commandline.
-                match c.parse_arguments(&cmd, cwd.as_ref()) {
+                let parse_start = Instant::now();
+                let parsed = c.parse_arguments(&cmd, cwd.as_ref());
+                let parse_time = parse_start.elapsed();
+                stats.argument_parses += 1;
+                stats.parse_duration += parse_time;
+                match parsed {
                     CompilerArguments::Ok(args) => {
                         stats.requests_executed += 1;
-                        res.set_compile_started(CompileStarted::new());
                         let (tx, rx) = Body::pair();
-                        self.start_compile_task(c, args, cmd, cwd, tx);
-                        return Message::WithBody(res, rx)
+                        self.start_compile_task(c, args, cmd, cwd, cache_control, explain, priority,
+                                                read_only, build_id, user, parse_time, tx);
+                        return Message::WithBody(Response::CompileStarted, rx)
                     }
-                    CompilerArguments::CannotCache => {
+                    CompilerArguments::CannotCache(why) => {
                         stats.requests_not_cacheable += 1;
+                        *stats.not_cacheable_reasons.entry(why.to_owned()).or_insert(0) += 1;
                     }
                     CompilerArguments::NotCompilation => {
                         stats.requests_not_compile += 1;
@@ -513,8 +1572,7 @@ impl<C> SccacheService<C>
             }
         }
 
-        res.set_unhandled_compile(UnhandledCompile::new());
-        Message::WithoutBody(res)
+        Message::WithoutBody(Response::UnhandledCompile)
     }
 
     /// Start running `cmd` in a thread on our thread pool, in `cwd`.
@@ -523,110 +1581,283 @@ impl<C> SccacheService<C>
                           parsed_arguments: ParsedArguments,
                           arguments: Vec<String>,
                           cwd: String,
-                          tx: mpsc::Sender<Result<ServerResponse>>) {
-        let cache_control = if self.force_recache {
-            CacheControl::ForceRecache
-        } else {
-            CacheControl::Default
-        };
-        let result = compiler.get_cached_or_compile(&self.creator,
-                                                    &self.storage,
-                                                    &arguments,
-                                                    &parsed_arguments,
-                                                    &cwd,
-                                                    cache_control,
-                                                    &self.pool);
+                          cache_control: CacheControl,
+                          explain: bool,
+                          priority: Priority,
+                          read_only: bool,
+                          build_id: Option<String>,
+                          user: String,
+                          parse_time: Duration,
+                          tx: mpsc::Sender<Result<Response>>) {
+        let start = Instant::now();
+        let out_file = parsed_arguments.output_file().into_owned();
+        let id = self.last_compile_id.get() + 1;
+        self.last_compile_id.set(id);
+        let executable = compiler.executable.clone();
+        self.active_compiles.set(self.active_compiles.get() + 1);
+        self.compiles.borrow_mut().insert(id, CompileActivity {
+            executable: executable.clone(),
+            input: parsed_arguments.input.clone(),
+            start: start,
+            phase: CompilePhase::Queued,
+        });
+        let explainer = if explain { Some(self.explainer.clone()) } else { None };
+        let me = self.clone();
+        let result = self.acquire_job_slot(priority).and_then(move |()| {
+            me.set_compile_phase(id, CompilePhase::Running);
+            let mut storage = me.storage.borrow().clone();
+            if read_only {
+                storage = Arc::new(ReadOnlyStorage::new(storage)) as Arc<Storage>;
+            }
+            if let Some(ref audit_log) = *me.audit_log.borrow() {
+                storage = Arc::new(AuditedStorage::new(storage, audit_log.clone(), user)) as Arc<Storage>;
+            }
+            let dist_client = me.dist_client.borrow().clone();
+            compiler.get_cached_or_compile(&me.creator,
+                                           &storage,
+                                           &arguments,
+                                           &parsed_arguments,
+                                           &cwd,
+                                           cache_control,
+                                           explainer,
+                                           dist_client,
+                                           &me.in_flight,
+                                           &me.pool)
+        });
         let me = self.clone();
         let task = result.then(move |result| {
-            let mut res = ServerResponse::new();
-            let mut finish = CompileFinished::new();
+            me.release_job_slot();
+            me.active_compiles.set(me.active_compiles.get() - 1);
+            me.compiles.borrow_mut().remove(&id);
+            let mut finish = CompileFinished {
+                retcode: None,
+                signal: None,
+                stdout: vec!(),
+                stderr: vec!(),
+                error: None,
+            };
             let mut cache_write = None;
+            let mut outcome = "error";
+            let mut phase_timings = PhaseTimings::default();
             let mut stats = me.stats.borrow_mut();
+            stats.compile_duration.record(start.elapsed());
             match result {
-                Ok((compiled, out)) => {
+                Ok((compiled, out, timings)) => {
+                    phase_timings = timings;
+                    if let Some(duration) = timings.preprocess {
+                        stats.preprocessor_runs += 1;
+                        stats.preprocess_duration += duration;
+                    }
+                    if let Some(duration) = timings.compile {
+                        stats.compiler_runs += 1;
+                        stats.compiler_duration += duration;
+                    }
+                    debug!("[{}]: Phase timings: parse {}, preprocess {}, cache lookup {}, compile {}, total {}",
+                           out_file, fmt_duration(Some(parse_time)), fmt_duration(timings.preprocess),
+                           fmt_duration(timings.cache_lookup), fmt_duration(timings.compile),
+                           fmt_duration(Some(start.elapsed())));
                     match compiled {
                         CompileResult::Error => {
                             stats.cache_errors += 1;
                         }
                         CompileResult::CacheHit(duration) => {
+                            outcome = "hit";
                             stats.cache_hits += 1;
                             stats.cache_read_hit_duration += duration;
+                            // The time the compiler took to produce the entry,
+                            // less the time it took to serve it instead.
+                            if let Some(cached) = timings.cached_compile {
+                                let elapsed = start.elapsed();
+                                if cached > elapsed {
+                                    stats.time_saved += cached - elapsed;
+                                }
+                            }
                         },
                         CompileResult::CacheMiss(miss_type, duration, future) => {
                             match miss_type {
                                 MissType::Normal => {
+                                    outcome = "miss";
                                     stats.cache_misses += 1;
                                 }
                                 MissType::ForcedRecache => {
+                                    outcome = "recache";
                                     stats.cache_misses += 1;
                                     stats.forced_recaches += 1;
                                 }
+                                MissType::CacheUnavailable => {
+                                    outcome = "cache_unavailable";
+                                    stats.cache_misses += 1;
+                                    stats.cache_unavailable += 1;
+                                }
                             }
                             stats.cache_read_miss_duration += duration;
                             cache_write = Some(future);
                         }
                         CompileResult::NotCacheable => {
+                            outcome = "not_cacheable";
                             stats.cache_misses += 1;
                             stats.non_cacheable_compilations += 1;
                         }
                         CompileResult::CompileFailed => {
+                            outcome = "compile_failed";
                             stats.compile_fails += 1;
                         }
+                        CompileResult::Bypassed => {
+                            outcome = "bypassed";
+                            stats.cache_bypasses += 1;
+                        }
                     };
                     let Output { status, stdout, stderr } = out;
                     status.code()
                         .map_or_else(
                             || trace!("CompileFinished missing retcode"),
-                            |s| { trace!("CompileFinished retcode: {}", s); finish.set_retcode(s) });
-                    //TODO: sort out getting signal return on Unix
-                    finish.set_stdout(stdout);
-                    finish.set_stderr(stderr);
+                            |s| { trace!("CompileFinished retcode: {}", s); finish.retcode = Some(s) });
+                    finish.signal = status_signal(status);
+                    finish.stdout = stdout;
+                    finish.stderr = stderr;
+                }
+                Err(Error(ErrorKind::ProcessTimedOut, _)) => {
+                    debug!("[{}]: Compiler process timed out", out_file);
+                    outcome = "timed_out";
+                    stats.compile_timeouts += 1;
+                    finish.retcode = Some(-2);
+                    finish.stderr = format!("sccache: {}\n", ErrorKind::ProcessTimedOut.description())
+                        .into_bytes();
                 }
-                Err(_) => {
+                Err(e) => {
                     stats.cache_errors += 1;
-                    //TODO: figure out a better way to communicate this?
-                    finish.set_retcode(-2);
+                    let reasons = e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                    warn!("[{}]: Compilation failed: {}", out_file, reasons.join(": "));
+                    finish.error = Some(reasons.join(": "));
                 }
             };
-            res.set_compile_finished(finish);
-            let send = tx.send(Ok(res));
-
-            let me = me.clone();
-            let cache_write = cache_write.then(move |result| {
-                match result {
-                    Err(e) => {
-                        debug!("Error executing cache write: {}", e);
-                        me.stats.borrow_mut().cache_write_errors += 1;
-                    }
-                    //TODO: save cache stats!
-                    Ok(Some(info)) => {
-                        debug!("[{}]: Cache write finished in {}.{:03}s", info.object_file, info.duration.as_secs(), info.duration.subsec_nanos() / 1000_000);
-                        me.stats.borrow_mut().cache_writes += 1;
-                        me.stats.borrow_mut().cache_write_duration += info.duration;
-                    }
-
-                    Ok(None) => {}
+            drop(stats);
+            if let Some(ref build_id) = build_id {
+                me.build_stats_for(build_id, |stats| stats.record(outcome));
+            }
+            log_compile_event(id, &executable, &out_file, outcome, finish.retcode, parse_time,
+                              &phase_timings, start.elapsed());
+            let outcome_metric = format!("compile.{}", outcome);
+            let mut metrics = vec!(Metric::Count(&outcome_metric, 1),
+                                   Metric::Timing("time.parse", parse_time),
+                                   Metric::Timing("time.total", start.elapsed()));
+            for &(name, duration) in &[("time.preprocess", phase_timings.preprocess),
+                                       ("time.cache_lookup", phase_timings.cache_lookup),
+                                       ("time.compile", phase_timings.compile)] {
+                if let Some(duration) = duration {
+                    metrics.push(Metric::Timing(name, duration));
                 }
-                Ok(())
-            });
+            }
+            me.send_metrics(&metrics);
 
-            send.join(cache_write).then(|_| Ok(()))
+            // The client doesn't need to wait for the cache write, so send
+            // its result right away and store the entry in the background.
+            if let Some(cache_write) = cache_write {
+                me.queue_cache_write(cache_write);
+            }
+            tx.send(Ok(Response::CompileFinished(finish))).then(|_| Ok(()))
         });
 
         self.handle.spawn(task);
     }
+
+    /// Wait until fewer than `max_jobs` compilations are running, then take
+    /// a job slot, which must be given back with `release_job_slot`.
+    /// Interactive compilations get a slot before queued batch ones.
+    fn acquire_job_slot(&self, priority: Priority) -> SFuture<()> {
+        let full = self.max_jobs.get().map_or(false, |max| self.running_jobs.get() >= max);
+        if !full {
+            self.running_jobs.set(self.running_jobs.get() + 1);
+            return Box::new(future::ok(()));
+        }
+        debug!("{} compilations running, queueing compile", self.running_jobs.get());
+        let (tx, rx) = oneshot::channel();
+        self.queued_jobs.borrow_mut().push(priority, tx);
+        Box::new(rx.map_err(|_| "job queue dropped".into()))
+    }
+
+    /// Give back a job slot, handing it straight to the next queued
+    /// compilation if there is one.
+    fn release_job_slot(&self) {
+        match self.queued_jobs.borrow_mut().pop_front() {
+            Some(tx) => tx.complete(()),
+            None => self.running_jobs.set(self.running_jobs.get() - 1),
+        }
+    }
+
+    /// The number of cache writes in progress, including the uploads the
+    /// storage is still doing after their write completed.
+    fn pending_writes(&self) -> usize {
+        self.pending_writes.get() + self.storage.borrow().background_writes()
+    }
+
+    /// Run `cache_write` in the background, unless too many writes are
+    /// already in progress, in which case it is dropped.
+    fn queue_cache_write(&self, cache_write: SFuture<CacheWriteInfo>) {
+        if self.pending_writes() >= self.max_pending_writes.get() {
+            debug!("Too many cache writes in progress, dropping entry");
+            self.stats.borrow_mut().cache_writes_dropped += 1;
+            return;
+        }
+        self.pending_writes.set(self.pending_writes.get() + 1);
+        let me = self.clone();
+        let task = cache_write.then(move |result| {
+            me.pending_writes.set(me.pending_writes.get() - 1);
+            let mut stats = me.stats.borrow_mut();
+            match result {
+                Err(Error(ErrorKind::ReadOnlyCache, _)) => {
+                    stats.cache_writes_skipped += 1;
+                }
+                Err(Error(ErrorKind::CacheUnavailable, _)) => {
+                    debug!("Cache unavailable, not writing entry");
+                }
+                Err(Error(ErrorKind::EntryTooLarge(size), _)) => {
+                    debug!("Not storing cache entry of {} bytes", size);
+                    stats.cache_writes_too_large += 1;
+                }
+                Err(e) => {
+                    debug!("Error executing cache write: {}", e);
+                    stats.cache_write_errors += 1;
+                    me.send_metrics(&[Metric::Count("cache_write.error", 1)]);
+                }
+                //TODO: save cache stats!
+                Ok(info) => {
+                    debug!("[{}]: Cache write finished in {}.{:03}s", info.object_file, info.duration.as_secs(), info.duration.subsec_nanos() / 1000_000);
+                    stats.cache_writes += 1;
+                    stats.cache_write_duration += info.duration;
+                    me.send_metrics(&[Metric::Count("cache_write.ok", 1),
+                                      Metric::Timing("time.cache_write", info.duration)]);
+                }
+            }
+            Ok(())
+        });
+        self.handle.spawn(task);
+    }
 }
 
+/// How many of the most common reasons for calls not being cacheable
+/// `--show-stats` shows.
+const TOP_NOT_CACHEABLE_REASONS: usize = 5;
+
+/// How many builds to keep the statistics of, see `BuildStats`.
+const MAX_BUILDS: usize = 1000;
+
 /// Statistics about the cache.
 struct ServerStats {
     /// The count of client compile requests.
     pub compile_requests: u64,
     /// The count of client requests that used an unsupported compiler.
     pub requests_unsupported_compiler: u64,
+    /// The count of client requests that used a compiler excluded from
+    /// caching by `SCCACHE_COMPILER_ALLOW` or `SCCACHE_COMPILER_DENY`.
+    pub requests_excluded_compiler: u64,
     /// The count of client requests that were not compilation.
     pub requests_not_compile: u64,
     /// The count of client requests that were not cacheable.
     pub requests_not_cacheable: u64,
+    /// The count of client requests that were not cacheable, by the
+    /// argument or kind of argument that made them so.
+    pub not_cacheable_reasons: BTreeMap<String, u64>,
     /// The count of client requests that were executed.
     pub requests_executed: u64,
     /// The count of errors handling compile requests.
@@ -639,18 +1870,50 @@ struct ServerStats {
     pub non_cacheable_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
     pub forced_recaches: u64,
+    /// The count of compilations run without using the cache at all.
+    pub cache_bypasses: u64,
+    /// The count of cache misses because the cache couldn't be reached.
+    pub cache_unavailable: u64,
     /// The count of errors writing to cache.
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
+    /// The count of cache writes skipped because the cache is read-only.
+    pub cache_writes_skipped: u64,
+    /// The count of cache writes dropped because too many were in progress.
+    pub cache_writes_dropped: u64,
+    /// The count of cache writes skipped because the entry was too large.
+    pub cache_writes_too_large: u64,
     /// The total time spent writing cache entries.
     pub cache_write_duration: Duration,
     /// The total time spent reading cache hits.
     pub cache_read_hit_duration: Duration,
     /// The total time spent reading cache misses.
     pub cache_read_miss_duration: Duration,
+    /// The total time cache hits saved, estimated from how long the
+    /// compiler took to produce each entry.
+    pub time_saved: Duration,
     /// The count of compilation failures.
     pub compile_fails: u64,
+    /// The count of compilations whose compiler or preprocessor was killed
+    /// for running too long.
+    pub compile_timeouts: u64,
+    /// The count of compiler command lines parsed.
+    pub argument_parses: u64,
+    /// The total time spent parsing compiler command lines.
+    pub parse_duration: Duration,
+    /// The count of preprocessor runs.
+    pub preprocessor_runs: u64,
+    /// The total time spent running the preprocessor.
+    pub preprocess_duration: Duration,
+    /// The count of compiler runs.
+    pub compiler_runs: u64,
+    /// The total time spent running the compiler.
+    pub compiler_duration: Duration,
+    /// The durations of executed compile requests.
+    pub compile_duration: Histogram,
+    /// When counting started, in seconds since the epoch.
+    pub since: i64,
 }
 
 impl Default for ServerStats {
@@ -658,100 +1921,459 @@ impl Default for ServerStats {
         ServerStats {
             compile_requests: u64::default(),
             requests_unsupported_compiler: u64::default(),
+            requests_excluded_compiler: u64::default(),
             requests_not_compile: u64::default(),
             requests_not_cacheable: u64::default(),
+            not_cacheable_reasons: BTreeMap::new(),
             requests_executed: u64::default(),
             cache_errors: u64::default(),
             cache_hits: u64::default(),
             cache_misses: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
+            cache_bypasses: u64::default(),
+            cache_unavailable: u64::default(),
             cache_write_errors: u64::default(),
             cache_writes: u64::default(),
+            cache_writes_skipped: u64::default(),
+            cache_writes_dropped: u64::default(),
+            cache_writes_too_large: u64::default(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
+            time_saved: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
             compile_fails: u64::default(),
+            compile_timeouts: u64::default(),
+            argument_parses: u64::default(),
+            parse_duration: Duration::new(0, 0),
+            preprocessor_runs: u64::default(),
+            preprocess_duration: Duration::new(0, 0),
+            compiler_runs: u64::default(),
+            compiler_duration: Duration::new(0, 0),
+            compile_duration: Histogram::default(),
+            since: time::get_time().sec,
         }
     }
 }
 
+/// Call `$m!` with the counters in `ServerStats`, and then with its durations.
+macro_rules! with_persisted_stats {
+    ($counters:ident, $durations:ident) => {
+        $counters!(compile_requests, requests_unsupported_compiler, requests_excluded_compiler,
+                   requests_not_compile, requests_not_cacheable, requests_executed, cache_errors,
+                   cache_hits, cache_misses, non_cacheable_compilations, forced_recaches, cache_bypasses,
+                   cache_unavailable, cache_write_errors, cache_writes, cache_writes_skipped,
+                   cache_writes_dropped, cache_writes_too_large, compile_fails,
+                   compile_timeouts, argument_parses, preprocessor_runs, compiler_runs);
+        $durations!(cache_write_duration, cache_read_hit_duration, cache_read_miss_duration,
+                    time_saved, parse_duration, preprocess_duration, compiler_duration);
+    }
+}
+
 impl ServerStats {
+    /// Convert these statistics to JSON, to be saved. The compile duration
+    /// histogram is only kept while the server runs.
+    fn to_json(&self) -> Value {
+        let stats = self;
+        let mut json = BTreeMap::new();
+        macro_rules! counters {
+            ($($field:ident),*) => {
+                $(json.insert(stringify!($field).to_owned(), Value::U64(stats.$field));)*
+            }
+        }
+        macro_rules! durations {
+            ($($field:ident),*) => {
+                $(json.insert(concat!(stringify!($field), "_ms").to_owned(),
+                              Value::U64(stats.$field.as_secs() * 1000 +
+                                         (stats.$field.subsec_nanos() / 1000_000) as u64));)*
+            }
+        }
+        with_persisted_stats!(counters, durations);
+        let reasons = stats.not_cacheable_reasons.iter()
+            .map(|(why, &count)| (why.clone(), Value::U64(count)))
+            .collect();
+        json.insert("not_cacheable_reasons".to_owned(), Value::Object(reasons));
+        json.insert("since".to_owned(), Value::I64(stats.since));
+        Value::Object(json)
+    }
+
+    /// Read statistics saved by `to_json`. Missing values are zero.
+    fn from_json(json: &Value) -> ServerStats {
+        let mut stats = ServerStats::default();
+        let get = |name: &str| json.find(name).and_then(Value::as_u64).unwrap_or(0);
+        macro_rules! counters {
+            ($($field:ident),*) => {
+                $(stats.$field = get(stringify!($field));)*
+            }
+        }
+        macro_rules! durations {
+            ($($field:ident),*) => {
+                $(stats.$field = Duration::from_millis(get(concat!(stringify!($field), "_ms")));)*
+            }
+        }
+        with_persisted_stats!(counters, durations);
+        if let Some(reasons) = json.find("not_cacheable_reasons").and_then(Value::as_object) {
+            stats.not_cacheable_reasons = reasons.iter()
+                .filter_map(|(why, count)| count.as_u64().map(|count| (why.clone(), count)))
+                .collect();
+        }
+        if let Some(since) = json.find("since").and_then(Value::as_i64) {
+            stats.since = since;
+        }
+        stats
+    }
+
+    /// The `n` most common reasons for requests not being cacheable, most
+    /// common first.
+    fn top_not_cacheable_reasons(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut reasons = self.not_cacheable_reasons.iter()
+            .map(|(why, &count)| (&why[..], count))
+            .collect::<Vec<_>>();
+        // Sorting is stable, so equally common reasons stay in name order.
+        reasons.sort_by(|a, b| b.1.cmp(&a.1));
+        reasons.truncate(n);
+        reasons
+    }
+
     fn to_cache_statistics(&self) -> Vec<CacheStatistic> {
         macro_rules! set_stat {
             ($vec:ident, $var:expr, $name:expr) => {{
-                let mut stat = CacheStatistic::new();
-                stat.set_name(String::from($name));
-                stat.set_count($var);
-                $vec.push(stat);
+                $vec.push(CacheStatistic::count($name, $var));
             }};
         }
 
         macro_rules! set_duration_stat {
             ($vec:ident, $dur:expr, $num:expr, $name:expr) => {{
-                let mut stat = CacheStatistic::new();
-                stat.set_name(String::from($name));
-                if $num > 0 {
+                let value = if $num > 0 {
                     let duration = $dur / $num as u32;
-                    stat.set_str(format!("{}.{:03} s", duration.as_secs(), duration.subsec_nanos() / 1000_000));
+                    format!("{}.{:03} s", duration.as_secs(), duration.subsec_nanos() / 1000_000)
                 } else {
-                    stat.set_str("0.000 s".to_owned());
-                }
-                $vec.push(stat);
+                    "0.000 s".to_owned()
+                };
+                $vec.push(CacheStatistic::str($name, value));
             }};
         }
 
-        let mut stats_vec = vec!();
-        set_stat!(stats_vec, self.compile_requests, "Compile requests");
-        set_stat!(stats_vec, self.requests_executed, "Compile requests executed");
-        set_stat!(stats_vec, self.cache_hits, "Cache hits");
-        set_stat!(stats_vec, self.cache_misses, "Cache misses");
-        set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
-        set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
-        set_stat!(stats_vec, self.compile_fails, "Compilation failures");
-        set_stat!(stats_vec, self.cache_errors, "Cache errors");
-        set_stat!(stats_vec, self.non_cacheable_compilations, "Successful compilations which could not be cached");
-        set_stat!(stats_vec, self.requests_not_cacheable, "Non-cacheable calls");
-        set_stat!(stats_vec, self.requests_not_compile, "Non-compilation calls");
-        set_stat!(stats_vec, self.requests_unsupported_compiler, "Unsupported compiler calls");
-        set_duration_stat!(stats_vec, self.cache_write_duration, self.cache_writes, "Average cache write");
-        set_duration_stat!(stats_vec, self.cache_read_miss_duration, self.cache_misses, "Average cache read miss");
-        set_duration_stat!(stats_vec, self.cache_read_hit_duration, self.cache_hits, "Average cache read hit");
+        let mut requests = vec!();
+        let since = time::at(time::Timespec::new(self.since, 0))
+            .strftime("%Y-%m-%d %H:%M:%S")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        requests.push(CacheStatistic::str("Counting since", since));
+        set_stat!(requests, self.compile_requests, "Compile requests");
+        set_stat!(requests, self.requests_executed, "Compile requests executed");
+        set_stat!(requests, self.requests_not_cacheable, "Non-cacheable calls");
+        for (why, count) in self.top_not_cacheable_reasons(TOP_NOT_CACHEABLE_REASONS) {
+            set_stat!(requests, count, format!("Non-cacheable calls ({})", why));
+        }
+        set_stat!(requests, self.requests_not_compile, "Non-compilation calls");
+        set_stat!(requests, self.requests_unsupported_compiler, "Unsupported compiler calls");
+        set_stat!(requests, self.requests_excluded_compiler, "Excluded compiler calls");
+
+        let mut cache = vec!();
+        set_stat!(cache, self.cache_hits, "Cache hits");
+        set_stat!(cache, self.cache_misses, "Cache misses");
+        cache.push(CacheStatistic::str("Estimated time saved", fmt_time_saved(self.time_saved)));
+        set_stat!(cache, self.forced_recaches, "Forced recaches");
+        set_stat!(cache, self.cache_bypasses, "Cache bypassed (SCCACHE_NO_CACHE)");
+        set_stat!(cache, self.cache_writes_skipped, "Cache writes skipped (read-only)");
+        set_stat!(cache, self.cache_writes_too_large, "Cache writes skipped (too large)");
+        set_stat!(cache, self.non_cacheable_compilations, "Successful compilations which could not be cached");
+
+        let mut errors = vec!();
+        set_stat!(errors, self.cache_unavailable, "Cache misses (cache unavailable)");
+        set_stat!(errors, self.cache_write_errors, "Cache write errors");
+        set_stat!(errors, self.cache_writes_dropped, "Cache writes dropped (queue full)");
+        set_stat!(errors, self.compile_fails, "Compilation failures");
+        set_stat!(errors, self.compile_timeouts, "Compilations timed out");
+        set_stat!(errors, self.cache_errors, "Cache errors");
+
+        let mut timings = vec!();
+        set_duration_stat!(timings, self.cache_write_duration, self.cache_writes, "Average cache write");
+        set_duration_stat!(timings, self.cache_read_miss_duration, self.cache_misses, "Average cache read miss");
+        set_duration_stat!(timings, self.cache_read_hit_duration, self.cache_hits, "Average cache read hit");
+        set_duration_stat!(timings, self.parse_duration, self.argument_parses, "Average argument parsing");
+        set_duration_stat!(timings, self.preprocess_duration, self.preprocessor_runs, "Average preprocessing");
+        set_duration_stat!(timings, self.compiler_duration, self.compiler_runs, "Average compiler run");
+
+        let mut stats_vec = in_group(StatGroup::Requests, requests);
+        stats_vec.extend(in_group(StatGroup::Cache, cache));
+        stats_vec.extend(in_group(StatGroup::Errors, errors));
+        stats_vec.extend(in_group(StatGroup::Timings, timings));
         stats_vec
     }
 }
 
+/// The statistics of the compilations of one build.
+struct BuildStats {
+    /// The count of client compile requests.
+    compile_requests: u64,
+    /// The count of cache hits.
+    cache_hits: u64,
+    /// The count of cache misses, including compilations that couldn't be
+    /// cached, as in `ServerStats`.
+    cache_misses: u64,
+    /// The count of compilation failures.
+    compile_fails: u64,
+    /// The count of errors handling compile requests, including timeouts.
+    cache_errors: u64,
+    /// When counting started, in seconds since the epoch.
+    since: i64,
+    /// When the last compile request of the build arrived.
+    last_seen: Instant,
+}
+
+impl BuildStats {
+    fn new() -> BuildStats {
+        BuildStats {
+            compile_requests: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+            compile_fails: 0,
+            cache_errors: 0,
+            since: time::get_time().sec,
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Count a compilation with the `outcome` it is logged with.
+    fn record(&mut self, outcome: &str) {
+        match outcome {
+            "hit" => self.cache_hits += 1,
+            "miss" | "recache" | "cache_unavailable" | "not_cacheable" => self.cache_misses += 1,
+            "compile_failed" => self.compile_fails += 1,
+            "error" | "timed_out" => self.cache_errors += 1,
+            _ => {}
+        }
+    }
+
+    fn to_cache_statistics(&self, build_id: &str) -> Vec<CacheStatistic> {
+        let since = time::at(time::Timespec::new(self.since, 0))
+            .strftime("%Y-%m-%d %H:%M:%S")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        let mut stats = in_group(StatGroup::Requests, vec!(
+            CacheStatistic::str("Build", build_id.to_owned()),
+            CacheStatistic::str("Counting since", since),
+            CacheStatistic::count("Compile requests", self.compile_requests)));
+        stats.extend(in_group(StatGroup::Cache, vec!(
+            CacheStatistic::count("Cache hits", self.cache_hits),
+            CacheStatistic::count("Cache misses", self.cache_misses))));
+        stats.extend(in_group(StatGroup::Errors, vec!(
+            CacheStatistic::count("Compilation failures", self.compile_fails),
+            CacheStatistic::count("Cache errors", self.cache_errors))));
+        stats
+    }
+}
+
+/// Put each of `stats` in `group`.
+fn in_group(group: StatGroup, stats: Vec<CacheStatistic>) -> Vec<CacheStatistic> {
+    stats.into_iter().map(|s| s.in_group(group)).collect()
+}
+
+/// Format the time saved by cache hits in hours, minutes and seconds.
+fn fmt_time_saved(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Format `duration`, of a phase that may not have run, for logging.
+fn fmt_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(d) => format!("{}.{:03}s", d.as_secs(), d.subsec_nanos() / 1000_000),
+        None => "-".to_owned(),
+    }
+}
+
+/// Load statistics saved to `path`, if there are any.
+fn load_stats(path: &Path) -> Result<Option<ServerStats>> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let json: Value = serde_json::from_str(&contents).chain_err(|| "invalid statistics file")?;
+    Ok(Some(ServerStats::from_json(&json)))
+}
+
+/// Save `stats` to `path`, replacing it atomically.
+fn save_stats(path: &Path, stats: &ServerStats) {
+    let tmp = path.with_extension(format!("json.tmp{}", process::id()));
+    let res = serde_json::to_string(&stats.to_json())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .and_then(|json| File::create(&tmp).and_then(|mut f| f.write_all(json.as_bytes())))
+        .and_then(|()| fs::rename(&tmp, path));
+    if let Err(e) = res {
+        warn!("Failed to save statistics to {}: {}", path.display(), e);
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+/// Log the compilation `id` of `out_file` by `executable` having ended
+/// with `outcome`, with the durations of its phases, as an event for JSON
+/// logs.
+fn log_compile_event(id: u64,
+                     executable: &str,
+                     out_file: &str,
+                     outcome: &str,
+                     retcode: Option<i32>,
+                     parse_time: Duration,
+                     timings: &PhaseTimings,
+                     total: Duration) {
+    let mut fields = BTreeMap::new();
+    fields.insert("event".to_owned(), Value::String("compile".to_owned()));
+    fields.insert("id".to_owned(), Value::U64(id));
+    fields.insert("compiler".to_owned(), Value::String(executable.to_owned()));
+    fields.insert("output".to_owned(), Value::String(out_file.to_owned()));
+    fields.insert("outcome".to_owned(), Value::String(outcome.to_owned()));
+    if let Some(retcode) = retcode {
+        fields.insert("status".to_owned(), Value::I64(retcode as i64));
+    }
+    fields.insert("parse_ms".to_owned(), logging::millis(parse_time));
+    for &(name, duration) in &[("preprocess_ms", timings.preprocess),
+                               ("cache_lookup_ms", timings.cache_lookup),
+                               ("compile_ms", timings.compile)] {
+        if let Some(duration) = duration {
+            fields.insert(name.to_owned(), logging::millis(duration));
+        }
+    }
+    fields.insert("total_ms".to_owned(), logging::millis(total));
+    let message = format!("[{}]: Compile {} finished: {} in {}", out_file, id, outcome,
+                          fmt_duration(Some(total)));
+    logging::log_event(LogLevel::Info, "sccache::server", &message, fields);
+}
+
+/// Format the statistics of a storage backend for `--show-stats`.
+fn backend_statistics(backend: &BackendStats) -> Vec<CacheStatistic> {
+    fn latencies(latencies: &Latencies) -> String {
+        let ms = |p| latencies.percentile(p).map(|d| {
+            d.as_secs() * 1000 + (d.subsec_nanos() / 1000_000) as u64
+        }).unwrap_or(0);
+        format!("{}/{}/{} ms", ms(50), ms(90), ms(99))
+    }
+
+    let mut stats_vec = vec!();
+    for &(name, count) in [("reads", backend.reads),
+                           ("read errors", backend.read_errors),
+                           ("writes", backend.writes),
+                           ("write errors", backend.write_errors)].iter() {
+        stats_vec.push(CacheStatistic::count(format!("{} {}", backend.name, name), count));
+    }
+    for &(name, size) in [("bytes read", backend.bytes_read),
+                          ("bytes written", backend.bytes_written)].iter() {
+        stats_vec.push(CacheStatistic::size(format!("{} {}", backend.name, name), size));
+    }
+    for &(name, l) in [("read latency p50/p90/p99", &backend.read_latencies),
+                           ("write latency p50/p90/p99", &backend.write_latencies)].iter() {
+        stats_vec.push(CacheStatistic::str(format!("{} {}", backend.name, name), latencies(l)));
+    }
+    in_group(StatGroup::Backends, stats_vec)
+}
+
+/// Render metrics about a server in the Prometheus text format.
+fn render_metrics(stats: &ServerStats,
+                  storage: &Storage,
+                  active_compiles: usize,
+                  pending_writes: usize) -> String {
+    let mut metrics = Metrics::new();
+    for &(name, help, value) in [
+        ("sccache_compile_requests_total", "Compile requests received.", stats.compile_requests),
+        ("sccache_compile_requests_executed_total", "Compile requests executed.", stats.requests_executed),
+        ("sccache_cache_hits_total", "Cache hits.", stats.cache_hits),
+        ("sccache_cache_misses_total", "Cache misses.", stats.cache_misses),
+        ("sccache_time_saved_seconds_total", "Compilation time saved by cache hits, estimated.", stats.time_saved.as_secs()),
+        ("sccache_forced_recaches_total", "Cache misses forced by SCCACHE_RECACHE.", stats.forced_recaches),
+        ("sccache_cache_bypasses_total", "Compilations run without the cache because of SCCACHE_NO_CACHE.", stats.cache_bypasses),
+        ("sccache_cache_unavailable_total", "Cache misses because the cache couldn't be reached.", stats.cache_unavailable),
+        ("sccache_cache_errors_total", "Errors handling compile requests.", stats.cache_errors),
+        ("sccache_cache_writes_total", "Cache entries stored.", stats.cache_writes),
+        ("sccache_cache_write_errors_total", "Errors storing cache entries.", stats.cache_write_errors),
+        ("sccache_cache_writes_skipped_total", "Cache writes skipped because the cache is read-only.", stats.cache_writes_skipped),
+        ("sccache_cache_writes_dropped_total", "Cache writes dropped because too many were in progress.", stats.cache_writes_dropped),
+        ("sccache_cache_writes_too_large_total", "Cache writes skipped because the entry was too large.", stats.cache_writes_too_large),
+        ("sccache_compile_failures_total", "Compilations that failed.", stats.compile_fails),
+        ("sccache_compile_timeouts_total", "Compilations killed for running too long.", stats.compile_timeouts),
+        ("sccache_non_cacheable_compilations_total", "Successful compilations which could not be cached.", stats.non_cacheable_compilations),
+        ("sccache_requests_not_cacheable_total", "Non-cacheable calls.", stats.requests_not_cacheable),
+        ("sccache_requests_not_compile_total", "Non-compilation calls.", stats.requests_not_compile),
+        ("sccache_requests_unsupported_compiler_total", "Calls with an unsupported compiler.", stats.requests_unsupported_compiler),
+        ("sccache_requests_excluded_compiler_total", "Calls with a compiler excluded from caching.", stats.requests_excluded_compiler),
+    ].iter() {
+        metrics.counter(name, help, &[(String::new(), value)]);
+    }
+    let reasons = stats.not_cacheable_reasons.iter()
+        .map(|(why, &count)| (label("reason", why), count))
+        .collect::<Vec<_>>();
+    metrics.counter("sccache_requests_not_cacheable_by_reason_total",
+                    "Non-cacheable calls, by the argument or kind of argument that made them so.",
+                    &reasons);
+    metrics.histogram("sccache_compile_duration_seconds", "Time taken by executed compile requests.",
+                      &[(String::new(), &stats.compile_duration)]);
+    metrics.gauge("sccache_active_compiles", "Compilations in progress.",
+                  &[(String::new(), active_compiles as u64)]);
+    metrics.gauge("sccache_pending_cache_writes", "Cache writes in progress.",
+                  &[(String::new(), pending_writes as u64)]);
+    if let Some(size) = storage.current_size() {
+        metrics.gauge("sccache_cache_size_bytes", "Size of the local cache.", &[(String::new(), size as u64)]);
+    }
+    if let Some(size) = storage.max_size() {
+        metrics.gauge("sccache_cache_max_size_bytes", "Maximum size of the local cache.", &[(String::new(), size as u64)]);
+    }
+    if let Some(entries) = storage.entry_count() {
+        metrics.gauge("sccache_cache_entries", "Entries in the local cache.", &[(String::new(), entries as u64)]);
+    }
+
+    let backends = storage.backend_stats();
+    let backends = backends.iter().map(|b| b.borrow()).collect::<Vec<_>>();
+    let counter = |metrics: &mut Metrics, name, help, value: &Fn(&BackendStats) -> u64| {
+        let values = backends.iter().map(|b| (label("backend", &b.name), value(b))).collect::<Vec<_>>();
+        metrics.counter(name, help, &values);
+    };
+    counter(&mut metrics, "sccache_backend_reads_total", "Lookups in each storage backend.", &|b| b.reads);
+    counter(&mut metrics, "sccache_backend_read_errors_total", "Failed lookups in each storage backend.", &|b| b.read_errors);
+    counter(&mut metrics, "sccache_backend_writes_total", "Entries stored in each storage backend.", &|b| b.writes);
+    counter(&mut metrics, "sccache_backend_write_errors_total", "Failed writes to each storage backend.", &|b| b.write_errors);
+    counter(&mut metrics, "sccache_backend_read_bytes_total", "Size of the entries found in each storage backend.", &|b| b.bytes_read);
+    counter(&mut metrics, "sccache_backend_written_bytes_total", "Size of the entries stored in each storage backend.", &|b| b.bytes_written);
+    let histograms = backends.iter().map(|b| (label("backend", &b.name), &b.read_histogram)).collect::<Vec<_>>();
+    metrics.histogram("sccache_backend_read_duration_seconds", "Time taken by lookups in each storage backend.", &histograms);
+    let histograms = backends.iter().map(|b| (label("backend", &b.name), &b.write_histogram)).collect::<Vec<_>>();
+    metrics.histogram("sccache_backend_write_duration_seconds", "Time taken by writes to each storage backend.", &histograms);
+    metrics.into_string()
+}
+
 /// tokio-proto protocol implementation for sccache
 struct SccacheProto;
 
 impl<I> ServerProto<I> for SccacheProto
     where I: Io + 'static,
 {
-    type Request = ClientRequest;
+    type Request = Request;
     type RequestBody = ();
-    type Response = ServerResponse;
-    type ResponseBody = ServerResponse;
+    type Response = Response;
+    type ResponseBody = Response;
     type Error = Error;
     type Transport = SccacheTransport<I>;
     type BindTransport = future::FutureResult<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: I) -> Self::BindTransport {
         future::ok(SccacheTransport {
-            inner: io.framed(ProtobufCodec::new()),
+            inner: io.framed(SccacheCodec),
         })
     }
 }
 
 /// Implementation of `Stream + Sink` that tokio-proto is expecting. This takes
-/// a `Framed` instance using `ProtobufCodec` and performs a simple map
-/// operation on the sink/stream halves to translate the protobuf message types
+/// a `Framed` instance using `SccacheCodec` and performs a simple map
+/// operation on the sink/stream halves to translate the protocol message types
 /// to the `Frame` types that tokio-proto expects.
 struct SccacheTransport<I> {
-    inner: Framed<I, ProtobufCodec<ClientRequest, ServerResponse>>,
+    inner: Framed<I, SccacheCodec>,
 }
 
 impl<I: Io> Stream for SccacheTransport<I> {
-    type Item = Frame<ClientRequest, (), Error>;
+    type Item = Frame<Request, (), Error>;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
@@ -766,7 +2388,7 @@ impl<I: Io> Stream for SccacheTransport<I> {
 }
 
 impl<I: Io> Sink for SccacheTransport<I> {
-    type SinkItem = Frame<ServerResponse, ServerResponse, Error>;
+    type SinkItem = Frame<Response, Response, Error>;
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: Self::SinkItem)
@@ -811,70 +2433,57 @@ impl<I: Io> Sink for SccacheTransport<I> {
 
 impl<I: Io + 'static> Transport for SccacheTransport<I> {}
 
-/// Simple tokio-core `Codec` which uses stock protobuf functions to
-/// decode/encode protobuf messages.
-struct ProtobufCodec<Request, Response> {
-    _marker: marker::PhantomData<fn() -> (Request, Response)>,
-}
-
-impl<Request, Response> ProtobufCodec<Request, Response>
-    where Request: protobuf::Message + protobuf::MessageStatic,
-          Response: protobuf::Message,
-{
-    fn new() -> ProtobufCodec<Request, Response> {
-        ProtobufCodec { _marker: marker::PhantomData }
-    }
-}
+/// tokio-core `Codec` for the frames of `protocol`.
+///
+/// Clients speaking another protocol version can only shut the server down,
+/// the connection is closed on any other request from them.
+struct SccacheCodec;
 
-impl<Request, Response> Codec for ProtobufCodec<Request, Response>
-    where Request: protobuf::Message + protobuf::MessageStatic,
-          Response: protobuf::Message,
-{
+impl Codec for SccacheCodec {
     type In = Request;
     type Out = Response;
 
     fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Request>> {
-        if buf.as_slice().len() == 0 {
+        if buf.len() == 0 {
             return Ok(None)
         }
-        match parse_length_delimited_from_bytes::<Request>(buf.as_slice()) {
-            Ok(req) => {
-                let size = req.write_length_delimited_to_bytes().unwrap().len();
-                buf.drain_to(size);
-                Ok(Some(req))
-            }
-            // Unexpected EOF is OK, just means we haven't read enough
-            // bytes. It would be nice if this were discriminated more
-            // usefully.
-            // Issue filed: https://github.com/stepancheg/rust-protobuf/issues/154
-            Err(ProtobufError::WireError(s)) => {
-                if s == "truncated message" {
-                    Ok(None)
-                } else {
-                    Err(io::Error::new(io::ErrorKind::Other, s))
+        let version = buf.as_slice()[0];
+        if version != PROTOCOL_VERSION {
+            return match protocol::is_shutdown_frame(buf.as_slice()) {
+                Some(true) => {
+                    let (_, len) = protocol::parse_header(buf.as_slice());
+                    buf.drain_to(HEADER_LEN + len);
+                    Ok(Some(Request::Shutdown))
                 }
+                Some(false) => {
+                    Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("request from a client of protocol version {}", version)))
+                }
+                None => Ok(None),
             }
-            Err(ProtobufError::IoError(ioe)) => Err(ioe),
-            Err(ProtobufError::MessageNotInitialized { message }) => {
-                Err(io::Error::new(io::ErrorKind::Other, message))
-            }
         }
+        if buf.len() < HEADER_LEN {
+            return Ok(None)
+        }
+        let (_, len) = protocol::parse_header(buf.as_slice());
+        protocol::check_message_len(len)?;
+        if buf.len() < HEADER_LEN + len {
+            return Ok(None)
+        }
+        let frame = buf.drain_to(HEADER_LEN + len);
+        protocol::decode(&frame.as_slice()[HEADER_LEN..]).map(Some)
     }
 
     fn encode(&mut self, msg: Response, buf: &mut Vec<u8>) -> io::Result<()> {
-        let bytes = msg.write_length_delimited_to_bytes().map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, e)
-        })?;
-        buf.extend_from_slice(&bytes);
-        Ok(())
+        protocol::encode(&msg, buf)
     }
 }
 
 struct ShutdownOrInactive {
     rx: mpsc::Receiver<ServerMessage>,
     handle: Handle,
-    timeout: Timeout,
-    timeout_dur: Duration,
+    timeout: Option<Timeout>,
+    timeout_dur: Option<Duration>,
 }
 
 impl Future for ShutdownOrInactive {
@@ -888,16 +2497,60 @@ impl Future for ShutdownOrInactive {
                 // Shutdown received!
                 Async::Ready(Some(ServerMessage::Shutdown)) => return Ok(().into()),
                 Async::Ready(Some(ServerMessage::Request)) => {
-                    self.timeout = Timeout::new(self.timeout_dur, &self.handle)?;
+                    if let Some(timeout_dur) = self.timeout_dur {
+                        self.timeout = Some(Timeout::new(timeout_dur, &self.handle)?);
+                    }
                 }
                 // All services have shut down, in theory this isn't possible...
                 Async::Ready(None) => return Ok(().into()),
             }
         }
-        self.timeout.poll()
+        match self.timeout {
+            Some(ref mut timeout) => timeout.poll(),
+            None => Ok(Async::NotReady),
+        }
     }
 }
 
+/// Compilations waiting for a job slot. Interactive compilations are
+/// taken before batch ones, and each in the order they arrived.
+struct JobQueue<T> {
+    interactive: VecDeque<T>,
+    batch: VecDeque<T>,
+}
+
+impl<T> JobQueue<T> {
+    fn new() -> JobQueue<T> {
+        JobQueue {
+            interactive: VecDeque::new(),
+            batch: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, priority: Priority, job: T) {
+        match priority {
+            Priority::Interactive => self.interactive.push_back(job),
+            Priority::Batch => self.batch.push_back(job),
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        self.interactive.pop_front().or_else(|| self.batch.pop_front())
+    }
+
+    fn len(&self) -> usize {
+        self.interactive.len() + self.batch.len()
+    }
+}
+
+/// A compilation in progress.
+struct CompileActivity {
+    executable: String,
+    input: String,
+    start: Instant,
+    phase: CompilePhase,
+}
+
 /// Helper future which tracks the `ActiveInfo` below. This future will resolve
 /// once all instances of `ActiveInfo` have been dropped.
 struct WaitUntilZero {
@@ -957,3 +2610,91 @@ impl Future for WaitUntilZero {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test::utils::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    #[test]
+    fn test_config_id() {
+        let id = config_id(vars(&[("SCCACHE_BUCKET", "a"), ("SCCACHE_DIR", "/tmp/cache")]).into_iter());
+        // The order of settings, unrelated settings, and those selecting
+        // or started with the server don't matter.
+        assert_eq!(id, config_id(vars(&[("SCCACHE_DIR", "/tmp/cache"), ("HOME", "/home/me"),
+                                          ("SCCACHE_SERVER_ID", "auto"), ("SCCACHE_START_SERVER", "1"),
+                                          ("SCCACHE_BUCKET", "a")]).into_iter()));
+        // Nor do settings only the client reads.
+        assert_eq!(id, config_id(vars(&[("SCCACHE_BUCKET", "a"), ("SCCACHE_DIR", "/tmp/cache"),
+                                          ("SCCACHE_RECACHE", "1"), ("SCCACHE_PRIORITY", "batch")]).into_iter()));
+        assert!(id != config_id(vars(&[("SCCACHE_BUCKET", "b"), ("SCCACHE_DIR", "/tmp/cache")]).into_iter()));
+    }
+
+    #[test]
+    fn test_job_queue() {
+        let mut queue = JobQueue::new();
+        queue.push(Priority::Batch, 1);
+        queue.push(Priority::Batch, 2);
+        queue.push(Priority::Interactive, 3);
+        queue.push(Priority::Interactive, 4);
+        assert_eq!(vec!(3, 4, 1, 2), (0..5).filter_map(|_| queue.pop_front()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_instance_port() {
+        let port = instance_port("project-a", 4226);
+        assert!(port > 4226 && port <= 5226);
+        assert_eq!(port, instance_port("project-a", 4226));
+    }
+
+    #[test]
+    fn test_persist_stats() {
+        let f = TestFixture::new();
+        let path = f.tempdir.path().join(".stats.json");
+        assert!(load_stats(&path).unwrap().is_none());
+        let mut stats = ServerStats::default();
+        stats.cache_hits = 3;
+        stats.not_cacheable_reasons.insert("-fmodules".to_owned(), 2);
+        stats.cache_write_duration = Duration::from_millis(1500);
+        stats.time_saved = Duration::from_secs(3725);
+        stats.since = 1000;
+        save_stats(&path, &stats);
+        let loaded = load_stats(&path).unwrap().unwrap();
+        assert_eq!(3, loaded.cache_hits);
+        assert_eq!(0, loaded.cache_misses);
+        assert_eq!(Duration::from_millis(1500), loaded.cache_write_duration);
+        assert_eq!("1h 02m 05s", fmt_time_saved(loaded.time_saved));
+        assert_eq!(1000, loaded.since);
+        assert_eq!(stats.not_cacheable_reasons, loaded.not_cacheable_reasons);
+    }
+
+    #[test]
+    fn test_build_stats() {
+        let mut stats = BuildStats::new();
+        for outcome in &["hit", "hit", "miss", "not_cacheable", "compile_failed", "timed_out", "bypassed"] {
+            stats.record(outcome);
+        }
+        let stats = stats.to_cache_statistics("ci-42");
+        assert_eq!(CacheStatistic::str("Build", "ci-42".to_owned()).in_group(StatGroup::Requests), stats[0]);
+        assert_eq!(vec!(CacheStatistic::count("Cache hits", 2).in_group(StatGroup::Cache),
+                        CacheStatistic::count("Cache misses", 2).in_group(StatGroup::Cache),
+                        CacheStatistic::count("Compilation failures", 1).in_group(StatGroup::Errors),
+                        CacheStatistic::count("Cache errors", 1).in_group(StatGroup::Errors)),
+                   &stats[3..]);
+    }
+
+    #[test]
+    fn test_top_not_cacheable_reasons() {
+        let mut stats = ServerStats::default();
+        for &(why, count) in [("-fmodules", 1), ("@", 3), ("multiple input files", 2),
+                              ("no output file", 2)].iter() {
+            stats.not_cacheable_reasons.insert(why.to_owned(), count);
+        }
+        assert_eq!(vec!(("@", 3), ("multiple input files", 2), ("no output file", 2)),
+                   stats.top_not_cacheable_reasons(3));
+    }
+}
@@ -5,9 +5,10 @@
 use chrono::{Duration, UTC, DateTime};
 use futures::{Future, Async, IntoFuture, Stream};
 use futures::future::{self, Shared};
+use http::{HttpsClient, https_client};
 use hyper::{self, Client, Method};
 use hyper::client::{HttpConnector, Request};
-use hyper::header::Connection;
+use hyper::header::{Connection, ContentLength, ContentType};
 use regex::Regex;
 use serde_json::{Value, from_str};
 use std::ascii::AsciiExt;
@@ -407,6 +408,121 @@ impl ProvideAwsCredentials for IamProvider {
     }
 }
 
+/// How long before STS credentials expire to replace them.
+const WEB_IDENTITY_REFRESH_MARGIN: i64 = 300;
+
+/// Provides AWS credentials for a role by exchanging a web identity (OIDC)
+/// token with STS, as set up by EKS service accounts or GitHub Actions.
+///
+/// The token is read from the file named by `AWS_WEB_IDENTITY_TOKEN_FILE` on
+/// each exchange, since it is rotated in place, and the role from `AWS_ROLE_ARN`.
+pub struct WebIdentityProvider {
+    client: HttpsClient,
+    token_file: PathBuf,
+    role_arn: String,
+    session_name: String,
+    endpoint: String,
+}
+
+impl WebIdentityProvider {
+    /// Create a new `WebIdentityProvider` from the environment, if it is
+    /// configured there.
+    pub fn from_environment(handle: &Handle) -> Option<WebIdentityProvider> {
+        let token_file = match var_os("AWS_WEB_IDENTITY_TOKEN_FILE") {
+            Some(ref f) if !f.is_empty() => PathBuf::from(f),
+            _ => return None,
+        };
+        let role_arn = match var("AWS_ROLE_ARN") {
+            Ok(ref arn) if !arn.is_empty() => arn.to_owned(),
+            _ => return None,
+        };
+        let session_name = var("AWS_ROLE_SESSION_NAME").unwrap_or("sccache".to_owned());
+        let endpoint = match var("AWS_REGION").or(var("AWS_DEFAULT_REGION")) {
+            Ok(ref region) if !region.is_empty() => format!("https://sts.{}.amazonaws.com/", region),
+            _ => "https://sts.amazonaws.com/".to_owned(),
+        };
+        Some(WebIdentityProvider {
+            client: https_client(handle),
+            token_file: token_file,
+            role_arn: role_arn,
+            session_name: session_name,
+            endpoint: endpoint,
+        })
+    }
+
+    fn token(&self) -> Result<String> {
+        let mut token = String::new();
+        File::open(&self.token_file)
+            .and_then(|mut f| f.read_to_string(&mut token))
+            .chain_err(|| format!("failed to read {}", self.token_file.display()))?;
+        Ok(token.trim().to_owned())
+    }
+}
+
+impl ProvideAwsCredentials for WebIdentityProvider {
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        let token = match self.token() {
+            Ok(token) => token,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let body = format!("Action=AssumeRoleWithWebIdentity&Version=2011-06-15&RoleArn={}&RoleSessionName={}&WebIdentityToken={}",
+                           url_encode(&self.role_arn), url_encode(&self.session_name), url_encode(&token));
+        let mut req = Request::new(Method::Post, self.endpoint.parse().unwrap());
+        req.headers_mut().set(ContentType::form_url_encoded());
+        req.headers_mut().set(ContentLength(body.len() as u64));
+        req.set_body(body.into_bytes());
+        debug!("Assuming role {} with web identity", self.role_arn);
+        let response = self.client.request(req).chain_err(|| {
+            "failed to send STS request"
+        }).and_then(|response| -> SFuture<Vec<u8>> {
+            if response.status().class() != hyper::status::StatusClass::Success {
+                return future::err(ErrorKind::BadHTTPStatus(response.status().clone()).into()).boxed();
+            }
+            Box::new(response.body().fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read STS response"
+            }))
+        });
+        Box::new(response.and_then(|body| {
+            let body = String::from_utf8(body).chain_err(|| "STS response is not UTF-8")?;
+            parse_assume_role_response(&body)
+        }))
+    }
+}
+
+/// Parse the credentials out of an `AssumeRoleWithWebIdentity` response.
+fn parse_assume_role_response(body: &str) -> Result<AwsCredentials> {
+    let element = |name: &str| -> Result<String> {
+        let re = Regex::new(&format!("<{0}>([^<]*)</{0}>", name)).unwrap();
+        match re.captures(body).and_then(|c| c.at(1)) {
+            Some(value) => Ok(value.trim().to_owned()),
+            None => bail!("Couldn't find {} in STS response.", name),
+        }
+    };
+    let expiration: DateTime<UTC> = element("Expiration")?.parse().chain_err(|| {
+        "failed to parse expiration time"
+    })?;
+    // Replace the credentials well before they expire, so that requests
+    // already signed with them don't fail.
+    let expires_at = expiration - Duration::seconds(WEB_IDENTITY_REFRESH_MARGIN);
+    Ok(AwsCredentials::new(element("AccessKeyId")?, element("SecretAccessKey")?,
+                           Some(element("SessionToken")?), expires_at))
+}
+
+/// Percent-encode `s` for use in a form body.
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
 /// Wrapper for ProvideAwsCredentials that caches the credentials returned by the
 /// wrapped provider.  Each time the credentials are accessed, they are checked to see if
 /// they have expired, in which case they are retrieved from the wrapped provider again.
@@ -446,8 +562,9 @@ impl <P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider
 /// The following sources are checked in order for credentials when calling `credentials`:
 ///
 /// 1. Environment variables: `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
-/// 2. AWS credentials file. Usually located at `~/.aws/credentials`.
-/// 3. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
+/// 2. A web identity token exchanged with STS: `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`
+/// 3. AWS credentials file. Usually located at `~/.aws/credentials`.
+/// 4. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
 ///
 /// If the sources are exhausted without finding credentials, an error is returned.
 #[derive(Clone)]
@@ -463,6 +580,13 @@ impl ProvideAwsCredentials for ChainProvider {
             c
         });
         let mut creds = Box::new(creds) as SFuture<_>;
+        if let Some(provider) = WebIdentityProvider::from_environment(&self.handle) {
+            let alternate = provider.credentials().map(|c| {
+                debug!("Using AWS credentials from web identity");
+                c
+            });
+            creds = Box::new(creds.or_else(|_| alternate));
+        }
         for provider in self.profile_providers.iter() {
             let alternate = provider.credentials();
             creds = Box::new(creds.or_else(|_| alternate));
@@ -474,7 +598,7 @@ impl ProvideAwsCredentials for ChainProvider {
                 c
             })
         }).map_err(|_| {
-		    "Couldn't find AWS credentials in environment, credentials file, or IAM role.".into()
+		    "Couldn't find AWS credentials in environment, web identity, credentials file, or IAM role.".into()
         }))
     }
 }
@@ -3,16 +3,18 @@
 
 use std::ascii::AsciiExt;
 use std::fmt;
+use std::rc::Rc;
 
 use crypto::digest::Digest;
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha1::Sha1;
 use futures::{Future, Stream};
+use futures::future;
+use http::{HttpsClient, https_client};
 use hyper::{self, header};
 use hyper::Method;
-use hyper::client::{Client, Request};
-use hyper_tls::HttpsConnector;
+use hyper::client::Request;
 use rustc_serialize::base64::{ToBase64, STANDARD};
 use simples3::credential::*;
 use time;
@@ -30,6 +32,9 @@ pub enum Ssl {
     No,
 }
 
+/// How many times to retry the upload of a single part of a multipart upload.
+const PART_RETRIES: usize = 2;
+
 fn base_url(endpoint: &str, ssl: Ssl) -> String {
     format!("{}://{}/",
             match ssl {
@@ -53,7 +58,7 @@ fn signature(string_to_sign: &str, signing_key: &str) -> String {
 pub struct Bucket {
     name: String,
     base_url: String,
-    client: Client<HttpsConnector>,
+    client: HttpsClient,
 }
 
 impl fmt::Display for Bucket {
@@ -68,9 +73,7 @@ impl Bucket {
         Bucket {
             name: name.to_owned(),
             base_url: base_url,
-            client: Client::configure()
-                        .connector(HttpsConnector::new(1, handle))
-                        .build(handle),
+            client: https_client(handle),
         }
     }
 
@@ -95,20 +98,27 @@ impl Bucket {
         }))
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials)
-               -> SFuture<()> {
-        let url = format!("{}{}", self.base_url, key);
-        debug!("PUT {}", url);
-        let mut request = Request::new(Method::Put, url.parse().unwrap());
+    /// Build a signed request for `key`, with `subresource` (such as `uploads`)
+    /// appended to the URL and included in the signature.
+    fn signed_request(&self, method: Method, key: &str, subresource: &str, content_type: &str,
+                      storage_class: bool, creds: &AwsCredentials) -> Request {
+        let path = if subresource.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}?{}", key, subresource)
+        };
+        let url = format!("{}{}", self.base_url, path);
+        debug!("{} {}", method, url);
+        let mut request = Request::new(method.clone(), url.parse().unwrap());
 
-        let content_type = "application/octet-stream";
         let date = time::now().rfc822z().to_string();
         let mut canonical_headers = String::new();
         let token = creds.token().as_ref().map(|s| s.as_str());
+        let storage_class = if storage_class { Some("REDUCED_REDUNDANCY") } else { None };
         // Keep the list of header values sorted!
         for (header, maybe_value) in vec![
             ("x-amz-security-token", token),
-            ("x-amz-storage-class", Some("REDUCED_REDUNDANCY")),
+            ("x-amz-storage-class", storage_class),
             ] {
             if let Some(ref value) = maybe_value {
                 request.headers_mut()
@@ -116,15 +126,24 @@ impl Bucket {
                 canonical_headers.push_str(format!("{}:{}\n", header.to_ascii_lowercase(), value).as_ref());
             }
         }
-        let auth = self.auth("PUT", &date, key, "", &canonical_headers, content_type, creds);
+        let auth = self.auth(&method.to_string(), &date, &path, "", &canonical_headers, content_type, creds);
         request.headers_mut().set_raw("Date", vec!(date.into_bytes()));
-        request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
+        if !content_type.is_empty() {
+            request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
+        }
+        request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
+        request
+    }
+
+    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials)
+               -> SFuture<()> {
+        let mut request = self.signed_request(Method::Put, key, "", "application/octet-stream",
+                                              true, creds);
         request.headers_mut().set(header::ContentLength(content.len() as u64));
         request.headers_mut().set(header::CacheControl(vec![
             // Two weeks
             header::CacheDirective::MaxAge(1296000)
         ]));
-        request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
         request.set_body(content);
 
         Box::new(self.client.request(request).then(|result| {
@@ -146,6 +165,74 @@ impl Bucket {
         }))
     }
 
+    /// Start a multipart upload to `key`, returning its upload id.
+    fn initiate_multipart(&self, key: &str, creds: &AwsCredentials) -> SFuture<String> {
+        let mut request = self.signed_request(Method::Post, key, "uploads",
+                                              "application/octet-stream", true, creds);
+        request.headers_mut().set(header::CacheControl(vec![
+            // Two weeks
+            header::CacheDirective::MaxAge(1296000)
+        ]));
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed to start multipart upload"
+        }).and_then(read_body).and_then(|body| {
+            let body = String::from_utf8_lossy(&body);
+            xml_element(&body, "UploadId").map(|s| s.to_owned()).ok_or(
+                "no UploadId in multipart upload response".into())
+        }))
+    }
+
+    /// Upload part `number` of the multipart upload `upload_id`, returning its ETag.
+    fn upload_part(&self, key: &str, upload_id: &str, number: usize, content: Vec<u8>,
+                   creds: &AwsCredentials) -> SFuture<String> {
+        let subresource = format!("partNumber={}&uploadId={}", number, upload_id);
+        let mut request = self.signed_request(Method::Put, key, &subresource, "", false, creds);
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+        Box::new(self.client.request(request).chain_err(move || {
+            format!("failed to upload part {}", number)
+        }).and_then(|res| {
+            if res.status().class() != hyper::status::StatusClass::Success {
+                return Err(ErrorKind::BadHTTPStatus(res.status().clone()).into());
+            }
+            match res.headers().get::<header::ETag>() {
+                Some(etag) => Ok(format!("\"{}\"", etag.tag())),
+                None => Err("no ETag in part upload response".into()),
+            }
+        }))
+    }
+
+    /// Finish the multipart upload `upload_id` from the ETags of its parts, in order.
+    fn complete_multipart(&self, key: &str, upload_id: &str, etags: Vec<String>,
+                          creds: &AwsCredentials) -> SFuture<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            body.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                                   i + 1, etag));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let subresource = format!("uploadId={}", upload_id);
+        let mut request = self.signed_request(Method::Post, key, &subresource, "", false, creds);
+        request.headers_mut().set(header::ContentLength(body.len() as u64));
+        request.set_body(body.into_bytes());
+        Box::new(self.client.request(request).chain_err(|| {
+            "failed to complete multipart upload"
+        }).and_then(read_body).and_then(|body| {
+            // Errors can be reported in a successful response.
+            if String::from_utf8_lossy(&body).contains("<Error>") {
+                bail!("multipart upload failed to complete");
+            }
+            Ok(())
+        }))
+    }
+
+    /// Abandon the multipart upload `upload_id`, so its parts aren't stored.
+    fn abort_multipart(&self, key: &str, upload_id: &str, creds: &AwsCredentials) -> SFuture<()> {
+        let subresource = format!("uploadId={}", upload_id);
+        let request = self.signed_request(Method::Delete, key, &subresource, "", false, creds);
+        Box::new(self.client.request(request).then(|_| Ok(())))
+    }
+
     // http://docs.aws.amazon.com/AmazonS3/latest/dev/RESTAuthentication.html
     fn auth(&self, verb: &str, date: &str, path: &str,
             md5: &str, headers: &str, content_type: &str, creds: &AwsCredentials) -> String {
@@ -160,3 +247,70 @@ impl Bucket {
         format!("AWS {}:{}", creds.aws_access_key_id(), signature)
     }
 }
+
+fn read_body(res: hyper::client::Response) -> SFuture<Vec<u8>> {
+    if res.status().class() != hyper::status::StatusClass::Success {
+        return Box::new(future::err(ErrorKind::BadHTTPStatus(res.status().clone()).into()));
+    }
+    Box::new(res.body().fold(Vec::new(), |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        Ok::<_, hyper::Error>(body)
+    }).chain_err(|| {
+        "failed to read HTTP body"
+    }))
+}
+
+/// Get the text of the first `name` element in `xml`.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    xml.find(&open).and_then(|start| {
+        let start = start + open.len();
+        xml[start..].find(&close).map(|end| &xml[start..start + end])
+    })
+}
+
+/// Upload part `number` of `upload_id`, retrying up to `retries` more times on failure.
+fn upload_part_with_retry(bucket: Rc<Bucket>, key: String, upload_id: String, number: usize,
+                          content: Vec<u8>, creds: AwsCredentials, retries: usize)
+                          -> SFuture<String> {
+    let part = bucket.upload_part(&key, &upload_id, number, content.clone(), &creds);
+    Box::new(part.or_else(move |e| {
+        if retries == 0 {
+            return Box::new(future::err(e)) as SFuture<_>;
+        }
+        warn!("Retrying upload of part {}: {}", number, e);
+        upload_part_with_retry(bucket, key, upload_id, number, content, creds, retries - 1)
+    }))
+}
+
+/// Upload `content` to `key` in `bucket` as a multipart upload of parts of
+/// `part_size` bytes, uploaded in parallel.
+pub fn put_multipart(bucket: Rc<Bucket>, key: &str, content: Vec<u8>, creds: &AwsCredentials,
+                     part_size: usize) -> SFuture<()> {
+    let key = key.to_owned();
+    let creds = creds.clone();
+    let start = bucket.initiate_multipart(&key, &creds);
+    Box::new(start.and_then(move |upload_id| {
+        debug!("Uploading {} in {} parts", key, (content.len() + part_size - 1) / part_size);
+        let parts = content.chunks(part_size).enumerate().map(|(i, chunk)| {
+            // Part numbers start at 1.
+            upload_part_with_retry(bucket.clone(), key.clone(), upload_id.clone(), i + 1,
+                                   chunk.to_vec(), creds.clone(), PART_RETRIES)
+        }).collect::<Vec<_>>();
+        let complete_bucket = bucket.clone();
+        let (complete_key, complete_id, complete_creds) = (key.clone(), upload_id.clone(), creds.clone());
+        future::join_all(parts).and_then(move |etags| {
+            complete_bucket.complete_multipart(&complete_key, &complete_id, etags, &complete_creds)
+        }).or_else(move |e| {
+            bucket.abort_multipart(&key, &upload_id, &creds).then(move |_| Err(e))
+        })
+    }))
+}
+
+#[test]
+fn test_xml_element() {
+    let xml = "<InitiateMultipartUploadResult><Key>a/b</Key><UploadId>abc123</UploadId></InitiateMultipartUploadResult>";
+    assert_eq!(Some("abc123"), xml_element(xml, "UploadId"));
+    assert_eq!(None, xml_element(xml, "ETag"));
+}
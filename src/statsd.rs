@@ -0,0 +1,141 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server metrics pushed to a statsd or DogStatsD agent over UDP.
+
+use std::env;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use errors::*;
+
+/// The prefix of metric names, unless `SCCACHE_STATSD_PREFIX` is set.
+const DEFAULT_PREFIX: &'static str = "sccache";
+
+/// Sends metrics to a statsd agent. Sending never blocks, and metrics that
+/// can't be sent are dropped, as they would be on the way anyway.
+pub struct Statsd {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// Prepended to metric names, with a dot.
+    prefix: String,
+    /// DogStatsD tags, such as `env:ci`, added to every metric.
+    tags: Vec<String>,
+}
+
+/// A metric to send.
+pub enum Metric<'a> {
+    /// Add to a counter.
+    Count(&'a str, u64),
+    /// Record a duration.
+    Timing(&'a str, Duration),
+}
+
+impl Statsd {
+    /// Create a client sending to `addr`, a `host:port`.
+    pub fn new(addr: &str, prefix: &str, tags: Vec<String>) -> Result<Statsd> {
+        let addr = addr.to_socket_addrs()
+            .chain_err(|| format!("failed to resolve {}", addr))?
+            .next()
+            .ok_or_else(|| format!("{} has no addresses", addr))?;
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd {
+            socket: socket,
+            addr: addr,
+            prefix: prefix.to_owned(),
+            tags: tags,
+        })
+    }
+
+    /// Create a client from `SCCACHE_STATSD_ADDR`, `SCCACHE_STATSD_PREFIX`
+    /// and `SCCACHE_STATSD_TAGS`, unless no address is set.
+    pub fn from_environment() -> Result<Option<Statsd>> {
+        let addr = match env::var("SCCACHE_STATSD_ADDR") {
+            Ok(addr) => addr,
+            Err(_) => return Ok(None),
+        };
+        let prefix = env::var("SCCACHE_STATSD_PREFIX").unwrap_or(DEFAULT_PREFIX.to_owned());
+        let tags = env::var("SCCACHE_STATSD_TAGS").unwrap_or_default()
+            .split(',')
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| tag.to_owned())
+            .collect();
+        Statsd::new(&addr, &prefix, tags).map(Some)
+    }
+
+    /// Where metrics are sent.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Format `metric` as a line of the statsd protocol.
+    fn format(&self, metric: &Metric) -> String {
+        let mut line = match *metric {
+            Metric::Count(name, count) => format!("{}.{}:{}|c", self.prefix, name, count),
+            Metric::Timing(name, duration) => {
+                format!("{}.{}:{}|ms", self.prefix, name,
+                        duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64)
+            }
+        };
+        if !self.tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&self.tags.join(","));
+        }
+        line
+    }
+
+    /// Send `metrics` in a single packet.
+    pub fn send(&self, metrics: &[Metric]) {
+        let packet = metrics.iter().map(|m| self.format(m)).collect::<Vec<_>>().join("\n");
+        match self.socket.send_to(packet.as_bytes(), self.addr) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!("Dropping statsd metrics, the socket is busy");
+            }
+            Err(e) => debug!("Failed to send statsd metrics to {}: {}", self.addr, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[test]
+    fn test_send() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = agent.local_addr().unwrap().to_string();
+
+        let statsd = Statsd::new(&addr, "build", vec!()).unwrap();
+        statsd.send(&[Metric::Count("compile.hit", 1),
+                      Metric::Timing("time.total", Duration::from_millis(1500))]);
+        let mut buf = [0; 512];
+        let len = agent.recv(&mut buf).unwrap();
+        assert_eq!("build.compile.hit:1|c\nbuild.time.total:1500|ms",
+                   String::from_utf8_lossy(&buf[..len]));
+
+        let statsd = Statsd::new(&addr, "sccache", vec!("env:ci".to_owned(), "team:core".to_owned())).unwrap();
+        statsd.send(&[Metric::Count("cache_write.error", 2)]);
+        let len = agent.recv(&mut buf).unwrap();
+        assert_eq!("sccache.cache_write.error:2|c|#env:ci,team:core",
+                   String::from_utf8_lossy(&buf[..len]));
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for running the server as a systemd service: accepting a
+//! listening socket passed by socket activation, and reporting the
+//! server's state with `sd_notify`.
+//!
+//! See `sd_listen_fds(3)` and `sd_notify(3)` for the protocols.
+
+#[cfg(unix)]
+use libc;
+use std::env;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+/// The first file descriptor passed by socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether systemd passed listening sockets to this process.
+#[cfg(unix)]
+fn has_listen_fds() -> bool {
+    let pid = env::var("LISTEN_PID").ok().and_then(|s| s.parse::<libc::pid_t>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|s| s.parse::<u32>().ok());
+    pid == Some(unsafe { libc::getpid() }) && fds.map_or(false, |n| n > 0)
+}
+
+#[cfg(not(unix))]
+fn has_listen_fds() -> bool { false }
+
+/// Whether this process was started by systemd as a service, in which case
+/// it must stay in the foreground.
+pub fn is_managed() -> bool {
+    has_listen_fds() || env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Take the listening socket passed by socket activation, if any.
+///
+/// Only the first socket is used. The activation variables are removed
+/// from the environment so that compilers don't see them.
+#[cfg(unix)]
+pub fn listen_fd() -> Option<RawFd> {
+    let fd = if has_listen_fds() { Some(LISTEN_FDS_START) } else { None };
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if let Some(fd) = fd {
+        // Don't leak the socket to compilers.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    fd
+}
+
+/// Send `state`, such as `READY=1`, to systemd if it's supervising this
+/// process.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if path.to_string_lossy().starts_with('@') {
+        debug!("sd_notify: abstract notification sockets aren't supported");
+        return;
+    }
+    trace!("sd_notify({:?})", state);
+    let res = UnixDatagram::unbound().and_then(|socket| {
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(e) = res {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// This is a no-op on Windows.
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
@@ -17,19 +17,25 @@ use ::client::{
     connect_to_server,
 };
 use ::commands::{
+    CompileStatus,
     do_compile,
+    request_health_check,
+    request_server_info,
     request_shutdown,
     request_stats,
+    request_zero_stats,
 };
 use env_logger;
+use futures::future;
 use futures::sync::oneshot::{self, Sender};
 use futures_cpupool::CpuPool;
 use ::mock_command::*;
 use ::server::{
+    ServerAddr,
     ServerMessage,
     SccacheServer,
 };
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{
     Cursor,
     Write,
@@ -73,7 +79,7 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
                             .map(|s| *s)
                             .unwrap_or(usize::MAX);
     let pool = CpuPool::new(1);
-    let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
+    let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool).unwrap());
 
     // Create a server on a background thread, get some useful bits from it.
     let (tx, rx) = mpsc::channel();
@@ -97,18 +103,47 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
     (port, shutdown_tx, creator, handle)
 }
 
+/// The address of a server run by `run_server_thread` on `port`.
+fn server_addr(port: u16) -> ServerAddr {
+    ServerAddr::Tcp(format!("127.0.0.1:{}", port).parse().unwrap())
+}
+
 #[test]
 fn test_server_shutdown() {
     let f = TestFixture::new();
     let (port, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
-    let conn = connect_to_server(port).unwrap();
+    let mut conn = connect_to_server(&server_addr(port)).unwrap();
+    // It's idle.
+    let info = request_server_info(&mut conn).unwrap();
+    assert!(info.pid > 0);
+    assert_eq!(0, info.active_compiles);
+    assert_eq!(0, info.pending_writes);
     // Ask it to shut down
-    request_shutdown(conn).unwrap();
+    let shutting_down = request_shutdown(conn).unwrap();
+    // Nothing was left running.
+    assert_eq!(0, shutting_down.unfinished_compiles);
+    assert_eq!(0, shutting_down.unfinished_writes);
     // Ensure that it shuts down.
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_health_check() {
+    let f = TestFixture::new();
+    let (port, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(&server_addr(port)).unwrap();
+    let report = request_health_check(conn).unwrap();
+    assert_eq!(1, report.backends.len());
+    assert!(!report.backends[0].read_only);
+    assert_eq!(None, report.backends[0].write_error);
+    assert_eq!(None, report.backends[0].read_error);
+    assert_eq!(port, report.server_info.port);
+    let conn = connect_to_server(&server_addr(port)).unwrap();
+    request_shutdown(conn).unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_idle_timeout() {
     let f = TestFixture::new();
@@ -126,7 +161,7 @@ fn test_server_stats() {
     let f = TestFixture::new();
     let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&server_addr(port)).unwrap();
     // Ask it for stats.
     let stats = cache_stats_map(request_stats(conn).unwrap());
     assert_eq!(&CacheStat::Count(0), stats.get("Compile requests").unwrap());
@@ -141,7 +176,7 @@ fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
     let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&server_addr(port)).unwrap();
     {
         let mut c = server_creator.lock().unwrap();
         // The server will check the compiler, so pretend to be an unsupported
@@ -165,7 +200,7 @@ fn test_server_unsupported_compiler() {
     let mut stderr = Cursor::new(Vec::new());
     let path = Some(f.paths);
     let mut core = Core::new().unwrap();
-    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, &mut stdout, &mut stderr).unwrap());
+    assert_eq!(CompileStatus::Exited(0), do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, &mut stdout, &mut stderr).unwrap());
     // Make sure we ran the mock processes.
     assert_eq!(0, server_creator.lock().unwrap().children.len());
     assert_eq!(0, client_creator.lock().unwrap().children.len());
@@ -190,7 +225,7 @@ fn test_server_compile() {
     const PREPROCESSOR_STDERR : &'static [u8] = b"preprocessor stderr";
     const STDOUT : &'static [u8] = b"some stdout";
     const STDERR : &'static [u8] = b"some stderr";
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&server_addr(port)).unwrap();
     {
         let mut c = server_creator.lock().unwrap();
         // The server will check the compiler. Pretend it's GCC.
@@ -221,11 +256,20 @@ fn test_server_compile() {
     let mut stderr = Cursor::new(Vec::new());
     let path = Some(f.paths);
     let mut core = Core::new().unwrap();
-    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, &mut stdout, &mut stderr).unwrap());
+    assert_eq!(CompileStatus::Exited(0), do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, &mut stdout, &mut stderr).unwrap());
     // Make sure we ran the mock processes.
     assert_eq!(0, server_creator.lock().unwrap().children.len());
     assert_eq!(STDOUT, stdout.into_inner().as_slice());
     assert_eq!(STDERR, stderr.into_inner().as_slice());
+    // The compile was counted, until the statistics are zeroed.
+    let stats = cache_stats_map(request_stats(connect_to_server(&server_addr(port)).unwrap()).unwrap());
+    assert_map_contains!(stats,
+                         ("Compile requests".to_owned(), CacheStat::Count(1)),
+                         ("Cache misses".to_owned(), CacheStat::Count(1)));
+    let stats = cache_stats_map(request_zero_stats(connect_to_server(&server_addr(port)).unwrap()).unwrap());
+    assert_map_contains!(stats,
+                         ("Compile requests".to_owned(), CacheStat::Count(0)),
+                         ("Cache misses".to_owned(), CacheStat::Count(0)));
     // Shut down the server.
     sender.complete(ServerMessage::Shutdown);
     // Ensure that it shuts down.
@@ -248,3 +292,33 @@ fn test_server_port_in_use() {
             "Output did not contain 'Failed to start server:':\n========\n{}\n========",
             s);
 }
+
+#[test]
+#[cfg(unix)]
+fn test_server_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let f = TestFixture::new();
+    let path = f.tempdir.path().join("run").join("server.sock");
+    let addr = ServerAddr::Unix(path.clone());
+    let pool = CpuPool::new(1);
+    let storage = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool).unwrap());
+    let (tx, rx) = mpsc::channel();
+    let server_addr = addr.clone();
+    let child = thread::spawn(move || {
+        let core = Core::new().unwrap();
+        let srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> =
+            SccacheServer::bind(&server_addr, pool, core, storage).unwrap();
+        tx.send(()).unwrap();
+        srv.run(future::empty::<(), ()>()).unwrap();
+    });
+    rx.recv().unwrap();
+    // Only the current user may connect to the server.
+    let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+    assert_eq!(0o600, mode(&path));
+    assert_eq!(0o700, mode(path.parent().unwrap()));
+    let conn = connect_to_server(&addr).unwrap();
+    request_shutdown(conn).unwrap();
+    child.join().unwrap();
+    assert!(!path.exists());
+}
@@ -15,7 +15,7 @@
 #[cfg(unix)]
 use libc;
 use mock_command::*;
-use protocol::CacheStats;
+use protocol::{CacheStats, CacheStatValue};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
@@ -174,8 +174,12 @@ pub enum CacheStat {
     Size(u64),
 }
 
-pub fn cache_stats_map(mut stats: CacheStats) -> HashMap<String, CacheStat> {
-    stats.take_stats().into_iter().map(|mut s| (s.take_name(), if s.has_count() { CacheStat::Count(s.get_count()) } else if s.has_size() { CacheStat::Size(s.get_size()) } else { CacheStat::String(s.take_str()) })).collect()
+pub fn cache_stats_map(stats: CacheStats) -> HashMap<String, CacheStat> {
+    stats.into_iter().map(|s| (s.name, match s.value {
+        CacheStatValue::Count(count) => CacheStat::Count(count),
+        CacheStatValue::Size(size) => CacheStat::Size(size),
+        CacheStatValue::Str(s) => CacheStat::String(s),
+    })).collect()
 }
 
 #[test]
@@ -0,0 +1,179 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS settings, shared by the HTTP clients of remote storage and the
+//! parts of distributed compilation.
+
+use native_tls::{self, Certificate, Pkcs12, TlsAcceptor, TlsConnector};
+#[cfg(unix)]
+use openssl::pkcs12::Pkcs12 as OpensslPkcs12;
+#[cfg(unix)]
+use openssl::pkey::PKey;
+#[cfg(unix)]
+use openssl::x509::X509;
+use rustc_serialize::base64::FromBase64;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+
+/// Client certificates and certificate authorities to use for TLS.
+#[derive(Default)]
+pub struct TlsConfig {
+    /// The client certificate and its private key, as PKCS #12.
+    identity: Option<Vec<u8>>,
+    /// Certificate authorities to trust besides the system ones, as DER.
+    roots: Vec<Vec<u8>>,
+}
+
+/// Read the whole file at `path`.
+pub fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = vec!();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .chain_err(|| format!("failed to read {}", path.display()))?;
+    Ok(contents)
+}
+
+/// Split the PEM certificates in `pem` into separate certificates.
+fn split_pem(pem: &[u8]) -> Vec<&[u8]> {
+    const END: &'static [u8] = b"-----END CERTIFICATE-----";
+    let mut certs = vec!();
+    let mut rest = pem;
+    while let Some(end) = rest.windows(END.len()).position(|w| w == END) {
+        certs.push(&rest[..end + END.len()]);
+        rest = &rest[end + END.len()..];
+    }
+    certs
+}
+
+/// Decode the PEM certificate `pem` to DER.
+fn pem_to_der(pem: &[u8]) -> Result<Vec<u8>> {
+    let pem = String::from_utf8_lossy(pem);
+    let body = pem.lines()
+        .map(|line| line.trim())
+        .skip_while(|line| *line != "-----BEGIN CERTIFICATE-----")
+        .skip(1)
+        .take_while(|line| *line != "-----END CERTIFICATE-----")
+        .collect::<String>();
+    if body.is_empty() {
+        bail!("not a PEM certificate");
+    }
+    body.from_base64().chain_err(|| "invalid base64 in PEM certificate")
+}
+
+impl TlsConfig {
+    /// Read the client certificate from `SCCACHE_TLS_CLIENT_CERT` and its key
+    /// from `SCCACHE_TLS_CLIENT_KEY`, and certificate authorities to trust
+    /// from `SCCACHE_TLS_CA_BUNDLE`, all of them PEM files.
+    pub fn from_env() -> Result<TlsConfig> {
+        let mut config = TlsConfig::default();
+        match (env::var_os("SCCACHE_TLS_CLIENT_CERT"), env::var_os("SCCACHE_TLS_CLIENT_KEY")) {
+            (Some(cert), Some(key)) => {
+                config.set_identity(&read_file(Path::new(&cert))?, &read_file(Path::new(&key))?)?;
+            }
+            (None, None) => {}
+            _ => bail!("SCCACHE_TLS_CLIENT_CERT and SCCACHE_TLS_CLIENT_KEY must be set together"),
+        }
+        if let Some(bundle) = env::var_os("SCCACHE_TLS_CA_BUNDLE") {
+            config.add_roots(&read_file(Path::new(&bundle))?)?;
+        }
+        Ok(config)
+    }
+
+    /// Authenticate with the PEM certificate `cert` and private key `key`.
+    #[cfg(unix)]
+    pub fn set_identity(&mut self, cert: &[u8], key: &[u8]) -> Result<()> {
+        let cert = X509::from_pem(cert).chain_err(|| "invalid client certificate")?;
+        let key = PKey::private_key_from_pem(key).chain_err(|| "invalid client key")?;
+        let pkcs12 = OpensslPkcs12::builder()
+            .build("", "sccache", &key, &cert)
+            .and_then(|p| p.to_der())
+            .chain_err(|| "client certificate doesn't match its key")?;
+        self.identity = Some(pkcs12);
+        Ok(())
+    }
+
+    /// Authenticate with the PEM certificate `cert` and private key `key`,
+    /// which needs OpenSSL to convert them for the system TLS library.
+    #[cfg(not(unix))]
+    pub fn set_identity(&mut self, _cert: &[u8], _key: &[u8]) -> Result<()> {
+        bail!("client certificates are only supported on Unix")
+    }
+
+    /// Trust the certificate authorities in the PEM bundle `bundle`.
+    pub fn add_roots(&mut self, bundle: &[u8]) -> Result<()> {
+        let certs = split_pem(bundle);
+        if certs.is_empty() {
+            bail!("no certificates in CA bundle");
+        }
+        for pem in certs {
+            let cert = pem_to_der(pem).chain_err(|| "invalid certificate in CA bundle")?;
+            self.roots.push(cert);
+        }
+        Ok(())
+    }
+
+    /// Build a `TlsConnector` using these settings.
+    pub fn connector(&self) -> Result<TlsConnector> {
+        let tls = || -> ::std::result::Result<TlsConnector, native_tls::Error> {
+            let mut builder = TlsConnector::builder()?;
+            if let Some(ref identity) = self.identity {
+                builder.identity(Pkcs12::from_der(identity, "")?)?;
+            }
+            for root in self.roots.iter() {
+                builder.add_root_certificate(Certificate::from_der(root)?)?;
+            }
+            builder.build()
+        };
+        tls().chain_err(|| "failed to set up TLS")
+    }
+
+    /// Build a `TlsAcceptor` serving with the certificate of these settings.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let identity = match self.identity {
+            Some(ref identity) => identity,
+            None => bail!("no certificate to serve TLS with"),
+        };
+        let tls = || -> ::std::result::Result<TlsAcceptor, native_tls::Error> {
+            TlsAcceptor::builder(Pkcs12::from_der(identity, "")?)?.build()
+        };
+        tls().chain_err(|| "failed to set up TLS")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_pem() {
+        let bundle = b"# CA one\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                       -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let certs = split_pem(bundle);
+        assert_eq!(2, certs.len());
+        assert!(certs[1].starts_with(b"\n-----BEGIN CERTIFICATE-----\nBBBB"));
+        assert!(split_pem(b"not a certificate").is_empty());
+    }
+
+    #[test]
+    fn test_pem_to_der() {
+        let pem = b"-----BEGIN CERTIFICATE-----\nAAEC\nAwQ=\n-----END CERTIFICATE-----";
+        assert_eq!(vec![0, 1, 2, 3, 4], pem_to_der(pem).unwrap());
+        assert!(pem_to_der(b"-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----").is_err());
+        assert!(pem_to_der(b"AAEC").is_err());
+    }
+}
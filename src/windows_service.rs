@@ -0,0 +1,405 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the server as a Windows service, which starts with the system
+//! and is shared by all users of the machine, instead of each user's
+//! clients starting a server of their own.
+//!
+//! The service control manager runs `sccache --internal-run-service`,
+//! which logs to the Application event log and is restarted if it fails.
+
+#![allow(non_camel_case_types, non_snake_case)]
+
+use advapi32::{
+    CloseServiceHandle,
+    ControlService,
+    CreateServiceW,
+    DeleteService,
+    OpenSCManagerW,
+    OpenServiceW,
+    RegisterServiceCtrlHandlerExW,
+    SetServiceStatus,
+    StartServiceCtrlDispatcherW,
+};
+use commands::get_addr;
+use futures::sync::oneshot;
+use log::{Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use logging;
+use server;
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use winapi::{
+    BOOL,
+    DELETE,
+    DWORD,
+    ERROR_CALL_NOT_IMPLEMENTED,
+    ERROR_FAILED_SERVICE_CONTROLLER_CONNECT,
+    ERROR_SERVICE_DOES_NOT_EXIST,
+    ERROR_SERVICE_EXISTS,
+    ERROR_SERVICE_SPECIFIC_ERROR,
+    HANDLE,
+    LPCWSTR,
+    LPVOID,
+    LPWSTR,
+    NO_ERROR,
+    PSID,
+    SC_HANDLE,
+    SC_MANAGER_CONNECT,
+    SC_MANAGER_CREATE_SERVICE,
+    SERVICE_ACCEPT_SHUTDOWN,
+    SERVICE_ACCEPT_STOP,
+    SERVICE_ALL_ACCESS,
+    SERVICE_CONFIG_DESCRIPTION,
+    SERVICE_CONFIG_FAILURE_ACTIONS,
+    SERVICE_CONFIG_FAILURE_ACTIONS_FLAG,
+    SERVICE_CONTROL_INTERROGATE,
+    SERVICE_CONTROL_SHUTDOWN,
+    SERVICE_CONTROL_STOP,
+    SERVICE_QUERY_STATUS,
+    SERVICE_RUNNING,
+    SERVICE_START_PENDING,
+    SERVICE_STATUS,
+    SERVICE_STATUS_HANDLE,
+    SERVICE_STOP,
+    SERVICE_STOP_PENDING,
+    SERVICE_STOPPED,
+    SERVICE_TABLE_ENTRYW,
+    SERVICE_WIN32_OWN_PROCESS,
+    WORD,
+};
+
+use errors::*;
+
+/// The name of the service, which is also the source of its events.
+const SERVICE_NAME: &'static str = "sccache";
+
+/// The name the service is shown with.
+const DISPLAY_NAME: &'static str = "sccache compiler cache";
+
+const DESCRIPTION: &'static str = "Caches the results of compilations for all users of this machine.";
+
+/// The account the service runs as. Compilers are run as this account on
+/// behalf of any user, so it has as few privileges as possible.
+const SERVICE_ACCOUNT: &'static str = "NT AUTHORITY\\LocalService";
+
+/// How long stopping the service may take, in milliseconds.
+const STOP_WAIT_HINT_MS: DWORD = 30_000;
+
+/// How long to wait before restarting the service after it failed, in
+/// milliseconds.
+const RESTART_DELAY_MS: DWORD = 10_000;
+
+/// The number of seconds after which the count of failures is reset.
+const FAILURE_RESET_PERIOD_SECS: DWORD = 24 * 60 * 60;
+
+// Definitions that winapi and advapi32-sys don't have yet.
+
+const SERVICE_AUTO_START: DWORD = 0x00000002;
+const SERVICE_ERROR_NORMAL: DWORD = 0x00000001;
+const SC_ACTION_RESTART: DWORD = 1;
+const EVENTLOG_ERROR_TYPE: WORD = 0x0001;
+const EVENTLOG_WARNING_TYPE: WORD = 0x0002;
+const EVENTLOG_INFORMATION_TYPE: WORD = 0x0004;
+
+#[repr(C)]
+struct SERVICE_DESCRIPTIONW {
+    lpDescription: LPWSTR,
+}
+
+#[repr(C)]
+struct SC_ACTION {
+    Type: DWORD,
+    Delay: DWORD,
+}
+
+#[repr(C)]
+struct SERVICE_FAILURE_ACTIONSW {
+    dwResetPeriod: DWORD,
+    lpRebootMsg: LPWSTR,
+    lpCommand: LPWSTR,
+    cActions: DWORD,
+    lpsaActions: *mut SC_ACTION,
+}
+
+#[repr(C)]
+struct SERVICE_FAILURE_ACTIONS_FLAG {
+    fFailureActionsOnNonCrashFailures: BOOL,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn ChangeServiceConfig2W(hService: SC_HANDLE, dwInfoLevel: DWORD, lpInfo: LPVOID) -> BOOL;
+    fn StartServiceW(hService: SC_HANDLE, dwNumServiceArgs: DWORD,
+                     lpServiceArgVectors: *const LPCWSTR) -> BOOL;
+    fn RegisterEventSourceW(lpUNCServerName: LPCWSTR, lpSourceName: LPCWSTR) -> HANDLE;
+    fn ReportEventW(hEventLog: HANDLE, wType: WORD, wCategory: WORD, dwEventID: DWORD,
+                    lpUserSid: PSID, wNumStrings: WORD, dwDataSize: DWORD,
+                    lpStrings: *const LPCWSTR, lpRawData: LPVOID) -> BOOL;
+}
+
+/// `s` as a nul-terminated wide string.
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+/// A handle to the service control manager or a service, closed when
+/// dropped.
+struct ScHandle(SC_HANDLE);
+
+impl ScHandle {
+    /// Connect to the service control manager of this machine.
+    fn manager(access: DWORD) -> Result<ScHandle> {
+        let handle = unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error())
+                .chain_err(|| "couldn't connect to the service control manager; run as an administrator");
+        }
+        Ok(ScHandle(handle))
+    }
+
+    /// Change the configuration of this service.
+    fn change_config<T>(&self, level: DWORD, info: &mut T) -> Result<()> {
+        if unsafe { ChangeServiceConfig2W(self.0, level, info as *mut T as LPVOID) } == 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "couldn't configure the service");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/// Install the server as a service that starts with the system, and
+/// start it.
+pub fn install() -> Result<()> {
+    let exe = env::current_exe()?;
+    let command = format!("\"{}\" --internal-run-service", exe.display());
+    let manager = ScHandle::manager(SC_MANAGER_CREATE_SERVICE)?;
+    let handle = unsafe {
+        CreateServiceW(manager.0,
+                       wide(SERVICE_NAME).as_ptr(),
+                       wide(DISPLAY_NAME).as_ptr(),
+                       SERVICE_ALL_ACCESS,
+                       SERVICE_WIN32_OWN_PROCESS,
+                       SERVICE_AUTO_START,
+                       SERVICE_ERROR_NORMAL,
+                       wide(&command).as_ptr(),
+                       ptr::null(),
+                       ptr::null_mut(),
+                       ptr::null(),
+                       wide(SERVICE_ACCOUNT).as_ptr(),
+                       ptr::null())
+    };
+    if handle.is_null() {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(ERROR_SERVICE_EXISTS as i32) {
+            bail!("The {} service is already installed", SERVICE_NAME);
+        }
+        return Err(e).chain_err(|| "couldn't create the service");
+    }
+    let service = ScHandle(handle);
+    let mut description = wide(DESCRIPTION);
+    service.change_config(SERVICE_CONFIG_DESCRIPTION, &mut SERVICE_DESCRIPTIONW {
+        lpDescription: description.as_mut_ptr(),
+    })?;
+    // Restart the service whenever it fails, including when it stops with
+    // an error rather than crashing.
+    let mut actions = [SC_ACTION { Type: SC_ACTION_RESTART, Delay: RESTART_DELAY_MS },
+                       SC_ACTION { Type: SC_ACTION_RESTART, Delay: RESTART_DELAY_MS },
+                       SC_ACTION { Type: SC_ACTION_RESTART, Delay: RESTART_DELAY_MS }];
+    service.change_config(SERVICE_CONFIG_FAILURE_ACTIONS, &mut SERVICE_FAILURE_ACTIONSW {
+        dwResetPeriod: FAILURE_RESET_PERIOD_SECS,
+        lpRebootMsg: ptr::null_mut(),
+        lpCommand: ptr::null_mut(),
+        cActions: actions.len() as DWORD,
+        lpsaActions: actions.as_mut_ptr(),
+    })?;
+    service.change_config(SERVICE_CONFIG_FAILURE_ACTIONS_FLAG, &mut SERVICE_FAILURE_ACTIONS_FLAG {
+        fFailureActionsOnNonCrashFailures: 1,
+    })?;
+    if unsafe { StartServiceW(service.0, 0, ptr::null()) } == 0 {
+        return Err(io::Error::last_os_error()).chain_err(|| "the service was installed, but couldn't be started");
+    }
+    Ok(())
+}
+
+/// Stop and remove the service.
+pub fn uninstall() -> Result<()> {
+    let manager = ScHandle::manager(SC_MANAGER_CONNECT)?;
+    let access = SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE;
+    let handle = unsafe { OpenServiceW(manager.0, wide(SERVICE_NAME).as_ptr(), access) };
+    if handle.is_null() {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST as i32) {
+            bail!("The {} service isn't installed", SERVICE_NAME);
+        }
+        return Err(e).chain_err(|| "couldn't open the service");
+    }
+    let service = ScHandle(handle);
+    let mut status = stopped_status();
+    // The service is removed once it has stopped, if it's running.
+    unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) };
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(io::Error::last_os_error()).chain_err(|| "couldn't remove the service");
+    }
+    Ok(())
+}
+
+/// Run the service, for the service control manager.
+pub fn run() -> Result<()> {
+    let name = wide(SERVICE_NAME);
+    let table = [SERVICE_TABLE_ENTRYW { lpServiceName: name.as_ptr(), lpServiceProc: Some(service_main) },
+                 SERVICE_TABLE_ENTRYW { lpServiceName: ptr::null(), lpServiceProc: None }];
+    // This returns once the service has stopped.
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT as i32) {
+            bail!("--internal-run-service is run by the service control manager; use --install-service");
+        }
+        return Err(e).chain_err(|| "couldn't connect to the service control manager");
+    }
+    Ok(())
+}
+
+/// What the control handler of the service needs.
+struct Context {
+    /// Completed to stop the server.
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+    /// The `SERVICE_STATUS_HANDLE` to report the service's status with.
+    status_handle: AtomicUsize,
+}
+
+fn stopped_status() -> SERVICE_STATUS {
+    SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: SERVICE_STOPPED,
+        dwControlsAccepted: 0,
+        dwWin32ExitCode: NO_ERROR,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        dwWaitHint: 0,
+    }
+}
+
+/// Report that the service is in `state`, having failed with
+/// `exit_code` if it's not zero.
+fn set_status(handle: SERVICE_STATUS_HANDLE, state: DWORD, exit_code: DWORD) {
+    let mut status = stopped_status();
+    status.dwCurrentState = state;
+    if state == SERVICE_RUNNING {
+        status.dwControlsAccepted = SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN;
+    }
+    if state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING {
+        status.dwWaitHint = STOP_WAIT_HINT_MS;
+    }
+    if exit_code != 0 {
+        status.dwWin32ExitCode = ERROR_SERVICE_SPECIFIC_ERROR;
+        status.dwServiceSpecificExitCode = exit_code;
+    }
+    unsafe { SetServiceStatus(handle, &mut status) };
+}
+
+unsafe extern "system" fn service_main(_argc: DWORD, _argv: *mut LPWSTR) {
+    // Unless logging was configured otherwise.
+    let _ = logging::install(Box::new(EventLogger::new()), LogLevelFilter::Info);
+    let (tx, rx) = oneshot::channel();
+    // The handler may be called until the process exits, so the context
+    // is never freed.
+    let context = Box::into_raw(Box::new(Context {
+        stop: Mutex::new(Some(tx)),
+        status_handle: AtomicUsize::new(0),
+    }));
+    let handle = RegisterServiceCtrlHandlerExW(wide(SERVICE_NAME).as_ptr(), Some(control_handler),
+                                               context as LPVOID);
+    if handle.is_null() {
+        error!("Couldn't register the service control handler: {}", io::Error::last_os_error());
+        return;
+    }
+    (*context).status_handle.store(handle as usize, Ordering::SeqCst);
+    set_status(handle, SERVICE_START_PENDING, 0);
+    let res = get_addr().and_then(|addr| server::run_service(&addr, rx, || {
+        info!("The {} service is running", SERVICE_NAME);
+        set_status(handle, SERVICE_RUNNING, 0);
+    }));
+    match res {
+        Ok(()) => set_status(handle, SERVICE_STOPPED, 0),
+        Err(e) => {
+            error!("The {} service failed: {}", SERVICE_NAME, e);
+            set_status(handle, SERVICE_STOPPED, 1);
+        }
+    }
+}
+
+unsafe extern "system" fn control_handler(control: DWORD, _event_type: DWORD, _event_data: LPVOID,
+                                          context: LPVOID) -> DWORD {
+    let context = &*(context as *const Context);
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            let handle = context.status_handle.load(Ordering::SeqCst) as SERVICE_STATUS_HANDLE;
+            set_status(handle, SERVICE_STOP_PENDING, 0);
+            if let Some(tx) = context.stop.lock().unwrap().take() {
+                tx.complete(());
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// A logger reporting to the Application event log, for the service.
+struct EventLogger {
+    /// The event source `HANDLE`, or zero if it couldn't be registered.
+    source: usize,
+}
+
+impl EventLogger {
+    fn new() -> EventLogger {
+        let source = unsafe { RegisterEventSourceW(ptr::null(), wide(SERVICE_NAME).as_ptr()) };
+        EventLogger {
+            source: source as usize,
+        }
+    }
+}
+
+impl Log for EventLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.source != 0 && metadata.level() <= ::log::max_log_level()
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let kind = match record.level() {
+            LogLevel::Error => EVENTLOG_ERROR_TYPE,
+            LogLevel::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(&format!("{}", record.args()));
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(self.source as HANDLE, kind, 0, 0, ptr::null_mut(), 1, 0,
+                         strings.as_ptr(), ptr::null_mut());
+        }
+    }
+}